@@ -0,0 +1,246 @@
+//! Admission control for the API layer.
+//!
+//! Every request must be admitted before it touches the database. Ingest
+//! and query requests have separate concurrency limits and bounded wait
+//! queues; once a queue is full, new requests are rejected immediately with
+//! [`Error::Overloaded`] and a retry-after hint instead of piling up. Under
+//! overload this keeps the WAL append path at its normal latency for the
+//! requests that are admitted, rather than slowing everyone down.
+
+use crate::error::{Error, Result};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Kind of work a request performs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// Writes that append to the WAL
+    Ingest,
+    /// Reads
+    Query,
+}
+
+/// Limits enforced by an [`AdmissionController`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdmissionConfig {
+    /// Ingest requests executing at once
+    pub max_concurrent_ingest: usize,
+    /// Ingest requests allowed to wait for a slot
+    pub max_ingest_queue: usize,
+    /// Queries executing at once
+    pub max_concurrent_queries: usize,
+    /// Queries allowed to wait for a slot
+    pub max_query_queue: usize,
+    /// Delay suggested to rejected clients
+    pub retry_after: Duration,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_ingest: 64,
+            max_ingest_queue: 1024,
+            max_concurrent_queries: 32,
+            max_query_queue: 256,
+            retry_after: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Load of one request class
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+    /// Requests currently executing
+    pub in_flight: usize,
+    /// Requests waiting for a slot
+    pub queued: usize,
+    /// Requests admitted so far
+    pub admitted: u64,
+    /// Requests shed so far
+    pub rejected: u64,
+}
+
+struct Lane {
+    slots: Arc<Semaphore>,
+    concurrency: usize,
+    max_queue: usize,
+    queued: AtomicUsize,
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Lane {
+    fn new(concurrency: usize, max_queue: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+            max_queue,
+            queued: AtomicUsize::new(0),
+            admitted: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            in_flight: self.concurrency - self.slots.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            admitted: self.admitted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Place in a wait queue, given up when the wait ends or is cancelled
+struct QueuePlace<'a>(&'a AtomicUsize);
+
+impl Drop for QueuePlace<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Slot held by an admitted request; dropping it frees the slot
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Concurrency limiter with queue-depth based load shedding
+pub struct AdmissionController {
+    ingest: Lane,
+    query: Lane,
+    retry_after: Duration,
+}
+
+impl AdmissionController {
+    /// Create a controller enforcing `config`
+    pub fn new(config: AdmissionConfig) -> Result<Self> {
+        if config.max_concurrent_ingest == 0 || config.max_concurrent_queries == 0 {
+            return Err(Error::Configuration(
+                "Admission concurrency limits must be positive".to_string(),
+            ));
+        }
+        Ok(Self {
+            ingest: Lane::new(config.max_concurrent_ingest, config.max_ingest_queue),
+            query: Lane::new(config.max_concurrent_queries, config.max_query_queue),
+            retry_after: config.retry_after,
+        })
+    }
+
+    fn lane(&self, class: RequestClass) -> &Lane {
+        match class {
+            RequestClass::Ingest => &self.ingest,
+            RequestClass::Query => &self.query,
+        }
+    }
+
+    /// Admit a request, waiting for a slot if one is queued.
+    ///
+    /// Fails with [`Error::Overloaded`] when every slot is taken and the
+    /// wait queue is full.
+    pub async fn admit(&self, class: RequestClass) -> Result<AdmissionPermit> {
+        let lane = self.lane(class);
+        if let Ok(permit) = lane.slots.clone().try_acquire_owned() {
+            lane.admitted.fetch_add(1, Ordering::Relaxed);
+            return Ok(AdmissionPermit { _permit: permit });
+        }
+
+        let reserved = lane
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < lane.max_queue).then_some(queued + 1)
+            });
+        if reserved.is_err() {
+            lane.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::Overloaded {
+                retry_after: self.retry_after,
+            });
+        }
+
+        let place = QueuePlace(&lane.queued);
+        let permit = lane.slots.clone().acquire_owned().await;
+        drop(place);
+        let permit =
+            permit.map_err(|_| Error::Network("Admission controller closed".to_string()))?;
+        lane.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(AdmissionPermit { _permit: permit })
+    }
+
+    /// Current load of a request class
+    pub fn stats(&self, class: RequestClass) -> AdmissionStats {
+        self.lane(class).stats()
+    }
+}
+
+impl Default for AdmissionController {
+    fn default() -> Self {
+        Self::new(AdmissionConfig::default()).expect("default admission config is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sheds_load_when_queue_is_full() {
+        let controller = Arc::new(
+            AdmissionController::new(AdmissionConfig {
+                max_concurrent_ingest: 1,
+                max_ingest_queue: 1,
+                retry_after: Duration::from_millis(250),
+                ..AdmissionConfig::default()
+            })
+            .unwrap(),
+        );
+
+        let running = controller.admit(RequestClass::Ingest).await.unwrap();
+        let waiter = {
+            let controller = controller.clone();
+            tokio::spawn(async move { controller.admit(RequestClass::Ingest).await })
+        };
+        while controller.stats(RequestClass::Ingest).queued == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        match controller.admit(RequestClass::Ingest).await {
+            Err(Error::Overloaded { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_millis(250))
+            }
+            other => panic!("expected overload, got {:?}", other),
+        }
+        // Queries have their own slots.
+        assert!(controller.admit(RequestClass::Query).await.is_ok());
+
+        drop(running);
+        assert!(waiter.await.unwrap().is_ok());
+        let stats = controller.stats(RequestClass::Ingest);
+        assert_eq!((stats.admitted, stats.rejected, stats.queued), (2, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_leaves_queue() {
+        let controller = Arc::new(
+            AdmissionController::new(AdmissionConfig {
+                max_concurrent_queries: 1,
+                max_query_queue: 1,
+                ..AdmissionConfig::default()
+            })
+            .unwrap(),
+        );
+        let _running = controller.admit(RequestClass::Query).await.unwrap();
+        let waiter = {
+            let controller = controller.clone();
+            tokio::spawn(async move { controller.admit(RequestClass::Query).await })
+        };
+        while controller.stats(RequestClass::Query).queued == 0 {
+            tokio::task::yield_now().await;
+        }
+        waiter.abort();
+        assert!(waiter.await.unwrap_err().is_cancelled());
+        assert_eq!(controller.stats(RequestClass::Query).queued, 0);
+    }
+}
@@ -0,0 +1,293 @@
+//! Token exchange for browser and other untrusted clients.
+//!
+//! Long-lived API keys must not be embedded in a web page. Instead, a
+//! client's backend exchanges its API key at [`TOKEN_PATH`] for a
+//! short-lived JWT (HS256) restricted to the scopes it asks for, and hands
+//! that token to the browser. Every later request carries it as a bearer
+//! token, and the server rebuilds the [`Principal`] row-level security
+//! applies to from its claims.
+
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::query::security::Principal;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Route of the token exchange endpoint
+pub const TOKEN_PATH: &str = "/auth/token";
+
+/// Default lifetime of issued tokens
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// JOSE header of issued tokens
+const TOKEN_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// What a token allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Point-in-time and range queries
+    Query,
+    /// Live event subscriptions
+    Subscribe,
+    /// Writes
+    Ingest,
+}
+
+/// Principal and scopes an API key grants
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyGrant {
+    /// Identity tokens issued for the key run as
+    pub principal: Principal,
+    /// Scopes tokens may be restricted to
+    pub scopes: Vec<TokenScope>,
+}
+
+/// Body of a token request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenRequest {
+    /// API key to exchange
+    pub api_key: String,
+    /// Requested scopes; empty requests every scope of the key
+    #[serde(default)]
+    pub scope: Vec<TokenScope>,
+}
+
+/// Body of a token response, shaped like an OAuth 2.0 token response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenResponse {
+    /// Signed JWT
+    pub access_token: String,
+    /// Always `Bearer`
+    pub token_type: String,
+    /// Seconds until the token expires
+    pub expires_in: u64,
+    /// Scopes the token grants
+    pub scope: Vec<TokenScope>,
+}
+
+/// Claims of an issued token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// Principal ID
+    pub sub: String,
+    /// Principal roles
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Principal attributes
+    #[serde(default)]
+    pub attrs: HashMap<String, Value>,
+    /// Granted scopes
+    pub scope: Vec<TokenScope>,
+    /// Issue time, in seconds since the epoch
+    pub iat: i64,
+    /// Expiry time, in seconds since the epoch
+    pub exp: i64,
+}
+
+impl TokenClaims {
+    /// Principal the token's requests run as
+    pub fn principal(&self) -> Principal {
+        Principal {
+            id: self.sub.clone(),
+            roles: self.roles.clone(),
+            attributes: self.attrs.clone(),
+        }
+    }
+
+    /// Fail unless the token grants `scope`
+    pub fn require(&self, scope: TokenScope) -> Result<()> {
+        if self.scope.contains(&scope) {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized(format!(
+                "Token lacks the {:?} scope",
+                scope
+            )))
+        }
+    }
+}
+
+/// Exchanges API keys for signed, short-lived tokens and verifies them
+pub struct TokenIssuer {
+    signing_key: hmac::Key,
+    ttl: Duration,
+    /// Grants by SHA-256 digest of the API key
+    api_keys: HashMap<[u8; 32], ApiKeyGrant>,
+}
+
+impl TokenIssuer {
+    /// Issuer signing tokens with `secret`, which must be at least 32 bytes
+    pub fn new(secret: &[u8]) -> Result<Self> {
+        if secret.len() < 32 {
+            return Err(Error::Configuration(
+                "Token signing secret must be at least 32 bytes".to_string(),
+            ));
+        }
+        Ok(Self {
+            signing_key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            ttl: DEFAULT_TOKEN_TTL,
+            api_keys: HashMap::new(),
+        })
+    }
+
+    /// Set the lifetime of issued tokens
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Accept `api_key`, issuing tokens for `principal` with at most `scopes`
+    pub fn with_api_key(
+        mut self,
+        api_key: &str,
+        principal: Principal,
+        scopes: Vec<TokenScope>,
+    ) -> Self {
+        self.api_keys
+            .insert(digest(api_key), ApiKeyGrant { principal, scopes });
+        self
+    }
+
+    /// Exchange an API key for a token valid from `now`.
+    ///
+    /// Fails with [`Error::Unauthorized`] for an unknown key or a scope the
+    /// key does not grant.
+    pub fn issue(&self, request: &TokenRequest, now: Timestamp) -> Result<TokenResponse> {
+        let grant = self
+            .api_keys
+            .get(&digest(&request.api_key))
+            .ok_or_else(|| Error::Unauthorized("Unknown API key".to_string()))?;
+        let scope = if request.scope.is_empty() {
+            grant.scopes.clone()
+        } else {
+            if let Some(denied) = request.scope.iter().find(|s| !grant.scopes.contains(s)) {
+                return Err(Error::Unauthorized(format!(
+                    "API key does not grant the {:?} scope",
+                    denied
+                )));
+            }
+            request.scope.clone()
+        };
+        let claims = TokenClaims {
+            sub: grant.principal.id.clone(),
+            roles: grant.principal.roles.clone(),
+            attrs: grant.principal.attributes.clone(),
+            scope: scope.clone(),
+            iat: now.as_secs(),
+            exp: now.as_secs() + self.ttl.as_secs() as i64,
+        };
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(TOKEN_HEADER),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = hmac::sign(&self.signing_key, signed.as_bytes());
+        Ok(TokenResponse {
+            access_token: format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature)),
+            token_type: "Bearer".to_string(),
+            expires_in: self.ttl.as_secs(),
+            scope,
+        })
+    }
+
+    /// Claims of `token` if it was issued here and has not expired at `now`
+    pub fn verify(&self, token: &str, now: Timestamp) -> Result<TokenClaims> {
+        let invalid = || Error::Unauthorized("Malformed or forged token".to_string());
+        let (signed, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        hmac::verify(&self.signing_key, signed.as_bytes(), &signature).map_err(|_| invalid())?;
+
+        let (header, claims) = signed.split_once('.').ok_or_else(invalid)?;
+        let header = URL_SAFE_NO_PAD.decode(header).map_err(|_| invalid())?;
+        if header != TOKEN_HEADER.as_bytes() {
+            return Err(invalid());
+        }
+        let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| invalid())?;
+        let claims: TokenClaims = serde_json::from_slice(&claims).map_err(|_| invalid())?;
+        if now.as_secs() >= claims.exp {
+            return Err(Error::Unauthorized("Token expired".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Claims of the bearer token in an `Authorization` header value
+    pub fn verify_bearer(&self, authorization: &str, now: Timestamp) -> Result<TokenClaims> {
+        let token = authorization
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| Error::Unauthorized("Expected a bearer token".to_string()))?;
+        self.verify(token.trim(), now)
+    }
+}
+
+impl fmt::Debug for TokenIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenIssuer")
+            .field("ttl", &self.ttl)
+            .field("api_keys", &self.api_keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn digest(api_key: &str) -> [u8; 32] {
+    Sha256::digest(api_key.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_verify() {
+        assert!(TokenIssuer::new(b"short").is_err());
+        let issuer = TokenIssuer::new(&[7; 32])
+            .unwrap()
+            .with_ttl(Duration::from_secs(60))
+            .with_api_key(
+                "key-1",
+                Principal::new("alice")
+                    .with_role("tenant")
+                    .with_attribute("tenant", "acme"),
+                vec![TokenScope::Query, TokenScope::Subscribe],
+            );
+        let now = Timestamp::from_secs(1_700_000_000);
+        let request = |key: &str, scope: Vec<TokenScope>| TokenRequest {
+            api_key: key.to_string(),
+            scope,
+        };
+
+        let token = issuer
+            .issue(&request("key-1", vec![TokenScope::Subscribe]), now)
+            .unwrap();
+        assert_eq!(token.expires_in, 60);
+        let claims = issuer
+            .verify_bearer(&format!("Bearer {}", token.access_token), now)
+            .unwrap();
+        assert_eq!(claims.principal().attributes["tenant"], "acme");
+        assert!(claims.require(TokenScope::Subscribe).is_ok());
+        assert!(claims.require(TokenScope::Query).is_err());
+
+        let all = issuer.issue(&request("key-1", vec![]), now).unwrap();
+        assert_eq!(all.scope, vec![TokenScope::Query, TokenScope::Subscribe]);
+        assert!(issuer
+            .issue(&request("key-1", vec![TokenScope::Ingest]), now)
+            .is_err());
+        assert!(issuer.issue(&request("key-2", vec![]), now).is_err());
+
+        // Expired, tampered and foreign tokens are rejected.
+        let later = Timestamp::from_secs(now.as_secs() + 60);
+        assert!(issuer.verify(&token.access_token, later).is_err());
+        let mut tampered = token.access_token.clone();
+        tampered.insert(tampered.find('.').unwrap() + 1, 'x');
+        assert!(issuer.verify(&tampered, now).is_err());
+        let other = TokenIssuer::new(&[8; 32]).unwrap();
+        assert!(other.verify(&token.access_token, now).is_err());
+    }
+}
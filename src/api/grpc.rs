@@ -1,13 +1,122 @@
 //! gRPC API implementation
 
+use crate::api::admission::AdmissionController;
+use crate::db::TemporalDB;
+use crate::distributed::compat::{
+    negotiate_cluster, Handshake, NegotiatedVersions, ProtocolVersions,
+};
+use crate::distributed::gossip::{GossipNode, NodeId};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 /// gRPC server
 pub struct GrpcServer {
+    admission: Arc<AdmissionController>,
+    db: Option<Arc<TemporalDB>>,
+    /// Cluster membership requests are routed by
+    gossip: Option<Arc<Mutex<GossipNode>>>,
+    /// Versions supported by the connected peers, by node ID
+    peers: Mutex<HashMap<String, ProtocolVersions>>,
     // TODO: Implement gRPC server using tonic
 }
 
 impl GrpcServer {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            admission: Arc::new(AdmissionController::default()),
+            db: None,
+            gossip: None,
+            peers: Mutex::default(),
+        }
+    }
+
+    /// Share an admission controller, e.g. between the gRPC and REST servers
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Database whose write formats follow the versions negotiated with
+    /// the connected peers
+    pub fn with_db(mut self, db: Arc<TemporalDB>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Route requests by the membership view of `gossip`
+    pub fn with_gossip(mut self, gossip: Arc<Mutex<GossipNode>>) -> Self {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    /// Admission controller every request must pass through
+    pub fn admission(&self) -> &Arc<AdmissionController> {
+        &self.admission
+    }
+
+    /// Node to serve a request needing `features`: the least loaded live
+    /// member supporting all of them, so during a rolling upgrade such
+    /// requests never reach a node still on an older version
+    pub fn route(&self, features: &[&str]) -> Result<NodeId> {
+        let gossip = self
+            .gossip
+            .as_ref()
+            .ok_or_else(|| Error::Configuration("No cluster membership to route by".to_string()))?;
+        let candidates = gossip
+            .lock()
+            .expect("GrpcServer poisoned gossip lock")
+            .route_candidates(features);
+        candidates.into_iter().next().ok_or_else(|| {
+            Error::Distributed(format!("No live node supports {}", features.join(", ")))
+        })
+    }
+
+    /// Answer a peer's handshake with the versions to use on the
+    /// connection; fails if the peer shares no version of some format
+    pub fn handshake(&self, hello: &Handshake) -> Result<NegotiatedVersions> {
+        Ok(ProtocolVersions::current()
+            .intersect(&hello.protocols)?
+            .newest())
+    }
+
+    /// Accept a connecting peer: negotiate the connection versions and pin
+    /// the database to the formats every connected node reads.
+    ///
+    /// A peer that would leave the cluster without a common format is
+    /// refused and not recorded.
+    pub async fn accept_peer(&self, hello: &Handshake) -> Result<NegotiatedVersions> {
+        let negotiated = self.handshake(hello)?;
+        let cluster = {
+            let mut peers = self.peers.lock().expect("GrpcServer poisoned peers lock");
+            let previous = peers.insert(hello.node_id.clone(), hello.protocols);
+            let cluster = negotiate_cluster(peers.values());
+            if cluster.is_err() {
+                match previous {
+                    Some(protocols) => peers.insert(hello.node_id.clone(), protocols),
+                    None => peers.remove(&hello.node_id),
+                };
+            }
+            cluster?
+        };
+        if let Some(db) = &self.db {
+            db.pin_formats(&cluster).await?;
+        }
+        Ok(negotiated)
+    }
+
+    /// Forget a disconnected peer, moving the database to newer formats if
+    /// the peer was the last to need older ones
+    pub async fn remove_peer(&self, node_id: &str) -> Result<NegotiatedVersions> {
+        let cluster = {
+            let mut peers = self.peers.lock().expect("GrpcServer poisoned peers lock");
+            peers.remove(node_id);
+            negotiate_cluster(peers.values())?
+        };
+        if let Some(db) = &self.db {
+            db.pin_formats(&cluster).await?;
+        }
+        Ok(cluster)
     }
 }
 
@@ -16,3 +125,66 @@ impl Default for GrpcServer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::temporal::Timestamp;
+    use crate::distributed::compat::VersionRange;
+    use crate::distributed::gossip::NodeMetadata;
+    use crate::storage::wal::{RecordCodec, WAL_VERSION, WAL_VERSION_BASELINE};
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_old_peer_pins_wal_format() {
+        let temp_dir = TempDir::new().unwrap();
+        let db = Arc::new(
+            TemporalDB::builder()
+                .with_data_dir(temp_dir.path())
+                .build()
+                .await
+                .unwrap(),
+        );
+        let server = GrpcServer::new().with_db(db.clone());
+        let wal_path = temp_dir.path().join("wal.log");
+        let wal_version = || RecordCodec::scan_file(&wal_path).unwrap().version;
+
+        let mut old = Handshake::new("old-node");
+        old.protocols.wal = VersionRange::new(WAL_VERSION_BASELINE, WAL_VERSION_BASELINE);
+        let negotiated = server.accept_peer(&old).await.unwrap();
+        assert_eq!(negotiated.wal, WAL_VERSION_BASELINE);
+        // The empty WAL drops its header at once.
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+
+        db.insert("sensor", 1, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        assert_eq!(wal_version(), Some(WAL_VERSION_BASELINE));
+
+        // The WAL keeps its format while it holds records.
+        let cluster = server.remove_peer("old-node").await.unwrap();
+        assert_eq!(cluster.wal, WAL_VERSION);
+        assert_eq!(wal_version(), Some(WAL_VERSION_BASELINE));
+
+        let mut alien = Handshake::new("alien");
+        alien.protocols.wal = VersionRange::new(WAL_VERSION + 1, WAL_VERSION + 1);
+        assert!(server.accept_peer(&alien).await.is_err());
+        assert!(server.peers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_route_by_feature() {
+        let mut peer =
+            GossipNode::new().with_metadata(NodeMetadata::new("new").with_feature("sql"));
+        let gossip = Arc::new(Mutex::new(
+            GossipNode::new().with_metadata(NodeMetadata::new("old")),
+        ));
+        gossip.lock().unwrap().merge(peer.tick());
+        let server = GrpcServer::new().with_gossip(gossip);
+
+        assert_eq!(server.route(&["sql"]).unwrap(), "new");
+        assert!(server.route(&["sql", "vector"]).is_err());
+        assert!(GrpcServer::new().route(&[]).is_err());
+    }
+}
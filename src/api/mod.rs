@@ -1,7 +1,11 @@
 //! API layer (gRPC, REST)
 
+pub mod admission;
+pub mod auth;
 pub mod grpc;
 pub mod rest;
 
+pub use admission::*;
+pub use auth::*;
 pub use grpc::*;
 pub use rest::*;
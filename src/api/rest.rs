@@ -1,13 +1,151 @@
 //! REST API implementation
 
+use crate::api::admission::{AdmissionController, RequestClass};
+use crate::api::auth::{TokenIssuer, TokenRequest, TokenResponse, TokenScope, TOKEN_PATH};
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::query::security::Principal;
+use bytes::Bytes;
+use http::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+use http::{Method, StatusCode};
+use http_body_util::{BodyExt, Full};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Request accepted by the REST service
+pub type RestRequest = http::Request<Full<Bytes>>;
+
+/// Response returned by the REST service
+pub type RestResponse = http::Response<Full<Bytes>>;
+
+/// Cross-origin policy letting browser clients on `origins` call the API.
+///
+/// Clients authenticate with bearer tokens rather than cookies, so
+/// credentialed requests are not allowed.
+pub fn browser_cors(origins: impl IntoIterator<Item = HeaderValue>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .max_age(Duration::from_secs(600))
+}
+
 /// REST server
 pub struct RestServer {
+    admission: Arc<AdmissionController>,
+    cors: CorsLayer,
+    tokens: Option<Arc<TokenIssuer>>,
     // TODO: Implement REST API
 }
 
 impl RestServer {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            admission: Arc::new(AdmissionController::default()),
+            cors: CorsLayer::new(),
+            tokens: None,
+        }
+    }
+
+    /// Share an admission controller, e.g. between the gRPC and REST servers
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Admission controller every request must pass through
+    pub fn admission(&self) -> &Arc<AdmissionController> {
+        &self.admission
+    }
+
+    /// Set the cross-origin policy, e.g. [`browser_cors`]. By default no
+    /// cross-origin access is allowed.
+    pub fn with_cors(mut self, cors: CorsLayer) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    /// Serve [`TOKEN_PATH`] and require bearer tokens issued by `tokens`
+    pub fn with_token_issuer(mut self, tokens: Arc<TokenIssuer>) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    fn token_issuer(&self) -> Result<&TokenIssuer> {
+        self.tokens
+            .as_deref()
+            .ok_or_else(|| Error::Configuration("Token exchange is not enabled".to_string()))
+    }
+
+    /// Handle `POST /auth/token`
+    pub fn issue_token(&self, request: &TokenRequest) -> Result<TokenResponse> {
+        self.token_issuer()?.issue(request, Timestamp::now())
+    }
+
+    /// Principal of a request carrying the `Authorization` header value
+    /// `authorization`; fails unless it holds a valid token granting `scope`
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        scope: TokenScope,
+    ) -> Result<Principal> {
+        let authorization =
+            authorization.ok_or_else(|| Error::Unauthorized("Missing bearer token".to_string()))?;
+        let claims = self
+            .token_issuer()?
+            .verify_bearer(authorization, Timestamp::now())?;
+        claims.require(scope)?;
+        Ok(claims.principal())
+    }
+
+    /// Route a request to its handler once the admission controller lets
+    /// it through; shed requests get `503 Service Unavailable` with a
+    /// `Retry-After` header
+    pub async fn handle(&self, request: RestRequest) -> RestResponse {
+        let (parts, body) = request.into_parts();
+        let class = match parts.method {
+            Method::GET | Method::HEAD => RequestClass::Query,
+            _ if parts.uri.path() == TOKEN_PATH => RequestClass::Query,
+            _ => RequestClass::Ingest,
+        };
+        let _permit = match self.admission.admit(class).await {
+            Ok(permit) => permit,
+            Err(e) => return error_response(&e),
+        };
+        match (&parts.method, parts.uri.path()) {
+            (&Method::POST, TOKEN_PATH) if self.tokens.is_some() => {
+                let body = match body.collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(never) => match never {},
+                };
+                let result = serde_json::from_slice::<TokenRequest>(&body)
+                    .map_err(Error::from)
+                    .and_then(|request| self.issue_token(&request));
+                match result {
+                    Ok(token) => json_response(StatusCode::OK, &token),
+                    Err(e) => error_response(&e),
+                }
+            }
+            _ => empty_response(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// The routes as a tower service behind the cross-origin policy, ready
+    /// to be mounted in an HTTP server
+    pub fn service(
+        self: Arc<Self>,
+    ) -> impl Service<RestRequest, Response = RestResponse, Error = Infallible> + Clone + Send {
+        let server = self;
+        server
+            .cors
+            .clone()
+            .layer(tower::service_fn(move |request: RestRequest| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(request).await) }
+            }))
     }
 }
 
@@ -16,3 +154,162 @@ impl Default for RestServer {
         Self::new()
     }
 }
+
+fn empty_response(status: StatusCode) -> RestResponse {
+    let mut response = RestResponse::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+fn json_response<T: serde::Serialize>(status: StatusCode, body: &T) -> RestResponse {
+    match serde_json::to_vec(body) {
+        Ok(json) => {
+            let mut response = RestResponse::new(Full::new(Bytes::from(json)));
+            *response.status_mut() = status;
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(_) => empty_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Response reporting `error`, with `{"error": <message>}` as the body
+fn error_response(error: &Error) -> RestResponse {
+    let status = match error {
+        Error::Serialization(_) => StatusCode::BAD_REQUEST,
+        Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        Error::Overloaded { .. } | Error::Stale { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    let mut response = json_response(status, &serde_json::json!({ "error": error.to_string() }));
+    if let Error::Overloaded { retry_after } = error {
+        let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::admission::AdmissionConfig;
+    use tower::ServiceExt;
+
+    const ORIGIN: &str = "https://app.example.com";
+
+    async fn send(
+        service: impl Service<RestRequest, Response = RestResponse, Error = Infallible>,
+        request: http::request::Builder,
+        body: &str,
+    ) -> (RestResponse, Bytes) {
+        let request = request
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let bytes = body.collect().await.unwrap().to_bytes();
+        (RestResponse::from_parts(parts, Full::default()), bytes)
+    }
+
+    #[tokio::test]
+    async fn test_token_exchange_and_cors() {
+        let issuer = TokenIssuer::new(&[1; 32]).unwrap().with_api_key(
+            "key",
+            Principal::new("web"),
+            vec![TokenScope::Query],
+        );
+        let server = RestServer::new()
+            .with_token_issuer(Arc::new(issuer))
+            .with_cors(browser_cors([HeaderValue::from_static(ORIGIN)]));
+        let server = Arc::new(server);
+        let service = server.clone().service();
+
+        let (response, body) = send(
+            service.clone(),
+            http::Request::post(TOKEN_PATH).header("origin", ORIGIN),
+            r#"{"api_key": "key"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            HeaderValue::from_static(ORIGIN)
+        );
+        let token: TokenResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(token.scope, vec![TokenScope::Query]);
+        let bearer = format!("Bearer {}", token.access_token);
+        let principal = server
+            .authenticate(Some(&bearer), TokenScope::Query)
+            .unwrap();
+        assert_eq!(principal.id, "web");
+        assert!(server
+            .authenticate(Some(&bearer), TokenScope::Subscribe)
+            .is_err());
+        assert!(server.authenticate(None, TokenScope::Query).is_err());
+
+        let (response, _) = send(
+            service.clone(),
+            http::Request::post(TOKEN_PATH),
+            r#"{"api_key": "wrong"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let (preflight, _) = send(
+            service.clone(),
+            http::Request::options(TOKEN_PATH)
+                .header("origin", ORIGIN)
+                .header("access-control-request-method", "POST"),
+            "",
+        )
+        .await;
+        assert_eq!(
+            preflight.headers()["access-control-allow-headers"],
+            "authorization,content-type"
+        );
+        let (response, _) = send(
+            service,
+            http::Request::post(TOKEN_PATH).header("origin", "https://evil.example"),
+            r#"{"api_key": "key"}"#,
+        )
+        .await;
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+
+        // Without an issuer there is no token route.
+        let (response, _) = send(
+            Arc::new(RestServer::new()).service(),
+            http::Request::post(TOKEN_PATH),
+            r#"{"api_key": "key"}"#,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_overloaded_requests_are_shed() {
+        let admission = Arc::new(
+            AdmissionController::new(AdmissionConfig {
+                max_concurrent_queries: 1,
+                max_query_queue: 0,
+                retry_after: Duration::from_millis(1500),
+                ..AdmissionConfig::default()
+            })
+            .unwrap(),
+        );
+        let server = Arc::new(RestServer::new().with_admission(admission.clone()));
+        let busy = admission.admit(RequestClass::Query).await.unwrap();
+
+        let (response, _) = send(server.clone().service(), http::Request::get("/"), "").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+
+        drop(busy);
+        let (response, _) = send(server.service(), http::Request::get("/"), "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(admission.stats(RequestClass::Query).rejected, 1);
+    }
+}
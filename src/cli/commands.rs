@@ -1,16 +1,34 @@
 //! CLI commands
 
+use crate::config::Config;
+use crate::core::temporal::Timestamp;
+use crate::error::Result;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 /// Temporal-DB CLI
 #[derive(Parser)]
 #[command(name = "temporal-db")]
 #[command(about = "Event-sourced temporal database")]
 pub struct Cli {
+    /// JSON config file
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    /// Settings from `--config`, or the defaults
+    pub fn load_config(&self) -> Result<Config> {
+        match &self.config {
+            Some(path) => Config::load(path),
+            None => Ok(Config::default()),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Start the database server
@@ -37,4 +55,32 @@ pub enum Commands {
         #[arg(short, long)]
         timestamp: Option<String>,
     },
+    /// Verify segment checksums and event decoding
+    Doctor {
+        /// Data directory, overriding the config file
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+        /// Rewrite damaged segments without their unreadable blocks
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Rebuild a data directory from backups and the archived WAL
+    Restore {
+        /// Backup directories, the full backup first, then its incrementals
+        #[arg(short, long = "backup", required = true)]
+        backups: Vec<PathBuf>,
+        /// Empty directory to restore into, overriding the config file
+        #[arg(short, long)]
+        data_dir: Option<PathBuf>,
+        /// Directory holding the archived WAL objects to replay
+        #[arg(long)]
+        archive: Option<PathBuf>,
+        /// Key prefix of the archived WAL objects
+        #[arg(long, default_value = "wal")]
+        archive_prefix: String,
+        /// Replay only the events recorded up to this time, e.g.
+        /// '2024-06-01T12:00' (UTC)
+        #[arg(long, requires = "archive")]
+        until: Option<Timestamp>,
+    },
 }
@@ -0,0 +1,368 @@
+//! Database configuration.
+//!
+//! [`Config`] gathers the storage and runtime settings of a [`TemporalDB`]
+//! in one validated, serializable struct, so the same settings can come
+//! from code (through [`TemporalDBBuilder`]), the CLI, or a JSON config
+//! file.
+//!
+//! [`TemporalDB`]: crate::db::TemporalDB
+
+use crate::core::event::EventOrdering;
+use crate::db::TemporalDB;
+use crate::error::{Error, Result};
+use crate::query::DEFAULT_QUERY_CACHE_ENTRIES;
+use crate::storage::{
+    HotEntityConfig, ViewSnapshotPolicy, WalCompression, WalKeyConfig, WalSyncPolicy,
+    DEFAULT_BLOCK_CACHE_BLOCKS, DEFAULT_SCAN_PARALLELISM, MIN_WAL_VERSION, WAL_VERSION,
+    WAL_VERSION_BASELINE,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where events are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalKind {
+    /// Process memory only; nothing survives a restart
+    #[default]
+    InMemory,
+    /// WAL and segment files under [`Config::data_dir`]
+    Segmented,
+}
+
+/// Storage and runtime settings of a database
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Journal implementation
+    pub journal: JournalKind,
+    /// Data directory; required for [`JournalKind::Segmented`]
+    pub data_dir: Option<PathBuf>,
+    /// When WAL appends are synced to disk
+    pub wal_sync: WalSyncPolicy,
+    /// Per-record WAL compression, if any
+    pub wal_compression: Option<WalCompression>,
+    /// WAL record encryption keys, if any; independent of how segments are
+    /// stored
+    pub wal_encryption: Option<WalKeyConfig>,
+    /// WAL format to write instead of the newest, e.g. while older nodes
+    /// are still in the cluster
+    pub wal_write_version: Option<u32>,
+    /// Flush the journal in the background at this interval
+    #[serde(with = "duration_ms")]
+    pub flush_interval: Option<Duration>,
+    /// Decoded segment blocks kept in memory
+    pub block_cache_blocks: usize,
+    /// Segments a range query reads concurrently
+    pub scan_parallelism: usize,
+    /// Hot entities whose timelines are pinned in memory
+    pub hot_entity_capacity: usize,
+    /// Query results kept for repeated queries; `0` disables the cache
+    pub query_cache_entries: usize,
+    /// Snapshot the current-state view after this many events, so restarts
+    /// replay only the events since; `0` disables view snapshots
+    pub view_snapshot_events: u64,
+    /// Events older than this may be expired by retention
+    #[serde(with = "duration_ms")]
+    pub retention: Option<Duration>,
+    /// Ordering of events sharing a timestamp
+    pub ordering: EventOrdering,
+    /// Node ID to stamp committed events with a hybrid logical clock for,
+    /// or `None` to leave them unstamped
+    pub hlc_node: Option<u64>,
+    /// Preload the block cache and the previous run's hot entities on open
+    pub warmup: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            journal: JournalKind::default(),
+            data_dir: None,
+            wal_sync: WalSyncPolicy::default(),
+            wal_compression: None,
+            wal_encryption: None,
+            wal_write_version: None,
+            flush_interval: None,
+            block_cache_blocks: DEFAULT_BLOCK_CACHE_BLOCKS,
+            scan_parallelism: DEFAULT_SCAN_PARALLELISM,
+            hot_entity_capacity: HotEntityConfig::default().capacity,
+            query_cache_entries: DEFAULT_QUERY_CACHE_ENTRIES,
+            view_snapshot_events: ViewSnapshotPolicy::default().every_events,
+            retention: None,
+            ordering: EventOrdering::default(),
+            hlc_node: None,
+            warmup: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parse a JSON config document and validate it
+    pub fn from_json(json: &str) -> Result<Self> {
+        let config: Self = serde_json::from_str(json)
+            .map_err(|e| Error::Configuration(format!("Invalid config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load and validate a JSON config file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// Check that the settings are consistent
+    pub fn validate(&self) -> Result<()> {
+        match (self.journal, &self.data_dir) {
+            (JournalKind::Segmented, None) => {
+                return Err(Error::Configuration(
+                    "A segmented journal needs a data directory".to_string(),
+                ))
+            }
+            (JournalKind::InMemory, Some(dir)) => {
+                return Err(Error::Configuration(format!(
+                    "Data directory {} given for an in-memory journal",
+                    dir.display()
+                )))
+            }
+            _ => {}
+        }
+        if self.flush_interval == Some(Duration::ZERO) {
+            return Err(Error::Configuration(
+                "Flush interval must be positive".to_string(),
+            ));
+        }
+        if self.scan_parallelism == 0 {
+            return Err(Error::Configuration(
+                "Scan parallelism must be positive".to_string(),
+            ));
+        }
+        if self.retention == Some(Duration::ZERO) {
+            return Err(Error::Configuration(
+                "Retention must be positive".to_string(),
+            ));
+        }
+        if let Some(compression) = &self.wal_compression {
+            if !zstd::compression_level_range().contains(&compression.level) {
+                return Err(Error::Configuration(format!(
+                    "Unsupported WAL compression level {}",
+                    compression.level
+                )));
+            }
+        }
+        if let Some(keys) = &self.wal_encryption {
+            keys.key_ring()?;
+        }
+        if let Some(version) = self.wal_write_version {
+            if !(MIN_WAL_VERSION..=WAL_VERSION).contains(&version) {
+                return Err(Error::Configuration(format!(
+                    "Unsupported WAL version {}",
+                    version
+                )));
+            }
+            if version == WAL_VERSION_BASELINE
+                && (self.wal_compression.is_some() || self.wal_encryption.is_some())
+            {
+                return Err(Error::Configuration(
+                    "The baseline WAL format supports no compression or encryption".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder for a [`TemporalDB`], see [`TemporalDB::builder`]
+#[derive(Debug, Clone, Default)]
+pub struct TemporalDBBuilder {
+    config: Config,
+}
+
+impl TemporalDBBuilder {
+    /// Start from default settings (an in-memory database)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from existing settings, e.g. a loaded config file
+    pub fn from_config(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Store data in `dir` using a segmented journal
+    pub fn with_data_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.config.journal = JournalKind::Segmented;
+        self.config.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Keep all data in memory
+    pub fn in_memory(mut self) -> Self {
+        self.config.journal = JournalKind::InMemory;
+        self.config.data_dir = None;
+        self
+    }
+
+    /// Set when WAL appends are synced to disk
+    pub fn with_wal_sync(mut self, policy: WalSyncPolicy) -> Self {
+        self.config.wal_sync = policy;
+        self
+    }
+
+    /// Compress large WAL records
+    pub fn with_wal_compression(mut self, compression: WalCompression) -> Self {
+        self.config.wal_compression = Some(compression);
+        self
+    }
+
+    /// Encrypt WAL records with the configured keys
+    pub fn with_wal_encryption(mut self, keys: WalKeyConfig) -> Self {
+        self.config.wal_encryption = Some(keys);
+        self
+    }
+
+    /// Write WAL records in format `version`, see
+    /// [`FileWAL::with_write_version`](crate::storage::FileWAL::with_write_version)
+    pub fn with_wal_write_version(mut self, version: u32) -> Self {
+        self.config.wal_write_version = Some(version);
+        self
+    }
+
+    /// Flush the journal in the background every `interval`
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.config.flush_interval = Some(interval);
+        self
+    }
+
+    /// Keep at most `blocks` decoded segment blocks in memory
+    pub fn with_block_cache_blocks(mut self, blocks: usize) -> Self {
+        self.config.block_cache_blocks = blocks;
+        self
+    }
+
+    /// Read up to `segments` segments concurrently in range queries
+    pub fn with_scan_parallelism(mut self, segments: usize) -> Self {
+        self.config.scan_parallelism = segments;
+        self
+    }
+
+    /// Pin the timelines of at most `entities` hot entities
+    pub fn with_hot_entity_capacity(mut self, entities: usize) -> Self {
+        self.config.hot_entity_capacity = entities;
+        self
+    }
+
+    /// Keep at most `entries` query results for repeated queries; `0`
+    /// disables the cache
+    pub fn with_query_cache_entries(mut self, entries: usize) -> Self {
+        self.config.query_cache_entries = entries;
+        self
+    }
+
+    /// Snapshot the current-state view every `events` events; `0`
+    /// disables view snapshots
+    pub fn with_view_snapshot_events(mut self, events: u64) -> Self {
+        self.config.view_snapshot_events = events;
+        self
+    }
+
+    /// Allow events older than `max_age` to be expired
+    pub fn with_retention(mut self, max_age: Duration) -> Self {
+        self.config.retention = Some(max_age);
+        self
+    }
+
+    /// Order events sharing a timestamp by `ordering`
+    pub fn with_ordering(mut self, ordering: EventOrdering) -> Self {
+        self.config.ordering = ordering;
+        self
+    }
+
+    /// Stamp committed events with a hybrid logical clock as node `node`
+    pub fn with_hlc_node(mut self, node: u64) -> Self {
+        self.config.hlc_node = Some(node);
+        self
+    }
+
+    /// Preload the block cache and the previous run's hot entities on open
+    pub fn with_warmup(mut self) -> Self {
+        self.config.warmup = true;
+        self
+    }
+
+    /// The validated settings
+    pub fn config(&self) -> Result<Config> {
+        self.config.validate()?;
+        Ok(self.config.clone())
+    }
+
+    /// Open the database
+    pub async fn build(self) -> Result<TemporalDB> {
+        TemporalDB::open_with_config(self.config()?).await
+    }
+}
+
+/// Serialize optional durations as whole milliseconds
+mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(d) => s.serialize_some(&(d.as_millis() as u64)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_millis))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_roundtrip_and_validation() {
+        let config = TemporalDBBuilder::new()
+            .with_data_dir("/var/lib/temporal-db")
+            .with_wal_sync(WalSyncPolicy::Always)
+            .with_flush_interval(Duration::from_secs(5))
+            .config()
+            .unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains("\"flush_interval\":5000"));
+        assert_eq!(Config::from_json(&json).unwrap(), config);
+
+        let partial = Config::from_json(r#"{"block_cache_blocks": 8}"#).unwrap();
+        assert_eq!(partial.block_cache_blocks, 8);
+        assert_eq!(partial.journal, JournalKind::InMemory);
+
+        assert!(Config::from_json(r#"{"journal": "segmented"}"#).is_err());
+        assert!(Config::from_json(r#"{"flush_interval": 0}"#).is_err());
+        assert!(Config::from_json(r#"{"cache": 1}"#).is_err());
+
+        let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let encrypted = format!(
+            r#"{{"wal_encryption": {{"current": 2, "keys": {{"1": "{0}", "2": "{0}"}}}}}}"#,
+            key
+        );
+        let keys = Config::from_json(&encrypted)
+            .unwrap()
+            .wal_encryption
+            .unwrap();
+        assert_eq!(keys.key_ring().unwrap().current_id(), 2);
+        assert!(
+            Config::from_json(r#"{"wal_encryption": {"current": 1, "keys": {"1": "ab"}}}"#)
+                .is_err()
+        );
+
+        assert!(Config::from_json(r#"{"wal_write_version": 1}"#).is_ok());
+        assert!(Config::from_json(r#"{"wal_write_version": 9}"#).is_err());
+        let compressed =
+            r#"{"wal_write_version": 1, "wal_compression": {"min_size": 0, "level": 3}}"#;
+        assert!(Config::from_json(compressed).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! Aggregates: entity state reduced from its events.
+//!
+//! By default an entity's value is its latest event. An [`Aggregate`]
+//! registered for an entity type instead folds all of an entity's events
+//! with a [`Reducer`], e.g. summing deposits into a balance. Folding a long
+//! history on every query is slow, so every `snapshot_every` events the
+//! reduced state is persisted as an [`AggregateSnapshot`] on an internal
+//! companion entity (`$sys:snapshot:<entity_id>`), and queries fold only
+//! the events after the latest snapshot at or before the queried time.
+//!
+//! A snapshot becomes stale when an event at or before its valid time is
+//! recorded later (a backdated insert or correction). Such writes are noted
+//! on a second companion entity (`$sys:snapshot-invalidated:<entity_id>`),
+//! and stale snapshots are skipped.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Prefix of the companion entity holding an aggregate's snapshots
+pub const SNAPSHOT_ENTITY_PREFIX: &str = "$sys:snapshot:";
+
+/// Prefix of the companion entity recording backdated writes
+pub const SNAPSHOT_INVALIDATION_ENTITY_PREFIX: &str = "$sys:snapshot-invalidated:";
+
+/// Event type of an aggregate snapshot
+pub const AGGREGATE_SNAPSHOT: &str = "aggregate.snapshot";
+
+/// Event type recorded when a backdated write makes snapshots stale
+pub const AGGREGATE_SNAPSHOT_INVALIDATED: &str = "aggregate.snapshot_invalidated";
+
+/// Type of an entity: the part of its ID before the first `:`
+pub fn entity_type(entity_id: &str) -> &str {
+    entity_id.split_once(':').map_or(entity_id, |(t, _)| t)
+}
+
+/// Folds an entity's events into its state
+pub trait Reducer: Send + Sync {
+    /// State after `event`, given the state before it (`None` initially).
+    /// Returning `None` means the entity has no value.
+    fn apply(
+        &self,
+        state: Option<serde_json::Value>,
+        event: &Event,
+    ) -> Result<Option<serde_json::Value>>;
+}
+
+impl<F> Reducer for F
+where
+    F: Fn(Option<serde_json::Value>, &Event) -> Result<Option<serde_json::Value>> + Send + Sync,
+{
+    fn apply(
+        &self,
+        state: Option<serde_json::Value>,
+        event: &Event,
+    ) -> Result<Option<serde_json::Value>> {
+        self(state, event)
+    }
+}
+
+/// Reducer and snapshot cadence of an entity type
+#[derive(Clone)]
+pub struct Aggregate {
+    reducer: Arc<dyn Reducer>,
+    snapshot_every: u64,
+}
+
+impl Aggregate {
+    /// Aggregate folding events with `reducer`, snapshotting every
+    /// `snapshot_every` events (at least 1)
+    pub fn new(reducer: impl Reducer + 'static, snapshot_every: u64) -> Self {
+        Self {
+            reducer: Arc::new(reducer),
+            snapshot_every: snapshot_every.max(1),
+        }
+    }
+
+    /// Events recorded per entity between snapshots
+    pub fn snapshot_every(&self) -> u64 {
+        self.snapshot_every
+    }
+
+    /// Fold `events`, in order, into `state`
+    pub fn fold<'a>(
+        &self,
+        state: Option<serde_json::Value>,
+        events: impl IntoIterator<Item = &'a Event>,
+    ) -> Result<Option<serde_json::Value>> {
+        events
+            .into_iter()
+            .try_fold(state, |state, event| self.reducer.apply(state, event))
+    }
+}
+
+impl fmt::Debug for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aggregate")
+            .field("snapshot_every", &self.snapshot_every)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Payload of an [`AGGREGATE_SNAPSHOT`] event, recorded at valid time
+/// `through`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggregateSnapshot {
+    /// Entity the state belongs to
+    pub entity_id: String,
+    /// Reduced state of the events at or before `through`
+    pub state: Option<serde_json::Value>,
+    /// Valid time of the latest folded event
+    pub through: Timestamp,
+}
+
+impl AggregateSnapshot {
+    /// Companion entity holding the snapshots of `entity_id`
+    pub fn entity_for(entity_id: &str) -> String {
+        format!("{}{}", SNAPSHOT_ENTITY_PREFIX, entity_id)
+    }
+
+    /// Companion entity recording backdated writes to `entity_id`
+    pub fn invalidation_entity_for(entity_id: &str) -> String {
+        format!("{}{}", SNAPSHOT_INVALIDATION_ENTITY_PREFIX, entity_id)
+    }
+
+    /// Whether a backdated write recorded by `invalidation` made the
+    /// snapshot recorded by `snapshot` stale
+    pub fn is_invalidated_by(&self, snapshot: &Event, invalidation: &Event) -> bool {
+        invalidation.timestamp() <= self.through
+            && invalidation.metadata.transaction_time >= snapshot.metadata.transaction_time
+    }
+}
+
+/// Registered aggregates and per-entity snapshot bookkeeping
+#[derive(Debug, Default)]
+pub struct AggregateRegistry {
+    /// Aggregates by entity type
+    aggregates: HashMap<String, Aggregate>,
+    /// Events recorded per entity since its last snapshot
+    since_snapshot: HashMap<String, u64>,
+    /// Valid time of each entity's latest snapshot, once looked up
+    latest_snapshot: HashMap<String, Option<Timestamp>>,
+}
+
+impl AggregateRegistry {
+    /// Empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reduce entities of `entity_type` with `aggregate`, replacing any
+    /// aggregate registered before
+    pub fn register(&mut self, entity_type: impl Into<String>, aggregate: Aggregate) {
+        self.aggregates.insert(entity_type.into(), aggregate);
+    }
+
+    /// Aggregate of the type of `entity_id`
+    pub fn get(&self, entity_id: &str) -> Option<&Aggregate> {
+        self.aggregates.get(entity_type(entity_id))
+    }
+
+    /// Count an event of `entity_id`; returns whether a snapshot is due
+    pub fn record_event(&mut self, entity_id: &str) -> bool {
+        let Some(every) = self.get(entity_id).map(Aggregate::snapshot_every) else {
+            return false;
+        };
+        let count = self
+            .since_snapshot
+            .entry(entity_id.to_string())
+            .or_default();
+        *count += 1;
+        if *count < every {
+            return false;
+        }
+        *count = 0;
+        true
+    }
+
+    /// Valid time of the latest snapshot of `entity_id`: `None` if it has
+    /// not been looked up yet, `Some(None)` if there is none
+    pub fn latest_snapshot(&self, entity_id: &str) -> Option<Option<Timestamp>> {
+        self.latest_snapshot.get(entity_id).copied()
+    }
+
+    /// Remember the valid time of the latest snapshot of `entity_id`
+    pub fn set_latest_snapshot(&mut self, entity_id: &str, through: Option<Timestamp>) {
+        self.latest_snapshot.insert(entity_id.to_string(), through);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    #[test]
+    fn test_fold_and_invalidation() {
+        let deposit = |amount: i64, secs: i64| {
+            Event::new(
+                "account.deposited".to_string(),
+                Timestamp::from_secs(secs),
+                "account:1".to_string(),
+                EventPayload::from_json(&amount).unwrap(),
+            )
+        };
+        let balance = Aggregate::new(
+            |state: Option<serde_json::Value>, event: &Event| {
+                let total = state.and_then(|s| s.as_i64()).unwrap_or(0);
+                Ok(Some((total + event.payload().to_json::<i64>()?).into()))
+            },
+            0,
+        );
+        assert_eq!(balance.snapshot_every(), 1);
+        assert_eq!(entity_type("account:1"), "account");
+        assert_eq!(entity_type("plain"), "plain");
+
+        let events = [deposit(5, 10), deposit(7, 20)];
+        let state = balance.fold(None, &events).unwrap();
+        assert_eq!(state, Some(12.into()));
+        assert_eq!(
+            balance.fold(state, &[deposit(1, 30)]).unwrap(),
+            Some(13.into())
+        );
+
+        let snapshot = AggregateSnapshot {
+            entity_id: "account:1".to_string(),
+            state: Some(12.into()),
+            through: Timestamp::from_secs(20),
+        };
+        let recorded = Event::new(
+            AGGREGATE_SNAPSHOT.to_string(),
+            snapshot.through,
+            AggregateSnapshot::entity_for("account:1"),
+            EventPayload::from_json(&snapshot).unwrap(),
+        );
+        let mut backdated = deposit(1, 15);
+        backdated.metadata.transaction_time = recorded.metadata.transaction_time;
+        assert!(snapshot.is_invalidated_by(&recorded, &backdated));
+        assert!(!snapshot.is_invalidated_by(&recorded, &deposit(1, 25)));
+    }
+}
@@ -1,15 +1,42 @@
 //! Event types for event sourcing
 
+use crate::core::hlc::HlcTimestamp;
+use crate::core::numeric::{Number, NumericPayload, NUMERIC_FORMAT};
+use crate::core::schema::SchemaId;
 use crate::core::temporal::Timestamp;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::fmt;
 use uuid::Uuid;
 
 /// Event type identifier
 pub type EventType = String;
 
+/// Tag prefix under which [`EventMetadata::valid_to`] is stored, followed
+/// by the end of the valid range in nanoseconds. Keeping it in the stored
+/// tags leaves the WAL and segment layout of events unchanged; it never
+/// appears in [`EventMetadata::tags`].
+pub const VALID_TO_TAG_PREFIX: &str = "valid_to:";
+
+/// Tag prefix under which [`EventMetadata::schema`] is stored, followed by
+/// the schema ID; like [`VALID_TO_TAG_PREFIX`], it never appears in
+/// [`EventMetadata::tags`].
+pub const SCHEMA_TAG_PREFIX: &str = "schema:";
+
+/// Tag prefix under which [`EventMetadata::hlc`] is stored, followed by the
+/// stamp as `<nanos>.<logical>.<node>`
+pub const HLC_TAG_PREFIX: &str = "hlc:";
+
+/// Tag marking an event that corrects a previously recorded value
+pub const CORRECTION_TAG: &str = "correction";
+
+/// Event type of a tombstone, which ends an entity's value
+pub const ENTITY_DELETED: &str = "entity.deleted";
+
 /// Unique event identifier
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct EventId {
     /// UUID of the event
     pub id: Uuid,
@@ -40,7 +67,8 @@ impl fmt::Display for EventId {
 }
 
 /// Event metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "StoredMetadata")]
 pub struct EventMetadata {
     /// Event ID
     pub id: EventId,
@@ -60,6 +88,12 @@ pub struct EventMetadata {
     pub actor: Option<String>,
     /// Additional tags for filtering/indexing
     pub tags: Vec<String>,
+    /// End of the event's valid range (exclusive), if it has one
+    pub valid_to: Option<Timestamp>,
+    /// Schema the payload was checked against, if its entity type has one
+    pub schema: Option<SchemaId>,
+    /// Hybrid logical clock stamp of the write, if the writer keeps a clock
+    pub hlc: Option<HlcTimestamp>,
 }
 
 impl EventMetadata {
@@ -79,6 +113,9 @@ impl EventMetadata {
             causation_id: None,
             actor: None,
             tags: Vec::new(),
+            valid_to: None,
+            schema: None,
+            hlc: None,
         }
     }
 
@@ -111,6 +148,113 @@ impl EventMetadata {
         self.tags.extend(tags);
         self
     }
+
+    /// End the event's valid range at `to` (exclusive)
+    pub fn with_valid_to(mut self, to: Timestamp) -> Self {
+        self.valid_to = Some(to);
+        self
+    }
+
+    /// Record the schema the payload was checked against
+    pub fn with_schema(mut self, schema: SchemaId) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Stamp the write with a hybrid logical clock
+    pub fn with_hlc(mut self, stamp: HlcTimestamp) -> Self {
+        self.hlc = Some(stamp);
+        self
+    }
+}
+
+/// Serialized layout of [`EventMetadata`], with the valid range end, the
+/// schema and the clock stamp stored as [`VALID_TO_TAG_PREFIX`],
+/// [`SCHEMA_TAG_PREFIX`] and [`HLC_TAG_PREFIX`] tags
+#[derive(Deserialize)]
+struct StoredMetadata {
+    id: EventId,
+    event_type: EventType,
+    timestamp: Timestamp,
+    transaction_time: Timestamp,
+    entity_id: String,
+    correlation_id: Option<String>,
+    causation_id: Option<EventId>,
+    actor: Option<String>,
+    tags: Vec<String>,
+}
+
+impl From<StoredMetadata> for EventMetadata {
+    fn from(stored: StoredMetadata) -> Self {
+        let mut valid_to = None;
+        let mut schema = None;
+        let mut hlc = None;
+        let mut tags = stored.tags;
+        tags.retain(|tag| {
+            let nanos = tag
+                .strip_prefix(VALID_TO_TAG_PREFIX)
+                .and_then(|n| n.parse().ok());
+            valid_to = valid_to.or(nanos.map(Timestamp::from_nanos));
+            let id = tag
+                .strip_prefix(SCHEMA_TAG_PREFIX)
+                .and_then(|id| id.parse::<SchemaId>().ok());
+            let stamp = tag
+                .strip_prefix(HLC_TAG_PREFIX)
+                .and_then(|stamp| stamp.parse::<HlcTimestamp>().ok());
+            let is_typed = nanos.is_some() || id.is_some() || stamp.is_some();
+            schema = schema.take().or(id);
+            hlc = hlc.or(stamp);
+            !is_typed
+        });
+        Self {
+            id: stored.id,
+            event_type: stored.event_type,
+            timestamp: stored.timestamp,
+            transaction_time: stored.transaction_time,
+            entity_id: stored.entity_id,
+            correlation_id: stored.correlation_id,
+            causation_id: stored.causation_id,
+            actor: stored.actor,
+            tags,
+            valid_to,
+            schema,
+            hlc,
+        }
+    }
+}
+
+impl Serialize for EventMetadata {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let valid_to = self
+            .valid_to
+            .map(|to| format!("{}{}", VALID_TO_TAG_PREFIX, to.as_nanos()));
+        let schema = self
+            .schema
+            .as_ref()
+            .map(|id| format!("{}{}", SCHEMA_TAG_PREFIX, id));
+        let hlc = self.hlc.map(|stamp| format!("{}{}", HLC_TAG_PREFIX, stamp));
+        let tags: Vec<&str> = self
+            .tags
+            .iter()
+            .map(String::as_str)
+            .chain(valid_to.as_deref())
+            .chain(schema.as_deref())
+            .chain(hlc.as_deref())
+            .collect();
+        let mut state = serializer.serialize_struct("EventMetadata", 9)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("event_type", &self.event_type)?;
+        state.serialize_field("timestamp", &self.timestamp)?;
+        state.serialize_field("transaction_time", &self.transaction_time)?;
+        state.serialize_field("entity_id", &self.entity_id)?;
+        state.serialize_field("correlation_id", &self.correlation_id)?;
+        state.serialize_field("causation_id", &self.causation_id)?;
+        state.serialize_field("actor", &self.actor)?;
+        state.serialize_field("tags", &tags)?;
+        state.end()
+    }
 }
 
 /// Event payload (serialized data)
@@ -137,9 +281,46 @@ impl EventPayload {
         })
     }
 
-    /// Deserialize from JSON
+    /// Deserialize from JSON. Numeric payloads read as their plain value.
     pub fn to_json<T: for<'de> Deserialize<'de>>(&self) -> Result<T, serde_json::Error> {
-        serde_json::from_slice(&self.data)
+        serde_json::from_slice(&self.json_data()?)
+    }
+
+    /// The payload as JSON bytes, converting numeric payloads to their value
+    pub fn json_data(&self) -> Result<Cow<'_, [u8]>, serde_json::Error> {
+        if self.format != NUMERIC_FORMAT {
+            return Ok(Cow::Borrowed(&self.data));
+        }
+        let numeric = self.to_numeric().map_err(serde::de::Error::custom)?;
+        let data = match numeric.value {
+            Number::Int(i) => serde_json::to_vec(&i)?,
+            Number::Float(f) => serde_json::to_vec(&f)?,
+        };
+        Ok(Cow::Owned(data))
+    }
+
+    /// Create a numeric time-series payload
+    pub fn from_numeric(value: &NumericPayload) -> Self {
+        Self {
+            data: bincode::serialize(value).expect("numeric payloads always serialize"),
+            format: NUMERIC_FORMAT.to_string(),
+        }
+    }
+
+    /// Decode a numeric payload
+    pub fn to_numeric(&self) -> Result<NumericPayload, bincode::Error> {
+        if self.format != NUMERIC_FORMAT {
+            return Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "Expected a numeric payload, found format '{}'",
+                self.format
+            ))));
+        }
+        bincode::deserialize(&self.data)
+    }
+
+    /// Whether this is a numeric time-series payload
+    pub fn is_numeric(&self) -> bool {
+        self.format == NUMERIC_FORMAT
     }
 
     /// Create payload from bincode-serialized data
@@ -217,6 +398,27 @@ impl Event {
     pub fn payload(&self) -> &EventPayload {
         &self.payload
     }
+
+    /// End of the event's valid range (exclusive), if it has one
+    pub fn valid_to(&self) -> Option<Timestamp> {
+        self.metadata.valid_to
+    }
+
+    /// Hybrid logical clock stamp of the write, if it has one
+    pub fn hlc(&self) -> Option<HlcTimestamp> {
+        self.metadata.hlc
+    }
+
+    /// Whether the event is a tombstone, see [`ENTITY_DELETED`]
+    pub fn is_tombstone(&self) -> bool {
+        self.event_type() == ENTITY_DELETED
+    }
+
+    /// Whether the event's value is in effect at `at`; tombstones carry no
+    /// value and never are
+    pub fn is_valid_at(&self, at: Timestamp) -> bool {
+        !self.is_tombstone() && self.timestamp() <= at && self.valid_to().is_none_or(|to| at < to)
+    }
 }
 
 /// Builder for events
@@ -256,6 +458,18 @@ impl EventBuilder {
         self
     }
 
+    /// End the event's valid range at `to` (exclusive)
+    pub fn valid_to(mut self, to: Timestamp) -> Self {
+        self.metadata = self.metadata.with_valid_to(to);
+        self
+    }
+
+    /// Stamp the write with a hybrid logical clock
+    pub fn hlc(mut self, stamp: HlcTimestamp) -> Self {
+        self.metadata = self.metadata.with_hlc(stamp);
+        self
+    }
+
     /// Build the event
     pub fn build(self) -> Event {
         Event {
@@ -265,6 +479,105 @@ impl EventBuilder {
     }
 }
 
+/// How events sharing a valid-time timestamp are ordered.
+///
+/// Events are always ordered by [`Event::timestamp`] first; the policy only
+/// decides ties, and with it which event AS OF lookups treat as the latest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EventOrdering {
+    /// Break ties by transaction time, then by event ID.
+    ///
+    /// The result depends only on the events themselves, so every replica
+    /// and every rebuild orders them the same way.
+    #[default]
+    TransactionTime,
+    /// Keep tied events in the order they were appended.
+    ///
+    /// Deterministic only as long as events are always appended in the same
+    /// order, e.g. on a single node replaying its own log.
+    AppendOrder,
+    /// Break ties by [`Event::hlc`], then like
+    /// [`EventOrdering::TransactionTime`].
+    ///
+    /// Unlike transaction times, clock stamps of writes from different
+    /// nodes respect causality under clock skew. Events without a stamp
+    /// order before stamped ones.
+    HybridClock,
+}
+
+impl EventOrdering {
+    /// Compare two events under this policy.
+    ///
+    /// Under [`EventOrdering::AppendOrder`] tied events compare equal, so a
+    /// stable sort keeps them in arrival order.
+    pub fn compare(self, a: &Event, b: &Event) -> Ordering {
+        let by_time = a.timestamp().cmp(&b.timestamp());
+        match self {
+            Self::TransactionTime => by_time
+                .then_with(|| {
+                    a.metadata
+                        .transaction_time
+                        .cmp(&b.metadata.transaction_time)
+                })
+                .then_with(|| a.id().cmp(&b.id())),
+            Self::AppendOrder => by_time,
+            Self::HybridClock => by_time
+                .then_with(|| a.hlc().cmp(&b.hlc()))
+                .then_with(|| Self::TransactionTime.compare(a, b)),
+        }
+    }
+
+    /// Sort events in place under this policy (stable)
+    pub fn sort(self, events: &mut [Event]) {
+        events.sort_by(|a, b| self.compare(a, b));
+    }
+
+    /// The last of `events` under this policy, e.g. the AS OF answer among
+    /// candidates
+    pub fn latest<I: IntoIterator<Item = Event>>(self, events: I) -> Option<Event> {
+        // `max_by` returns the last of equally maximal elements.
+        events.into_iter().max_by(|a, b| self.compare(a, b))
+    }
+}
+
+/// Order events so that every event comes after the event that caused it.
+///
+/// Events whose cause is not in `events` are treated as roots. Among events
+/// whose causes have all been emitted, the one recorded first (by
+/// transaction time, then valid time) goes next, so unrelated branches
+/// interleave in recording order.
+pub fn causal_order(events: Vec<Event>) -> Vec<Event> {
+    let index_of: HashMap<EventId, usize> = events
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.id(), i))
+        .collect();
+
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); events.len()];
+    let mut ready = BinaryHeap::new();
+    let key = |i: usize| {
+        let m = &events[i].metadata;
+        Reverse((m.transaction_time, m.timestamp, i))
+    };
+    for (i, event) in events.iter().enumerate() {
+        match event.metadata.causation_id.and_then(|c| index_of.get(&c)) {
+            Some(&parent) if parent != i => children[parent].push(i),
+            _ => ready.push(key(i)),
+        }
+    }
+
+    let mut order = Vec::with_capacity(events.len());
+    while let Some(Reverse((_, _, i))) = ready.pop() {
+        order.push(i);
+        for &child in &children[i] {
+            ready.push(key(child));
+        }
+    }
+
+    let mut slots: Vec<Option<Event>> = events.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| slots[i].take()).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -293,4 +606,111 @@ mod tests {
         assert_eq!(event.metadata.actor, Some("user:123".to_string()));
         assert!(event.metadata.tags.contains(&"important".to_string()));
     }
+
+    #[test]
+    fn test_valid_to_keeps_stored_layout() {
+        let payload = EventPayload::from_json(&serde_json::json!(1)).unwrap();
+        let to = Timestamp::from_secs(2000);
+        let event = Event::builder(
+            "test.event".to_string(),
+            Timestamp::from_secs(1000),
+            "entity:1".to_string(),
+            payload,
+        )
+        .tag("important".to_string())
+        .valid_to(to)
+        .build();
+        assert_eq!(event.metadata.tags, vec!["important".to_string()]);
+
+        // Stored as the tag earlier releases wrote, and read back typed.
+        let mut legacy = event.metadata.clone();
+        legacy.valid_to = None;
+        legacy
+            .tags
+            .push(format!("{}{}", VALID_TO_TAG_PREFIX, to.as_nanos()));
+        let stored = bincode::serialize(&event).unwrap();
+        assert_eq!(
+            bincode::serialize(&event.metadata).unwrap(),
+            bincode::serialize(&legacy).unwrap()
+        );
+        let decoded: Event = bincode::deserialize(&stored).unwrap();
+        assert_eq!(decoded.valid_to(), Some(to));
+        assert_eq!(decoded.metadata.tags, vec!["important".to_string()]);
+
+        let schema = SchemaId::new("entity", 2);
+        let typed = event.metadata.clone().with_schema(schema.clone());
+        let decoded: EventMetadata =
+            bincode::deserialize(&bincode::serialize(&typed).unwrap()).unwrap();
+        assert_eq!(decoded.schema, Some(schema));
+        assert_eq!(decoded.tags, vec!["important".to_string()]);
+    }
+
+    #[test]
+    fn test_causal_order() {
+        let make = |secs: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!(secs)).unwrap();
+            Event::new("test.event".to_string(), Timestamp::from_secs(secs), "e".to_string(), payload)
+        };
+        let root = make(1);
+        let mut child = make(2);
+        child.metadata.causation_id = Some(root.id());
+        let mut grandchild = make(3);
+        grandchild.metadata.causation_id = Some(child.id());
+        // Recorded before its cause, but must still come after it.
+        grandchild.metadata.transaction_time = Timestamp::from_secs(0);
+
+        let ids: Vec<EventId> = causal_order(vec![grandchild.clone(), child.clone(), root.clone()])
+            .iter()
+            .map(|e| e.id())
+            .collect();
+        assert_eq!(ids, vec![root.id(), child.id(), grandchild.id()]);
+    }
+
+    #[test]
+    fn test_event_ordering_tiebreak() {
+        let make = |tx_secs: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!(tx_secs)).unwrap();
+            let mut event = Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(10),
+                "e".to_string(),
+                payload,
+            );
+            event.metadata.transaction_time = Timestamp::from_secs(tx_secs);
+            event
+        };
+        let (later, earlier) = (make(200), make(100));
+
+        // Appended out of transaction-time order.
+        let events = vec![later.clone(), earlier.clone()];
+        let latest = EventOrdering::TransactionTime.latest(events.clone()).unwrap();
+        assert_eq!(latest.id(), later.id());
+        let latest = EventOrdering::AppendOrder.latest(events.clone()).unwrap();
+        assert_eq!(latest.id(), earlier.id());
+
+        let mut sorted = events;
+        EventOrdering::TransactionTime.sort(&mut sorted);
+        assert_eq!(sorted[0].id(), earlier.id());
+
+        // Identical transaction times fall back to the event ID.
+        let (mut a, mut b) = (make(100), make(100));
+        if a.id() > b.id() {
+            std::mem::swap(&mut a, &mut b);
+        }
+        assert_eq!(EventOrdering::TransactionTime.compare(&a, &b), Ordering::Less);
+
+        // A clock stamp orders writes whose transaction times disagree,
+        // and survives storage.
+        let stamp = |logical| HlcTimestamp::new(Timestamp::from_secs(10), logical, 1);
+        let (mut cause, mut effect) = (later, earlier);
+        cause.metadata = cause.metadata.with_hlc(stamp(0));
+        effect.metadata = effect.metadata.with_hlc(stamp(1));
+        let latest = EventOrdering::HybridClock
+            .latest(vec![effect.clone(), cause.clone()])
+            .unwrap();
+        assert_eq!(latest.id(), effect.id());
+        let decoded: Event = bincode::deserialize(&bincode::serialize(&effect).unwrap()).unwrap();
+        assert_eq!(decoded.hlc(), Some(stamp(1)));
+        assert!(decoded.metadata.tags.is_empty());
+    }
 }
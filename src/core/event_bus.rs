@@ -0,0 +1,251 @@
+//! In-process delivery of committed events to registered handlers.
+//!
+//! An [`EventBus`] hands every committed event to the [`EventHandler`]s
+//! whose [`SubscriptionFilter`] matches it. Each handler has its own queue
+//! and task, so a slow handler holds back only itself. Events reach a
+//! handler in commit order, hence in order per entity, and a handler that
+//! fails is retried with backoff until it succeeds: delivery is
+//! at-least-once while the process runs. Queues live in memory; consumers
+//! that must resume after a restart should also keep a
+//! [`ProjectionCheckpoint`](crate::db::ProjectionCheckpoint).
+
+use crate::core::event::Event;
+use crate::core::subscription::SubscriptionFilter;
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// Identifier of a registered handler
+pub type HandlerId = u64;
+
+/// Delay before retrying a failed handler; it doubles on every failure
+pub const HANDLER_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Longest delay between retries of a failed handler
+pub const HANDLER_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Code run for committed events
+#[async_trait::async_trait]
+pub trait EventHandler: Send + Sync + 'static {
+    /// Handle `event`; an error makes the bus retry it
+    async fn handle(&self, event: &Event) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<F> EventHandler for F
+where
+    F: Fn(&Event) -> Result<()> + Send + Sync + 'static,
+{
+    async fn handle(&self, event: &Event) -> Result<()> {
+        self(event)
+    }
+}
+
+/// Handler receiving JSON payloads decoded as `T`.
+///
+/// Events whose payload does not decode as `T` are skipped with a warning,
+/// since retrying them cannot succeed.
+pub struct TypedHandler<T, F> {
+    handler: F,
+    _payload: PhantomData<fn(T)>,
+}
+
+impl<T, F> TypedHandler<T, F>
+where
+    T: DeserializeOwned + Send + 'static,
+    F: Fn(&Event, T) -> Result<()> + Send + Sync + 'static,
+{
+    /// Wrap `handler`, which gets each event with its decoded payload
+    pub fn new(handler: F) -> Self {
+        Self {
+            handler,
+            _payload: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T, F> EventHandler for TypedHandler<T, F>
+where
+    T: DeserializeOwned + Send + 'static,
+    F: Fn(&Event, T) -> Result<()> + Send + Sync + 'static,
+{
+    async fn handle(&self, event: &Event) -> Result<()> {
+        match event.payload().to_json::<T>() {
+            Ok(payload) => (self.handler)(event, payload),
+            Err(e) => {
+                tracing::warn!(
+                    "Skipping event {} of {}: payload does not decode: {}",
+                    event.id(),
+                    event.entity_id(),
+                    e
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Registration {
+    filter: SubscriptionFilter,
+    queue: mpsc::UnboundedSender<Event>,
+}
+
+/// Delivers committed events to in-process handlers
+pub struct EventBus {
+    handlers: Mutex<HashMap<HandlerId, Registration>>,
+    next_id: Mutex<HandlerId>,
+    /// Events queued or being handled, over all handlers
+    pending: Arc<watch::Sender<usize>>,
+}
+
+impl EventBus {
+    /// Bus without handlers
+    pub fn new() -> Self {
+        Self {
+            handlers: Mutex::new(HashMap::new()),
+            next_id: Mutex::new(1),
+            pending: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    /// Deliver the events `filter` matches to `handler` from now on.
+    ///
+    /// Spawns the handler's task, so it must be called within a Tokio
+    /// runtime.
+    pub fn register<H: EventHandler>(&self, filter: SubscriptionFilter, handler: H) -> HandlerId {
+        let (queue, mut events) = mpsc::unbounded_channel::<Event>();
+        let pending = self.pending.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let mut delay = HANDLER_RETRY_DELAY;
+                while let Err(e) = handler.handle(&event).await {
+                    tracing::warn!(
+                        "Event handler failed on event {}, retrying in {:?}: {}",
+                        event.id(),
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(HANDLER_MAX_RETRY_DELAY);
+                }
+                pending.send_modify(|n| *n -= 1);
+            }
+        });
+
+        let mut next_id = self.next_id.lock().expect("EventBus poisoned lock");
+        let id = *next_id;
+        *next_id += 1;
+        self.handlers
+            .lock()
+            .expect("EventBus poisoned lock")
+            .insert(id, Registration { filter, queue });
+        id
+    }
+
+    /// Stop delivering to a handler; events already queued for it are still
+    /// handled. Returns whether it was registered.
+    pub fn unregister(&self, id: HandlerId) -> bool {
+        self.handlers
+            .lock()
+            .expect("EventBus poisoned lock")
+            .remove(&id)
+            .is_some()
+    }
+
+    /// Number of registered handlers
+    pub fn handler_count(&self) -> usize {
+        self.handlers.lock().expect("EventBus poisoned lock").len()
+    }
+
+    /// Queue `event` for every handler whose filter matches it
+    pub fn publish(&self, event: &Event) {
+        let handlers = self.handlers.lock().expect("EventBus poisoned lock");
+        for registration in handlers.values() {
+            if !registration.filter.matches(event) {
+                continue;
+            }
+            self.pending.send_modify(|n| *n += 1);
+            if registration.queue.send(event.clone()).is_err() {
+                // The handler's task is gone, e.g. because it panicked.
+                self.pending.send_modify(|n| *n -= 1);
+            }
+        }
+    }
+
+    /// Wait until every event published so far has been handled
+    pub async fn idle(&self) {
+        let mut pending = self.pending.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = pending.wait_for(|n| *n == 0).await;
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventBus")
+            .field("handlers", &self.handler_count())
+            .field("pending", &*self.pending.borrow())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use crate::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn event(entity_id: &str, value: i64) -> Event {
+        Event::new(
+            "value.changed".to_string(),
+            Timestamp::from_secs(value),
+            entity_id.to_string(),
+            EventPayload::from_json(&value).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_handlers_retry_in_order() {
+        let bus = EventBus::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let (log, failed) = (seen.clone(), failures.clone());
+        let id = bus.register(
+            SubscriptionFilter::new().with_entity_prefix("order:"),
+            TypedHandler::new(move |_: &Event, value: i64| {
+                // Fail the second event once.
+                if value == 2 && failed.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(Error::Other("transient".to_string()));
+                }
+                log.lock().unwrap().push(value);
+                Ok(())
+            }),
+        );
+
+        for value in 1..=3 {
+            bus.publish(&event("order:1", value));
+        }
+        bus.publish(&event("user:1", 4));
+        bus.idle().await;
+        assert_eq!(*seen.lock().unwrap(), [1, 2, 3]);
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+
+        assert!(bus.unregister(id));
+        bus.publish(&event("order:1", 5));
+        bus.idle().await;
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,122 @@
+//! Filters on event metadata.
+//!
+//! An [`EventFilter`] selects events by who caused them, their tags and
+//! their place in a causal chain. Journal and database range queries accept
+//! one, so audit questions such as "what did this actor change last week"
+//! are answered without scanning in user code.
+
+use crate::core::event::{Event, EventId};
+use serde::{Deserialize, Serialize};
+
+/// Metadata criteria of a query; unset criteria match everything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventFilter {
+    /// Only events caused by this actor
+    pub actor: Option<String>,
+    /// Only events carrying at least one of these tags
+    pub any_tags: Vec<String>,
+    /// Only events carrying all of these tags
+    pub all_tags: Vec<String>,
+    /// Only events stamped with this correlation ID
+    pub correlation_id: Option<String>,
+    /// Only events caused by this event
+    pub causation_id: Option<EventId>,
+}
+
+impl EventFilter {
+    /// Filter matching every event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match events caused by `actor`
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    /// Also accept events tagged `tag`
+    pub fn with_any_tag(mut self, tag: impl Into<String>) -> Self {
+        self.any_tags.push(tag.into());
+        self
+    }
+
+    /// Require events to be tagged `tag`
+    pub fn with_required_tag(mut self, tag: impl Into<String>) -> Self {
+        self.all_tags.push(tag.into());
+        self
+    }
+
+    /// Only match events stamped with `correlation_id`
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Only match events caused by the event `causation_id`
+    pub fn with_causation_id(mut self, causation_id: EventId) -> Self {
+        self.causation_id = Some(causation_id);
+        self
+    }
+
+    /// Whether the filter matches every event
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `event` passes the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        let metadata = &event.metadata;
+        let tagged = |tag: &String| metadata.tags.contains(tag);
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| metadata.actor.as_ref() == Some(actor))
+            && self
+                .correlation_id
+                .as_ref()
+                .is_none_or(|id| metadata.correlation_id.as_ref() == Some(id))
+            && self
+                .causation_id
+                .is_none_or(|id| metadata.causation_id == Some(id))
+            && (self.any_tags.is_empty() || self.any_tags.iter().any(tagged))
+            && self.all_tags.iter().all(tagged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+
+    #[test]
+    fn test_filter_matches_metadata() {
+        let cause = EventId::new();
+        let event = Event::builder(
+            "order.updated".to_string(),
+            Timestamp::from_secs(1000),
+            "order:1".to_string(),
+            EventPayload::from_json(&1).unwrap(),
+        )
+        .actor("alice".to_string())
+        .tags(vec!["billing".to_string(), "manual".to_string()])
+        .correlation_id("checkout-7".to_string())
+        .causation_id(cause)
+        .build();
+
+        assert!(EventFilter::new().matches(&event));
+        let audit = EventFilter::new()
+            .with_actor("alice")
+            .with_any_tag("refund")
+            .with_any_tag("billing")
+            .with_required_tag("manual")
+            .with_correlation_id("checkout-7")
+            .with_causation_id(cause);
+        assert!(audit.matches(&event));
+        assert!(!audit.clone().with_required_tag("refund").matches(&event));
+        assert!(!EventFilter::new().with_actor("bob").matches(&event));
+        assert!(!EventFilter::new()
+            .with_causation_id(EventId::new())
+            .matches(&event));
+    }
+}
@@ -0,0 +1,148 @@
+//! Parent-child relationships between entities.
+//!
+//! An entity has at most one parent at a time. Parent changes are ordinary
+//! events recorded on an internal companion entity
+//! (`$sys:relationship:<child_id>`), so the hierarchy at any point in time
+//! can be reconstructed. The
+//! [`HierarchyIndex`] keeps those changes per child and the children ever
+//! linked to each parent, so "children of `order:42` as of `t`" only looks
+//! at that parent's children.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// Prefix of the companion entity holding an entity's parent changes
+pub const RELATIONSHIP_ENTITY_PREFIX: &str = "$sys:relationship:";
+
+/// Event type recorded when an entity's parent is set or cleared
+pub const PARENT_CHANGED: &str = "relationship.parent_changed";
+
+/// Payload of a [`PARENT_CHANGED`] event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParentLink {
+    /// Child entity
+    pub child: String,
+    /// New parent; `None` detaches the child
+    pub parent: Option<String>,
+}
+
+impl ParentLink {
+    /// Companion entity ID under which parent changes of `child` are recorded
+    pub fn entity_for(child: &str) -> String {
+        format!("{}{}", RELATIONSHIP_ENTITY_PREFIX, child)
+    }
+}
+
+/// Index over relationship events
+#[derive(Debug, Clone, Default)]
+pub struct HierarchyIndex {
+    /// Parent changes per child, in timestamp order
+    links: HashMap<String, Vec<(Timestamp, Option<String>)>>,
+    /// Children ever linked to each parent
+    children: HashMap<String, BTreeSet<String>>,
+}
+
+impl HierarchyIndex {
+    /// Empty index
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index `event` if it is a [`PARENT_CHANGED`] event. Of several
+    /// changes at one timestamp the last applied wins.
+    pub fn apply_event(&mut self, event: &Event) {
+        if event.event_type() != PARENT_CHANGED {
+            return;
+        }
+        let Ok(link) = event.payload().to_json::<ParentLink>() else {
+            tracing::warn!("Ignoring malformed relationship event {}", event.id());
+            return;
+        };
+        if let Some(parent) = &link.parent {
+            self.children
+                .entry(parent.clone())
+                .or_default()
+                .insert(link.child.clone());
+        }
+        let changes = self.links.entry(link.child).or_default();
+        let at = event.timestamp();
+        let position = changes.partition_point(|(t, _)| *t <= at);
+        changes.insert(position, (at, link.parent));
+    }
+
+    /// Parent of `child` at `at`
+    pub fn parent_as_of(&self, child: &str, at: Timestamp) -> Option<&str> {
+        let changes = self.links.get(child)?;
+        let position = changes.partition_point(|(t, _)| *t <= at);
+        changes[..position].last()?.1.as_deref()
+    }
+
+    /// Children of `parent` at `at`, in entity order
+    pub fn children_as_of(&self, parent: &str, at: Timestamp) -> Vec<String> {
+        self.children
+            .get(parent)
+            .into_iter()
+            .flatten()
+            .filter(|child| self.parent_as_of(child, at) == Some(parent))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `ancestor` is `entity` or one of its ancestors at `at`
+    pub fn is_ancestor_as_of(&self, ancestor: &str, entity: &str, at: Timestamp) -> bool {
+        let mut current = Some(entity);
+        // A well-formed hierarchy is acyclic; the bound guards against
+        // cycles recorded by other writers.
+        for _ in 0..=self.links.len() {
+            match current {
+                Some(id) if id == ancestor => return true,
+                Some(id) => current = self.parent_as_of(id, at),
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn link(child: &str, parent: Option<&str>, secs: i64) -> Event {
+        let link = ParentLink {
+            child: child.to_string(),
+            parent: parent.map(str::to_string),
+        };
+        Event::new(
+            PARENT_CHANGED.to_string(),
+            Timestamp::from_secs(secs),
+            ParentLink::entity_for(child),
+            EventPayload::from_json(&link).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_children_as_of() {
+        let mut index = HierarchyIndex::new();
+        index.apply_event(&link("item:2", Some("order:42"), 200));
+        index.apply_event(&link("item:1", Some("order:42"), 100));
+        index.apply_event(&link("item:1", Some("order:43"), 300));
+        index.apply_event(&link("item:2", None, 400));
+
+        let secs = Timestamp::from_secs;
+        assert!(index.children_as_of("order:42", secs(50)).is_empty());
+        assert_eq!(
+            index.children_as_of("order:42", secs(250)),
+            vec!["item:1", "item:2"]
+        );
+        assert_eq!(index.children_as_of("order:42", secs(300)), vec!["item:2"]);
+        assert_eq!(index.children_as_of("order:43", secs(300)), vec!["item:1"]);
+        assert!(index.children_as_of("order:42", secs(400)).is_empty());
+        assert_eq!(index.parent_as_of("item:1", secs(150)), Some("order:42"));
+        assert!(index.is_ancestor_as_of("order:43", "item:1", secs(300)));
+        assert!(!index.is_ancestor_as_of("order:42", "item:1", secs(300)));
+    }
+}
@@ -0,0 +1,187 @@
+//! Hybrid logical clocks.
+//!
+//! Wall-clock timestamps of writes made on different nodes may tie or,
+//! under clock skew, run backwards. A [`HybridClock`] issues
+//! [`HlcTimestamp`]s that stay close to wall-clock time but never repeat or
+//! go backwards on a node, and move past every remote stamp the node has
+//! observed, so causally later writes always compare greater. The node ID
+//! breaks the remaining ties, giving every replica the same total order.
+
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Default limit on how far ahead of the local clock an observed stamp may
+/// be
+pub const DEFAULT_MAX_CLOCK_OFFSET: Duration = Duration::from_millis(500);
+
+/// Point on a hybrid logical clock; ordered by physical time, then logical
+/// counter, then node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct HlcTimestamp {
+    /// Wall-clock time, or the latest one observed if it was ahead
+    pub physical: Timestamp,
+    /// Counter ordering stamps sharing a physical time
+    pub logical: u32,
+    /// Node that issued the stamp
+    pub node: u64,
+}
+
+impl HlcTimestamp {
+    pub fn new(physical: Timestamp, logical: u32, node: u64) -> Self {
+        Self {
+            physical,
+            logical,
+            node,
+        }
+    }
+}
+
+impl From<Timestamp> for HlcTimestamp {
+    /// Stamp of a plain timestamp, ordered before every clock stamp at the
+    /// same physical time
+    fn from(physical: Timestamp) -> Self {
+        Self::new(physical, 0, 0)
+    }
+}
+
+impl fmt::Display for HlcTimestamp {
+    /// `<nanos>.<logical>.<node>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.physical.as_nanos(),
+            self.logical,
+            self.node
+        )
+    }
+}
+
+impl FromStr for HlcTimestamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Temporal(format!("Invalid HLC timestamp: {}", s));
+        let mut parts = s.splitn(3, '.');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let physical = next()?.parse().map_err(|_| invalid())?;
+        let logical = next()?.parse().map_err(|_| invalid())?;
+        let node = next()?.parse().map_err(|_| invalid())?;
+        Ok(Self::new(Timestamp::from_nanos(physical), logical, node))
+    }
+}
+
+/// Hybrid logical clock of one node
+#[derive(Debug)]
+pub struct HybridClock {
+    node: u64,
+    max_offset: Duration,
+    /// Physical time and logical counter of the last stamp issued
+    last: Mutex<(Timestamp, u32)>,
+}
+
+impl HybridClock {
+    /// Clock of `node`, rejecting stamps more than
+    /// [`DEFAULT_MAX_CLOCK_OFFSET`] ahead
+    pub fn new(node: u64) -> Self {
+        Self {
+            node,
+            max_offset: DEFAULT_MAX_CLOCK_OFFSET,
+            last: Mutex::new((Timestamp::from_nanos(i64::MIN), 0)),
+        }
+    }
+
+    /// Reject observed stamps more than `offset` ahead of the local clock
+    pub fn with_max_offset(mut self, offset: Duration) -> Self {
+        self.max_offset = offset;
+        self
+    }
+
+    /// Node the clock issues stamps for
+    pub fn node(&self) -> u64 {
+        self.node
+    }
+
+    /// Stamp a local event
+    pub fn now(&self) -> HlcTimestamp {
+        self.now_at(Timestamp::now())
+    }
+
+    /// Stamp a local event with the wall clock reading `wall`
+    pub fn now_at(&self, wall: Timestamp) -> HlcTimestamp {
+        let mut last = self.last.lock().expect("HybridClock poisoned lock");
+        *last = if wall > last.0 {
+            (wall, 0)
+        } else {
+            (last.0, last.1 + 1)
+        };
+        HlcTimestamp::new(last.0, last.1, self.node)
+    }
+
+    /// Account for a stamp received from another node, returning a local
+    /// stamp after it; fails if the stamp is too far ahead of the local
+    /// clock
+    pub fn observe(&self, remote: HlcTimestamp) -> Result<HlcTimestamp> {
+        self.observe_at(remote, Timestamp::now())
+    }
+
+    /// Account for a stamp received from another node with the wall clock
+    /// reading `wall`
+    pub fn observe_at(&self, remote: HlcTimestamp, wall: Timestamp) -> Result<HlcTimestamp> {
+        let max_offset = self.max_offset.as_nanos().min(i64::MAX as u128) as i64;
+        let ahead = remote.physical.as_nanos().saturating_sub(wall.as_nanos());
+        if ahead > max_offset {
+            return Err(Error::Temporal(format!(
+                "Clock of node {} is {}ms ahead, more than the {}ms allowed",
+                remote.node,
+                ahead / 1_000_000,
+                self.max_offset.as_millis()
+            )));
+        }
+        let mut last = self.last.lock().expect("HybridClock poisoned lock");
+        let physical = wall.max(last.0).max(remote.physical);
+        let logical = match (physical == last.0, physical == remote.physical) {
+            (true, true) => last.1.max(remote.logical) + 1,
+            (true, false) => last.1 + 1,
+            (false, true) => remote.logical + 1,
+            (false, false) => 0,
+        };
+        *last = (physical, logical);
+        Ok(HlcTimestamp::new(physical, logical, self.node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamps_never_go_backwards() {
+        let ts = Timestamp::from_millis;
+        let clock = HybridClock::new(1);
+        let a = clock.now_at(ts(100));
+        let b = clock.now_at(ts(100));
+        // The wall clock stepping back does not move stamps back.
+        let c = clock.now_at(ts(90));
+        assert_eq!((a.logical, b.logical, c.logical), (0, 1, 2));
+        assert!(a < b && b < c && c.physical == ts(100));
+
+        // A remote stamp slightly ahead is moved past.
+        let remote = HlcTimestamp::new(ts(300), 4, 2);
+        let d = clock.observe_at(remote, ts(200)).unwrap();
+        assert_eq!(d, HlcTimestamp::new(ts(300), 5, 1));
+        assert!(clock.now_at(ts(250)) > d);
+        assert_eq!(clock.now_at(ts(400)), HlcTimestamp::new(ts(400), 0, 1));
+        let skewed = HlcTimestamp::new(ts(2_000), 0, 2);
+        assert!(clock.observe_at(skewed, ts(400)).is_err());
+
+        let parsed: HlcTimestamp = d.to_string().parse().unwrap();
+        assert_eq!(parsed, d);
+        assert!("1.2".parse::<HlcTimestamp>().is_err());
+    }
+}
@@ -1,9 +1,31 @@
 //! Core data types and models
 
+pub mod aggregate;
 pub mod event;
+pub mod event_bus;
+pub mod filter;
+pub mod hierarchy;
+pub mod hlc;
+pub mod lease;
+pub mod namespace;
+pub mod numeric;
+pub mod schema;
+pub mod subscription;
 pub mod temporal;
 pub mod timeline;
+pub mod trigger;
 
+pub use aggregate::*;
 pub use event::*;
+pub use event_bus::*;
+pub use filter::*;
+pub use hierarchy::*;
+pub use hlc::*;
+pub use lease::*;
+pub use namespace::*;
+pub use numeric::*;
+pub use schema::*;
+pub use subscription::*;
 pub use temporal::*;
 pub use timeline::*;
+pub use trigger::*;
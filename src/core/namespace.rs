@@ -0,0 +1,45 @@
+//! Reserved namespace for entities the database keeps for itself.
+//!
+//! Bookkeeping such as leases, parent links, aggregate snapshots and
+//! projection checkpoints is recorded as events on companion entities whose
+//! IDs start with [`INTERNAL_ENTITY_PREFIX`]. Users cannot write to such IDs, and they are
+//! left out of entity listings, scans, subscriptions and hot-entity
+//! tracking.
+
+/// Prefix of every internal entity ID
+pub const INTERNAL_ENTITY_PREFIX: &str = "$sys:";
+
+/// Whether `entity_id` is in the internal namespace
+pub fn is_internal_entity(entity_id: &str) -> bool {
+    entity_id.starts_with(INTERNAL_ENTITY_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::aggregate::AggregateSnapshot;
+    use crate::core::hierarchy::ParentLink;
+    use crate::core::lease::Lease;
+    use crate::crdt::ConflictRecord;
+    use crate::db::PROJECTION_ENTITY_PREFIX;
+    use crate::distributed::{hint_entity, TxnId};
+    use crate::outbox::OUTBOX_ENTITY_PREFIX;
+
+    #[test]
+    fn test_companion_entities_are_internal() {
+        assert!(is_internal_entity(&Lease::entity_for("order:1")));
+        assert!(is_internal_entity(&ParentLink::entity_for("order:1")));
+        assert!(is_internal_entity(&AggregateSnapshot::entity_for(
+            "order:1"
+        )));
+        assert!(is_internal_entity(
+            &AggregateSnapshot::invalidation_entity_for("order:1")
+        ));
+        assert!(is_internal_entity(&ConflictRecord::entity_for("order:1")));
+        assert!(is_internal_entity(PROJECTION_ENTITY_PREFIX));
+        assert!(is_internal_entity(OUTBOX_ENTITY_PREFIX));
+        assert!(is_internal_entity(&TxnId::new().entity()));
+        assert!(is_internal_entity(&hint_entity("node-1")));
+        assert!(!is_internal_entity("lease:order:1"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Numeric time-series payloads.
+//!
+//! Metrics are dominated by events carrying one number. A [`NumericPayload`]
+//! stores that number (and an optional unit) in a compact binary form, and
+//! segments store blocks of numeric events column-wise with Gorilla
+//! compression (see [`crate::storage::gorilla`]).
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// [`EventPayload::format`](crate::core::event::EventPayload::format) of
+/// numeric payloads
+pub const NUMERIC_FORMAT: &str = "numeric";
+
+/// Event type of samples recorded with
+/// [`TemporalDB::insert_numeric`](crate::db::TemporalDB::insert_numeric)
+pub const NUMERIC_SAMPLE: &str = "metric.sample";
+
+/// A sample value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Number {
+    /// Integer sample, e.g. a counter
+    Int(i64),
+    /// Floating-point sample, e.g. a gauge
+    Float(f64),
+}
+
+impl Number {
+    /// Value as a float
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Self::Int(i) => i as f64,
+            Self::Float(f) => f,
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for Number {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Payload of a numeric sample
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NumericPayload {
+    /// Sample value
+    pub value: Number,
+    /// Unit, e.g. `ms` or `bytes`
+    pub unit: Option<String>,
+}
+
+impl NumericPayload {
+    /// Sample of `value` without a unit
+    pub fn new(value: impl Into<Number>) -> Self {
+        Self {
+            value: value.into(),
+            unit: None,
+        }
+    }
+
+    /// Set the unit
+    pub fn with_unit(mut self, unit: impl Into<String>) -> Self {
+        self.unit = Some(unit.into());
+        self
+    }
+}
@@ -0,0 +1,425 @@
+//! Payload schemas of entity types.
+//!
+//! An [`EntitySchema`] lists the top-level fields of an entity type's JSON
+//! payloads and their kinds. Once a schema is registered for a type, value
+//! writes to its entities are checked against the latest version and record
+//! its [`SchemaId`] in their metadata; writes that do not match are
+//! rejected. A [`SchemaRegistry`] also binds each entity type to the one
+//! Rust type its typed store reads and writes.
+//!
+//! A [`SchemaReport`] describes the payload shapes that actually occur in a
+//! journal and when entities moved from one to another, e.g. to plan an
+//! upcaster before registering a new schema version.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// Version of an entity type's schema
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SchemaId {
+    /// Entity type the schema applies to
+    pub entity_type: String,
+    /// Schema version, increasing with every change
+    pub version: u32,
+}
+
+impl SchemaId {
+    /// Version `version` of the schema of `entity_type`
+    pub fn new(entity_type: impl Into<String>, version: u32) -> Self {
+        Self {
+            entity_type: entity_type.into(),
+            version,
+        }
+    }
+}
+
+impl fmt::Display for SchemaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.entity_type, self.version)
+    }
+}
+
+impl FromStr for SchemaId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (entity_type, version) = s
+            .rsplit_once('@')
+            .and_then(|(t, v)| Some((t, v.parse().ok()?)))
+            .ok_or_else(|| Error::Serialization(format!("Invalid schema ID: {}", s)))?;
+        Ok(Self::new(entity_type, version))
+    }
+}
+
+/// JSON kind of a payload field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FieldKind {
+    /// `true` or `false`
+    Bool,
+    /// Any number
+    Number,
+    /// String
+    String,
+    /// Array
+    Array,
+    /// Object
+    Object,
+    /// Any value
+    Any,
+}
+
+impl FieldKind {
+    /// Kind of `value`; `null` is [`FieldKind::Any`]
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => Self::Any,
+            Value::Bool(_) => Self::Bool,
+            Value::Number(_) => Self::Number,
+            Value::String(_) => Self::String,
+            Value::Array(_) => Self::Array,
+            Value::Object(_) => Self::Object,
+        }
+    }
+
+    /// Whether `value` is of this kind
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            Self::Bool => value.is_boolean(),
+            Self::Number => value.is_number(),
+            Self::String => value.is_string(),
+            Self::Array => value.is_array(),
+            Self::Object => value.is_object(),
+            Self::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FieldSpec {
+    kind: FieldKind,
+    required: bool,
+}
+
+/// Top-level fields of an entity type's payloads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntitySchema {
+    id: SchemaId,
+    fields: BTreeMap<String, FieldSpec>,
+}
+
+impl EntitySchema {
+    /// Empty version `version` of the schema of `entity_type`
+    pub fn new(entity_type: impl Into<String>, version: u32) -> Self {
+        Self {
+            id: SchemaId::new(entity_type, version),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Require a field of `kind`
+    pub fn with_field(mut self, name: impl Into<String>, kind: FieldKind) -> Self {
+        let spec = FieldSpec {
+            kind,
+            required: true,
+        };
+        self.fields.insert(name.into(), spec);
+        self
+    }
+
+    /// Allow a field of `kind` that may be missing or `null`
+    pub fn with_optional_field(mut self, name: impl Into<String>, kind: FieldKind) -> Self {
+        let spec = FieldSpec {
+            kind,
+            required: false,
+        };
+        self.fields.insert(name.into(), spec);
+        self
+    }
+
+    /// Schema ID
+    pub fn id(&self) -> &SchemaId {
+        &self.id
+    }
+
+    /// Check that `payload` is an object with exactly the schema's fields
+    pub fn validate(&self, payload: &Value) -> Result<()> {
+        let mismatch = |reason: String| {
+            Error::Query(format!(
+                "Payload does not match schema {}: {}",
+                self.id, reason
+            ))
+        };
+        let Value::Object(object) = payload else {
+            return Err(mismatch("expected an object".to_string()));
+        };
+        for (name, spec) in &self.fields {
+            match object.get(name) {
+                None | Some(Value::Null) if !spec.required => {}
+                None => return Err(mismatch(format!("missing field '{}'", name))),
+                Some(value) if !spec.kind.matches(value) => {
+                    return Err(mismatch(format!("field '{}' is not {:?}", name, spec.kind)))
+                }
+                Some(_) => {}
+            }
+        }
+        match object.keys().find(|name| !self.fields.contains_key(*name)) {
+            Some(name) => Err(mismatch(format!("unknown field '{}'", name))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Registered schema versions and typed-store bindings per entity type
+#[derive(Debug, Default)]
+pub struct SchemaRegistry {
+    /// Schema versions of each entity type, oldest first
+    schemas: HashMap<String, Vec<EntitySchema>>,
+    /// Rust type bound to each entity type by a typed store
+    types: HashMap<String, &'static str>,
+}
+
+impl SchemaRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new schema version; it must be newer than the latest one
+    /// of its entity type
+    pub fn register(&mut self, schema: EntitySchema) -> Result<()> {
+        let versions = self
+            .schemas
+            .entry(schema.id.entity_type.clone())
+            .or_default();
+        if let Some(latest) = versions.last() {
+            if schema.id.version <= latest.id.version {
+                return Err(Error::Configuration(format!(
+                    "Schema {} is not newer than {}",
+                    schema.id, latest.id
+                )));
+            }
+        }
+        versions.push(schema);
+        Ok(())
+    }
+
+    /// Latest schema of `entity_type`, if one is registered
+    pub fn latest(&self, entity_type: &str) -> Option<&EntitySchema> {
+        self.schemas.get(entity_type)?.last()
+    }
+
+    /// Registered schema with the given ID
+    pub fn get(&self, id: &SchemaId) -> Option<&EntitySchema> {
+        self.schemas
+            .get(&id.entity_type)?
+            .iter()
+            .find(|schema| schema.id.version == id.version)
+    }
+
+    /// Bind `entity_type` to the Rust type named `type_name`, failing if it
+    /// is already bound to another one
+    pub fn bind_type(&mut self, entity_type: &str, type_name: &'static str) -> Result<()> {
+        let bound = self
+            .types
+            .entry(entity_type.to_string())
+            .or_insert(type_name);
+        if *bound != type_name {
+            return Err(Error::Configuration(format!(
+                "Entity type '{}' is already stored as {}",
+                entity_type, bound
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Kind of a JSON payload and, for objects, of its top-level fields
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PayloadShape {
+    /// Kind of the payload
+    pub kind: FieldKind,
+    /// Kinds of the payload's fields, if it is an object
+    pub fields: BTreeMap<String, FieldKind>,
+}
+
+impl PayloadShape {
+    /// Shape of `payload`
+    pub fn of(payload: &Value) -> Self {
+        let fields = match payload {
+            Value::Object(object) => object
+                .iter()
+                .map(|(name, value)| (name.clone(), FieldKind::of(value)))
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+        Self {
+            kind: FieldKind::of(payload),
+            fields,
+        }
+    }
+}
+
+/// A payload shape found by a schema report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShapeSummary {
+    /// Schema version recorded on the events, if any
+    pub schema: Option<SchemaId>,
+    /// Shape of the payloads
+    pub shape: PayloadShape,
+    /// Events with this shape
+    pub events: u64,
+    /// Entities with at least one event of this shape
+    pub entities: u64,
+    /// Valid time of the first event with this shape
+    pub first_seen: Timestamp,
+    /// Valid time of the last event with this shape
+    pub last_seen: Timestamp,
+}
+
+/// An entity's payload shape changing from one event to the next
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShapeChange {
+    /// Entity ID
+    pub entity_id: String,
+    /// Valid time of the first event with the new shape
+    pub at: Timestamp,
+    /// Index of the previous shape in [`SchemaReport::shapes`]
+    pub from: usize,
+    /// Index of the new shape in [`SchemaReport::shapes`]
+    pub to: usize,
+}
+
+/// Payload shapes of a set of entities over a time range
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaReport {
+    /// Shapes found, in order of first appearance
+    pub shapes: Vec<ShapeSummary>,
+    /// Shape changes, in time order
+    pub changes: Vec<ShapeChange>,
+    /// Events without a JSON value, such as deletions and numeric samples
+    pub skipped: u64,
+}
+
+impl SchemaReport {
+    /// Create an empty report
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the events of one entity, in timestamp order
+    pub fn add_timeline<'a>(
+        &mut self,
+        entity_id: &str,
+        events: impl IntoIterator<Item = &'a Event>,
+    ) {
+        let mut seen = Vec::new();
+        let mut previous = None;
+        for event in events {
+            let payload = (event.payload().format == "json" && !event.is_tombstone())
+                .then(|| event.payload().to_json::<Value>().ok())
+                .flatten();
+            let Some(payload) = payload else {
+                self.skipped += 1;
+                continue;
+            };
+            let schema = event.metadata.schema.clone();
+            let shape = PayloadShape::of(&payload);
+            let ts = event.timestamp();
+            let index = match self
+                .shapes
+                .iter()
+                .position(|s| s.schema == schema && s.shape == shape)
+            {
+                Some(index) => index,
+                None => {
+                    self.shapes.push(ShapeSummary {
+                        schema,
+                        shape,
+                        events: 0,
+                        entities: 0,
+                        first_seen: ts,
+                        last_seen: ts,
+                    });
+                    self.shapes.len() - 1
+                }
+            };
+            let summary = &mut self.shapes[index];
+            summary.events += 1;
+            summary.first_seen = summary.first_seen.min(ts);
+            summary.last_seen = summary.last_seen.max(ts);
+            if !seen.contains(&index) {
+                seen.push(index);
+                summary.entities += 1;
+            }
+            if let Some(from) = previous.filter(|from| *from != index) {
+                self.changes.push(ShapeChange {
+                    entity_id: entity_id.to_string(),
+                    at: ts,
+                    from,
+                    to: index,
+                });
+            }
+            previous = Some(index);
+        }
+    }
+
+    /// Order shapes by first appearance and changes by time, once every
+    /// timeline has been added
+    pub fn finish(mut self) -> Self {
+        let mut order: Vec<usize> = (0..self.shapes.len()).collect();
+        order.sort_by_key(|&i| self.shapes[i].first_seen);
+        let mut renumber = vec![0; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            renumber[old] = new;
+        }
+        let mut shapes: Vec<_> = self.shapes.into_iter().map(Some).collect();
+        self.shapes = order.iter().filter_map(|&i| shapes[i].take()).collect();
+        for change in &mut self.changes {
+            change.from = renumber[change.from];
+            change.to = renumber[change.to];
+        }
+        self.changes
+            .sort_by(|a, b| a.at.cmp(&b.at).then_with(|| a.entity_id.cmp(&b.entity_id)));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_and_register_versions() {
+        let v1 = EntitySchema::new("user", 1)
+            .with_field("name", FieldKind::String)
+            .with_optional_field("age", FieldKind::Number);
+        assert!(v1.validate(&json!({ "name": "Ann" })).is_ok());
+        assert!(v1.validate(&json!({ "name": "Ann", "age": null })).is_ok());
+        assert!(v1.validate(&json!({ "age": 3 })).is_err());
+        assert!(v1.validate(&json!({ "name": 1 })).is_err());
+        assert!(v1
+            .validate(&json!({ "name": "Ann", "email": "a@b" }))
+            .is_err());
+        assert!(v1.validate(&json!("Ann")).is_err());
+
+        let mut registry = SchemaRegistry::new();
+        registry.register(v1.clone()).unwrap();
+        assert!(registry.register(v1).is_err());
+        registry
+            .register(EntitySchema::new("user", 2).with_field("name", FieldKind::String))
+            .unwrap();
+        assert_eq!(registry.latest("user").unwrap().id().version, 2);
+        assert!(registry.get(&"user@1".parse().unwrap()).is_some());
+
+        registry.bind_type("user", "User").unwrap();
+        registry.bind_type("user", "User").unwrap();
+        assert!(registry.bind_type("user", "Account").is_err());
+    }
+}
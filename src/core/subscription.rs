@@ -0,0 +1,153 @@
+//! Live subscriptions to newly appended events.
+//!
+//! Every committed event is broadcast to the open subscriptions, each of
+//! which keeps the events its [`SubscriptionFilter`] matches. Subscribers
+//! that fall more than [`SUBSCRIPTION_BUFFER`] events behind miss the
+//! oldest ones rather than slowing down writers.
+
+use crate::core::event::Event;
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Events buffered per subscriber before the oldest are dropped
+pub const SUBSCRIPTION_BUFFER: usize = 1024;
+
+/// Events a subscription receives; unset criteria match everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubscriptionFilter {
+    /// Only entities whose ID starts with this prefix
+    pub entity_prefix: Option<String>,
+    /// Only events of this type
+    pub event_type: Option<String>,
+    /// Only events carrying at least one of these tags
+    pub tags: Vec<String>,
+}
+
+impl SubscriptionFilter {
+    /// Filter matching every event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only receive events of entities whose ID starts with `prefix`
+    pub fn with_entity_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.entity_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only receive events of `event_type`
+    pub fn with_event_type(mut self, event_type: impl Into<String>) -> Self {
+        self.event_type = Some(event_type.into());
+        self
+    }
+
+    /// Also accept events tagged `tag`
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Whether `event` passes the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        self.entity_prefix
+            .as_ref()
+            .is_none_or(|prefix| event.entity_id().starts_with(prefix.as_str()))
+            && self
+                .event_type
+                .as_ref()
+                .is_none_or(|t| event.event_type() == t)
+            && (self.tags.is_empty()
+                || event
+                    .metadata
+                    .tags
+                    .iter()
+                    .any(|tag| self.tags.contains(tag)))
+    }
+}
+
+/// Fans committed events out to subscribers
+#[derive(Debug, Clone)]
+pub struct EventBroadcaster {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBroadcaster {
+    /// Broadcaster buffering [`SUBSCRIPTION_BUFFER`] events per subscriber
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(SUBSCRIPTION_BUFFER);
+        Self { sender }
+    }
+
+    /// Send `event` to the open subscriptions
+    pub fn publish(&self, event: &Event) {
+        if self.sender.receiver_count() > 0 {
+            // Fails only when the last subscriber went away meanwhile.
+            let _ = self.sender.send(event.clone());
+        }
+    }
+
+    /// Number of open subscriptions
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Stream of the events published from now on that `accept` keeps; it
+    /// ends when the broadcaster is dropped
+    pub fn subscribe<F>(&self, accept: F) -> BoxStream<'static, Event>
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+    {
+        stream::unfold(
+            (self.sender.subscribe(), accept),
+            |(mut receiver, accept)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) if accept(&event) => return Some((event, (receiver, accept))),
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!("Subscriber lagged, {} events dropped", missed);
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+}
+
+impl Default for EventBroadcaster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+
+    #[test]
+    fn test_filter_matches() {
+        let event = Event::builder(
+            "order.placed".to_string(),
+            Timestamp::from_secs(1),
+            "order:1".to_string(),
+            EventPayload::from_json(&1).unwrap(),
+        )
+        .tags(vec!["eu".to_string()])
+        .build();
+
+        assert!(SubscriptionFilter::new().matches(&event));
+        let orders = SubscriptionFilter::new()
+            .with_entity_prefix("order:")
+            .with_event_type("order.placed");
+        assert!(orders.matches(&event));
+        assert!(orders.clone().with_tag("us").with_tag("eu").matches(&event));
+        assert!(!orders.with_tag("us").matches(&event));
+        assert!(!SubscriptionFilter::new()
+            .with_event_type("order.shipped")
+            .matches(&event));
+    }
+}
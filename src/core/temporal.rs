@@ -1,8 +1,10 @@
 //! Temporal data types and time handling
 
-use chrono::{DateTime, Utc};
+use crate::error::Error;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::str::FromStr;
 
 /// Timestamp representing a point in time with nanosecond precision
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -69,7 +71,7 @@ impl Timestamp {
     /// Convert to chrono DateTime
     pub fn to_datetime(&self) -> DateTime<Utc> {
         DateTime::from_timestamp(self.as_secs(), (self.nanos % 1_000_000_000) as u32)
-            .unwrap_or_else(|| Utc::now())
+            .unwrap_or_else(Utc::now)
     }
 
     /// Add duration in nanoseconds
@@ -93,6 +95,36 @@ impl fmt::Display for Timestamp {
     }
 }
 
+/// Parses RFC 3339 (`2024-06-01T12:00:00+02:00`), or a date and time
+/// without offset (`2024-06-01T12:00`, `2024-06-01 12:00:30`) or a date
+/// alone, read as UTC
+impl FromStr for Timestamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+            return Ok(dt.with_timezone(&Utc).into());
+        }
+        const FORMATS: [&str; 4] = [
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%d %H:%M",
+        ];
+        let naive = FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok());
+        naive
+            .or_else(|| {
+                let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+                date.and_hms_opt(0, 0, 0)
+            })
+            .map(|dt| dt.and_utc().into())
+            .ok_or_else(|| Error::Temporal(format!("Invalid timestamp: {}", s)))
+    }
+}
+
 impl From<DateTime<Utc>> for Timestamp {
     fn from(dt: DateTime<Utc>) -> Self {
         Self {
@@ -243,6 +275,20 @@ mod tests {
         assert_eq!(ts2.as_millis(), 1_000_000);
     }
 
+    #[test]
+    fn test_timestamp_from_str() {
+        let noon = Timestamp::from_secs(1_717_243_200);
+        assert_eq!("2024-06-01T12:00".parse::<Timestamp>().unwrap(), noon);
+        assert_eq!("2024-06-01 12:00:00".parse::<Timestamp>().unwrap(), noon);
+        let offset = "2024-06-01T14:00:00+02:00";
+        assert_eq!(offset.parse::<Timestamp>().unwrap(), noon);
+        assert_eq!(
+            "2024-06-01".parse::<Timestamp>().unwrap(),
+            Timestamp::from_secs(1_717_200_000)
+        );
+        assert!("yesterday".parse::<Timestamp>().is_err());
+    }
+
     #[test]
     fn test_time_period() {
         let start = Timestamp::from_secs(1000);
@@ -1,6 +1,6 @@
 //! Timeline: sequence of events for an entity
 
-use crate::core::event::Event;
+use crate::core::event::{Event, EventOrdering};
 use crate::core::temporal::Timestamp;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -14,30 +14,48 @@ pub struct Timeline {
     events: BTreeMap<Timestamp, Vec<Event>>,
     /// Current version (number of events)
     version: u64,
+    /// How events sharing a timestamp are ordered
+    #[serde(default)]
+    ordering: EventOrdering,
 }
 
 impl Timeline {
     /// Create a new timeline for an entity
     pub fn new(entity_id: String) -> Self {
+        Self::with_ordering(entity_id, EventOrdering::default())
+    }
+
+    /// Create a new timeline ordering same-timestamp events by `ordering`
+    pub fn with_ordering(entity_id: String, ordering: EventOrdering) -> Self {
         Self {
             entity_id,
             events: BTreeMap::new(),
             version: 0,
+            ordering,
         }
     }
 
+    /// Policy ordering same-timestamp events
+    pub fn ordering(&self) -> EventOrdering {
+        self.ordering
+    }
+
     /// Get entity ID
     pub fn entity_id(&self) -> &str {
         &self.entity_id
     }
 
-    /// Append an event to the timeline
+    /// Append an event to the timeline.
+    ///
+    /// Events sharing a timestamp are kept sorted by the timeline's
+    /// [`EventOrdering`], so the result does not depend on arrival order
+    /// unless the policy says so.
     pub fn append(&mut self, event: Event) {
-        let timestamp = event.timestamp();
-        self.events
-            .entry(timestamp)
-            .or_insert_with(Vec::new)
-            .push(event);
+        let ordering = self.ordering;
+        let tied = self.events.entry(event.timestamp()).or_default();
+        let position =
+            tied.partition_point(|e| ordering.compare(e, &event) != std::cmp::Ordering::Greater);
+        tied.insert(position, event);
         self.version += 1;
     }
 
@@ -55,10 +73,20 @@ impl Timeline {
 
     /// Get events in time range [start, end)
     pub fn events_in_range(&self, start: Timestamp, end: Timestamp) -> Vec<&Event> {
+        self.iter_range(start, end).collect()
+    }
+
+    /// Iterate over events in time range [start, end), reading them only
+    /// as they are consumed
+    pub fn iter_range(&self, start: Timestamp, end: Timestamp) -> impl Iterator<Item = &Event> {
         self.events
             .range(start..end)
             .flat_map(|(_, events)| events.iter())
-            .collect()
+    }
+
+    /// Get events at exactly a timestamp, in tie order
+    pub fn events_at(&self, timestamp: Timestamp) -> &[Event] {
+        self.events.get(&timestamp).map_or(&[], Vec::as_slice)
     }
 
     /// Get events up to a timestamp (inclusive)
@@ -85,6 +113,14 @@ impl Timeline {
             .and_then(|(_, events)| events.first())
     }
 
+    /// Remove all events strictly before a timestamp, returning how many were removed
+    pub fn remove_before(&mut self, timestamp: Timestamp) -> usize {
+        let kept = self.events.split_off(&timestamp);
+        let removed = self.len();
+        self.events = kept;
+        removed
+    }
+
     /// Get current version
     pub fn version(&self) -> u64 {
         self.version
@@ -195,4 +231,32 @@ mod tests {
         assert!(latest.is_some());
         assert_eq!(latest.unwrap().timestamp(), ts1);
     }
+
+    #[test]
+    fn test_same_timestamp_ordering() {
+        let ts = Timestamp::from_secs(1000);
+        let mut first = create_test_event(ts, "entity:1");
+        first.metadata.transaction_time = Timestamp::from_secs(1);
+        let mut second = create_test_event(ts, "entity:1");
+        second.metadata.transaction_time = Timestamp::from_secs(2);
+
+        // Replicas receiving the events in different orders agree.
+        let mut a = Timeline::new("entity:1".to_string());
+        a.append_many(vec![first.clone(), second.clone()]);
+        let mut b = Timeline::new("entity:1".to_string());
+        b.append_many(vec![second.clone(), first.clone()]);
+        assert_eq!(a.latest_before(ts).unwrap().id(), second.id());
+        assert_eq!(b.latest_before(ts).unwrap().id(), second.id());
+
+        let mut merged = Timeline::new("entity:1".to_string());
+        merged.append(second.clone());
+        merged.merge(&a);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged.events().next().unwrap().id(), first.id());
+
+        let mut arrival =
+            Timeline::with_ordering("entity:1".to_string(), EventOrdering::AppendOrder);
+        arrival.append_many(vec![second, first.clone()]);
+        assert_eq!(arrival.latest_before(ts).unwrap().id(), first.id());
+    }
 }
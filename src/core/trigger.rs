@@ -0,0 +1,314 @@
+//! Effective-at triggers.
+//!
+//! A fact may be recorded before it takes effect, e.g. a price change valid
+//! from next Monday. Triggers notify interested parties when such a fact
+//! becomes effective, i.e. when the clock reaches its valid time.
+//!
+//! Future-valid events wait in a [`PendingQueue`] ordered by valid time.
+//! The queue only holds events after its watermark, the valid time up to
+//! which events have been delivered. Persisting the watermark is enough to
+//! rebuild the queue from the journal after a restart, so no fact is
+//! delivered twice or skipped.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// File in a data directory holding the trigger watermark
+pub const TRIGGER_STATE_FILE: &str = "TRIGGERS";
+
+/// Identifies a registered trigger
+pub type TriggerId = u64;
+
+/// Events a trigger fires for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TriggerFilter {
+    /// Only entities whose ID starts with this prefix
+    pub entity_prefix: Option<String>,
+    /// Only events of this type
+    pub event_type: Option<String>,
+}
+
+impl TriggerFilter {
+    /// Filter matching every event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only fire for entities whose ID starts with `prefix`
+    pub fn with_entity_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.entity_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only fire for events of `event_type`
+    pub fn with_event_type(mut self, event_type: impl Into<String>) -> Self {
+        self.event_type = Some(event_type.into());
+        self
+    }
+
+    /// Whether `event` passes the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        self.entity_prefix
+            .as_ref()
+            .is_none_or(|prefix| event.entity_id().starts_with(prefix.as_str()))
+            && self
+                .event_type
+                .as_ref()
+                .is_none_or(|t| event.event_type() == t)
+    }
+}
+
+/// A fact that became effective, delivered to a trigger
+#[derive(Debug, Clone)]
+pub struct EffectiveNotification {
+    /// Trigger that fired
+    pub trigger: TriggerId,
+    /// The event that became effective
+    pub event: Event,
+}
+
+/// Future-valid events by valid time
+#[derive(Debug, Clone)]
+pub struct PendingQueue {
+    pending: BTreeMap<Timestamp, Vec<Event>>,
+    watermark: Timestamp,
+}
+
+impl PendingQueue {
+    /// Empty queue; events at or before `watermark` are already delivered
+    pub fn new(watermark: Timestamp) -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            watermark,
+        }
+    }
+
+    /// Valid time up to which events have been delivered
+    pub fn watermark(&self) -> Timestamp {
+        self.watermark
+    }
+
+    /// Queue `event` if it becomes effective after the watermark
+    pub fn schedule(&mut self, event: &Event) -> bool {
+        if event.timestamp() <= self.watermark {
+            return false;
+        }
+        self.pending
+            .entry(event.timestamp())
+            .or_default()
+            .push(event.clone());
+        true
+    }
+
+    /// Valid time of the next pending event
+    pub fn next_due(&self) -> Option<Timestamp> {
+        self.pending.keys().next().copied()
+    }
+
+    /// Remove the events effective at or before `now`, in valid-time order,
+    /// and advance the watermark to `now`
+    pub fn take_due(&mut self, now: Timestamp) -> Vec<Event> {
+        if now <= self.watermark {
+            return Vec::new();
+        }
+        let later = match now.as_nanos().checked_add(1) {
+            Some(next) => self.pending.split_off(&Timestamp::from_nanos(next)),
+            None => BTreeMap::new(),
+        };
+        let due = std::mem::replace(&mut self.pending, later);
+        self.watermark = now;
+        due.into_values().flatten().collect()
+    }
+
+    /// Number of pending events
+    pub fn len(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Whether no event is pending
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Persisted trigger state
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct TriggerState {
+    watermark: Timestamp,
+}
+
+/// Registered triggers and the pending queue they fire from
+#[derive(Debug)]
+pub struct TriggerRegistry {
+    queue: PendingQueue,
+    triggers: HashMap<TriggerId, (TriggerFilter, mpsc::UnboundedSender<EffectiveNotification>)>,
+    next_id: TriggerId,
+    /// Where the watermark is persisted, if anywhere
+    state_path: Option<PathBuf>,
+}
+
+impl TriggerRegistry {
+    /// Registry whose queue starts at `watermark`
+    pub fn new(watermark: Timestamp) -> Self {
+        Self {
+            queue: PendingQueue::new(watermark),
+            triggers: HashMap::new(),
+            next_id: 1,
+            state_path: None,
+        }
+    }
+
+    /// Registry persisting its watermark in `dir`, resuming from the stored
+    /// watermark or, for a new directory, from `now`
+    pub fn open(dir: &Path, now: Timestamp) -> Result<Self> {
+        let path = dir.join(TRIGGER_STATE_FILE);
+        let watermark = match std::fs::read(&path) {
+            Ok(data) => {
+                serde_json::from_slice::<TriggerState>(&data)
+                    .map_err(|e| {
+                        Error::Storage(format!("Invalid trigger state {}: {}", path.display(), e))
+                    })?
+                    .watermark
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => now,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            state_path: Some(path),
+            ..Self::new(watermark)
+        })
+    }
+
+    /// Pending events
+    pub fn queue(&self) -> &PendingQueue {
+        &self.queue
+    }
+
+    /// Queue `event` if it becomes effective in the future
+    pub fn schedule(&mut self, event: &Event) -> bool {
+        self.queue.schedule(event)
+    }
+
+    /// Register a trigger; notifications arrive on the returned receiver
+    /// until it is dropped
+    pub fn register(
+        &mut self,
+        filter: TriggerFilter,
+    ) -> (TriggerId, mpsc::UnboundedReceiver<EffectiveNotification>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.triggers.insert(id, (filter, sender));
+        (id, receiver)
+    }
+
+    /// Remove a trigger; returns whether it was registered
+    pub fn unregister(&mut self, id: TriggerId) -> bool {
+        self.triggers.remove(&id).is_some()
+    }
+
+    /// Number of registered triggers
+    pub fn trigger_count(&self) -> usize {
+        self.triggers.len()
+    }
+
+    /// Deliver the events effective at or before `now` to matching triggers,
+    /// returning the number of notifications sent.
+    ///
+    /// Nothing is delivered while no trigger is registered, so facts
+    /// becoming effective meanwhile wait for the first registration.
+    pub fn fire_due(&mut self, now: Timestamp) -> Result<usize> {
+        if self.triggers.is_empty() || now <= self.queue.watermark() {
+            return Ok(0);
+        }
+        let mut sent = 0;
+        for event in self.queue.take_due(now) {
+            self.triggers.retain(|id, (filter, sender)| {
+                if !filter.matches(&event) {
+                    return true;
+                }
+                let notification = EffectiveNotification {
+                    trigger: *id,
+                    event: event.clone(),
+                };
+                // A dropped receiver unregisters the trigger.
+                let delivered = sender.send(notification).is_ok();
+                sent += delivered as usize;
+                delivered
+            });
+        }
+        self.persist()?;
+        Ok(sent)
+    }
+
+    /// Store the watermark, atomically replacing the previous state
+    fn persist(&self) -> Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let state = TriggerState {
+            watermark: self.queue.watermark(),
+        };
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&state)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn fact(entity: &str, secs: i64) -> Event {
+        Event::new(
+            "value.changed".to_string(),
+            Timestamp::from_secs(secs),
+            entity.to_string(),
+            EventPayload::from_json(&secs).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_fires_when_effective_and_resumes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let secs = Timestamp::from_secs;
+        let mut registry = TriggerRegistry::open(dir.path(), secs(100)).unwrap();
+        assert!(!registry.schedule(&fact("price:1", 50)));
+        assert!(registry.schedule(&fact("price:1", 300)));
+        assert!(registry.schedule(&fact("price:2", 200)));
+        assert!(registry.schedule(&fact("stock:1", 200)));
+
+        // Nothing is consumed until a trigger is registered.
+        assert_eq!(registry.fire_due(secs(250)).unwrap(), 0);
+        let (id, mut prices) = registry.register(TriggerFilter::new().with_entity_prefix("price:"));
+        assert_eq!(registry.fire_due(secs(250)).unwrap(), 1);
+        let fired = prices.try_recv().unwrap();
+        assert_eq!(fired.trigger, id);
+        assert_eq!(fired.event.entity_id(), "price:2");
+        assert!(prices.try_recv().is_err());
+        assert_eq!(registry.queue().len(), 1);
+
+        // After a restart the queue is rebuilt from the watermark on.
+        let mut registry = TriggerRegistry::open(dir.path(), secs(1000)).unwrap();
+        assert_eq!(registry.queue().watermark(), secs(250));
+        for event in [fact("price:2", 200), fact("price:1", 300)] {
+            registry.schedule(&event);
+        }
+        let (_, mut prices) = registry.register(TriggerFilter::new());
+        assert_eq!(registry.fire_due(secs(400)).unwrap(), 1);
+        assert_eq!(prices.try_recv().unwrap().event.timestamp(), secs(300));
+
+        // Dropping the receiver unregisters the trigger.
+        drop(prices);
+        registry.schedule(&fact("price:1", 500));
+        assert_eq!(registry.fire_due(secs(600)).unwrap(), 0);
+        assert_eq!(registry.trigger_count(), 0);
+    }
+}
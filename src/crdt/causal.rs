@@ -0,0 +1,188 @@
+//! Causal delivery of remote CRDT operations
+//!
+//! Operation-based CRDTs assume every operation is applied after the
+//! operations it depends on: a remove of an [`ORSet`](crate::crdt::ORSet)
+//! element applied before the add it observed removes nothing, and the add
+//! then survives. Each operation therefore carries the [`VectorClock`] of
+//! its replica when it was made, and a [`CausalBuffer`] holds remote
+//! operations until everything they depend on has been delivered.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of operations of each node a replica has seen
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VectorClock {
+    counts: BTreeMap<usize, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of operations of `node` seen
+    pub fn get(&self, node: usize) -> u64 {
+        self.counts.get(&node).copied().unwrap_or(0)
+    }
+
+    /// Count an operation of `node`; returns the new count
+    pub fn increment(&mut self, node: usize) -> u64 {
+        let count = self.counts.entry(node).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Take the higher count of every node
+    pub fn merge(&mut self, other: &Self) {
+        for (node, count) in &other.counts {
+            let kept = self.counts.entry(*node).or_insert(0);
+            *kept = (*kept).max(*count);
+        }
+    }
+
+    /// Whether every operation seen by `other` has been seen by this clock
+    pub fn dominates(&self, other: &Self) -> bool {
+        other
+            .counts
+            .iter()
+            .all(|(node, count)| self.get(*node) >= *count)
+    }
+
+    /// Whether neither clock has seen every operation the other has
+    pub fn concurrent_with(&self, other: &Self) -> bool {
+        !self.dominates(other) && !other.dominates(self)
+    }
+}
+
+/// Operation of the replica of `node`, stamped with the replica's clock
+/// once the operation was counted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CausalOp<O> {
+    /// Node of the replica the operation was made on
+    pub node: usize,
+    /// Clock of that replica including the operation
+    pub clock: VectorClock,
+    /// The operation
+    pub op: O,
+}
+
+impl<O> CausalOp<O> {
+    /// Stamp local operation `op` of the replica of `node`, counting it in
+    /// `clock`
+    pub fn local(node: usize, clock: &mut VectorClock, op: O) -> Self {
+        clock.increment(node);
+        Self {
+            node,
+            clock: clock.clone(),
+            op,
+        }
+    }
+}
+
+/// Holds remote operations until the operations they depend on have been
+/// delivered, then releases them in causal order
+#[derive(Debug, Clone)]
+pub struct CausalBuffer<O> {
+    delivered: VectorClock,
+    pending: Vec<CausalOp<O>>,
+}
+
+impl<O> CausalBuffer<O> {
+    /// Buffer of a replica that has seen the operations counted in
+    /// `delivered`
+    pub fn new(delivered: VectorClock) -> Self {
+        Self {
+            delivered,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Operations delivered so far
+    pub fn delivered(&self) -> &VectorClock {
+        &self.delivered
+    }
+
+    /// Number of operations waiting for their dependencies
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Count a local operation of `node`, made on this replica, as
+    /// delivered
+    pub fn record_local(&mut self, node: usize) -> u64 {
+        self.delivered.increment(node)
+    }
+
+    /// Receive `op`; returns the operations it makes deliverable, `op`
+    /// itself included, in the order to apply them. Operations already
+    /// delivered or already waiting are dropped.
+    pub fn receive(&mut self, op: CausalOp<O>) -> Vec<CausalOp<O>> {
+        let duplicate = self.delivered.get(op.node) >= op.clock.get(op.node)
+            || self
+                .pending
+                .iter()
+                .any(|p| p.node == op.node && p.clock.get(p.node) == op.clock.get(op.node));
+        if !duplicate {
+            self.pending.push(op);
+        }
+        let mut ready = Vec::new();
+        while let Some(i) = self.pending.iter().position(|p| self.deliverable(p)) {
+            let op = self.pending.remove(i);
+            self.delivered.increment(op.node);
+            ready.push(op);
+        }
+        ready
+    }
+
+    /// Whether `op` is the next operation of its node and every operation
+    /// of other nodes it depends on has been delivered
+    fn deliverable(&self, op: &CausalOp<O>) -> bool {
+        op.clock.get(op.node) == self.delivered.get(op.node) + 1
+            && op
+                .clock
+                .counts
+                .iter()
+                .all(|(node, count)| *node == op.node || self.delivered.get(*node) >= *count)
+    }
+}
+
+impl<O> Default for CausalBuffer<O> {
+    fn default() -> Self {
+        Self::new(VectorClock::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hlc::HybridClock;
+    use crate::crdt::{CrdtEntity, ORSet, ORSetOp};
+
+    #[test]
+    fn test_buffer_delivers_remove_after_observed_add() {
+        let clock = HybridClock::new(1);
+        let x = || "x".to_string();
+        let mut vc = VectorClock::new();
+        let add = CausalOp::local(1, &mut vc, ORSetOp::Add(x(), clock.now()));
+        // Node 2 removes the element after receiving the add.
+        let mut seen = vc.clone();
+        let remove = CausalOp::local(2, &mut seen, ORSetOp::Remove(x(), clock.now()));
+        assert!(seen.dominates(&vc) && !vc.concurrent_with(&seen));
+
+        let mut buffer = CausalBuffer::default();
+        assert!(buffer.receive(remove.clone()).is_empty());
+        assert!(buffer.receive(remove).is_empty());
+        assert_eq!(buffer.pending(), 1);
+        let ready = buffer.receive(add.clone());
+        assert_eq!(ready.iter().map(|op| op.node).collect::<Vec<_>>(), [1, 2]);
+        assert!(buffer.receive(add).is_empty());
+        assert_eq!((buffer.pending(), buffer.delivered()), (0, &seen));
+
+        let mut set: ORSet<String> = ORSet::empty(3);
+        for op in &ready {
+            set.apply(op.node, &op.op);
+        }
+        assert!(!set.contains(&x()));
+    }
+}
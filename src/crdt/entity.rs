@@ -0,0 +1,283 @@
+//! CRDT-backed entities.
+//!
+//! A CRDT entity (see [`TemporalDB::crdt`](crate::TemporalDB::crdt)) is
+//! stored like any other entity, as events: every local operation is
+//! recorded as a [`CRDT_OP`] event and every replica state received from
+//! another node as a [`CRDT_MERGE`] event. Its state at any time is
+//! rebuilt by folding those events into an empty replica, so history
+//! queries work on it as on plain values. Tombstones of removals every
+//! replica has seen are dropped at [`CRDT_GC`] events.
+//!
+//! All three are tagged [`CRDT_TAG`], so another node can follow them with
+//! a subscription and apply them with
+//! [`TemporalDB::apply_remote_events`](crate::TemporalDB::apply_remote_events).
+//! Each operation records the replica it was made on, so it counts for
+//! that replica wherever it is folded.
+
+use crate::core::event::Event;
+use crate::core::hlc::HlcTimestamp;
+use crate::core::temporal::Timestamp;
+use crate::crdt::types::{GCounter, GSet, LWWMap, LWWRegister, ORSet, CRDT};
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Event type of a local operation on a CRDT entity
+pub const CRDT_OP: &str = "crdt.op";
+
+/// Event type of a replica state merged into a CRDT entity
+pub const CRDT_MERGE: &str = "crdt.merge";
+
+/// Event type recording the causal stability watermark tombstones of a CRDT
+/// entity were pruned at
+pub const CRDT_GC: &str = "crdt.gc";
+
+/// Tag of every event of a CRDT entity
+pub const CRDT_TAG: &str = "crdt";
+
+/// Whether `event` is an event of a CRDT entity; such events commute, so
+/// replicas apply them in any order without conflicts
+pub fn is_crdt_event(event: &Event) -> bool {
+    matches!(event.event_type(), CRDT_OP | CRDT_MERGE | CRDT_GC)
+}
+
+/// CRDT that can back an entity
+pub trait CrdtEntity: CRDT + Serialize + DeserializeOwned {
+    /// Operation, persisted in the payload of a [`CRDT_OP`] event
+    type Op: Serialize + DeserializeOwned;
+
+    /// Replica of node `node` without any operation applied
+    fn empty(node: usize) -> Self;
+
+    /// Apply an operation made on the replica of node `node`
+    fn apply(&mut self, node: usize, op: &Self::Op);
+
+    /// Form in which local operation `op` of the replica of node `node`
+    /// is persisted, given the state before it; `op` itself by default.
+    /// Operations that count every time they are applied, like
+    /// increments, persist their result instead, so a replica that
+    /// receives both the operation and a state including it counts it
+    /// once.
+    fn persisted_op(&self, _node: usize, op: Self::Op) -> Self::Op {
+        op
+    }
+
+    /// Drop tombstones of removals before `stable`, a time every replica
+    /// has seen; returns how many were dropped
+    fn prune_tombstones(&mut self, _stable: Timestamp) -> usize {
+        0
+    }
+}
+
+/// Payload of a [`CRDT_OP`] event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrdtOpRecord<O> {
+    /// Node of the replica the operation was made on
+    pub node: usize,
+    /// The operation
+    pub op: O,
+}
+
+/// State of the replica of node `node` after `events` of a CRDT entity
+pub fn fold_crdt<'a, T: CrdtEntity>(
+    node: usize,
+    events: impl IntoIterator<Item = &'a Event>,
+) -> Result<T> {
+    let mut state = T::empty(node);
+    for event in events {
+        match event.event_type() {
+            CRDT_OP => {
+                let record: CrdtOpRecord<T::Op> = event.payload().to_json()?;
+                state.apply(record.node, &record.op);
+            }
+            CRDT_MERGE => state.merge(&event.payload().to_json()?),
+            CRDT_GC => {
+                state.prune_tombstones(event.payload().to_json()?);
+            }
+            other => {
+                return Err(Error::Crdt(format!(
+                    "Event {} of {} is not a CRDT operation: {}",
+                    event.id(),
+                    event.entity_id(),
+                    other
+                )))
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// Operation on a [`GCounter`] entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GCounterOp {
+    /// Increase the count of the replica
+    Increment(u64),
+    /// The count of the replica reached a total; increments are persisted
+    /// in this form
+    Total(u64),
+}
+
+impl CrdtEntity for GCounter {
+    type Op = GCounterOp;
+
+    fn empty(node: usize) -> Self {
+        GCounter::new(node, node + 1)
+    }
+
+    fn apply(&mut self, node: usize, op: &GCounterOp) {
+        match *op {
+            GCounterOp::Increment(by) => self.increment_node(node, by),
+            GCounterOp::Total(total) => self.raise_node(node, total),
+        }
+    }
+
+    fn persisted_op(&self, node: usize, op: GCounterOp) -> GCounterOp {
+        match op {
+            GCounterOp::Increment(by) => GCounterOp::Total(self.count_of(node) + by),
+            total => total,
+        }
+    }
+}
+
+impl<T> CrdtEntity for GSet<T>
+where
+    T: std::hash::Hash + Eq + Clone + Send + Sync + Serialize + DeserializeOwned,
+{
+    /// Element added
+    type Op = T;
+
+    fn empty(_node: usize) -> Self {
+        GSet::new()
+    }
+
+    fn apply(&mut self, _node: usize, op: &T) {
+        self.add(op.clone());
+    }
+}
+
+impl<T> CrdtEntity for LWWRegister<T>
+where
+    T: Clone + PartialEq + Default + Send + Sync + Serialize + DeserializeOwned,
+{
+    /// Value written and the stamp of the write
+    type Op = (T, HlcTimestamp);
+
+    /// Register holding `T::default()`, older than every write
+    fn empty(_node: usize) -> Self {
+        LWWRegister::new(T::default(), Timestamp::from_nanos(i64::MIN))
+    }
+
+    fn apply(&mut self, _node: usize, (value, stamp): &(T, HlcTimestamp)) {
+        self.set_stamped(value.clone(), *stamp);
+    }
+}
+
+/// Operation on an [`ORSet`] entity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ORSetOp<T> {
+    /// Add an element with the tag of the write
+    Add(T, HlcTimestamp),
+    /// Remove the adds of an element observed so far
+    Remove(T, HlcTimestamp),
+}
+
+impl<T> CrdtEntity for ORSet<T>
+where
+    T: Clone + PartialEq + Send + Sync + Serialize + DeserializeOwned,
+{
+    type Op = ORSetOp<T>;
+
+    fn empty(_node: usize) -> Self {
+        ORSet::new()
+    }
+
+    fn apply(&mut self, _node: usize, op: &ORSetOp<T>) {
+        match op {
+            ORSetOp::Add(element, stamp) => self.add(element.clone(), *stamp),
+            ORSetOp::Remove(element, stamp) => {
+                self.remove(element, *stamp);
+            }
+        }
+    }
+
+    fn prune_tombstones(&mut self, stable: Timestamp) -> usize {
+        ORSet::prune_tombstones(self, stable)
+    }
+}
+
+/// Operation on an [`LWWMap`] entity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LWWMapOp<K, V> {
+    /// Write a value under a key
+    Set(K, V, HlcTimestamp),
+    /// Remove a key
+    Remove(K, HlcTimestamp),
+}
+
+impl<K, V> CrdtEntity for LWWMap<K, V>
+where
+    K: Ord + Clone + Send + Sync + Serialize + DeserializeOwned,
+    V: Clone + PartialEq + Send + Sync + Serialize + DeserializeOwned,
+{
+    type Op = LWWMapOp<K, V>;
+
+    fn empty(_node: usize) -> Self {
+        LWWMap::new()
+    }
+
+    fn apply(&mut self, _node: usize, op: &LWWMapOp<K, V>) {
+        match op {
+            LWWMapOp::Set(key, value, stamp) => self.set(key.clone(), value.clone(), *stamp),
+            LWWMapOp::Remove(key, stamp) => self.remove(key.clone(), *stamp),
+        }
+    }
+
+    fn prune_tombstones(&mut self, stable: Timestamp) -> usize {
+        LWWMap::prune_tombstones(self, stable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(event_type: &str, payload: impl Serialize) -> Event {
+        let payload = EventPayload::from_json(&payload).unwrap();
+        let ts = Timestamp::from_secs(1);
+        Event::new(
+            event_type.to_string(),
+            ts,
+            "metric:views".to_string(),
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_folds_ops_and_merged_states() {
+        let op = |node, op| CrdtOpRecord { node, op };
+        let mut remote = GCounter::empty(3);
+        let increment = remote.persisted_op(3, GCounterOp::Increment(10));
+        assert_eq!(increment, GCounterOp::Total(10));
+        remote.apply(3, &increment);
+        let events = [
+            event(CRDT_OP, op(1, GCounterOp::Increment(2))),
+            event(CRDT_MERGE, &remote),
+            // The operation behind the merged state, replicated as well,
+            // counts only once.
+            event(CRDT_OP, op(3, increment)),
+            event(CRDT_OP, op(1, GCounterOp::Total(7))),
+            event(CRDT_MERGE, &remote),
+        ];
+        let counter: GCounter = fold_crdt(1, &events).unwrap();
+        assert_eq!(counter.value(), 17);
+        assert!(events.iter().all(is_crdt_event));
+
+        let add = |node, element| CrdtOpRecord { node, op: element };
+        let adds = [event(CRDT_OP, add(1, "a")), event(CRDT_OP, add(2, "b"))];
+        let set: GSet<String> = fold_crdt(1, &adds).unwrap();
+        assert!(set.contains(&"a".to_string()) && set.elements().len() == 2);
+        let value = event("value.changed", 1);
+        assert!(fold_crdt::<GCounter>(1, &[value]).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Law checks for CRDT implementations
+//!
+//! Replicas converge only if merging is commutative, associative and
+//! idempotent: the same states merged in any order, grouping or number of
+//! times give the same result. [`check`] property-tests the three laws on
+//! states generated by [`Arbitrary`], comparing results with
+//! [`CRDT::equals`], so custom CRDTs can be validated before they back an
+//! entity:
+//!
+//! ```ignore
+//! #[test]
+//! fn my_counter_is_a_crdt() {
+//!     temporal_db::crdt::laws::check::<MyCounter>();
+//! }
+//! ```
+//!
+//! Generated states must be ones replicas can reach; e.g. two states that
+//! hold a write with the same clock stamp must hold the same write.
+
+use crate::crdt::types::{GCounter, GSet, CRDT};
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Number of generated cases per law
+pub const DEFAULT_TESTS: u64 = 200;
+
+/// Property-test the merge laws of `T` with [`DEFAULT_TESTS`] cases each;
+/// panics with a counterexample if one fails
+pub fn check<T: CRDT + Arbitrary + Debug>() {
+    check_with::<T>(DEFAULT_TESTS);
+}
+
+/// Property-test the merge laws of `T` with `tests` cases each
+pub fn check_with<T: CRDT + Arbitrary + Debug>(tests: u64) {
+    let mut qc = QuickCheck::new().tests(tests);
+    qc.quickcheck(merge_commutes::<T> as fn(T, T) -> bool);
+    qc.quickcheck(merge_associates::<T> as fn(T, T, T) -> bool);
+    qc.quickcheck(merge_idempotent::<T> as fn(T, T) -> bool);
+}
+
+/// `a ⊔ b == b ⊔ a`
+pub fn merge_commutes<T: CRDT>(a: T, b: T) -> bool {
+    merged(&a, &b).equals(&merged(&b, &a))
+}
+
+/// `(a ⊔ b) ⊔ c == a ⊔ (b ⊔ c)`
+pub fn merge_associates<T: CRDT>(a: T, b: T, c: T) -> bool {
+    merged(&merged(&a, &b), &c).equals(&merged(&a, &merged(&b, &c)))
+}
+
+/// `a ⊔ a == a`, and merging `b` a second time changes nothing
+pub fn merge_idempotent<T: CRDT>(a: T, b: T) -> bool {
+    let once = merged(&a, &b);
+    merged(&a, &a).equals(&a) && merged(&once, &b).equals(&once)
+}
+
+fn merged<T: CRDT>(a: &T, b: &T) -> T {
+    let mut merged = a.clone();
+    merged.merge(b);
+    merged
+}
+
+impl Arbitrary for GCounter {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let nodes = usize::arbitrary(g) % 4 + 1;
+        let mut counter = GCounter::new(usize::arbitrary(g) % nodes, nodes);
+        for node in 0..nodes {
+            counter.increment_node(node, u64::from(u16::arbitrary(g)));
+        }
+        counter
+    }
+}
+
+impl<T: Arbitrary + Hash + Eq + Send + Sync> Arbitrary for GSet<T> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let mut set = GSet::new();
+        for element in Vec::<T>::arbitrary(g) {
+            set.add(element);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hlc::HlcTimestamp;
+    use crate::core::temporal::Timestamp;
+    use crate::crdt::{LWWMap, ORSet};
+
+    fn stamp(tick: u8) -> HlcTimestamp {
+        HlcTimestamp::new(Timestamp::from_secs(i64::from(tick)), 0, 0)
+    }
+
+    // Writes are derived from their stamps, so states that share a stamp
+    // agree on the write, as replicas do.
+    impl Arbitrary for ORSet<u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let mut set = ORSet::new();
+            for (tick, removed) in Vec::<(u8, bool)>::arbitrary(g) {
+                let element = tick % 8;
+                set.add(element, stamp(tick));
+                if removed {
+                    set.remove(&element, stamp(tick.saturating_add(16)));
+                }
+            }
+            set
+        }
+    }
+
+    impl Arbitrary for LWWMap<u8, u8> {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let mut map = LWWMap::new();
+            for (key, tick) in Vec::<(u8, u8)>::arbitrary(g) {
+                match tick % 3 {
+                    0 => map.remove(key, stamp(tick)),
+                    _ => map.set(key, key ^ tick, stamp(tick)),
+                }
+            }
+            map
+        }
+    }
+
+    #[test]
+    fn test_crate_crdts_obey_merge_laws() {
+        check::<GCounter>();
+        check::<GSet<u8>>();
+        check::<ORSet<u8>>();
+        check::<LWWMap<u8, u8>>();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_detects_non_commutative_merge() {
+        #[derive(Debug, Clone)]
+        struct Overwrite(u8);
+
+        impl CRDT for Overwrite {
+            fn merge(&mut self, other: &Self) {
+                self.0 = other.0;
+            }
+
+            fn equals(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl Arbitrary for Overwrite {
+            fn arbitrary(g: &mut Gen) -> Self {
+                Overwrite(u8::arbitrary(g))
+            }
+        }
+
+        check::<Overwrite>();
+    }
+}
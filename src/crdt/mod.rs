@@ -1,7 +1,12 @@
 //! Conflict-free Replicated Data Types (CRDTs)
 
+pub mod causal;
+pub mod entity;
+pub mod laws;
 pub mod resolver;
 pub mod types;
 
+pub use causal::*;
+pub use entity::*;
 pub use resolver::*;
 pub use types::*;
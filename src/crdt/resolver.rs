@@ -1,10 +1,332 @@
 //! CRDT conflict resolution
+//!
+//! CRDT values merge without conflicts, but plain values replicated from
+//! other nodes can clash: two writes of the same entity at the same valid
+//! time with different payloads. A [`ResolutionPolicy`], chosen per entity
+//! type in [`ConflictPolicies`], decides which write the entity keeps, and
+//! [`TemporalDB::apply_remote_events`](crate::TemporalDB::apply_remote_events)
+//! records every decision as a [`CONFLICT_RESOLVED`] event on an internal
+//! companion entity (`$sys:conflict:<entity_id>`).
 
+use crate::core::aggregate::entity_type;
+use crate::core::event::{Event, EventId, EventOrdering};
+use crate::core::temporal::Timestamp;
 use crate::crdt::types::CRDT;
 use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of the companion entity recording an entity's resolved conflicts
+pub const CONFLICT_ENTITY_PREFIX: &str = "$sys:conflict:";
+
+/// Event type recorded when a conflict is resolved
+pub const CONFLICT_RESOLVED: &str = "conflict.resolved";
 
 /// Resolve conflicts between CRDT instances
 pub fn resolve_conflict<T: CRDT>(local: &mut T, remote: &T) -> Result<()> {
     local.merge(remote);
     Ok(())
 }
+
+/// A local and a remote write of the same entity at the same valid time
+/// with different payloads
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    /// Write this node holds, the latest at the valid time
+    pub local: Event,
+    /// Write received from another node
+    pub remote: Event,
+}
+
+impl Conflict {
+    /// Entity both writes belong to
+    pub fn entity_id(&self) -> &str {
+        self.local.entity_id()
+    }
+
+    /// Valid time of both writes
+    pub fn valid_time(&self) -> Timestamp {
+        self.local.timestamp()
+    }
+
+    /// How the local write compares to the remote one by clock stamp, then
+    /// transaction time, then event ID; the same on every node
+    pub fn compare_writes(&self) -> Ordering {
+        EventOrdering::HybridClock.compare(&self.local, &self.remote)
+    }
+}
+
+/// Which write a conflicting entity keeps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Resolution {
+    /// Keep the local write and drop the remote one
+    KeepLocal,
+    /// Replace the local write with the remote one
+    TakeRemote,
+    /// Replace both with a new value
+    Merged(serde_json::Value),
+    /// Leave the local write in place for now; the conflict is resolved
+    /// later, e.g. by an operator
+    Deferred,
+}
+
+/// Decides conflicts between local and remote writes
+pub trait ResolutionPolicy: Send + Sync {
+    /// Name recorded with every resolution
+    fn name(&self) -> &str;
+
+    /// Resolve `conflict`
+    fn resolve(&self, conflict: &Conflict) -> Resolution;
+}
+
+/// Keep the later write, see [`Conflict::compare_writes`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastWriterWins;
+
+impl ResolutionPolicy for LastWriterWins {
+    fn name(&self) -> &str {
+        "last_writer_wins"
+    }
+
+    fn resolve(&self, conflict: &Conflict) -> Resolution {
+        match conflict.compare_writes() {
+            Ordering::Less => Resolution::TakeRemote,
+            _ => Resolution::KeepLocal,
+        }
+    }
+}
+
+/// Keep the earlier write, see [`Conflict::compare_writes`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstWriterWins;
+
+impl ResolutionPolicy for FirstWriterWins {
+    fn name(&self) -> &str {
+        "first_writer_wins"
+    }
+
+    fn resolve(&self, conflict: &Conflict) -> Resolution {
+        match conflict.compare_writes() {
+            Ordering::Greater => Resolution::TakeRemote,
+            _ => Resolution::KeepLocal,
+        }
+    }
+}
+
+/// Resolve conflicts with a closure
+pub struct Custom<F> {
+    name: String,
+    resolve: F,
+}
+
+impl<F> Custom<F>
+where
+    F: Fn(&Conflict) -> Resolution + Send + Sync,
+{
+    /// Policy named `name` resolving conflicts with `resolve`
+    pub fn new(name: impl Into<String>, resolve: F) -> Self {
+        Self {
+            name: name.into(),
+            resolve,
+        }
+    }
+}
+
+impl<F> ResolutionPolicy for Custom<F>
+where
+    F: Fn(&Conflict) -> Resolution + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn resolve(&self, conflict: &Conflict) -> Resolution {
+        (self.resolve)(conflict)
+    }
+}
+
+impl<F> fmt::Debug for Custom<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Custom")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Defer every conflict to a queue resolved by hand; clones share the
+/// queue
+#[derive(Debug, Clone, Default)]
+pub struct ManualConflictQueue {
+    pending: Arc<Mutex<Vec<Conflict>>>,
+}
+
+impl ManualConflictQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of conflicts waiting
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether no conflict is waiting
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Remove and return the conflicts waiting, oldest first
+    pub fn take(&self) -> Vec<Conflict> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Conflict>> {
+        self.pending
+            .lock()
+            .expect("ManualConflictQueue poisoned lock")
+    }
+}
+
+impl ResolutionPolicy for ManualConflictQueue {
+    fn name(&self) -> &str {
+        "manual"
+    }
+
+    fn resolve(&self, conflict: &Conflict) -> Resolution {
+        self.lock().push(conflict.clone());
+        Resolution::Deferred
+    }
+}
+
+/// Resolution policies by entity type, falling back to
+/// [`LastWriterWins`]
+#[derive(Clone)]
+pub struct ConflictPolicies {
+    by_type: HashMap<String, Arc<dyn ResolutionPolicy>>,
+    default: Arc<dyn ResolutionPolicy>,
+}
+
+impl ConflictPolicies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve conflicts of entities of `entity_type` with `policy`
+    pub fn set(&mut self, entity_type: &str, policy: Arc<dyn ResolutionPolicy>) {
+        self.by_type.insert(entity_type.to_string(), policy);
+    }
+
+    /// Resolve conflicts of entity types without a policy of their own with
+    /// `policy`
+    pub fn set_default(&mut self, policy: Arc<dyn ResolutionPolicy>) {
+        self.default = policy;
+    }
+
+    /// Policy resolving conflicts of `entity_id`
+    pub fn for_entity(&self, entity_id: &str) -> Arc<dyn ResolutionPolicy> {
+        self.by_type
+            .get(entity_type(entity_id))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl Default for ConflictPolicies {
+    fn default() -> Self {
+        Self {
+            by_type: HashMap::new(),
+            default: Arc::new(LastWriterWins),
+        }
+    }
+}
+
+impl fmt::Debug for ConflictPolicies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut types: Vec<(&str, &str)> = self
+            .by_type
+            .iter()
+            .map(|(t, policy)| (t.as_str(), policy.name()))
+            .collect();
+        types.sort();
+        f.debug_struct("ConflictPolicies")
+            .field("by_type", &types)
+            .field("default", &self.default.name())
+            .finish()
+    }
+}
+
+/// Payload of a [`CONFLICT_RESOLVED`] event, recorded at the valid time of
+/// the conflict
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    /// Entity the writes belong to
+    pub entity_id: String,
+    /// Write the node held
+    pub local: EventId,
+    /// Write received from another node
+    pub remote: EventId,
+    /// Name of the policy that decided
+    pub policy: String,
+    /// Decision taken
+    pub resolution: Resolution,
+}
+
+impl ConflictRecord {
+    /// Companion entity ID under which conflicts of `entity_id` are
+    /// recorded
+    pub fn entity_for(entity_id: &str) -> String {
+        format!("{}{}", CONFLICT_ENTITY_PREFIX, entity_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn write(entity_id: &str, value: &str, tx_secs: i64) -> Event {
+        let payload = EventPayload::from_json(&value).unwrap();
+        let ts = Timestamp::from_secs(10);
+        let mut event = Event::new(
+            "value.changed".to_string(),
+            ts,
+            entity_id.to_string(),
+            payload,
+        );
+        event.metadata.transaction_time = Timestamp::from_secs(tx_secs);
+        event
+    }
+
+    #[test]
+    fn test_policies_resolve_by_entity_type() {
+        let queue = ManualConflictQueue::new();
+        let mut policies = ConflictPolicies::new();
+        policies.set("stock", Arc::new(FirstWriterWins));
+        policies.set("audit", Arc::new(queue.clone()));
+        let merge = Custom::new("concat", |c: &Conflict| {
+            let local: String = c.local.payload().to_json().unwrap();
+            let remote: String = c.remote.payload().to_json().unwrap();
+            Resolution::Merged(serde_json::json!(format!("{}+{}", local, remote)))
+        });
+        policies.set("note", Arc::new(merge));
+
+        let resolve = |entity_id: &str| {
+            let conflict = Conflict {
+                local: write(entity_id, "a", 100),
+                remote: write(entity_id, "b", 200),
+            };
+            policies.for_entity(entity_id).resolve(&conflict)
+        };
+        assert_eq!(resolve("order:1"), Resolution::TakeRemote);
+        assert_eq!(resolve("stock:1"), Resolution::KeepLocal);
+        assert_eq!(resolve("note:1"), Resolution::Merged("a+b".into()));
+        assert_eq!(resolve("audit:1"), Resolution::Deferred);
+        let deferred = queue.take();
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].entity_id(), "audit:1");
+        assert!(queue.is_empty());
+    }
+}
@@ -1,8 +1,9 @@
 //! CRDT type implementations
 
+use crate::core::hlc::HlcTimestamp;
 use crate::core::temporal::Timestamp;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 /// Trait for CRDT types
 pub trait CRDT: Clone + Send + Sync {
@@ -13,44 +14,62 @@ pub trait CRDT: Clone + Send + Sync {
     fn equals(&self, other: &Self) -> bool;
 }
 
-/// Last-Writer-Wins Register
+/// Last-Writer-Wins Register.
+///
+/// Writes are ordered by their [`HlcTimestamp`], so concurrent writes at
+/// the same wall-clock time resolve the same way on every replica: by
+/// logical counter, then by node.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LWWRegister<T> {
     value: T,
-    timestamp: Timestamp,
+    stamp: HlcTimestamp,
 }
 
 impl<T: Clone + PartialEq> LWWRegister<T> {
     pub fn new(value: T, timestamp: Timestamp) -> Self {
-        Self { value, timestamp }
+        Self::new_stamped(value, timestamp.into())
+    }
+
+    /// Register holding `value` written at `stamp`
+    pub fn new_stamped(value: T, stamp: HlcTimestamp) -> Self {
+        Self { value, stamp }
     }
 
     pub fn value(&self) -> &T {
         &self.value
     }
 
+    /// Wall-clock time of the last write
     pub fn timestamp(&self) -> Timestamp {
-        self.timestamp
+        self.stamp.physical
+    }
+
+    /// Clock stamp of the last write
+    pub fn stamp(&self) -> HlcTimestamp {
+        self.stamp
     }
 
     pub fn set(&mut self, value: T, timestamp: Timestamp) {
-        if timestamp >= self.timestamp {
+        self.set_stamped(value, timestamp.into());
+    }
+
+    /// Write `value` at `stamp` unless a later write is held; a write with
+    /// an equal stamp is the one already held
+    pub fn set_stamped(&mut self, value: T, stamp: HlcTimestamp) {
+        if stamp > self.stamp {
             self.value = value;
-            self.timestamp = timestamp;
+            self.stamp = stamp;
         }
     }
 }
 
 impl<T: Clone + PartialEq + Send + Sync> CRDT for LWWRegister<T> {
     fn merge(&mut self, other: &Self) {
-        if other.timestamp >= self.timestamp {
-            self.value = other.value.clone();
-            self.timestamp = other.timestamp;
-        }
+        self.set_stamped(other.value.clone(), other.stamp);
     }
 
     fn equals(&self, other: &Self) -> bool {
-        self.value == other.value && self.timestamp == other.timestamp
+        self.value == other.value && self.stamp == other.stamp
     }
 }
 
@@ -139,7 +158,28 @@ impl GCounter {
     }
 
     pub fn increment(&mut self, by: u64) {
-        self.counts[self.node_id] += by;
+        self.increment_node(self.node_id, by);
+    }
+
+    /// Count `by` for the replica of `node`, e.g. replaying its operations
+    pub fn increment_node(&mut self, node: usize, by: u64) {
+        if self.counts.len() <= node {
+            self.counts.resize(node + 1, 0);
+        }
+        self.counts[node] += by;
+    }
+
+    /// Raise the count of the replica of `node` to `total` if it is lower
+    pub fn raise_node(&mut self, node: usize, total: u64) {
+        if self.counts.len() <= node {
+            self.counts.resize(node + 1, 0);
+        }
+        self.counts[node] = self.counts[node].max(total);
+    }
+
+    /// Count of the replica of `node`
+    pub fn count_of(&self, node: usize) -> u64 {
+        self.counts.get(node).copied().unwrap_or(0)
     }
 
     pub fn value(&self) -> u64 {
@@ -149,10 +189,12 @@ impl GCounter {
 
 impl CRDT for GCounter {
     fn merge(&mut self, other: &Self) {
+        // Replicas may know of different numbers of nodes.
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
         for (i, count) in other.counts.iter().enumerate() {
-            if i < self.counts.len() {
-                self.counts[i] = self.counts[i].max(*count);
-            }
+            self.counts[i] = self.counts[i].max(*count);
         }
     }
 
@@ -160,3 +202,317 @@ impl CRDT for GCounter {
         self.counts == other.counts
     }
 }
+
+/// Observed-Remove Set.
+///
+/// Every add is tagged with the [`HlcTimestamp`] of the write; a remove
+/// deletes the tags it has observed, so an add concurrent with a remove
+/// survives it. Removed tags are kept as tombstones, stamped with the
+/// removal, so a replica that has not seen the removal yet cannot bring
+/// the element back by merging; see [`ORSet::prune_tombstones`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
+pub struct ORSet<T> {
+    /// Element of every live add, by tag
+    #[serde(with = "pairs")]
+    adds: BTreeMap<HlcTimestamp, T>,
+    /// Stamp of the removal of every removed tag
+    #[serde(with = "pairs")]
+    removed: BTreeMap<HlcTimestamp, HlcTimestamp>,
+}
+
+impl<T: Clone + PartialEq> ORSet<T> {
+    pub fn new() -> Self {
+        Self {
+            adds: BTreeMap::new(),
+            removed: BTreeMap::new(),
+        }
+    }
+
+    /// Add `element` with the tag `stamp`
+    pub fn add(&mut self, element: T, stamp: HlcTimestamp) {
+        if !self.removed.contains_key(&stamp) {
+            self.adds.insert(stamp, element);
+        }
+    }
+
+    /// Remove the adds of `element` observed so far; returns whether there
+    /// were any
+    pub fn remove(&mut self, element: &T, stamp: HlcTimestamp) -> bool {
+        let tags: Vec<HlcTimestamp> = self
+            .adds
+            .iter()
+            .filter(|(_, e)| *e == element)
+            .map(|(tag, _)| *tag)
+            .collect();
+        for tag in &tags {
+            self.adds.remove(tag);
+            self.removed.insert(*tag, stamp);
+        }
+        !tags.is_empty()
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.adds.values().any(|e| e == element)
+    }
+
+    /// Elements in the set, in the order they were first added
+    pub fn elements(&self) -> Vec<&T> {
+        let mut elements: Vec<&T> = Vec::new();
+        for element in self.adds.values() {
+            if !elements.contains(&element) {
+                elements.push(element);
+            }
+        }
+        elements
+    }
+
+    /// Number of tombstones kept
+    pub fn tombstones(&self) -> usize {
+        self.removed.len()
+    }
+
+    /// Drop the tombstones of removals before `stable`, a time every
+    /// replica has seen (see
+    /// [`ClusterStatus::stability_watermark`](crate::distributed::ClusterStatus::stability_watermark));
+    /// returns how many were dropped
+    pub fn prune_tombstones(&mut self, stable: Timestamp) -> usize {
+        let before = self.removed.len();
+        self.removed.retain(|_, removal| removal.physical >= stable);
+        before - self.removed.len()
+    }
+}
+
+impl<T: Clone + PartialEq + Send + Sync> CRDT for ORSet<T> {
+    fn merge(&mut self, other: &Self) {
+        for (tag, removal) in &other.removed {
+            self.adds.remove(tag);
+            let kept = self.removed.entry(*tag).or_insert(*removal);
+            *kept = (*kept).max(*removal);
+        }
+        for (tag, element) in &other.adds {
+            if !self.removed.contains_key(tag) {
+                self.adds.insert(*tag, element.clone());
+            }
+        }
+    }
+
+    fn equals(&self, other: &Self) -> bool {
+        self.adds == other.adds
+    }
+}
+
+impl<T: Clone + PartialEq> Default for ORSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Map of Last-Writer-Wins registers.
+///
+/// A removed key keeps a register without a value, a tombstone, so an
+/// older write merged later does not bring it back; see
+/// [`LWWMap::prune_tombstones`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "K: Serialize, V: Serialize",
+    deserialize = "K: Deserialize<'de> + Ord, V: Deserialize<'de>"
+))]
+pub struct LWWMap<K, V> {
+    #[serde(with = "pairs")]
+    entries: BTreeMap<K, LWWRegister<Option<V>>>,
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> LWWMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Write `value` under `key` at `stamp` unless a later write is held
+    pub fn set(&mut self, key: K, value: V, stamp: HlcTimestamp) {
+        self.write(key, Some(value), stamp);
+    }
+
+    /// Remove `key` at `stamp` unless a later write is held
+    pub fn remove(&mut self, key: K, stamp: HlcTimestamp) {
+        self.write(key, None, stamp);
+    }
+
+    fn write(&mut self, key: K, value: Option<V>, stamp: HlcTimestamp) {
+        match self.entries.get_mut(&key) {
+            Some(register) => register.set_stamped(value, stamp),
+            None => {
+                self.entries
+                    .insert(key, LWWRegister::new_stamped(value, stamp));
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(|r| r.value().as_ref())
+    }
+
+    /// Keys with a value and their values, in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(k, r)| r.value().as_ref().map(|v| (k, v)))
+    }
+
+    /// Number of keys with a value
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of tombstones kept
+    pub fn tombstones(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|r| r.value().is_none())
+            .count()
+    }
+
+    /// Drop the tombstones of removals before `stable`, a time every
+    /// replica has seen; returns how many were dropped
+    pub fn prune_tombstones(&mut self, stable: Timestamp) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, r| r.value().is_some() || r.timestamp() >= stable);
+        before - self.entries.len()
+    }
+}
+
+impl<K, V> CRDT for LWWMap<K, V>
+where
+    K: Ord + Clone + Send + Sync,
+    V: Clone + PartialEq + Send + Sync,
+{
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.entries {
+            match self.entries.get_mut(key) {
+                Some(local) => local.merge(register),
+                None => {
+                    self.entries.insert(key.clone(), register.clone());
+                }
+            }
+        }
+    }
+
+    fn equals(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<K: Ord + Clone, V: Clone + PartialEq> Default for LWWMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialize maps as sequences of pairs, for keys JSON objects cannot hold
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::hlc::HybridClock;
+
+    #[test]
+    fn test_lww_register_breaks_ties_by_clock() {
+        let wall = Timestamp::from_secs(10);
+        let (a, b) = (HybridClock::new(1), HybridClock::new(2));
+        let mut left = LWWRegister::new_stamped("a1", a.now_at(wall));
+        let mut right = LWWRegister::new_stamped("b1", b.now_at(wall));
+        right.set_stamped("b2", b.now_at(wall));
+        let snapshot = left.clone();
+        left.merge(&right);
+        right.merge(&snapshot);
+        assert!(left.equals(&right));
+        assert_eq!(*left.value(), "b2");
+
+        // The same write merged again changes nothing.
+        left.merge(&left.clone());
+        assert_eq!(left.stamp().logical, 1);
+        // Plain timestamps order before clock stamps at the same time.
+        left.set("plain", wall);
+        assert_eq!(*left.value(), "b2");
+        left.set("later", Timestamp::from_secs(11));
+        assert_eq!(
+            (*left.value(), left.timestamp()),
+            ("later", Timestamp::from_secs(11))
+        );
+    }
+
+    #[test]
+    fn test_tombstones_pruned_once_stable() {
+        let (a, b) = (HybridClock::new(1), HybridClock::new(2));
+        let wall = |secs| Timestamp::from_secs(secs);
+        let mut left = ORSet::new();
+        left.add("x", a.now_at(wall(1)));
+        let mut right = left.clone();
+        // A remove concurrent with a re-add keeps the re-added element.
+        left.remove(&"x", a.now_at(wall(2)));
+        right.add("x", b.now_at(wall(2)));
+        let stale = left.clone();
+        left.merge(&right);
+        assert_eq!(left.elements(), [&"x"]);
+        left.remove(&"x", a.now_at(wall(3)));
+        assert_eq!(left.tombstones(), 2);
+
+        assert_eq!(left.prune_tombstones(wall(3)), 1);
+        assert_eq!(left.prune_tombstones(wall(4)), 1);
+        left.merge(&stale);
+        assert!(!left.contains(&"x"));
+        let json = serde_json::to_string(&right).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ORSet<String>>(&json)
+                .unwrap()
+                .elements()
+                .len(),
+            1
+        );
+
+        let mut map = LWWMap::new();
+        map.set("k", 1, a.now_at(wall(5)));
+        map.set("gone", 2, a.now_at(wall(5)));
+        let old = map.clone();
+        map.remove("gone", a.now_at(wall(6)));
+        map.merge(&old);
+        assert_eq!(
+            (map.len(), map.get(&"gone"), map.tombstones()),
+            (1, None, 1)
+        );
+        assert_eq!(map.prune_tombstones(wall(6)), 0);
+        assert_eq!(map.prune_tombstones(wall(7)), 1);
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(&"k", &1)]);
+    }
+}
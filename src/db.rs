@@ -1,11 +1,104 @@
 //! Main database implementation
 
-use crate::core::event::{Event, EventPayload};
-use crate::core::temporal::Timestamp;
+use crate::backup::{backup_full, backup_incremental, BackupManifest};
+use crate::config::{Config, JournalKind, TemporalDBBuilder};
+use crate::core::aggregate::{
+    entity_type, Aggregate, AggregateRegistry, AggregateSnapshot, AGGREGATE_SNAPSHOT,
+    AGGREGATE_SNAPSHOT_INVALIDATED,
+};
+use crate::core::event::{
+    causal_order, Event, EventId, EventOrdering, EventPayload, CORRECTION_TAG, ENTITY_DELETED,
+};
+use crate::core::event_bus::{EventBus, EventHandler, HandlerId};
+use crate::core::filter::EventFilter;
+use crate::core::hierarchy::{HierarchyIndex, ParentLink, PARENT_CHANGED};
+use crate::core::hlc::HybridClock;
+use crate::core::lease::{Lease, LEASE_ACQUIRED, LEASE_RELEASED, LEASE_RENEWED};
+use crate::core::namespace::{is_internal_entity, INTERNAL_ENTITY_PREFIX};
+use crate::core::numeric::{NumericPayload, NUMERIC_SAMPLE};
+use crate::core::schema::{EntitySchema, SchemaRegistry, SchemaReport};
+use crate::core::subscription::{EventBroadcaster, SubscriptionFilter};
+use crate::core::temporal::{TemporalValue, TimePeriod, Timestamp};
+use crate::core::trigger::{EffectiveNotification, TriggerFilter, TriggerId, TriggerRegistry};
+use crate::crdt::{
+    fold_crdt, is_crdt_event, Conflict, ConflictPolicies, ConflictRecord, CrdtEntity, CrdtOpRecord,
+    Resolution, ResolutionPolicy, CONFLICT_RESOLVED, CRDT_GC, CRDT_MERGE, CRDT_OP, CRDT_TAG,
+};
+use crate::distributed::{
+    NegotiatedVersions, NodeId, PreparedPart, PreparedTransactions, TxnId, TXN_DECIDED,
+    TXN_FINISHED, TXN_PREPARED,
+};
 use crate::error::{Error, Result};
-use crate::storage::{EventJournal, InMemoryJournal, InMemoryMaterializedView, MaterializedView};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::export::{
+    SnapshotEntity, SnapshotEvent, SnapshotManifest, SnapshotOptions, SnapshotWriter,
+};
+use crate::index::{
+    IndexAdvisor, ProjectedEvent, RebuildOptions, RebuildProgress, SecondaryIndex, WorkloadTracker,
+};
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::outbox::{
+    outbox_tag, OutboxCheckpoint, OutboxSource, OUTBOX_BATCH_SIZE, OUTBOX_CHECKPOINT,
+    OUTBOX_ENTITY_PREFIX,
+};
+use crate::query::{
+    count_events_by_entity, execute_plan, read_indexed, stream_entity_range, AccessPath,
+    HyperLogLog, MemoryBudget, MemoryTracker, OffsetFn, OffsetRow, Optimizer, ParamSlot,
+    ParamValue, PreparedQuery, Principal, QueryCacheStats, QueryPlan, QueryPlanner,
+    QueryResultCache, QueryStatistics, QueryStream, ReservoirSampler, SecurityPolicy, Statistics,
+    TDigest, TemporalQuery, WindowSpec, HISTOGRAM_BUCKET,
+};
+use crate::storage::journal::truncate_after_ties;
+use crate::storage::{
+    AggFn, AsyncFileWAL, AsyncWriteAheadLog, CompareOp, EntityPage, EventCursor, EventJournal,
+    EventPage, FieldPath, FieldPredicate, FieldScan, FieldValue, HotEntity, HotEntityCache,
+    HotEntityConfig, HotSet, InMemoryJournal, InMemoryMaterializedView, Lsn, MaterializedView,
+    RetentionPolicy, RetentionReport, ScrubPolicy, ScrubReport, SegmentedJournal, ViewSnapshot,
+    ViewSnapshotPolicy, ViewSnapshotter, WarmupReport, WindowAggregator, WindowBucket,
+};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify, RwLock};
+
+/// Subdirectory of a data directory holding segment files and the manifest
+pub const SEGMENTS_DIR: &str = "segments";
+
+/// File name of the write-ahead log inside a data directory
+pub const WAL_FILE: &str = "wal.log";
+
+/// Prefix of the entity holding a projection's checkpoint events
+pub const PROJECTION_ENTITY_PREFIX: &str = "$sys:projection:";
+
+/// Event type recorded when a projection checkpoint is saved
+pub const PROJECTION_CHECKPOINT: &str = "projection.checkpoint";
+
+/// Entities read from the catalog at a time when visiting every entity
+const CATALOG_PAGE_SIZE: usize = 1024;
+
+/// Events a read-only mount replays into its view at a time
+const MOUNT_BATCH: usize = 1024;
+
+/// How often the background job of a segmented database enforces retention
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the background job of a segmented database scrubs segments
+pub const SCRUB_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often the background job of a segmented database checks whether a
+/// view snapshot is due
+pub const VIEW_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the background job of a segmented database records its hot
+/// set for warmup on the next open
+pub const HOT_SET_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest the trigger job sleeps before checking for due facts again
+pub const TRIGGER_POLL_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Main temporal database
 pub struct TemporalDB {
@@ -13,18 +106,518 @@ pub struct TemporalDB {
     journal: Arc<RwLock<dyn EventJournal>>,
     /// Current state cache / materialized view
     view: Arc<dyn MaterializedView>,
+    /// Access tracking and pinned timelines for hot entities
+    hot: Arc<HotEntityCache>,
+    /// Whether writes are rejected
+    read_only: bool,
+    /// Serializes lease check-and-append so two callers cannot both win
+    lease_lock: tokio::sync::Mutex<()>,
+    /// Serializes local CRDT operations, persisted relative to the state
+    /// before them
+    crdt_lock: tokio::sync::Mutex<()>,
+    /// Latency histograms and counters
+    metrics: Arc<Metrics>,
+    /// Clock committed events are stamped with, if configured
+    clock: Option<HybridClock>,
+    /// Settings the database was opened with
+    config: Config,
+    /// Retention policy enforced by `enforce_retention` and the background job
+    retention: Arc<Mutex<RetentionPolicy>>,
+    /// Scrub policy applied by `scrub` and the background job
+    scrub: Arc<Mutex<ScrubPolicy>>,
+    /// Payload field lookups served, for index recommendations
+    workload: WorkloadTracker,
+    /// Memory each query may buffer before spilling or failing
+    query_memory: Mutex<MemoryBudget>,
+    /// Transaction time up to which every write replicated to this node
+    /// was applied, by source
+    replication_watermarks: Mutex<HashMap<String, Timestamp>>,
+    /// Cross-shard transactions prepared here, awaiting commit or abort
+    prepared: Mutex<PreparedTransactions>,
+    /// Row-level security applied to sessions
+    security: Mutex<Arc<SecurityPolicy>>,
+    /// Parent-child relationships, indexed from relationship events
+    hierarchy: Mutex<HierarchyIndex>,
+    /// Effective-at triggers and the future-valid facts they wait for
+    triggers: Arc<Mutex<TriggerRegistry>>,
+    /// Wakes the trigger job when an earlier fact is scheduled
+    trigger_wake: Arc<Notify>,
+    /// Started with the first registered trigger
+    trigger_job: std::sync::Once,
+    /// Pushes committed events to live subscriptions
+    subscriptions: EventBroadcaster,
+    /// Delivers committed events to in-process handlers
+    event_bus: EventBus,
+    /// Statistics and indexes queries are planned with
+    planner: Arc<Mutex<QueryPlanner>>,
+    /// Results of recent queries, dropped when new events change them
+    query_cache: Arc<QueryResultCache>,
+    /// Aggregates by entity type and their snapshot bookkeeping
+    aggregates: Mutex<AggregateRegistry>,
+    /// Payload schemas and typed-store bindings by entity type
+    schemas: Mutex<SchemaRegistry>,
+    /// How clashing writes replicated from other nodes are resolved
+    conflict_policies: Mutex<ConflictPolicies>,
+    /// Applies commits to `view` and snapshots it, for segmented databases
+    view_snapshots: Option<Arc<ViewSnapshotter>>,
 }
 
 impl TemporalDB {
+    /// Configure and open a database, see [`TemporalDBBuilder`]
+    pub fn builder() -> TemporalDBBuilder {
+        TemporalDBBuilder::new()
+    }
+
+    /// Open a database with the given settings.
+    ///
+    /// A segmented journal recovers the data already in its directory: the
+    /// segment catalog, unflushed WAL records and the current-state view,
+    /// which is loaded from its latest snapshot plus the events after it.
+    pub async fn open_with_config(config: Config) -> Result<Self> {
+        config.validate()?;
+        let view: Arc<dyn MaterializedView> = Arc::new(InMemoryMaterializedView::new());
+        let mut view_snapshots = None;
+        let mut hierarchy = HierarchyIndex::new();
+        let mut triggers = TriggerRegistry::new(Timestamp::now());
+        let mut planner = QueryPlanner::new();
+        let mut prepared = PreparedTransactions::default();
+        let journal: Arc<RwLock<dyn EventJournal>> = match (config.journal, &config.data_dir) {
+            (JournalKind::Segmented, Some(dir)) => {
+                let mut wal = AsyncFileWAL::open(dir.join(WAL_FILE))
+                    .await?
+                    .with_sync_policy(config.wal_sync);
+                if let Some(compression) = config.wal_compression {
+                    wal = wal.with_compression(compression);
+                }
+                if let Some(keys) = &config.wal_encryption {
+                    wal = wal.with_key_provider(Arc::new(keys.key_ring()?));
+                }
+                if let Some(version) = config.wal_write_version {
+                    wal.set_write_version(version).await?;
+                }
+                let journal = SegmentedJournal::open(dir.join(SEGMENTS_DIR), wal)
+                    .await?
+                    .with_ordering(config.ordering)
+                    .with_block_cache_capacity(config.block_cache_blocks)
+                    .with_scan_parallelism(config.scan_parallelism);
+                triggers = TriggerRegistry::open(dir, Timestamp::now())?;
+                // Stream the history block by block rather than loading it.
+                journal.visit_events_after(0, |_, event| {
+                    hierarchy.apply_event(event);
+                    triggers.schedule(event);
+                    if is_internal_entity(event.entity_id()) {
+                        prepared.observe(event);
+                    } else {
+                        planner.observe(event);
+                    }
+                    ControlFlow::Continue(())
+                })?;
+                let snapshotter = ViewSnapshotter::new(
+                    dir.join(SEGMENTS_DIR),
+                    view.clone(),
+                    ViewSnapshotPolicy {
+                        every_events: config.view_snapshot_events,
+                    },
+                );
+                snapshotter.recover(&journal).await?;
+                view_snapshots = Some(Arc::new(snapshotter));
+                Arc::new(RwLock::new(journal))
+            }
+            _ => Arc::new(RwLock::new(InMemoryJournal::with_ordering(config.ordering))),
+        };
+
+        let planner = Arc::new(Mutex::new(planner));
+        journal.write().await.register_index(planner.clone());
+        let db = Self {
+            hierarchy: Mutex::new(hierarchy),
+            triggers: Arc::new(Mutex::new(triggers)),
+            prepared: Mutex::new(prepared),
+            view_snapshots,
+            ..Self::from_parts(config, journal, view, planner)
+        };
+        if let Some(interval) = db.config.flush_interval {
+            let journal = Arc::downgrade(&db.journal);
+            spawn_periodic_flush(journal, db.metrics.clone(), interval);
+        }
+        if db.config.journal == JournalKind::Segmented {
+            let journal = Arc::downgrade(&db.journal);
+            let (cache, stats) = (db.query_cache.clone(), db.planner.clone());
+            spawn_retention_job(journal.clone(), db.retention.clone(), cache, stats);
+            spawn_scrub_job(journal, db.scrub.clone(), db.metrics.clone());
+        }
+        if let Some(snapshotter) = &db.view_snapshots {
+            spawn_view_snapshot_job(Arc::downgrade(snapshotter));
+        }
+        if let (JournalKind::Segmented, Some(dir)) = (db.config.journal, &db.config.data_dir) {
+            spawn_hot_set_job(Arc::downgrade(&db.hot), dir.clone());
+        }
+        if db.config.warmup {
+            db.warm_up().await?;
+        }
+        Ok(db)
+    }
+
     /// Create a new in-memory temporal database
     pub fn in_memory() -> Result<Self> {
-        let view = InMemoryMaterializedView::new();
+        Self::in_memory_with_ordering(EventOrdering::default())
+    }
+
+    /// Create a new in-memory temporal database that orders events sharing
+    /// a timestamp by `ordering`
+    pub fn in_memory_with_ordering(ordering: EventOrdering) -> Result<Self> {
+        let planner: Arc<Mutex<QueryPlanner>> = Arc::default();
+        let mut journal = InMemoryJournal::with_ordering(ordering);
+        journal.register_index(planner.clone());
+        let config = Config {
+            ordering,
+            ..Config::default()
+        };
+        let view = Arc::new(InMemoryMaterializedView::new());
+        Ok(Self::from_parts(
+            config,
+            Arc::new(RwLock::new(journal)),
+            view,
+            planner,
+        ))
+    }
+
+    /// A database over `journal` and `view` as set up by `config`, with
+    /// every other part empty; the constructors start from it
+    fn from_parts(
+        config: Config,
+        journal: Arc<RwLock<dyn EventJournal>>,
+        view: Arc<dyn MaterializedView>,
+        planner: Arc<Mutex<QueryPlanner>>,
+    ) -> Self {
+        Self {
+            journal,
+            view,
+            hot: Arc::new(HotEntityCache::new(HotEntityConfig {
+                capacity: config.hot_entity_capacity,
+                ordering: config.ordering,
+                ..HotEntityConfig::default()
+            })),
+            read_only: false,
+            lease_lock: tokio::sync::Mutex::new(()),
+            crdt_lock: tokio::sync::Mutex::new(()),
+            metrics: Arc::new(Metrics::new()),
+            clock: config.hlc_node.map(HybridClock::new),
+            retention: Arc::new(Mutex::new(RetentionPolicy {
+                max_age: config.retention,
+                ..RetentionPolicy::default()
+            })),
+            scrub: Arc::default(),
+            workload: WorkloadTracker::new(),
+            query_memory: Mutex::default(),
+            replication_watermarks: Mutex::default(),
+            prepared: Mutex::default(),
+            security: Mutex::default(),
+            hierarchy: Mutex::default(),
+            triggers: Arc::new(Mutex::new(TriggerRegistry::new(Timestamp::now()))),
+            trigger_wake: Arc::default(),
+            trigger_job: std::sync::Once::new(),
+            subscriptions: EventBroadcaster::new(),
+            event_bus: EventBus::new(),
+            planner,
+            query_cache: Arc::new(QueryResultCache::new(config.query_cache_entries)),
+            aggregates: Mutex::default(),
+            schemas: Mutex::default(),
+            conflict_policies: Mutex::default(),
+            view_snapshots: None,
+            config,
+        }
+    }
+
+    /// Mount a data directory read-only.
+    ///
+    /// Queries read the finalized segments listed in the manifest in place,
+    /// through their block indexes; the WAL is never opened and no data in
+    /// `dir` is modified. All write operations on the returned database
+    /// fail.
+    ///
+    /// This is safe while another process owns the directory as a writer:
+    /// the manifest and rewritten segments are replaced atomically by
+    /// rename, and a shared reader lock is taken when the directory is
+    /// writable so the writer keeps the files of dropped segments until the
+    /// readers are gone.
+    pub async fn open_read_only<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::mount(dir.as_ref(), None).await
+    }
+
+    /// Mount a data directory read-only as it was at system time `as_of`.
+    ///
+    /// Events recorded after `as_of` (by transaction time) are hidden, which
+    /// allows inspecting a backup or production snapshot at a past point.
+    pub async fn open_as_of<P: AsRef<Path>>(dir: P, as_of: Timestamp) -> Result<Self> {
+        Self::mount(dir.as_ref(), Some(as_of)).await
+    }
+
+    async fn mount(dir: &Path, as_of: Option<Timestamp>) -> Result<Self> {
+        let mut journal = SegmentedJournal::open_read_only(dir.join(SEGMENTS_DIR), as_of)?;
+        let view = Arc::new(InMemoryMaterializedView::new());
+        let mut hierarchy = HierarchyIndex::new();
+        let mut planner = QueryPlanner::new();
+        // The view is rebuilt in batches so memory stays bounded.
+        let mut position = 0;
+        loop {
+            let mut batch = Vec::with_capacity(MOUNT_BATCH);
+            journal.visit_events_after(position, |lsn, event| {
+                hierarchy.apply_event(event);
+                if !is_internal_entity(event.entity_id()) {
+                    planner.observe(event);
+                }
+                batch.push(event.clone());
+                position = lsn;
+                if batch.len() < MOUNT_BATCH {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            })?;
+            for event in &batch {
+                view.apply_event(event).await?;
+            }
+            if batch.len() < MOUNT_BATCH {
+                break;
+            }
+        }
+
+        let planner = Arc::new(Mutex::new(planner));
+        journal.register_index(planner.clone());
+        let config = Config {
+            journal: JournalKind::Segmented,
+            data_dir: Some(dir.to_path_buf()),
+            ..Config::default()
+        };
         Ok(Self {
-            journal: Arc::new(RwLock::new(InMemoryJournal::new())),
-            view: Arc::new(view),
+            read_only: true,
+            hierarchy: Mutex::new(hierarchy),
+            ..Self::from_parts(config, Arc::new(RwLock::new(journal)), view, planner)
+        })
+    }
+
+    /// Whether this database rejects writes
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Settings this database was opened with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace the retention policy.
+    ///
+    /// Segmented databases enforce it every [`RETENTION_INTERVAL`] in the
+    /// background; [`TemporalDB::enforce_retention`] applies it right away.
+    pub fn set_retention(&self, policy: RetentionPolicy) {
+        *self
+            .retention
+            .lock()
+            .expect("TemporalDB poisoned retention lock") = policy;
+    }
+
+    /// Current retention policy
+    pub fn retention(&self) -> RetentionPolicy {
+        self.retention
+            .lock()
+            .expect("TemporalDB poisoned retention lock")
+            .clone()
+    }
+
+    /// Replace the scrub policy used by [`TemporalDB::scrub`] and, every
+    /// [`SCRUB_INTERVAL`], the background job
+    pub fn set_scrub_policy(&self, policy: ScrubPolicy) {
+        *self.scrub.lock().expect("TemporalDB poisoned scrub lock") = policy;
+    }
+
+    /// Current scrub policy
+    pub fn scrub_policy(&self) -> ScrubPolicy {
+        self.scrub
+            .lock()
+            .expect("TemporalDB poisoned scrub lock")
+            .clone()
+    }
+
+    /// Replace the memory budget of each query's sort and group-by
+    /// operators. Cached results are dropped, so queries run within it
+    /// right away.
+    pub fn set_query_memory(&self, budget: MemoryBudget) {
+        *self
+            .query_memory
+            .lock()
+            .expect("TemporalDB poisoned query memory lock") = budget;
+        self.query_cache.clear();
+    }
+
+    /// Current per-query memory budget
+    pub fn query_memory(&self) -> MemoryBudget {
+        self.query_memory
+            .lock()
+            .expect("TemporalDB poisoned query memory lock")
+            .clone()
+    }
+
+    /// Memory tracker for a new query
+    fn query_tracker(&self) -> Arc<MemoryTracker> {
+        MemoryTracker::new(self.query_memory())
+    }
+
+    /// Write new segments and WAL records in the versions negotiated with
+    /// the cluster, so that nodes not yet upgraded can still read them
+    pub async fn pin_formats(&self, versions: &NegotiatedVersions) -> Result<()> {
+        let segment = u8::try_from(versions.segment).map_err(|_| {
+            Error::Configuration(format!("Unsupported segment version {}", versions.segment))
+        })?;
+        self.journal
+            .write()
+            .await
+            .pin_write_versions(segment, versions.wal)
+            .await
+    }
+
+    /// Snapshot the current-state view now rather than waiting for the
+    /// background job. Returns the LSN the snapshot covers, or `None` for
+    /// databases without a data directory.
+    pub async fn snapshot_view(&self) -> Result<Option<Lsn>> {
+        self.ensure_writable()?;
+        match &self.view_snapshots {
+            Some(snapshotter) => snapshotter.snapshot().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Current-state view in the view snapshot format, e.g. to compact a
+    /// Raft log with [`RaftNode::compact`](crate::distributed::RaftNode::compact)
+    pub async fn export_view(&self) -> Result<ViewSnapshot> {
+        let lsn = match &self.view_snapshots {
+            Some(snapshotter) => snapshotter.applied_lsn().await,
+            None => 0,
+        };
+        Ok(ViewSnapshot {
+            lsn,
+            created_at: Timestamp::now(),
+            entries: self.view.export_state().await?,
         })
     }
 
+    /// Replace the current-state view with `snapshot`, e.g. one installed
+    /// from a Raft leader, see
+    /// [`RaftNode::take_installed_snapshot`](crate::distributed::RaftNode::take_installed_snapshot)
+    pub async fn import_view(&self, snapshot: ViewSnapshot) -> Result<()> {
+        self.ensure_writable()?;
+        self.view.import_state(snapshot.entries).await
+    }
+
+    /// Verify the next segments in rotation, repairing damaged ones from the
+    /// policy's replica or flagging them for restore
+    pub async fn scrub(&self) -> Result<ScrubReport> {
+        self.ensure_writable()?;
+        let policy = self.scrub_policy();
+        let report = scrub_journal(&self.journal, &policy).await?;
+        self.metrics.record_scrub(&report);
+        Ok(report)
+    }
+
+    /// Replace the row-level security policy applied to new sessions
+    pub fn set_security_policy(&self, policy: SecurityPolicy) {
+        *self
+            .security
+            .lock()
+            .expect("TemporalDB poisoned security lock") = Arc::new(policy);
+    }
+
+    /// Current row-level security policy
+    pub fn security_policy(&self) -> Arc<SecurityPolicy> {
+        self.security
+            .lock()
+            .expect("TemporalDB poisoned security lock")
+            .clone()
+    }
+
+    /// Open a session for `principal`.
+    ///
+    /// Queries through the session only return events the current
+    /// [`SecurityPolicy`] lets the principal see. A principal without a
+    /// role declared in the policy sees nothing.
+    pub fn session(&self, principal: Principal) -> SecureSession<'_> {
+        SecureSession {
+            db: self,
+            principal,
+            policy: self.security_policy(),
+        }
+    }
+
+    /// Drop (or archive) the segments expired under the retention policy.
+    ///
+    /// When events are removed the optimizer's statistics are recomputed
+    /// from the remaining ones.
+    pub async fn enforce_retention(&self) -> Result<RetentionReport> {
+        self.ensure_writable()?;
+        let policy = self.retention();
+        retain_journal(&self.journal, &policy, &self.query_cache, &self.planner).await
+    }
+
+    /// Register a trigger firing when a matching fact becomes effective,
+    /// i.e. when the clock reaches its valid time.
+    ///
+    /// Facts recorded with a valid time in the past do not fire. Facts
+    /// becoming effective while no trigger is registered are delivered once
+    /// one is, also after a restart of a segmented database. Notifications
+    /// arrive on the returned receiver; dropping it unregisters the trigger.
+    pub async fn register_trigger(
+        &self,
+        filter: TriggerFilter,
+    ) -> Result<(TriggerId, mpsc::UnboundedReceiver<EffectiveNotification>)> {
+        self.ensure_writable()?;
+        let registration = self
+            .triggers
+            .lock()
+            .expect("TemporalDB poisoned triggers lock")
+            .register(filter);
+        self.trigger_job.call_once(|| {
+            spawn_trigger_job(Arc::downgrade(&self.triggers), self.trigger_wake.clone())
+        });
+        // Facts may already be due.
+        self.trigger_wake.notify_one();
+        Ok(registration)
+    }
+
+    /// Remove a trigger; returns whether it was registered
+    pub fn unregister_trigger(&self, id: TriggerId) -> bool {
+        self.triggers
+            .lock()
+            .expect("TemporalDB poisoned triggers lock")
+            .unregister(id)
+    }
+
+    /// Deliver the facts that became effective by now, returning the number
+    /// of notifications sent. The trigger job does this in the background.
+    pub fn fire_due_triggers(&self) -> Result<usize> {
+        self.triggers
+            .lock()
+            .expect("TemporalDB poisoned triggers lock")
+            .fire_due(Timestamp::now())
+    }
+
+    /// Snapshot of latency histograms and counters for this instance
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Count replica writes this node, as coordinator, did not hold as
+    /// hints
+    pub(crate) fn record_hints_dropped(&self, writes: u64) {
+        self.metrics.record_hints_dropped(writes);
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Storage("Database is opened read-only".to_string()));
+        }
+        Ok(())
+    }
+
     /// Insert a value for an entity at a specific timestamp
     pub async fn insert<V: serde::Serialize>(
         &self,
@@ -32,175 +625,5810 @@ impl TemporalDB {
         value: V,
         timestamp: Timestamp,
     ) -> Result<()> {
-        // Serialize value
-        let payload = EventPayload::from_json(&value)
-            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = self.value_event(entity_id, value, timestamp)?;
+        self.commit(event).await
+    }
 
-        // Create event
+    /// Record a numeric time-series sample.
+    ///
+    /// Samples are stored compactly and Gorilla-compressed in segments;
+    /// [`TemporalDB::query_as_of`] reads them as plain numbers.
+    pub async fn insert_numeric(
+        &self,
+        entity_id: &str,
+        sample: NumericPayload,
+        timestamp: Timestamp,
+    ) -> Result<()> {
         let event = Event::new(
-            "value.changed".to_string(),
+            NUMERIC_SAMPLE.to_string(),
             timestamp,
             entity_id.to_string(),
-            payload,
+            EventPayload::from_numeric(&sample),
         );
-
-        // Append to journal
-        self.journal.write().await.append(event.clone()).await?;
-
-        // Update materialized view
-        self.view.apply_event(&event).await?;
-
-        Ok(())
+        self.commit(event).await
     }
 
-    /// Query value at a specific timestamp (AS OF)
-    pub async fn query_as_of<V: for<'de> serde::Deserialize<'de>>(
+    /// Numeric samples of an entity with timestamps in `[start, end)`, in
+    /// timestamp order; non-numeric events are skipped.
+    ///
+    /// Only the entity's segment blocks overlapping the range are read, see
+    /// [`EventJournal::get_events`].
+    pub async fn query_numeric_range(
         &self,
         entity_id: &str,
-        timestamp: Timestamp,
-    ) -> Result<Option<V>> {
-        // Get latest event before or at timestamp
-        let event = self
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<(Timestamp, NumericPayload)>> {
+        let start_time = Instant::now();
+        self.hot.record_read(entity_id);
+        let events = self
             .journal
             .read()
             .await
-            .get_latest_event(entity_id, timestamp)
+            .get_events(entity_id, start, end)
             .await?;
+        let samples = events
+            .iter()
+            .filter(|e| e.payload().is_numeric())
+            .map(|e| Ok((e.timestamp(), e.payload().to_numeric()?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(samples)
+    }
 
-        match event {
-            Some(e) => {
-                let value: V = e
-                    .payload()
-                    .to_json()
-                    .map_err(|e| Error::Serialization(e.to_string()))?;
-                Ok(Some(value))
-            }
-            None => Ok(None),
+    /// Insert a value that is only valid from `from` until `to` (exclusive).
+    ///
+    /// `from` may lie in the future, e.g. for a scheduled price change.
+    /// [`TemporalDB::query_as_of`] returns the value only within its range;
+    /// once it ends the entity has no value until a later one is inserted.
+    pub async fn insert_valid_range<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> Result<()> {
+        if from >= to {
+            return Err(Error::Temporal(format!(
+                "Empty valid range [{}, {})",
+                from, to
+            )));
         }
+        let mut event = self.value_event(entity_id, value, from)?;
+        event.metadata.valid_to = Some(to);
+        self.commit(event).await
     }
 
-    /// Query values in a time range
-    pub async fn query_range<V: for<'de> serde::Deserialize<'de>>(
+    /// Insert a value that is only valid from `from` until `to` (exclusive)
+    #[deprecated(note = "renamed to `insert_valid_range`")]
+    pub async fn insert_valid<V: serde::Serialize>(
         &self,
         entity_id: &str,
-        start: Timestamp,
-        end: Timestamp,
-    ) -> Result<Vec<V>> {
-        let events = self
-            .journal
-            .read()
-            .await
-            .get_events(entity_id, start, end)
-            .await?;
+        value: V,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> Result<()> {
+        self.insert_valid_range(entity_id, value, from, to).await
+    }
 
-        let mut values = Vec::new();
-        for event in events {
-            let value: V = event
-                .payload()
-                .to_json()
-                .map_err(|e| Error::Serialization(e.to_string()))?;
-            values.push(value);
+    /// Correct the value an entity had from `valid_time` on.
+    ///
+    /// The correction is recorded as a new version with the current
+    /// transaction time, so it wins over what was recorded for
+    /// `valid_time` before while [`TemporalDB::query_as_of_system`] can still
+    /// answer what was believed earlier.
+    pub async fn correct<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        valid_time: Timestamp,
+    ) -> Result<()> {
+        let mut event = self.value_event(entity_id, value, valid_time)?;
+        event.metadata = event.metadata.with_tag(CORRECTION_TAG.to_string());
+        self.commit(event).await
+    }
+
+    /// Delete an entity as of `timestamp` by appending a tombstone.
+    ///
+    /// [`TemporalDB::query_as_of`] returns `None` from `timestamp` on until
+    /// a later value is inserted; history before it stays queryable.
+    pub async fn delete(&self, entity_id: &str, timestamp: Timestamp) -> Result<()> {
+        let payload = EventPayload::from_json(&serde_json::Value::Null)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            ENTITY_DELETED.to_string(),
+            timestamp,
+            entity_id.to_string(),
+            payload,
+        );
+        self.commit(event).await
+    }
+
+    /// Append a domain event as given, with its type and metadata.
+    ///
+    /// Unlike [`TemporalDB::insert`], which records every value as a
+    /// `value.changed` event, this keeps the caller's event type (e.g.
+    /// `OrderPlaced`), actor, tags and causal links.
+    pub async fn append_event(&self, event: Event) -> Result<()> {
+        self.commit(event).await
+    }
+
+    /// Append domain events in order, atomically.
+    ///
+    /// The whole batch is checked before anything is appended, so a
+    /// reserved entity ID or an entity locked by a prepared transaction
+    /// rejects all of it. The events then go to the
+    /// journal as one batch under one lock, see
+    /// [`EventJournal::append_batch`]: no other write lands between them,
+    /// and a storage failure appends none of them.
+    pub async fn append_events(&self, mut events: Vec<Event>) -> Result<()> {
+        self.ensure_writable()?;
+        for event in &mut events {
+            self.stamp(event)?;
+        }
+        self.commit_events(&events, None).await?;
+        for event in &events {
+            self.maintain_aggregate(event).await?;
         }
+        Ok(())
+    }
 
-        Ok(values)
+    /// Append a domain event of type `event_type` carrying `payload` as
+    /// JSON, returning its ID, e.g. to link events it causes
+    pub async fn emit<P: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        event_type: &str,
+        payload: P,
+        timestamp: Timestamp,
+    ) -> Result<EventId> {
+        let payload =
+            EventPayload::from_json(&payload).map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            event_type.to_string(),
+            timestamp,
+            entity_id.to_string(),
+            payload,
+        );
+        let id = event.id();
+        self.commit(event).await?;
+        Ok(id)
     }
 
-    /// Get current value for an entity
-    pub async fn get_current<V: for<'de> serde::Deserialize<'de>>(
+    /// Build a `value.changed` event carrying `value` as JSON, checked
+    /// against the latest schema of the entity's type if it has one
+    pub(crate) fn value_event<V: serde::Serialize>(
         &self,
         entity_id: &str,
-    ) -> Result<Option<V>> {
-        match self.view.get_current_raw(entity_id).await? {
-            Some(data) => {
-                let payload = EventPayload::new(data, "json".to_string());
-                let value: V =
-                    payload.to_json().map_err(|e| Error::Serialization(e.to_string()))?;
-                Ok(Some(value))
-            }
-            None => Ok(None),
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<Event> {
+        // Serialize value
+        let payload =
+            EventPayload::from_json(&value).map_err(|e| Error::Serialization(e.to_string()))?;
+
+        let mut event = Event::new(
+            "value.changed".to_string(),
+            timestamp,
+            entity_id.to_string(),
+            payload,
+        );
+        let schemas = self
+            .schemas
+            .lock()
+            .expect("TemporalDB poisoned schemas lock");
+        if let Some(schema) = schemas.latest(entity_type(entity_id)) {
+            let json = serde_json::to_value(&value)?;
+            schema
+                .validate(&json)
+                .map_err(|e| Error::Query(format!("{}: {}", entity_id, e)))?;
+            event.metadata.schema = Some(schema.id().clone());
         }
+        Ok(event)
     }
 
-    /// Get all events for an entity
-    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
-        self.journal
-            .read()
-            .await
-            .get_entity_events(entity_id)
-            .await
+    /// Append a user event, update all derived state and maintain the
+    /// snapshots of its aggregate
+    async fn commit(&self, mut event: Event) -> Result<()> {
+        self.stamp(&mut event)?;
+        self.commit_event(&event).await?;
+        self.maintain_aggregate(&event).await
     }
 
-    /// Flush pending writes
-    pub async fn flush(&self) -> Result<()> {
-        self.journal.write().await.flush().await
+    /// Check a user event may be appended and stamp it with the hybrid
+    /// logical clock, if the database keeps one
+    fn stamp(&self, event: &mut Event) -> Result<()> {
+        if is_internal_entity(event.entity_id()) {
+            return Err(Error::Query(format!(
+                "Entity IDs starting with {} are reserved: {}",
+                INTERNAL_ENTITY_PREFIX,
+                event.entity_id()
+            )));
+        }
+        if let Some(clock) = &self.clock {
+            // Events stamped elsewhere keep their stamp and move the clock
+            // past it.
+            match event.hlc() {
+                Some(remote) => {
+                    clock.observe(remote)?;
+                }
+                None => event.metadata.hlc = Some(clock.now()),
+            }
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Append an event the database records for itself on an internal
+    /// entity
+    pub(crate) async fn commit_internal(&self, event: Event) -> Result<()> {
+        debug_assert!(is_internal_entity(event.entity_id()));
+        self.commit_event(&event).await
+    }
 
-    #[tokio::test]
-    async fn test_insert_and_query() {
-        let db = TemporalDB::in_memory().unwrap();
-        let ts1 = Timestamp::from_secs(1000);
+    /// Append an event and update all derived state
+    async fn commit_event(&self, event: &Event) -> Result<()> {
+        self.commit_events(std::slice::from_ref(event), None).await
+    }
 
-        // Insert value
-        db.insert("user:1", "active", ts1).await.unwrap();
+    /// Append events under one journal lock, several as one batch, and
+    /// update all derived state. Fails if a prepared transaction other
+    /// than `txn` locks the entity of one of them. Events on internal
+    /// entities are neither tracked as hot nor published.
+    async fn commit_events(&self, events: &[Event], txn: Option<TxnId>) -> Result<()> {
+        self.ensure_writable()?;
+        let start = Instant::now();
 
-        // Query at same time
-        let value: Option<String> = db.query_as_of("user:1", ts1).await.unwrap();
-        assert_eq!(value, Some("active".to_string()));
+        // Append to journal and update the materialized view in journal
+        // order, which view snapshots count on
+        {
+            let mut journal = self.journal.write().await;
+            // Checked under the journal lock, so a transaction preparing
+            // meanwhile either sees the write or rejects it.
+            if let Some((entity_id, holder)) = self
+                .prepared
+                .lock()
+                .expect("TemporalDB poisoned prepared transactions lock")
+                .conflict(events, txn)
+            {
+                return Err(Error::Distributed(format!(
+                    "Entity {} is locked by transaction {}",
+                    entity_id, holder
+                )));
+            }
+            match events {
+                [event] => journal.append(event.clone()).await?,
+                _ => journal.append_batch(events.to_vec()).await?,
+            }
+            for event in events {
+                match &self.view_snapshots {
+                    Some(snapshotter) => {
+                        snapshotter.apply(event).await?;
+                    }
+                    None => self.view.apply_event(event).await?,
+                }
+                // Queued under the journal lock so handlers see commit order.
+                if !is_internal_entity(event.entity_id()) {
+                    self.event_bus.publish(event);
+                    self.query_cache.invalidate(event);
+                }
+            }
+        }
+        for event in events {
+            let entity_id = event.entity_id();
+            self.hierarchy
+                .lock()
+                .expect("TemporalDB poisoned hierarchy lock")
+                .apply_event(event);
+            let scheduled = self
+                .triggers
+                .lock()
+                .expect("TemporalDB poisoned triggers lock")
+                .schedule(event);
+            if scheduled {
+                self.trigger_wake.notify_one();
+            }
 
-        // Query before (should return None)
-        let value: Option<String> = db.query_as_of("user:1", Timestamp::from_secs(500)).await.unwrap();
-        assert_eq!(value, None);
+            if !is_internal_entity(entity_id) {
+                // Keep hot-entity tracking and pinned timelines up to date
+                self.hot.apply_event(event);
+                if self.hot.record_write(entity_id) {
+                    self.pin_hot_entity(entity_id).await?;
+                }
 
-        // Query after (should return the value)
-        let value: Option<String> = db.query_as_of("user:1", Timestamp::from_secs(2000)).await.unwrap();
-        assert_eq!(value, Some("active".to_string()));
+                self.subscriptions.publish(event);
+            }
+            let size = bincode::serialized_size(event).unwrap_or(0);
+            self.metrics.record_append(start.elapsed(), size);
+        }
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn test_multiple_values() {
-        let db = TemporalDB::in_memory().unwrap();
-        let ts1 = Timestamp::from_secs(1000);
-        let ts2 = Timestamp::from_secs(2000);
+    /// Stream of the events committed from now on that match `filter`.
+    ///
+    /// A subscriber that falls far behind misses the oldest events (see
+    /// [`SUBSCRIPTION_BUFFER`](crate::core::SUBSCRIPTION_BUFFER)). The
+    /// stream ends when the database is dropped.
+    pub fn subscribe(&self, filter: SubscriptionFilter) -> BoxStream<'static, Event> {
+        self.subscriptions
+            .subscribe(move |event| filter.matches(event))
+    }
 
-        db.insert("user:1", "active", ts1).await.unwrap();
-        db.insert("user:1", "inactive", ts2).await.unwrap();
+    /// Run `handler` after each commit of an event `filter` matches.
+    ///
+    /// Events reach the handler in commit order and are retried until it
+    /// succeeds, see [`EventBus`]. Handlers run on their own tasks, so
+    /// commits do not wait for them.
+    pub fn register_handler<H: EventHandler>(
+        &self,
+        filter: SubscriptionFilter,
+        handler: H,
+    ) -> HandlerId {
+        self.event_bus.register(filter, handler)
+    }
 
-        // Query at first time
-        let value: Option<String> = db.query_as_of("user:1", ts1).await.unwrap();
-        assert_eq!(value, Some("active".to_string()));
+    /// Stop running a handler. Returns whether it was registered.
+    pub fn unregister_handler(&self, id: HandlerId) -> bool {
+        self.event_bus.unregister(id)
+    }
 
-        // Query at second time
-        let value: Option<String> = db.query_as_of("user:1", ts2).await.unwrap();
-        assert_eq!(value, Some("inactive".to_string()));
+    /// Wait until the handlers have handled every event committed so far
+    pub async fn handlers_idle(&self) {
+        self.event_bus.idle().await
+    }
+
+    /// Reduce entities of `entity_type` (the part of their ID before the
+    /// first `:`) with `aggregate`.
+    ///
+    /// [`TemporalDB::query_as_of`] then returns the reduced state of such
+    /// entities, folding only the events after the latest snapshot.
+    pub fn register_aggregate(&self, entity_type: &str, aggregate: Aggregate) {
+        self.aggregates
+            .lock()
+            .expect("TemporalDB poisoned aggregates lock")
+            .register(entity_type, aggregate);
+    }
+
+    /// Resolve conflicts between local writes and writes replicated from
+    /// other nodes to entities of `entity_type` with `policy`, see
+    /// [`TemporalDB::apply_remote_events`]. Types without a policy use
+    /// [`LastWriterWins`](crate::crdt::LastWriterWins).
+    pub fn set_resolution_policy(
+        &self,
+        entity_type: &str,
+        policy: impl ResolutionPolicy + 'static,
+    ) {
+        self.conflict_policies
+            .lock()
+            .expect("TemporalDB poisoned conflict policies lock")
+            .set(entity_type, Arc::new(policy));
+    }
+
+    /// Register a new payload schema version for an entity type.
+    ///
+    /// From then on, values written to entities of the type must match it
+    /// and record its ID in [`EventMetadata::schema`]. Versions must
+    /// increase.
+    pub fn register_schema(&self, schema: EntitySchema) -> Result<()> {
+        self.schemas
+            .lock()
+            .expect("TemporalDB poisoned schemas lock")
+            .register(schema)
+    }
+
+    /// Typed access to entities of `entity_type`, whose payloads are `T`.
+    ///
+    /// The type needs a registered schema, and each entity type can only be
+    /// bound to one `T`.
+    pub fn typed<T>(&self, entity_type: &str) -> Result<TypedStore<'_, T>>
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        let mut schemas = self
+            .schemas
+            .lock()
+            .expect("TemporalDB poisoned schemas lock");
+        if schemas.latest(entity_type).is_none() {
+            return Err(Error::Configuration(format!(
+                "No schema registered for entity type '{}'",
+                entity_type
+            )));
+        }
+        schemas.bind_type(entity_type, std::any::type_name::<T>())?;
+        Ok(TypedStore {
+            db: self,
+            entity_type: entity_type.to_string(),
+            _type: PhantomData,
+        })
+    }
+
+    /// Apply a batch of events replicated from the journal of `source`,
+    /// see [`TemporalDB::apply_remote_events`], then advance the
+    /// replication watermark of `source` to `through`.
+    ///
+    /// The source ships its journal in append order, so once the batch is
+    /// applied, this node holds every write the source recorded up to
+    /// transaction time `through`, the latest in the shipped part of its
+    /// journal. A batch shipped again never moves the watermark back.
+    pub async fn apply_replicated(
+        &self,
+        source: &str,
+        events: Vec<Event>,
+        through: Timestamp,
+    ) -> Result<SyncReport> {
+        let report = self.apply_remote_events(events).await?;
+        let mut watermarks = self
+            .replication_watermarks
+            .lock()
+            .expect("TemporalDB poisoned replication watermarks lock");
+        let watermark = watermarks.entry(source.to_string()).or_insert(through);
+        *watermark = (*watermark).max(through);
+        Ok(report)
+    }
+
+    /// Transaction time up to which the writes of every source were
+    /// applied, the lowest of their watermarks, once
+    /// [`TemporalDB::apply_replicated`] applied a batch
+    pub fn replication_watermark(&self) -> Option<Timestamp> {
+        self.replication_watermarks
+            .lock()
+            .expect("TemporalDB poisoned replication watermarks lock")
+            .values()
+            .min()
+            .copied()
+    }
+
+    /// Query a follower for the value valid at `valid_time` as its sources
+    /// knew it at `known_at` (see [`TemporalDB::query_as_of_system`]),
+    /// failing with [`Error::Stale`] unless the replication watermark of
+    /// every source has reached `known_at`. Writes the sources recorded by
+    /// then are all applied, so the answer is the one they give.
+    pub async fn follower_query_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        valid_time: Timestamp,
+        known_at: Timestamp,
+    ) -> Result<Option<V>> {
+        let watermark = self.replication_watermark();
+        if watermark.is_none_or(|w| known_at > w) {
+            return Err(Error::Stale {
+                requested: known_at,
+                watermark,
+            });
+        }
+        self.query_as_of_system(entity_id, valid_time, known_at)
+            .await
+    }
+
+    /// Stage `events` as this node's part of cross-shard transaction
+    /// `txn`, coordinated by node `coordinator`, see
+    /// [`ShardedTransaction`](crate::distributed::ShardedTransaction).
+    ///
+    /// Nothing is appended until [`TemporalDB::commit_transaction`]. Until
+    /// then or [`TemporalDB::abort_transaction`], the entities of `events`
+    /// are locked: preparing another transaction that writes them fails,
+    /// and so does any other write to them, e.g. with
+    /// [`TemporalDB::insert`]. The part is recorded in the journal, so a
+    /// reopened database holds it prepared again.
+    pub async fn prepare_transaction(
+        &self,
+        txn: TxnId,
+        coordinator: &str,
+        events: Vec<Event>,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        if let Some(event) = events.iter().find(|e| is_internal_entity(e.entity_id())) {
+            return Err(Error::Query(format!(
+                "Entity IDs starting with {} are reserved: {}",
+                INTERNAL_ENTITY_PREFIX,
+                event.entity_id()
+            )));
+        }
+        let part = PreparedPart {
+            coordinator: coordinator.to_string(),
+            events,
+        };
+        let payload =
+            EventPayload::from_bincode(&part).map_err(|e| Error::Serialization(e.to_string()))?;
+        self.prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock")
+            .prepare(txn, part)?;
+        let record = Event::new(
+            TXN_PREPARED.to_string(),
+            Timestamp::now(),
+            txn.entity(),
+            payload,
+        );
+        if let Err(e) = self.commit_internal(record).await {
+            self.prepared
+                .lock()
+                .expect("TemporalDB poisoned prepared transactions lock")
+                .release(txn);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Append the events prepared for `txn` as one batch, see
+    /// [`TemporalDB::append_events`], and unlock their entities, returning
+    /// whether any were prepared. If committing fails, the part stays
+    /// prepared, so committing again finishes it; events already in the
+    /// journal, e.g. appended before a crash, are not appended twice.
+    pub async fn commit_transaction(&self, txn: TxnId) -> Result<bool> {
+        let staged = self
+            .prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock")
+            .take(txn);
+        let Some(part) = staged else {
+            return Ok(false);
+        };
+        let committed = self.append_prepared(txn, &part).await;
+        let mut prepared = self
+            .prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock");
+        match committed {
+            Ok(()) => {
+                prepared.release(txn);
+                Ok(true)
+            }
+            Err(e) => {
+                prepared.restore(txn, part);
+                Err(e)
+            }
+        }
+    }
+
+    /// Append the events of `part` not in the journal yet, then record
+    /// that `txn` committed here
+    async fn append_prepared(&self, txn: TxnId, part: &PreparedPart) -> Result<()> {
+        let mut events = Vec::with_capacity(part.events.len());
+        for event in &part.events {
+            if !self.contains_event(event).await? {
+                let mut event = event.clone();
+                self.stamp(&mut event)?;
+                events.push(event);
+            }
+        }
+        if !events.is_empty() {
+            self.commit_events(&events, Some(txn)).await?;
+        }
+        for event in &events {
+            self.maintain_aggregate(event).await?;
+        }
+        self.record_transaction(TXN_FINISHED, txn, true).await
+    }
+
+    /// Drop the events prepared for `txn` and unlock their entities,
+    /// returning whether any were prepared
+    pub async fn abort_transaction(&self, txn: TxnId) -> Result<bool> {
+        let staged = self
+            .prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock")
+            .ids()
+            .contains(&txn);
+        if !staged {
+            return Ok(false);
+        }
+        self.record_transaction(TXN_FINISHED, txn, false).await?;
+        Ok(self
+            .prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock")
+            .release(txn))
+    }
+
+    /// Transactions prepared here and not yet committed or aborted
+    pub fn prepared_transactions(&self) -> Vec<TxnId> {
+        self.prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock")
+            .ids()
+    }
+
+    /// Transactions prepared here and not yet committed or aborted, with
+    /// their coordinators
+    pub(crate) fn in_doubt_transactions(&self) -> Vec<(TxnId, NodeId)> {
+        let prepared = self
+            .prepared
+            .lock()
+            .expect("TemporalDB poisoned prepared transactions lock");
+        prepared
+            .ids()
+            .into_iter()
+            .filter_map(|txn| Some((txn, prepared.coordinator(txn)?.to_string())))
+            .collect()
+    }
+
+    /// Record the decision of this node, as coordinator, whether `txn`
+    /// commits
+    pub(crate) async fn decide_transaction(&self, txn: TxnId, commit: bool) -> Result<()> {
+        self.record_transaction(TXN_DECIDED, txn, commit).await
+    }
+
+    /// Whether this node, as coordinator, decided `txn` commits, `None` if
+    /// it decided nothing
+    pub async fn transaction_decision(&self, txn: TxnId) -> Result<Option<bool>> {
+        let records = self
+            .journal
+            .read()
+            .await
+            .get_entity_events(&txn.entity())
+            .await?;
+        let decision = records.iter().rev().find(|e| e.event_type() == TXN_DECIDED);
+        decision
+            .map(|e| e.payload().to_json())
+            .transpose()
+            .map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    async fn record_transaction(&self, event_type: &str, txn: TxnId, commit: bool) -> Result<()> {
+        let payload =
+            EventPayload::from_json(&commit).map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            event_type.to_string(),
+            Timestamp::now(),
+            txn.entity(),
+            payload,
+        );
+        self.commit_internal(event).await
+    }
+
+    /// Whether the journal holds `event`
+    async fn contains_event(&self, event: &Event) -> Result<bool> {
+        let valid_time = event.timestamp();
+        let end = Timestamp::from_nanos(valid_time.as_nanos().saturating_add(1));
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_events(event.entity_id(), valid_time, end)
+            .await?;
+        Ok(events.iter().any(|e| e.id() == event.id()))
+    }
+
+    /// Apply writes replicated from another node, in order.
+    ///
+    /// Writes already applied are skipped, and events of CRDT entities
+    /// (see [`crdt`](crate::crdt::entity)) are applied as they come. Any
+    /// other write whose entity holds a different write at the same valid
+    /// time is a [`Conflict`], resolved by the policy of the entity type
+    /// (see [`TemporalDB::set_resolution_policy`]); the decision is
+    /// recorded as a [`CONFLICT_RESOLVED`] event, see
+    /// [`TemporalDB::conflicts`]. Deferred conflicts leave the local write
+    /// in place and are not recorded.
+    pub async fn apply_remote_events(&self, events: Vec<Event>) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        for remote in events {
+            let entity_id = remote.entity_id().to_string();
+            let valid_time = remote.timestamp();
+            let end = Timestamp::from_nanos(valid_time.as_nanos().saturating_add(1));
+            let mut local = self
+                .journal
+                .read()
+                .await
+                .get_events(&entity_id, valid_time, end)
+                .await?;
+            let applied = local
+                .iter()
+                .any(|e| e.id() == remote.id() || e.metadata.causation_id == Some(remote.id()));
+            let recorded = self
+                .conflict_records(&entity_id, valid_time, end)
+                .await?
+                .iter()
+                .any(|record| record.remote == remote.id());
+            if applied || recorded {
+                report.duplicates += 1;
+                continue;
+            }
+            self.config.ordering.sort(&mut local);
+            let conflict = match local.pop() {
+                Some(latest)
+                    if !is_crdt_event(&remote)
+                        && (latest.event_type() != remote.event_type()
+                            || latest.payload().data != remote.payload().data) =>
+                {
+                    Conflict {
+                        local: latest,
+                        remote,
+                    }
+                }
+                _ => {
+                    self.commit(remote).await?;
+                    report.applied += 1;
+                    continue;
+                }
+            };
+
+            let policy = self
+                .conflict_policies
+                .lock()
+                .expect("TemporalDB poisoned conflict policies lock")
+                .for_entity(&entity_id);
+            let resolution = policy.resolve(&conflict);
+            let payload = match &resolution {
+                Resolution::Deferred => {
+                    report.deferred += 1;
+                    continue;
+                }
+                Resolution::KeepLocal => None,
+                Resolution::TakeRemote => Some(conflict.remote.payload().clone()),
+                Resolution::Merged(value) => Some(
+                    EventPayload::from_json(value)
+                        .map_err(|e| Error::Serialization(e.to_string()))?,
+                ),
+            };
+            // The winner is written anew so it is the latest write at the
+            // valid time under every ordering.
+            if let Some(payload) = payload {
+                let event_type = conflict.remote.event_type().to_string();
+                let mut event = Event::new(event_type, valid_time, entity_id.clone(), payload);
+                event.metadata.causation_id = Some(conflict.remote.id());
+                self.commit(event).await?;
+            }
+            let record = ConflictRecord {
+                entity_id: entity_id.clone(),
+                local: conflict.local.id(),
+                remote: conflict.remote.id(),
+                policy: policy.name().to_string(),
+                resolution,
+            };
+            let payload = EventPayload::from_json(&record)
+                .map_err(|e| Error::Serialization(e.to_string()))?;
+            let event = Event::new(
+                CONFLICT_RESOLVED.to_string(),
+                valid_time,
+                ConflictRecord::entity_for(&entity_id),
+                payload,
+            );
+            self.commit_internal(event).await?;
+            report.resolved += 1;
+        }
+        Ok(report)
+    }
+
+    /// Conflicts resolved on `entity_id`, by valid time
+    pub async fn conflicts(&self, entity_id: &str) -> Result<Vec<ConflictRecord>> {
+        let (start, end) = (
+            Timestamp::from_nanos(i64::MIN),
+            Timestamp::from_nanos(i64::MAX),
+        );
+        self.conflict_records(entity_id, start, end).await
+    }
+
+    async fn conflict_records(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<ConflictRecord>> {
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_events(&ConflictRecord::entity_for(entity_id), start, end)
+            .await?;
+        events
+            .iter()
+            .map(|e| {
+                e.payload()
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// CRDT-backed entity `entity_id` of type `T`, whose operations are
+    /// applied to this database's replica, node
+    /// [`Config::hlc_node`] (or 0)
+    pub fn crdt<T: CrdtEntity>(&self, entity_id: &str) -> CrdtStore<'_, T> {
+        CrdtStore {
+            db: self,
+            entity_id: entity_id.to_string(),
+            node: self.config.hlc_node.unwrap_or(0) as usize,
+            _type: PhantomData,
+        }
+    }
+
+    fn aggregate_for(&self, entity_id: &str) -> Option<Aggregate> {
+        self.aggregates
+            .lock()
+            .expect("TemporalDB poisoned aggregates lock")
+            .get(entity_id)
+            .cloned()
+    }
+
+    /// Invalidate snapshots a backdated `event` made stale and take a new
+    /// snapshot when one is due
+    async fn maintain_aggregate(&self, event: &Event) -> Result<()> {
+        let entity_id = event.entity_id();
+        let Some(aggregate) = self.aggregate_for(entity_id) else {
+            return Ok(());
+        };
+        let latest = self
+            .aggregates
+            .lock()
+            .expect("TemporalDB poisoned aggregates lock")
+            .latest_snapshot(entity_id);
+        let latest = match latest {
+            Some(latest) => latest,
+            None => self
+                .latest_snapshot(entity_id, Timestamp::from_nanos(i64::MAX))
+                .await?
+                .map(|(_, snapshot)| snapshot.through),
+        };
+        if latest.is_some_and(|through| event.timestamp() <= through) {
+            let invalidation = Event::new(
+                AGGREGATE_SNAPSHOT_INVALIDATED.to_string(),
+                event.timestamp(),
+                AggregateSnapshot::invalidation_entity_for(entity_id),
+                EventPayload::from_json(&event.id())?,
+            );
+            self.commit_internal(invalidation).await?;
+        }
+
+        let due = {
+            let mut registry = self
+                .aggregates
+                .lock()
+                .expect("TemporalDB poisoned aggregates lock");
+            registry.set_latest_snapshot(entity_id, latest);
+            registry.record_event(entity_id)
+        };
+        if !due {
+            return Ok(());
+        }
+        let journal = self.journal.read().await;
+        let newest = journal
+            .get_latest_event(entity_id, Timestamp::from_nanos(i64::MAX))
+            .await?;
+        drop(journal);
+        let Some(through) = newest.map(|e| e.timestamp()) else {
+            return Ok(());
+        };
+        if latest == Some(through) {
+            return Ok(());
+        }
+        let snapshot = AggregateSnapshot {
+            entity_id: entity_id.to_string(),
+            state: self.fold_aggregate(entity_id, &aggregate, through).await?,
+            through,
+        };
+        let event = Event::new(
+            AGGREGATE_SNAPSHOT.to_string(),
+            through,
+            AggregateSnapshot::entity_for(entity_id),
+            EventPayload::from_json(&snapshot)?,
+        );
+        self.commit_internal(event).await?;
+        self.aggregates
+            .lock()
+            .expect("TemporalDB poisoned aggregates lock")
+            .set_latest_snapshot(entity_id, Some(through));
+        Ok(())
+    }
+
+    /// Latest snapshot of `entity_id` at or before `timestamp`, with the
+    /// event recording it
+    async fn latest_snapshot(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<(Event, AggregateSnapshot)>> {
+        let event = self
+            .journal
+            .read()
+            .await
+            .get_latest_event(&AggregateSnapshot::entity_for(entity_id), timestamp)
+            .await?;
+        event
+            .map(|e| {
+                let snapshot = decode_payload(&e)?;
+                Ok((e, snapshot))
+            })
+            .transpose()
+    }
+
+    /// State of an aggregate entity at `timestamp`, folded from the latest
+    /// snapshot that is not stale
+    async fn fold_aggregate(
+        &self,
+        entity_id: &str,
+        aggregate: &Aggregate,
+        timestamp: Timestamp,
+    ) -> Result<Option<serde_json::Value>> {
+        let mut snapshot = self.latest_snapshot(entity_id, timestamp).await?;
+        let journal = self.journal.read().await;
+        if let Some((recorded, latest)) = &snapshot {
+            let invalidations = journal
+                .get_entity_events(&AggregateSnapshot::invalidation_entity_for(entity_id))
+                .await?;
+            if invalidations
+                .iter()
+                .any(|i| latest.is_invalidated_by(recorded, i))
+            {
+                snapshot = None;
+            }
+        }
+        let (state, from) = match snapshot {
+            Some((_, snapshot)) => (snapshot.state, snapshot.through.as_nanos() + 1),
+            None => (None, i64::MIN),
+        };
+        let end = Timestamp::from_nanos(timestamp.as_nanos().saturating_add(1));
+        let mut events = journal
+            .get_events(entity_id, Timestamp::from_nanos(from), end)
+            .await?;
+        drop(journal);
+        self.config.ordering.sort(&mut events);
+        aggregate.fold(state, &events)
+    }
+
+    /// Make `parent` the parent of `child` from `timestamp` on, replacing
+    /// any previous parent.
+    ///
+    /// Fails if `child` is `parent` or one of its ancestors at `timestamp`.
+    pub async fn set_parent(&self, child: &str, parent: &str, timestamp: Timestamp) -> Result<()> {
+        let cycle = self
+            .hierarchy
+            .lock()
+            .expect("TemporalDB poisoned hierarchy lock")
+            .is_ancestor_as_of(child, parent, timestamp);
+        if cycle {
+            return Err(Error::Query(format!(
+                "Making {} the parent of {} would create a cycle",
+                parent, child
+            )));
+        }
+        self.record_parent(child, Some(parent), timestamp).await
+    }
+
+    /// Detach `child` from its parent from `timestamp` on
+    pub async fn clear_parent(&self, child: &str, timestamp: Timestamp) -> Result<()> {
+        self.record_parent(child, None, timestamp).await
+    }
+
+    async fn record_parent(
+        &self,
+        child: &str,
+        parent: Option<&str>,
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        let link = ParentLink {
+            child: child.to_string(),
+            parent: parent.map(str::to_string),
+        };
+        let payload =
+            EventPayload::from_json(&link).map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            PARENT_CHANGED.to_string(),
+            timestamp,
+            ParentLink::entity_for(child),
+            payload,
+        );
+        self.commit_internal(event).await
+    }
+
+    /// Parent of `child` at `timestamp`
+    pub fn parent_as_of(&self, child: &str, timestamp: Timestamp) -> Option<String> {
+        self.hierarchy
+            .lock()
+            .expect("TemporalDB poisoned hierarchy lock")
+            .parent_as_of(child, timestamp)
+            .map(str::to_string)
+    }
+
+    /// Children of `parent` at `timestamp`, in entity order
+    pub fn children_as_of(&self, parent: &str, timestamp: Timestamp) -> Vec<String> {
+        self.hierarchy
+            .lock()
+            .expect("TemporalDB poisoned hierarchy lock")
+            .children_as_of(parent, timestamp)
+    }
+
+    /// Value at `timestamp` of every child of `parent` at `timestamp`, in
+    /// entity order. Children without a valid value are skipped.
+    pub async fn query_children_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        parent: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<(String, V)>> {
+        self.query_children_as_of_visible(Visibility::All, parent, timestamp)
+            .await
+    }
+
+    async fn query_children_as_of_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        parent: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<(String, V)>> {
+        let mut values = Vec::new();
+        for child in self.children_as_of(parent, timestamp) {
+            if let Some(value) = self
+                .query_as_of_visible(visibility, &child, timestamp)
+                .await?
+            {
+                values.push((child, value));
+            }
+        }
+        Ok(values)
+    }
+
+    /// Open a correlation scope.
+    ///
+    /// Every event inserted through the scope is stamped with
+    /// `correlation_id` and, unless stated otherwise, caused by the previous
+    /// event inserted through it. The whole saga can then be read back with
+    /// [`TemporalDB::get_saga`].
+    pub fn correlation_scope(&self, correlation_id: impl Into<String>) -> CorrelationScope<'_> {
+        CorrelationScope {
+            db: self,
+            correlation_id: correlation_id.into(),
+            last_event: Mutex::new(None),
+        }
+    }
+
+    /// Get every event of a saga across all entities, causes before effects
+    pub async fn get_saga(&self, correlation_id: &str) -> Result<Vec<Event>> {
+        self.get_saga_visible(Visibility::All, correlation_id).await
+    }
+
+    async fn get_saga_visible(
+        &self,
+        visibility: Visibility<'_>,
+        correlation_id: &str,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_events_by_correlation(correlation_id)
+            .await?;
+        let saga = visibility.filter(causal_order(events));
+        self.metrics.record_query(start.elapsed());
+        Ok(saga)
+    }
+
+    /// Query value at a specific timestamp (AS OF)
+    pub async fn query_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        self.query_as_of_visible(Visibility::All, entity_id, timestamp)
+            .await
+    }
+
+    async fn query_as_of_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        let start = Instant::now();
+        if let Some(aggregate) = self.aggregate_for(entity_id) {
+            let state = match visibility {
+                Visibility::All => {
+                    self.fold_aggregate(entity_id, &aggregate, timestamp)
+                        .await?
+                }
+                // Snapshots include events the principal may not see.
+                Visibility::Principal(..) => {
+                    let end = Timestamp::from_nanos(timestamp.as_nanos().saturating_add(1));
+                    let events = self
+                        .journal
+                        .read()
+                        .await
+                        .get_events(entity_id, Timestamp::from_nanos(i64::MIN), end)
+                        .await?;
+                    let mut events = visibility.filter(events);
+                    self.config.ordering.sort(&mut events);
+                    aggregate.fold(None, &events)?
+                }
+            };
+            let value = state.map(serde_json::from_value).transpose()?;
+            self.metrics.record_query(start.elapsed());
+            return Ok(value);
+        }
+        let event = self
+            .valid_event_as_of(entity_id, timestamp)
+            .await?
+            .filter(|e| visibility.allows(e));
+        let value = event.map(|e| decode_payload(&e)).transpose()?;
+        self.metrics.record_query(start.elapsed());
+        Ok(value)
+    }
+
+    /// Query many entities at one timestamp (AS OF) in a single journal
+    /// pass; entities without a value valid at `timestamp` are left out
+    pub async fn query_as_of_many<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<HashMap<String, V>> {
+        self.query_as_of_many_visible(Visibility::All, entity_ids, timestamp)
+            .await
+    }
+
+    async fn query_as_of_many_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<HashMap<String, V>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_latest_events(entity_ids, timestamp)
+            .await?;
+        let values = decode_valid(visibility.filter(events), timestamp)?;
+        self.metrics.record_query(start.elapsed());
+        Ok(values)
+    }
+
+    /// Query every entity whose ID starts with `prefix` at one timestamp
+    /// (AS OF) in a single journal pass; entities without a value valid at
+    /// `timestamp` are left out
+    pub async fn query_as_of_prefix<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<BTreeMap<String, V>> {
+        self.query_as_of_prefix_visible(Visibility::All, prefix, timestamp)
+            .await
+    }
+
+    async fn query_as_of_prefix_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<BTreeMap<String, V>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_latest_events_with_prefix(prefix, timestamp)
+            .await?
+            .into_iter()
+            .filter(|e| !is_internal_entity(e.entity_id()) && visibility.allows(e))
+            .collect();
+        let values = decode_valid(events, timestamp)?;
+        self.metrics.record_query(start.elapsed());
+        Ok(values)
+    }
+
+    /// Entities whose ID starts with `prefix` and whose value at `t2`
+    /// differs from their value at `t1`, with both values, in entity order.
+    ///
+    /// Candidates are found with a range scan over the events in `(t1, t2]`
+    /// instead of reading every entity at both times, so a value whose valid
+    /// range merely runs out in between is not reported.
+    pub async fn diff<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        prefix: &str,
+        t1: Timestamp,
+        t2: Timestamp,
+    ) -> Result<Vec<EntityDiff<V>>> {
+        self.diff_visible(Visibility::All, prefix, t1, t2).await
+    }
+
+    async fn diff_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        prefix: &str,
+        t1: Timestamp,
+        t2: Timestamp,
+    ) -> Result<Vec<EntityDiff<V>>> {
+        if t1 > t2 {
+            return Err(Error::Query(format!(
+                "Diff start {} is after its end {}",
+                t1, t2
+            )));
+        }
+        let start = Instant::now();
+        let journal = self.journal.read().await;
+        let mut changed = BTreeSet::new();
+        let scan_start = Timestamp::from_nanos(t1.as_nanos().saturating_add(1));
+        let scan_end = Timestamp::from_nanos(t2.as_nanos().saturating_add(1));
+        journal
+            .scan_range(scan_start, scan_end, &mut |event| {
+                let id = event.entity_id();
+                if id.starts_with(prefix) && !is_internal_entity(id) && !changed.contains(id) {
+                    changed.insert(id.to_string());
+                }
+            })
+            .await?;
+        let entity_ids: Vec<&str> = changed.iter().map(String::as_str).collect();
+        let before = journal.get_latest_events(&entity_ids, t1).await?;
+        let after = journal.get_latest_events(&entity_ids, t2).await?;
+        drop(journal);
+
+        let mut before: HashMap<String, serde_json::Value> =
+            decode_valid(visibility.filter(before), t1)?;
+        let mut after: HashMap<String, serde_json::Value> =
+            decode_valid(visibility.filter(after), t2)?;
+        let mut diffs = Vec::new();
+        for entity_id in changed {
+            let old = before.remove(&entity_id);
+            let new = after.remove(&entity_id);
+            if old != new {
+                diffs.push(EntityDiff {
+                    entity_id,
+                    before: old.map(serde_json::from_value).transpose()?,
+                    after: new.map(serde_json::from_value).transpose()?,
+                });
+            }
+        }
+        self.metrics.record_query(start.elapsed());
+        Ok(diffs)
+    }
+
+    /// Latest event of an entity valid at `timestamp`
+    async fn valid_event_as_of(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<Event>> {
+        if self.hot.record_read(entity_id) {
+            self.pin_hot_entity(entity_id).await?;
+        }
+
+        // Get latest event before or at timestamp, preferring the pinned window
+        let cached = self.hot.latest_before(entity_id, timestamp);
+        self.metrics.record_cache_lookup(cached.is_some());
+        let event = match cached {
+            Some(event) => event,
+            None => {
+                self.journal
+                    .read()
+                    .await
+                    .get_latest_event(entity_id, timestamp)
+                    .await?
+            }
+        };
+
+        // The latest value may have a valid range that already ended
+        Ok(event.filter(|e| e.is_valid_at(timestamp)))
+    }
+
+    /// Query what the database said about `valid_time` as of
+    /// `system_time`: the value valid then, ignoring events recorded
+    /// (transaction time) after `system_time`, such as later inserts and
+    /// corrections.
+    ///
+    /// Aggregates are folded from the events known at `system_time`. With
+    /// `system_time` in the present this matches [`TemporalDB::query_as_of`].
+    pub async fn query_as_of_system<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        valid_time: Timestamp,
+        system_time: Timestamp,
+    ) -> Result<Option<V>> {
+        self.query_as_of_system_visible(Visibility::All, entity_id, valid_time, system_time)
+            .await
+    }
+
+    async fn query_as_of_system_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        valid_time: Timestamp,
+        system_time: Timestamp,
+    ) -> Result<Option<V>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_entity_events(entity_id)
+            .await?;
+        let known = events
+            .into_iter()
+            .filter(|e| e.metadata.transaction_time <= system_time && e.timestamp() <= valid_time);
+        let value = match self.aggregate_for(entity_id) {
+            // Snapshots reflect what is known now, so fold from scratch.
+            Some(aggregate) => {
+                let mut events = visibility.filter(known.collect());
+                self.config.ordering.sort(&mut events);
+                let state = aggregate.fold(None, &events)?;
+                state.map(serde_json::from_value).transpose()?
+            }
+            None => self
+                .config
+                .ordering
+                .latest(known)
+                .filter(|e| e.is_valid_at(valid_time) && visibility.allows(e))
+                .map(|e| decode_payload(&e))
+                .transpose()?,
+        };
+        self.metrics.record_query(start.elapsed());
+        Ok(value)
+    }
+
+    /// Query values in a time range
+    pub async fn query_range<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<V>> {
+        self.query_range_filtered(entity_id, start, end, &EventFilter::new())
+            .await
+    }
+
+    /// Query the values in a time range set by events matching `filter`
+    pub async fn query_range_filtered<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<V>> {
+        self.query_range_visible(Visibility::All, entity_id, start, end, filter)
+            .await
+    }
+
+    async fn query_range_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<V>> {
+        let start_time = Instant::now();
+        self.hot.record_read(entity_id);
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_events_filtered(entity_id, start, end, filter)
+            .await?;
+
+        let mut values = Vec::new();
+        for event in events
+            .iter()
+            .filter(|e| !e.is_tombstone() && visibility.allows(e))
+        {
+            let value: V = event
+                .payload()
+                .to_json()
+                .map_err(|e| Error::Serialization(e.to_string()))?;
+            values.push(value);
+        }
+
+        self.metrics.record_query(start_time.elapsed());
+        Ok(values)
+    }
+
+    /// Stream the events that set an entity's values in `[start, end)`, in
+    /// timestamp order, reading the history a page at a time as the stream
+    /// is polled rather than collecting it like [`TemporalDB::query_range`].
+    pub fn query_range_stream(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> QueryStream {
+        self.query_range_stream_visible(entity_id, start, end, |_| true)
+    }
+
+    fn query_range_stream_visible<F>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        allows: F,
+    ) -> QueryStream
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+    {
+        self.hot.record_read(entity_id);
+        let journal = self.journal.clone();
+        let keep = move |e: &Event| !e.is_tombstone() && allows(e);
+        stream_entity_range(journal, entity_id.to_string(), start, end, keep)
+    }
+
+    /// Events of user entities with a timestamp in `[start, end)` that
+    /// match `filter`, in timestamp order.
+    ///
+    /// Filters with a correlation ID are served from the correlation index,
+    /// so audit queries over a workflow do not scan the range.
+    pub async fn find_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Event>> {
+        self.find_events_visible(Visibility::All, start, end, filter)
+            .await
+    }
+
+    async fn find_events_visible(
+        &self,
+        visibility: Visibility<'_>,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Event>> {
+        let start_time = Instant::now();
+        let mut events = self
+            .journal
+            .read()
+            .await
+            .find_events(start, end, filter)
+            .await?;
+        events.retain(|e| !is_internal_entity(e.entity_id()) && visibility.allows(e));
+        events.sort_by(|a, b| {
+            a.timestamp()
+                .cmp(&b.timestamp())
+                .then_with(|| a.id().cmp(&b.id()))
+        });
+        self.metrics.record_query(start_time.elapsed());
+        Ok(events)
+    }
+
+    /// The first `limit` events of user entities in `[start, end)` that
+    /// match `filter` and `keep`, in timestamp order, and the ones after
+    /// them sharing the last one's timestamp; see
+    /// [`EventJournal::scan_range_page`].
+    ///
+    /// Filters with a correlation ID are served from the correlation index,
+    /// like [`TemporalDB::find_events`].
+    pub(crate) async fn find_events_page(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+        keep: impl Fn(&Event) -> bool + Sync,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        if filter.correlation_id.is_some() {
+            let mut events = self.find_events(start, end, filter).await?;
+            events.retain(keep);
+            truncate_after_ties(&mut events, limit);
+            return Ok(events);
+        }
+        let start_time = Instant::now();
+        let keep = |e: &Event| !is_internal_entity(e.entity_id()) && filter.matches(e) && keep(e);
+        let events = self
+            .journal
+            .read()
+            .await
+            .scan_range_page(start, end, &keep, limit)
+            .await?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(events)
+    }
+
+    /// Values of an entity valid at some point in `[start, end)`, each with
+    /// the period it was valid for.
+    ///
+    /// A value is valid from its event until the next change, its valid-to
+    /// or a delete, whichever comes first; the last value is open-ended.
+    /// Periods are not clipped to the queried range.
+    pub async fn query_history<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<TemporalValue<V>>> {
+        self.query_history_visible(Visibility::All, entity_id, start, end)
+            .await
+    }
+
+    async fn query_history_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<TemporalValue<V>>> {
+        let start_time = Instant::now();
+        self.hot.record_read(entity_id);
+        let mut events = self
+            .journal
+            .read()
+            .await
+            .get_entity_events(entity_id)
+            .await?;
+        self.config.ordering.sort(&mut events);
+        // Of several events at one valid time only the winning (last) one
+        // counts.
+        events.dedup_by(|next, prev| {
+            let same = next.timestamp() == prev.timestamp();
+            if same {
+                std::mem::swap(next, prev);
+            }
+            same
+        });
+
+        let mut history = Vec::new();
+        for (i, event) in events.iter().enumerate() {
+            if event.timestamp() >= end {
+                continue;
+            }
+            let next_change = events.get(i + 1).map(|e| e.timestamp());
+            if let Some(value) = history_value(visibility, event, next_change, start)? {
+                history.push(value);
+            }
+        }
+
+        self.metrics.record_query(start_time.elapsed());
+        Ok(history)
+    }
+
+    /// One page of [`TemporalDB::query_history`]: up to `limit` values (at
+    /// least one) after `cursor`.
+    ///
+    /// Pass the previous page's [`HistoryPage::next_cursor`] as `cursor` to
+    /// continue. The first page starts at the last change at or before
+    /// `start` and later pages read on from their cursor, so walking a long
+    /// history never re-reads the changes before the page.
+    pub async fn query_history_page<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage<V>> {
+        let visibility = Visibility::All;
+        self.query_history_page_visible(visibility, entity_id, start, end, cursor, limit)
+            .await
+    }
+
+    async fn query_history_page_visible<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage<V>> {
+        let start_time = Instant::now();
+        self.hot.record_read(entity_id);
+        let limit = limit.max(1);
+        let journal = self.journal.read().await;
+        let mut after = match cursor {
+            Some(cursor) => Some(*cursor),
+            None => journal
+                .get_latest_event(entity_id, start)
+                .await?
+                .map(|event| EventCursor::before(event.timestamp())),
+        };
+
+        let batch = limit.saturating_add(1);
+        let mut values = Vec::new();
+        // Events at one timestamp, settled once a later event shows up
+        let mut tied: Vec<(EventCursor, Event)> = Vec::new();
+        let next_cursor = 'read: loop {
+            let located = journal
+                .get_events_after(entity_id, after.as_ref(), batch)
+                .await?;
+            let exhausted = located.len() < batch;
+            after = located.last().map(|(cursor, _)| *cursor).or(after);
+            let mut incoming: Vec<_> = located.into_iter().map(Some).collect();
+            if exhausted {
+                incoming.push(None);
+            }
+            for next in incoming {
+                let next_change = next.as_ref().map(|(cursor, _)| cursor.timestamp());
+                let settled = tied
+                    .first()
+                    .is_some_and(|(cursor, _)| Some(cursor.timestamp()) != next_change);
+                if settled {
+                    let resume = tied.last().map(|(cursor, _)| *cursor);
+                    // Of several events at one valid time only the winning
+                    // one counts.
+                    let winner = self.config.ordering.latest(tied.drain(..).map(|(_, e)| e));
+                    let Some(event) = winner.filter(|e| e.timestamp() < end) else {
+                        break 'read None;
+                    };
+                    if let Some(value) = history_value(visibility, &event, next_change, start)? {
+                        values.push(value);
+                    }
+                    if values.len() == limit {
+                        break 'read next_change.and(resume);
+                    }
+                }
+                match next {
+                    Some(next) => tied.push(next),
+                    None => break 'read None,
+                }
+            }
+        };
+
+        self.metrics.record_query(start_time.elapsed());
+        Ok(HistoryPage {
+            values,
+            next_cursor,
+        })
+    }
+
+    /// Get current value for an entity
+    pub async fn get_current<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+    ) -> Result<Option<V>> {
+        match self.view.get_current_raw(entity_id).await? {
+            Some(data) => {
+                let payload = EventPayload::new(data, "json".to_string());
+                let value: V = payload
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Get all events for an entity
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        self.get_entity_events_visible(Visibility::All, entity_id)
+            .await
+    }
+
+    /// Events of an entity recorded at or after `since`, by transaction
+    /// time, e.g. to catch up on writes taken while copying its history
+    pub async fn get_entity_events_recorded_since(
+        &self,
+        entity_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_entity_events_recorded_since(entity_id, since)
+            .await?;
+        self.metrics.record_query(start.elapsed());
+        Ok(events)
+    }
+
+    async fn get_entity_events_visible(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_entity_events(entity_id)
+            .await?;
+        self.metrics.record_query(start.elapsed());
+        Ok(visibility.filter(events))
+    }
+
+    /// Up to `limit` events of an entity after `cursor`, in timestamp order
+    /// with ties in append order.
+    ///
+    /// Pass the previous page's [`EventPage::next_cursor`] as `cursor` to
+    /// continue; each page reads from the cursor's timestamp on, so paging
+    /// through millions of events costs the same per page.
+    pub async fn get_entity_events_page(
+        &self,
+        entity_id: &str,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<EventPage> {
+        self.get_entity_events_page_visible(Visibility::All, entity_id, cursor, limit)
+            .await
+    }
+
+    async fn get_entity_events_page_visible(
+        &self,
+        visibility: Visibility<'_>,
+        entity_id: &str,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<EventPage> {
+        let start = Instant::now();
+        let located = self
+            .journal
+            .read()
+            .await
+            .get_events_after(entity_id, cursor, limit.saturating_add(1))
+            .await?;
+        let mut page = EventPage::from_located(located, limit);
+        page.events = visibility.filter(page.events);
+        self.metrics.record_query(start.elapsed());
+        Ok(page)
+    }
+
+    /// Whether `entity_id` may have events; `false` means it has none.
+    ///
+    /// Answered from the journal's entity filter without reading events.
+    /// Lookups of an entity that never had events return early the same way.
+    pub async fn may_contain_entity(&self, entity_id: &str) -> bool {
+        self.journal.read().await.may_contain_entity(entity_id)
+    }
+
+    /// List up to `limit` entity IDs starting with `prefix`, in entity
+    /// order.
+    ///
+    /// Pass the previous page's [`EntityPage::next_cursor`] as `cursor` to
+    /// continue; each page only visits its own range of the entity catalog.
+    /// Deleted entities are listed, since their history remains queryable.
+    pub async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        self.list_entities_visible(Visibility::All, prefix, cursor, limit)
+            .await
+    }
+
+    async fn list_entities_visible(
+        &self,
+        visibility: Visibility<'_>,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        if limit == 0 {
+            return Err(Error::Query(
+                "Entity page limit must be positive".to_string(),
+            ));
+        }
+        let start = Instant::now();
+        let journal = self.journal.read().await;
+        let mut page = journal.list_entities(prefix, cursor, limit).await?;
+        if let Visibility::Principal(..) = visibility {
+            // An entity is listed if the principal may see any of its
+            // events; later catalog pages fill in for hidden ones.
+            let mut entities = Vec::with_capacity(limit);
+            loop {
+                let mut candidates = std::mem::take(&mut page.entities).into_iter();
+                for entity_id in candidates.by_ref() {
+                    let events = journal.get_entity_events(&entity_id).await?;
+                    if events.iter().any(|e| visibility.allows(e)) {
+                        entities.push(entity_id);
+                        if entities.len() == limit {
+                            break;
+                        }
+                    }
+                }
+                if entities.len() == limit {
+                    let more = candidates.next().is_some() || page.next_cursor.is_some();
+                    page.next_cursor = more.then(|| entities[limit - 1].clone());
+                    break;
+                }
+                match page.next_cursor.take() {
+                    Some(cursor) => {
+                        page = journal.list_entities(prefix, Some(&cursor), limit).await?
+                    }
+                    None => break,
+                }
+            }
+            page.entities = entities;
+        }
+        drop(journal);
+        self.metrics.record_query(start.elapsed());
+        Ok(page)
+    }
+
+    /// Get every event whose JSON payload field at `path` equals `value`.
+    ///
+    /// With an index on the field over every entity (see
+    /// [`TemporalDB::create_index`]) only the events it lists are read.
+    /// Otherwise segmented journals serve the extracted values from their
+    /// field cache, so repeated lookups on a field skip payload parsing.
+    /// Lookups are recorded for [`TemporalDB::index_advisor`].
+    pub async fn find_by_field(&self, path: &FieldPath, value: &FieldValue) -> Result<Vec<Event>> {
+        self.find_by_field_visible(Visibility::All, path, value)
+            .await
+    }
+
+    async fn find_by_field_visible(
+        &self,
+        visibility: Visibility<'_>,
+        path: &FieldPath,
+        value: &FieldValue,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        let journal = self.journal.read().await;
+        let predicate = FieldPredicate::new(path.clone(), CompareOp::Eq, value.clone());
+        let indexed: Option<Vec<_>> = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .indexes()
+            .covering("", path)
+            .and_then(|index| index.lookup(&predicate))
+            .map(|entries| entries.into_iter().cloned().collect());
+        let mut scan = match indexed {
+            Some(entries) => {
+                let events = read_indexed(&*journal, &entries).await?;
+                FieldScan {
+                    scanned: events.len() as u64,
+                    events: events
+                        .into_iter()
+                        .filter(|e| predicate.matches_event(e))
+                        .collect(),
+                }
+            }
+            None => journal.scan_field(path, value).await?,
+        };
+        drop(journal);
+        scan.events
+            .retain(|e| !is_internal_entity(e.entity_id()) && visibility.allows(e));
+        self.workload
+            .record(path, scan.scanned, scan.events.len() as u64);
+        self.metrics.record_query(start.elapsed());
+        Ok(scan.events)
+    }
+
+    /// Uniform random sample of at most `size` events with a timestamp in
+    /// `[start, end)`, in one pass and fixed memory
+    pub async fn sample_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        size: usize,
+    ) -> Result<Vec<Event>> {
+        self.sample_events_visible(Visibility::All, start, end, size)
+            .await
+    }
+
+    async fn sample_events_visible(
+        &self,
+        visibility: Visibility<'_>,
+        start: Timestamp,
+        end: Timestamp,
+        size: usize,
+    ) -> Result<Vec<Event>> {
+        let start_time = Instant::now();
+        let mut sampler = ReservoirSampler::new(size);
+        self.journal
+            .read()
+            .await
+            .scan_range(start, end, &mut |event| {
+                if !is_internal_entity(event.entity_id()) && visibility.allows(event) {
+                    sampler.add(event.clone());
+                }
+            })
+            .await?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(sampler.into_sample())
+    }
+
+    /// Approximate number of distinct entities with events in
+    /// `[start, end)`, within about 1%
+    pub async fn approx_distinct_entities(&self, start: Timestamp, end: Timestamp) -> Result<u64> {
+        self.approx_distinct_entities_visible(Visibility::All, start, end)
+            .await
+    }
+
+    async fn approx_distinct_entities_visible(
+        &self,
+        visibility: Visibility<'_>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<u64> {
+        let start_time = Instant::now();
+        let mut sketch = HyperLogLog::default();
+        self.journal
+            .read()
+            .await
+            .scan_range(start, end, &mut |event| {
+                if !is_internal_entity(event.entity_id()) && visibility.allows(event) {
+                    sketch.add_str(event.entity_id());
+                }
+            })
+            .await?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(sketch.estimate())
+    }
+
+    /// Approximate `quantiles` (0 to 1) of the numeric payload field at
+    /// `path` over events in `[start, end)`; `None` where no event has a
+    /// numeric value there
+    pub async fn approx_percentiles(
+        &self,
+        path: &FieldPath,
+        start: Timestamp,
+        end: Timestamp,
+        quantiles: &[f64],
+    ) -> Result<Vec<Option<f64>>> {
+        self.approx_percentiles_visible(Visibility::All, path, start, end, quantiles)
+            .await
+    }
+
+    async fn approx_percentiles_visible(
+        &self,
+        visibility: Visibility<'_>,
+        path: &FieldPath,
+        start: Timestamp,
+        end: Timestamp,
+        quantiles: &[f64],
+    ) -> Result<Vec<Option<f64>>> {
+        let start_time = Instant::now();
+        let mut digest = TDigest::default();
+        self.journal
+            .read()
+            .await
+            .scan_range(start, end, &mut |event| {
+                if is_internal_entity(event.entity_id()) || !visibility.allows(event) {
+                    return;
+                }
+                if let Some(value) = path.extract(event).and_then(|v| v.as_f64()) {
+                    digest.add(value);
+                }
+            })
+            .await?;
+        let values = quantiles.iter().map(|q| digest.quantile(*q)).collect();
+        self.metrics.record_query(start_time.elapsed());
+        Ok(values)
+    }
+
+    /// Aggregate the numeric payload field at `path` of events on entities
+    /// starting with `entity_prefix` over windows of `window` from `start`
+    /// to `end` (exclusive).
+    ///
+    /// The journal folds values into one accumulator per window as it
+    /// scans, so events are never collected. Only windows with a value are
+    /// returned, in time order.
+    pub async fn aggregate(
+        &self,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        window: Duration,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let visibility = Visibility::All;
+        self.aggregate_visible(visibility, entity_prefix, start, end, window, agg, path)
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn aggregate_visible(
+        &self,
+        visibility: Visibility<'_>,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        window: Duration,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let start_time = Instant::now();
+        let mut aggregator = WindowAggregator::new(agg, start, window)?;
+        let allows = |e: &Event| !is_internal_entity(e.entity_id()) && visibility.allows(e);
+        self.journal
+            .read()
+            .await
+            .aggregate_field(entity_prefix, start, end, path, &mut aggregator, &allows)
+            .await?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(aggregator.finish())
+    }
+
+    /// Execute a parsed `SELECT`, returning the matching events in timestamp
+    /// order.
+    ///
+    /// Results are sorted within the [`TemporalDB::query_memory`] budget,
+    /// spilling to disk when it is used up.
+    pub async fn execute(&self, query: &TemporalQuery) -> Result<Vec<Event>> {
+        self.execute_visible(Visibility::All, query).await
+    }
+
+    async fn execute_visible(
+        &self,
+        visibility: Visibility<'_>,
+        query: &TemporalQuery,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        if let Some(events) = self.query_cache.get(query) {
+            self.metrics.record_query(start.elapsed());
+            return Ok(visibility.filter(events));
+        }
+        // Planned under the journal lock, so index plans list every event
+        // committed before the query runs.
+        let journal = self.journal.read().await;
+        let plan = self.explain(query);
+        let tracker = self.query_tracker();
+        let events = execute_plan(&*journal, query, &plan, tracker, |_| true).await?;
+        // Cached under the journal lock, so no commit can change the result
+        // before it is cached.
+        self.query_cache.insert(query, &events);
+        drop(journal);
+        self.metrics.record_query(start.elapsed());
+        Ok(visibility.filter(events))
+    }
+
+    /// Hit, miss and invalidation counters of the query result cache
+    pub fn query_cache_stats(&self) -> QueryCacheStats {
+        self.query_cache.stats()
+    }
+
+    async fn execute_plan_visible(
+        &self,
+        visibility: Visibility<'_>,
+        query: &TemporalQuery,
+        plan: &QueryPlan,
+    ) -> Result<Vec<Event>> {
+        let start = Instant::now();
+        let journal = self.journal.read().await;
+        // Index plans list the events indexed when they were made.
+        let plan = match plan.access {
+            AccessPath::TagIndex { .. }
+            | AccessPath::FieldIndex { .. }
+            | AccessPath::TextIndex { .. } => self.explain(query),
+            _ => plan.clone(),
+        };
+        let tracker = self.query_tracker();
+        let allows = move |e: &Event| visibility.allows(e);
+        let events = execute_plan(&*journal, query, &plan, tracker, allows).await?;
+        self.metrics.record_query(start.elapsed());
+        Ok(events)
+    }
+
+    /// Validate and plan `template` once, with parameter `$n` filling
+    /// `slots[n - 1]`, see [`PreparedQuery::new`]
+    pub fn prepare(&self, template: TemporalQuery, slots: Vec<ParamSlot>) -> Result<PreparedQuery> {
+        let prepared = PreparedQuery::new(template, slots)?;
+        let plan = self.explain(&prepared.planning_query());
+        Ok(prepared.with_plan(plan))
+    }
+
+    /// Execute `prepared` with `values` bound to its parameters
+    pub async fn execute_prepared(
+        &self,
+        prepared: &PreparedQuery,
+        values: &[ParamValue],
+    ) -> Result<Vec<Event>> {
+        self.execute_prepared_visible(Visibility::All, prepared, values)
+            .await
+    }
+
+    async fn execute_prepared_visible(
+        &self,
+        visibility: Visibility<'_>,
+        prepared: &PreparedQuery,
+        values: &[ParamValue],
+    ) -> Result<Vec<Event>> {
+        let (query, plan) = prepared.bind(values)?;
+        self.execute_plan_visible(visibility, &query, &plan).await
+    }
+
+    /// Fold the numeric payload field at `path` of the events `query`
+    /// selects with `agg` over `window`, like
+    /// `SELECT agg(path) ... GROUP BY WINDOW(ts, ...)`
+    pub async fn execute_window(
+        &self,
+        query: &TemporalQuery,
+        window: WindowSpec,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let events = self.execute(query).await?;
+        window.aggregate(&events, agg, path)
+    }
+
+    /// The events `query` selects, each with the `LAG` or `LEAD` of the
+    /// payload field at `path` over its entity's events
+    pub async fn execute_offset(
+        &self,
+        query: &TemporalQuery,
+        function: OffsetFn,
+        path: &FieldPath,
+    ) -> Result<Vec<OffsetRow>> {
+        let events = self.execute(query).await?;
+        Ok(function.evaluate(events, path))
+    }
+
+    /// Plan [`TemporalDB::execute`] would run `query` with
+    pub fn explain(&self, query: &TemporalQuery) -> QueryPlan {
+        self.planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .plan(query)
+    }
+
+    /// Event counts by type and time, and secondary index cardinalities,
+    /// that queries are planned with
+    pub fn statistics(&self) -> Statistics {
+        self.planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .report()
+    }
+
+    /// Plan queries with `optimizer`, e.g. one with additional rules.
+    /// Cached results are dropped, so queries run with it right away.
+    pub fn set_optimizer(&self, optimizer: Optimizer) {
+        self.planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .set_optimizer(optimizer);
+        self.query_cache.clear();
+    }
+
+    /// Number of events per entity with a timestamp in `[start, end)`, in
+    /// entity order, grouped within the [`TemporalDB::query_memory`] budget
+    pub async fn count_by_entity(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<(String, u64)>> {
+        self.count_by_entity_visible(Visibility::All, start, end)
+            .await
+    }
+
+    async fn count_by_entity_visible(
+        &self,
+        visibility: Visibility<'_>,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<(String, u64)>> {
+        let start_time = Instant::now();
+        let journal = self.journal.read().await;
+        let tracker = self.query_tracker();
+        let counts = count_events_by_entity(&*journal, start, end, tracker, move |e| {
+            visibility.allows(e)
+        })
+        .await?;
+        self.metrics.record_query(start_time.elapsed());
+        Ok(counts)
+    }
+
+    /// Payload shapes of the entities starting with `entity_prefix` over
+    /// events with a timestamp in `[start, end)`, and when each entity's
+    /// shape changed, e.g. to plan an upcaster before a schema change
+    pub async fn schema_report(
+        &self,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<SchemaReport> {
+        self.schema_report_visible(Visibility::All, entity_prefix, start, end)
+            .await
+    }
+
+    async fn schema_report_visible(
+        &self,
+        visibility: Visibility<'_>,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<SchemaReport> {
+        let start_time = Instant::now();
+        let mut report = SchemaReport::new();
+        let journal = self.journal.read().await;
+        let mut cursor = None;
+        loop {
+            let page = journal
+                .list_entities(entity_prefix, cursor.as_deref(), CATALOG_PAGE_SIZE)
+                .await?;
+            for entity_id in &page.entities {
+                let events = journal.get_events(entity_id, start, end).await?;
+                let visible = events.iter().filter(|e| visibility.allows(e));
+                report.add_timeline(entity_id, visible);
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        drop(journal);
+        self.metrics.record_query(start_time.elapsed());
+        Ok(report.finish())
+    }
+
+    /// Index suggestions based on the payload field lookups served so far
+    pub fn index_advisor(&self) -> IndexAdvisor {
+        self.workload.advisor()
+    }
+
+    /// Index the payload field at `path` of the events of entities whose ID
+    /// starts with `entity_prefix`, e.g. `status` of `order:` entities.
+    ///
+    /// The index is built from the events committed so far and maintained
+    /// as events are committed. Queries filtering on the field, over
+    /// entities it covers, then read only the events it lists. Indexes are
+    /// kept in memory, so a reopened database needs them created again.
+    /// Returns `false` if the index already exists.
+    pub async fn create_index(&self, entity_prefix: &str, path: &FieldPath) -> Result<bool> {
+        self.create_covering_index(entity_prefix, path, &[]).await
+    }
+
+    /// Index the payload field at `path` like [`TemporalDB::create_index`],
+    /// also storing the fields at `include` with each entry, so
+    /// [`TemporalDB::index_history`] answers histories of those fields
+    /// without reading events. Returns `false` if an index on `path` over
+    /// `entity_prefix` already exists.
+    pub async fn create_covering_index(
+        &self,
+        entity_prefix: &str,
+        path: &FieldPath,
+        include: &[FieldPath],
+    ) -> Result<bool> {
+        // Commits update the planner under the journal lock, so holding it
+        // no event is missed or indexed twice.
+        let journal = self.journal.read().await;
+        let exists = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .indexes()
+            .get(entity_prefix, path)
+            .is_some();
+        if exists {
+            return Ok(false);
+        }
+        let mut index =
+            SecondaryIndex::new(entity_prefix, path.clone()).with_included(include.to_vec());
+        let start = Timestamp::from_nanos(i64::MIN);
+        let end = Timestamp::from_nanos(i64::MAX);
+        journal
+            .scan_range(start, end, &mut |event| {
+                if !is_internal_entity(event.entity_id()) {
+                    index.insert(event);
+                }
+            })
+            .await?;
+        let added = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .indexes_mut()
+            .add(index);
+        Ok(added)
+    }
+
+    /// Values of the payload fields at `paths` over `entity_id`'s history,
+    /// in timestamp order, read from a covering index storing all of them
+    /// (see [`TemporalDB::create_covering_index`]); `None` if there is none.
+    ///
+    /// Only events with a scalar in the indexed field are listed, and no
+    /// event is read, so deletes and corrections are not applied.
+    pub fn index_history(
+        &self,
+        entity_id: &str,
+        paths: &[FieldPath],
+    ) -> Option<Vec<ProjectedEvent>> {
+        if is_internal_entity(entity_id) {
+            return None;
+        }
+        let start = Instant::now();
+        let history = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .indexes()
+            .storing(entity_id, paths)?
+            .history(entity_id, paths);
+        self.metrics.record_query(start.elapsed());
+        history
+    }
+
+    /// Index the words of the string fields at `paths` of the events of
+    /// entities whose ID starts with `entity_prefix`, e.g. `note` of
+    /// `audit:` entities.
+    ///
+    /// Like [`TemporalDB::create_index`], the index is built from the
+    /// events committed so far, maintained as events are committed and kept
+    /// in memory. Queries with a [`TextMatch`](crate::index::TextMatch) on
+    /// those fields, over entities it covers, then read only the events it
+    /// lists within their time range. Returns `false` if the index already
+    /// exists.
+    #[cfg(feature = "fulltext")]
+    pub async fn create_fulltext_index(
+        &self,
+        entity_prefix: &str,
+        paths: &[FieldPath],
+    ) -> Result<bool> {
+        let journal = self.journal.read().await;
+        let exists = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .fulltext()
+            .get(entity_prefix, paths)
+            .is_some();
+        if exists {
+            return Ok(false);
+        }
+        let mut index = crate::index::FullTextIndex::new(entity_prefix, paths.to_vec())?;
+        let mut failed = None;
+        let start = Timestamp::from_nanos(i64::MIN);
+        let end = Timestamp::from_nanos(i64::MAX);
+        journal
+            .scan_range(start, end, &mut |event| {
+                if failed.is_none() && !is_internal_entity(event.entity_id()) {
+                    failed = index.insert(event).err();
+                }
+            })
+            .await?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        let added = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .fulltext_mut()
+            .add(index);
+        Ok(added)
+    }
+
+    /// Drop the full-text index of `paths` over `entity_prefix`; returns
+    /// whether it existed
+    #[cfg(feature = "fulltext")]
+    pub fn drop_fulltext_index(&self, entity_prefix: &str, paths: &[FieldPath]) -> bool {
+        self.planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .fulltext_mut()
+            .remove(entity_prefix, paths)
+    }
+
+    /// Rebuild the secondary index named `name` (see
+    /// [`SecondaryIndex::name`]) from the journal while the database stays
+    /// online.
+    ///
+    /// The journal is read in chunks of about
+    /// [`RebuildOptions::chunk_events`] events, holding it only while a
+    /// chunk is read, so commits go on and are indexed into the new copy as
+    /// they happen. Reads pause as needed to stay under the options' rate
+    /// limit, and progress is published after every chunk. Queries use the
+    /// old index until the new one replaces it at the end.
+    pub async fn rebuild_index(
+        &self,
+        name: &str,
+        options: RebuildOptions,
+    ) -> Result<RebuildProgress> {
+        let started = Instant::now();
+        let (chunks, total) = {
+            let mut planner = self
+                .planner
+                .lock()
+                .expect("TemporalDB poisoned planner lock");
+            if !planner.indexes_mut().begin_rebuild(name) {
+                return Err(Error::Index(format!(
+                    "No index {} to rebuild, or it is being rebuilt",
+                    name
+                )));
+            }
+            let statistics = planner.statistics();
+            let chunks = options.chunks(&statistics.time_buckets(), HISTOGRAM_BUCKET);
+            (chunks, statistics.total_events())
+        };
+        let mut progress = RebuildProgress {
+            total,
+            ..Default::default()
+        };
+        let backfilled = self
+            .backfill_index(name, &options, &chunks, &mut progress, started)
+            .await;
+        let mut planner = self
+            .planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock");
+        if let Err(e) = backfilled {
+            planner.indexes_mut().abort_rebuild(name);
+            return Err(e);
+        }
+        if !planner.indexes_mut().finish_rebuild(name) {
+            let dropped = format!("Index {} dropped while rebuilt", name);
+            return Err(Error::Index(dropped));
+        }
+        drop(planner);
+        progress.done = true;
+        progress.update(started.elapsed());
+        if let Some(sender) = &options.progress {
+            sender.send_replace(progress);
+        }
+        Ok(progress)
+    }
+
+    /// Read `chunks` of the journal into the copy of index `name` being
+    /// rebuilt, pausing and reporting progress between them
+    async fn backfill_index(
+        &self,
+        name: &str,
+        options: &RebuildOptions,
+        chunks: &[(Timestamp, Timestamp)],
+        progress: &mut RebuildProgress,
+        started: Instant,
+    ) -> Result<()> {
+        for &(start, end) in chunks {
+            let mut events = Vec::new();
+            self.journal
+                .read()
+                .await
+                .scan_range(start, end, &mut |event| {
+                    if !is_internal_entity(event.entity_id()) {
+                        events.push(event.clone());
+                    }
+                })
+                .await?;
+            // Commits since the rebuild began are already in the copy.
+            {
+                let mut planner = self
+                    .planner
+                    .lock()
+                    .expect("TemporalDB poisoned planner lock");
+                for event in &events {
+                    if planner.indexes_mut().backfill(name, event) {
+                        progress.indexed += 1;
+                    }
+                }
+            }
+            progress.events += events.len() as u64;
+            progress.update(started.elapsed());
+            if let Some(sender) = &options.progress {
+                sender.send_replace(*progress);
+            }
+            let pause = options.pause(progress.events, started.elapsed());
+            if !pause.is_zero() {
+                tokio::time::sleep(pause).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the index on `path` over `entity_prefix`; returns whether it
+    /// existed
+    pub fn drop_index(&self, entity_prefix: &str, path: &FieldPath) -> bool {
+        self.planner
+            .lock()
+            .expect("TemporalDB poisoned planner lock")
+            .indexes_mut()
+            .remove(entity_prefix, path)
+    }
+
+    /// Flush pending writes
+    pub async fn flush(&self) -> Result<()> {
+        let start = Instant::now();
+        self.journal.write().await.flush().await?;
+        self.metrics.record_flush(start.elapsed());
+        Ok(())
+    }
+
+    /// Acquire an advisory lease on an entity for `ttl`.
+    ///
+    /// Fails if another lease on the entity is still active. The lease is
+    /// recorded as a `lease.acquired` event, so [`TemporalDB::lease_as_of`]
+    /// can tell who held the entity at any past time.
+    pub async fn acquire_lease(&self, entity_id: &str, ttl: Duration) -> Result<Lease> {
+        self.acquire_lease_at(entity_id, ttl, Timestamp::now())
+            .await
+    }
+
+    async fn acquire_lease_at(
+        &self,
+        entity_id: &str,
+        ttl: Duration,
+        now: Timestamp,
+    ) -> Result<Lease> {
+        let _guard = self.lease_lock.lock().await;
+        if let Some(current) = self.lease_as_of(entity_id, now).await? {
+            return Err(Error::Storage(format!(
+                "Entity {} is leased until {}",
+                entity_id, current.expires_at
+            )));
+        }
+
+        let lease = Lease::new(entity_id, now, ttl);
+        self.record_lease(LEASE_ACQUIRED, &lease, now).await?;
+        Ok(lease)
+    }
+
+    /// Extend a lease held by the caller to expire `ttl` from now
+    pub async fn renew_lease(&self, lease: &Lease, ttl: Duration) -> Result<Lease> {
+        self.renew_lease_at(lease, ttl, Timestamp::now()).await
+    }
+
+    async fn renew_lease_at(&self, lease: &Lease, ttl: Duration, now: Timestamp) -> Result<Lease> {
+        let _guard = self.lease_lock.lock().await;
+        self.ensure_lease_held(lease, now).await?;
+        let renewed = lease.renewed(now, ttl);
+        self.record_lease(LEASE_RENEWED, &renewed, now).await?;
+        Ok(renewed)
+    }
+
+    /// Give up a lease before it expires
+    pub async fn release_lease(&self, lease: &Lease) -> Result<()> {
+        self.release_lease_at(lease, Timestamp::now()).await
+    }
+
+    async fn release_lease_at(&self, lease: &Lease, now: Timestamp) -> Result<()> {
+        let _guard = self.lease_lock.lock().await;
+        self.ensure_lease_held(lease, now).await?;
+        self.record_lease(LEASE_RELEASED, &lease.released(now), now)
+            .await
+    }
+
+    /// Get the lease active on an entity at `timestamp`, if any
+    pub async fn lease_as_of(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<Lease>> {
+        let event = self
+            .journal
+            .read()
+            .await
+            .get_latest_event(&Lease::entity_for(entity_id), timestamp)
+            .await?;
+        match event {
+            Some(e) => {
+                let lease: Lease = e
+                    .payload()
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                Ok(lease.is_active_at(timestamp).then_some(lease))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn ensure_lease_held(&self, lease: &Lease, now: Timestamp) -> Result<()> {
+        match self.lease_as_of(&lease.entity_id, now).await? {
+            Some(current) if current.lease_id == lease.lease_id => Ok(()),
+            _ => Err(Error::Storage(format!(
+                "Lease {} on {} is no longer held",
+                lease.lease_id, lease.entity_id
+            ))),
+        }
+    }
+
+    async fn record_lease(&self, event_type: &str, lease: &Lease, now: Timestamp) -> Result<()> {
+        let payload =
+            EventPayload::from_json(lease).map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            event_type.to_string(),
+            now,
+            Lease::entity_for(&lease.entity_id),
+            payload,
+        );
+        self.commit_internal(event).await
+    }
+
+    /// Up to `limit` events positioned after `lsn`, each with its
+    /// position, in append order, including those of internal entities.
+    ///
+    /// Consumers follow the journal with it from a position saved as a
+    /// [projection checkpoint](TemporalDB::projection_checkpoint), so
+    /// unlike [`TemporalDB::subscribe`] they miss nothing while they lag
+    /// behind or are stopped.
+    pub async fn events_after(&self, lsn: Lsn, limit: usize) -> Result<Vec<(Lsn, Event)>> {
+        self.journal.read().await.events_after(lsn, limit).await
+    }
+
+    /// Access the stored progress of a downstream projection.
+    ///
+    /// Consumers that replay the change stream can persist the LSN they have
+    /// processed here instead of in a separate offset store.
+    pub fn projection_checkpoint(&self, name: &str) -> ProjectionCheckpointHandle<'_> {
+        ProjectionCheckpointHandle {
+            db: self,
+            name: name.to_string(),
+        }
+    }
+
+    /// Consume an outbox source into the journal, see [`OutboxConsumer`]
+    pub fn outbox_consumer<S: OutboxSource>(&self, source: S) -> OutboxConsumer<'_, S> {
+        OutboxConsumer {
+            db: self,
+            source,
+            batch_size: OUTBOX_BATCH_SIZE,
+            polling: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Export the state of every entity as of `timestamp` to a snapshot
+    /// archive in `dir`. See [`crate::export`] for the format.
+    pub async fn export_snapshot<P: AsRef<Path>>(
+        &self,
+        timestamp: Timestamp,
+        dir: P,
+    ) -> Result<SnapshotManifest> {
+        self.export_snapshot_with(timestamp, dir, SnapshotOptions::default())
+            .await
+    }
+
+    /// Export a snapshot archive, optionally including every event valid at
+    /// or before `timestamp`
+    pub async fn export_snapshot_with<P: AsRef<Path>>(
+        &self,
+        timestamp: Timestamp,
+        dir: P,
+        options: SnapshotOptions,
+    ) -> Result<SnapshotManifest> {
+        let mut writer = SnapshotWriter::create(dir, timestamp, options)?;
+        let journal = self.journal.read().await;
+        // The entity catalog lists every user entity ever written, deleted
+        // ones included, in entity ID order.
+        let mut cursor = None;
+        loop {
+            let page = journal
+                .list_entities("", cursor.as_deref(), CATALOG_PAGE_SIZE)
+                .await?;
+            for entity_id in &page.entities {
+                let latest = journal.get_latest_event(entity_id, timestamp).await?;
+                // Deleted entities and expired valid ranges have no state.
+                if let Some(event) = latest.filter(|e| e.is_valid_at(timestamp)) {
+                    writer.write_entity(&SnapshotEntity::from_event(&event))?;
+                }
+                if writer.includes_history() {
+                    for event in journal.get_entity_events(entity_id).await? {
+                        if event.timestamp() <= timestamp {
+                            writer.write_event(&SnapshotEvent::from(&event))?;
+                        }
+                    }
+                }
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        writer.finish()
+    }
+
+    /// Take a full backup of the data directory into `dest`.
+    ///
+    /// Pending writes are flushed first, and writes wait until the copy is
+    /// done. See [`crate::backup`].
+    pub async fn backup<P: AsRef<Path>>(&self, dest: P) -> Result<BackupManifest> {
+        self.with_flushed_data_dir(|dir| backup_full(dir, dest))
+            .await
+    }
+
+    /// Back up what changed since the backup in `base` into `dest`
+    pub async fn backup_incremental<P: AsRef<Path>, B: AsRef<Path>>(
+        &self,
+        dest: P,
+        base: B,
+    ) -> Result<BackupManifest> {
+        self.with_flushed_data_dir(|dir| backup_incremental(dir, dest, base))
+            .await
+    }
+
+    /// Flush the journal and run `f` on the data directory while holding
+    /// off writers
+    async fn with_flushed_data_dir<T>(&self, f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+        self.ensure_writable()?;
+        let dir = self.config.data_dir.as_deref().ok_or_else(|| {
+            Error::Storage("An in-memory database has no data directory".to_string())
+        })?;
+        let mut journal = self.journal.write().await;
+        journal.flush().await?;
+        f(dir)
+    }
+
+    /// Inspect the current set of hot entities, hottest first
+    pub fn hot_entities(&self) -> Vec<HotEntity> {
+        self.hot.hot_set()
+    }
+
+    /// Record the current hot set in the data directory, so that opening
+    /// with [`Config::warmup`] pins the same entities again.
+    ///
+    /// Segmented databases also record it every [`HOT_SET_INTERVAL`] in the
+    /// background.
+    pub fn save_hot_set(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let Some(dir) = &self.config.data_dir else {
+            return Ok(());
+        };
+        let entities = self.hot.hot_set().into_iter().map(|h| h.entity_id);
+        HotSet::new(entities.collect()).store(dir)
+    }
+
+    /// Pin the hot entities recorded by the previous run and preload the
+    /// newest segment blocks into the block cache
+    pub async fn warm_up(&self) -> Result<WarmupReport> {
+        let mut report = WarmupReport::default();
+        if let Some(dir) = &self.config.data_dir {
+            let recorded = HotSet::load(dir)?.map(|h| h.entities).unwrap_or_default();
+            for entity_id in recorded.iter().take(self.hot.config().capacity) {
+                self.pin_hot_entity(entity_id).await?;
+                report.entities += 1;
+            }
+        }
+        report.blocks = self.journal.read().await.warm_up().await?;
+        Ok(report)
+    }
+
+    /// Load an entity's history from the journal and pin its recent events
+    async fn pin_hot_entity(&self, entity_id: &str) -> Result<()> {
+        let events = self
+            .journal
+            .read()
+            .await
+            .get_entity_events(entity_id)
+            .await?;
+        self.hot.pin(entity_id, events);
+        Ok(())
+    }
+}
+
+/// Decode the payloads of `events` valid at `timestamp`, keyed by entity
+fn decode_valid<V, C>(events: Vec<Event>, timestamp: Timestamp) -> Result<C>
+where
+    V: for<'de> serde::Deserialize<'de>,
+    C: FromIterator<(String, V)>,
+{
+    events
+        .into_iter()
+        .filter(|e| e.is_valid_at(timestamp))
+        .map(|e| Ok((e.entity_id().to_string(), decode_payload(&e)?)))
+        .collect()
+}
+
+/// Events a read may return: all of them, or those a principal may see
+/// under a security policy. Every read goes through [`Visibility::allows`].
+#[derive(Clone, Copy)]
+enum Visibility<'a> {
+    All,
+    Principal(&'a SecurityPolicy, &'a Principal),
+}
+
+impl Visibility<'_> {
+    fn allows(&self, event: &Event) -> bool {
+        match self {
+            Visibility::All => true,
+            Visibility::Principal(policy, principal) => policy.allows(principal, event),
+        }
+    }
+
+    fn filter(&self, mut events: Vec<Event>) -> Vec<Event> {
+        events.retain(|e| self.allows(e));
+        events
+    }
+}
+
+/// Value of `event` in an entity's history, valid until `next_change`, its
+/// valid-to or a delete; `None` if it is deleted, hidden or no longer valid
+/// at `start`. Hidden values are left out without changing the periods of
+/// the others.
+fn history_value<V: for<'de> serde::Deserialize<'de>>(
+    visibility: Visibility<'_>,
+    event: &Event,
+    next_change: Option<Timestamp>,
+    start: Timestamp,
+) -> Result<Option<TemporalValue<V>>> {
+    if event.is_tombstone() || !visibility.allows(event) {
+        return Ok(None);
+    }
+    let until = match (next_change, event.valid_to()) {
+        (Some(next), Some(to)) => Some(next.min(to)),
+        (next, to) => next.or(to),
+    };
+    if until.is_some_and(|u| u <= start) {
+        return Ok(None);
+    }
+    Ok(Some(TemporalValue::new(
+        decode_payload(event)?,
+        TimePeriod::range(event.timestamp(), until),
+        event.metadata.transaction_time,
+    )))
+}
+
+/// Deserialize the JSON payload of a value event
+fn decode_payload<V: for<'de> serde::Deserialize<'de>>(event: &Event) -> Result<V> {
+    event
+        .payload()
+        .to_json()
+        .map_err(|e| Error::Serialization(e.to_string()))
+}
+
+/// Flush `journal` every `interval` until the database is dropped
+fn spawn_periodic_flush(
+    journal: Weak<RwLock<dyn EventJournal>>,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(journal) = journal.upgrade() else {
+                break;
+            };
+            let start = Instant::now();
+            let result = journal.write().await.flush().await;
+            match result {
+                Ok(()) => metrics.record_flush(start.elapsed()),
+                Err(e) => tracing::warn!("Background flush failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Fire triggers as facts become effective until the database is dropped
+fn spawn_trigger_job(triggers: Weak<Mutex<TriggerRegistry>>, wake: Arc<Notify>) {
+    tokio::spawn(async move {
+        loop {
+            let Some(registry) = triggers.upgrade() else {
+                break;
+            };
+            let next_due = {
+                let mut registry = registry.lock().expect("TemporalDB poisoned triggers lock");
+                if let Err(e) = registry.fire_due(Timestamp::now()) {
+                    tracing::warn!("Firing triggers failed: {}", e);
+                }
+                registry.queue().next_due()
+            };
+            drop(registry);
+
+            let wait = next_due.map_or(TRIGGER_POLL_INTERVAL, |due| {
+                let nanos = due.as_nanos().saturating_sub(Timestamp::now().as_nanos());
+                Duration::from_nanos(nanos.max(0) as u64).min(TRIGGER_POLL_INTERVAL)
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = wake.notified() => {}
+            }
+        }
+    });
+}
+
+/// Snapshot the view whenever one is due, checking every
+/// [`VIEW_SNAPSHOT_INTERVAL`] until the database is dropped
+/// Record the hot set in `dir` whenever it has changed, until the database
+/// is dropped
+fn spawn_hot_set_job(hot: Weak<HotEntityCache>, dir: PathBuf) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(HOT_SET_INTERVAL);
+        ticker.tick().await;
+        let mut saved = Vec::new();
+        loop {
+            ticker.tick().await;
+            let Some(hot) = hot.upgrade() else {
+                break;
+            };
+            let entities: Vec<String> = hot.hot_set().into_iter().map(|h| h.entity_id).collect();
+            if entities == saved {
+                continue;
+            }
+            match HotSet::new(entities.clone()).store(&dir) {
+                Ok(()) => saved = entities,
+                Err(e) => tracing::warn!("Recording the hot set failed: {}", e),
+            }
+        }
+    });
+}
+
+fn spawn_view_snapshot_job(snapshotter: Weak<ViewSnapshotter>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(VIEW_SNAPSHOT_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(snapshotter) = snapshotter.upgrade() else {
+                break;
+            };
+            if let Err(e) = snapshotter.snapshot_if_due().await {
+                tracing::warn!("View snapshot failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Scrub segments every [`SCRUB_INTERVAL`] until the database is dropped
+fn spawn_scrub_job(
+    journal: Weak<RwLock<dyn EventJournal>>,
+    policy: Arc<Mutex<ScrubPolicy>>,
+    metrics: Arc<Metrics>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SCRUB_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(journal) = journal.upgrade() else {
+                break;
+            };
+            let policy = policy
+                .lock()
+                .expect("TemporalDB poisoned scrub lock")
+                .clone();
+            let result = scrub_journal(&journal, &policy).await;
+            match result {
+                Ok(report) => metrics.record_scrub(&report),
+                Err(e) => tracing::warn!("Background scrub failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Scrub the next segments under the read lock, taking the write lock
+/// only to repair damaged ones
+async fn scrub_journal(
+    journal: &RwLock<dyn EventJournal>,
+    policy: &ScrubPolicy,
+) -> Result<ScrubReport> {
+    let mut report = journal.read().await.scrub(policy).await?;
+    if !report.damaged.is_empty() {
+        journal.write().await.repair(&mut report, policy).await?;
+    }
+    Ok(report)
+}
+
+/// Enforce `policy` and, if events were removed, drop cached results and
+/// recompute the planner's statistics from the remaining events
+async fn retain_journal(
+    journal: &RwLock<dyn EventJournal>,
+    policy: &RetentionPolicy,
+    query_cache: &QueryResultCache,
+    planner: &Mutex<QueryPlanner>,
+) -> Result<RetentionReport> {
+    let mut journal = journal.write().await;
+    let report = journal.enforce_retention(policy, Timestamp::now()).await?;
+    if report.events > 0 {
+        query_cache.clear();
+        let mut statistics = QueryStatistics::new();
+        let start = Timestamp::from_nanos(i64::MIN);
+        let end = Timestamp::from_nanos(i64::MAX);
+        // Index partitions before every event left hold only removed ones.
+        let mut earliest = end;
+        journal
+            .scan_range(start, end, &mut |event| {
+                if !is_internal_entity(event.entity_id()) {
+                    statistics.observe(event);
+                    earliest = earliest.min(event.timestamp());
+                }
+            })
+            .await?;
+        let mut planner = planner.lock().expect("TemporalDB poisoned planner lock");
+        planner.set_statistics(statistics);
+        planner.drop_index_partitions(earliest);
+    }
+    Ok(report)
+}
+
+/// Enforce the retention policy every [`RETENTION_INTERVAL`] until the
+/// database is dropped
+fn spawn_retention_job(
+    journal: Weak<RwLock<dyn EventJournal>>,
+    policy: Arc<Mutex<RetentionPolicy>>,
+    query_cache: Arc<QueryResultCache>,
+    planner: Arc<Mutex<QueryPlanner>>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RETENTION_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(journal) = journal.upgrade() else {
+                break;
+            };
+            let policy = policy
+                .lock()
+                .expect("TemporalDB poisoned retention lock")
+                .clone();
+            if policy.is_unlimited() {
+                continue;
+            }
+            let result = retain_journal(&journal, &policy, &query_cache, &planner).await;
+            if let Err(e) = result {
+                tracing::warn!("Retention enforcement failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Outcome of [`TemporalDB::apply_remote_events`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncReport {
+    /// Writes applied without a conflict
+    pub applied: usize,
+    /// Writes skipped because they were applied before
+    pub duplicates: usize,
+    /// Conflicts resolved and recorded
+    pub resolved: usize,
+    /// Conflicts the policy deferred
+    pub deferred: usize,
+}
+
+/// Value of an entity at two times, see [`TemporalDB::diff`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityDiff<V> {
+    /// Entity ID
+    pub entity_id: String,
+    /// Value at the earlier time, if the entity had one
+    pub before: Option<V>,
+    /// Value at the later time, if the entity had one
+    pub after: Option<V>,
+}
+
+/// One page of [`TemporalDB::query_history_page`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPage<V> {
+    /// Values with their valid periods, in time order
+    pub values: Vec<TemporalValue<V>>,
+    /// Cursor of the next page; `None` on the last page
+    pub next_cursor: Option<EventCursor>,
+}
+
+/// Progress of a downstream projection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectionCheckpoint {
+    /// Projection name
+    pub name: String,
+    /// Last LSN the projection has fully processed
+    pub lsn: Lsn,
+    /// When the checkpoint was saved
+    pub updated_at: Timestamp,
+}
+
+/// Reads and writes one projection's checkpoint.
+///
+/// Created with [`TemporalDB::projection_checkpoint`]. Checkpoints are stored
+/// as events on the internal `$sys:projection:<name>` entity, so earlier
+/// positions remain queryable with [`TemporalDB::query_as_of`].
+pub struct ProjectionCheckpointHandle<'a> {
+    db: &'a TemporalDB,
+    name: String,
+}
+
+impl ProjectionCheckpointHandle<'_> {
+    /// Projection name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entity_id(&self) -> String {
+        format!("{}{}", PROJECTION_ENTITY_PREFIX, self.name)
+    }
+
+    /// Get the latest saved checkpoint, if any
+    pub async fn get(&self) -> Result<Option<ProjectionCheckpoint>> {
+        let event = self
+            .db
+            .journal
+            .read()
+            .await
+            .get_latest_event(&self.entity_id(), Timestamp::from_nanos(i64::MAX))
+            .await?;
+        match event {
+            Some(e) => {
+                let checkpoint = e
+                    .payload()
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                Ok(Some(checkpoint))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Save the LSN the projection has processed up to
+    pub async fn set(&self, lsn: Lsn) -> Result<ProjectionCheckpoint> {
+        let checkpoint = ProjectionCheckpoint {
+            name: self.name.clone(),
+            lsn,
+            updated_at: Timestamp::now(),
+        };
+        let payload = EventPayload::from_json(&checkpoint)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            PROJECTION_CHECKPOINT.to_string(),
+            checkpoint.updated_at,
+            self.entity_id(),
+            payload,
+        );
+        self.db.commit_internal(event).await?;
+        Ok(checkpoint)
+    }
+}
+
+/// Ingests the records of an outbox source exactly once.
+///
+/// Created with [`TemporalDB::outbox_consumer`]. Each record becomes an event
+/// tagged with its source offset, and the last offset of every batch is
+/// checkpointed on the internal `$sys:outbox:<source>` entity; see
+/// [`crate::outbox`].
+pub struct OutboxConsumer<'a, S> {
+    db: &'a TemporalDB,
+    source: S,
+    batch_size: usize,
+    /// Held while polling, so concurrent polls cannot ingest a record twice
+    polling: tokio::sync::Mutex<()>,
+}
+
+impl<S: OutboxSource> OutboxConsumer<'_, S> {
+    /// Read at most `batch_size` records per poll
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Source being consumed
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    fn entity_id(&self) -> String {
+        format!("{}{}", OUTBOX_ENTITY_PREFIX, self.source.name())
+    }
+
+    /// Get the latest saved checkpoint, if any
+    pub async fn checkpoint(&self) -> Result<Option<OutboxCheckpoint>> {
+        let event = self
+            .db
+            .journal
+            .read()
+            .await
+            .get_latest_event(&self.entity_id(), Timestamp::from_nanos(i64::MAX))
+            .await?;
+        match event {
+            Some(e) => {
+                let checkpoint = e
+                    .payload()
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                Ok(Some(checkpoint))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether an event tagged `tag` is already in `entity_id`'s timeline
+    async fn is_ingested(&self, entity_id: &str, tag: &str) -> Result<bool> {
+        let events = self
+            .db
+            .journal
+            .read()
+            .await
+            .get_entity_events(entity_id)
+            .await?;
+        let tagged = |e: &Event| e.metadata.tags.iter().any(|t| t == tag);
+        Ok(events.iter().any(tagged))
+    }
+
+    /// Ingest the next batch of records after the checkpoint, returning how
+    /// many records were consumed
+    pub async fn poll_once(&self) -> Result<usize> {
+        let _polling = self.polling.lock().await;
+        let after = self.checkpoint().await?.map(|c| c.offset);
+        let records = self.source.poll(after, self.batch_size).await?;
+        let Some(last) = records.last().map(|r| r.offset) else {
+            return Ok(0);
+        };
+        let consumed = records.len();
+        // Records appended before a crash cut off the last checkpoint come
+        // first; once one is missing, none of the later ones were appended
+        let mut resuming = true;
+        for record in records {
+            let tag = outbox_tag(self.source.name(), record.offset);
+            if resuming && self.is_ingested(&record.entity_id, &tag).await? {
+                continue;
+            }
+            resuming = false;
+            let payload = EventPayload::from_json(&record.payload)
+                .map_err(|e| Error::Serialization(e.to_string()))?;
+            let mut event = Event::new(
+                record.event_type,
+                record.timestamp,
+                record.entity_id,
+                payload,
+            );
+            event.metadata.tags.push(tag);
+            self.db.commit(event).await?;
+        }
+
+        let checkpoint = OutboxCheckpoint {
+            source: self.source.name().to_string(),
+            offset: last,
+            updated_at: Timestamp::now(),
+        };
+        let payload = EventPayload::from_json(&checkpoint)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::new(
+            OUTBOX_CHECKPOINT.to_string(),
+            checkpoint.updated_at,
+            self.entity_id(),
+            payload,
+        );
+        self.db.commit_internal(event).await?;
+        Ok(consumed)
+    }
+
+    /// Poll until the source has no records after the checkpoint, returning
+    /// how many records were consumed
+    pub async fn drain(&self) -> Result<usize> {
+        let mut consumed = 0;
+        loop {
+            match self.poll_once().await? {
+                0 => return Ok(consumed),
+                n => consumed += n,
+            }
+        }
+    }
+}
+
+/// Stamps a correlation ID on events inserted through it.
+///
+/// Created with [`TemporalDB::correlation_scope`]. Scopes are cheap; a
+/// workflow can reopen the same correlation ID later, in which case the
+/// causal chain restarts from the first event inserted through the new scope.
+pub struct CorrelationScope<'a> {
+    db: &'a TemporalDB,
+    correlation_id: String,
+    last_event: Mutex<Option<EventId>>,
+}
+
+impl CorrelationScope<'_> {
+    /// Correlation ID stamped on events
+    pub fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+
+    /// ID of the most recent event inserted through this scope
+    pub fn last_event_id(&self) -> Option<EventId> {
+        *self
+            .last_event
+            .lock()
+            .expect("CorrelationScope poisoned lock")
+    }
+
+    /// Insert a value caused by the previous event of this scope
+    pub async fn insert<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<EventId> {
+        let cause = self.last_event_id();
+        self.insert_with_cause(entity_id, value, timestamp, cause)
+            .await
+    }
+
+    /// Insert a value caused by a specific event, e.g. to fan out several
+    /// steps from one trigger
+    pub async fn insert_caused_by<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+        cause: EventId,
+    ) -> Result<EventId> {
+        self.insert_with_cause(entity_id, value, timestamp, Some(cause))
+            .await
+    }
+
+    async fn insert_with_cause<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+        cause: Option<EventId>,
+    ) -> Result<EventId> {
+        let mut event = self.db.value_event(entity_id, value, timestamp)?;
+        event.metadata.correlation_id = Some(self.correlation_id.clone());
+        event.metadata.causation_id = cause;
+        let id = event.id();
+
+        self.db.commit(event).await?;
+        *self
+            .last_event
+            .lock()
+            .expect("CorrelationScope poisoned lock") = Some(id);
+        Ok(id)
+    }
+}
+
+/// Reads and writes entities of one type as values of `T`.
+///
+/// Created with [`TemporalDB::typed`]. Entities are addressed by the part
+/// of their ID after the type, e.g. `"1"` for `user:1`.
+pub struct TypedStore<'a, T> {
+    db: &'a TemporalDB,
+    entity_type: String,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedStore<'_, T>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+{
+    /// Entity type of the store
+    pub fn entity_type(&self) -> &str {
+        &self.entity_type
+    }
+
+    /// Full entity ID of `id`
+    pub fn entity_id(&self, id: &str) -> String {
+        format!("{}:{}", self.entity_type, id)
+    }
+
+    /// Insert `value` for entity `id` at `timestamp`, checked against the
+    /// latest schema of the type
+    pub async fn insert(&self, id: &str, value: &T, timestamp: Timestamp) -> Result<()> {
+        self.db.insert(&self.entity_id(id), value, timestamp).await
+    }
+
+    /// Value of entity `id` at `timestamp`
+    pub async fn query_as_of(&self, id: &str, timestamp: Timestamp) -> Result<Option<T>> {
+        self.db.query_as_of(&self.entity_id(id), timestamp).await
+    }
+
+    /// Current value of entity `id`
+    pub async fn get_current(&self, id: &str) -> Result<Option<T>> {
+        self.db.get_current(&self.entity_id(id)).await
+    }
+
+    /// Values of entity `id` changed in `[start, end)` with their valid
+    /// periods
+    pub async fn query_history(
+        &self,
+        id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<TemporalValue<T>>> {
+        self.db.query_history(&self.entity_id(id), start, end).await
+    }
+}
+
+/// Operations on a CRDT-backed entity, see [`crdt`](crate::crdt::entity).
+///
+/// Created with [`TemporalDB::crdt`].
+pub struct CrdtStore<'a, T> {
+    db: &'a TemporalDB,
+    entity_id: String,
+    node: usize,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: CrdtEntity> CrdtStore<'_, T> {
+    /// ID of the entity
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// Record a local operation
+    pub async fn apply(&self, op: T::Op) -> Result<()> {
+        let _guard = self.db.crdt_lock.lock().await;
+        let op = self.state().await?.persisted_op(self.node, op);
+        let record = CrdtOpRecord {
+            node: self.node,
+            op,
+        };
+        self.record(CRDT_OP, &record).await
+    }
+
+    /// Merge the state of a replica received from another node
+    pub async fn merge_remote(&self, state: &T) -> Result<()> {
+        self.record(CRDT_MERGE, state).await
+    }
+
+    /// Drop tombstones of removals before `stable`, a time every replica
+    /// has seen, e.g. the cluster's
+    /// [`stability_watermark`](crate::distributed::ClusterStatus::stability_watermark);
+    /// returns how many were dropped
+    pub async fn collect_garbage(&self, stable: Timestamp) -> Result<usize> {
+        let pruned = self.state().await?.prune_tombstones(stable);
+        if pruned > 0 {
+            self.record(CRDT_GC, &stable).await?;
+        }
+        Ok(pruned)
+    }
+
+    /// Current state of the local replica
+    pub async fn state(&self) -> Result<T> {
+        self.state_as_of(Timestamp::from_nanos(i64::MAX - 1)).await
+    }
+
+    /// State of the local replica at `timestamp`
+    pub async fn state_as_of(&self, timestamp: Timestamp) -> Result<T> {
+        let end = Timestamp::from_nanos(timestamp.as_nanos().saturating_add(1));
+        let mut events = self
+            .db
+            .journal
+            .read()
+            .await
+            .get_events(&self.entity_id, Timestamp::from_nanos(i64::MIN), end)
+            .await?;
+        self.db.config.ordering.sort(&mut events);
+        fold_crdt(self.node, &events)
+    }
+
+    async fn record<P: Serialize>(&self, event_type: &str, payload: &P) -> Result<()> {
+        let payload =
+            EventPayload::from_json(payload).map_err(|e| Error::Serialization(e.to_string()))?;
+        let event = Event::builder(
+            event_type.to_string(),
+            Timestamp::now(),
+            self.entity_id.clone(),
+            payload,
+        )
+        .tag(CRDT_TAG.to_string())
+        .build();
+        self.db.commit(event).await
+    }
+}
+
+/// Queries on behalf of a principal, filtered by row-level security.
+///
+/// Created with [`TemporalDB::session`]. The session keeps the policy that
+/// was current when it was opened.
+pub struct SecureSession<'a> {
+    db: &'a TemporalDB,
+    principal: Principal,
+    policy: Arc<SecurityPolicy>,
+}
+
+impl SecureSession<'_> {
+    /// Principal the session queries as
+    pub fn principal(&self) -> &Principal {
+        &self.principal
+    }
+
+    /// Stream of the committed events matching `filter` that the principal
+    /// may see, see [`TemporalDB::subscribe`]
+    pub fn subscribe(&self, filter: SubscriptionFilter) -> BoxStream<'static, Event> {
+        let (policy, principal) = (self.policy.clone(), self.principal.clone());
+        self.db.subscriptions.subscribe(move |event| {
+            filter.matches(event) && Visibility::Principal(&policy, &principal).allows(event)
+        })
+    }
+
+    fn visibility(&self) -> Visibility<'_> {
+        Visibility::Principal(&self.policy, &self.principal)
+    }
+
+    /// Query value at a specific timestamp (AS OF); `None` if the value
+    /// valid then is hidden from the principal. Aggregates fold only the
+    /// events the principal may see.
+    pub async fn query_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        self.db
+            .query_as_of_visible(self.visibility(), entity_id, timestamp)
+            .await
+    }
+
+    /// Query many entities at one timestamp (AS OF), leaving out hidden
+    /// values, see [`TemporalDB::query_as_of_many`]
+    pub async fn query_as_of_many<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<HashMap<String, V>> {
+        self.db
+            .query_as_of_many_visible(self.visibility(), entity_ids, timestamp)
+            .await
+    }
+
+    /// Query every entity whose ID starts with `prefix` at one timestamp
+    /// (AS OF), leaving out hidden values
+    pub async fn query_as_of_prefix<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<BTreeMap<String, V>> {
+        self.db
+            .query_as_of_prefix_visible(self.visibility(), prefix, timestamp)
+            .await
+    }
+
+    /// Visible entities starting with `prefix` whose value changed between
+    /// `t1` and `t2`
+    pub async fn diff<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        prefix: &str,
+        t1: Timestamp,
+        t2: Timestamp,
+    ) -> Result<Vec<EntityDiff<V>>> {
+        self.db
+            .diff_visible(self.visibility(), prefix, t1, t2)
+            .await
+    }
+
+    /// Value at `timestamp` of every visible child of `parent`
+    pub async fn query_children_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        parent: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<(String, V)>> {
+        self.db
+            .query_children_as_of_visible(self.visibility(), parent, timestamp)
+            .await
+    }
+
+    /// Query the value valid at `valid_time` as of `system_time`; `None`
+    /// if it is hidden from the principal
+    pub async fn query_as_of_system<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        valid_time: Timestamp,
+        system_time: Timestamp,
+    ) -> Result<Option<V>> {
+        self.db
+            .query_as_of_system_visible(self.visibility(), entity_id, valid_time, system_time)
+            .await
+    }
+
+    /// Query visible values in a time range
+    pub async fn query_range<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<V>> {
+        self.query_range_filtered(entity_id, start, end, &EventFilter::new())
+            .await
+    }
+
+    /// Query the visible values in a time range set by events matching
+    /// `filter`
+    pub async fn query_range_filtered<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<V>> {
+        self.db
+            .query_range_visible(self.visibility(), entity_id, start, end, filter)
+            .await
+    }
+
+    /// Stream the visible events that set an entity's values in
+    /// `[start, end)`, see [`TemporalDB::query_range_stream`]
+    pub fn query_range_stream(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> QueryStream {
+        let (policy, principal) = (self.policy.clone(), self.principal.clone());
+        let allows = move |e: &Event| Visibility::Principal(&policy, &principal).allows(e);
+        self.db
+            .query_range_stream_visible(entity_id, start, end, allows)
+    }
+
+    /// Visible events in `[start, end)` that match `filter`, in timestamp
+    /// order
+    pub async fn find_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Event>> {
+        self.db
+            .find_events_visible(self.visibility(), start, end, filter)
+            .await
+    }
+
+    /// Visible values of an entity valid at some point in `[start, end)`,
+    /// see [`TemporalDB::query_history`]
+    pub async fn query_history<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<TemporalValue<V>>> {
+        self.db
+            .query_history_visible(self.visibility(), entity_id, start, end)
+            .await
+    }
+
+    /// One page of the visible values of an entity, see
+    /// [`TemporalDB::query_history_page`]
+    pub async fn query_history_page<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<HistoryPage<V>> {
+        let visibility = self.visibility();
+        self.db
+            .query_history_page_visible(visibility, entity_id, start, end, cursor, limit)
+            .await
+    }
+
+    /// Get the visible events of an entity
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        self.db
+            .get_entity_events_visible(self.visibility(), entity_id)
+            .await
+    }
+
+    /// One page of the visible events of an entity, see
+    /// [`TemporalDB::get_entity_events_page`]. A page may come back short
+    /// or empty while later pages still hold visible events.
+    pub async fn get_entity_events_page(
+        &self,
+        entity_id: &str,
+        cursor: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<EventPage> {
+        self.db
+            .get_entity_events_page_visible(self.visibility(), entity_id, cursor, limit)
+            .await
+    }
+
+    /// List entities with at least one visible event, see
+    /// [`TemporalDB::list_entities`]. A page may come back short or empty
+    /// while later pages still hold visible entities.
+    pub async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        self.db
+            .list_entities_visible(self.visibility(), prefix, cursor, limit)
+            .await
+    }
+
+    /// Get the visible events whose payload field at `path` equals `value`
+    pub async fn find_by_field(&self, path: &FieldPath, value: &FieldValue) -> Result<Vec<Event>> {
+        self.db
+            .find_by_field_visible(self.visibility(), path, value)
+            .await
+    }
+
+    /// Get the visible events of a saga, causes before effects
+    pub async fn get_saga(&self, correlation_id: &str) -> Result<Vec<Event>> {
+        self.db
+            .get_saga_visible(self.visibility(), correlation_id)
+            .await
+    }
+
+    /// Uniform random sample of the visible events in `[start, end)`
+    pub async fn sample_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        size: usize,
+    ) -> Result<Vec<Event>> {
+        self.db
+            .sample_events_visible(self.visibility(), start, end, size)
+            .await
+    }
+
+    /// Approximate number of distinct entities with visible events in
+    /// `[start, end)`
+    pub async fn approx_distinct_entities(&self, start: Timestamp, end: Timestamp) -> Result<u64> {
+        self.db
+            .approx_distinct_entities_visible(self.visibility(), start, end)
+            .await
+    }
+
+    /// Execute a parsed `SELECT` over the visible events
+    pub async fn execute(&self, query: &TemporalQuery) -> Result<Vec<Event>> {
+        self.db.execute_visible(self.visibility(), query).await
+    }
+
+    /// Execute `prepared` over the visible events
+    pub async fn execute_prepared(
+        &self,
+        prepared: &PreparedQuery,
+        values: &[ParamValue],
+    ) -> Result<Vec<Event>> {
+        let visibility = self.visibility();
+        self.db
+            .execute_prepared_visible(visibility, prepared, values)
+            .await
+    }
+
+    /// Window aggregate over the visible events, see
+    /// [`TemporalDB::execute_window`]
+    pub async fn execute_window(
+        &self,
+        query: &TemporalQuery,
+        window: WindowSpec,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let events = self.execute(query).await?;
+        window.aggregate(&events, agg, path)
+    }
+
+    /// `LAG` or `LEAD` over the visible events, see
+    /// [`TemporalDB::execute_offset`]
+    pub async fn execute_offset(
+        &self,
+        query: &TemporalQuery,
+        function: OffsetFn,
+        path: &FieldPath,
+    ) -> Result<Vec<OffsetRow>> {
+        let events = self.execute(query).await?;
+        Ok(function.evaluate(events, path))
+    }
+
+    /// Number of visible events per entity in `[start, end)`
+    pub async fn count_by_entity(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<(String, u64)>> {
+        self.db
+            .count_by_entity_visible(self.visibility(), start, end)
+            .await
+    }
+
+    /// Payload shapes of the visible events of entities starting with
+    /// `entity_prefix` in `[start, end)`
+    pub async fn schema_report(
+        &self,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<SchemaReport> {
+        self.db
+            .schema_report_visible(self.visibility(), entity_prefix, start, end)
+            .await
+    }
+
+    /// Approximate `quantiles` of the payload field at `path` over the
+    /// visible events in `[start, end)`
+    pub async fn approx_percentiles(
+        &self,
+        path: &FieldPath,
+        start: Timestamp,
+        end: Timestamp,
+        quantiles: &[f64],
+    ) -> Result<Vec<Option<f64>>> {
+        self.db
+            .approx_percentiles_visible(self.visibility(), path, start, end, quantiles)
+            .await
+    }
+
+    /// Aggregate the payload field at `path` of visible events over windows
+    /// of `window` in `[start, end)`
+    pub async fn aggregate(
+        &self,
+        entity_prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        window: Duration,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let visibility = self.visibility();
+        self.db
+            .aggregate_visible(visibility, entity_prefix, start, end, window, agg, path)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_insert_and_query() {
+        let db = TemporalDB::in_memory().unwrap();
+        let ts1 = Timestamp::from_secs(1000);
+
+        // Insert value
+        db.insert("user:1", "active", ts1).await.unwrap();
+
+        // Query at same time
+        let value: Option<String> = db.query_as_of("user:1", ts1).await.unwrap();
+        assert_eq!(value, Some("active".to_string()));
+
+        // Query before (should return None)
+        let value: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(500))
+            .await
+            .unwrap();
+        assert_eq!(value, None);
+
+        // Query after (should return the value)
+        let value: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        assert_eq!(value, Some("active".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_values() {
+        let db = TemporalDB::in_memory().unwrap();
+        let ts1 = Timestamp::from_secs(1000);
+        let ts2 = Timestamp::from_secs(2000);
+
+        db.insert("user:1", "active", ts1).await.unwrap();
+        db.insert("user:1", "inactive", ts2).await.unwrap();
+
+        // Query at first time
+        let value: Option<String> = db.query_as_of("user:1", ts1).await.unwrap();
+        assert_eq!(value, Some("active".to_string()));
+
+        // Query at second time
+        let value: Option<String> = db.query_as_of("user:1", ts2).await.unwrap();
+        assert_eq!(value, Some("inactive".to_string()));
 
         // Query in between
-        let value: Option<String> = db.query_as_of("user:1", Timestamp::from_secs(1500)).await.unwrap();
+        let value: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(1500))
+            .await
+            .unwrap();
         assert_eq!(value, Some("active".to_string()));
     }
 
     #[tokio::test]
-    async fn test_range_query() {
+    async fn test_range_query() {
+        let db = TemporalDB::in_memory().unwrap();
+        let ts1 = Timestamp::from_secs(1000);
+        let ts2 = Timestamp::from_secs(2000);
+        let ts3 = Timestamp::from_secs(3000);
+
+        db.insert("user:1", "v1", ts1).await.unwrap();
+        db.insert("user:1", "v2", ts2).await.unwrap();
+        db.insert("user:1", "v3", ts3).await.unwrap();
+
+        // Query range
+        let values: Vec<String> = db
+            .query_range(
+                "user:1",
+                Timestamp::from_secs(1500),
+                Timestamp::from_secs(2500),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0], "v2");
+    }
+
+    #[tokio::test]
+    async fn test_insert_valid_range() {
+        let db = TemporalDB::in_memory().unwrap();
+        let now = Timestamp::now();
+        let from = Timestamp::from_nanos(now.as_nanos() + 3_600_000_000_000);
+        let to = Timestamp::from_nanos(from.as_nanos() + 3_600_000_000_000);
+
+        db.insert("price:1", 10, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert_valid_range("price:1", 12, from, to)
+            .await
+            .unwrap();
+
+        // Scheduled, not yet effective
+        let value: Option<i32> = db.query_as_of("price:1", now).await.unwrap();
+        assert_eq!(value, Some(10));
+        let value: Option<i32> = db.query_as_of("price:1", from).await.unwrap();
+        assert_eq!(value, Some(12));
+        // Expired
+        let value: Option<i32> = db.query_as_of("price:1", to).await.unwrap();
+        assert_eq!(value, None);
+
+        let empty = db.insert_valid_range("price:1", 13, to, from).await;
+        assert!(empty.is_err());
+
+        // The old name still works.
+        #[allow(deprecated)]
+        let old = db.insert_valid("price:2", 1, from, to).await;
+        old.unwrap();
+        let events = db.get_entity_events("price:2").await.unwrap();
+        assert_eq!(events[0].metadata.valid_to, Some(to));
+    }
+
+    #[tokio::test]
+    async fn test_query_as_of_system_replays_what_was_recorded() {
+        let db = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        let recorded = || async {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            let now = Timestamp::now();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            now
+        };
+        let before = recorded().await;
+        db.insert("user:1", "active", secs(100)).await.unwrap();
+        let inserted = recorded().await;
+        // Learned later: the user was suspended back at 200.
+        db.insert("user:1", "suspended", secs(200)).await.unwrap();
+        let backdated = recorded().await;
+        db.delete("user:1", secs(300)).await.unwrap();
+
+        let as_of = |valid, system| db.query_as_of_system::<String>("user:1", valid, system);
+        assert_eq!(as_of(secs(250), before).await.unwrap(), None);
+        assert_eq!(
+            as_of(secs(250), inserted).await.unwrap().as_deref(),
+            Some("active")
+        );
+        assert_eq!(
+            as_of(secs(250), backdated).await.unwrap().as_deref(),
+            Some("suspended")
+        );
+        assert_eq!(
+            as_of(secs(150), backdated).await.unwrap().as_deref(),
+            Some("active")
+        );
+        // The deletion was not known yet at `backdated`, but is now.
+        assert_eq!(
+            as_of(secs(350), backdated).await.unwrap().as_deref(),
+            Some("suspended")
+        );
+        assert_eq!(as_of(secs(350), Timestamp::now()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_correction_keeps_earlier_belief() {
+        let db = TemporalDB::in_memory().unwrap();
+        let valid = Timestamp::from_secs(1000);
+        db.insert("salary:1", 100, valid).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let believed = Timestamp::now();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        db.correct("salary:1", 120, valid).await.unwrap();
+
+        // What was true then, as known now
+        let value: Option<i32> = db.query_as_of("salary:1", valid).await.unwrap();
+        assert_eq!(value, Some(120));
+        // What we believed then
+        let value: Option<i32> = db
+            .query_as_of_system("salary:1", valid, believed)
+            .await
+            .unwrap();
+        assert_eq!(value, Some(100));
+        let value: Option<i32> = db
+            .query_as_of_system("salary:1", valid, Timestamp::now())
+            .await
+            .unwrap();
+        assert_eq!(value, Some(120));
+        let value: Option<i32> = db
+            .query_as_of_system("salary:1", Timestamp::from_secs(999), Timestamp::now())
+            .await
+            .unwrap();
+        assert_eq!(value, None);
+
+        // Aggregates fold only the events recorded by then.
+        db.register_aggregate(
+            "account",
+            Aggregate::new(
+                |state: Option<serde_json::Value>, event: &Event| {
+                    let total = state.and_then(|s| s.as_i64()).unwrap_or(0);
+                    Ok(Some((total + event.payload().to_json::<i64>()?).into()))
+                },
+                1,
+            ),
+        );
+        db.insert("account:1", 10, valid).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let before_backdate = Timestamp::now();
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        db.insert("account:1", 5, Timestamp::from_secs(500))
+            .await
+            .unwrap();
+        let total: Option<i64> = db
+            .query_as_of_system("account:1", valid, before_backdate)
+            .await
+            .unwrap();
+        assert_eq!(total, Some(10));
+        let total: Option<i64> = db.query_as_of("account:1", valid).await.unwrap();
+        assert_eq!(total, Some(15));
+    }
+
+    #[tokio::test]
+    async fn test_index_advisor_tracks_field_lookups() {
+        let db = TemporalDB::in_memory().unwrap();
+        for i in 0..20 {
+            let value = serde_json::json!({"status": if i % 2 == 0 { "open" } else { "closed" }});
+            db.insert(&format!("order:{}", i), value, Timestamp::from_secs(1000))
+                .await
+                .unwrap();
+        }
+
+        let status = FieldPath::parse("status").unwrap();
+        for _ in 0..3 {
+            let open = db
+                .find_by_field(&status, &FieldValue::Str("open".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(open.len(), 10);
+        }
+
+        let recommendations = db.index_advisor().recommendations();
+        assert_eq!(recommendations.len(), 1);
+        assert_eq!(recommendations[0].field, status);
+        assert_eq!(recommendations[0].kind, crate::index::IndexKind::Bitmap);
+        assert_eq!(recommendations[0].estimated_events_saved, 30);
+    }
+
+    #[tokio::test]
+    async fn test_query_history_periods() {
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("user:1", "draft", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("user:1", "active", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        db.insert("user:1", "suspended", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        db.insert("user:1", "closed", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        db.delete("user:1", Timestamp::from_secs(4000))
+            .await
+            .unwrap();
+        db.insert("user:1", "reopened", Timestamp::from_secs(5000))
+            .await
+            .unwrap();
+
+        let history: Vec<TemporalValue<String>> = db
+            .query_history(
+                "user:1",
+                Timestamp::from_secs(1500),
+                Timestamp::from_secs(5000),
+            )
+            .await
+            .unwrap();
+        let periods: Vec<_> = history
+            .iter()
+            .map(|v| (v.value.as_str(), v.valid_time))
+            .collect();
+        let secs = Timestamp::from_secs;
+        assert_eq!(
+            periods,
+            vec![
+                ("draft", TimePeriod::range(secs(1000), Some(secs(2000)))),
+                ("active", TimePeriod::range(secs(2000), Some(secs(3000)))),
+                ("closed", TimePeriod::range(secs(3000), Some(secs(4000)))),
+            ]
+        );
+
+        let all: Vec<TemporalValue<String>> = db
+            .query_history("user:1", secs(0), secs(10_000))
+            .await
+            .unwrap();
+        assert_eq!(
+            all.last().unwrap().valid_time,
+            TimePeriod::forever(secs(5000))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_pages_follow_cursors() {
+        let db = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        let times = [1000, 2000, 3000, 3000, 4000];
+        for (value, at) in ["a", "b", "c", "d", "e"].into_iter().zip(times) {
+            db.insert("user:1", value, secs(at)).await.unwrap();
+        }
+        db.delete("user:1", secs(5000)).await.unwrap();
+
+        let mut ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db
+                .get_entity_events_page("user:1", cursor.as_ref(), 2)
+                .await
+                .unwrap();
+            ids.extend(page.events.iter().map(Event::id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        let all = db.get_entity_events("user:1").await.unwrap();
+        assert_eq!(ids, all.iter().map(Event::id).collect::<Vec<_>>());
+
+        let expected: Vec<TemporalValue<String>> = db
+            .query_history("user:1", secs(1500), secs(10_000))
+            .await
+            .unwrap();
+        let mut values = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page: HistoryPage<String> = db
+                .query_history_page("user:1", secs(1500), secs(10_000), cursor.as_ref(), 1)
+                .await
+                .unwrap();
+            values.extend(page.values.into_iter().map(|v| (v.value, v.valid_time)));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|v| (v.value, v.valid_time))
+            .collect();
+        assert_eq!(values, expected);
+        assert_eq!(values.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_list_entities_pages() {
+        let db = TemporalDB::in_memory().unwrap();
+        for id in ["user:3", "user:1", "order:1", "user:2"] {
+            db.insert(id, 1, Timestamp::from_secs(1000)).await.unwrap();
+        }
+
+        let first = db.list_entities("user:", None, 2).await.unwrap();
+        assert_eq!(first.entities, vec!["user:1", "user:2"]);
+        let second = db
+            .list_entities("user:", first.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(second.entities, vec!["user:3"]);
+        assert!(second.next_cursor.is_none());
+        assert_eq!(
+            db.list_entities("", None, 10).await.unwrap().entities.len(),
+            4
+        );
+        assert!(db.list_entities("", None, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_internal_entities_are_hidden() {
+        use futures::StreamExt;
+
+        let db = TemporalDB::in_memory().unwrap();
+        let ts = Timestamp::from_secs(1000);
+        let mut events = db.subscribe(SubscriptionFilter::new());
+        db.acquire_lease("job:1", Duration::from_secs(60))
+            .await
+            .unwrap();
+        db.projection_checkpoint("indexer").set(1).await.unwrap();
+        db.set_parent("job:1", "batch:1", ts).await.unwrap();
+        db.insert("job:1", "queued", ts).await.unwrap();
+
+        // Only the user event is published and listed.
+        let next = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.entity_id(), "job:1");
+        let page = db.list_entities("", None, 10).await.unwrap();
+        assert_eq!(page.entities, vec!["job:1"]);
+        assert!(db
+            .insert(&Lease::entity_for("job:1"), "forged", ts)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_children_as_of() {
+        let db = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        db.insert("item:1", "widget", secs(100)).await.unwrap();
+        db.insert("item:2", "gadget", secs(100)).await.unwrap();
+        db.set_parent("item:1", "order:42", secs(100))
+            .await
+            .unwrap();
+        db.set_parent("item:2", "order:42", secs(200))
+            .await
+            .unwrap();
+        db.set_parent("item:1", "order:43", secs(300))
+            .await
+            .unwrap();
+
+        let children: Vec<(String, String)> = db
+            .query_children_as_of("order:42", secs(250))
+            .await
+            .unwrap();
+        assert_eq!(
+            children,
+            vec![
+                ("item:1".to_string(), "widget".to_string()),
+                ("item:2".to_string(), "gadget".to_string()),
+            ]
+        );
+        assert_eq!(db.children_as_of("order:42", secs(300)), vec!["item:2"]);
+        assert_eq!(
+            db.parent_as_of("item:1", secs(300)).as_deref(),
+            Some("order:43")
+        );
+        // Relationship events do not change the child's value.
+        let value: Option<String> = db.query_as_of("item:1", secs(300)).await.unwrap();
+        assert_eq!(value.as_deref(), Some("widget"));
+
+        db.set_parent("order:43", "customer:1", secs(300))
+            .await
+            .unwrap();
+        assert!(db
+            .set_parent("customer:1", "item:1", secs(300))
+            .await
+            .is_err());
+        db.clear_parent("item:2", secs(400)).await.unwrap();
+        assert!(db.children_as_of("order:42", secs(400)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_approximate_queries() {
+        let db = TemporalDB::in_memory().unwrap();
+        for i in 0..1000i64 {
+            db.insert(
+                &format!("sensor:{}", i % 200),
+                serde_json::json!({ "reading": i }),
+                Timestamp::from_secs(i),
+            )
+            .await
+            .unwrap();
+        }
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(500));
+
+        let sample = db.sample_events(start, end, 50).await.unwrap();
+        assert_eq!(sample.len(), 50);
+        assert!(sample.iter().all(|e| e.timestamp() < end));
+
+        let distinct = db.approx_distinct_entities(start, end).await.unwrap();
+        assert!((195..=205).contains(&distinct), "{}", distinct);
+
+        let path = FieldPath::parse("reading").unwrap();
+        let quantiles = db
+            .approx_percentiles(&path, start, end, &[0.5, 1.0])
+            .await
+            .unwrap();
+        assert!((quantiles[0].unwrap() - 250.0).abs() < 10.0);
+        assert_eq!(quantiles[1], Some(499.0));
+    }
+
+    #[tokio::test]
+    async fn test_execute_uses_cheapest_plan() {
+        use crate::index::TagFilter;
+        use crate::query::AccessPath;
+        let ids = |events: Vec<Event>| events.iter().map(Event::id).collect::<Vec<_>>();
+        let db = TemporalDB::in_memory().unwrap();
+        for i in 0..200i64 {
+            let entity_id = format!("sensor:{}", i % 20);
+            db.insert(&entity_id, i, Timestamp::from_secs(i))
+                .await
+                .unwrap();
+        }
+        let payload = EventPayload::from_json(&7).unwrap();
+        let ts = Timestamp::from_secs(50);
+        let event = Event::builder("alarm".to_string(), ts, "sensor:3".to_string(), payload)
+            .tag("critical".to_string())
+            .build();
+        db.append_event(event).await.unwrap();
+
+        let alarms = TemporalQuery::select().with_event_type("alarm");
+        let plan = db.explain(&alarms);
+        assert_eq!(plan.access, AccessPath::TypeIndex("alarm".to_string()));
+        let critical = TemporalQuery::select().with_tag("critical");
+        let entities = vec!["sensor:3".to_string()];
+        let by_tag = AccessPath::TagIndex { entities };
+        assert_eq!(db.explain(&critical).access, by_tag);
+
+        let events = ids(db.execute(&alarms).await.unwrap());
+        assert_eq!(events.len(), 1);
+        assert_eq!(ids(db.execute(&critical).await.unwrap()), events);
+        db.set_optimizer(Optimizer::without_rules());
+        assert_eq!(db.explain(&critical).access, AccessPath::SegmentScan);
+        assert_eq!(ids(db.execute(&critical).await.unwrap()), events);
+
+        let payload = EventPayload::from_json(&8).unwrap();
+        let event = Event::builder("alarm".to_string(), ts, "sensor:4".to_string(), payload)
+            .tags(vec!["critical".to_string(), "muted".to_string()])
+            .build();
+        db.append_event(event).await.unwrap();
+        let unmuted = TemporalQuery::select()
+            .with_tag_filter(TagFilter::tag("critical") & !TagFilter::tag("muted"));
+        assert_eq!(ids(db.execute(&unmuted).await.unwrap()), events);
+        db.set_optimizer(Optimizer::new());
+        assert_eq!(db.explain(&unmuted).access, by_tag);
+        assert_eq!(ids(db.execute(&unmuted).await.unwrap()), events);
+    }
+
+    #[tokio::test]
+    async fn test_secondary_index_serves_field_filters() {
+        use crate::query::TimeRange;
+        let ids = |events: Vec<Event>| events.iter().map(Event::id).collect::<Vec<_>>();
+        let db = TemporalDB::in_memory().unwrap();
+        let status = FieldPath::parse("status").unwrap();
+        for i in 0..200 {
+            // Events before and after the index is created are indexed.
+            if i == 150 {
+                assert!(db.create_index("order:", &status).await.unwrap());
+                assert!(!db.create_index("order:", &status).await.unwrap());
+            }
+            let entity_id = format!("order:{}", i % 20);
+            let status = if i % 20 == 3 { "paid" } else { "open" };
+            let payload = serde_json::json!({ "status": status });
+            db.insert(&entity_id, payload, Timestamp::from_secs(i * 60))
+                .await
+                .unwrap();
+        }
+        let payload = serde_json::json!({ "status": "paid" });
+        db.insert("user:1", payload, Timestamp::from_secs(1))
+            .await
+            .unwrap();
+
+        let paid = TemporalQuery::select()
+            .with_entity_prefix("order:")
+            .with_field_predicate(FieldPredicate::parse("status = 'paid'").unwrap())
+            .with_time_range(TimeRange::From(Timestamp::from_secs(6000).as_nanos()));
+        let plan = db.explain(&paid);
+        let AccessPath::FieldIndex { entries, .. } = &plan.access else {
+            panic!("expected an index plan, got {:?}", plan.access);
+        };
+        assert_eq!(entries.len(), 5);
+        let indexed = ids(db.execute(&paid).await.unwrap());
+        assert_eq!(indexed.len(), 5);
+        db.set_optimizer(Optimizer::without_rules());
+        assert_eq!(ids(db.execute(&paid).await.unwrap()), indexed);
+
+        // Lookups over every entity need an index with an empty prefix.
+        let value = FieldValue::Str("paid".to_string());
+        assert_eq!(db.find_by_field(&status, &value).await.unwrap().len(), 11);
+        db.create_index("", &status).await.unwrap();
+        assert_eq!(db.find_by_field(&status, &value).await.unwrap().len(), 11);
+        let workload = db.index_advisor().workload()[0].1.clone();
+        assert_eq!(workload.scanned, 201 + 11);
+        assert!(db.drop_index("order:", &status));
+        assert!(!db.drop_index("order:", &status));
+    }
+
+    #[tokio::test]
+    async fn test_covering_index_serves_status_history() {
+        let db = TemporalDB::in_memory().unwrap();
+        let status = FieldPath::parse("status").unwrap();
+        let by = FieldPath::parse("by").unwrap();
+        let update = |status: &str, by: &str| serde_json::json!({ "status": status, "by": by });
+        let ts = Timestamp::from_secs;
+        db.insert("user:1", update("new", "signup"), ts(10))
+            .await
+            .unwrap();
+        db.insert("user:1", update("active", "admin"), ts(20))
+            .await
+            .unwrap();
+        let paths = [status.clone(), by.clone()];
+        assert!(db.index_history("user:1", &paths).is_none());
+
+        // Events before and after the index is created are covered.
+        db.create_covering_index("user:", &status, &[by])
+            .await
+            .unwrap();
+        db.insert("user:1", update("banned", "admin"), ts(15))
+            .await
+            .unwrap();
+        let history = db.index_history("user:1", &paths).unwrap();
+        let values: Vec<_> = history.iter().map(|p| p.fields[0].clone()).collect();
+        let str = |s: &str| Some(FieldValue::Str(s.to_string()));
+        assert_eq!(values, [str("new"), str("banned"), str("active")]);
+        assert_eq!(history[1].fields[1], str("admin"));
+    }
+
+    #[cfg(feature = "fulltext")]
+    #[tokio::test]
+    async fn test_fulltext_index_serves_text_matches_in_range() {
+        use crate::index::TextMatch;
+        use crate::query::TimeRange;
+
+        let db = TemporalDB::in_memory().unwrap();
+        let ts = |hours: i64| Timestamp::from_secs(hours * 3600);
+        let notes = [
+            ("audit:1", "Refund issued", 10),
+            ("audit:2", "refund denied", 400),
+            ("audit:3", "Order shipped", 20),
+            ("audit:4", "partial refund", 25),
+        ];
+        for (entity_id, note, secs) in notes {
+            let payload = serde_json::json!({ "note": note });
+            db.insert(entity_id, payload, ts(secs)).await.unwrap();
+        }
+        for hour in 0..1000 {
+            let payload = serde_json::json!({ "note": "routine check" });
+            db.insert("audit:9", payload, ts(hour)).await.unwrap();
+        }
+        let window = TimeRange::Between {
+            start: ts(0).as_nanos(),
+            end: ts(300).as_nanos(),
+        };
+        let query = TemporalQuery::select()
+            .with_entity_prefix("audit:")
+            .with_time_range(window)
+            .with_text_match(TextMatch::parse("note MATCH 'refund'").unwrap());
+        let entities = |events: Vec<Event>| {
+            events
+                .iter()
+                .map(|e| e.entity_id().to_string())
+                .collect::<Vec<_>>()
+        };
+        let scanned = entities(db.execute(&query).await.unwrap());
+        assert_eq!(scanned, ["audit:1", "audit:4"]);
+
+        let note = FieldPath::parse("note").unwrap();
+        assert!(db.create_fulltext_index("audit:", &[note]).await.unwrap());
+        db.insert("audit:5", serde_json::json!({ "note": "refund" }), ts(29))
+            .await
+            .unwrap();
+        let plan = db.explain(&query);
+        assert!(matches!(plan.access, AccessPath::TextIndex { ref entries } if entries.len() == 3));
+        let indexed = entities(db.execute(&query).await.unwrap());
+        assert_eq!(indexed, ["audit:1", "audit:4", "audit:5"]);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_index_online_with_progress() {
+        let db = TemporalDB::in_memory().unwrap();
+        let status = FieldPath::parse("status").unwrap();
+        db.create_index("order:", &status).await.unwrap();
+        for i in 0..60i64 {
+            let status = ["open", "paid"][i as usize % 2];
+            let status = serde_json::json!({ "status": status });
+            let ts = Timestamp::from_secs(i * 600);
+            db.insert(&format!("order:{}", i % 7), status, ts)
+                .await
+                .unwrap();
+        }
+        let missing = db.rebuild_index("order:total", RebuildOptions::new()).await;
+        assert!(matches!(missing, Err(Error::Index(_))));
+
+        let (sender, mut receiver) = tokio::sync::watch::channel(RebuildProgress::default());
+        let options = RebuildOptions::new()
+            .with_chunk_events(10)
+            .with_max_events_per_sec(600)
+            .with_progress(sender);
+        let late = serde_json::json!({ "status": "void" });
+        let (progress, inserted) = tokio::join!(db.rebuild_index("order:status", options), async {
+            receiver.changed().await.unwrap();
+            let ts = Timestamp::from_secs(30_000);
+            db.insert("order:1", late, ts).await
+        });
+        inserted.unwrap();
+        let progress = progress.unwrap();
+        assert!(progress.done && progress.elapsed >= Duration::from_millis(90));
+        assert_eq!((progress.total, progress.eta), (60, Some(Duration::ZERO)));
+        assert_eq!(*receiver.borrow_and_update(), progress);
+
+        // The event committed mid-rebuild, in a chunk read after it, is
+        // indexed once.
+        let index = &db.statistics().indexes[0];
+        assert_eq!((index.entries, index.distinct_values), (61, 3));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_queries_served_from_cache() {
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("order:1", "placed", ts(10)).await.unwrap();
+        let as_of = crate::query::TimeRange::AsOf(ts(100).as_nanos());
+        let query = TemporalQuery::select()
+            .with_entity("order:1")
+            .with_time_range(as_of);
+
+        assert_eq!(db.execute(&query).await.unwrap().len(), 1);
+        assert_eq!(db.execute(&query).await.unwrap().len(), 1);
+        assert_eq!(db.query_cache_stats().hits, 1);
+
+        // Neither event is selected by the query, so its result stays.
+        db.insert("order:2", "placed", ts(20)).await.unwrap();
+        db.insert("order:1", "shipped", ts(200)).await.unwrap();
+        db.execute(&query).await.unwrap();
+        assert_eq!(db.query_cache_stats().hits, 2);
+
+        // A late event before the AS OF time changes it.
+        db.insert("order:1", "paid", ts(50)).await.unwrap();
+        assert_eq!(db.execute(&query).await.unwrap().len(), 2);
+        assert_eq!(db.query_cache_stats().invalidations, 1);
+    }
+
+    #[tokio::test]
+    async fn test_prepared_query_runs_with_bound_values() {
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        for i in 0..30i64 {
+            let entity_id = format!("sensor:{}", i % 3);
+            db.insert(&entity_id, i, ts(i)).await.unwrap();
+        }
+        let slots = vec![ParamSlot::EntityId, ParamSlot::AsOf];
+        let prepared = db.prepare(TemporalQuery::select(), slots).unwrap();
+
+        let values = ["sensor:1".into(), ts(10).into()];
+        let events = db.execute_prepared(&prepared, &values).await.unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|e| e.entity_id() == "sensor:1"));
+        let values = ["sensor:2".into(), ts(29).into()];
+        let events = db.execute_prepared(&prepared, &values).await.unwrap();
+        assert_eq!(events.len(), 10);
+        let swapped = [ts(29).into(), "sensor:2".into()];
+        assert!(db.execute_prepared(&prepared, &swapped).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_memory_budget() {
+        let db = TemporalDB::in_memory().unwrap();
+        for i in 0..100i64 {
+            let entity_id = format!("sensor:{}", i % 4);
+            db.insert(&entity_id, i, Timestamp::from_secs(100 - i))
+                .await
+                .unwrap();
+        }
+        let query = TemporalQuery::select();
+
+        let dir = tempfile::tempdir().unwrap();
+        db.set_query_memory(MemoryBudget::new(2048).with_spill_dir(dir.path()));
+        let events = db.execute(&query).await.unwrap();
+        assert_eq!(events.len(), 100);
+        assert_eq!(events[0].timestamp(), Timestamp::from_secs(1));
+        let counts = db
+            .count_by_entity(Timestamp::from_secs(0), Timestamp::from_secs(200))
+            .await
+            .unwrap();
+        assert_eq!(counts[3], ("sensor:3".to_string(), 25));
+
+        // Without spilling the sort hits the hard cap and fails the query.
+        db.set_query_memory(MemoryBudget::new(2048).without_spilling());
+        let err = db.execute(&query).await.unwrap_err().to_string();
+        assert!(err.contains("memory limit of 2048 bytes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_typed_store_enforces_schema() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct User {
+            name: String,
+            age: Option<u32>,
+        }
+
+        let db = TemporalDB::in_memory().unwrap();
+        assert!(db.typed::<User>("user").is_err());
+        let schema = EntitySchema::new("user", 1)
+            .with_field("name", crate::core::FieldKind::String)
+            .with_optional_field("age", crate::core::FieldKind::Number);
+        db.register_schema(schema).unwrap();
+
+        let users = db.typed::<User>("user").unwrap();
+        let ann = User {
+            name: "Ann".to_string(),
+            age: None,
+        };
+        users
+            .insert("1", &ann, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        assert_eq!(users.get_current("1").await.unwrap(), Some(ann));
+        let events = db.get_entity_events("user:1").await.unwrap();
+        assert_eq!(events[0].metadata.schema, Some("user@1".parse().unwrap()));
+
+        // Writes that do not match the schema are rejected on every path.
+        let bob = serde_json::json!({ "nick": "b" });
+        let err = db
+            .insert("user:2", bob, Timestamp::now())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("schema user@1"), "{}", err);
+        assert!(db.typed::<String>("user").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_diff_between_two_times() {
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        for (entity_id, value, secs) in [
+            ("user:1", "a", 1000),
+            ("user:2", "b", 1000),
+            ("user:4", "d", 1000),
+            ("user:1", "a2", 2000),
+            ("user:2", "b", 2000),
+            ("user:3", "c", 2500),
+            ("user:2", "b2", 3500),
+            ("order:1", "o", 2000),
+        ] {
+            db.insert(entity_id, value, ts(secs)).await.unwrap();
+        }
+        db.delete("user:4", ts(2200)).await.unwrap();
+
+        let diffs: Vec<EntityDiff<String>> = db.diff("user:", ts(1500), ts(3000)).await.unwrap();
+        fn change(d: &EntityDiff<String>) -> (&str, Option<&str>, Option<&str>) {
+            (&d.entity_id, d.before.as_deref(), d.after.as_deref())
+        }
+        let changes: Vec<_> = diffs.iter().map(change).collect();
+        assert_eq!(
+            changes,
+            [
+                ("user:1", Some("a"), Some("a2")),
+                ("user:3", None, Some("c")),
+                ("user:4", Some("d"), None),
+            ]
+        );
+        let reversed = db.diff::<String>("user:", ts(3000), ts(1500)).await;
+        assert!(reversed.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_filters_on_range_queries() {
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        for (actor, tag, value, secs) in [
+            ("alice", "manual", 1, 1000),
+            ("bob", "import", 2, 2000),
+            ("alice", "import", 3, 3000),
+        ] {
+            let payload = EventPayload::from_json(&value).unwrap();
+            let event = Event::builder(
+                "value.changed".to_string(),
+                ts(secs),
+                "order:1".to_string(),
+                payload,
+            )
+            .actor(actor.to_string())
+            .tag(tag.to_string())
+            .build();
+            db.commit(event).await.unwrap();
+        }
+        let scope = db.correlation_scope("checkout-7");
+        scope.insert("order:2", 4, ts(2500)).await.unwrap();
+
+        let by_alice = EventFilter::new().with_actor("alice");
+        let values: Vec<i32> = db
+            .query_range_filtered("order:1", ts(0), ts(5000), &by_alice)
+            .await
+            .unwrap();
+        assert_eq!(values, [1, 3]);
+
+        let imports = EventFilter::new().with_required_tag("import");
+        let events = db.find_events(ts(0), ts(2500), &imports).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].metadata.actor.as_deref(), Some("bob"));
+
+        let checkout = EventFilter::new().with_correlation_id("checkout-7");
+        let events = db.find_events(ts(0), ts(5000), &checkout).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].entity_id(), "order:2");
+    }
+
+    #[tokio::test]
+    async fn test_schema_report_tracks_shape_changes() {
+        use crate::core::FieldKind;
+        use serde_json::json;
+
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("user:1", json!({ "name": "Ann" }), ts(1000))
+            .await
+            .unwrap();
+        db.insert("user:2", json!({ "name": "Bob" }), ts(1100))
+            .await
+            .unwrap();
+        db.insert("order:1", json!(7), ts(1200)).await.unwrap();
+        let v1 = EntitySchema::new("user", 1)
+            .with_field("name", FieldKind::String)
+            .with_field("age", FieldKind::Number);
+        db.register_schema(v1).unwrap();
+        db.insert("user:1", json!({ "name": "Ann", "age": 30 }), ts(2000))
+            .await
+            .unwrap();
+        db.delete("user:2", ts(2500)).await.unwrap();
+
+        let report = db.schema_report("user:", ts(0), ts(3000)).await.unwrap();
+        assert_eq!(report.shapes.len(), 2);
+        assert_eq!(report.shapes[0].schema, None);
+        assert_eq!((report.shapes[0].events, report.shapes[0].entities), (2, 2));
+        assert_eq!(report.shapes[1].schema, Some("user@1".parse().unwrap()));
+        assert_eq!(report.shapes[1].shape.fields["age"], FieldKind::Number);
+        assert_eq!(report.shapes[1].first_seen, ts(2000));
+        assert_eq!(report.changes.len(), 1);
+        let change = &report.changes[0];
+        assert_eq!(change.entity_id, "user:1");
+        assert_eq!((change.at, change.from, change.to), (ts(2000), 0, 1));
+        assert_eq!(report.skipped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_as_of_many() {
+        let db = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        db.insert("user:1", "a", secs(100)).await.unwrap();
+        db.insert("user:1", "b", secs(300)).await.unwrap();
+        db.insert("user:2", "c", secs(100)).await.unwrap();
+        db.insert("user:3", "d", secs(400)).await.unwrap();
+        db.insert("order:1", "e", secs(100)).await.unwrap();
+        db.delete("user:2", secs(200)).await.unwrap();
+
+        let values: HashMap<String, String> = db
+            .query_as_of_many(&["user:1", "user:2", "order:1", "missing"], secs(250))
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["user:1"], "a");
+        assert_eq!(values["order:1"], "e");
+
+        let users: BTreeMap<String, String> =
+            db.query_as_of_prefix("user:", secs(500)).await.unwrap();
+        let users: Vec<_> = users.into_iter().collect();
+        assert_eq!(
+            users,
+            vec![
+                ("user:1".to_string(), "b".to_string()),
+                ("user:3".to_string(), "d".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_numeric_samples() {
+        let db = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        for i in 0..5 {
+            let sample = NumericPayload::new(0.5 * i as f64).with_unit("ms");
+            db.insert_numeric("latency:api", sample, secs(100 + i * 10))
+                .await
+                .unwrap();
+        }
+        db.insert_numeric("requests:api", NumericPayload::new(42), secs(100))
+            .await
+            .unwrap();
+
+        let value: f64 = db
+            .query_as_of("latency:api", secs(125))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, 1.0);
+        let current: Option<i64> = db.get_current("requests:api").await.unwrap();
+        assert_eq!(current, Some(42));
+
+        let samples = db
+            .query_numeric_range("latency:api", secs(110), secs(130))
+            .await
+            .unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].0, secs(110));
+        assert_eq!(samples[1].1, NumericPayload::new(1.0).with_unit("ms"));
+    }
+
+    #[tokio::test]
+    async fn test_append_domain_events() {
+        use serde_json::json;
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        let placed = db
+            .emit("order:1", "OrderPlaced", json!({"total": 30}), ts(1000))
+            .await
+            .unwrap();
+        let payload = EventPayload::from_json(&json!({"carrier": "ups"})).unwrap();
+        let shipped = Event::builder(
+            "OrderShipped".to_string(),
+            ts(2000),
+            "order:1".to_string(),
+            payload,
+        )
+        .actor("warehouse".to_string())
+        .causation_id(placed)
+        .build();
+        db.append_event(shipped).await.unwrap();
+
+        let events = db.get_entity_events("order:1").await.unwrap();
+        let types: Vec<_> = events.iter().map(|e| e.event_type()).collect();
+        assert_eq!(types, ["OrderPlaced", "OrderShipped"]);
+        assert_eq!(events[1].metadata.causation_id, Some(placed));
+        assert_eq!(events[1].metadata.actor.as_deref(), Some("warehouse"));
+
+        let event = |entity: &str| {
+            let payload = EventPayload::from_json(&json!({})).unwrap();
+            let entity = entity.to_string();
+            Event::new("OrderPaid".to_string(), ts(3000), entity, payload)
+        };
+        let batch = vec![event("order:2"), event("$sys:order")];
+        assert!(db.append_events(batch).await.is_err());
+        assert!(db.get_entity_events("order:2").await.unwrap().is_empty());
+        let batch = vec![event("order:1"), event("order:2")];
+        db.append_events(batch).await.unwrap();
+        assert_eq!(db.get_entity_events("order:2").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handlers_run_after_commit() {
+        use crate::core::event_bus::TypedHandler;
+        let db = TemporalDB::in_memory().unwrap();
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let log = totals.clone();
+        let filter = SubscriptionFilter::new().with_entity_prefix("order:");
+        let handler = TypedHandler::new(move |event: &Event, total: u32| {
+            let entity_id = event.entity_id().to_string();
+            log.lock().unwrap().push((entity_id, total));
+            Ok(())
+        });
+        db.register_handler(filter, handler);
+
+        db.insert("order:1", 30, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("user:1", 1, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("order:1", 45, Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        db.handlers_idle().await;
+        let expected = [("order:1".to_string(), 30), ("order:1".to_string(), 45)];
+        assert_eq!(*totals.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_delete_ends_value() {
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("user:1", "active", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.delete("user:1", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+
+        let before: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(1500))
+            .await
+            .unwrap();
+        assert_eq!(before, Some("active".to_string()));
+        let after: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        assert_eq!(after, None);
+        let current: Option<String> = db.get_current("user:1").await.unwrap();
+        assert_eq!(current, None);
+        let history: Vec<String> = db
+            .query_range(
+                "user:1",
+                Timestamp::from_secs(0),
+                Timestamp::from_secs(3000),
+            )
+            .await
+            .unwrap();
+        assert_eq!(history, vec!["active".to_string()]);
+
+        db.insert("user:1", "back", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        let revived: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        assert_eq!(revived, Some("back".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_session_filters_by_security_policy() {
+        let db = TemporalDB::in_memory().unwrap();
+        let ts = Timestamp::from_secs(1000);
+        db.insert("doc:1", serde_json::json!({"tenant": "acme"}), ts)
+            .await
+            .unwrap();
+        db.insert("doc:2", serde_json::json!({"tenant": "globex"}), ts)
+            .await
+            .unwrap();
+        db.set_security_policy(
+            SecurityPolicy::new()
+                .with_predicate("tenant", "payload.tenant == $principal.tenant")
+                .unwrap(),
+        );
+
+        let session = db.session(
+            Principal::new("alice")
+                .with_role("tenant")
+                .with_attribute("tenant", "acme"),
+        );
+        let visible: Option<serde_json::Value> = session.query_as_of("doc:1", ts).await.unwrap();
+        assert!(visible.is_some());
+        let hidden: Option<serde_json::Value> = session.query_as_of("doc:2", ts).await.unwrap();
+        assert!(hidden.is_none());
+        assert!(session.get_entity_events("doc:2").await.unwrap().is_empty());
+
+        let path = FieldPath::parse("tenant").unwrap();
+        let globex = FieldValue::Str("globex".to_string());
+        let hidden = session.find_by_field(&path, &globex).await.unwrap();
+        assert!(hidden.is_empty());
+        assert_eq!(db.find_by_field(&path, &globex).await.unwrap().len(), 1);
+
+        // Bulk, listing, history and sampling reads are filtered too.
+        let many: HashMap<String, serde_json::Value> = session
+            .query_as_of_many(&["doc:1", "doc:2"], ts)
+            .await
+            .unwrap();
+        assert_eq!(many.keys().collect::<Vec<_>>(), vec!["doc:1"]);
+        let prefixed: BTreeMap<String, serde_json::Value> =
+            session.query_as_of_prefix("doc:", ts).await.unwrap();
+        assert_eq!(prefixed.len(), 1);
+        let page = session.list_entities("doc:", None, 1).await.unwrap();
+        assert_eq!(page.entities, vec!["doc:1"]);
+        let last = session
+            .list_entities("doc:", page.next_cursor.as_deref(), 1)
+            .await
+            .unwrap();
+        assert_eq!(last, EntityPage::default());
+        let end = Timestamp::from_secs(2000);
+        let history: Vec<TemporalValue<serde_json::Value>> =
+            session.query_history("doc:2", ts, end).await.unwrap();
+        assert!(history.is_empty());
+        let known: Option<serde_json::Value> =
+            session.query_as_of_system("doc:2", ts, end).await.unwrap();
+        assert!(known.is_none());
+        assert_eq!(session.sample_events(ts, end, 10).await.unwrap().len(), 1);
+        assert_eq!(session.approx_distinct_entities(ts, end).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_folds_only_visible_events() {
+        let db = TemporalDB::in_memory().unwrap();
+        db.register_aggregate(
+            "account",
+            Aggregate::new(
+                |state: Option<serde_json::Value>, event: &Event| {
+                    let total = state.and_then(|s| s.as_i64()).unwrap_or(0);
+                    let amount = event.payload().to_json::<serde_json::Value>()?["amount"]
+                        .as_i64()
+                        .unwrap_or(0);
+                    Ok(Some((total + amount).into()))
+                },
+                2,
+            ),
+        );
+        for (i, tenant) in ["acme", "globex", "acme", "globex"].iter().enumerate() {
+            let deposit = serde_json::json!({ "tenant": tenant, "amount": 10 * (i + 1) });
+            db.insert("account:1", deposit, Timestamp::from_secs(i as i64 + 1))
+                .await
+                .unwrap();
+        }
+        db.set_security_policy(
+            SecurityPolicy::new()
+                .with_predicate("tenant", "payload.tenant == $principal.tenant")
+                .unwrap(),
+        );
+        let session = db.session(
+            Principal::new("alice")
+                .with_role("tenant")
+                .with_attribute("tenant", "acme"),
+        );
+        let at = Timestamp::from_secs(10);
+        assert_eq!(
+            db.query_as_of::<i64>("account:1", at).await.unwrap(),
+            Some(100)
+        );
+        assert_eq!(
+            session.query_as_of::<i64>("account:1", at).await.unwrap(),
+            Some(40)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_stream_new_events() {
+        use futures::StreamExt;
+
+        let db = TemporalDB::in_memory().unwrap();
+        let ts = Timestamp::from_secs(1000);
+        db.insert("doc:0", serde_json::json!({"tenant": "acme"}), ts)
+            .await
+            .unwrap();
+        db.set_security_policy(
+            SecurityPolicy::new()
+                .with_predicate("tenant", "payload.tenant == $principal.tenant")
+                .unwrap(),
+        );
+        let docs = SubscriptionFilter::new().with_entity_prefix("doc:");
+        let mut all = db.subscribe(docs.clone());
+        let mut acme = db
+            .session(
+                Principal::new("alice")
+                    .with_role("tenant")
+                    .with_attribute("tenant", "acme"),
+            )
+            .subscribe(docs);
+
+        for (entity, tenant) in [("doc:1", "globex"), ("user:1", "acme"), ("doc:2", "acme")] {
+            db.insert(entity, serde_json::json!({ "tenant": tenant }), ts)
+                .await
+                .unwrap();
+        }
+        async fn next(stream: &mut BoxStream<'static, Event>) -> Event {
+            tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+        // Only events committed after subscribing arrive.
+        assert_eq!(next(&mut all).await.entity_id(), "doc:1");
+        assert_eq!(next(&mut all).await.entity_id(), "doc:2");
+        assert_eq!(next(&mut acme).await.entity_id(), "doc:2");
+
+        drop(db);
+        assert!(all.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_snapshots() {
+        use futures::StreamExt;
+
         let db = TemporalDB::in_memory().unwrap();
-        let ts1 = Timestamp::from_secs(1000);
-        let ts2 = Timestamp::from_secs(2000);
-        let ts3 = Timestamp::from_secs(3000);
+        let events = db.subscribe(SubscriptionFilter::new());
+        db.register_aggregate(
+            "account",
+            Aggregate::new(
+                |state: Option<serde_json::Value>, event: &Event| {
+                    let total = state.and_then(|s| s.as_i64()).unwrap_or(0);
+                    Ok(Some((total + event.payload().to_json::<i64>()?).into()))
+                },
+                3,
+            ),
+        );
+        for i in 1..=10 {
+            db.insert("account:1", i, Timestamp::from_secs(i * 10))
+                .await
+                .unwrap();
+        }
+        let snapshots = db
+            .get_entity_events(&AggregateSnapshot::entity_for("account:1"))
+            .await
+            .unwrap();
+        assert_eq!(snapshots.len(), 3);
 
-        db.insert("user:1", "v1", ts1).await.unwrap();
-        db.insert("user:1", "v2", ts2).await.unwrap();
-        db.insert("user:1", "v3", ts3).await.unwrap();
+        let balance = |secs| db.query_as_of::<i64>("account:1", Timestamp::from_secs(secs));
+        assert_eq!(balance(5).await.unwrap(), None);
+        assert_eq!(balance(35).await.unwrap(), Some(6));
+        assert_eq!(balance(100).await.unwrap(), Some(55));
 
-        // Query range
-        let values: Vec<String> = db
-            .query_range("user:1", Timestamp::from_secs(1500), Timestamp::from_secs(2500))
+        // A backdated deposit makes the later snapshots stale.
+        db.insert("account:1", 100, Timestamp::from_secs(15))
+            .await
+            .unwrap();
+        let invalidations = db
+            .get_entity_events(&AggregateSnapshot::invalidation_entity_for("account:1"))
             .await
             .unwrap();
+        assert_eq!(invalidations.len(), 1);
+        assert_eq!(balance(10).await.unwrap(), Some(1));
+        assert_eq!(balance(35).await.unwrap(), Some(106));
+        assert_eq!(balance(100).await.unwrap(), Some(155));
 
-        assert_eq!(values.len(), 1);
-        assert_eq!(values[0], "v2");
+        // Snapshots are neither listed nor published.
+        let page = db.list_entities("", None, 10).await.unwrap();
+        assert_eq!(page.entities, vec!["account:1"]);
+        drop(db);
+        let published: Vec<Event> = events.collect().await;
+        assert_eq!(published.len(), 11);
+        assert!(published.iter().all(|e| e.entity_id() == "account:1"));
+    }
+
+    #[tokio::test]
+    async fn test_hot_entities_serve_queries() {
+        let db = TemporalDB::in_memory().unwrap();
+        let threshold = db.hot.config().promote_threshold as i64;
+
+        for i in 0..threshold {
+            db.insert("user:1", format!("v{}", i), Timestamp::from_secs(1000 + i))
+                .await
+                .unwrap();
+        }
+        db.insert("user:2", "cold", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+
+        let hot = db.hot_entities();
+        assert_eq!(hot.len(), 1);
+        assert_eq!(hot[0].entity_id, "user:1");
+
+        // Answers from the pinned window match the journal.
+        let value: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(1001))
+            .await
+            .unwrap();
+        assert_eq!(value, Some("v1".to_string()));
+        let value: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(500))
+            .await
+            .unwrap();
+        assert_eq!(value, None);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshot() {
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("user:1", "active", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("user:1", "inactive", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        let _: Option<String> = db
+            .query_as_of("user:1", Timestamp::from_secs(1500))
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+
+        let metrics = db.metrics();
+        assert_eq!(metrics.events_appended, 2);
+        assert_eq!(metrics.append.count, 2);
+        assert!(metrics.bytes_written > 0);
+        assert_eq!(metrics.query.count, 1);
+        assert_eq!(metrics.flush.count, 1);
+        assert_eq!(metrics.cache_hits + metrics.cache_misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_correlation_scope_saga() {
+        let db = TemporalDB::in_memory().unwrap();
+        let saga = db.correlation_scope("order-42");
+
+        let placed = saga
+            .insert("order:42", "placed", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        let reserved = saga
+            .insert("stock:7", "reserved", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        let charged = saga
+            .insert_caused_by("payment:9", "charged", Timestamp::from_secs(2000), placed)
+            .await
+            .unwrap();
+        db.insert("order:42", "unrelated", Timestamp::from_secs(4000))
+            .await
+            .unwrap();
+
+        let events = db.get_saga("order-42").await.unwrap();
+        let ids: Vec<EventId> = events.iter().map(|e| e.id()).collect();
+        assert_eq!(ids, vec![placed, reserved, charged]);
+        assert_eq!(events[1].metadata.causation_id, Some(placed));
+        assert!(events
+            .iter()
+            .all(|e| e.metadata.correlation_id.as_deref() == Some("order-42")));
+        assert!(db.get_saga("other").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_entity_leases() {
+        let db = TemporalDB::in_memory().unwrap();
+        let ttl = Duration::from_secs(30);
+        let t = Timestamp::from_secs;
+
+        let lease = db.acquire_lease_at("job:1", ttl, t(100)).await.unwrap();
+        assert!(db.acquire_lease_at("job:1", ttl, t(110)).await.is_err());
+        assert!(db.acquire_lease_at("job:2", ttl, t(110)).await.is_ok());
+
+        let renewed = db.renew_lease_at(&lease, ttl, t(120)).await.unwrap();
+        assert_eq!(renewed.expires_at, t(150));
+        db.release_lease_at(&renewed, t(140)).await.unwrap();
+        assert!(db.renew_lease_at(&renewed, ttl, t(141)).await.is_err());
+
+        let next = db.acquire_lease_at("job:1", ttl, t(145)).await.unwrap();
+        assert_ne!(next.lease_id, lease.lease_id);
+
+        // Lease history is queryable as of any time.
+        assert_eq!(db.lease_as_of("job:1", t(99)).await.unwrap(), None);
+        let held = db.lease_as_of("job:1", t(135)).await.unwrap().unwrap();
+        assert_eq!(held.lease_id, lease.lease_id);
+        assert_eq!(db.lease_as_of("job:1", t(142)).await.unwrap(), None);
+        assert_eq!(db.lease_as_of("job:1", t(200)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_projection_checkpoints() {
+        let db = TemporalDB::in_memory().unwrap();
+        let indexer = db.projection_checkpoint("search-indexer");
+        assert_eq!(indexer.get().await.unwrap(), None);
+
+        let first = indexer.set(10).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        indexer.set(25).await.unwrap();
+        assert_eq!(indexer.get().await.unwrap().unwrap().lsn, 25);
+        assert_eq!(db.projection_checkpoint("other").get().await.unwrap(), None);
+
+        // Earlier progress stays queryable.
+        let earlier: Option<ProjectionCheckpoint> = db
+            .query_as_of(
+                &format!("{}search-indexer", PROJECTION_ENTITY_PREFIX),
+                first.updated_at,
+            )
+            .await
+            .unwrap();
+        assert_eq!(earlier, Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_consumer_ingests_exactly_once() {
+        use crate::outbox::InMemoryOutbox;
+        use serde_json::json;
+
+        let ts = Timestamp::from_secs;
+        let db = TemporalDB::in_memory().unwrap();
+        let outbox = InMemoryOutbox::new("orders");
+        for i in 1..=5 {
+            outbox.push("order:1", "order.updated", json!(i), ts(i));
+        }
+        let consumer = db.outbox_consumer(outbox).with_batch_size(2);
+        assert_eq!(consumer.drain().await.unwrap(), 5);
+        assert_eq!(consumer.checkpoint().await.unwrap().unwrap().offset, 5);
+        assert_eq!(consumer.poll_once().await.unwrap(), 0);
+
+        // A crash after appending record 6 but before the checkpoint
+        let outbox = consumer.source();
+        let offset = outbox.push("order:2", "order.created", json!(6), ts(6));
+        outbox.push("order:2", "order.updated", json!(7), ts(7));
+        let mut event = Event::new(
+            "order.created".to_string(),
+            ts(6),
+            "order:2".to_string(),
+            EventPayload::from_json(&6).unwrap(),
+        );
+        event.metadata.tags.push(outbox_tag("orders", offset));
+        db.commit(event).await.unwrap();
+
+        assert_eq!(consumer.drain().await.unwrap(), 2);
+        assert_eq!(db.get_entity_events("order:1").await.unwrap().len(), 5);
+        let resumed = db.get_entity_events("order:2").await.unwrap();
+        assert_eq!(resumed.len(), 2);
+        assert_eq!(resumed[1].metadata.tags, ["outbox:orders@7"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_mount() {
+        use crate::storage::{InMemoryWAL, SegmentedJournal};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join(SEGMENTS_DIR), InMemoryWAL::new()).unwrap();
+        let event = |entity_id: &str, value: &str, secs: i64| {
+            Event::new(
+                "value.changed".to_string(),
+                Timestamp::from_secs(secs),
+                entity_id.to_string(),
+                EventPayload::from_json(&value).unwrap(),
+            )
+        };
+
+        journal.append(event("user:1", "v1", 1000)).await.unwrap();
+        journal.flush().await.unwrap();
+        let cutoff = Timestamp::now();
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        journal.append(event("user:1", "v2", 2000)).await.unwrap();
+        journal.append(event("user:2", "v1", 2000)).await.unwrap();
+        journal.flush().await.unwrap();
+
+        let db = TemporalDB::open_read_only(temp_dir.path()).await.unwrap();
+        assert!(db.is_read_only());
+        let value: Option<String> = db.get_current("user:1").await.unwrap();
+        assert_eq!(value, Some("v2".to_string()));
+        assert!(db.insert("user:1", "v3", Timestamp::now()).await.is_err());
+
+        let snapshot = TemporalDB::open_as_of(temp_dir.path(), cutoff)
+            .await
+            .unwrap();
+        let value: Option<String> = snapshot
+            .query_as_of("user:1", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        assert_eq!(value, Some("v1".to_string()));
+        let value: Option<String> = snapshot.get_current("user:1").await.unwrap();
+        assert_eq!(value, Some("v1".to_string()));
+        let page = snapshot.list_entities("user:", None, 10).await.unwrap();
+        assert_eq!(page.entities, ["user:1"]);
+        assert!(snapshot
+            .get_entity_events("user:2")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_view_export_and_import() {
+        let leader = TemporalDB::in_memory().unwrap();
+        leader
+            .insert("user:1", "v1", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        let follower = TemporalDB::in_memory().unwrap();
+        let view = leader.export_view().await.unwrap();
+        follower.import_view(view).await.unwrap();
+        let value: Option<String> = follower.get_current("user:1").await.unwrap();
+        assert_eq!(value, Some("v1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_triggers_fire_when_effective() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let builder = TemporalDB::builder().with_data_dir(temp_dir.path());
+        let soon = || Timestamp::from_nanos(Timestamp::now().as_nanos() + 200_000_000);
+
+        let db = builder.clone().build().await.unwrap();
+        let (_, mut prices) = db
+            .register_trigger(TriggerFilter::new().with_entity_prefix("price:"))
+            .await
+            .unwrap();
+        db.insert("price:0", 10, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("stock:1", 5, soon()).await.unwrap();
+        db.insert("price:1", 20, soon()).await.unwrap();
+        let fired = tokio::time::timeout(Duration::from_secs(5), prices.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fired.event.entity_id(), "price:1");
+
+        // A fact still pending at shutdown fires after the restart.
+        db.insert("price:2", 30, soon()).await.unwrap();
+        drop(prices);
+        drop(db);
+        let db = builder.build().await.unwrap();
+        let (_, mut prices) = db.register_trigger(TriggerFilter::new()).await.unwrap();
+        let fired = tokio::time::timeout(Duration::from_secs(5), prices.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fired.event.entity_id(), "price:2");
+        // Facts delivered before the restart do not fire again.
+        assert!(prices.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builder_reopens_data_dir() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let builder = TemporalDB::builder()
+            .with_data_dir(temp_dir.path())
+            .with_block_cache_blocks(4);
+
+        let db = builder.clone().build().await.unwrap();
+        db.insert("user:1", "v1", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        db.insert("user:1", "v2", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+        drop(db);
+
+        let db = builder.build().await.unwrap();
+        assert_eq!(db.config().journal, JournalKind::Segmented);
+        let value: Option<String> = db.get_current("user:1").await.unwrap();
+        assert_eq!(value, Some("v2".to_string()));
+        assert_eq!(db.get_entity_events("user:1").await.unwrap().len(), 2);
+
+        assert!(TemporalDB::builder()
+            .with_flush_interval(Duration::ZERO)
+            .build()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retention_recomputes_statistics() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db = TemporalDB::builder()
+            .with_data_dir(temp_dir.path())
+            .build()
+            .await
+            .unwrap();
+        let value = FieldPath::parse("value").unwrap();
+        db.create_index("sensor:", &value).await.unwrap();
+        for i in 0..10 {
+            let reading = serde_json::json!({ "value": i });
+            db.insert("sensor:1", reading, Timestamp::from_secs(1000 + i * 86_400))
+                .await
+                .unwrap();
+        }
+        db.flush().await.unwrap();
+        let reading = serde_json::json!({ "value": 10 });
+        db.insert("sensor:1", reading, Timestamp::now())
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        assert_eq!(db.statistics().total_events, 11);
+        assert_eq!(db.statistics().indexes[0].partitions, 11);
+
+        db.set_retention(RetentionPolicy::new().with_max_age(Duration::from_secs(3600)));
+        let report = db.enforce_retention().await.unwrap();
+        assert_eq!(report.events, 10);
+        let statistics = db.statistics();
+        assert_eq!(statistics.total_events, 1);
+        assert_eq!(statistics.time_buckets.len(), 1);
+        // Index partitions of the expired days were dropped with them.
+        let index = &statistics.indexes[0];
+        assert_eq!((index.entries, index.partitions), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_crdt_entity_merges_remote_replicas() {
+        use crate::crdt::{CrdtEntity, GCounter, GCounterOp, CRDT};
+
+        let db = TemporalDB::builder()
+            .with_hlc_node(1)
+            .build()
+            .await
+            .unwrap();
+        let views = db.crdt::<GCounter>("metric:views");
+        views.apply(GCounterOp::Increment(3)).await.unwrap();
+        let before = Timestamp::now();
+        views.apply(GCounterOp::Increment(4)).await.unwrap();
+
+        let mut remote = GCounter::empty(2);
+        remote.apply(2, &GCounterOp::Increment(10));
+        views.merge_remote(&remote).await.unwrap();
+        views.merge_remote(&remote).await.unwrap();
+        assert_eq!(views.state().await.unwrap().value(), 17);
+        assert_eq!(views.state_as_of(before).await.unwrap().value(), 3);
+
+        // The remote node converges on the same value from our state.
+        remote.merge(&views.state().await.unwrap());
+        assert_eq!(remote.value(), 17);
+        db.insert("metric:views", 1, Timestamp::now())
+            .await
+            .unwrap();
+        assert!(views.state().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_follower_reads_up_to_replication_watermark() {
+        let leader = TemporalDB::in_memory().unwrap();
+        let follower = TemporalDB::in_memory().unwrap();
+        let secs = Timestamp::from_secs;
+        leader.insert("user:1", "active", secs(100)).await.unwrap();
+        let read = follower.follower_query_as_of::<String>("user:1", secs(100), secs(0));
+        assert!(matches!(
+            read.await,
+            Err(Error::Stale {
+                watermark: None,
+                ..
+            })
+        ));
+
+        let events = leader.get_entity_events("user:1").await.unwrap();
+        let through = events[0].metadata.transaction_time;
+        follower
+            .apply_replicated("eu", events, through)
+            .await
+            .unwrap();
+        follower
+            .apply_replicated("eu", Vec::new(), secs(0))
+            .await
+            .unwrap();
+        assert_eq!(follower.replication_watermark(), Some(through));
+        // Another source that has not shipped as far holds reads back.
+        follower
+            .apply_replicated("us", Vec::new(), secs(0))
+            .await
+            .unwrap();
+        let read = follower.follower_query_as_of::<String>("user:1", secs(100), through);
+        assert!(matches!(
+            read.await,
+            Err(Error::Stale {
+                watermark: Some(w),
+                ..
+            }) if w == secs(0)
+        ));
+        follower
+            .apply_replicated("us", Vec::new(), through)
+            .await
+            .unwrap();
+        let value: Option<String> = follower
+            .follower_query_as_of("user:1", secs(100), through)
+            .await
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("active"));
+        // A correction the leader records later may not have arrived.
+        let later = Timestamp::from_nanos(through.as_nanos() + 1);
+        let read = follower.follower_query_as_of::<String>("user:1", secs(100), later);
+        assert!(matches!(read.await, Err(Error::Stale { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_crdt_events_replicate_through_subscriptions() {
+        use crate::crdt::{GCounter, GCounterOp, CRDT_TAG};
+        use futures::StreamExt;
+
+        let open = |node| TemporalDB::builder().with_hlc_node(node).build();
+        let (a, b) = (open(1).await.unwrap(), open(2).await.unwrap());
+        let mut feed = a.subscribe(SubscriptionFilter::new().with_tag(CRDT_TAG));
+        a.crdt::<GCounter>("metric:views")
+            .apply(GCounterOp::Increment(3))
+            .await
+            .unwrap();
+        let views = b.crdt::<GCounter>("metric:views");
+        views.apply(GCounterOp::Increment(4)).await.unwrap();
+        let event = feed.next().await.unwrap();
+
+        // Operations of both nodes count wherever they are folded, and
+        // replaying them is a no-op.
+        let report = b.apply_remote_events(vec![event.clone()]).await.unwrap();
+        assert_eq!((report.applied, report.resolved), (1, 0));
+        let report = b.apply_remote_events(vec![event]).await.unwrap();
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(views.state().await.unwrap().value(), 7);
+        let events = b.get_entity_events("metric:views").await.unwrap();
+        a.apply_remote_events(events).await.unwrap();
+        let state = a.crdt::<GCounter>("metric:views").state().await.unwrap();
+        assert_eq!(state.value(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_crdt_entity_prunes_stable_tombstones() {
+        use crate::core::hlc::HybridClock;
+        use crate::crdt::{ORSet, ORSetOp};
+
+        let db = TemporalDB::in_memory().unwrap();
+        let tags = db.crdt::<ORSet<String>>("post:1:tags");
+        let clock = HybridClock::new(0);
+        for tag in ["rust", "db"] {
+            let add = ORSetOp::Add(tag.to_string(), clock.now());
+            tags.apply(add).await.unwrap();
+        }
+        let remove = ORSetOp::Remove("db".to_string(), clock.now());
+        tags.apply(remove).await.unwrap();
+        assert_eq!(tags.state().await.unwrap().tombstones(), 1);
+
+        let before = Timestamp::from_secs(0);
+        assert_eq!(tags.collect_garbage(before).await.unwrap(), 0);
+        let stable = Timestamp::now();
+        assert_eq!(tags.collect_garbage(stable).await.unwrap(), 1);
+        let state = tags.state().await.unwrap();
+        assert_eq!(state.elements(), [&"rust".to_string()]);
+        assert_eq!(state.tombstones(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_remote_writes_resolve_conflicts_by_policy() {
+        use crate::crdt::{ManualConflictQueue, Resolution};
+
+        let open = |node| TemporalDB::builder().with_hlc_node(node).build();
+        let (a, b) = (open(1).await.unwrap(), open(2).await.unwrap());
+        let queue = ManualConflictQueue::new();
+        for db in [&a, &b] {
+            db.set_resolution_policy("audit", queue.clone());
+        }
+        let ts = Timestamp::from_secs(100);
+        a.insert("order:1", "placed", ts).await.unwrap();
+        a.insert("audit:1", "checked", ts).await.unwrap();
+        b.insert("order:1", "cancelled", ts).await.unwrap();
+        b.insert("audit:1", "flagged", ts).await.unwrap();
+        b.insert("order:2", "placed", ts).await.unwrap();
+
+        async fn sync(from: &TemporalDB, ids: &[&str]) -> Vec<Event> {
+            let mut events = Vec::new();
+            for id in ids {
+                events.extend(from.get_entity_events(id).await.unwrap());
+            }
+            events
+        }
+        let ids = ["order:1", "order:2", "audit:1"];
+        let report = a.apply_remote_events(sync(&b, &ids).await).await.unwrap();
+        let expected = SyncReport {
+            applied: 1,
+            duplicates: 0,
+            resolved: 1,
+            deferred: 1,
+        };
+        assert_eq!(report, expected);
+        b.apply_remote_events(sync(&a, &ids).await).await.unwrap();
+        // Replaying is a no-op apart from deferred conflicts.
+        let report = a.apply_remote_events(sync(&b, &ids).await).await.unwrap();
+        assert_eq!((report.applied, report.resolved), (0, 0));
+
+        // Both nodes keep the later write of order:1.
+        for db in [&a, &b] {
+            let value: Option<String> = db.query_as_of("order:1", ts).await.unwrap();
+            assert_eq!(value.as_deref(), Some("cancelled"));
+        }
+        let conflicts = a.conflicts("order:1").await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].policy, "last_writer_wins");
+        assert_eq!(conflicts[0].resolution, Resolution::TakeRemote);
+        let kept = b.conflicts("order:1").await.unwrap();
+        assert_eq!(kept[0].resolution, Resolution::KeepLocal);
+
+        // Deferred conflicts leave each node's write for an operator.
+        assert!(a.conflicts("audit:1").await.unwrap().is_empty());
+        assert_eq!(queue.len(), 3);
+        let value: Option<String> = a.query_as_of("audit:1", ts).await.unwrap();
+        assert_eq!(value.as_deref(), Some("checked"));
+    }
+
+    #[tokio::test]
+    async fn test_hlc_node_stamps_committed_events() {
+        use crate::core::hlc::HlcTimestamp;
+
+        let db = TemporalDB::builder()
+            .with_hlc_node(7)
+            .with_ordering(EventOrdering::HybridClock)
+            .build()
+            .await
+            .unwrap();
+        let ts = Timestamp::from_secs(100);
+        db.insert("order:1", "placed", ts).await.unwrap();
+        db.insert("order:1", "paid", ts).await.unwrap();
+
+        // A write from a node whose clock is ahead moves this clock past it.
+        let ahead = Timestamp::from_nanos(Timestamp::now().as_nanos() + 100_000_000);
+        let remote = HlcTimestamp::new(ahead, 3, 2);
+        let stamped = |value: &str, stamp| {
+            let payload = EventPayload::from_json(&value).unwrap();
+            let (event_type, entity_id) = ("value.changed".to_string(), "order:1".to_string());
+            Event::builder(event_type, ts, entity_id, payload)
+                .hlc(stamp)
+                .build()
+        };
+        db.append_event(stamped("shipped", remote)).await.unwrap();
+        db.insert("order:1", "delivered", ts).await.unwrap();
+
+        let events = db.get_entity_events("order:1").await.unwrap();
+        let stamps: Vec<HlcTimestamp> = events.iter().filter_map(Event::hlc).collect();
+        assert_eq!(stamps.len(), 4);
+        assert!(stamps.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(stamps[2], remote);
+        assert_eq!((stamps[3].physical, stamps[3].node), (ahead, 7));
+        let value: Option<String> = db.query_as_of("order:1", ts).await.unwrap();
+        assert_eq!(value.as_deref(), Some("delivered"));
+
+        let skewed = HlcTimestamp::new(Timestamp::from_secs(i64::MAX / 1_000_000_000), 0, 2);
+        assert!(db.append_event(stamped("lost", skewed)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_over_windows() {
+        use serde_json::json;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db = TemporalDB::builder()
+            .with_data_dir(temp_dir.path())
+            .build()
+            .await
+            .unwrap();
+        let ts = Timestamp::from_secs;
+        for (entity_id, secs, temp) in [
+            ("sensor:1", 1000, 10),
+            ("sensor:2", 1030, 20),
+            ("other:1", 1040, 99),
+            ("sensor:1", 1070, 5),
+        ] {
+            let reading = json!({ "temp": temp });
+            db.insert(entity_id, reading, ts(secs)).await.unwrap();
+        }
+        db.flush().await.unwrap();
+        db.insert("sensor:2", json!({ "temp": 7 }), ts(1110))
+            .await
+            .unwrap();
+        db.insert("sensor:2", json!({ "note": "off" }), ts(1115))
+            .await
+            .unwrap();
+
+        let path = FieldPath::parse("temp").unwrap();
+        let window = Duration::from_secs(60);
+        let avg = db
+            .aggregate("sensor:", ts(1000), ts(1200), window, AggFn::Avg, &path)
+            .await
+            .unwrap();
+        let windows: Vec<_> = avg.iter().map(|b| (b.start, b.count, b.value)).collect();
+        assert_eq!(windows, [(ts(1000), 2, 15.0), (ts(1060), 2, 6.0)]);
+        let count = db
+            .aggregate("sensor:", ts(1000), ts(1100), window, AggFn::Count, &path)
+            .await
+            .unwrap();
+        assert_eq!(count[1].value, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_restores_hot_set() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let builder = TemporalDB::builder().with_data_dir(temp_dir.path());
+
+        let db = builder.clone().build().await.unwrap();
+        for i in 0..db.hot.config().promote_threshold as i64 {
+            db.insert("user:1", i, Timestamp::from_secs(1000 + i))
+                .await
+                .unwrap();
+        }
+        db.insert("user:2", 0, Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        db.save_hot_set().unwrap();
+        drop(db);
+
+        let db = builder.clone().build().await.unwrap();
+        assert!(db.hot_entities().is_empty());
+        drop(db);
+
+        let db = builder.with_warmup().build().await.unwrap();
+        let hot = db.hot_entities();
+        assert_eq!(hot.len(), 1);
+        assert_eq!(hot[0].entity_id, "user:1");
+        let report = db.warm_up().await.unwrap();
+        assert_eq!(report.entities, 1);
+        assert!(report.blocks > 0);
+    }
+
+    #[tokio::test]
+    async fn test_view_recovers_from_snapshot() {
+        use crate::storage::ViewSnapshot;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let builder = TemporalDB::builder().with_data_dir(temp_dir.path());
+        let db = builder.clone().build().await.unwrap();
+        for (i, value) in ["v1", "v2"].iter().enumerate() {
+            db.insert("user:1", value, Timestamp::from_secs(1000 + i as i64))
+                .await
+                .unwrap();
+        }
+        assert_eq!(db.snapshot_view().await.unwrap(), Some(2));
+        db.insert("user:2", "w1", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        drop(db);
+
+        let segments_dir = temp_dir.path().join(SEGMENTS_DIR);
+        assert_eq!(ViewSnapshot::load(&segments_dir).unwrap().unwrap().lsn, 2);
+        let db = builder.build().await.unwrap();
+        let value: Option<String> = db.get_current("user:1").await.unwrap();
+        assert_eq!(value, Some("v2".to_string()));
+        let value: Option<String> = db.get_current("user:2").await.unwrap();
+        assert_eq!(value, Some("w1".to_string()));
+        assert_eq!(db.snapshot_view().await.unwrap(), Some(3));
+        let in_memory = TemporalDB::in_memory().unwrap();
+        assert_eq!(in_memory.snapshot_view().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_export_snapshot() {
+        use crate::export::SnapshotArchive;
+
+        let db = TemporalDB::in_memory().unwrap();
+        db.insert("user:1", "v1", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("user:1", "v2", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+        db.insert("user:2", "late", Timestamp::from_secs(4000))
+            .await
+            .unwrap();
+        db.set_parent("user:1", "org:1", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.insert("user:3", "gone", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.delete("user:3", Timestamp::from_secs(1500))
+            .await
+            .unwrap();
+        db.insert("user:4", "deleted later", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        db.delete("user:4", Timestamp::from_secs(3000))
+            .await
+            .unwrap();
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("snapshot");
+        let options = SnapshotOptions {
+            include_history: true,
+        };
+        let manifest = db
+            .export_snapshot_with(Timestamp::from_secs(2000), &dir, options)
+            .await
+            .unwrap();
+        assert_eq!(manifest.state.records, 2);
+
+        let archive = SnapshotArchive::open(&dir).unwrap();
+        archive.verify().unwrap();
+        let state = archive.read_state().unwrap();
+        assert_eq!(state[0].entity_id, "user:1");
+        assert_eq!(state[0].value, serde_json::json!("v1"));
+        assert_eq!(state[1].entity_id, "user:4");
+        // Deleted entities keep their history; the parent link is internal
+        // and not exported.
+        assert_eq!(archive.read_history().unwrap().unwrap().len(), 4);
+
+        // An existing archive is never overwritten.
+        assert!(db
+            .export_snapshot(Timestamp::from_secs(2000), &dir)
+            .await
+            .is_err());
     }
 }
@@ -0,0 +1,183 @@
+//! Version negotiation for rolling upgrades
+//!
+//! Every node advertises the range of segment, WAL and RPC format versions
+//! it can read in a [`Handshake`]. Two nodes talk, and a cluster writes, at
+//! the highest version all parties support. During an upgrade, upgraded
+//! nodes keep writing the old segment and WAL formats (see
+//! [`TemporalDB::pin_formats`](crate::db::TemporalDB::pin_formats)) while
+//! reading both, and switch once the last old node is gone.
+
+use crate::error::{Error, Result};
+use crate::storage::segment_file::{MIN_SEGMENT_VERSION, SEGMENT_VERSION};
+use crate::storage::wal::{MIN_WAL_VERSION, WAL_VERSION};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the node-to-node RPC protocol
+pub const RPC_VERSION: u32 = 1;
+
+/// Oldest RPC protocol version still served
+pub const MIN_RPC_VERSION: u32 = 1;
+
+/// Inclusive range of supported versions of one format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionRange {
+    /// Oldest supported version
+    pub min: u32,
+    /// Newest supported version
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// Versions `min` to `max`
+    pub fn new(min: u32, max: u32) -> Self {
+        Self { min, max }
+    }
+
+    /// Whether `version` is in the range
+    pub fn contains(&self, version: u32) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// Versions in both ranges, if any
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        let range = Self::new(self.min.max(other.min), self.max.min(other.max));
+        (range.min <= range.max).then_some(range)
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..={}", self.min, self.max)
+    }
+}
+
+/// Format versions a node supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolVersions {
+    /// Segment file format
+    pub segment: VersionRange,
+    /// WAL record format
+    pub wal: VersionRange,
+    /// RPC protocol
+    pub rpc: VersionRange,
+}
+
+impl ProtocolVersions {
+    /// Versions supported by this build
+    pub fn current() -> Self {
+        Self {
+            segment: VersionRange::new(MIN_SEGMENT_VERSION as u32, SEGMENT_VERSION as u32),
+            wal: VersionRange::new(MIN_WAL_VERSION, WAL_VERSION),
+            rpc: VersionRange::new(MIN_RPC_VERSION, RPC_VERSION),
+        }
+    }
+
+    /// Versions supported by both sides; fails if a format has none in
+    /// common
+    pub fn intersect(&self, other: &Self) -> Result<Self> {
+        let common = |name: &str, a: &VersionRange, b: &VersionRange| {
+            a.intersect(b).ok_or_else(|| {
+                Error::Distributed(format!("No common {} version: {} vs {}", name, a, b))
+            })
+        };
+        Ok(Self {
+            segment: common("segment", &self.segment, &other.segment)?,
+            wal: common("WAL", &self.wal, &other.wal)?,
+            rpc: common("RPC", &self.rpc, &other.rpc)?,
+        })
+    }
+
+    /// Newest version of every format
+    pub fn newest(&self) -> NegotiatedVersions {
+        NegotiatedVersions {
+            segment: self.segment.max,
+            wal: self.wal.max,
+            rpc: self.rpc.max,
+        }
+    }
+}
+
+impl Default for ProtocolVersions {
+    fn default() -> Self {
+        Self::current()
+    }
+}
+
+/// Versions to use when writing or talking to peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NegotiatedVersions {
+    /// Segment format to write
+    pub segment: u32,
+    /// WAL record format to write
+    pub wal: u32,
+    /// RPC protocol to speak
+    pub rpc: u32,
+}
+
+/// First message exchanged when two nodes connect
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Sender node ID
+    pub node_id: String,
+    /// Sender software version
+    pub software_version: String,
+    /// Versions the sender supports
+    pub protocols: ProtocolVersions,
+}
+
+impl Handshake {
+    /// Handshake of this build
+    pub fn new(node_id: impl Into<String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocols: ProtocolVersions::current(),
+        }
+    }
+
+    /// Versions to use with the peer that sent `remote`
+    pub fn negotiate(&self, remote: &Handshake) -> Result<NegotiatedVersions> {
+        Ok(self.protocols.intersect(&remote.protocols)?.newest())
+    }
+}
+
+/// Versions every node in `nodes` supports, or an error if some format has
+/// no version all of them read
+pub fn negotiate_cluster<'a>(
+    nodes: impl IntoIterator<Item = &'a ProtocolVersions>,
+) -> Result<NegotiatedVersions> {
+    let mut common = ProtocolVersions::current();
+    for node in nodes {
+        common = common.intersect(node)?;
+    }
+    Ok(common.newest())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiates_newest_common_versions() {
+        let new = Handshake::new("b");
+        let mut old = Handshake::new("a");
+        old.protocols.segment = VersionRange::new(1, 1);
+
+        let versions = new.negotiate(&old).unwrap();
+        assert_eq!(versions.segment, 1);
+        assert_eq!(versions.rpc, RPC_VERSION);
+        assert_eq!(
+            negotiate_cluster([&new.protocols, &old.protocols]).unwrap(),
+            versions
+        );
+        assert_eq!(
+            negotiate_cluster([&new.protocols]).unwrap().segment,
+            SEGMENT_VERSION as u32
+        );
+
+        let mut future = Handshake::new("c");
+        future.protocols.rpc = VersionRange::new(RPC_VERSION + 1, RPC_VERSION + 2);
+        assert!(new.negotiate(&future).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Queries over a dataset split across databases.
+//!
+//! A [`FederatedDB`] sends each query to every member database (e.g. one
+//! store per region) concurrently, merges the results by timestamp and tags
+//! every result with the member it came from. A failing member fails the
+//! whole query, so a result is never silently partial.
+
+use crate::core::event::Event;
+use crate::core::filter::EventFilter;
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::error::{Error, Result};
+use crate::query::parser::TemporalQuery;
+use futures::future::try_join_all;
+use std::future::Future;
+use std::sync::Arc;
+
+/// A result and the member database it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sourced<T> {
+    /// Name of the member
+    pub member: String,
+    /// The result
+    pub item: T,
+}
+
+/// Facade querying several databases as one
+#[derive(Clone, Default)]
+pub struct FederatedDB {
+    members: Vec<(String, Arc<TemporalDB>)>,
+}
+
+impl FederatedDB {
+    /// Create a federation without members
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the member `db`, named `name` in provenance
+    pub fn with_member(mut self, name: impl Into<String>, db: Arc<TemporalDB>) -> Self {
+        self.members.push((name.into(), db));
+        self
+    }
+
+    /// Names of the members, in the order they were added
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.members.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Run `query` on every member and merge the events by timestamp; ties
+    /// keep the member order
+    async fn fan_out<'a, F, Fut>(&'a self, query: F) -> Result<Vec<Sourced<Event>>>
+    where
+        F: Fn(&'a TemporalDB) -> Fut,
+        Fut: Future<Output = Result<Vec<Event>>>,
+    {
+        if self.members.is_empty() {
+            return Err(Error::Configuration(
+                "Federation has no members".to_string(),
+            ));
+        }
+        let results = try_join_all(self.members.iter().map(|(name, db)| {
+            let events = query(db);
+            async move {
+                events
+                    .await
+                    .map_err(|e| Error::Distributed(format!("Member {} failed: {}", name, e)))
+            }
+        }))
+        .await?;
+        let mut merged: Vec<_> = self
+            .members
+            .iter()
+            .zip(results)
+            .flat_map(|((name, _), events)| {
+                events.into_iter().map(|event| Sourced {
+                    member: name.clone(),
+                    item: event,
+                })
+            })
+            .collect();
+        merged.sort_by_key(|sourced| sourced.item.timestamp());
+        Ok(merged)
+    }
+
+    /// Execute a parsed `SELECT` on every member
+    pub async fn execute(&self, query: &TemporalQuery) -> Result<Vec<Sourced<Event>>> {
+        self.fan_out(|db| db.execute(query)).await
+    }
+
+    /// Events in `[start, end)` matching `filter` on every member, see
+    /// [`TemporalDB::find_events`]
+    pub async fn find_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Sourced<Event>>> {
+        self.fan_out(|db| db.find_events(start, end, filter)).await
+    }
+
+    /// Events of an entity on every member. Members whose entity filter
+    /// rules the entity out are skipped.
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Sourced<Event>>> {
+        self.fan_out(|db| async move {
+            if !db.may_contain_entity(entity_id).await {
+                return Ok(Vec::new());
+            }
+            db.get_entity_events(entity_id).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_merges_members_by_timestamp() {
+        let eu = Arc::new(TemporalDB::in_memory().unwrap());
+        let us = Arc::new(TemporalDB::in_memory().unwrap());
+        eu.insert("order:1", "placed", Timestamp::from_secs(1000))
+            .await
+            .unwrap();
+        us.insert("order:1", "paid", Timestamp::from_secs(1500))
+            .await
+            .unwrap();
+        eu.insert("order:1", "shipped", Timestamp::from_secs(2000))
+            .await
+            .unwrap();
+
+        let federation = FederatedDB::new()
+            .with_member("eu", eu)
+            .with_member("us", us);
+        let events = federation.get_entity_events("order:1").await.unwrap();
+        let members: Vec<_> = events.iter().map(|e| e.member.as_str()).collect();
+        assert_eq!(members, ["eu", "us", "eu"]);
+
+        let start = Timestamp::from_secs(1200);
+        let found = federation
+            .find_events(start, Timestamp::now(), &EventFilter::new())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(FederatedDB::new()
+            .get_entity_events("order:1")
+            .await
+            .is_err());
+    }
+}
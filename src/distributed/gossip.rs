@@ -1,13 +1,353 @@
 //! Gossip protocol for membership
+//!
+//! Every node keeps a heartbeat counter and the latest [`NodeMetadata`] of
+//! each member it has heard of. A [`GossipMessage`] carries the sender's
+//! whole view; receivers keep, per node, the entry with the highest
+//! incarnation and heartbeat. The incarnation increases every time a node
+//! starts, so a restarted node's fresh heartbeat wins over the one it had
+//! before. Members whose heartbeat has not advanced within the failure
+//! timeout are reported as down.
+//!
+//! Nodes also gossip the time up to which they have applied every write
+//! (see [`GossipNode::report_seen`]); the earliest such time across the
+//! cluster is the causal stability watermark, before which CRDT tombstones
+//! can be dropped, see [`ClusterStatus::stability_watermark`].
+//!
+//! Metadata includes the features a node supports, so during a rolling
+//! upgrade requests that need a new feature are only routed to nodes that
+//! already run a version with it, see [`GossipNode::route_candidates`] and
+//! [`GrpcServer::route`](crate::api::GrpcServer::route).
+
+use crate::core::temporal::Timestamp;
+use crate::distributed::compat::{negotiate_cluster, NegotiatedVersions, ProtocolVersions};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Node identifier
+pub type NodeId = String;
+
+/// How long a member's heartbeat may stall before it is considered down
+pub const DEFAULT_FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Self-reported description of a node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    /// Node ID
+    pub node_id: NodeId,
+    /// Software version
+    pub version: String,
+    /// Features the node supports
+    pub features: BTreeSet<String>,
+    /// Storage capacity in bytes
+    pub storage_capacity: u64,
+    /// Storage used in bytes
+    pub storage_used: u64,
+    /// Load, e.g. in-flight requests relative to capacity
+    pub load: f64,
+    /// Storage and RPC format versions the node supports
+    #[serde(default)]
+    pub protocols: ProtocolVersions,
+    /// Time up to which the node has applied every write, if reported
+    #[serde(default)]
+    pub seen_through: Option<Timestamp>,
+    /// Address other nodes send requests to, see
+    /// [`NodeServer`](crate::distributed::transport::NodeServer)
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+impl NodeMetadata {
+    /// Metadata of a node running this crate version
+    pub fn new(node_id: impl Into<NodeId>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: BTreeSet::new(),
+            storage_capacity: 0,
+            storage_used: 0,
+            load: 0.0,
+            protocols: ProtocolVersions::current(),
+            seen_through: None,
+            address: None,
+        }
+    }
+
+    /// Set the software version
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Advertise support for `feature`
+    pub fn with_feature(mut self, feature: impl Into<String>) -> Self {
+        self.features.insert(feature.into());
+        self
+    }
+
+    /// Set the storage capacity in bytes
+    pub fn with_storage_capacity(mut self, bytes: u64) -> Self {
+        self.storage_capacity = bytes;
+        self
+    }
+
+    /// Set the address other nodes send requests to
+    pub fn with_address(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Whether the node supports every feature in `features`
+    pub fn supports_all(&self, features: &[&str]) -> bool {
+        features.iter().all(|f| self.features.contains(*f))
+    }
+}
+
+/// One member as gossiped
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GossipEntry {
+    /// Member metadata
+    pub metadata: NodeMetadata,
+    /// Incarnation of the member, increased every time it starts
+    #[serde(default)]
+    pub incarnation: u64,
+    /// Heartbeat counter within the incarnation, increased by the member
+    /// itself
+    pub heartbeat: u64,
+}
+
+impl GossipEntry {
+    /// Whether the entry is more recent than `other`
+    fn is_newer_than(&self, other: &GossipEntry) -> bool {
+        (self.incarnation, self.heartbeat) > (other.incarnation, other.heartbeat)
+    }
+}
+
+/// Gossip payload: the sender's view of the cluster
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GossipMessage {
+    /// Sender
+    pub from: NodeId,
+    /// Every member the sender knows, itself included
+    pub entries: Vec<GossipEntry>,
+}
+
+/// A member as seen by the local node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStatus {
+    /// Latest gossiped metadata
+    pub metadata: NodeMetadata,
+    /// Latest heartbeat
+    pub heartbeat: u64,
+    /// Whether the heartbeat advanced within the failure timeout
+    pub alive: bool,
+}
+
+/// Cluster membership as seen by the local node
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterStatus {
+    /// Local node ID
+    pub local: NodeId,
+    /// Every known member, local node included, in node ID order
+    pub nodes: Vec<NodeStatus>,
+}
+
+impl ClusterStatus {
+    /// Members currently up
+    pub fn alive(&self) -> impl Iterator<Item = &NodeStatus> {
+        self.nodes.iter().filter(|n| n.alive)
+    }
+
+    /// Distinct software versions of live members; more than one means an
+    /// upgrade is in progress
+    pub fn versions(&self) -> BTreeSet<&str> {
+        self.alive().map(|n| n.metadata.version.as_str()).collect()
+    }
+
+    /// Earliest time up to which every known member has applied every
+    /// write, or `None` while a member has not reported one. Members that
+    /// are down hold it back, as they may still send older writes.
+    pub fn stability_watermark(&self) -> Option<Timestamp> {
+        self.nodes
+            .iter()
+            .map(|n| n.metadata.seen_through)
+            .min()
+            .flatten()
+    }
+
+    /// Format versions every live member supports
+    pub fn negotiated_versions(&self) -> Result<NegotiatedVersions> {
+        negotiate_cluster(self.alive().map(|n| &n.metadata.protocols))
+    }
+}
+
+struct Member {
+    entry: GossipEntry,
+    updated_at: Instant,
+}
 
 /// Gossip node for membership management
 pub struct GossipNode {
-    // TODO: Implement gossip protocol
+    local: GossipEntry,
+    members: HashMap<NodeId, Member>,
+    failure_timeout: Duration,
 }
 
 impl GossipNode {
+    /// Node with a random ID and default metadata, starting a new
+    /// incarnation
     pub fn new() -> Self {
-        Self {}
+        // Wall-clock milliseconds grow across restarts of the node.
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Self {
+            local: GossipEntry {
+                metadata: NodeMetadata::new(Uuid::new_v4().to_string()),
+                incarnation,
+                heartbeat: 0,
+            },
+            members: HashMap::new(),
+            failure_timeout: DEFAULT_FAILURE_TIMEOUT,
+        }
+    }
+
+    /// Advertise `metadata`, including the node ID
+    pub fn with_metadata(mut self, metadata: NodeMetadata) -> Self {
+        self.local.metadata = metadata;
+        self
+    }
+
+    /// Start at `incarnation`, e.g. a restart counter the caller persists,
+    /// instead of the wall clock. It must grow every time the node starts.
+    pub fn with_incarnation(mut self, incarnation: u64) -> Self {
+        self.local.incarnation = incarnation;
+        self
+    }
+
+    /// Consider members down once their heartbeat stalls for `timeout`
+    pub fn with_failure_timeout(mut self, timeout: Duration) -> Self {
+        self.failure_timeout = timeout;
+        self
+    }
+
+    /// Local node ID
+    pub fn node_id(&self) -> &str {
+        &self.local.metadata.node_id
+    }
+
+    /// Local metadata
+    pub fn metadata(&self) -> &NodeMetadata {
+        &self.local.metadata
+    }
+
+    /// Update the local load and storage use, gossiped from the next tick
+    pub fn report_usage(&mut self, load: f64, storage_used: u64) {
+        self.local.metadata.load = load;
+        self.local.metadata.storage_used = storage_used;
+    }
+
+    /// Advertise support for `feature` from the next tick
+    pub fn advertise(&mut self, feature: impl Into<String>) {
+        self.local.metadata.features.insert(feature.into());
+    }
+
+    /// Stop advertising `feature` from the next tick
+    pub fn withdraw(&mut self, feature: &str) {
+        self.local.metadata.features.remove(feature);
+    }
+
+    /// Report that the local node has applied every write up to `through`,
+    /// gossiped from the next tick; the time never moves back
+    pub fn report_seen(&mut self, through: Timestamp) {
+        let seen = &mut self.local.metadata.seen_through;
+        *seen = Some(seen.map_or(through, |s| s.max(through)));
+    }
+
+    /// Advance the local heartbeat and return the message to send to peers
+    pub fn tick(&mut self) -> GossipMessage {
+        self.local.heartbeat += 1;
+        self.digest()
+    }
+
+    /// The local view of the cluster, without advancing the heartbeat
+    pub fn digest(&self) -> GossipMessage {
+        let mut entries = vec![self.local.clone()];
+        entries.extend(self.members.values().map(|m| m.entry.clone()));
+        GossipMessage {
+            from: self.node_id().to_string(),
+            entries,
+        }
+    }
+
+    /// Merge a peer's view, keeping the newest entry of every member by
+    /// incarnation, then heartbeat
+    pub fn merge(&mut self, message: GossipMessage) {
+        let now = Instant::now();
+        for entry in message.entries {
+            let node_id = &entry.metadata.node_id;
+            if *node_id == self.local.metadata.node_id {
+                continue;
+            }
+            match self.members.get_mut(node_id) {
+                Some(member) if !entry.is_newer_than(&member.entry) => {}
+                Some(member) => {
+                    member.entry = entry;
+                    member.updated_at = now;
+                }
+                None => {
+                    self.members.insert(
+                        node_id.clone(),
+                        Member {
+                            entry,
+                            updated_at: now,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Every known member with its metadata and liveness
+    pub fn cluster_status(&self) -> ClusterStatus {
+        let mut nodes: Vec<NodeStatus> = self
+            .members
+            .values()
+            .map(|m| NodeStatus {
+                metadata: m.entry.metadata.clone(),
+                heartbeat: m.entry.heartbeat,
+                alive: m.updated_at.elapsed() < self.failure_timeout,
+            })
+            .collect();
+        nodes.push(NodeStatus {
+            metadata: self.local.metadata.clone(),
+            heartbeat: self.local.heartbeat,
+            alive: true,
+        });
+        nodes.sort_by(|a, b| a.metadata.node_id.cmp(&b.metadata.node_id));
+        ClusterStatus {
+            local: self.node_id().to_string(),
+            nodes,
+        }
+    }
+
+    /// Live nodes that support every feature a request requires, least
+    /// loaded first.
+    ///
+    /// Nodes still on a version without one of the features are skipped,
+    /// so requests are never routed to a node that cannot serve them.
+    pub fn route_candidates(&self, required: &[&str]) -> Vec<NodeId> {
+        let mut candidates: Vec<_> = self
+            .cluster_status()
+            .nodes
+            .into_iter()
+            .filter(|n| n.alive && n.metadata.supports_all(required))
+            .map(|n| n.metadata)
+            .collect();
+        candidates.sort_by(|a, b| a.load.total_cmp(&b.load));
+        candidates.into_iter().map(|m| m.node_id).collect()
     }
 }
 
@@ -16,3 +356,88 @@ impl Default for GossipNode {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gossip_spreads_metadata_and_routes_by_feature() {
+        let mut old = GossipNode::new().with_metadata(NodeMetadata::new("a").with_version("0.1.0"));
+        let mut new = GossipNode::new().with_metadata(
+            NodeMetadata::new("b")
+                .with_version("0.2.0")
+                .with_feature("valid-ranges"),
+        );
+        let mut third =
+            GossipNode::new().with_metadata(NodeMetadata::new("c").with_feature("valid-ranges"));
+        third.report_usage(0.9, 100);
+
+        // a hears about c only through b.
+        new.merge(third.tick());
+        old.merge(new.tick());
+        let status = old.cluster_status();
+        assert_eq!(status.nodes.len(), 3);
+        assert_eq!(status.nodes[2].metadata.load, 0.9);
+        assert_eq!(status.versions().len(), 2);
+
+        assert_eq!(old.route_candidates(&["valid-ranges"]), vec!["b", "c"]);
+        assert_eq!(old.route_candidates(&[]).len(), 3);
+
+        // Stale entries do not overwrite newer ones.
+        let stale = GossipMessage {
+            from: "c".to_string(),
+            entries: vec![GossipEntry {
+                metadata: NodeMetadata::new("b"),
+                incarnation: 0,
+                heartbeat: 0,
+            }],
+        };
+        old.merge(stale);
+        assert!(old.cluster_status().nodes[1]
+            .metadata
+            .supports_all(&["valid-ranges"]));
+
+        let old = old.with_failure_timeout(Duration::ZERO);
+        assert_eq!(old.route_candidates(&[]), vec!["a"]);
+    }
+
+    #[test]
+    fn test_restarted_node_wins_with_new_incarnation() {
+        let mut observer = GossipNode::new().with_metadata(NodeMetadata::new("a"));
+        let mut before = GossipNode::new()
+            .with_metadata(NodeMetadata::new("b").with_version("0.1.0"))
+            .with_incarnation(1);
+        for _ in 0..5 {
+            observer.merge(before.tick());
+        }
+
+        // The restarted node's heartbeat starts over but still wins.
+        let mut after = GossipNode::new()
+            .with_metadata(NodeMetadata::new("b").with_version("0.2.0"))
+            .with_incarnation(2);
+        observer.merge(after.tick());
+        let status = observer.cluster_status();
+        assert_eq!(status.nodes[1].heartbeat, 1);
+        assert_eq!(status.nodes[1].metadata.version, "0.2.0");
+
+        // Late gossip from the old incarnation is ignored.
+        observer.merge(before.tick());
+        assert_eq!(observer.cluster_status().nodes[1].metadata.version, "0.2.0");
+    }
+
+    #[test]
+    fn test_stability_watermark_is_earliest_report() {
+        let mut a = GossipNode::new().with_metadata(NodeMetadata::new("a"));
+        let mut b = GossipNode::new().with_metadata(NodeMetadata::new("b"));
+        a.report_seen(Timestamp::from_secs(50));
+        a.merge(b.tick());
+        assert_eq!(a.cluster_status().stability_watermark(), None);
+
+        b.report_seen(Timestamp::from_secs(30));
+        b.report_seen(Timestamp::from_secs(20));
+        a.merge(b.tick());
+        let watermark = a.cluster_status().stability_watermark();
+        assert_eq!(watermark, Some(Timestamp::from_secs(30)));
+    }
+}
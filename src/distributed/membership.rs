@@ -0,0 +1,412 @@
+//! Joining and leaving a sharded cluster
+//!
+//! A [`ClusterMember`] ties a node's gossip membership to its share of the
+//! [`HashRing`]. Only members advertising [`SERVING_FEATURE`] are on the
+//! ring, and members reach each other at the address in their gossiped
+//! metadata through the [`NodeServer`] of [`ClusterMember::server`].
+//!
+//! A node joining exchanges gossip with a seed at a known address, copies
+//! the shards it is about to own from their current owners and switches to
+//! the new ring. It then copies again what the old owners recorded since
+//! the copy began, and only then advertises the feature and accepts
+//! requests; the other members pick up the new ring with
+//! [`ClusterMember::refresh`]. Leaving reverses this: the node copies its
+//! shards to their next owners, then withdraws.
+//!
+//! Members not refreshed yet still route writes by the old ring, so every
+//! refresh also forwards the writes a member recorded for shards it does
+//! not own to their owners, following its journal from where the previous
+//! refresh stopped.
+
+use crate::core::event::Event;
+use crate::core::namespace::is_internal_entity;
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::distributed::gossip::{GossipNode, NodeId};
+use crate::distributed::sharding::{
+    HashRing, RebalanceProgress, ShardManager, ShardedDB, DEFAULT_REBALANCE_PAGE, DEFAULT_VNODES,
+};
+use crate::distributed::transport::{ClusterSecret, NodeClient, NodeServer, TcpClient};
+use crate::error::{Error, Result};
+use crate::storage::journal::Lsn;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Feature members advertise while they own shards
+pub const SERVING_FEATURE: &str = "shards";
+
+/// Where a member is in its lifetime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberState {
+    /// Not on the ring yet
+    Joining,
+    /// Owns shards and accepts requests
+    Serving,
+    /// Handing its shards over
+    Leaving,
+    /// Off the ring
+    Left,
+}
+
+/// Member of a sharded cluster
+pub struct ClusterMember {
+    gossip: Arc<Mutex<GossipNode>>,
+    sharded: ShardedDB,
+    secret: ClusterSecret,
+    vnodes: u32,
+    state: MemberState,
+    /// Journal position up to which writes for other nodes' shards were
+    /// forwarded
+    forwarded: Lsn,
+}
+
+impl ClusterMember {
+    /// Member gossiping as `gossip`, storing its shards in `db`; `shards`
+    /// and `secret` must be the same on every member
+    pub fn new(
+        gossip: GossipNode,
+        db: Arc<TemporalDB>,
+        shards: u32,
+        secret: ClusterSecret,
+    ) -> Self {
+        let local = gossip.node_id().to_string();
+        Self {
+            gossip: Arc::new(Mutex::new(gossip)),
+            sharded: ShardedDB::new(local, db, ShardManager::with_shards(shards)),
+            secret,
+            vnodes: DEFAULT_VNODES,
+            state: MemberState::Joining,
+            forwarded: 0,
+        }
+    }
+
+    /// Take `vnodes` points per member on the ring; must be the same on
+    /// every member
+    pub fn with_vnodes(mut self, vnodes: u32) -> Self {
+        self.vnodes = vnodes;
+        self
+    }
+
+    /// Send requests and handovers for `node` to `client`, instead of the
+    /// address it gossips
+    pub fn connect(&mut self, node: impl Into<NodeId>, client: Arc<dyn NodeClient>) {
+        self.sharded.connect(node, client);
+    }
+
+    /// Lifetime state
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    /// Gossip membership, to tick and merge peers' views into
+    pub fn gossip(&self) -> &Arc<Mutex<GossipNode>> {
+        &self.gossip
+    }
+
+    fn lock_gossip(&self) -> MutexGuard<'_, GossipNode> {
+        self.gossip
+            .lock()
+            .expect("ClusterMember poisoned gossip lock")
+    }
+
+    /// Server answering other members from the local database, to listen
+    /// at the address the member gossips
+    pub fn server(&self) -> NodeServer {
+        NodeServer::new(self.sharded.local_db().clone(), self.secret.clone())
+            .with_gossip(self.gossip.clone())
+    }
+
+    /// Ring of the live members serving shards
+    pub fn ring(&self) -> HashRing {
+        let mut ring = HashRing::with_vnodes(self.vnodes);
+        for node in self.lock_gossip().route_candidates(&[SERVING_FEATURE]) {
+            ring.add_node(node);
+        }
+        ring
+    }
+
+    /// Router to the owners of every entity; fails unless serving
+    pub fn sharded(&self) -> Result<&ShardedDB> {
+        match self.state {
+            MemberState::Serving => Ok(&self.sharded),
+            state => Err(Error::Distributed(format!(
+                "Node {} is not serving requests, it is {:?}",
+                self.sharded.local_node(),
+                state
+            ))),
+        }
+    }
+
+    /// Join the cluster of the member listening at `seed_addr`: learn the
+    /// members from its gossip, copy the shards this node will own from
+    /// their current owners, then start serving. The node's own address
+    /// starts a new cluster.
+    pub async fn join(&mut self, seed_addr: &str) -> Result<RebalanceProgress> {
+        if self.state != MemberState::Joining {
+            return Err(Error::Distributed(format!(
+                "Node {} cannot join, it is {:?}",
+                self.sharded.local_node(),
+                self.state
+            )));
+        }
+        let address = self.lock_gossip().metadata().address.clone();
+        let Some(address) = address else {
+            return Err(Error::Configuration(format!(
+                "Node {} has no address to gossip",
+                self.sharded.local_node()
+            )));
+        };
+        if seed_addr != address {
+            let seed: Arc<dyn NodeClient> =
+                Arc::new(TcpClient::new(seed_addr, self.secret.clone()));
+            self.exchange_gossip(&seed).await?;
+        }
+        self.connect_members();
+        self.refresh_assignment();
+        let mut ring = self.ring();
+        ring.add_node(self.sharded.local_node());
+        let progress = self.hand_over(&ring).await?;
+        self.lock_gossip().advertise(SERVING_FEATURE);
+        self.state = MemberState::Serving;
+        self.announce().await;
+        Ok(progress)
+    }
+
+    /// Leave the cluster: copy this node's shards to their next owners,
+    /// then stop serving. Keep the server running and refreshing until
+    /// every member has refreshed, so writes routed here by the old ring
+    /// are forwarded.
+    pub async fn leave(&mut self) -> Result<RebalanceProgress> {
+        if self.state != MemberState::Serving {
+            return Err(Error::Distributed(format!(
+                "Node {} cannot leave, it is {:?}",
+                self.sharded.local_node(),
+                self.state
+            )));
+        }
+        self.state = MemberState::Leaving;
+        let mut ring = self.ring();
+        ring.remove_node(self.sharded.local_node());
+        if ring.nodes().is_empty() {
+            self.state = MemberState::Serving;
+            return Err(Error::Distributed(
+                "The last member cannot hand its shards over".to_string(),
+            ));
+        }
+        let progress = match self.hand_over(&ring).await {
+            Ok(progress) => progress,
+            Err(e) => {
+                self.state = MemberState::Serving;
+                return Err(e);
+            }
+        };
+        self.lock_gossip().withdraw(SERVING_FEATURE);
+        self.state = MemberState::Left;
+        self.announce().await;
+        Ok(progress)
+    }
+
+    /// Route by the ring of the members currently serving, e.g. after
+    /// gossip announced a member joining or leaving, and forward the
+    /// writes recorded here for shards other members own; returns how many
+    /// events were forwarded
+    pub async fn refresh(&mut self) -> Result<usize> {
+        match self.state {
+            MemberState::Joining | MemberState::Leaving => return Ok(0),
+            MemberState::Serving | MemberState::Left => {
+                self.connect_members();
+                self.refresh_assignment();
+            }
+        }
+        self.forward_strays().await
+    }
+
+    fn refresh_assignment(&mut self) {
+        let mut shards = self.sharded.shards().clone();
+        shards.assign_by_ring(&self.ring());
+        self.sharded.set_shards(shards);
+    }
+
+    /// Connect to the gossiped address of every member not connected yet
+    fn connect_members(&mut self) {
+        let status = self.lock_gossip().cluster_status();
+        for node in status.nodes {
+            let id = node.metadata.node_id;
+            if self.sharded.node(&id).is_some() {
+                continue;
+            }
+            if let Some(address) = node.metadata.address {
+                let client = TcpClient::new(address, self.secret.clone());
+                self.sharded.connect(id, Arc::new(client));
+            }
+        }
+    }
+
+    /// Send the local view of the cluster to `peer` and merge its view
+    async fn exchange_gossip(&self, peer: &Arc<dyn NodeClient>) -> Result<()> {
+        let message = self.lock_gossip().tick();
+        let view = peer.exchange_gossip(message).await?;
+        self.lock_gossip().merge(view);
+        Ok(())
+    }
+
+    /// Tell every connected member about a change of the local state
+    async fn announce(&self) {
+        let local = self.sharded.local_node();
+        for (node, client) in self.sharded.connections() {
+            if node != local {
+                // Members missed here learn it from later gossip.
+                let _ = self.exchange_gossip(client).await;
+            }
+        }
+    }
+
+    /// Copy the shards moving to their owners on `ring`, route by it, then
+    /// copy again what the old owners recorded meanwhile; routing goes
+    /// back to the previous assignment if that fails
+    async fn hand_over(&mut self, ring: &HashRing) -> Result<RebalanceProgress> {
+        let started = Timestamp::now();
+        let catch_up = self
+            .sharded
+            .plan_rebalance(ring)
+            .with_recorded_since(started);
+        let (shards, mut progress) = self.sharded.plan_rebalance(ring).run().await?;
+        let previous = self.sharded.shards().clone();
+        self.sharded.set_shards(shards);
+        let caught_up = match catch_up.run().await {
+            Ok((_, caught_up)) => caught_up,
+            Err(e) => {
+                self.sharded.set_shards(previous);
+                return Err(e);
+            }
+        };
+        progress.events += caught_up.events;
+        progress.elapsed += caught_up.elapsed;
+        if let Err(e) = self.forward_strays().await {
+            self.sharded.set_shards(previous);
+            return Err(e);
+        }
+        Ok(progress)
+    }
+
+    /// Forward the events recorded since the last call for shards other
+    /// members own to those members
+    async fn forward_strays(&mut self) -> Result<usize> {
+        let db = self.sharded.local_db().clone();
+        let local = self.sharded.local_node().to_string();
+        let mut forwarded = 0;
+        loop {
+            let page = db
+                .events_after(self.forwarded, DEFAULT_REBALANCE_PAGE)
+                .await?;
+            let Some(&(last, _)) = page.last() else {
+                break;
+            };
+            let mut strays: BTreeMap<String, Vec<Event>> = BTreeMap::new();
+            for (_, event) in page {
+                if is_internal_entity(event.entity_id()) {
+                    continue;
+                }
+                match self.sharded.shards().owner(event.entity_id()) {
+                    Some(owner) if owner != local => {
+                        strays.entry(owner.to_string()).or_default().push(event);
+                    }
+                    _ => {}
+                }
+            }
+            for (node, events) in strays {
+                let client = self
+                    .sharded
+                    .node(&node)
+                    .ok_or_else(|| Error::Distributed(format!("No connection to node {}", node)))?;
+                forwarded += events.len();
+                client.apply_remote_events(events).await?;
+            }
+            self.forwarded = last;
+        }
+        Ok(forwarded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::gossip::NodeMetadata;
+    use tokio::net::TcpListener;
+
+    async fn member(id: &str) -> (ClusterMember, Arc<TemporalDB>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let db = Arc::new(TemporalDB::in_memory().unwrap());
+        let gossip = GossipNode::new().with_metadata(NodeMetadata::new(id).with_address(&address));
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        let member = ClusterMember::new(gossip, db.clone(), 16, secret);
+        tokio::spawn(member.server().serve(listener));
+        (member, db, address)
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_hand_shards_over() {
+        let (mut a, a_db, a_addr) = member("a").await;
+        let (mut b, b_db, _) = member("b").await;
+        a.join(&a_addr).await.unwrap();
+        assert!(b.sharded().is_err());
+
+        let ts = Timestamp::from_secs(100);
+        let ids: Vec<String> = (0..20).map(|i| format!("user:{}", i)).collect();
+        for id in &ids {
+            a.sharded()
+                .unwrap()
+                .insert(id, id.as_str(), ts)
+                .await
+                .unwrap();
+        }
+
+        let joined = b.join(&a_addr).await.unwrap();
+        assert!(joined.entities > 0 && b.state() == MemberState::Serving);
+        let on_b = b_db.list_entities("", None, 100).await.unwrap().entities;
+        assert_eq!(on_b.len() as u64, joined.entities);
+
+        // Before refreshing, a still routes b's shards to itself; the
+        // refresh forwards what it took meanwhile.
+        let moved = on_b[0].clone();
+        let later = Timestamp::from_secs(200);
+        a.sharded()
+            .unwrap()
+            .insert(&moved, "stale route", later)
+            .await
+            .unwrap();
+        assert!(a.refresh().await.unwrap() > 0);
+        for id in &ids {
+            let owner = a.sharded().unwrap().shards().owner(id).unwrap();
+            assert_eq!(Some(owner), b.sharded().unwrap().shards().owner(id));
+        }
+        let value: Option<String> = b_db.query_as_of(&moved, later).await.unwrap();
+        assert_eq!(value.as_deref(), Some("stale route"));
+
+        // Writes b took while serving are handed back as it leaves, and
+        // those routed to it before a refreshed are forwarded.
+        b.sharded()
+            .unwrap()
+            .insert(&moved, "updated", later)
+            .await
+            .unwrap();
+        b.leave().await.unwrap();
+        assert_eq!(b.state(), MemberState::Left);
+        let value: Option<String> = a_db.query_as_of(&moved, later).await.unwrap();
+        assert_eq!(value.as_deref(), Some("updated"));
+        let latest = Timestamp::from_secs(300);
+        a.sharded()
+            .unwrap()
+            .insert(&moved, "late", latest)
+            .await
+            .unwrap();
+        b.refresh().await.unwrap();
+        a.refresh().await.unwrap();
+        assert_eq!(a.ring().nodes(), ["a"]);
+        let sharded = a.sharded().unwrap();
+        let value: Option<String> = sharded.query_as_of(&moved, latest).await.unwrap();
+        assert_eq!(value.as_deref(), Some("late"));
+        assert!(a.leave().await.is_err());
+    }
+}
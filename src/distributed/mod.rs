@@ -1,9 +1,29 @@
 //! Distributed systems components
 
+pub mod compat;
+pub mod federation;
 pub mod gossip;
+pub mod membership;
 pub mod raft;
+pub mod raft_group;
+pub mod raft_storage;
+pub mod region;
+pub mod replication;
+pub mod scatter;
 pub mod sharding;
+pub mod transaction;
+pub mod transport;
 
+pub use compat::*;
+pub use federation::*;
 pub use gossip::*;
+pub use membership::*;
 pub use raft::*;
+pub use raft_group::*;
+pub use raft_storage::*;
+pub use region::*;
+pub use replication::*;
+pub use scatter::*;
 pub use sharding::*;
+pub use transaction::*;
+pub use transport::*;
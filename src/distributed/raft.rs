@@ -1,18 +1,943 @@
 //! Raft consensus implementation
+//!
+//! A [`RaftNode`] is a state machine without I/O: the caller advances its
+//! clock with [`RaftNode::tick`], hands it the messages other nodes sent
+//! with [`RaftNode::step`], and sends whatever
+//! [`RaftNode::take_messages`] returns. Nodes elect a leader, which
+//! replicates batches of events proposed with [`RaftNode::propose`] to
+//! the other nodes. Once a majority holds a batch it is committed, and
+//! every node returns it from [`RaftNode::take_committed`] in the same
+//! order, to be applied to the journal, e.g. with
+//! [`TemporalDB::apply_remote_events`](crate::TemporalDB::apply_remote_events).
+//! A [`RaftGroup`](crate::distributed::RaftGroup) does all of this over the
+//! cluster transport.
+//!
+//! Every elected leader first commits an empty batch of its own term, so
+//! batches left by earlier leaders are committed without waiting for new
+//! proposals.
+//!
+//! Applied entries can be compacted with [`RaftNode::compact`] into a
+//! [`RaftSnapshot`] of the events they carried and the view state they
+//! built, exported in the [`ViewSnapshot`] format with
+//! [`TemporalDB::export_view`], and dropped from the log. A node joining
+//! late or lagging behind the compacted prefix gets the snapshot instead of
+//! the entries, see [`RaftNode::take_installed_snapshot`]: it applies the
+//! events to its journal, so it holds their history, and imports the view
+//! with [`TemporalDB::import_view`].
+//!
+//! [`TemporalDB::export_view`]: crate::TemporalDB::export_view
+//! [`TemporalDB::import_view`]: crate::TemporalDB::import_view
+//!
+//! A node opened with [`RaftNode::open`] saves its term, its vote and its
+//! log to a [`RaftStorage`] before it answers the message that changed
+//! them, and restores them when opened again, so a restarted node neither
+//! votes twice in a term nor loses entries it acknowledged.
+
+use crate::core::event::Event;
+use crate::distributed::gossip::NodeId;
+use crate::distributed::raft_storage::{MemoryRaftStorage, RaftStorage};
+use crate::error::{Error, Result};
+use crate::storage::view_snapshot::ViewSnapshot;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Ticks without hearing from a leader before a follower starts an
+/// election, before jitter
+pub const DEFAULT_ELECTION_TICKS: u64 = 10;
+
+/// Ticks between heartbeats of a leader
+pub const DEFAULT_HEARTBEAT_TICKS: u64 = 3;
+
+/// Role of a node in its current term
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// Follows the leader of the term, if any
+    Follower,
+    /// Asks the other nodes for votes
+    Candidate,
+    /// Replicates the log to the other nodes
+    Leader,
+}
+
+/// Batch of events at a position of the replicated log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Term of the leader that appended the entry
+    pub term: u64,
+    /// Position in the log, from 1
+    pub index: u64,
+    /// Events of the batch, empty for the entry a new leader appends
+    pub events: Vec<Event>,
+}
+
+/// Compacted prefix of the replicated log
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RaftSnapshot {
+    /// Index of the last entry the snapshot covers, 0 if none
+    pub last_index: u64,
+    /// Term of that entry
+    pub last_term: u64,
+    /// Events of the entries covered, in log order
+    pub events: Vec<Event>,
+    /// State of the materialized view after applying every entry covered,
+    /// `None` if none is
+    pub view: Option<ViewSnapshot>,
+}
+
+/// Message between Raft nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RaftMessage {
+    /// A candidate asks for a vote
+    RequestVote {
+        term: u64,
+        /// Index of the candidate's last entry
+        last_log_index: u64,
+        /// Term of the candidate's last entry
+        last_log_term: u64,
+    },
+    /// Answer to [`RaftMessage::RequestVote`]
+    Vote { term: u64, granted: bool },
+    /// The leader replicates entries following `prev_log_index`; a
+    /// heartbeat carries none
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        /// Commit index of the leader
+        leader_commit: u64,
+    },
+    /// The leader sends its snapshot to a node missing entries it
+    /// compacted; answered with [`RaftMessage::AppendResponse`]
+    InstallSnapshot { term: u64, snapshot: RaftSnapshot },
+    /// Answer to [`RaftMessage::AppendEntries`] and
+    /// [`RaftMessage::InstallSnapshot`]
+    AppendResponse {
+        term: u64,
+        success: bool,
+        /// Last index known to match the leader's log on success; on
+        /// failure, the index to retry from after
+        match_index: u64,
+    },
+}
+
+impl RaftMessage {
+    fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote { term, .. }
+            | RaftMessage::Vote { term, .. }
+            | RaftMessage::AppendEntries { term, .. }
+            | RaftMessage::InstallSnapshot { term, .. }
+            | RaftMessage::AppendResponse { term, .. } => *term,
+        }
+    }
+}
+
+/// Message with its sender and recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Sender
+    pub from: NodeId,
+    /// Recipient
+    pub to: NodeId,
+    /// The message
+    pub message: RaftMessage,
+}
 
 /// Raft node state
 pub struct RaftNode {
-    // TODO: Implement Raft
+    id: NodeId,
+    peers: Vec<NodeId>,
+    election_ticks: u64,
+    heartbeat_ticks: u64,
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    leader: Option<NodeId>,
+    /// Compacted entries
+    snapshot: RaftSnapshot,
+    /// Entries after the snapshot
+    log: Vec<LogEntry>,
+    /// Snapshot installed from the leader and not taken yet
+    installed: Option<RaftSnapshot>,
+    commit_index: u64,
+    /// Index of the last entry returned by `take_committed`
+    applied: u64,
+    /// Ticks since the timer was last reset
+    elapsed: u64,
+    election_timeout: u64,
+    votes: BTreeSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    outbox: Vec<Envelope>,
+    storage: Box<dyn RaftStorage>,
 }
 
 impl RaftNode {
-    pub fn new() -> Self {
-        Self {}
+    /// Follower `id` of a cluster with the other members `peers`, with an
+    /// empty log kept in memory only
+    pub fn new(id: impl Into<NodeId>, peers: Vec<NodeId>) -> Self {
+        Self::with_storage(id.into(), peers, Box::new(MemoryRaftStorage::new()))
+    }
+
+    /// Follower `id` of a cluster with the other members `peers`, restoring
+    /// the term, vote and log saved in `storage` and saving them there from
+    /// now on. Committed entries are returned again by
+    /// [`RaftNode::take_committed`] once the leader confirms them.
+    pub fn open(
+        id: impl Into<NodeId>,
+        peers: Vec<NodeId>,
+        mut storage: Box<dyn RaftStorage>,
+    ) -> Result<Self> {
+        let state = storage.load()?;
+        let mut node = Self::with_storage(id.into(), peers, storage);
+        if let Some(state) = state {
+            node.term = state.term;
+            node.voted_for = state.voted_for;
+            node.commit_index = state.snapshot.last_index;
+            node.applied = state.snapshot.last_index;
+            node.snapshot = state.snapshot;
+            node.log = state.log;
+            node.reset_timer();
+        }
+        Ok(node)
+    }
+
+    fn with_storage(id: NodeId, peers: Vec<NodeId>, storage: Box<dyn RaftStorage>) -> Self {
+        let mut node = Self {
+            id,
+            peers,
+            election_ticks: DEFAULT_ELECTION_TICKS,
+            heartbeat_ticks: DEFAULT_HEARTBEAT_TICKS,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            snapshot: RaftSnapshot::default(),
+            log: Vec::new(),
+            installed: None,
+            commit_index: 0,
+            applied: 0,
+            elapsed: 0,
+            election_timeout: 0,
+            votes: BTreeSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            outbox: Vec::new(),
+            storage,
+        };
+        node.reset_timer();
+        node
+    }
+
+    /// Start an election after `ticks` without a leader, plus a jitter of
+    /// up to as many ticks again, so nodes rarely time out together
+    pub fn with_election_ticks(mut self, ticks: u64) -> Self {
+        self.election_ticks = ticks.max(1);
+        self.reset_timer();
+        self
+    }
+
+    /// Send heartbeats every `ticks` while leader; must be well below the
+    /// election ticks
+    pub fn with_heartbeat_ticks(mut self, ticks: u64) -> Self {
+        self.heartbeat_ticks = ticks.max(1);
+        self
+    }
+
+    /// Local node ID
+    pub fn node_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Role in the current term
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Current term
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Leader of the current term, if known
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    /// Index of the last committed entry, 0 if none
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// The local log after the snapshot, committed or not
+    pub fn log(&self) -> &[LogEntry] {
+        &self.log
+    }
+
+    /// Entries compacted so far
+    pub fn snapshot(&self) -> &RaftSnapshot {
+        &self.snapshot
+    }
+
+    /// Advance the clock by one tick: a leader sends heartbeats, any other
+    /// node starts an election once the leader has been silent too long
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= self.heartbeat_ticks => {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+            Role::Leader => {}
+            _ if self.elapsed >= self.election_timeout => self.start_election()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Append a batch of events to the log; fails unless this node is the
+    /// leader. Returns the index of the entry, committed once
+    /// [`RaftNode::commit_index`] reaches it.
+    pub fn propose(&mut self, events: Vec<Event>) -> Result<u64> {
+        if self.role != Role::Leader {
+            return Err(Error::Distributed(match &self.leader {
+                Some(leader) => format!("not the leader; the leader is {leader}"),
+                None => "not the leader; no leader is elected".to_string(),
+            }));
+        }
+        let index = self.append(events)?;
+        self.advance_commit();
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// Handle a message from another node; on error, nothing is answered
+    pub fn step(&mut self, envelope: Envelope) -> Result<()> {
+        let Envelope { from, message, .. } = envelope;
+        if message.term() > self.term {
+            self.become_follower(message.term(), None)?;
+        }
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_ref().is_none_or(|v| *v == from);
+                if granted {
+                    self.storage.save_hard_state(self.term, Some(&from))?;
+                    self.voted_for = Some(from.clone());
+                    self.elapsed = 0;
+                }
+                let vote = RaftMessage::Vote {
+                    term: self.term,
+                    granted,
+                };
+                self.send(from, vote);
+            }
+            RaftMessage::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    let response = RaftMessage::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                    };
+                    self.send(from, response);
+                    return Ok(());
+                }
+                self.follow(term, &from)?;
+                let response =
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit)?;
+                self.send(from, response);
+            }
+            RaftMessage::InstallSnapshot { term, snapshot } => {
+                let success = term == self.term;
+                let match_index = snapshot.last_index;
+                if success {
+                    self.follow(term, &from)?;
+                    self.install_snapshot(snapshot)?;
+                }
+                let response = RaftMessage::AppendResponse {
+                    term: self.term,
+                    success,
+                    match_index: if success { match_index } else { 0 },
+                };
+                self.send(from, response);
+            }
+            RaftMessage::AppendResponse {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+                if success {
+                    let matched = {
+                        let matched = self.match_index.entry(from.clone()).or_insert(0);
+                        *matched = (*matched).max(match_index);
+                        *matched
+                    };
+                    self.next_index.insert(from.clone(), matched + 1);
+                    self.advance_commit();
+                    if matched < self.last_index() {
+                        self.send_append(&from);
+                    }
+                } else {
+                    self.next_index.insert(from.clone(), match_index + 1);
+                    self.send_append(&from);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Messages to send, in order
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Entries committed since the last call, in log order. After a
+    /// snapshot was installed, they follow it, see
+    /// [`RaftNode::take_installed_snapshot`].
+    pub fn take_committed(&mut self) -> Vec<LogEntry> {
+        let start = self.position(self.applied + 1);
+        let end = self.position(self.commit_index + 1);
+        self.applied = self.commit_index;
+        self.log[start..end].to_vec()
+    }
+
+    /// Snapshot installed from the leader since the last call, if any. Its
+    /// events are applied to the journal and its view state replaces the
+    /// local one, see
+    /// [`TemporalDB::import_view`](crate::TemporalDB::import_view), before
+    /// the entries [`RaftNode::take_committed`] returns next are applied.
+    pub fn take_installed_snapshot(&mut self) -> Option<RaftSnapshot> {
+        self.installed.take()
+    }
+
+    /// Replace the entries returned by [`RaftNode::take_committed`] so far
+    /// with a snapshot of their events and `view`, the view state after
+    /// applying them, and drop them from the log; returns how many entries
+    /// were dropped
+    pub fn compact(&mut self, view: ViewSnapshot) -> Result<usize> {
+        let through = self.applied;
+        if through <= self.snapshot.last_index {
+            return Ok(0);
+        }
+        let compacted = self.position(through + 1);
+        let mut events = self.snapshot.events.clone();
+        events.extend(
+            self.log[..compacted]
+                .iter()
+                .flat_map(|entry| entry.events.iter().cloned()),
+        );
+        let snapshot = RaftSnapshot {
+            last_index: through,
+            last_term: self.term_at(through),
+            events,
+            view: Some(view),
+        };
+        self.storage
+            .save_snapshot(&snapshot, &self.log[compacted..])?;
+        self.log.drain(..compacted);
+        self.snapshot = snapshot;
+        Ok(compacted)
+    }
+
+    fn append_entries(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> Result<RaftMessage> {
+        // Compacted entries are committed, so they match the leader's.
+        let compacted = prev_log_index < self.snapshot.last_index;
+        if !compacted
+            && (prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term)
+        {
+            // Retry from before the mismatch; the leader moves back one
+            // entry at a time until the logs agree.
+            return Ok(RaftMessage::AppendResponse {
+                term: self.term,
+                success: false,
+                match_index: prev_log_index.saturating_sub(1).min(self.last_index()),
+            });
+        }
+        let last_new = prev_log_index + entries.len() as u64;
+        // Entries from the first one the log lacks or disagrees with on
+        // replace the rest of the log. Committed entries always match, so
+        // only uncommitted ones are dropped.
+        let new: Vec<LogEntry> = entries
+            .into_iter()
+            .skip_while(|entry| {
+                entry.index <= self.snapshot.last_index
+                    || (entry.index <= self.last_index() && self.term_at(entry.index) == entry.term)
+            })
+            .collect();
+        if let Some(first) = new.first() {
+            self.storage.append_entries(&new)?;
+            if first.index <= self.last_index() {
+                let position = self.position(first.index);
+                self.log.truncate(position);
+            }
+            self.log.extend(new);
+        }
+        // A delayed message may cover fewer entries than already committed.
+        self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+        Ok(RaftMessage::AppendResponse {
+            term: self.term,
+            success: true,
+            match_index: last_new,
+        })
+    }
+
+    /// Replace the entries `snapshot` covers; entries after it are kept if
+    /// the log agrees with it
+    fn install_snapshot(&mut self, snapshot: RaftSnapshot) -> Result<()> {
+        if snapshot.last_index <= self.commit_index {
+            return Ok(());
+        }
+        let covered = if snapshot.last_index <= self.last_index()
+            && self.term_at(snapshot.last_index) == snapshot.last_term
+        {
+            self.position(snapshot.last_index + 1)
+        } else {
+            self.log.len()
+        };
+        self.storage
+            .save_snapshot(&snapshot, &self.log[covered..])?;
+        self.log.drain(..covered);
+        self.commit_index = snapshot.last_index;
+        self.applied = snapshot.last_index;
+        self.installed = Some(snapshot.clone());
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    /// Follow `leader`, which sent a message of the current `term`
+    fn follow(&mut self, term: u64, leader: &NodeId) -> Result<()> {
+        if self.role != Role::Follower || self.leader.as_ref() != Some(leader) {
+            self.become_follower(term, Some(leader.clone()))?;
+        }
+        self.elapsed = 0;
+        Ok(())
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        self.storage
+            .save_hard_state(self.term + 1, Some(&self.id))?;
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id.clone());
+        self.votes = BTreeSet::from([self.id.clone()]);
+        self.reset_timer();
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        for peer in self.peers.clone() {
+            let request = RaftMessage::RequestVote {
+                term: self.term,
+                last_log_index: self.last_index(),
+                last_log_term: self.last_term(),
+            };
+            self.send(peer, request);
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.storage.save_hard_state(term, None)?;
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.reset_timer();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.elapsed = 0;
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|p| (p.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|p| (p.clone(), 0)).collect();
+        self.append(Vec::new())?;
+        self.advance_commit();
+        self.broadcast_append();
+        Ok(())
+    }
+
+    fn append(&mut self, events: Vec<Event>) -> Result<u64> {
+        let entry = LogEntry {
+            term: self.term,
+            index: self.last_index() + 1,
+            events,
+        };
+        self.storage.append_entries(std::slice::from_ref(&entry))?;
+        let index = entry.index;
+        self.log.push(entry);
+        Ok(index)
+    }
+
+    /// Commit the highest entry of the current term a majority holds;
+    /// earlier entries are committed with it
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let holders = 1 + self.match_index.values().filter(|m| **m >= index).count();
+            if holders >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: &NodeId) {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        let append = if next <= self.snapshot.last_index {
+            RaftMessage::InstallSnapshot {
+                term: self.term,
+                snapshot: self.snapshot.clone(),
+            }
+        } else {
+            let prev_log_index = next - 1;
+            RaftMessage::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index),
+                entries: self.log[self.position(next)..].to_vec(),
+                leader_commit: self.commit_index,
+            }
+        };
+        self.send(peer.clone(), append);
+    }
+
+    fn send(&mut self, to: NodeId, message: RaftMessage) {
+        self.outbox.push(Envelope {
+            from: self.id.clone(),
+            to,
+            message,
+        });
+    }
+
+    fn reset_timer(&mut self) {
+        let mut hasher = DefaultHasher::new();
+        (&self.id, self.term).hash(&mut hasher);
+        self.elapsed = 0;
+        self.election_timeout = self.election_ticks + hasher.finish() % self.election_ticks;
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.last_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    /// Term of the entry at `index`, 0 before the first entry and for
+    /// compacted entries other than the last
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.snapshot.last_index {
+            return self.snapshot.last_term;
+        }
+        match index.checked_sub(self.snapshot.last_index + 1) {
+            Some(position) => self.log.get(position as usize).map_or(0, |e| e.term),
+            None => 0,
+        }
+    }
+
+    /// Position in the log of the entry at `index`, which must follow the
+    /// snapshot
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot.last_index - 1) as usize
     }
 }
 
-impl Default for RaftNode {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use crate::distributed::raft_storage::FileRaftStorage;
+
+    fn cluster(ids: &[&str]) -> Vec<RaftNode> {
+        ids.iter()
+            .map(|id| {
+                let peers = ids.iter().filter(|p| *p != id).map(|p| p.to_string());
+                RaftNode::new(*id, peers.collect())
+            })
+            .collect()
+    }
+
+    /// Run `ticks` ticks, delivering every message between nodes that are
+    /// not in `down`
+    fn run(nodes: &mut [RaftNode], ticks: usize, down: &[&str]) {
+        for _ in 0..ticks {
+            for node in nodes.iter_mut() {
+                node.tick().unwrap();
+            }
+            loop {
+                let mut messages = Vec::new();
+                for node in nodes.iter_mut() {
+                    messages.extend(node.take_messages());
+                }
+                if messages.is_empty() {
+                    break;
+                }
+                for envelope in messages {
+                    if down.contains(&envelope.from.as_str())
+                        || down.contains(&envelope.to.as_str())
+                    {
+                        continue;
+                    }
+                    let to = nodes.iter_mut().find(|n| n.node_id() == envelope.to);
+                    to.unwrap().step(envelope).unwrap();
+                }
+            }
+        }
+    }
+
+    fn leader<'a>(nodes: &'a mut [RaftNode], down: &[&str]) -> &'a mut RaftNode {
+        let mut leaders = nodes
+            .iter_mut()
+            .filter(|n| n.role() == Role::Leader && !down.contains(&n.node_id()));
+        let leader = leaders.next().expect("no leader elected");
+        assert!(leaders.next().is_none());
+        leader
+    }
+
+    fn batch(entity_id: &str) -> Vec<Event> {
+        let payload = EventPayload::from_json(&1).unwrap();
+        vec![Event::new(
+            "value.changed".to_string(),
+            Timestamp::now(),
+            entity_id.to_string(),
+            payload,
+        )]
+    }
+
+    /// View state holding `entities`
+    fn view_state<S: AsRef<str>>(entities: &[S]) -> ViewSnapshot {
+        let entries = entities
+            .iter()
+            .map(|e| (e.as_ref().to_string(), Vec::new()));
+        ViewSnapshot {
+            lsn: entities.len() as u64,
+            created_at: Timestamp::now(),
+            entries: entries.collect(),
+        }
+    }
+
+    fn committed_entities(node: &mut RaftNode) -> Vec<String> {
+        node.take_committed()
+            .iter()
+            .flat_map(|e| e.events.iter().map(|ev| ev.entity_id().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_elects_leader_and_replicates_batches() {
+        let mut nodes = cluster(&["a", "b", "c"]);
+        run(&mut nodes, 30, &[]);
+        let leader = leader(&mut nodes, &[]);
+        let term = leader.term();
+        let id = leader.node_id().to_string();
+        leader.propose(batch("x")).unwrap();
+        let index = leader.propose(batch("y")).unwrap();
+        run(&mut nodes, 5, &[]);
+
+        for node in nodes.iter_mut() {
+            assert_eq!((node.term(), node.leader()), (term, Some(id.as_str())));
+            assert_eq!(node.commit_index(), index);
+            assert_eq!(committed_entities(node), ["x", "y"]);
+            assert!(node.take_committed().is_empty());
+        }
+        let follower = nodes.iter_mut().find(|n| n.role() == Role::Follower);
+        assert!(follower.unwrap().propose(batch("z")).is_err());
+    }
+
+    #[test]
+    fn test_new_leader_overwrites_uncommitted_entries() {
+        let mut nodes = cluster(&["a", "b", "c"]);
+        run(&mut nodes, 30, &[]);
+        let old = leader(&mut nodes, &[]);
+        let old_id = old.node_id().to_string();
+        let old_term = old.term();
+        old.propose(batch("x")).unwrap();
+        run(&mut nodes, 5, &[]);
+
+        // Cut off from the others, the old leader cannot commit.
+        let down = [old_id.as_str()];
+        let stale = nodes.iter_mut().find(|n| n.node_id() == old_id).unwrap();
+        let stale_index = stale.propose(batch("lost")).unwrap();
+        run(&mut nodes, 40, &down);
+        let new = leader(&mut nodes, &down);
+        assert!(new.term() > old_term);
+        new.propose(batch("y")).unwrap();
+        run(&mut nodes, 5, &down);
+
+        run(&mut nodes, 10, &[]);
+        let leaders = nodes.iter().filter(|n| n.role() == Role::Leader).count();
+        assert_eq!(leaders, 1);
+        for node in nodes.iter_mut() {
+            assert_eq!(committed_entities(node), ["x", "y"]);
+            assert!(node.commit_index() >= stale_index);
+        }
+    }
+
+    #[test]
+    fn test_lagging_node_catches_up_from_snapshot() {
+        let mut nodes = cluster(&["a", "b", "c"]);
+        run(&mut nodes, 30, &[]);
+        let lagging = nodes.iter().position(|n| n.role() != Role::Leader).unwrap();
+        let lagging_id = nodes[lagging].node_id().to_string();
+        leader(&mut nodes, &[]).propose(batch("x")).unwrap();
+        run(&mut nodes, 5, &[]);
+
+        let down = [lagging_id.as_str()];
+        let current = leader(&mut nodes, &down);
+        current.propose(batch("y")).unwrap();
+        current.propose(batch("z")).unwrap();
+        run(&mut nodes, 5, &down);
+        for node in nodes.iter_mut().filter(|n| n.node_id() != lagging_id) {
+            let applied = committed_entities(node);
+            let through = node.commit_index();
+            assert!(node.compact(view_state(&applied)).unwrap() > 0);
+            assert!(node.log().is_empty());
+            assert_eq!(node.snapshot().last_index, through);
+        }
+
+        run(&mut nodes, 40, &[]);
+        let node = &mut nodes[lagging];
+        let snapshot = node.take_installed_snapshot().unwrap();
+        let history: Vec<_> = snapshot.events.iter().map(|e| e.entity_id()).collect();
+        assert_eq!(history, ["x", "y", "z"]);
+        let view = snapshot.view.unwrap();
+        let entities: Vec<_> = view.entries.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(entities, ["x", "y", "z"]);
+        assert!(node.take_installed_snapshot().is_none());
+        assert!(committed_entities(node).is_empty());
+
+        leader(&mut nodes, &[]).propose(batch("w")).unwrap();
+        run(&mut nodes, 5, &[]);
+        assert_eq!(committed_entities(&mut nodes[lagging]), ["w"]);
+        // Only applied entries are compacted.
+        let applied = nodes[lagging].log().len();
+        let view = view_state(&["x", "y", "z", "w"]);
+        assert_eq!(nodes[lagging].compact(view).unwrap(), applied);
+        assert!(nodes[lagging].log().is_empty());
+    }
+
+    #[test]
+    fn test_single_node_commits_alone() {
+        let mut node = RaftNode::new("solo", Vec::new()).with_election_ticks(2);
+        assert!(node.propose(batch("x")).is_err());
+        for _ in 0..4 {
+            node.tick().unwrap();
+        }
+        assert_eq!(node.role(), Role::Leader);
+        let index = node.propose(batch("x")).unwrap();
+        assert_eq!(node.commit_index(), index);
+        assert_eq!(committed_entities(&mut node), ["x"]);
+        assert!(node.take_messages().is_empty());
+    }
+
+    #[test]
+    fn test_restarted_node_keeps_term_vote_and_log() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("raft");
+        let open = || {
+            let storage = Box::new(FileRaftStorage::open(&dir).unwrap());
+            RaftNode::open("a", vec!["b".to_string(), "c".to_string()], storage).unwrap()
+        };
+        let request = |from: &str, term| Envelope {
+            from: from.to_string(),
+            to: "a".to_string(),
+            message: RaftMessage::RequestVote {
+                term,
+                last_log_index: 1,
+                last_log_term: 1,
+            },
+        };
+        let granted = |node: &mut RaftNode| {
+            let messages = node.take_messages();
+            matches!(
+                messages.last().map(|m| &m.message),
+                Some(RaftMessage::Vote { granted: true, .. })
+            )
+        };
+
+        let mut node = open();
+        node.step(request("b", 1)).unwrap();
+        assert!(granted(&mut node));
+        let entries = vec![LogEntry {
+            term: 1,
+            index: 1,
+            events: batch("x"),
+        }];
+        let append = Envelope {
+            from: "b".to_string(),
+            to: "a".to_string(),
+            message: RaftMessage::AppendEntries {
+                term: 1,
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries,
+                leader_commit: 0,
+            },
+        };
+        node.step(append).unwrap();
+        drop(node);
+
+        let mut node = open();
+        assert_eq!(node.term(), 1);
+        assert_eq!(node.log().len(), 1);
+        // Another candidate of the same term does not get a second vote.
+        node.step(request("c", 1)).unwrap();
+        assert!(!granted(&mut node));
+        node.step(request("b", 1)).unwrap();
+        assert!(granted(&mut node));
     }
 }
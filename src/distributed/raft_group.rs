@@ -0,0 +1,502 @@
+//! Raft driven over the cluster transport
+//!
+//! A [`RaftGroup`] runs a [`RaftNode`] for the local database: it ticks the
+//! node on an interval, sends its messages to the other members as
+//! [`NodeRequest::Raft`] over their [`NodeClient`]s, steps the messages a
+//! [`NodeServer`](crate::distributed::NodeServer) hands it (see
+//! [`NodeServer::with_raft`](crate::distributed::NodeServer::with_raft)),
+//! and applies committed batches to the database with
+//! [`TemporalDB::apply_remote_events`], in log order. The leader applies
+//! its own batches the same way, so every member's journal holds the same
+//! events. A member lagging behind the entries compacted with
+//! [`RaftGroup::compact`] applies the events of the leader's snapshot to
+//! its journal instead, and imports its view.
+//!
+//! A group is a [`NodeClient`] too: writes and the steps of cross-shard
+//! transactions sent to it are replicated, and answered once applied on
+//! the leader. A [`ShardedDB`](crate::distributed::ShardedDB) reaching a
+//! shard through the group's leader thus prepares and commits the shard's
+//! part of a transaction on every member, so the part and its locks
+//! outlive the leader.
+//!
+//! Messages to each member are sent in order by a task of their own, so a
+//! slow or unreachable member holds up neither the others nor the server
+//! answering it; a message that cannot be delivered is dropped, and Raft
+//! sends it again.
+
+use crate::core::event::{Event, EventPayload};
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::distributed::gossip::NodeId;
+use crate::distributed::raft::{Envelope, RaftNode, Role};
+use crate::distributed::transaction::{PreparedPart, TxnId, TXN_FINISHED, TXN_PREPARED};
+use crate::distributed::transport::{NodeClient, NodeRequest, NodeResponse};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Time between ticks of the Raft node, by default
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time a proposal may take to be committed and applied, by default
+pub const DEFAULT_PROPOSAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Proposals waiting for their entry to be applied, by index, with the term
+/// they were proposed in
+type Waiting = HashMap<u64, (u64, oneshot::Sender<Result<NodeResponse>>)>;
+
+/// Raft node of the local database, connected to the other members
+pub struct RaftGroup {
+    db: Arc<TemporalDB>,
+    node: Mutex<RaftNode>,
+    peers: HashMap<NodeId, Arc<dyn NodeClient>>,
+    outbox: HashMap<NodeId, mpsc::UnboundedSender<Envelope>>,
+    waiting: Mutex<Waiting>,
+    /// Held while committed entries are taken and applied, so they are
+    /// applied in log order
+    applying: tokio::sync::Mutex<()>,
+    proposal_timeout: Duration,
+}
+
+impl RaftGroup {
+    /// Group applying the batches `node` commits to `db`; the node's peers
+    /// are reached once added with [`RaftGroup::with_peer`]
+    pub fn new(db: Arc<TemporalDB>, node: RaftNode) -> Self {
+        Self {
+            db,
+            node: Mutex::new(node),
+            peers: HashMap::new(),
+            outbox: HashMap::new(),
+            waiting: Mutex::default(),
+            applying: tokio::sync::Mutex::new(()),
+            proposal_timeout: DEFAULT_PROPOSAL_TIMEOUT,
+        }
+    }
+
+    /// Send the messages for member `node` to `client`
+    pub fn with_peer(mut self, node: impl Into<NodeId>, client: Arc<dyn NodeClient>) -> Self {
+        self.peers.insert(node.into(), client);
+        self
+    }
+
+    /// Fail a proposal not applied within `timeout`
+    pub fn with_proposal_timeout(mut self, timeout: Duration) -> Self {
+        self.proposal_timeout = timeout;
+        self
+    }
+
+    /// Start sending messages to the peers and ticking the node every
+    /// `interval`, until the group is dropped
+    pub fn start(mut self, interval: Duration) -> Arc<Self> {
+        for (node, client) in &self.peers {
+            let (sender, mut messages) = mpsc::unbounded_channel::<Envelope>();
+            self.outbox.insert(node.clone(), sender);
+            let (node, client) = (node.clone(), client.clone());
+            tokio::spawn(async move {
+                while let Some(envelope) = messages.recv().await {
+                    if let Err(e) = client.raft_message(envelope).await {
+                        tracing::warn!("Sending a Raft message to {} failed: {}", node, e);
+                    }
+                }
+            });
+        }
+        let group = Arc::new(self);
+        spawn_tick_job(Arc::downgrade(&group), interval);
+        group
+    }
+
+    /// Local node ID
+    pub fn node_id(&self) -> String {
+        self.lock_node().node_id().to_string()
+    }
+
+    /// Whether the local node is the leader of the current term
+    pub fn is_leader(&self) -> bool {
+        self.lock_node().role() == Role::Leader
+    }
+
+    /// Leader of the current term, if known
+    pub fn leader(&self) -> Option<NodeId> {
+        self.lock_node().leader().map(str::to_string)
+    }
+
+    /// Replicate `events` as one batch and wait until it is committed and
+    /// applied to the local database; returns the index of its entry.
+    /// Fails unless the local node is the leader, if the entry is replaced
+    /// by another leader's, or if applying it fails.
+    pub async fn propose(&self, events: Vec<Event>) -> Result<u64> {
+        self.replicate(events).await.map(|(index, _)| index)
+    }
+
+    /// Propose `events`, see [`RaftGroup::propose`], returning the index of
+    /// their entry and the answer of applying it
+    async fn replicate(&self, events: Vec<Event>) -> Result<(u64, NodeResponse)> {
+        let (index, applied) = {
+            let mut node = self.lock_node();
+            let index = node.propose(events)?;
+            let (sender, applied) = oneshot::channel();
+            self.lock_waiting().insert(index, (node.term(), sender));
+            (index, applied)
+        };
+        self.advance().await?;
+        match tokio::time::timeout(self.proposal_timeout, applied).await {
+            Ok(Ok(result)) => result.map(|response| (index, response)),
+            Ok(Err(_)) => Err(Error::Distributed(format!(
+                "Raft group dropped the proposal at index {}",
+                index
+            ))),
+            Err(_) => {
+                self.lock_waiting().remove(&index);
+                Err(Error::Distributed(format!(
+                    "Proposal at index {} was not applied within {:?}",
+                    index, self.proposal_timeout
+                )))
+            }
+        }
+    }
+
+    /// Handle a message of another member, see [`RaftNode::step`]
+    pub async fn step(&self, envelope: Envelope) -> Result<()> {
+        self.lock_node().step(envelope)?;
+        self.advance().await
+    }
+
+    /// Advance the clock of the node by one tick, see [`RaftNode::tick`]
+    pub async fn tick(&self) -> Result<()> {
+        self.lock_node().tick()?;
+        self.advance().await
+    }
+
+    /// Compact the entries applied so far into a snapshot of their events
+    /// and the database's view, see [`RaftNode::compact`]; returns how many
+    /// entries were dropped from the log
+    pub async fn compact(&self) -> Result<usize> {
+        let _applying = self.applying.lock().await;
+        let view = self.db.export_view().await?;
+        self.lock_node().compact(view)
+    }
+
+    /// Send the node's messages, then apply what it committed
+    async fn advance(&self) -> Result<()> {
+        let messages = self.lock_node().take_messages();
+        for envelope in messages {
+            if let Some(outbox) = self.outbox.get(&envelope.to) {
+                // The receiving task ends only once the group is dropped.
+                let _ = outbox.send(envelope);
+            }
+        }
+
+        let _applying = self.applying.lock().await;
+        let (snapshot, committed) = {
+            let mut node = self.lock_node();
+            (node.take_installed_snapshot(), node.take_committed())
+        };
+        if let Some(snapshot) = snapshot {
+            // Events the journal holds already are skipped as duplicates.
+            for event in snapshot.events {
+                if let Err(e) = self.apply(vec![event]).await {
+                    tracing::warn!("Applying a Raft snapshot event failed: {}", e);
+                }
+            }
+            if let Some(view) = snapshot.view {
+                self.db.import_view(view).await?;
+            }
+        }
+        for entry in committed {
+            let waiting = self.lock_waiting().remove(&entry.index);
+            let result = self.apply(entry.events).await;
+            if let Err(e) = &result {
+                tracing::warn!("Applying Raft entry {} failed: {}", entry.index, e);
+            }
+            if let Some((term, sender)) = waiting {
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(Error::Distributed(format!(
+                        "Proposal at index {} was replaced by another leader's",
+                        entry.index
+                    )))
+                };
+                let _ = sender.send(result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the events of a committed entry: a transaction step, which
+    /// is alone in its entry, to the prepared transactions, any others with
+    /// [`TemporalDB::apply_remote_events`]
+    async fn apply(&self, events: Vec<Event>) -> Result<NodeResponse> {
+        let step = match events.as_slice() {
+            [step] => TxnId::from_entity(step.entity_id()).map(|txn| (txn, step)),
+            _ => None,
+        };
+        let Some((txn, step)) = step else {
+            let report = self.db.apply_remote_events(events).await?;
+            return Ok(NodeResponse::Applied(report));
+        };
+        match step.event_type() {
+            TXN_PREPARED => {
+                let part: PreparedPart = step
+                    .payload()
+                    .to_bincode()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                self.db
+                    .prepare_transaction(txn, &part.coordinator, part.events)
+                    .await?;
+                Ok(NodeResponse::Done)
+            }
+            TXN_FINISHED => {
+                let commit: bool = step
+                    .payload()
+                    .to_json()
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                let finished = if commit {
+                    self.db.commit_transaction(txn).await?
+                } else {
+                    self.db.abort_transaction(txn).await?
+                };
+                Ok(NodeResponse::Finished(finished))
+            }
+            other => Err(Error::Distributed(format!(
+                "Unknown step {} of transaction {}",
+                other, txn
+            ))),
+        }
+    }
+
+    /// Replicate step `event_type` of `txn`, carrying `payload`
+    async fn replicate_step(
+        &self,
+        event_type: &str,
+        txn: TxnId,
+        payload: EventPayload,
+    ) -> Result<NodeResponse> {
+        let step = Event::new(
+            event_type.to_string(),
+            Timestamp::now(),
+            txn.entity(),
+            payload,
+        );
+        Ok(self.replicate(vec![step]).await?.1)
+    }
+
+    fn lock_node(&self) -> MutexGuard<'_, RaftNode> {
+        self.node.lock().expect("RaftGroup poisoned node lock")
+    }
+
+    fn lock_waiting(&self) -> MutexGuard<'_, Waiting> {
+        self.waiting
+            .lock()
+            .expect("RaftGroup poisoned waiting lock")
+    }
+}
+
+#[async_trait]
+impl NodeClient for RaftGroup {
+    /// Writes and transaction steps are replicated and answered once
+    /// applied locally; reads are answered by the local database
+    async fn call(&self, request: NodeRequest) -> Result<NodeResponse> {
+        match request {
+            NodeRequest::Insert {
+                entity_id,
+                value,
+                timestamp,
+            } => {
+                let value: serde_json::Value = serde_json::from_slice(&value)?;
+                let event = self.db.value_event(&entity_id, value, timestamp)?;
+                self.replicate(vec![event]).await?;
+                Ok(NodeResponse::Done)
+            }
+            NodeRequest::ApplyEvents { events } => Ok(self.replicate(events).await?.1),
+            NodeRequest::Prepare {
+                txn,
+                coordinator,
+                events,
+            } => {
+                let part = PreparedPart {
+                    coordinator,
+                    events,
+                };
+                let payload = EventPayload::from_bincode(&part)
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                self.replicate_step(TXN_PREPARED, txn, payload).await
+            }
+            NodeRequest::Commit { txn } | NodeRequest::Abort { txn } => {
+                let commit = matches!(request, NodeRequest::Commit { .. });
+                let payload = EventPayload::from_json(&commit)
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                self.replicate_step(TXN_FINISHED, txn, payload).await
+            }
+            NodeRequest::Raft(envelope) => {
+                self.step(envelope).await?;
+                Ok(NodeResponse::Done)
+            }
+            request => self.db.call(request).await,
+        }
+    }
+}
+
+/// Tick the node of `group` every `interval` until the group is dropped
+fn spawn_tick_job(group: Weak<RaftGroup>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(group) = group.upgrade() else {
+                break;
+            };
+            if let Err(e) = group.tick().await {
+                tracing::warn!("Raft tick failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::temporal::Timestamp;
+    use crate::distributed::transport::{ClusterSecret, NodeServer, TcpClient};
+    use tokio::net::TcpListener;
+
+    /// Addresses of members `ids`, with the listeners bound to them
+    async fn bind(ids: &[&'static str]) -> (HashMap<&'static str, String>, Vec<TcpListener>) {
+        let mut addrs = HashMap::new();
+        let mut listeners = Vec::new();
+        for id in ids {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.insert(*id, listener.local_addr().unwrap().to_string());
+            listeners.push(listener);
+        }
+        (addrs, listeners)
+    }
+
+    /// Start member `id` on `listener`, reaching the others at `addrs`
+    fn start_member(
+        id: &str,
+        listener: TcpListener,
+        addrs: &HashMap<&'static str, String>,
+    ) -> (Arc<TemporalDB>, Arc<RaftGroup>) {
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        let db = Arc::new(TemporalDB::in_memory().unwrap());
+        let peers: Vec<NodeId> = addrs
+            .keys()
+            .filter(|p| **p != id)
+            .map(|p| p.to_string())
+            .collect();
+        let node = RaftNode::new(id, peers.clone()).with_election_ticks(5);
+        let mut group = RaftGroup::new(db.clone(), node);
+        for peer in peers {
+            let client = TcpClient::new(addrs[peer.as_str()].clone(), secret.clone());
+            group = group.with_peer(peer, Arc::new(client));
+        }
+        let group = group.start(Duration::from_millis(10));
+        let server = NodeServer::new(db.clone(), secret).with_raft(group.clone());
+        tokio::spawn(server.serve(listener));
+        (db, group)
+    }
+
+    async fn elect(groups: &[Arc<RaftGroup>]) -> Arc<RaftGroup> {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(leader) = groups.iter().find(|g| g.is_leader()) {
+                return leader.clone();
+            }
+            assert!(tokio::time::Instant::now() < deadline, "no leader elected");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Wait until `db` holds `event`
+    async fn applied(db: &TemporalDB, event: &Event) {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let events = db.get_entity_events(event.entity_id()).await.unwrap();
+            if events.iter().any(|e| e.id() == event.id()) {
+                return;
+            }
+            assert!(tokio::time::Instant::now() < deadline, "batch not applied");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_replicate_across_node_servers() {
+        let (addrs, listeners) = bind(&["a", "b", "c"]).await;
+        let (dbs, groups): (Vec<_>, Vec<_>) = ["a", "b", "c"]
+            .into_iter()
+            .zip(listeners)
+            .map(|(id, listener)| start_member(id, listener, &addrs))
+            .unzip();
+
+        let leader = elect(&groups).await;
+        let follower = groups.iter().find(|g| !g.is_leader()).unwrap();
+        let event = dbs[0]
+            .value_event("user:1", "active", Timestamp::from_secs(100))
+            .unwrap();
+        assert!(follower.propose(vec![event.clone()]).await.is_err());
+
+        leader.propose(vec![event.clone()]).await.unwrap();
+        for db in &dbs {
+            applied(db, &event).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_late_member_gets_the_compacted_history() {
+        let (addrs, mut listeners) = bind(&["a", "b", "c"]).await;
+        let late = listeners.pop().unwrap();
+        let (dbs, groups): (Vec<_>, Vec<_>) = ["a", "b"]
+            .into_iter()
+            .zip(listeners)
+            .map(|(id, listener)| start_member(id, listener, &addrs))
+            .unzip();
+
+        let leader = elect(&groups).await;
+        let event = dbs[0]
+            .value_event("user:1", "active", Timestamp::from_secs(100))
+            .unwrap();
+        leader.propose(vec![event.clone()]).await.unwrap();
+        assert!(leader.compact().await.unwrap() > 0);
+
+        let (db, _group) = start_member("c", late, &addrs);
+        applied(&db, &event).await;
+    }
+
+    #[tokio::test]
+    async fn test_transaction_parts_are_replicated() {
+        let (addrs, listeners) = bind(&["a", "b", "c"]).await;
+        let (dbs, groups): (Vec<_>, Vec<_>) = ["a", "b", "c"]
+            .into_iter()
+            .zip(listeners)
+            .map(|(id, listener)| start_member(id, listener, &addrs))
+            .unzip();
+        let leader = elect(&groups).await;
+        let client: &dyn NodeClient = leader.as_ref();
+        let ts = Timestamp::from_secs(100);
+
+        let txn = TxnId::new();
+        let event = dbs[0].value_event("account:1", 50, ts).unwrap();
+        client
+            .prepare_transaction(txn, "x", vec![event.clone()])
+            .await
+            .unwrap();
+        // The prepared part locks the entity on the leader.
+        assert!(client.insert("account:1", 0, ts).await.is_err());
+        assert!(client.commit_transaction(txn).await.unwrap());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        for db in &dbs {
+            applied(db, &event).await;
+            while !db.prepared_transactions().is_empty() {
+                assert!(tokio::time::Instant::now() < deadline, "part not committed");
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! Durable state of a Raft node
+//!
+//! A node must not forget its term, its vote or the entries it
+//! acknowledged: restarted with less, it could vote twice in one term or
+//! drop a committed entry. [`RaftNode`](crate::distributed::raft::RaftNode)
+//! saves them through a [`RaftStorage`] before it answers the message that
+//! changed them.
+//!
+//! [`FileRaftStorage`] keeps three files in a directory:
+//! - `RAFT_STATE`: magic `TDBRAFTS`, CRC32 of the body, bincode-serialized
+//!   term and vote, replaced atomically
+//! - `RAFT_SNAPSHOT`: magic `TDBRSNAP`, CRC32 of the body,
+//!   bincode-serialized [`RaftSnapshot`], replaced atomically
+//! - `RAFT_LOG`: entries after the snapshot, each a 4-byte length, a 4-byte
+//!   CRC32 and the bincode-serialized [`LogEntry`] (little-endian); a torn
+//!   record at the end is cut off on load
+
+use crate::distributed::gossip::NodeId;
+use crate::distributed::raft::{LogEntry, RaftSnapshot};
+use crate::error::{Error, Result};
+use crc32fast::Hasher as Crc32Hasher;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Term and vote file name inside a Raft directory
+pub const RAFT_STATE_FILE: &str = "RAFT_STATE";
+
+/// Snapshot file name inside a Raft directory
+pub const RAFT_SNAPSHOT_FILE: &str = "RAFT_SNAPSHOT";
+
+/// Log file name inside a Raft directory
+pub const RAFT_LOG_FILE: &str = "RAFT_LOG";
+
+const STATE_MAGIC: &[u8; 8] = b"TDBRAFTS";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TDBRSNAP";
+
+/// Everything a node saved, restored by
+/// [`RaftNode::open`](crate::distributed::raft::RaftNode::open)
+#[derive(Debug, Clone, Default)]
+pub struct RaftState {
+    /// Latest term the node has seen
+    pub term: u64,
+    /// Candidate the node voted for in `term`, if any
+    pub voted_for: Option<NodeId>,
+    /// Compacted prefix of the log
+    pub snapshot: RaftSnapshot,
+    /// Entries after the snapshot
+    pub log: Vec<LogEntry>,
+}
+
+/// Where a Raft node saves its state; every method returns once the
+/// change is durable
+pub trait RaftStorage: Send {
+    /// State saved so far, `None` for a node that never saved any
+    fn load(&mut self) -> Result<Option<RaftState>>;
+
+    /// Save the current term and the vote cast in it
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<&NodeId>) -> Result<()>;
+
+    /// Save `entries`, replacing the saved entries from the index of the
+    /// first one on
+    fn append_entries(&mut self, entries: &[LogEntry]) -> Result<()>;
+
+    /// Replace the saved snapshot, and the saved entries with `log`, the
+    /// entries following it
+    fn save_snapshot(&mut self, snapshot: &RaftSnapshot, log: &[LogEntry]) -> Result<()>;
+}
+
+/// Keeps the state in memory only, for nodes that need not survive a
+/// restart
+#[derive(Debug, Default)]
+pub struct MemoryRaftStorage {
+    state: Option<RaftState>,
+}
+
+impl MemoryRaftStorage {
+    /// Empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&mut self) -> &mut RaftState {
+        self.state.get_or_insert_with(RaftState::default)
+    }
+}
+
+impl RaftStorage for MemoryRaftStorage {
+    fn load(&mut self) -> Result<Option<RaftState>> {
+        Ok(self.state.clone())
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<&NodeId>) -> Result<()> {
+        let state = self.state();
+        state.term = term;
+        state.voted_for = voted_for.cloned();
+        Ok(())
+    }
+
+    fn append_entries(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        let state = self.state();
+        state.log.retain(|e| e.index < first.index);
+        state.log.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &RaftSnapshot, log: &[LogEntry]) -> Result<()> {
+        let state = self.state();
+        state.snapshot = snapshot.clone();
+        state.log = log.to_vec();
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+}
+
+/// Keeps the state in files of a directory, see the
+/// [module documentation](self)
+pub struct FileRaftStorage {
+    dir: PathBuf,
+    log: File,
+    /// Index and file offset of every entry in the log file
+    offsets: Vec<(u64, u64)>,
+}
+
+impl FileRaftStorage {
+    /// Open the storage in `dir`, creating the directory if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let log = open_log(&dir.join(RAFT_LOG_FILE))?;
+        Ok(Self {
+            dir,
+            log,
+            offsets: Vec::new(),
+        })
+    }
+
+    fn write_records(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let mut offset = self.log.seek(SeekFrom::End(0))?;
+        let mut buf = Vec::new();
+        for entry in entries {
+            let body =
+                bincode::serialize(entry).map_err(|e| Error::Serialization(e.to_string()))?;
+            let mut hasher = Crc32Hasher::new();
+            hasher.update(&body);
+            self.offsets.push((entry.index, offset));
+            offset += 8 + body.len() as u64;
+            buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+            buf.extend_from_slice(&body);
+        }
+        self.log.write_all(&buf)?;
+        self.log.sync_data()?;
+        Ok(())
+    }
+}
+
+impl RaftStorage for FileRaftStorage {
+    fn load(&mut self) -> Result<Option<RaftState>> {
+        let hard: Option<HardState> = load_file(&self.dir, RAFT_STATE_FILE, STATE_MAGIC)?;
+        let snapshot: Option<RaftSnapshot> =
+            load_file(&self.dir, RAFT_SNAPSHOT_FILE, SNAPSHOT_MAGIC)?;
+
+        let data = fs::read(self.dir.join(RAFT_LOG_FILE))?;
+        let mut entries = Vec::new();
+        self.offsets.clear();
+        let mut offset = 0;
+        while let Some((entry, len)) = decode_record(&data[offset..]) {
+            self.offsets.push((entry.index, offset as u64));
+            entries.push(entry);
+            offset += len;
+        }
+        if offset < data.len() {
+            // Cut off a record torn by a crash, so appends follow the last
+            // whole one.
+            self.log.set_len(offset as u64)?;
+            self.log.sync_data()?;
+        }
+
+        if hard.is_none() && snapshot.is_none() && entries.is_empty() {
+            return Ok(None);
+        }
+        let hard = hard.unwrap_or(HardState {
+            term: 0,
+            voted_for: None,
+        });
+        let snapshot = snapshot.unwrap_or_default();
+        // Entries the snapshot covers are left over if a crash came between
+        // writing the snapshot and rewriting the log.
+        entries.retain(|e| e.index > snapshot.last_index);
+        Ok(Some(RaftState {
+            term: hard.term,
+            voted_for: hard.voted_for,
+            snapshot,
+            log: entries,
+        }))
+    }
+
+    fn save_hard_state(&mut self, term: u64, voted_for: Option<&NodeId>) -> Result<()> {
+        let hard = HardState {
+            term,
+            voted_for: voted_for.cloned(),
+        };
+        store_file(&self.dir, RAFT_STATE_FILE, STATE_MAGIC, &hard)
+    }
+
+    fn append_entries(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        let kept = self
+            .offsets
+            .partition_point(|(index, _)| *index < first.index);
+        if let Some(&(_, offset)) = self.offsets.get(kept) {
+            self.log.set_len(offset)?;
+            self.offsets.truncate(kept);
+        }
+        self.write_records(entries)
+    }
+
+    fn save_snapshot(&mut self, snapshot: &RaftSnapshot, log: &[LogEntry]) -> Result<()> {
+        store_file(&self.dir, RAFT_SNAPSHOT_FILE, SNAPSHOT_MAGIC, snapshot)?;
+
+        // Rewrite the log with the entries after the snapshot.
+        let path = self.dir.join(RAFT_LOG_FILE);
+        let tmp_path = self.dir.join(format!("{RAFT_LOG_FILE}.tmp"));
+        let _ = fs::remove_file(&tmp_path);
+        self.log = open_log(&tmp_path)?;
+        self.offsets.clear();
+        self.write_records(log)?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?)
+}
+
+/// Entry of the record at the start of `data` and the record's length, if
+/// it is whole
+fn decode_record(data: &[u8]) -> Option<(LogEntry, usize)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let crc = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let body = data.get(8..8 + len)?;
+    let mut hasher = Crc32Hasher::new();
+    hasher.update(body);
+    if hasher.finalize() != crc {
+        return None;
+    }
+    let entry = bincode::deserialize(body).ok()?;
+    Some((entry, 8 + len))
+}
+
+fn load_file<T: DeserializeOwned>(dir: &Path, name: &str, magic: &[u8; 8]) -> Result<Option<T>> {
+    let path = dir.join(name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(&path)?;
+    if data.len() < 12 || &data[..8] != magic {
+        return Err(Error::Storage(format!(
+            "Invalid Raft state file: {}",
+            path.display()
+        )));
+    }
+    let crc = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+    let body = &data[12..];
+    let mut hasher = Crc32Hasher::new();
+    hasher.update(body);
+    if hasher.finalize() != crc {
+        return Err(Error::Storage(format!(
+            "Raft state checksum mismatch: {}",
+            path.display()
+        )));
+    }
+    let value = bincode::deserialize(body).map_err(|e| Error::Serialization(e.to_string()))?;
+    Ok(Some(value))
+}
+
+fn store_file<T: Serialize>(dir: &Path, name: &str, magic: &[u8; 8], value: &T) -> Result<()> {
+    let path = dir.join(name);
+    let tmp_path = dir.join(format!("{name}.tmp"));
+
+    let body = bincode::serialize(value).map_err(|e| Error::Serialization(e.to_string()))?;
+    let mut hasher = Crc32Hasher::new();
+    hasher.update(&body);
+
+    let mut tmp = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    tmp.write_all(magic)?;
+    tmp.write_all(&hasher.finalize().to_le_bytes())?;
+    tmp.write_all(&body)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir);
+    Ok(())
+}
+
+/// Make a rename in `dir` durable
+fn sync_dir(dir: &Path) {
+    if let Ok(dir_handle) = File::open(dir) {
+        let _ = dir_handle.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(term: u64, index: u64) -> LogEntry {
+        LogEntry {
+            term,
+            index,
+            events: Vec::new(),
+        }
+    }
+
+    fn terms(log: &[LogEntry]) -> Vec<(u64, u64)> {
+        log.iter().map(|e| (e.index, e.term)).collect()
+    }
+
+    #[test]
+    fn test_file_storage_survives_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("raft");
+        {
+            let mut storage = FileRaftStorage::open(&dir).unwrap();
+            assert!(storage.load().unwrap().is_none());
+            storage.save_hard_state(3, Some(&"b".to_string())).unwrap();
+            storage
+                .append_entries(&[entry(1, 1), entry(1, 2), entry(2, 3)])
+                .unwrap();
+            // A conflicting entry replaces the entries from its index on.
+            storage.append_entries(&[entry(3, 2)]).unwrap();
+        }
+        {
+            let mut storage = FileRaftStorage::open(&dir).unwrap();
+            let state = storage.load().unwrap().unwrap();
+            assert_eq!((state.term, state.voted_for.as_deref()), (3, Some("b")));
+            assert_eq!(terms(&state.log), [(1, 1), (2, 3)]);
+
+            let snapshot = RaftSnapshot {
+                last_index: 1,
+                last_term: 1,
+                ..RaftSnapshot::default()
+            };
+            storage.save_snapshot(&snapshot, &state.log[1..]).unwrap();
+            storage.append_entries(&[entry(3, 3)]).unwrap();
+        }
+        // A torn record at the end is dropped.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(RAFT_LOG_FILE))
+            .unwrap();
+        log.write_all(&[7, 0, 0]).unwrap();
+        drop(log);
+
+        let mut storage = FileRaftStorage::open(&dir).unwrap();
+        let state = storage.load().unwrap().unwrap();
+        assert_eq!(state.snapshot.last_index, 1);
+        assert_eq!(terms(&state.log), [(2, 3), (3, 3)]);
+        storage.append_entries(&[entry(3, 4)]).unwrap();
+        let state = storage.load().unwrap().unwrap();
+        assert_eq!(terms(&state.log), [(2, 3), (3, 3), (4, 3)]);
+    }
+}
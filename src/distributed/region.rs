@@ -0,0 +1,407 @@
+//! Asynchronous replication between regions
+//!
+//! Writes committed in one region are shipped to the others
+//! asynchronously: a [`RegionReplicator`] follows its region's journal with
+//! [`TemporalDB::events_after`] and sends the events to the other regions
+//! through their [`NodeClient`], which applies them with
+//! [`TemporalDB::apply_replicated`], so a slow or unreachable region never
+//! holds up local writes.
+//!
+//! Each region keeps a replication watermark per region shipping to it,
+//! and serves [follower reads](TemporalDB::follower_query_as_of) up to the
+//! lowest of them. A replicator registers its region with the others on
+//! its first poll, so their reads wait for it from then on; a region that
+//! writes nothing holds their watermark at its last shipped write.
+//!
+//! How far the journal was shipped to each region is saved as a projection
+//! checkpoint named [`REGION_CHECKPOINT_PREFIX`] plus the region name, see
+//! [`TemporalDB::projection_checkpoint`]. A batch the other region fails to
+//! apply is retried from there on the next poll, and a replicator spawned
+//! again, e.g. after a restart, resumes there, so no event is skipped.
+//!
+//! Conflicting writes of an entity in two regions are reconciled there:
+//! events of CRDT entities commute, and other writes at the same valid
+//! time are resolved by the entity type's policy, last writer wins by
+//! default, the same way in every region. Events a region receives again,
+//! from another region or from a retried batch, are recognized by ID and
+//! skipped, so shipping in both directions settles.
+
+use crate::core::event::Event;
+use crate::core::namespace::is_internal_entity;
+use crate::core::subscription::SubscriptionFilter;
+use crate::core::temporal::Timestamp;
+use crate::db::{SyncReport, TemporalDB};
+use crate::distributed::transport::NodeClient;
+use crate::error::{Error, Result};
+use crate::storage::Lsn;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Events shipped to the other regions at once, at most
+pub const REGION_BATCH: usize = 256;
+
+/// How long shipping waits before reading the journal again once it has
+/// caught up, or before retrying a failed batch
+pub const REGION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prefix of the projection checkpoints recording how far the journal was
+/// shipped to each region
+pub const REGION_CHECKPOINT_PREFIX: &str = "region:";
+
+/// Progress of shipping a region's events to one other region
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionStatus {
+    /// Name of the receiving region
+    pub region: String,
+    /// Events applied there
+    pub applied: u64,
+    /// Events it already held
+    pub duplicates: u64,
+    /// Conflicting writes it resolved
+    pub resolved: u64,
+    /// Batches it failed to apply
+    pub failures: u64,
+    /// Latest error, if any batch failed
+    pub last_error: Option<String>,
+    /// Valid time of the latest event shipped
+    pub shipped_through: Option<Timestamp>,
+    /// Position in the local journal shipped through
+    pub cursor: Lsn,
+}
+
+/// Ships the events committed in one region to the others
+pub struct RegionReplicator {
+    region: String,
+    local: Arc<TemporalDB>,
+    remotes: Vec<(String, Arc<dyn NodeClient>)>,
+    filter: SubscriptionFilter,
+    poll_interval: Duration,
+}
+
+impl RegionReplicator {
+    /// Replicator of region `region`, whose database is `local`
+    pub fn new(region: impl Into<String>, local: Arc<TemporalDB>) -> Self {
+        Self {
+            region: region.into(),
+            local,
+            remotes: Vec::new(),
+            filter: SubscriptionFilter::new(),
+            poll_interval: REGION_POLL_INTERVAL,
+        }
+    }
+
+    /// Ship events to region `region`, reached through `client`
+    pub fn with_remote(mut self, region: impl Into<String>, client: Arc<dyn NodeClient>) -> Self {
+        self.remotes.push((region.into(), client));
+        self
+    }
+
+    /// Ship only events matching `filter`
+    pub fn with_filter(mut self, filter: SubscriptionFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Wait `interval` before reading the journal again once caught up,
+    /// or before retrying a failed batch
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Ship the journal to every other region in the background, from
+    /// where the last replicator to it stopped, or from the start for a
+    /// new region, until the returned handle is stopped or the local
+    /// database is dropped
+    pub fn spawn(self) -> Result<RegionReplication> {
+        if self.remotes.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Region {} has no region to replicate to",
+                self.region
+            )));
+        }
+        let statuses: Vec<RegionStatus> = self
+            .remotes
+            .iter()
+            .map(|(region, _)| RegionStatus {
+                region: region.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let (sender, receiver) = watch::channel(statuses);
+        let sender = Arc::new(sender);
+        let tasks = self
+            .remotes
+            .into_iter()
+            .enumerate()
+            .map(|(i, (region, remote))| {
+                let mut shipment = Shipment {
+                    checkpoint: format!("{}{}", REGION_CHECKPOINT_PREFIX, region),
+                    source: self.region.clone(),
+                    remote,
+                    filter: self.filter.clone(),
+                    cursor: None,
+                    registered: false,
+                };
+                let local = Arc::downgrade(&self.local);
+                let (sender, interval) = (sender.clone(), self.poll_interval);
+                tokio::spawn(async move {
+                    while let Some(result) = shipment.ship_next(&local).await {
+                        let full = matches!(&result, Ok(shipped) if shipped.read == REGION_BATCH);
+                        sender.send_modify(|statuses| statuses[i].record(result));
+                        if !full {
+                            tokio::time::sleep(interval).await;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Ok(RegionReplication {
+            region: self.region,
+            status: receiver,
+            tasks,
+        })
+    }
+}
+
+/// A batch shipped to another region
+struct Shipped {
+    /// Journal events read, shipped or not
+    read: usize,
+    /// How the other region applied the shipped ones
+    report: SyncReport,
+    /// Valid time of the latest event shipped
+    through: Option<Timestamp>,
+    /// Position shipped through
+    cursor: Lsn,
+}
+
+impl RegionStatus {
+    fn record(&mut self, result: Result<Shipped>) {
+        match result {
+            Ok(shipped) => {
+                self.applied += shipped.report.applied as u64;
+                self.duplicates += shipped.report.duplicates as u64;
+                self.resolved += shipped.report.resolved as u64;
+                self.shipped_through = self.shipped_through.max(shipped.through);
+                self.cursor = shipped.cursor;
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Shipping of a region's journal to one other region
+struct Shipment {
+    /// Name of the projection checkpoint holding the cursor
+    checkpoint: String,
+    /// Name of the shipping region
+    source: String,
+    remote: Arc<dyn NodeClient>,
+    filter: SubscriptionFilter,
+    /// Position shipped through, once read from the checkpoint
+    cursor: Option<Lsn>,
+    /// Whether the other region has heard from this one
+    registered: bool,
+}
+
+impl Shipment {
+    /// Ship the next batch of the journal after the cursor, or `None`
+    /// once the local database is dropped
+    async fn ship_next(&mut self, local: &Weak<TemporalDB>) -> Option<Result<Shipped>> {
+        let local = local.upgrade()?;
+        Some(self.ship(&local).await)
+    }
+
+    async fn ship(&mut self, local: &TemporalDB) -> Result<Shipped> {
+        let cursor = match self.cursor {
+            Some(cursor) => cursor,
+            None => {
+                let saved = local.projection_checkpoint(&self.checkpoint).get().await?;
+                *self.cursor.insert(saved.map_or(0, |checkpoint| checkpoint.lsn))
+            }
+        };
+        let read = local.events_after(cursor, REGION_BATCH).await?;
+        let Some(&(last, _)) = read.last() else {
+            if !self.registered {
+                // Nothing to ship yet; reads there wait for this region all
+                // the same.
+                let start = Timestamp::from_nanos(i64::MIN);
+                self.remote
+                    .apply_replicated(&self.source, Vec::new(), start)
+                    .await?;
+                self.registered = true;
+            }
+            return Ok(Shipped {
+                read: 0,
+                report: SyncReport::default(),
+                through: None,
+                cursor,
+            });
+        };
+        // Conflict records and other bookkeeping stay in their region.
+        let batch: Vec<Event> = read
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|e| !is_internal_entity(e.entity_id()) && self.filter.matches(e))
+            .cloned()
+            .collect();
+        let through = batch.iter().map(Event::timestamp).max();
+        // Every write recorded until then is in the journal read so far.
+        let recorded = read
+            .iter()
+            .map(|(_, event)| event.metadata.transaction_time)
+            .fold(Timestamp::from_nanos(i64::MIN), Timestamp::max);
+        let shipped = !batch.is_empty();
+        let report = self
+            .remote
+            .apply_replicated(&self.source, batch, recorded)
+            .await?;
+        self.registered = true;
+        // Batches of internal events only are not saved, since saving the
+        // checkpoint appends one.
+        if shipped {
+            local.projection_checkpoint(&self.checkpoint).set(last).await?;
+        }
+        self.cursor = Some(last);
+        Ok(Shipped {
+            read: read.len(),
+            report,
+            through,
+            cursor: last,
+        })
+    }
+}
+
+/// Handle of a region's events being shipped in the background
+pub struct RegionReplication {
+    region: String,
+    status: watch::Receiver<Vec<RegionStatus>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RegionReplication {
+    /// Name of the shipping region
+    pub fn region(&self) -> &str {
+        &self.region
+    }
+
+    /// Progress towards every other region, in the order they were added
+    pub fn status(&self) -> Vec<RegionStatus> {
+        self.status.borrow().clone()
+    }
+
+    /// Stop shipping; a replicator spawned again resumes after the last
+    /// batch each region applied
+    pub fn stop(self) {
+        for task in self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::transport::{ClusterSecret, NodeServer, TcpClient};
+    use tokio::net::TcpListener;
+
+    async fn eventually<F: Fn() -> bool>(check: F) {
+        for _ in 0..200 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn test_regions_converge_on_conflicting_writes() {
+        let open = |node| TemporalDB::builder().with_hlc_node(node).build();
+        let eu = Arc::new(open(1).await.unwrap());
+        let us = Arc::new(open(2).await.unwrap());
+        let interval = Duration::from_millis(10);
+        let to_us = RegionReplicator::new("eu", eu.clone())
+            .with_remote("us", us.clone())
+            .with_poll_interval(interval)
+            .spawn()
+            .unwrap();
+        let to_eu = RegionReplicator::new("us", us.clone())
+            .with_remote("eu", eu.clone())
+            .with_poll_interval(interval)
+            .spawn()
+            .unwrap();
+        assert!(RegionReplicator::new("ap", eu.clone()).spawn().is_err());
+
+        let ts = Timestamp::from_secs(100);
+        eu.insert("order:1", "placed", ts).await.unwrap();
+        us.insert("order:1", "cancelled", ts).await.unwrap();
+        eu.insert("order:2", "placed", ts).await.unwrap();
+        let settled = |status: &RegionStatus| status.applied + status.resolved;
+        eventually(|| settled(&to_us.status()[0]) >= 2 && settled(&to_eu.status()[0]) >= 1).await;
+
+        for id in ["order:1", "order:2"] {
+            let mut values = Vec::new();
+            for db in [&eu, &us] {
+                let value: Option<String> = db.query_as_of(id, ts).await.unwrap();
+                values.push(value);
+            }
+            assert!(values[0].is_some() && values[0] == values[1]);
+        }
+        let status = &to_us.status()[0];
+        assert_eq!((status.region.as_str(), status.failures), ("us", 0));
+        assert_eq!(status.shipped_through, Some(ts));
+        to_us.stop();
+        to_eu.stop();
+    }
+
+    #[tokio::test]
+    async fn test_shipping_resumes_from_saved_cursor() {
+        let (eu, us) = (
+            Arc::new(TemporalDB::in_memory().unwrap()),
+            Arc::new(TemporalDB::in_memory().unwrap()),
+        );
+        // The other region is reached over the network.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        tokio::spawn(NodeServer::new(us.clone(), secret.clone()).serve(listener));
+        let client: Arc<dyn NodeClient> = Arc::new(TcpClient::new(addr, secret));
+        let replicator = || {
+            RegionReplicator::new("eu", eu.clone())
+                .with_remote("us", client.clone())
+                .with_poll_interval(Duration::from_millis(10))
+        };
+        // Events committed before shipping starts, more than a batch, are
+        // shipped too.
+        for i in 0..REGION_BATCH + 10 {
+            let id = format!("order:{}", i);
+            eu.insert(&id, "placed", Timestamp::from_secs(100))
+                .await
+                .unwrap();
+        }
+        let shipping = replicator().spawn().unwrap();
+        eventually(|| shipping.status()[0].applied == REGION_BATCH as u64 + 10).await;
+        shipping.stop();
+
+        eu.insert("order:0", "shipped", Timestamp::from_secs(200))
+            .await
+            .unwrap();
+        let shipping = replicator().spawn().unwrap();
+        eventually(|| shipping.status()[0].applied == 1).await;
+        let status = &shipping.status()[0];
+        assert_eq!((status.duplicates, status.failures), (0, 0));
+        let value: Option<String> = us.get_current("order:0").await.unwrap();
+        assert_eq!(value.as_deref(), Some("shipped"));
+        let events = eu.get_entity_events("order:0").await.unwrap();
+        let recorded = events.last().unwrap().metadata.transaction_time;
+        assert!(us.replication_watermark() >= Some(recorded));
+        shipping.stop();
+    }
+}
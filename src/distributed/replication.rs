@@ -0,0 +1,940 @@
+//! Replication with tunable quorums
+//!
+//! A [`ReplicatedDB`] keeps every entity on
+//! [`ReplicationConfig::replication_factor`] nodes, the first distinct
+//! nodes of the entity's [preference list](HashRing::preference_list),
+//! reached over a [`NodeClient`] each. A write is sent to all of them and
+//! acknowledged once [`ReplicationConfig::write_quorum`] have applied it;
+//! the others go on in the background. A read asks the replicas for the
+//! entity's events and answers once [`ReplicationConfig::read_quorum`]
+//! replied: their events are merged by event ID, replicas that missed
+//! some get them (read repair), and the value is read from a replica
+//! holding them all. Read repair is best effort: a replica failing it is
+//! logged and left to a later read.
+//!
+//! With `write_quorum + read_quorum > replication_factor` every read sees
+//! every acknowledged write; smaller quorums trade that for latency and
+//! availability.
+//!
+//! A replica that is down does not fail writes: the next node of the
+//! preference list stands in for it and keeps a copy, and the coordinator
+//! holds the write as a hint for the replica. A replica is down while it
+//! is disconnected, once it failed [`DEFAULT_FAILURES_BEFORE_DOWN`] writes
+//! in a row, or while gossip sees it dead (see
+//! [`ReplicatedDB::observe_liveness`]). Hints are handed off when the
+//! replica is back (see [`ReplicatedDB::reconnect`] and
+//! [`ReplicatedDB::replay_hints`]), or by a background task probing the
+//! replicas (see [`ReplicatedDB::spawn_hint_handoff`]), so every entity is
+//! back on all its replicas. A replica that fails a write gets a hint too,
+//! handed off with its next write or by that task.
+//!
+//! Hints are recorded on the coordinator's own node, on the internal
+//! entity [`hint_entity`] of the replica, and held again after a restart
+//! by [`ReplicatedDB::load_hints`]. At most [`DEFAULT_MAX_HINTS`] are held
+//! per replica; writes beyond that are left to read repair and counted in
+//! the node's [`MetricsSnapshot::hints_dropped`].
+//!
+//! [`MetricsSnapshot::hints_dropped`]: crate::metrics::MetricsSnapshot::hints_dropped
+
+use crate::core::event::{Event, EventId, EventPayload};
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::distributed::gossip::{ClusterStatus, NodeId};
+use crate::distributed::sharding::HashRing;
+use crate::distributed::transport::NodeClient;
+use crate::error::{Error, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Hints held for a replica at most, by default
+pub const DEFAULT_MAX_HINTS: usize = 10_000;
+
+/// Writes a replica fails in a row before it is marked down, by default
+pub const DEFAULT_FAILURES_BEFORE_DOWN: u32 = 3;
+
+/// How often held writes are handed off in the background, by default,
+/// see [`ReplicatedDB::spawn_hint_handoff`]
+pub const DEFAULT_HANDOFF_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prefix of the internal entities recording the hints for a replica
+pub const HINT_ENTITY_PREFIX: &str = "$sys:hint:";
+
+/// Event type recorded when writes are held for a replica, carrying them
+pub const HINT_STORED: &str = "hint.stored";
+
+/// Event type recorded when held writes were handed off, carrying their
+/// IDs
+pub const HINT_DELIVERED: &str = "hint.delivered";
+
+/// Internal entity ID under which the hints for `node` are recorded
+pub fn hint_entity(node: &str) -> String {
+    format!("{}{}", HINT_ENTITY_PREFIX, node)
+}
+
+/// How many nodes hold every entity, and how many must answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// Nodes holding every entity
+    pub replication_factor: usize,
+    /// Replicas that must apply a write before it is acknowledged
+    pub write_quorum: usize,
+    /// Replicas that must answer a read
+    pub read_quorum: usize,
+}
+
+impl ReplicationConfig {
+    /// Keep `replication_factor` copies, acknowledging writes at
+    /// `write_quorum` replicas and answering reads from `read_quorum`;
+    /// quorums must be between 1 and the replication factor
+    pub fn new(replication_factor: usize, write_quorum: usize, read_quorum: usize) -> Result<Self> {
+        let config = Self {
+            replication_factor,
+            write_quorum,
+            read_quorum,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the quorums are between 1 and the replication factor
+    pub fn validate(&self) -> Result<()> {
+        let valid = |quorum| (1..=self.replication_factor).contains(&quorum);
+        if !valid(self.write_quorum) || !valid(self.read_quorum) {
+            return Err(Error::Configuration(format!(
+                "Quorums must be between 1 and the replication factor {}, got W={} R={}",
+                self.replication_factor, self.write_quorum, self.read_quorum
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether every read sees every acknowledged write, i.e. the read and
+    /// write quorums overlap
+    pub fn is_strong(&self) -> bool {
+        self.write_quorum + self.read_quorum > self.replication_factor
+    }
+}
+
+impl Default for ReplicationConfig {
+    /// Three copies with majority quorums
+    fn default() -> Self {
+        Self {
+            replication_factor: 3,
+            write_quorum: 2,
+            read_quorum: 2,
+        }
+    }
+}
+
+/// Writes held for replicas that missed them
+#[derive(Debug, Default)]
+struct Hints {
+    pending: HashMap<NodeId, Vec<Event>>,
+    dropped: u64,
+}
+
+impl Hints {
+    /// Hold `events` for `node`, dropping those beyond `max`; returns the
+    /// events held
+    fn store(&mut self, node: &str, events: Vec<Event>, max: usize) -> Vec<Event> {
+        let pending = self.pending.entry(node.to_string()).or_default();
+        let room = max.saturating_sub(pending.len()).min(events.len());
+        self.dropped += (events.len() - room) as u64;
+        let held: Vec<Event> = events.into_iter().take(room).collect();
+        pending.extend(held.iter().cloned());
+        held
+    }
+
+    fn take(&mut self, node: &str) -> Vec<Event> {
+        self.pending.remove(node).unwrap_or_default()
+    }
+}
+
+/// Hints held in memory and recorded on the local node, so they survive a
+/// restart
+#[derive(Clone)]
+struct HintStore {
+    db: Arc<TemporalDB>,
+    hints: Arc<Mutex<Hints>>,
+    max: usize,
+}
+
+impl HintStore {
+    /// Hold `events` for `node` and record them; those beyond the maximum
+    /// are counted in the local node's metrics instead
+    async fn hold(&self, node: &str, events: Vec<Event>) -> Result<()> {
+        let total = events.len();
+        let held = self.lock().store(node, events, self.max);
+        if held.len() < total {
+            self.db.record_hints_dropped((total - held.len()) as u64);
+        }
+        if held.is_empty() {
+            return Ok(());
+        }
+        let payload =
+            EventPayload::from_bincode(&held).map_err(|e| Error::Serialization(e.to_string()))?;
+        self.record(HINT_STORED, node, payload).await
+    }
+
+    /// Hold again writes taken for `node` and not delivered, which are
+    /// recorded already
+    fn requeue(&self, node: &str, events: Vec<Event>) {
+        self.lock().store(node, events, self.max);
+    }
+
+    fn take(&self, node: &str) -> Vec<Event> {
+        self.lock().take(node)
+    }
+
+    /// Record that `events` held for `node` were delivered
+    async fn delivered(&self, node: &str, events: &[Event]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let ids: Vec<EventId> = events.iter().map(|e| e.id()).collect();
+        let payload =
+            EventPayload::from_bincode(&ids).map_err(|e| Error::Serialization(e.to_string()))?;
+        self.record(HINT_DELIVERED, node, payload).await
+    }
+
+    /// Hold the writes recorded for `node` and not delivered, in place of
+    /// those in memory; returns how many
+    async fn load(&self, node: &str) -> Result<usize> {
+        let records = self.db.get_entity_events(&hint_entity(node)).await?;
+        let mut stored: Vec<Event> = Vec::new();
+        let mut delivered: HashSet<EventId> = HashSet::new();
+        for record in records {
+            let decoded = match record.event_type() {
+                HINT_STORED => record
+                    .payload()
+                    .to_bincode::<Vec<Event>>()
+                    .map(|events| stored.extend(events)),
+                HINT_DELIVERED => record
+                    .payload()
+                    .to_bincode::<Vec<EventId>>()
+                    .map(|ids| delivered.extend(ids)),
+                _ => Ok(()),
+            };
+            decoded.map_err(|e| Error::Serialization(e.to_string()))?;
+        }
+        let mut seen = HashSet::new();
+        stored.retain(|e| !delivered.contains(&e.id()) && seen.insert(e.id()));
+        let held = stored.len();
+        self.lock().pending.insert(node.to_string(), stored);
+        Ok(held)
+    }
+
+    /// Deliver the writes held for `node` over `client`, holding them
+    /// again if it fails them, or probe the node if it is marked down and
+    /// none are held; the node is up once it answers. Returns how many
+    /// were delivered.
+    async fn hand_off(
+        &self,
+        node: &str,
+        client: &Arc<dyn NodeClient>,
+        health: &Mutex<Health>,
+        limit: u32,
+    ) -> Result<usize> {
+        let lock_health = || health.lock().expect("ReplicatedDB poisoned health lock");
+        let hints = self.take(node);
+        if hints.is_empty() {
+            if lock_health().down.contains(node) {
+                client.ping().await?;
+                lock_health().succeeded(node);
+            }
+            return Ok(0);
+        }
+        let delivered = hints.len();
+        if let Err(e) = client.apply_remote_events(hints.clone()).await {
+            lock_health().failed(node, limit);
+            self.requeue(node, hints);
+            return Err(e);
+        }
+        lock_health().succeeded(node);
+        self.delivered(node, &hints).await?;
+        Ok(delivered)
+    }
+
+    async fn record(&self, event_type: &str, node: &str, payload: EventPayload) -> Result<()> {
+        let event = Event::new(
+            event_type.to_string(),
+            Timestamp::now(),
+            hint_entity(node),
+            payload,
+        );
+        self.db.commit_internal(event).await
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Hints> {
+        self.hints.lock().expect("ReplicatedDB poisoned hints lock")
+    }
+}
+
+/// Replicas marked down and how many writes each failed in a row
+#[derive(Debug, Default)]
+struct Health {
+    failures: HashMap<NodeId, u32>,
+    down: HashSet<NodeId>,
+}
+
+impl Health {
+    /// Count a failed write of `node`, marking it down at `limit` in a row
+    fn failed(&mut self, node: &str, limit: u32) {
+        let failures = self.failures.entry(node.to_string()).or_default();
+        *failures += 1;
+        if *failures >= limit {
+            self.down.insert(node.to_string());
+        }
+    }
+
+    /// Mark `node` up
+    fn succeeded(&mut self, node: &str) {
+        self.failures.remove(node);
+        self.down.remove(node);
+    }
+}
+
+/// Facade replicating every entity to several nodes
+#[derive(Clone)]
+pub struct ReplicatedDB {
+    ring: HashRing,
+    config: ReplicationConfig,
+    nodes: Arc<Mutex<HashMap<NodeId, Arc<dyn NodeClient>>>>,
+    hints: HintStore,
+    health: Arc<Mutex<Health>>,
+    failures_before_down: u32,
+}
+
+impl ReplicatedDB {
+    /// Coordinator on node `local`, whose own database is `db`, replicating
+    /// over the nodes of `ring` as `config` says; hints are recorded in
+    /// `db`. Fails if the quorums are invalid.
+    pub fn new(
+        local: impl Into<NodeId>,
+        db: Arc<TemporalDB>,
+        ring: HashRing,
+        config: ReplicationConfig,
+    ) -> Result<Self> {
+        config.validate()?;
+        let client: Arc<dyn NodeClient> = db.clone();
+        Ok(Self {
+            ring,
+            config,
+            nodes: Arc::new(Mutex::new(HashMap::from([(local.into(), client)]))),
+            hints: HintStore {
+                db,
+                hints: Arc::default(),
+                max: DEFAULT_MAX_HINTS,
+            },
+            health: Arc::default(),
+            failures_before_down: DEFAULT_FAILURES_BEFORE_DOWN,
+        })
+    }
+
+    /// Send requests for replicas on `node` to `client`
+    pub fn with_node(self, node: impl Into<NodeId>, client: Arc<dyn NodeClient>) -> Self {
+        self.lock_nodes().insert(node.into(), client);
+        self
+    }
+
+    /// Hold at most `max_hints` writes for a replica that is down
+    pub fn with_max_hints(mut self, max_hints: usize) -> Self {
+        self.hints.max = max_hints;
+        self
+    }
+
+    /// Mark a replica down once it failed `writes` writes in a row
+    pub fn with_failures_before_down(mut self, writes: u32) -> Self {
+        self.failures_before_down = writes.max(1);
+        self
+    }
+
+    /// Take `node` down: its writes go to a stand-in and are held as hints
+    /// until it reconnects; returns its connection
+    pub fn disconnect(&mut self, node: &str) -> Option<Arc<dyn NodeClient>> {
+        self.lock_nodes().remove(node)
+    }
+
+    /// Bring `node` back over `client` and hand off the writes it missed,
+    /// returning how many
+    pub async fn reconnect(
+        &mut self,
+        node: impl Into<NodeId>,
+        client: Arc<dyn NodeClient>,
+    ) -> Result<usize> {
+        let node = node.into();
+        self.lock_nodes().insert(node.clone(), client);
+        self.lock_health().succeeded(&node);
+        self.hand_off(&node).await
+    }
+
+    /// Hand off the writes held for every connected replica, returning
+    /// how many were delivered; a replica marked down that takes them is
+    /// up again
+    pub async fn replay_hints(&self) -> Result<usize> {
+        let nodes: Vec<NodeId> = self.lock_nodes().keys().cloned().collect();
+        let mut delivered = 0;
+        for node in nodes {
+            delivered += self.hand_off(&node).await?;
+        }
+        Ok(delivered)
+    }
+
+    /// Hand off the writes held for connected replicas every `interval`
+    /// in the background, and probe those marked down, so a replica that
+    /// recovers is up again without [`ReplicatedDB::reconnect`] or
+    /// [`ReplicatedDB::replay_hints`]. Runs until the coordinator and its
+    /// clones are dropped.
+    pub fn spawn_hint_handoff(&self, interval: Duration) {
+        let nodes = Arc::downgrade(&self.nodes);
+        let (hints, health) = (self.hints.clone(), self.health.clone());
+        let limit = self.failures_before_down;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(nodes) = nodes.upgrade() else {
+                    break;
+                };
+                let clients: Vec<(NodeId, Arc<dyn NodeClient>)> = nodes
+                    .lock()
+                    .expect("ReplicatedDB poisoned nodes lock")
+                    .iter()
+                    .map(|(node, client)| (node.clone(), client.clone()))
+                    .collect();
+                drop(nodes);
+                for (node, client) in clients {
+                    if let Err(e) = hints.hand_off(&node, &client, &health, limit).await {
+                        tracing::warn!("Handing hints off to node {} failed: {}", node, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Hold the hints recorded on the local node and not handed off yet,
+    /// e.g. after a restart, returning how many
+    pub async fn load_hints(&self) -> Result<usize> {
+        let mut held = 0;
+        for node in self.ring.nodes() {
+            held += self.hints.load(node).await?;
+        }
+        Ok(held)
+    }
+
+    /// Mark replicas down or up as gossip sees them: writes for members
+    /// that are not alive go to stand-ins, and members alive again get the
+    /// writes they missed. Returns how many were handed off.
+    pub async fn observe_liveness(&self, status: &ClusterStatus) -> Result<usize> {
+        let mut delivered = 0;
+        for member in &status.nodes {
+            let node = &member.metadata.node_id;
+            if !member.alive {
+                self.lock_health().down.insert(node.clone());
+            } else if self.lock_health().down.remove(node) {
+                delivered += self.hand_off(node).await?;
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Whether `node` is disconnected or marked down, so writes for it are
+    /// held as hints
+    pub fn is_down(&self, node: &str) -> bool {
+        !self.lock_nodes().contains_key(node) || self.lock_health().down.contains(node)
+    }
+
+    /// Writes held for `node`
+    pub fn pending_hints(&self, node: &str) -> usize {
+        self.hints.lock().pending.get(node).map_or(0, Vec::len)
+    }
+
+    /// Writes not held because their replica had the most hints already,
+    /// see [`ReplicatedDB::with_max_hints`]; read repair restores them.
+    /// The local node's [metrics](TemporalDB::metrics) count them too.
+    pub fn dropped_hints(&self) -> u64 {
+        self.hints.lock().dropped
+    }
+
+    /// Replication settings
+    pub fn config(&self) -> &ReplicationConfig {
+        &self.config
+    }
+
+    /// Nodes holding `entity_id`, its owner first
+    pub fn replicas(&self, entity_id: &str) -> Vec<&str> {
+        self.ring
+            .preference_list(entity_id, self.config.replication_factor)
+    }
+
+    /// Insert a value on every replica, see [`TemporalDB::insert`];
+    /// returns once the write quorum applied it. The value is checked
+    /// against the schemas of the local node.
+    pub async fn insert<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        let targets = self.targets(entity_id);
+        if targets.is_empty() {
+            return Err(self.unreachable(entity_id));
+        }
+        // The same event, ID included, goes to every replica, so replicas
+        // and readers recognize it.
+        let event = self.hints.db.value_event(entity_id, value, timestamp)?;
+        for node in self.replicas(entity_id) {
+            if self.is_down(node) {
+                self.hints.hold(node, vec![event.clone()]).await?;
+            }
+        }
+        let mut writes: FuturesUnordered<_> = targets
+            .into_iter()
+            .map(|(node, client)| {
+                // Writes the replica missed go first, in the same batch.
+                let hinted = self.hints.take(node);
+                let (node, event) = (node.to_string(), event.clone());
+                let (hints, health) = (self.hints.clone(), self.health.clone());
+                let limit = self.failures_before_down;
+                tokio::spawn(async move {
+                    let mut batch = hinted.clone();
+                    batch.push(event.clone());
+                    let result = client.apply_remote_events(batch).await;
+                    let lock_health = || health.lock().expect("ReplicatedDB poisoned health lock");
+                    if result.is_ok() {
+                        lock_health().succeeded(&node);
+                        // Delivered hints not recorded as such are only
+                        // delivered again, and skipped as duplicates.
+                        let _ = hints.delivered(&node, &hinted).await;
+                    } else {
+                        lock_health().failed(&node, limit);
+                        hints.requeue(&node, hinted);
+                        let _ = hints.hold(&node, vec![event]).await;
+                    }
+                    result
+                })
+            })
+            .collect();
+        let mut acknowledged = 0;
+        let mut errors = Vec::new();
+        while let Some(write) = writes.next().await {
+            match write {
+                Ok(Ok(_)) => acknowledged += 1,
+                Ok(Err(e)) => errors.push(e.to_string()),
+                Err(e) => errors.push(e.to_string()),
+            }
+            if acknowledged == self.config.write_quorum {
+                // The remaining replicas apply the write in the background.
+                return Ok(());
+            }
+        }
+        Err(Error::Distributed(format!(
+            "Write of {} reached {} of {} replicas needed: {}",
+            entity_id,
+            acknowledged,
+            self.config.write_quorum,
+            errors.join("; ")
+        )))
+    }
+
+    /// Events of an entity merged from the read quorum, in timestamp order
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        Ok(self.read_quorum(entity_id).await?.1)
+    }
+
+    /// Value at `timestamp` as the read quorum knows it, see
+    /// [`TemporalDB::query_as_of`]; fails if no replica of the quorum
+    /// could be repaired to hold every event read
+    pub async fn query_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        let (replica, _) = self.read_quorum(entity_id).await?;
+        let replica = replica.ok_or_else(|| {
+            Error::Distributed(format!(
+                "No replica of {} holds every event read",
+                entity_id
+            ))
+        })?;
+        replica.query_as_of(entity_id, timestamp).await
+    }
+
+    /// Read the events of `entity_id` from the read quorum, merge them by
+    /// event ID and repair the replicas that missed some; returns a
+    /// replica holding all of them, if any, and the merged events
+    async fn read_quorum(
+        &self,
+        entity_id: &str,
+    ) -> Result<(Option<Arc<dyn NodeClient>>, Vec<Event>)> {
+        let mut reads: FuturesUnordered<_> = self
+            .targets(entity_id)
+            .into_iter()
+            .map(|(node, client)| async move {
+                let read = client.get_entity_events(entity_id).await;
+                (node, client, read)
+            })
+            .collect();
+        let mut answers = Vec::new();
+        let mut errors = Vec::new();
+        while let Some((node, client, read)) = reads.next().await {
+            match read {
+                Ok(events) => answers.push((node, client, events)),
+                Err(e) => errors.push(e.to_string()),
+            }
+            if answers.len() == self.config.read_quorum {
+                break;
+            }
+        }
+        if answers.len() < self.config.read_quorum {
+            return Err(Error::Distributed(format!(
+                "Read of {} reached {} of {} replicas needed: {}",
+                entity_id,
+                answers.len(),
+                self.config.read_quorum,
+                errors.join("; ")
+            )));
+        }
+
+        let mut merged: Vec<Event> = Vec::new();
+        let mut seen: HashSet<EventId> = HashSet::new();
+        for (_, _, events) in &answers {
+            for event in events {
+                if seen.insert(event.id()) {
+                    merged.push(event.clone());
+                }
+            }
+        }
+        merged.sort_by_key(|event| event.timestamp());
+        let mut complete = None;
+        for (node, client, events) in answers {
+            let held: HashSet<EventId> = events.iter().map(|e| e.id()).collect();
+            let missing: Vec<Event> = merged
+                .iter()
+                .filter(|e| !held.contains(&e.id()))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                if let Err(e) = client.apply_remote_events(missing).await {
+                    tracing::warn!(
+                        "Read repair of {} on node {} failed: {}",
+                        entity_id,
+                        node,
+                        e
+                    );
+                    continue;
+                }
+            }
+            complete.get_or_insert(client);
+        }
+        Ok((complete, merged))
+    }
+
+    /// The first [`ReplicationConfig::replication_factor`] nodes of the
+    /// preference list of `entity_id` that are up: its replicas, with
+    /// stand-ins for those that are down
+    fn targets(&self, entity_id: &str) -> Vec<(&str, Arc<dyn NodeClient>)> {
+        let health = self.lock_health();
+        let nodes = self.lock_nodes();
+        self.ring
+            .preference_list(entity_id, usize::MAX)
+            .into_iter()
+            .filter(|node| !health.down.contains(*node))
+            .filter_map(|node| nodes.get(node).map(|client| (node, client.clone())))
+            .take(self.config.replication_factor)
+            .collect()
+    }
+
+    /// Deliver the writes held for `node` if it is connected
+    async fn hand_off(&self, node: &str) -> Result<usize> {
+        let Some(client) = self.lock_nodes().get(node).cloned() else {
+            return Ok(0);
+        };
+        self.hints
+            .hand_off(node, &client, &self.health, self.failures_before_down)
+            .await
+    }
+
+    fn lock_nodes(&self) -> MutexGuard<'_, HashMap<NodeId, Arc<dyn NodeClient>>> {
+        self.nodes.lock().expect("ReplicatedDB poisoned nodes lock")
+    }
+
+    fn lock_health(&self) -> MutexGuard<'_, Health> {
+        self.health
+            .lock()
+            .expect("ReplicatedDB poisoned health lock")
+    }
+
+    fn unreachable(&self, entity_id: &str) -> Error {
+        Error::Distributed(format!("No replica of {} is reachable", entity_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::transport::{ClusterSecret, NodeServer, TcpClient};
+    use std::path::Path;
+    use tokio::net::TcpListener;
+
+    /// Coordinator on `a` over nodes `a` to `c`, reaching `c` over TCP
+    async fn cluster(config: ReplicationConfig) -> (ReplicatedDB, Vec<Arc<TemporalDB>>) {
+        let mut ring = HashRing::new();
+        for node in ["a", "b", "c"] {
+            ring.add_node(node);
+        }
+        let dbs: Vec<Arc<TemporalDB>> = (0..3)
+            .map(|_| Arc::new(TemporalDB::in_memory().unwrap()))
+            .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        tokio::spawn(NodeServer::new(dbs[2].clone(), secret.clone()).serve(listener));
+        let replicated = ReplicatedDB::new("a", dbs[0].clone(), ring, config)
+            .unwrap()
+            .with_node("b", dbs[1].clone())
+            .with_node("c", Arc::new(TcpClient::new(addr, secret)));
+        (replicated, dbs)
+    }
+
+    /// Database in `dir` opened read-only, so it fails every write
+    async fn read_only_replica(dir: &Path) -> TemporalDB {
+        let db = TemporalDB::builder()
+            .with_data_dir(dir)
+            .build()
+            .await
+            .unwrap();
+        db.insert("user:0", "seed", Timestamp::from_secs(1))
+            .await
+            .unwrap();
+        db.flush().await.unwrap();
+        drop(db);
+        TemporalDB::open_read_only(dir).await.unwrap()
+    }
+
+    #[test]
+    fn test_validates_quorums() {
+        assert!(ReplicationConfig::new(3, 0, 2).is_err());
+        assert!(ReplicationConfig::new(3, 2, 4).is_err());
+        assert!(ReplicationConfig::default().is_strong());
+        assert!(!ReplicationConfig::new(3, 1, 1).unwrap().is_strong());
+    }
+
+    #[tokio::test]
+    async fn test_quorum_reads_reconcile_and_repair_replicas() {
+        let (replicated, dbs) = cluster(ReplicationConfig::new(3, 3, 3).unwrap()).await;
+        let ts = Timestamp::from_secs(100);
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        for db in &dbs {
+            assert_eq!(db.get_entity_events("user:1").await.unwrap().len(), 1);
+        }
+
+        // A later write only one replica applied wins and is repaired.
+        let later = Timestamp::from_secs(200);
+        dbs[2].insert("user:1", "suspended", later).await.unwrap();
+        let value: Option<String> = replicated.query_as_of("user:1", later).await.unwrap();
+        assert_eq!(value.as_deref(), Some("suspended"));
+        let events = replicated.get_entity_events("user:1").await.unwrap();
+        assert_eq!(events.len(), 2);
+        for db in &dbs {
+            assert_eq!(db.get_entity_events("user:1").await.unwrap().len(), 2);
+        }
+    }
+
+    /// Coordinator on `a`, whose database is `local`, over in-memory nodes
+    /// `b` to `d`
+    fn four_nodes(local: Arc<TemporalDB>, config: ReplicationConfig) -> ReplicatedDB {
+        let nodes = ["a", "b", "c", "d"];
+        let mut ring = HashRing::new();
+        for node in nodes {
+            ring.add_node(node);
+        }
+        let mut replicated = ReplicatedDB::new("a", local, ring, config).unwrap();
+        for node in &nodes[1..] {
+            replicated = replicated.with_node(*node, Arc::new(TemporalDB::in_memory().unwrap()));
+        }
+        replicated
+    }
+
+    /// A replica of `entity_id` other than `a`
+    fn remote_replica(replicated: &ReplicatedDB, entity_id: &str) -> String {
+        let replicas = replicated.replicas(entity_id);
+        replicas
+            .into_iter()
+            .find(|n| *n != "a")
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_hints_are_handed_off_when_a_replica_returns() {
+        let local = Arc::new(TemporalDB::in_memory().unwrap());
+        let config = ReplicationConfig::new(3, 3, 1).unwrap();
+        let mut replicated = four_nodes(local, config);
+        let replica = remote_replica(&replicated, "user:1");
+        let down = replicated.disconnect(&replica).unwrap();
+
+        // The write still reaches three nodes, one of them a stand-in.
+        let ts = Timestamp::from_secs(100);
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        assert_eq!(replicated.pending_hints(&replica), 1);
+        assert!(down.get_entity_events("user:1").await.unwrap().is_empty());
+        let value: Option<String> = replicated.query_as_of("user:1", ts).await.unwrap();
+        assert_eq!(value.as_deref(), Some("active"));
+
+        let handed_off = replicated.reconnect(replica.clone(), down.clone());
+        assert_eq!(handed_off.await.unwrap(), 1);
+        assert_eq!(replicated.pending_hints(&replica), 0);
+        assert_eq!(down.get_entity_events("user:1").await.unwrap().len(), 1);
+        assert_eq!(replicated.replay_hints().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_fails_below_quorum() {
+        let mut ring = HashRing::new();
+        for node in ["a", "b", "c"] {
+            ring.add_node(node);
+        }
+        let db = Arc::new(TemporalDB::in_memory().unwrap());
+        let ts = Timestamp::from_secs(100);
+        let majority =
+            ReplicatedDB::new("a", db.clone(), ring.clone(), ReplicationConfig::default()).unwrap();
+        assert!(majority.insert("user:1", "x", ts).await.is_err());
+        assert!(majority.get_entity_events("user:1").await.is_err());
+
+        let one = ReplicationConfig::new(3, 1, 1).unwrap();
+        let single = ReplicatedDB::new("a", db, ring, one).unwrap();
+        single.insert("user:1", "x", ts).await.unwrap();
+        let value: Option<String> = single.query_as_of("user:1", ts).await.unwrap();
+        assert_eq!(value.as_deref(), Some("x"));
+    }
+
+    #[tokio::test]
+    async fn test_hints_survive_a_restart_of_the_coordinator() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let builder = TemporalDB::builder().with_data_dir(temp_dir.path());
+        // Writes reach every replica before they return, so no write still
+        // holds the local database when it is reopened.
+        let config = ReplicationConfig::new(3, 3, 1).unwrap();
+        let ts = Timestamp::from_secs(100);
+
+        let local = Arc::new(builder.clone().build().await.unwrap());
+        let mut replicated = four_nodes(local.clone(), config).with_max_hints(1);
+        let replica = remote_replica(&replicated, "user:1");
+        let down = replicated.disconnect(&replica).unwrap();
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        // Beyond the maximum, writes are counted rather than held.
+        replicated.insert("user:1", "blocked", ts).await.unwrap();
+        assert_eq!(replicated.pending_hints(&replica), 1);
+        assert_eq!(local.metrics().hints_dropped, 1);
+        drop((replicated, local));
+
+        let local = Arc::new(builder.build().await.unwrap());
+        let mut replicated = four_nodes(local, config);
+        replicated.disconnect(&replica);
+        assert_eq!(replicated.load_hints().await.unwrap(), 1);
+        assert_eq!(
+            replicated
+                .reconnect(replica.clone(), down.clone())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(down.get_entity_events("user:1").await.unwrap().len(), 1);
+        // Delivered hints are not held again.
+        assert_eq!(replicated.load_hints().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failing_replica_is_marked_down() {
+        let local = Arc::new(TemporalDB::in_memory().unwrap());
+        let config = ReplicationConfig::new(3, 3, 1).unwrap();
+        let mut replicated = four_nodes(local, config).with_failures_before_down(2);
+        let replica = remote_replica(&replicated, "user:1");
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let failing = read_only_replica(temp_dir.path()).await;
+        let healthy = replicated.disconnect(&replica).unwrap();
+        replicated = replicated.with_node(replica.clone(), Arc::new(failing));
+
+        let ts = Timestamp::from_secs(100);
+        for _ in 0..2 {
+            assert!(replicated.insert("user:1", "active", ts).await.is_err());
+        }
+        assert!(replicated.is_down(&replica));
+        // A stand-in takes the writes while the replica is down.
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        assert_eq!(replicated.pending_hints(&replica), 3);
+
+        replicated.disconnect(&replica);
+        assert_eq!(
+            replicated
+                .reconnect(replica.clone(), healthy)
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(!replicated.is_down(&replica));
+    }
+
+    #[tokio::test]
+    async fn test_recovered_replica_gets_hints_in_the_background() {
+        let local = Arc::new(TemporalDB::in_memory().unwrap());
+        let config = ReplicationConfig::new(3, 2, 1).unwrap();
+        let mut replicated = four_nodes(local, config).with_failures_before_down(1);
+        let replica = remote_replica(&replicated, "user:1");
+        // Nothing listens on the replica's address until it recovers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        replicated.disconnect(&replica);
+        let client = TcpClient::new(addr.to_string(), secret.clone());
+        replicated = replicated.with_node(replica.clone(), Arc::new(client));
+
+        let ts = Timestamp::from_secs(100);
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        // The replica fails the write in the background.
+        while !replicated.is_down(&replica) || replicated.pending_hints(&replica) == 0 {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let recovered = Arc::new(TemporalDB::in_memory().unwrap());
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(NodeServer::new(recovered.clone(), secret).serve(listener));
+        replicated.spawn_hint_handoff(Duration::from_millis(20));
+        while replicated.is_down(&replica) || replicated.pending_hints(&replica) > 0 {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let events = recovered.get_entity_events("user:1").await.unwrap();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reads_answer_when_read_repair_fails() {
+        let local = Arc::new(TemporalDB::in_memory().unwrap());
+        let config = ReplicationConfig::new(3, 2, 3).unwrap();
+        let mut replicated = four_nodes(local, config);
+        let replica = remote_replica(&replicated, "user:1");
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let failing = read_only_replica(temp_dir.path()).await;
+        replicated.disconnect(&replica);
+        replicated = replicated.with_node(replica.clone(), Arc::new(failing));
+
+        // The read-only replica misses the write and cannot be repaired.
+        let ts = Timestamp::from_secs(100);
+        replicated.insert("user:1", "active", ts).await.unwrap();
+        let events = replicated.get_entity_events("user:1").await.unwrap();
+        assert_eq!(events.len(), 1);
+        let value: Option<String> = replicated.query_as_of("user:1", ts).await.unwrap();
+        assert_eq!(value.as_deref(), Some("active"));
+    }
+}
@@ -0,0 +1,368 @@
+//! Scatter-gather queries over a sharded cluster
+//!
+//! [`ShardedDB::scatter_find_events`] splits a time-range query by shard:
+//! every node is sent a [`ShardQuery`] for the shards it owns over its
+//! [`NodeClient`], concurrently, and the answers are merged in timestamp
+//! order, ties ordered by the local database's [`EventOrdering`], into
+//! pages of a given size. Nodes apply the cursor and page size to an
+//! early-stopping range read, so a node reads about a page of events per
+//! request, and at most a page per node crosses the network. Events a node
+//! holds for shards it no longer owns,
+//! e.g. copies left by a rebalance, are left out, so no event is returned
+//! twice.
+//!
+//! Shards whose node is unassigned, unreachable or fails are reported as
+//! [`ShardFailure`]s next to the events of the other shards, so callers
+//! decide whether a partial answer will do.
+//!
+//! [`NodeClient`]: crate::distributed::transport::NodeClient
+
+use crate::core::event::{Event, EventId, EventOrdering};
+use crate::core::filter::EventFilter;
+use crate::core::hlc::HlcTimestamp;
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::distributed::federation::Sourced;
+use crate::distributed::gossip::NodeId;
+use crate::distributed::sharding::{ShardId, ShardManager, ShardedDB};
+use crate::distributed::transport::MAX_PAGE;
+use crate::error::{Error, Result};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Position after the last event of a page of a scatter-gather query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScatterCursor {
+    /// Timestamp of the last event returned
+    pub timestamp: Timestamp,
+    /// Transaction time of the last event returned
+    pub transaction_time: Timestamp,
+    /// Clock stamp of the last event returned, if any
+    pub hlc: Option<HlcTimestamp>,
+    /// ID of the last event returned
+    pub event_id: EventId,
+}
+
+impl ScatterCursor {
+    fn of(event: &Event) -> Self {
+        Self {
+            timestamp: event.timestamp(),
+            transaction_time: event.metadata.transaction_time,
+            hlc: event.hlc(),
+            event_id: event.id(),
+        }
+    }
+
+    /// Sort key under `ordering`, see [`EventOrdering::compare`]. Nodes
+    /// share no append order, so ties it leaves, and all ties under
+    /// [`EventOrdering::AppendOrder`], go by event ID.
+    fn key(
+        &self,
+        ordering: EventOrdering,
+    ) -> (Timestamp, Option<HlcTimestamp>, Timestamp, EventId) {
+        match ordering {
+            EventOrdering::TransactionTime => {
+                (self.timestamp, None, self.transaction_time, self.event_id)
+            }
+            EventOrdering::AppendOrder => {
+                let none = Timestamp::from_nanos(i64::MIN);
+                (self.timestamp, None, none, self.event_id)
+            }
+            EventOrdering::HybridClock => (
+                self.timestamp,
+                self.hlc,
+                self.transaction_time,
+                self.event_id,
+            ),
+        }
+    }
+}
+
+/// Part of a scatter-gather query one node answers for the shards it owns
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardQuery {
+    /// Start of the time range, inclusive
+    pub start: Timestamp,
+    /// End of the time range, exclusive
+    pub end: Timestamp,
+    /// Metadata criteria of the events
+    pub filter: EventFilter,
+    /// Number of shards entity IDs are hashed into
+    pub shard_count: u32,
+    /// Shards to read
+    pub shards: Vec<ShardId>,
+    /// Only events after this position
+    pub after: Option<ScatterCursor>,
+    /// Most events to return
+    pub limit: usize,
+    /// Order of events sharing a timestamp
+    pub ordering: EventOrdering,
+}
+
+impl ShardQuery {
+    /// The first `limit` events of the shards after the cursor in `db`, in
+    /// timestamp order, ties ordered by [`ShardQuery::ordering`].
+    ///
+    /// Only about `limit` events are read, plus those sharing the last
+    /// one's timestamp, however far into the range the cursor is.
+    pub async fn run(&self, db: &TemporalDB) -> Result<Vec<Event>> {
+        // Events at the cursor's timestamp may follow it in tie order.
+        let from = self
+            .after
+            .map_or(self.start, |c| c.timestamp.max(self.start));
+        let sharding = ShardManager::with_shards(self.shard_count);
+        let shards: HashSet<ShardId> = self.shards.iter().copied().collect();
+        let keep = |e: &Event| {
+            shards.contains(&sharding.shard_of(e.entity_id()))
+                && self
+                    .after
+                    .is_none_or(|c| ScatterCursor::of(e).key(self.ordering) > c.key(self.ordering))
+        };
+        let mut events = db
+            .find_events_page(from, self.end, &self.filter, keep, self.limit)
+            .await?;
+        events.sort_by_key(|e| ScatterCursor::of(e).key(self.ordering));
+        events.truncate(self.limit);
+        Ok(events)
+    }
+}
+
+/// Shards a scatter-gather query could not read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardFailure {
+    /// Node owning the shards, if they are assigned
+    pub node: Option<NodeId>,
+    /// The shards, in order
+    pub shards: Vec<ShardId>,
+    /// Why they could not be read
+    pub error: String,
+}
+
+/// Page of a scatter-gather query
+#[derive(Debug, Clone)]
+pub struct ScatterPage {
+    /// Events in timestamp order, ties ordered by the local database's
+    /// [`EventOrdering`], each with the node it came from
+    pub events: Vec<Sourced<Event>>,
+    /// Cursor of the next page; `None` on the last page
+    pub next_cursor: Option<ScatterCursor>,
+    /// Shards left out of the page
+    pub failures: Vec<ShardFailure>,
+}
+
+impl ScatterPage {
+    /// Whether some shards were left out
+    pub fn is_partial(&self) -> bool {
+        !self.failures.is_empty()
+    }
+}
+
+impl ShardedDB {
+    /// Up to `limit` events in `[start, end)` matching `filter` across
+    /// every shard, after `cursor`, see [`TemporalDB::find_events`]; pages
+    /// hold at most [`MAX_PAGE`] events.
+    ///
+    /// Pass the previous page's [`ScatterPage::next_cursor`] as `cursor` to
+    /// continue. Fails only if no shard could be read.
+    ///
+    /// [`TemporalDB::find_events`]: crate::TemporalDB::find_events
+    pub async fn scatter_find_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+        cursor: Option<&ScatterCursor>,
+        limit: usize,
+    ) -> Result<ScatterPage> {
+        let limit = limit.min(MAX_PAGE);
+        let ordering = self.local_db().config().ordering;
+        let shards = self.shards();
+        let mut owned: BTreeMap<&str, Vec<ShardId>> = BTreeMap::new();
+        let mut failures = Vec::new();
+        let mut unassigned = Vec::new();
+        for shard in 0..shards.shards() {
+            match shards.owner_of_shard(shard) {
+                Some(node) => owned.entry(node).or_default().push(shard),
+                None => unassigned.push(shard),
+            }
+        }
+        if !unassigned.is_empty() {
+            failures.push(ShardFailure {
+                node: None,
+                shards: unassigned,
+                error: "Shards are not assigned".to_string(),
+            });
+        }
+
+        // One event past the page tells whether another page follows.
+        let answers = join_all(owned.iter().map(|(node, node_shards)| {
+            let query = ShardQuery {
+                start,
+                end,
+                filter: filter.clone(),
+                shard_count: shards.shards(),
+                shards: node_shards.clone(),
+                after: cursor.copied(),
+                limit: limit.saturating_add(1),
+                ordering,
+            };
+            async move {
+                match self.node(node) {
+                    Some(client) => client.scatter(query).await,
+                    None => Err(Error::Distributed(format!(
+                        "No connection to node {}",
+                        node
+                    ))),
+                }
+            }
+        }))
+        .await;
+
+        let mut merged = Vec::new();
+        for ((node, node_shards), answer) in owned.into_iter().zip(answers) {
+            match answer {
+                Ok(events) => merged.extend(events.into_iter().map(|event| Sourced {
+                    member: node.to_string(),
+                    item: event,
+                })),
+                Err(e) => failures.push(ShardFailure {
+                    node: Some(node.to_string()),
+                    shards: node_shards,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        if failures.iter().map(|f| f.shards.len()).sum::<usize>() == shards.shards() as usize {
+            let errors: Vec<String> = failures.iter().map(|f| f.error.clone()).collect();
+            return Err(Error::Distributed(format!(
+                "No shard could be read: {}",
+                errors.join("; ")
+            )));
+        }
+
+        merged.sort_by_key(|sourced| ScatterCursor::of(&sourced.item).key(ordering));
+        let next_cursor = if merged.len() > limit {
+            merged.truncate(limit);
+            merged
+                .last()
+                .map(|sourced| ScatterCursor::of(&sourced.item))
+        } else {
+            None
+        };
+        Ok(ScatterPage {
+            events: merged,
+            next_cursor,
+            failures,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::transport::{ClusterSecret, NodeServer, TcpClient};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_pages_merged_events_and_reports_unavailable_shards() {
+        let mut shards = ShardManager::with_shards(4);
+        shards.assign_evenly(&["a".to_string(), "b".to_string()]);
+        let a = Arc::new(TemporalDB::in_memory().unwrap());
+        let b = Arc::new(TemporalDB::in_memory().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        tokio::spawn(NodeServer::new(b.clone(), secret.clone()).serve(listener));
+        let sharded = ShardedDB::new("a", a.clone(), shards.clone())
+            .with_node("b", Arc::new(TcpClient::new(addr, secret.clone())));
+        for i in 0..10 {
+            let ts = Timestamp::from_secs(100 + i);
+            sharded.insert(&format!("user:{}", i), i, ts).await.unwrap();
+        }
+        // Stale copies on nodes not owning the shard are left out.
+        let ids: Vec<String> = (0..10).map(|i| format!("user:{}", i)).collect();
+        for (node, db) in [("a", &a), ("b", &b)] {
+            let foreign = ids.iter().find(|id| shards.owner(id) != Some(node));
+            db.insert(foreign.unwrap(), -1, Timestamp::from_secs(100))
+                .await
+                .unwrap();
+        }
+
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(1000));
+        let filter = EventFilter::new();
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = sharded
+                .scatter_find_events(start, end, &filter, cursor.as_ref(), 3)
+                .await
+                .unwrap();
+            assert!(!page.is_partial() && page.events.len() <= 3);
+            seen.extend(page.events.into_iter().map(|s| s.item.timestamp()));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        let expected: Vec<_> = (0..10).map(|i| Timestamp::from_secs(100 + i)).collect();
+        assert_eq!(seen, expected);
+
+        // With node b down, its shards are reported next to a's events.
+        let partial = ShardedDB::new("a", a.clone(), shards.clone())
+            .with_node("b", Arc::new(TcpClient::new("127.0.0.1:1", secret)));
+        let page = partial
+            .scatter_find_events(start, end, &filter, None, 100)
+            .await
+            .unwrap();
+        assert!(page.is_partial());
+        assert_eq!(page.failures[0].node.as_deref(), Some("b"));
+        assert_eq!(page.failures[0].shards, shards.shards_of("b"));
+        assert!(page
+            .events
+            .iter()
+            .all(|s| shards.owner(s.item.entity_id()) == Some("a")));
+
+        let unassigned = ShardedDB::new("a", a, ShardManager::with_shards(4));
+        let all_down = unassigned
+            .scatter_find_events(start, end, &filter, None, 100)
+            .await;
+        assert!(all_down.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pages_order_ties_like_the_database() {
+        let mut shards = ShardManager::with_shards(4);
+        shards.assign_evenly(&["a".to_string(), "b".to_string()]);
+        let a = Arc::new(TemporalDB::in_memory().unwrap());
+        let b = Arc::new(TemporalDB::in_memory().unwrap());
+        let sharded = ShardedDB::new("a", a, shards).with_node("b", b);
+        let ts = Timestamp::from_secs(100);
+        for i in 0..12 {
+            sharded.insert(&format!("user:{}", i), i, ts).await.unwrap();
+        }
+
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(1000));
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = sharded
+                .scatter_find_events(start, end, &EventFilter::new(), cursor.as_ref(), 5)
+                .await
+                .unwrap();
+            seen.extend(page.events.into_iter().map(|s| s.item));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        // Ties go by transaction time, as in the database, not by event ID.
+        assert_eq!(seen.len(), 12);
+        let order: Vec<_> = seen
+            .iter()
+            .map(|e| (e.metadata.transaction_time, e.id()))
+            .collect();
+        assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
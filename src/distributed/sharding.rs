@@ -1,13 +1,231 @@
-//! Sharding with consistent hashing
+//! Sharding by entity hash
+//!
+//! A [`ShardManager`] hashes every entity ID into one of a fixed number of
+//! shards and assigns each shard to a node. The hash depends only on the
+//! entity ID, so every node agrees on an entity's shard; moving a shard
+//! to another node only changes the assignment.
+//!
+//! Shards are assigned to nodes with a [`HashRing`]: every node takes many
+//! points on the ring, its virtual nodes, and a shard belongs to the node
+//! of the first point at or after the shard's hash. Adding or removing a
+//! node only moves the shards next to its points, about 1/N of them.
+//!
+//! A [`ShardedDB`] routes reads and writes of an entity to the node owning
+//! its shard through a [`NodeClient`], e.g. a [`TcpClient`] for a node in
+//! another process, so callers use it like a single database.
+//! When the ring changes, a [`Rebalancer`] copies the entities of every
+//! moved shard to its new owner, e.g. on a background task, after which
+//! the new assignment is routed by.
+//!
+//! [`TcpClient`]: crate::distributed::transport::TcpClient
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::db::TemporalDB;
+use crate::distributed::gossip::NodeId;
+use crate::distributed::transport::NodeClient;
+use crate::error::{Error, Result};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Index of a shard
+pub type ShardId = u32;
+
+/// Number of shards of a default [`ShardManager`]
+pub const DEFAULT_SHARDS: u32 = 64;
+
+/// Points every node takes on a default [`HashRing`]
+pub const DEFAULT_VNODES: u32 = 128;
+
+/// Entities listed per page while a [`Rebalancer`] copies a shard
+pub const DEFAULT_REBALANCE_PAGE: usize = 1_000;
+
+/// Consistent hashing ring of nodes with virtual nodes
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: u32,
+    /// Every point on the ring and its node. Points of two nodes may hash
+    /// alike; they are then ordered by node ID, so ownership does not
+    /// depend on the order nodes joined in.
+    points: BTreeSet<(u32, NodeId)>,
+}
+
+impl HashRing {
+    /// Ring without nodes, [`DEFAULT_VNODES`] points per node
+    pub fn new() -> Self {
+        Self::with_vnodes(DEFAULT_VNODES)
+    }
+
+    /// Ring without nodes, `vnodes` points per node
+    pub fn with_vnodes(vnodes: u32) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            points: BTreeSet::new(),
+        }
+    }
+
+    /// Add `node` to the ring
+    pub fn add_node(&mut self, node: impl Into<NodeId>) {
+        let node = node.into();
+        for vnode in 0..self.vnodes {
+            let point = crc32fast::hash(format!("{}#{}", node, vnode).as_bytes());
+            self.points.insert((point, node.clone()));
+        }
+    }
+
+    /// Remove `node` from the ring
+    pub fn remove_node(&mut self, node: &str) {
+        self.points.retain(|(_, owner)| owner != node);
+    }
+
+    /// Nodes on the ring, in order
+    pub fn nodes(&self) -> Vec<&str> {
+        let nodes: BTreeSet<&str> = self.points.iter().map(|(_, node)| node.as_str()).collect();
+        nodes.into_iter().collect()
+    }
+
+    /// Node owning `key`: the node of the first point at or after its
+    /// hash, wrapping around; `None` on an empty ring
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.preference_list(key, 1).into_iter().next()
+    }
+
+    /// First `n` distinct nodes from the point of `key` on, wrapping
+    /// around: the owner of `key`, then the nodes to replicate it to
+    pub fn preference_list(&self, key: &str, n: usize) -> Vec<&str> {
+        let hash = crc32fast::hash(key.as_bytes());
+        let mut nodes: Vec<&str> = Vec::new();
+        let start = (hash, NodeId::new());
+        let clockwise = self
+            .points
+            .range(start.clone()..)
+            .chain(self.points.range(..start));
+        for (_, node) in clockwise {
+            if nodes.len() == n {
+                break;
+            }
+            if !nodes.contains(&node.as_str()) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A shard changing owner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMove {
+    /// The shard
+    pub shard: ShardId,
+    /// Previous owner, if the shard was assigned
+    pub from: Option<NodeId>,
+    /// New owner
+    pub to: NodeId,
+}
 
 /// Shard manager
+#[derive(Debug, Clone)]
 pub struct ShardManager {
-    // TODO: Implement consistent hashing
+    shards: u32,
+    /// Owner of every assigned shard
+    owners: HashMap<ShardId, NodeId>,
 }
 
 impl ShardManager {
+    /// Manager of [`DEFAULT_SHARDS`] shards, none assigned
     pub fn new() -> Self {
-        Self {}
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Manager of `shards` shards, none assigned
+    pub fn with_shards(shards: u32) -> Self {
+        Self {
+            shards: shards.max(1),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Number of shards
+    pub fn shards(&self) -> u32 {
+        self.shards
+    }
+
+    /// Shard `entity_id` belongs to
+    pub fn shard_of(&self, entity_id: &str) -> ShardId {
+        crc32fast::hash(entity_id.as_bytes()) % self.shards
+    }
+
+    /// Assign `shard` to `node`, replacing its previous owner
+    pub fn assign(&mut self, shard: ShardId, node: impl Into<NodeId>) -> Result<()> {
+        if shard >= self.shards {
+            return Err(Error::Configuration(format!(
+                "Shard {} out of range, there are {}",
+                shard, self.shards
+            )));
+        }
+        self.owners.insert(shard, node.into());
+        Ok(())
+    }
+
+    /// Assign the shards to `nodes` in turn
+    pub fn assign_evenly(&mut self, nodes: &[NodeId]) {
+        if nodes.is_empty() {
+            return;
+        }
+        for shard in 0..self.shards {
+            let node = nodes[shard as usize % nodes.len()].clone();
+            self.owners.insert(shard, node);
+        }
+    }
+
+    /// Assign every shard to its owner on `ring`; returns the shards that
+    /// changed owner. Shards stay as they are on an empty ring.
+    pub fn assign_by_ring(&mut self, ring: &HashRing) -> Vec<ShardMove> {
+        let mut moves = Vec::new();
+        for shard in 0..self.shards {
+            let Some(to) = ring.owner(&format!("shard:{}", shard)) else {
+                break;
+            };
+            let from = self.owners.insert(shard, to.to_string());
+            if from.as_deref() != Some(to) {
+                moves.push(ShardMove {
+                    shard,
+                    from,
+                    to: to.to_string(),
+                });
+            }
+        }
+        moves
+    }
+
+    /// Node owning `shard`, if assigned
+    pub fn owner_of_shard(&self, shard: ShardId) -> Option<&str> {
+        self.owners.get(&shard).map(String::as_str)
+    }
+
+    /// Node owning the shard of `entity_id`, if assigned
+    pub fn owner(&self, entity_id: &str) -> Option<&str> {
+        self.owner_of_shard(self.shard_of(entity_id))
+    }
+
+    /// Shards owned by `node`, in order
+    pub fn shards_of(&self, node: &str) -> Vec<ShardId> {
+        let mut shards: Vec<ShardId> = self
+            .owners
+            .iter()
+            .filter(|(_, owner)| *owner == node)
+            .map(|(shard, _)| *shard)
+            .collect();
+        shards.sort_unstable();
+        shards
     }
 }
 
@@ -16,3 +234,411 @@ impl Default for ShardManager {
         Self::new()
     }
 }
+
+/// Facade routing every entity to the node owning it
+#[derive(Clone)]
+pub struct ShardedDB {
+    local: NodeId,
+    db: Arc<TemporalDB>,
+    shards: ShardManager,
+    nodes: HashMap<NodeId, Arc<dyn NodeClient>>,
+}
+
+impl ShardedDB {
+    /// Router of node `local`, whose own database is `db`
+    pub fn new(local: impl Into<NodeId>, db: Arc<TemporalDB>, shards: ShardManager) -> Self {
+        let local = local.into();
+        Self {
+            nodes: HashMap::from([(local.clone(), db.clone() as Arc<dyn NodeClient>)]),
+            local,
+            db,
+            shards,
+        }
+    }
+
+    /// Forward requests for the shards of `node` to `client`
+    pub fn with_node(mut self, node: impl Into<NodeId>, client: Arc<dyn NodeClient>) -> Self {
+        self.connect(node, client);
+        self
+    }
+
+    /// Forward requests for the shards of `node` to `client` from now on
+    pub fn connect(&mut self, node: impl Into<NodeId>, client: Arc<dyn NodeClient>) {
+        self.nodes.insert(node.into(), client);
+    }
+
+    /// ID of the local node
+    pub fn local_node(&self) -> &str {
+        &self.local
+    }
+
+    /// Database of the local node
+    pub fn local_db(&self) -> &Arc<TemporalDB> {
+        &self.db
+    }
+
+    /// Shard assignment routed by
+    pub fn shards(&self) -> &ShardManager {
+        &self.shards
+    }
+
+    /// Route by `shards` from now on, e.g. once a [`Rebalancer`] copied
+    /// the moved shards
+    pub fn set_shards(&mut self, shards: ShardManager) {
+        self.shards = shards;
+    }
+
+    /// Rebalancer moving the shards to their owners on `ring`; routing is
+    /// unchanged until the assignment it returns is set
+    pub fn plan_rebalance(&self, ring: &HashRing) -> Rebalancer {
+        let mut shards = self.shards.clone();
+        let moves = shards.assign_by_ring(ring);
+        Rebalancer {
+            shards,
+            moves,
+            nodes: self.nodes.clone(),
+            page: DEFAULT_REBALANCE_PAGE,
+            recorded_since: None,
+            progress: None,
+        }
+    }
+
+    /// Client requests for the shards of `node` go to, if connected
+    pub fn node(&self, node: &str) -> Option<&Arc<dyn NodeClient>> {
+        self.nodes.get(node)
+    }
+
+    /// Every connected node and its client, the local one included
+    pub(crate) fn connections(&self) -> impl Iterator<Item = (&str, &Arc<dyn NodeClient>)> {
+        self.nodes
+            .iter()
+            .map(|(node, client)| (node.as_str(), client))
+    }
+
+    /// Whether `entity_id` belongs to a shard of the local node
+    pub fn is_local(&self, entity_id: &str) -> bool {
+        self.shards.owner(entity_id) == Some(self.local.as_str())
+    }
+
+    /// Client of the node owning `entity_id`
+    pub fn route(&self, entity_id: &str) -> Result<&dyn NodeClient> {
+        self.owning_node(entity_id)
+            .map(|(_, client)| client.as_ref())
+    }
+
+    /// Node owning `entity_id` and its client
+    pub(crate) fn owning_node(&self, entity_id: &str) -> Result<(&str, &Arc<dyn NodeClient>)> {
+        let shard = self.shards.shard_of(entity_id);
+        let node = self
+            .shards
+            .owner_of_shard(shard)
+            .ok_or_else(|| Error::Distributed(format!("Shard {} is not assigned", shard)))?;
+        let client = self.nodes.get(node).ok_or_else(|| {
+            Error::Distributed(format!(
+                "No connection to node {} owning shard {}",
+                node, shard
+            ))
+        })?;
+        Ok((node, client))
+    }
+
+    /// Insert a value on the owning node, see [`TemporalDB::insert`]
+    pub async fn insert<V: serde::Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        self.route(entity_id)?
+            .insert(entity_id, value, timestamp)
+            .await
+    }
+
+    /// Value at `timestamp` on the owning node, see
+    /// [`TemporalDB::query_as_of`]
+    pub async fn query_as_of<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        self.route(entity_id)?
+            .query_as_of(entity_id, timestamp)
+            .await
+    }
+
+    /// Values in `[start, end)` on the owning node, see
+    /// [`TemporalDB::query_range`]
+    pub async fn query_range<V: for<'de> serde::Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<V>> {
+        self.route(entity_id)?
+            .query_range(entity_id, start, end)
+            .await
+    }
+
+    /// Events of an entity on the owning node
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        self.route(entity_id)?.get_entity_events(entity_id).await
+    }
+}
+
+/// Progress of a rebalance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RebalanceProgress {
+    /// Shards changing owner
+    pub shards: usize,
+    /// Entities copied to their new owner so far
+    pub entities: u64,
+    /// Events copied so far, without those the new owner already held
+    pub events: u64,
+    /// Time since the rebalance began
+    pub elapsed: Duration,
+    /// Whether every moved shard was copied
+    pub done: bool,
+}
+
+/// Copies the entities of shards changing owner to their new owner
+pub struct Rebalancer {
+    shards: ShardManager,
+    moves: Vec<ShardMove>,
+    nodes: HashMap<NodeId, Arc<dyn NodeClient>>,
+    page: usize,
+    recorded_since: Option<Timestamp>,
+    progress: Option<watch::Sender<RebalanceProgress>>,
+}
+
+impl Rebalancer {
+    /// Shards changing owner
+    pub fn moves(&self) -> &[ShardMove] {
+        &self.moves
+    }
+
+    /// List `entities` entities of an old owner per page
+    pub fn with_page(mut self, entities: usize) -> Self {
+        self.page = entities.max(1);
+        self
+    }
+
+    /// Only copy events the old owners recorded at or after `since`, by
+    /// transaction time, e.g. to catch up on writes they took while an
+    /// earlier run copied
+    pub fn with_recorded_since(mut self, since: Timestamp) -> Self {
+        self.recorded_since = Some(since);
+        self
+    }
+
+    /// Publish progress on `sender` after every page
+    pub fn with_progress(mut self, sender: watch::Sender<RebalanceProgress>) -> Self {
+        self.progress = Some(sender);
+        self
+    }
+
+    /// Copy the entities of every moved shard from its old owner to its
+    /// new one; returns the new assignment to route by and the final
+    /// progress. Old owners keep their copy, and events the new owner
+    /// already holds are skipped, so an interrupted rebalance can run
+    /// again.
+    pub async fn run(self) -> Result<(ShardManager, RebalanceProgress)> {
+        let started = Instant::now();
+        let mut progress = RebalanceProgress {
+            shards: self.moves.len(),
+            ..Default::default()
+        };
+        let mut sources: HashMap<&str, HashSet<ShardId>> = HashMap::new();
+        for shard_move in &self.moves {
+            if let Some(from) = &shard_move.from {
+                sources.entry(from).or_default().insert(shard_move.shard);
+            }
+        }
+        for (from, moved) in sources {
+            let source = self.node(from)?;
+            let mut cursor = None;
+            loop {
+                let page = source
+                    .list_entities("", cursor.as_deref(), self.page)
+                    .await?;
+                for entity_id in &page.entities {
+                    if !moved.contains(&self.shards.shard_of(entity_id)) {
+                        continue;
+                    }
+                    let to = self.shards.owner(entity_id).unwrap_or_default();
+                    let events = match self.recorded_since {
+                        Some(since) => source.entity_events_since(entity_id, since).await?,
+                        None => source.get_entity_events(entity_id).await?,
+                    };
+                    if events.is_empty() {
+                        continue;
+                    }
+                    let report = self.node(to)?.apply_remote_events(events).await?;
+                    progress.entities += 1;
+                    progress.events += (report.applied + report.resolved) as u64;
+                }
+                progress.elapsed = started.elapsed();
+                if let Some(sender) = &self.progress {
+                    sender.send_replace(progress);
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+        progress.done = true;
+        progress.elapsed = started.elapsed();
+        if let Some(sender) = &self.progress {
+            sender.send_replace(progress);
+        }
+        Ok((self.shards, progress))
+    }
+
+    fn node(&self, node: &str) -> Result<&dyn NodeClient> {
+        self.nodes
+            .get(node)
+            .map(Arc::as_ref)
+            .ok_or_else(|| Error::Distributed(format!("No connection to node {}", node)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_routes_entities_to_owning_node() {
+        let mut shards = ShardManager::with_shards(8);
+        shards.assign_evenly(&["a".to_string(), "b".to_string()]);
+        assert_eq!(shards.shards_of("a"), [0, 2, 4, 6]);
+        assert!(shards.assign(8, "a").is_err());
+
+        let (a, b) = (
+            Arc::new(TemporalDB::in_memory().unwrap()),
+            Arc::new(TemporalDB::in_memory().unwrap()),
+        );
+        let from_a = ShardedDB::new("a", a.clone(), shards.clone()).with_node("b", b.clone());
+        let from_b = ShardedDB::new("b", b.clone(), shards).with_node("a", a.clone());
+        let ts = Timestamp::from_secs(100);
+        let ids: Vec<String> = (0..20).map(|i| format!("user:{}", i)).collect();
+        for id in &ids {
+            from_a.insert(id, id.as_str(), ts).await.unwrap();
+        }
+
+        for id in &ids {
+            let value: Option<String> = from_b.query_as_of(id, ts).await.unwrap();
+            assert_eq!(value.as_deref(), Some(id.as_str()));
+            let (owner, other) = if from_a.is_local(id) {
+                (&a, &b)
+            } else {
+                (&b, &a)
+            };
+            assert_eq!(owner.get_entity_events(id).await.unwrap().len(), 1);
+            assert!(other.get_entity_events(id).await.unwrap().is_empty());
+        }
+
+        let partial = ShardedDB::new("a", a, ShardManager::with_shards(8));
+        assert!(partial.insert("user:1", "x", ts).await.is_err());
+    }
+
+    #[test]
+    fn test_colliding_points_do_not_depend_on_join_order() {
+        // The first points of these nodes hash alike.
+        let (x, y) = ("1fdc0ee9", "5fb19e8e");
+        let point = |node: &str| crc32fast::hash(format!("{}#0", node).as_bytes());
+        assert_eq!(point(x), point(y));
+
+        let mut xy = HashRing::with_vnodes(1);
+        xy.add_node(x);
+        xy.add_node(y);
+        let mut yx = HashRing::with_vnodes(1);
+        yx.add_node(y);
+        yx.add_node(x);
+        for key in ["user:1", "user:2", "order:7"] {
+            assert_eq!(xy.owner(key), Some(x));
+            assert_eq!(yx.preference_list(key, 2), [x, y]);
+        }
+        xy.remove_node(x);
+        assert_eq!(xy.nodes(), [y]);
+        assert_eq!(xy.owner("user:1"), Some(y));
+    }
+
+    #[test]
+    fn test_ring_moves_few_shards_when_a_node_joins() {
+        let mut ring = HashRing::new();
+        for node in ["a", "b", "c"] {
+            ring.add_node(node);
+        }
+        let mut shards = ShardManager::with_shards(256);
+        assert_eq!(shards.assign_by_ring(&ring).len(), 256);
+        assert!(shards.assign_by_ring(&ring).is_empty());
+
+        ring.add_node("d");
+        let moves = shards.assign_by_ring(&ring);
+        assert!(moves.iter().all(|m| m.to == "d"));
+        assert!(moves.len() > 256 / 8 && moves.len() < 256 / 2);
+        ring.remove_node("d");
+        assert_eq!(shards.assign_by_ring(&ring).len(), moves.len());
+        assert_eq!(ring.nodes(), ["a", "b", "c"]);
+        let replicas = ring.preference_list("user:1", 5);
+        assert_eq!(replicas.len(), 3);
+        assert_eq!(Some(replicas[0]), ring.owner("user:1"));
+        assert_eq!(HashRing::new().owner("x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_copies_moved_shards_to_new_owner() {
+        let mut ring = HashRing::new();
+        ring.add_node("a");
+        let mut shards = ShardManager::with_shards(16);
+        shards.assign_by_ring(&ring);
+        let (a, b) = (
+            Arc::new(TemporalDB::in_memory().unwrap()),
+            Arc::new(TemporalDB::in_memory().unwrap()),
+        );
+        let mut sharded = ShardedDB::new("a", a.clone(), shards).with_node("b", b.clone());
+        let ts = Timestamp::from_secs(100);
+        let ids: Vec<String> = (0..30).map(|i| format!("user:{}", i)).collect();
+        for id in &ids {
+            sharded.insert(id, id.as_str(), ts).await.unwrap();
+        }
+
+        ring.add_node("b");
+        let catch_up = sharded
+            .plan_rebalance(&ring)
+            .with_recorded_since(Timestamp::now());
+        let (sender, receiver) = watch::channel(RebalanceProgress::default());
+        let rebalancer = sharded
+            .plan_rebalance(&ring)
+            .with_page(4)
+            .with_progress(sender);
+        let moved = rebalancer.moves().len();
+        assert!(moved > 0 && moved < 16);
+        let (shards, progress) = tokio::spawn(rebalancer.run()).await.unwrap().unwrap();
+        assert!(progress.done && *receiver.borrow() == progress);
+        assert_eq!(progress.entities, progress.events);
+        assert_eq!(
+            progress.entities as usize,
+            ids.iter()
+                .filter(|id| shards.owner(id) == Some("b"))
+                .count()
+        );
+
+        // The old owner took a write during the copy.
+        let moved = ids.iter().find(|id| shards.owner(id) == Some("b")).unwrap();
+        a.insert(moved, "late", ts).await.unwrap();
+        let (_, caught_up) = catch_up.run().await.unwrap();
+        assert_eq!((caught_up.entities, caught_up.events), (1, 1));
+
+        sharded.set_shards(shards);
+        for id in &ids {
+            let value: Option<String> = sharded.query_as_of(id, ts).await.unwrap();
+            let expected = if id == moved { "late" } else { id.as_str() };
+            assert_eq!(value.as_deref(), Some(expected));
+        }
+        assert_eq!(
+            b.list_entities("", None, 100).await.unwrap().entities.len() as u64,
+            progress.entities
+        );
+    }
+}
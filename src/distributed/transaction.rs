@@ -0,0 +1,439 @@
+//! Atomic transactions across shards
+//!
+//! A [`ShardedTransaction`] writes entities of several shards, possibly on
+//! several nodes, all or nothing, by two-phase commit. The node of every
+//! shard written to takes part:
+//!
+//! 1. Prepare: each participant stages its part of the transaction with
+//!    [`TemporalDB::prepare_transaction`], which locks the entities
+//!    against other transactions and other writes. If any participant
+//!    refuses, e.g. because another transaction holds one of its
+//!    entities, every participant aborts and nothing is written.
+//! 2. Commit: once all participants have prepared, the transaction is
+//!    decided, and each participant appends its part as one batch with
+//!    [`TemporalDB::commit_transaction`]. A participant that fails here
+//!    keeps its part staged, so [`ShardedDB::commit_prepared`] can finish
+//!    the transaction later.
+//!
+//! A participant may be a [`RaftGroup`](crate::distributed::RaftGroup),
+//! which replicates each step to its members before answering.
+//!
+//! Readers are not isolated from a transaction being committed: between
+//! the commits of two participants, one node's part is visible and the
+//! other's is not yet.
+//!
+//! Every step is recorded on the internal entity of the transaction (see
+//! [`TxnId::entity`]) before it takes effect: each participant records the
+//! part it prepared and when it finished it, and the coordinator records
+//! its decision to commit before any participant commits. A reopened
+//! participant prepares again the parts it had not finished, and
+//! [`ShardedDB::recover_transactions`] finishes them as their coordinator
+//! decided. A transaction its coordinator never decided to commit is
+//! aborted.
+//!
+//! [`TemporalDB::prepare_transaction`]: crate::db::TemporalDB::prepare_transaction
+//! [`TemporalDB::commit_transaction`]: crate::db::TemporalDB::commit_transaction
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::distributed::gossip::NodeId;
+use crate::distributed::sharding::ShardedDB;
+use crate::distributed::transport::NodeClient;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Prefix of the internal entities recording the steps of transactions
+pub const TXN_ENTITY_PREFIX: &str = "$sys:txn:";
+
+/// Event type recorded when a participant prepares its part of a
+/// transaction, carrying a [`PreparedPart`]
+pub const TXN_PREPARED: &str = "txn.prepared";
+
+/// Event type recorded when the coordinator decides a transaction, carrying
+/// whether it commits
+pub const TXN_DECIDED: &str = "txn.decided";
+
+/// Event type recorded when a participant has committed or aborted its
+/// part, carrying whether it committed
+pub const TXN_FINISHED: &str = "txn.finished";
+
+/// Unique transaction identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TxnId {
+    /// UUID of the transaction
+    pub id: Uuid,
+}
+
+impl TxnId {
+    /// Generate a new transaction ID
+    pub fn new() -> Self {
+        Self { id: Uuid::new_v4() }
+    }
+
+    /// Internal entity ID under which the steps of the transaction are
+    /// recorded
+    pub fn entity(&self) -> String {
+        format!("{}{}", TXN_ENTITY_PREFIX, self.id)
+    }
+
+    /// Transaction whose steps are recorded under `entity_id`, if any
+    pub fn from_entity(entity_id: &str) -> Option<Self> {
+        let id = entity_id.strip_prefix(TXN_ENTITY_PREFIX)?;
+        Uuid::parse_str(id).ok().map(|id| Self { id })
+    }
+}
+
+impl Default for TxnId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TxnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+/// Part of a transaction a participant prepared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreparedPart {
+    /// Node whose [`ShardedDB`] coordinates the transaction
+    pub coordinator: NodeId,
+    /// Events the participant appends on commit
+    pub events: Vec<Event>,
+}
+
+/// Transactions a participant has prepared but not yet committed or
+/// aborted, and the entities they lock
+#[derive(Debug, Default)]
+pub(crate) struct PreparedTransactions {
+    staged: HashMap<TxnId, PreparedPart>,
+    locks: HashMap<String, TxnId>,
+}
+
+impl PreparedTransactions {
+    /// Replay a transaction record read from the journal, so a reopened
+    /// participant holds the parts it prepared and did not finish
+    pub(crate) fn observe(&mut self, event: &Event) {
+        let Some(txn) = TxnId::from_entity(event.entity_id()) else {
+            return;
+        };
+        match event.event_type() {
+            TXN_PREPARED => {
+                // Records are in lock order, so replaying them cannot
+                // conflict.
+                if let Ok(part) = event.payload().to_bincode::<PreparedPart>() {
+                    let _ = self.prepare(txn, part);
+                }
+            }
+            TXN_FINISHED => {
+                self.release(txn);
+            }
+            _ => {}
+        }
+    }
+
+    /// Stage `part` of `txn` and lock its entities, failing if another
+    /// transaction holds one of them; preparing `txn` again replaces its
+    /// part
+    pub(crate) fn prepare(&mut self, txn: TxnId, part: PreparedPart) -> Result<()> {
+        if let Some((entity_id, holder)) = part.events.iter().find_map(|e| {
+            self.locks
+                .get(e.entity_id())
+                .filter(|holder| **holder != txn)
+                .map(|holder| (e.entity_id(), holder))
+        }) {
+            return Err(Error::Distributed(format!(
+                "Entity {} is locked by transaction {}",
+                entity_id, holder
+            )));
+        }
+        self.release(txn);
+        for event in &part.events {
+            self.locks.insert(event.entity_id().to_string(), txn);
+        }
+        self.staged.insert(txn, part);
+        Ok(())
+    }
+
+    /// Entity of `events` locked by a transaction other than `txn`, and
+    /// that transaction
+    pub(crate) fn conflict<'e>(
+        &self,
+        events: &'e [Event],
+        txn: Option<TxnId>,
+    ) -> Option<(&'e str, TxnId)> {
+        events.iter().find_map(|e| {
+            let holder = *self.locks.get(e.entity_id())?;
+            (Some(holder) != txn).then_some((e.entity_id(), holder))
+        })
+    }
+
+    /// Part staged for `txn`, taken out while it is appended; its entities
+    /// stay locked until [`PreparedTransactions::release`]
+    pub(crate) fn take(&mut self, txn: TxnId) -> Option<PreparedPart> {
+        self.staged.remove(&txn)
+    }
+
+    /// Stage the part of `txn` again, after committing it failed
+    pub(crate) fn restore(&mut self, txn: TxnId, part: PreparedPart) {
+        self.staged.insert(txn, part);
+    }
+
+    /// Drop `txn` and unlock its entities, returning whether it was staged
+    pub(crate) fn release(&mut self, txn: TxnId) -> bool {
+        self.locks.retain(|_, holder| *holder != txn);
+        self.staged.remove(&txn).is_some()
+    }
+
+    /// IDs of the staged transactions
+    pub(crate) fn ids(&self) -> Vec<TxnId> {
+        let mut ids: Vec<TxnId> = self.staged.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Coordinator of staged transaction `txn`
+    pub(crate) fn coordinator(&self, txn: TxnId) -> Option<&str> {
+        self.staged.get(&txn).map(|part| part.coordinator.as_str())
+    }
+}
+
+/// Writes to entities of any shards, committed atomically, see the
+/// [module documentation](self)
+pub struct ShardedTransaction<'a> {
+    db: &'a ShardedDB,
+    id: TxnId,
+    events: Vec<Event>,
+}
+
+impl ShardedTransaction<'_> {
+    /// ID of the transaction
+    pub fn id(&self) -> TxnId {
+        self.id
+    }
+
+    /// Insert a value, see [`TemporalDB::insert`](crate::db::TemporalDB::insert);
+    /// it is checked against the schemas of the local node
+    pub fn insert<V: serde::Serialize>(
+        &mut self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        let event = self
+            .db
+            .local_db()
+            .value_event(entity_id, value, timestamp)?;
+        self.append(event);
+        Ok(())
+    }
+
+    /// Append a domain event, see
+    /// [`TemporalDB::append_event`](crate::db::TemporalDB::append_event)
+    pub fn append(&mut self, mut event: Event) {
+        // The transaction's events can be found together, see
+        // [`TemporalDB::get_saga`].
+        if event.metadata.correlation_id.is_none() {
+            event.metadata.correlation_id = Some(self.id.to_string());
+        }
+        self.events.push(event);
+    }
+
+    /// Commit all writes or none, returning the transaction's ID
+    pub async fn commit(self) -> Result<TxnId> {
+        let mut participants: BTreeMap<&str, (&Arc<dyn NodeClient>, Vec<Event>)> = BTreeMap::new();
+        for event in self.events {
+            let (node, db) = self.db.owning_node(event.entity_id())?;
+            participants
+                .entry(node)
+                .or_insert_with(|| (db, Vec::new()))
+                .1
+                .push(event);
+        }
+
+        let coordinator = self.db.local_node();
+        let mut prepared: Vec<&Arc<dyn NodeClient>> = Vec::new();
+        for (db, events) in participants.into_values() {
+            if let Err(e) = db.prepare_transaction(self.id, coordinator, events).await {
+                // Undecided, the transaction is aborted by recovery on any
+                // participant this fails to reach.
+                for db in prepared {
+                    let _ = db.abort_transaction(self.id).await;
+                }
+                return Err(e);
+            }
+            prepared.push(db);
+        }
+
+        self.db.local_db().decide_transaction(self.id, true).await?;
+        self.db.commit_prepared(self.id).await?;
+        Ok(self.id)
+    }
+}
+
+impl ShardedDB {
+    /// Start a transaction over entities of any shards
+    pub fn transaction(&self) -> ShardedTransaction<'_> {
+        ShardedTransaction {
+            db: self,
+            id: TxnId::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Commit the part of prepared transaction `txn` each node still holds,
+    /// returning how many nodes committed one, e.g. to finish a transaction
+    /// whose commit failed on some node
+    pub async fn commit_prepared(&self, txn: TxnId) -> Result<usize> {
+        let mut committed = 0;
+        let mut failures = Vec::new();
+        for (node, db) in self.connections() {
+            match db.commit_transaction(txn).await {
+                Ok(true) => committed += 1,
+                Ok(false) => {}
+                Err(e) => failures.push(format!("{}: {}", node, e)),
+            }
+        }
+        if !failures.is_empty() {
+            return Err(Error::Distributed(format!(
+                "Transaction {} is committed but still prepared on {}",
+                txn,
+                failures.join(", ")
+            )));
+        }
+        Ok(committed)
+    }
+
+    /// Finish the transactions prepared on any node as their coordinator
+    /// decided, returning how many parts were finished. Transactions this
+    /// node coordinates and never decided to commit are aborted, so call it
+    /// after opening the nodes, before starting new transactions;
+    /// undecided ones of other coordinators are left to them.
+    pub async fn recover_transactions(&self) -> Result<usize> {
+        let mut finished = 0;
+        for (_, db) in self.connections() {
+            for (txn, coordinator) in db.in_doubt_transactions().await? {
+                let Some(decider) = self.node(&coordinator) else {
+                    continue;
+                };
+                let done = match decider.transaction_decision(txn).await? {
+                    Some(true) => db.commit_transaction(txn).await?,
+                    Some(false) => db.abort_transaction(txn).await?,
+                    None if coordinator == self.local_node() => db.abort_transaction(txn).await?,
+                    None => false,
+                };
+                finished += usize::from(done);
+            }
+        }
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TemporalDB;
+    use crate::distributed::sharding::ShardManager;
+
+    fn cluster() -> (ShardedDB, Arc<TemporalDB>, Arc<TemporalDB>) {
+        let mut shards = ShardManager::with_shards(8);
+        shards.assign_evenly(&["a".to_string(), "b".to_string()]);
+        let (a, b) = (
+            Arc::new(TemporalDB::in_memory().unwrap()),
+            Arc::new(TemporalDB::in_memory().unwrap()),
+        );
+        let sharded = ShardedDB::new("a", a.clone(), shards).with_node("b", b.clone());
+        (sharded, a, b)
+    }
+
+    #[tokio::test]
+    async fn test_transaction_commits_on_every_node_or_none() {
+        let (sharded, a, b) = cluster();
+        let ids: Vec<String> = (0..20).map(|i| format!("account:{}", i)).collect();
+        let local = ids.iter().find(|id| sharded.is_local(id)).unwrap();
+        let remote = ids.iter().find(|id| !sharded.is_local(id)).unwrap();
+        let ts = Timestamp::from_secs(100);
+
+        // Another transaction holds the remote entity: nothing is written.
+        let holder = TxnId::new();
+        let blocking = b.value_event(remote, 0, ts).unwrap();
+        b.prepare_transaction(holder, "b", vec![blocking])
+            .await
+            .unwrap();
+        // Writes outside the transaction are locked out too.
+        assert!(b.insert(remote, 1, ts).await.is_err());
+        let mut txn = sharded.transaction();
+        txn.insert(local, 50, ts).unwrap();
+        txn.insert(remote, 150, ts).unwrap();
+        assert!(matches!(txn.commit().await, Err(Error::Distributed(_))));
+        assert!(a.get_entity_events(local).await.unwrap().is_empty());
+        assert!(a.prepared_transactions().is_empty());
+
+        assert!(b.abort_transaction(holder).await.unwrap());
+        let mut txn = sharded.transaction();
+        txn.insert(local, 50, ts).unwrap();
+        txn.insert(remote, 150, ts).unwrap();
+        let id = txn.commit().await.unwrap();
+        let value: Option<i64> = sharded.query_as_of(local, ts).await.unwrap();
+        assert_eq!(value, Some(50));
+        let value: Option<i64> = sharded.query_as_of(remote, ts).await.unwrap();
+        assert_eq!(value, Some(150));
+        assert_eq!(b.get_saga(&id.to_string()).await.unwrap().len(), 1);
+        assert!(b.prepared_transactions().is_empty());
+        assert_eq!(sharded.commit_prepared(id).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reopened_participants_finish_as_decided() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let open = |node: &str| {
+            TemporalDB::builder()
+                .with_data_dir(temp_dir.path().join(node))
+                .build()
+        };
+        let ts = Timestamp::from_secs(100);
+        let (decided, undecided) = (TxnId::new(), TxnId::new());
+        {
+            let (a, b) = (open("a").await.unwrap(), open("b").await.unwrap());
+            let event = a.value_event("account:1", 1, ts).unwrap();
+            a.prepare_transaction(decided, "a", vec![event])
+                .await
+                .unwrap();
+            let event = b.value_event("account:2", 2, ts).unwrap();
+            b.prepare_transaction(decided, "a", vec![event])
+                .await
+                .unwrap();
+            // The coordinator decides and commits its own part, then stops
+            // before b commits.
+            a.decide_transaction(decided, true).await.unwrap();
+            assert!(a.commit_transaction(decided).await.unwrap());
+            let event = b.value_event("account:3", 3, ts).unwrap();
+            b.prepare_transaction(undecided, "a", vec![event])
+                .await
+                .unwrap();
+        }
+
+        let (a, b) = (open("a").await.unwrap(), open("b").await.unwrap());
+        let (a, b) = (Arc::new(a), Arc::new(b));
+        assert!(a.prepared_transactions().is_empty());
+        assert_eq!(b.prepared_transactions().len(), 2);
+        // Recovered parts still lock their entities.
+        let blocked = b.value_event("account:2", 0, ts).unwrap();
+        let prepared = b.prepare_transaction(TxnId::new(), "b", vec![blocked]);
+        assert!(matches!(prepared.await, Err(Error::Distributed(_))));
+
+        let sharded =
+            ShardedDB::new("a", a.clone(), ShardManager::with_shards(8)).with_node("b", b.clone());
+        assert_eq!(sharded.recover_transactions().await.unwrap(), 2);
+        assert!(b.prepared_transactions().is_empty());
+        let value: Option<i64> = b.query_as_of("account:2", ts).await.unwrap();
+        assert_eq!(value, Some(2));
+        assert!(b.get_entity_events("account:3").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,809 @@
+//! Requests between cluster nodes
+//!
+//! Nodes read and write each other's data through a [`NodeClient`]. A
+//! [`TemporalDB`] answers requests itself, for a node in the same process;
+//! a [`TcpClient`] sends every [`NodeRequest`] to the [`NodeServer`] of
+//! another node as a length-delimited bincode frame and waits for its
+//! [`NodeResponse`]. Errors of the remote node come back as
+//! [`Error::Distributed`], failures to reach it as [`Error::Network`].
+//!
+//! Every connection starts with a handshake: the server and the client
+//! each answer a random challenge of the other with its HMAC under the
+//! [`ClusterSecret`], so the secret never crosses the network, and a server
+//! answers no request of a peer that does not hold it.
+//!
+//! A node answers at most [`MAX_PAGE`] entity IDs or scattered events per
+//! request, and a response that would not fit in a frame fails instead.
+//!
+//! A node server shares the node's [`GossipNode`], so a node joining the
+//! cluster learns its members from any of them by address, and hands Raft
+//! messages to the node's [`RaftGroup`].
+//!
+//! Values travel as JSON, so the typed helpers on `dyn NodeClient` work
+//! with any serde type, like their [`TemporalDB`] counterparts.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::db::{SyncReport, TemporalDB};
+use crate::distributed::gossip::{GossipMessage, GossipNode, NodeId};
+use crate::distributed::raft::Envelope;
+use crate::distributed::raft_group::RaftGroup;
+use crate::distributed::scatter::ShardQuery;
+use crate::distributed::transaction::TxnId;
+use crate::error::{Error, Result};
+use crate::storage::entity_catalog::EntityPage;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Largest request or response frame, in bytes
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// How long a default [`TcpClient`] waits for an answer
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most entity IDs or scattered events a node returns for one request;
+/// larger pages are cut to it
+pub const MAX_PAGE: usize = 10_000;
+
+/// Length of the random challenges of the handshake, in bytes
+const CHALLENGE_LENGTH: usize = 32;
+
+/// Secret shared by the members of a cluster; a [`NodeServer`] only
+/// answers clients proving they hold it, and a [`TcpClient`] only trusts
+/// servers that do
+#[derive(Clone)]
+pub struct ClusterSecret {
+    key: hmac::Key,
+}
+
+impl ClusterSecret {
+    /// Secret of at least 32 bytes, the same on every member
+    pub fn new(secret: &[u8]) -> Result<Self> {
+        if secret.len() < 32 {
+            return Err(Error::Configuration(
+                "Cluster secret must be at least 32 bytes".to_string(),
+            ));
+        }
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        })
+    }
+
+    /// Proof of holding the secret, by the side `role`, for the server's
+    /// `challenge` and the client's `nonce`
+    fn prove(&self, role: &[u8], challenge: &[u8], nonce: &[u8]) -> hmac::Tag {
+        hmac::sign(&self.key, &[role, challenge, nonce].concat())
+    }
+
+    fn verify(&self, role: &[u8], challenge: &[u8], nonce: &[u8], proof: &[u8]) -> bool {
+        hmac::verify(&self.key, &[role, challenge, nonce].concat(), proof).is_ok()
+    }
+}
+
+const CLIENT_ROLE: &[u8] = b"client";
+const SERVER_ROLE: &[u8] = b"server";
+
+fn random_challenge() -> Result<[u8; CHALLENGE_LENGTH]> {
+    let mut challenge = [0; CHALLENGE_LENGTH];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| Error::Other("No randomness for a handshake challenge".to_string()))?;
+    Ok(challenge)
+}
+
+/// Request to a node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NodeRequest {
+    /// Insert a JSON value, see [`TemporalDB::insert`]
+    Insert {
+        entity_id: String,
+        value: Vec<u8>,
+        timestamp: Timestamp,
+    },
+    /// JSON value at a time, see [`TemporalDB::query_as_of`]
+    QueryAsOf {
+        entity_id: String,
+        timestamp: Timestamp,
+    },
+    /// JSON values in a time range, see [`TemporalDB::query_range`]
+    QueryRange {
+        entity_id: String,
+        start: Timestamp,
+        end: Timestamp,
+    },
+    /// Events of an entity, see [`TemporalDB::get_entity_events`]; only
+    /// those recorded at or after `recorded_since`, if set, see
+    /// [`TemporalDB::get_entity_events_recorded_since`]
+    EntityEvents {
+        entity_id: String,
+        recorded_since: Option<Timestamp>,
+    },
+    /// Page of entity IDs, see [`TemporalDB::list_entities`]; at most
+    /// [`MAX_PAGE`]
+    ListEntities {
+        prefix: String,
+        cursor: Option<String>,
+        limit: usize,
+    },
+    /// Apply events written elsewhere, see
+    /// [`TemporalDB::apply_remote_events`]
+    ApplyEvents { events: Vec<Event> },
+    /// Apply events shipped from another region, see
+    /// [`TemporalDB::apply_replicated`]
+    ApplyReplicated {
+        source: String,
+        events: Vec<Event>,
+        through: Timestamp,
+    },
+    /// Events of some shards, see [`ShardQuery::run`]; at most
+    /// [`MAX_PAGE`] plus one, telling whether another page follows
+    Scatter(ShardQuery),
+    /// Stage a transaction's part, see [`TemporalDB::prepare_transaction`]
+    Prepare {
+        txn: TxnId,
+        coordinator: NodeId,
+        events: Vec<Event>,
+    },
+    /// Append a staged part, see [`TemporalDB::commit_transaction`]
+    Commit { txn: TxnId },
+    /// Drop a staged part, see [`TemporalDB::abort_transaction`]
+    Abort { txn: TxnId },
+    /// Recorded outcome of a transaction, see
+    /// [`TemporalDB::transaction_decision`]
+    Decision { txn: TxnId },
+    /// Staged parts and their coordinators
+    InDoubt,
+    /// Merge the sender's view of the cluster, see [`GossipNode::merge`]
+    Gossip(GossipMessage),
+    /// Check the node answers
+    Ping,
+    /// Message of another Raft member, see [`RaftGroup::step`]
+    Raft(Envelope),
+}
+
+/// Answer of a node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NodeResponse {
+    /// The request was carried out
+    Done,
+    /// A JSON value, if there is one
+    Value(Option<Vec<u8>>),
+    /// JSON values
+    Values(Vec<Vec<u8>>),
+    /// Events
+    Events(Vec<Event>),
+    /// Page of entity IDs
+    Entities(EntityPage),
+    /// Outcome of applying events
+    Applied(SyncReport),
+    /// Whether a staged part was committed or aborted
+    Finished(bool),
+    /// Recorded outcome of a transaction, if decided
+    Decision(Option<bool>),
+    /// Staged transactions and their coordinators
+    InDoubt(Vec<(TxnId, NodeId)>),
+    /// The node's view of the cluster
+    Gossip(GossipMessage),
+    /// The request failed on the node
+    Failed(String),
+}
+
+/// Connection to a cluster node
+#[async_trait]
+pub trait NodeClient: Send + Sync {
+    /// Send `request` to the node and wait for its answer
+    async fn call(&self, request: NodeRequest) -> Result<NodeResponse>;
+}
+
+fn unexpected(response: NodeResponse) -> Error {
+    Error::Distributed(format!("Unexpected response from node: {:?}", response))
+}
+
+impl<'a> dyn NodeClient + 'a {
+    /// Insert a value on the node, see [`TemporalDB::insert`]
+    pub async fn insert<V: Serialize>(
+        &self,
+        entity_id: &str,
+        value: V,
+        timestamp: Timestamp,
+    ) -> Result<()> {
+        let request = NodeRequest::Insert {
+            entity_id: entity_id.to_string(),
+            value: serde_json::to_vec(&value)?,
+            timestamp,
+        };
+        match self.call(request).await? {
+            NodeResponse::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Value at `timestamp` on the node, see [`TemporalDB::query_as_of`]
+    pub async fn query_as_of<V: for<'de> Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<V>> {
+        let request = NodeRequest::QueryAsOf {
+            entity_id: entity_id.to_string(),
+            timestamp,
+        };
+        match self.call(request).await? {
+            NodeResponse::Value(value) => Ok(value
+                .map(|json| serde_json::from_slice(&json))
+                .transpose()?),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Values in `[start, end)` on the node, see
+    /// [`TemporalDB::query_range`]
+    pub async fn query_range<V: for<'de> Deserialize<'de>>(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<V>> {
+        let request = NodeRequest::QueryRange {
+            entity_id: entity_id.to_string(),
+            start,
+            end,
+        };
+        match self.call(request).await? {
+            NodeResponse::Values(values) => values
+                .iter()
+                .map(|json| Ok(serde_json::from_slice(json)?))
+                .collect(),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Events of an entity on the node
+    pub async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        self.entity_events(entity_id, None).await
+    }
+
+    /// Events of an entity the node recorded at or after `recorded_since`,
+    /// by transaction time
+    pub async fn entity_events_since(
+        &self,
+        entity_id: &str,
+        recorded_since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.entity_events(entity_id, Some(recorded_since)).await
+    }
+
+    async fn entity_events(
+        &self,
+        entity_id: &str,
+        recorded_since: Option<Timestamp>,
+    ) -> Result<Vec<Event>> {
+        let request = NodeRequest::EntityEvents {
+            entity_id: entity_id.to_string(),
+            recorded_since,
+        };
+        match self.call(request).await? {
+            NodeResponse::Events(events) => Ok(events),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Page of the entities on the node, see [`TemporalDB::list_entities`]
+    pub async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        let request = NodeRequest::ListEntities {
+            prefix: prefix.to_string(),
+            cursor: cursor.map(str::to_string),
+            limit,
+        };
+        match self.call(request).await? {
+            NodeResponse::Entities(page) => Ok(page),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Apply events written elsewhere on the node, see
+    /// [`TemporalDB::apply_remote_events`]
+    pub async fn apply_remote_events(&self, events: Vec<Event>) -> Result<SyncReport> {
+        match self.call(NodeRequest::ApplyEvents { events }).await? {
+            NodeResponse::Applied(report) => Ok(report),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Apply events shipped from region `source` on the node, see
+    /// [`TemporalDB::apply_replicated`]
+    pub async fn apply_replicated(
+        &self,
+        source: &str,
+        events: Vec<Event>,
+        through: Timestamp,
+    ) -> Result<SyncReport> {
+        let request = NodeRequest::ApplyReplicated {
+            source: source.to_string(),
+            events,
+            through,
+        };
+        match self.call(request).await? {
+            NodeResponse::Applied(report) => Ok(report),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Events of the shards `query` names on the node, see
+    /// [`ShardQuery::run`]
+    pub async fn scatter(&self, query: ShardQuery) -> Result<Vec<Event>> {
+        match self.call(NodeRequest::Scatter(query)).await? {
+            NodeResponse::Events(events) => Ok(events),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Stage the node's part of `txn`, see
+    /// [`TemporalDB::prepare_transaction`]
+    pub async fn prepare_transaction(
+        &self,
+        txn: TxnId,
+        coordinator: &str,
+        events: Vec<Event>,
+    ) -> Result<()> {
+        let request = NodeRequest::Prepare {
+            txn,
+            coordinator: coordinator.to_string(),
+            events,
+        };
+        match self.call(request).await? {
+            NodeResponse::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Append the node's part of `txn`, see
+    /// [`TemporalDB::commit_transaction`]
+    pub async fn commit_transaction(&self, txn: TxnId) -> Result<bool> {
+        match self.call(NodeRequest::Commit { txn }).await? {
+            NodeResponse::Finished(committed) => Ok(committed),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Drop the node's part of `txn`, see
+    /// [`TemporalDB::abort_transaction`]
+    pub async fn abort_transaction(&self, txn: TxnId) -> Result<bool> {
+        match self.call(NodeRequest::Abort { txn }).await? {
+            NodeResponse::Finished(aborted) => Ok(aborted),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Outcome of `txn` the node recorded as its coordinator, see
+    /// [`TemporalDB::transaction_decision`]
+    pub async fn transaction_decision(&self, txn: TxnId) -> Result<Option<bool>> {
+        match self.call(NodeRequest::Decision { txn }).await? {
+            NodeResponse::Decision(decision) => Ok(decision),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Transactions staged on the node and their coordinators
+    pub async fn in_doubt_transactions(&self) -> Result<Vec<(TxnId, NodeId)>> {
+        match self.call(NodeRequest::InDoubt).await? {
+            NodeResponse::InDoubt(parts) => Ok(parts),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Send the node `message` and return its view of the cluster
+    pub async fn exchange_gossip(&self, message: GossipMessage) -> Result<GossipMessage> {
+        match self.call(NodeRequest::Gossip(message)).await? {
+            NodeResponse::Gossip(view) => Ok(view),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Check the node answers
+    pub async fn ping(&self) -> Result<()> {
+        match self.call(NodeRequest::Ping).await? {
+            NodeResponse::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Hand the node's Raft group a message, see [`RaftGroup::step`]
+    pub async fn raft_message(&self, envelope: Envelope) -> Result<()> {
+        match self.call(NodeRequest::Raft(envelope)).await? {
+            NodeResponse::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+#[async_trait]
+impl NodeClient for TemporalDB {
+    async fn call(&self, request: NodeRequest) -> Result<NodeResponse> {
+        let to_json = |value: serde_json::Value| Ok(serde_json::to_vec(&value)?);
+        Ok(match request {
+            NodeRequest::Insert {
+                entity_id,
+                value,
+                timestamp,
+            } => {
+                let value: serde_json::Value = serde_json::from_slice(&value)?;
+                self.insert(&entity_id, value, timestamp).await?;
+                NodeResponse::Done
+            }
+            NodeRequest::QueryAsOf {
+                entity_id,
+                timestamp,
+            } => {
+                let value = self.query_as_of(&entity_id, timestamp).await?;
+                NodeResponse::Value(value.map(to_json).transpose()?)
+            }
+            NodeRequest::QueryRange {
+                entity_id,
+                start,
+                end,
+            } => {
+                let values = self.query_range(&entity_id, start, end).await?;
+                let values = values.into_iter().map(to_json).collect::<Result<_>>()?;
+                NodeResponse::Values(values)
+            }
+            NodeRequest::EntityEvents {
+                entity_id,
+                recorded_since,
+            } => NodeResponse::Events(match recorded_since {
+                Some(since) => {
+                    self.get_entity_events_recorded_since(&entity_id, since)
+                        .await?
+                }
+                None => self.get_entity_events(&entity_id).await?,
+            }),
+            NodeRequest::ListEntities {
+                prefix,
+                cursor,
+                limit,
+            } => NodeResponse::Entities(
+                self.list_entities(&prefix, cursor.as_deref(), limit.min(MAX_PAGE))
+                    .await?,
+            ),
+            NodeRequest::ApplyEvents { events } => {
+                NodeResponse::Applied(self.apply_remote_events(events).await?)
+            }
+            NodeRequest::ApplyReplicated {
+                source,
+                events,
+                through,
+            } => NodeResponse::Applied(self.apply_replicated(&source, events, through).await?),
+            NodeRequest::Scatter(mut query) => {
+                query.limit = query.limit.min(MAX_PAGE + 1);
+                NodeResponse::Events(query.run(self).await?)
+            }
+            NodeRequest::Prepare {
+                txn,
+                coordinator,
+                events,
+            } => {
+                self.prepare_transaction(txn, &coordinator, events).await?;
+                NodeResponse::Done
+            }
+            NodeRequest::Commit { txn } => {
+                NodeResponse::Finished(self.commit_transaction(txn).await?)
+            }
+            NodeRequest::Abort { txn } => {
+                NodeResponse::Finished(self.abort_transaction(txn).await?)
+            }
+            NodeRequest::Decision { txn } => {
+                NodeResponse::Decision(self.transaction_decision(txn).await?)
+            }
+            NodeRequest::InDoubt => NodeResponse::InDoubt(self.in_doubt_transactions()),
+            NodeRequest::Ping => NodeResponse::Done,
+            NodeRequest::Gossip(_) => {
+                return Err(Error::Distributed(
+                    "The database is not a cluster member".to_string(),
+                ))
+            }
+            NodeRequest::Raft(_) => {
+                return Err(Error::Distributed(
+                    "The database is not a Raft member".to_string(),
+                ))
+            }
+        })
+    }
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .new_codec()
+}
+
+/// Serves the requests of other nodes from the local database
+pub struct NodeServer {
+    db: Arc<TemporalDB>,
+    secret: ClusterSecret,
+    gossip: Option<Arc<Mutex<GossipNode>>>,
+    raft: Option<Arc<RaftGroup>>,
+}
+
+impl NodeServer {
+    /// Server answering from `db` the clients holding `secret`
+    pub fn new(db: Arc<TemporalDB>, secret: ClusterSecret) -> Self {
+        Self {
+            db,
+            secret,
+            gossip: None,
+            raft: None,
+        }
+    }
+
+    /// Merge the gossip of other nodes into `gossip` and answer with its
+    /// view
+    pub fn with_gossip(mut self, gossip: Arc<Mutex<GossipNode>>) -> Self {
+        self.gossip = Some(gossip);
+        self
+    }
+
+    /// Hand the Raft messages of other members to `raft`
+    pub fn with_raft(mut self, raft: Arc<RaftGroup>) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    async fn answer(&self, request: NodeRequest) -> Result<NodeResponse> {
+        match (request, &self.gossip, &self.raft) {
+            (NodeRequest::Gossip(message), Some(gossip), _) => {
+                let mut gossip = gossip.lock().expect("NodeServer poisoned gossip lock");
+                gossip.merge(message);
+                Ok(NodeResponse::Gossip(gossip.digest()))
+            }
+            (NodeRequest::Raft(envelope), _, Some(raft)) => {
+                raft.step(envelope).await?;
+                Ok(NodeResponse::Done)
+            }
+            (request, _, _) => self.db.call(request).await,
+        }
+    }
+
+    /// Answer the requests of every connection `listener` accepts, each on
+    /// its own task; returns only if accepting fails
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve_connection(stream).await {
+                    tracing::warn!("Connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut framed = Framed::new(stream, codec());
+        tokio::time::timeout(DEFAULT_REQUEST_TIMEOUT, self.handshake(&mut framed))
+            .await
+            .map_err(|_| Error::Unauthorized("Handshake timed out".to_string()))??;
+        while let Some(frame) = framed.next().await {
+            let response = match bincode::deserialize::<NodeRequest>(&frame?) {
+                Ok(request) => match self.answer(request).await {
+                    Ok(response) => response,
+                    Err(e) => NodeResponse::Failed(e.to_string()),
+                },
+                Err(e) => NodeResponse::Failed(Error::from(e).to_string()),
+            };
+            let mut reply = bincode::serialize(&response)?;
+            if reply.len() > MAX_FRAME_LENGTH {
+                let message = format!(
+                    "Response of {} bytes exceeds the frame limit of {}",
+                    reply.len(),
+                    MAX_FRAME_LENGTH
+                );
+                reply = bincode::serialize(&NodeResponse::Failed(message))?;
+            }
+            framed.send(Bytes::from(reply)).await?;
+        }
+        Ok(())
+    }
+
+    /// Challenge the client to prove it holds the secret, then prove it
+    /// in turn; an empty answer tells the client it was refused
+    async fn handshake(&self, framed: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Result<()> {
+        let challenge = random_challenge()?;
+        framed.send(Bytes::copy_from_slice(&challenge)).await?;
+        let answer = framed.next().await.ok_or_else(|| {
+            Error::Unauthorized("Connection closed during the handshake".to_string())
+        })??;
+        let (nonce, proof) = answer.split_at(CHALLENGE_LENGTH.min(answer.len()));
+        if !self.secret.verify(CLIENT_ROLE, &challenge, nonce, proof) {
+            let _ = framed.send(Bytes::new()).await;
+            return Err(Error::Unauthorized(
+                "Client does not hold the cluster secret".to_string(),
+            ));
+        }
+        let proof = self.secret.prove(SERVER_ROLE, &challenge, nonce);
+        framed.send(Bytes::copy_from_slice(proof.as_ref())).await?;
+        Ok(())
+    }
+}
+
+/// Client sending requests to the [`NodeServer`] at an address over one
+/// TCP connection, opened on first use and again after a failure
+pub struct TcpClient {
+    addr: String,
+    secret: ClusterSecret,
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<Framed<TcpStream, LengthDelimitedCodec>>>,
+}
+
+impl TcpClient {
+    /// Client of the node listening on `addr`, e.g. `10.0.0.7:7400`,
+    /// holding the cluster's `secret`
+    pub fn new(addr: impl Into<String>, secret: ClusterSecret) -> Self {
+        Self {
+            addr: addr.into(),
+            secret,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Give up on a request after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Address of the node
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    async fn exchange(
+        &self,
+        connection: &mut Option<Framed<TcpStream, LengthDelimitedCodec>>,
+        frame: Bytes,
+    ) -> Result<NodeResponse> {
+        let framed = match connection {
+            Some(framed) => framed,
+            None => {
+                let stream = TcpStream::connect(&self.addr).await?;
+                stream.set_nodelay(true)?;
+                let mut framed = Framed::new(stream, codec());
+                self.handshake(&mut framed).await?;
+                connection.insert(framed)
+            }
+        };
+        framed.send(frame).await?;
+        let reply = self.receive(framed).await?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    /// Answer the server's challenge and check its proof of the secret
+    async fn handshake(&self, framed: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Result<()> {
+        let challenge = self.receive(framed).await?;
+        let nonce = random_challenge()?;
+        let proof = self.secret.prove(CLIENT_ROLE, &challenge, &nonce);
+        let answer = [nonce.as_slice(), proof.as_ref()].concat();
+        framed.send(Bytes::from(answer)).await?;
+        let proof = self.receive(framed).await?;
+        if proof.is_empty() {
+            return Err(Error::Unauthorized(format!(
+                "Node {} refused the cluster secret",
+                self.addr
+            )));
+        }
+        if !self.secret.verify(SERVER_ROLE, &challenge, &nonce, &proof) {
+            return Err(Error::Unauthorized(format!(
+                "Node {} does not hold the cluster secret",
+                self.addr
+            )));
+        }
+        Ok(())
+    }
+
+    async fn receive(
+        &self,
+        framed: &mut Framed<TcpStream, LengthDelimitedCodec>,
+    ) -> Result<BytesMut> {
+        framed
+            .next()
+            .await
+            .ok_or_else(|| Error::Network(format!("Node {} closed the connection", self.addr)))?
+            .map_err(Error::from)
+    }
+}
+
+#[async_trait]
+impl NodeClient for TcpClient {
+    async fn call(&self, request: NodeRequest) -> Result<NodeResponse> {
+        let frame = Bytes::from(bincode::serialize(&request)?);
+        let mut connection = self.connection.lock().await;
+        let answer = tokio::time::timeout(self.timeout, self.exchange(&mut connection, frame))
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::Network(format!(
+                    "Node {} did not answer within {:?}",
+                    self.addr, self.timeout
+                )))
+            })
+            .map_err(|e| match e {
+                Error::Io(e) => Error::Network(format!("Node {}: {}", self.addr, e)),
+                e => e,
+            });
+        if answer.is_err() {
+            // The stream may hold half a frame; start over on a new one.
+            *connection = None;
+        }
+        match answer? {
+            NodeResponse::Failed(message) => Err(Error::Distributed(format!(
+                "Node {} failed: {}",
+                self.addr, message
+            ))),
+            response => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distributed::sharding::{ShardManager, ShardedDB};
+
+    #[tokio::test]
+    async fn test_sharded_requests_reach_a_node_over_tcp() {
+        let b = Arc::new(TemporalDB::in_memory().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let secret = ClusterSecret::new(&[7; 32]).unwrap();
+        tokio::spawn(NodeServer::new(b.clone(), secret.clone()).serve(listener));
+
+        let mut shards = ShardManager::with_shards(8);
+        shards.assign_evenly(&["a".to_string(), "b".to_string()]);
+        let a = Arc::new(TemporalDB::in_memory().unwrap());
+        let client: Arc<dyn NodeClient> = Arc::new(TcpClient::new(&addr, secret.clone()));
+        let sharded = ShardedDB::new("a", a.clone(), shards).with_node("b", client.clone());
+        let ts = Timestamp::from_secs(100);
+        let ids: Vec<String> = (0..20).map(|i| format!("user:{}", i)).collect();
+        for (i, id) in ids.iter().enumerate() {
+            sharded.insert(id, i, ts).await.unwrap();
+        }
+        for (i, id) in ids.iter().enumerate() {
+            let value: Option<usize> = sharded.query_as_of(id, ts).await.unwrap();
+            assert_eq!(value, Some(i));
+            let owner = if sharded.is_local(id) { &a } else { &b };
+            assert_eq!(owner.get_entity_events(id).await.unwrap().len(), 1);
+        }
+
+        // Errors of the remote node and unreachable nodes are told apart.
+        let failed = client.insert("$sys:user:1", 1, ts).await;
+        assert!(matches!(failed, Err(Error::Distributed(_))));
+        let down: Arc<dyn NodeClient> = Arc::new(TcpClient::new("127.0.0.1:1", secret));
+        assert!(matches!(
+            down.get_entity_events("user:1").await,
+            Err(Error::Network(_))
+        ));
+
+        // Clients without the cluster secret get no answer.
+        let stranger = ClusterSecret::new(&[8; 32]).unwrap();
+        let stranger: Arc<dyn NodeClient> = Arc::new(TcpClient::new(&addr, stranger));
+        assert!(matches!(
+            stranger.get_entity_events("user:1").await,
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(ClusterSecret::new(b"short").is_err());
+    }
+}
@@ -1,5 +1,7 @@
 //! Error types for Temporal-DB
 
+use crate::core::temporal::Timestamp;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Result type alias for Temporal-DB operations
@@ -44,6 +46,29 @@ pub enum Error {
     #[error("Configuration error: {0}")]
     Configuration(String),
 
+    /// Request shed because the server is saturated
+    #[error("Server overloaded, retry after {retry_after:?}")]
+    Overloaded {
+        /// Suggested delay before retrying
+        retry_after: Duration,
+    },
+
+    /// A replica was asked for a time it has not applied every write up to
+    #[error(
+        "Replica is stale: {requested} is after its replication watermark {}",
+        .watermark.map_or_else(|| "none".to_string(), |w| w.to_string())
+    )]
+    Stale {
+        /// Time the read was pinned to
+        requested: Timestamp,
+        /// Time up to which the replica applied every write, if reported
+        watermark: Option<Timestamp>,
+    },
+
+    /// Missing, invalid or expired credentials
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
     /// IO errors
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -82,3 +107,10 @@ impl From<tonic::Status> for Error {
         Error::Network(e.to_string())
     }
 }
+
+#[cfg(feature = "fulltext")]
+impl From<tantivy::TantivyError> for Error {
+    fn from(e: tantivy::TantivyError) -> Self {
+        Error::Query(e.to_string())
+    }
+}
@@ -0,0 +1,359 @@
+//! Point-in-time snapshot export.
+//!
+//! A snapshot archive is a directory meant for long-term archival that does
+//! not depend on the segment format. It holds:
+//!
+//! - `state.ndjson`: one [`SnapshotEntity`] per line, the value of every
+//!   user entity as of the snapshot time (internal entities such as leases
+//!   are left out);
+//! - `history.ndjson` (optional): one [`SnapshotEvent`] per line, every
+//!   event valid at or before the snapshot time;
+//! - `manifest.json`: a [`SnapshotManifest`] naming the format, its
+//!   version, and the record count and SHA-256 of each data file.
+//!
+//! Times are nanoseconds since the Unix epoch. JSON payloads are embedded
+//! as JSON; other payloads are hex-encoded strings. The manifest is written
+//! last, so an archive without one is incomplete.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Format identifier stored in every snapshot manifest
+pub const SNAPSHOT_FORMAT: &str = "temporal-db-snapshot";
+
+/// Current snapshot format version
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// File name of the snapshot manifest
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.json";
+
+/// File name of the point-in-time state
+pub const SNAPSHOT_STATE_FILE: &str = "state.ndjson";
+
+/// File name of the event history
+pub const SNAPSHOT_HISTORY_FILE: &str = "history.ndjson";
+
+/// What to include in a snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    /// Also export every event up to the snapshot time
+    pub include_history: bool,
+}
+
+/// Value of one entity as of the snapshot time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEntity {
+    /// Entity ID
+    pub entity_id: String,
+    /// Valid time of the event that set the value
+    pub valid_from: i64,
+    /// ID of that event
+    pub event_id: String,
+    /// Type of that event
+    pub event_type: String,
+    /// Payload format of the value
+    pub format: String,
+    /// The value
+    pub value: Value,
+}
+
+/// One exported event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotEvent {
+    /// Event ID
+    pub event_id: String,
+    /// Entity ID
+    pub entity_id: String,
+    /// Event type
+    pub event_type: String,
+    /// Valid time
+    pub valid_time: i64,
+    /// Transaction (recording) time
+    pub transaction_time: i64,
+    /// Correlation ID
+    pub correlation_id: Option<String>,
+    /// ID of the causing event
+    pub causation_id: Option<String>,
+    /// Actor that caused the event
+    pub actor: Option<String>,
+    /// Tags
+    pub tags: Vec<String>,
+    /// Payload format
+    pub format: String,
+    /// Payload
+    pub payload: Value,
+}
+
+impl From<&Event> for SnapshotEvent {
+    fn from(event: &Event) -> Self {
+        let metadata = &event.metadata;
+        Self {
+            event_id: metadata.id.to_string(),
+            entity_id: metadata.entity_id.clone(),
+            event_type: metadata.event_type.clone(),
+            valid_time: metadata.timestamp.as_nanos(),
+            transaction_time: metadata.transaction_time.as_nanos(),
+            correlation_id: metadata.correlation_id.clone(),
+            causation_id: metadata.causation_id.map(|id| id.to_string()),
+            actor: metadata.actor.clone(),
+            tags: metadata.tags.clone(),
+            format: event.payload.format.clone(),
+            payload: payload_value(event),
+        }
+    }
+}
+
+impl SnapshotEntity {
+    /// State record for the latest event of an entity
+    pub fn from_event(event: &Event) -> Self {
+        Self {
+            entity_id: event.entity_id().to_string(),
+            valid_from: event.timestamp().as_nanos(),
+            event_id: event.id().to_string(),
+            event_type: event.event_type().to_string(),
+            format: event.payload.format.clone(),
+            value: payload_value(event),
+        }
+    }
+}
+
+/// Data file listed in a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// File name inside the archive
+    pub name: String,
+    /// Number of NDJSON records
+    pub records: u64,
+    /// Hex SHA-256 of the file contents
+    pub sha256: String,
+}
+
+/// Self-describing header of a snapshot archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    /// Always [`SNAPSHOT_FORMAT`]
+    pub format: String,
+    /// Format version the archive was written with
+    pub version: u32,
+    /// Snapshot time
+    pub as_of: i64,
+    /// Snapshot time, human readable
+    pub as_of_rfc3339: String,
+    /// When the archive was written
+    pub created_at: i64,
+    /// Point-in-time state file
+    pub state: SnapshotFile,
+    /// Event history file, if exported
+    pub history: Option<SnapshotFile>,
+}
+
+/// Writes a snapshot archive
+pub struct SnapshotWriter {
+    dir: PathBuf,
+    as_of: Timestamp,
+    state: NdjsonWriter,
+    history: Option<NdjsonWriter>,
+}
+
+impl SnapshotWriter {
+    /// Start an archive in `dir`, which must not already hold one
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        as_of: Timestamp,
+        options: SnapshotOptions,
+    ) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        if dir.join(SNAPSHOT_MANIFEST_FILE).exists() {
+            return Err(Error::Storage(format!(
+                "Snapshot already exists in {}",
+                dir.display()
+            )));
+        }
+        let history = if options.include_history {
+            Some(NdjsonWriter::create(&dir, SNAPSHOT_HISTORY_FILE)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            state: NdjsonWriter::create(&dir, SNAPSHOT_STATE_FILE)?,
+            history,
+            dir,
+            as_of,
+        })
+    }
+
+    /// Whether history records are being exported
+    pub fn includes_history(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Add an entity's state
+    pub fn write_entity(&mut self, entity: &SnapshotEntity) -> Result<()> {
+        self.state.write(entity)
+    }
+
+    /// Add a history event; ignored unless history is exported
+    pub fn write_event(&mut self, event: &SnapshotEvent) -> Result<()> {
+        match self.history.as_mut() {
+            Some(history) => history.write(event),
+            None => Ok(()),
+        }
+    }
+
+    /// Finish the data files and write the manifest
+    pub fn finish(self) -> Result<SnapshotManifest> {
+        let manifest = SnapshotManifest {
+            format: SNAPSHOT_FORMAT.to_string(),
+            version: SNAPSHOT_VERSION,
+            as_of: self.as_of.as_nanos(),
+            as_of_rfc3339: self.as_of.to_string(),
+            created_at: Timestamp::now().as_nanos(),
+            state: self.state.finish()?,
+            history: self.history.map(NdjsonWriter::finish).transpose()?,
+        };
+
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_MANIFEST_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_MANIFEST_FILE))?;
+        Ok(manifest)
+    }
+}
+
+/// A snapshot archive opened for reading
+#[derive(Debug)]
+pub struct SnapshotArchive {
+    dir: PathBuf,
+    manifest: SnapshotManifest,
+}
+
+impl SnapshotArchive {
+    /// Open the archive in `dir` and check that this version can read it
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let data = fs::read(dir.join(SNAPSHOT_MANIFEST_FILE))?;
+        let manifest: SnapshotManifest = serde_json::from_slice(&data)?;
+        if manifest.format != SNAPSHOT_FORMAT {
+            return Err(Error::Storage(format!(
+                "Not a snapshot archive: format {}",
+                manifest.format
+            )));
+        }
+        if manifest.version > SNAPSHOT_VERSION {
+            return Err(Error::Storage(format!(
+                "Snapshot version {} is newer than supported version {}",
+                manifest.version, SNAPSHOT_VERSION
+            )));
+        }
+        Ok(Self { dir, manifest })
+    }
+
+    /// The archive's manifest
+    pub fn manifest(&self) -> &SnapshotManifest {
+        &self.manifest
+    }
+
+    /// Check every data file against its recorded checksum
+    pub fn verify(&self) -> Result<()> {
+        for file in std::iter::once(&self.manifest.state).chain(&self.manifest.history) {
+            let digest = hex(&Sha256::digest(fs::read(self.dir.join(&file.name))?));
+            if digest != file.sha256 {
+                return Err(Error::Storage(format!(
+                    "Snapshot file {} is corrupt",
+                    file.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Entity states, in entity ID order
+    pub fn read_state(&self) -> Result<Vec<SnapshotEntity>> {
+        self.read_ndjson(&self.manifest.state)
+    }
+
+    /// Exported events, if history was included
+    pub fn read_history(&self) -> Result<Option<Vec<SnapshotEvent>>> {
+        self.manifest
+            .history
+            .as_ref()
+            .map(|file| self.read_ndjson(file))
+            .transpose()
+    }
+
+    fn read_ndjson<T: for<'de> Deserialize<'de>>(&self, file: &SnapshotFile) -> Result<Vec<T>> {
+        let reader = BufReader::new(File::open(self.dir.join(&file.name))?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                records.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// NDJSON file writer that counts and hashes what it writes
+struct NdjsonWriter {
+    name: String,
+    out: BufWriter<File>,
+    hasher: Sha256,
+    records: u64,
+}
+
+impl NdjsonWriter {
+    fn create(dir: &Path, name: &str) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            out: BufWriter::new(File::create(dir.join(name))?),
+            hasher: Sha256::new(),
+            records: 0,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.hasher.update(&line);
+        self.out.write_all(&line)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<SnapshotFile> {
+        let file = self
+            .out
+            .into_inner()
+            .map_err(|e| Error::Io(e.into_error()))?;
+        file.sync_all()?;
+        Ok(SnapshotFile {
+            name: self.name,
+            records: self.records,
+            sha256: hex(&self.hasher.finalize()),
+        })
+    }
+}
+
+/// Payload as JSON, or as a hex string when it is not JSON
+fn payload_value(event: &Event) -> Value {
+    if event.payload.format == "json" {
+        if let Ok(value) = serde_json::from_slice(&event.payload.data) {
+            return value;
+        }
+    }
+    Value::String(hex(&event.payload.data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,182 @@
+//! Index recommendations from the query workload.
+//!
+//! The database records every payload field lookup it serves: which field
+//! was filtered on, how many events had to be examined and how many
+//! matched. Entity, event type and correlation lookups are already indexed
+//! by every journal and are not tracked.
+//!
+//! [`IndexAdvisor`] turns the recorded workload into index suggestions
+//! ranked by the scanning they would save. Selective predicates suit a
+//! secondary index; predicates that match a large share of the events,
+//! i.e. fields with few distinct values, suit a bitmap index.
+
+use crate::core::temporal::Timestamp;
+use crate::storage::field_cache::FieldPath;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Lookups a field needs before an index is suggested for it
+pub const MIN_ADVISED_QUERIES: u64 = 3;
+
+/// Share of events a lookup must match on average for a bitmap index to be
+/// suggested instead of a secondary index
+pub const BITMAP_MIN_SELECTIVITY: f64 = 0.05;
+
+/// Lookups recorded for one payload field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldWorkload {
+    /// Number of lookups
+    pub queries: u64,
+    /// Events examined over all lookups
+    pub scanned: u64,
+    /// Events matched over all lookups
+    pub matched: u64,
+    /// Time of the first lookup
+    pub first_seen: Timestamp,
+    /// Time of the latest lookup
+    pub last_seen: Timestamp,
+}
+
+impl FieldWorkload {
+    /// Average share of the examined events that matched
+    pub fn selectivity(&self) -> f64 {
+        if self.scanned == 0 {
+            0.0
+        } else {
+            self.matched as f64 / self.scanned as f64
+        }
+    }
+}
+
+/// Records the payload field lookups served by a database
+#[derive(Debug, Default)]
+pub struct WorkloadTracker {
+    fields: Mutex<HashMap<FieldPath, FieldWorkload>>,
+}
+
+impl WorkloadTracker {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a lookup on `path` that examined `scanned` events and matched
+    /// `matched` of them
+    pub fn record(&self, path: &FieldPath, scanned: u64, matched: u64) {
+        let now = Timestamp::now();
+        let mut fields = self.fields.lock().expect("workload tracker poisoned");
+        let workload = fields.entry(path.clone()).or_insert(FieldWorkload {
+            queries: 0,
+            scanned: 0,
+            matched: 0,
+            first_seen: now,
+            last_seen: now,
+        });
+        workload.queries += 1;
+        workload.scanned += scanned;
+        workload.matched += matched;
+        workload.last_seen = now;
+    }
+
+    /// Advisor over the workload recorded so far
+    pub fn advisor(&self) -> IndexAdvisor {
+        let fields = self.fields.lock().expect("workload tracker poisoned");
+        let mut workload: Vec<_> = fields
+            .iter()
+            .map(|(path, workload)| (path.clone(), workload.clone()))
+            .collect();
+        workload.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        IndexAdvisor { workload }
+    }
+}
+
+/// Kind of index to build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Value-to-events index for selective lookups
+    Secondary,
+    /// Per-value event bitmaps for fields with few distinct values
+    Bitmap,
+}
+
+/// Suggestion to index a payload field
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexRecommendation {
+    /// Field to index
+    pub field: FieldPath,
+    /// Suggested index kind
+    pub kind: IndexKind,
+    /// Lookups on the field so far
+    pub queries: u64,
+    /// Average share of examined events that matched
+    pub selectivity: f64,
+    /// Events the lookups so far would not have had to examine
+    pub estimated_events_saved: u64,
+}
+
+/// Index suggestions for a recorded workload, see
+/// [`TemporalDB::index_advisor`](crate::db::TemporalDB::index_advisor)
+#[derive(Debug, Clone)]
+pub struct IndexAdvisor {
+    workload: Vec<(FieldPath, FieldWorkload)>,
+}
+
+impl IndexAdvisor {
+    /// Recorded lookups per field, in field order
+    pub fn workload(&self) -> &[(FieldPath, FieldWorkload)] {
+        &self.workload
+    }
+
+    /// Fields worth indexing, most beneficial first.
+    ///
+    /// A field qualifies after [`MIN_ADVISED_QUERIES`] lookups that examined
+    /// more events than they matched.
+    pub fn recommendations(&self) -> Vec<IndexRecommendation> {
+        let mut recommendations: Vec<_> = self
+            .workload
+            .iter()
+            .filter(|(_, w)| w.queries >= MIN_ADVISED_QUERIES && w.scanned > w.matched)
+            .map(|(path, w)| IndexRecommendation {
+                field: path.clone(),
+                kind: if w.selectivity() >= BITMAP_MIN_SELECTIVITY {
+                    IndexKind::Bitmap
+                } else {
+                    IndexKind::Secondary
+                },
+                queries: w.queries,
+                selectivity: w.selectivity(),
+                estimated_events_saved: w.scanned - w.matched,
+            })
+            .collect();
+        recommendations.sort_by_key(|r| Reverse(r.estimated_events_saved));
+        recommendations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recommends_by_selectivity() {
+        let tracker = WorkloadTracker::new();
+        let status = FieldPath::parse("status").unwrap();
+        let order_id = FieldPath::parse("order.id").unwrap();
+        let rare = FieldPath::parse("rare").unwrap();
+        for _ in 0..3 {
+            tracker.record(&status, 1000, 300);
+            tracker.record(&order_id, 1000, 1);
+        }
+        tracker.record(&rare, 1000, 1);
+
+        let recommendations = tracker.advisor().recommendations();
+        assert_eq!(recommendations.len(), 2);
+        assert_eq!(recommendations[0].field, order_id);
+        assert_eq!(recommendations[0].kind, IndexKind::Secondary);
+        assert_eq!(recommendations[0].estimated_events_saved, 2997);
+        assert_eq!(recommendations[1].field, status);
+        assert_eq!(recommendations[1].kind, IndexKind::Bitmap);
+        assert_eq!(tracker.advisor().workload().len(), 3);
+    }
+}
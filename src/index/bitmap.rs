@@ -1,18 +1,264 @@
 //! Bitmap index for filtering
+//!
+//! A [`BitmapIndex`] maps keys (e.g. event tags) to the set of events
+//! having them. Events are numbered in the order they are indexed and each
+//! key holds a compressed [`RoaringBitmap`] of those ordinals, so a
+//! [`TagFilter`] combining keys with AND, OR and NOT is answered with
+//! bitmap intersections, unions and differences before any event is read.
 
-/// Bitmap index for fast filtering
+use crate::core::event::Event;
+use crate::storage::IndexMaintainer;
+use roaring::RoaringBitmap;
+use std::collections::HashMap;
+use std::ops::{BitAnd, BitOr, Not};
+
+/// Boolean combination of tags.
+///
+/// Built with `&`, `|` and `!`, e.g.
+/// `TagFilter::tag("billing") & !TagFilter::tag("manual")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagFilter {
+    /// Events carrying the tag
+    Tag(String),
+    /// Events matching every filter; matches everything when empty
+    All(Vec<TagFilter>),
+    /// Events matching at least one filter; matches nothing when empty
+    Any(Vec<TagFilter>),
+    /// Events not matching the filter
+    Not(Box<TagFilter>),
+}
+
+impl TagFilter {
+    /// Events carrying `tag`
+    pub fn tag(tag: impl Into<String>) -> Self {
+        TagFilter::Tag(tag.into())
+    }
+
+    /// Whether an event carrying `tags` matches
+    pub fn matches(&self, tags: &[String]) -> bool {
+        match self {
+            TagFilter::Tag(tag) => tags.contains(tag),
+            TagFilter::All(filters) => filters.iter().all(|f| f.matches(tags)),
+            TagFilter::Any(filters) => filters.iter().any(|f| f.matches(tags)),
+            TagFilter::Not(filter) => !filter.matches(tags),
+        }
+    }
+}
+
+impl BitAnd for TagFilter {
+    type Output = TagFilter;
+
+    fn bitand(self, rhs: TagFilter) -> TagFilter {
+        match self {
+            TagFilter::All(mut filters) => {
+                filters.push(rhs);
+                TagFilter::All(filters)
+            }
+            lhs => TagFilter::All(vec![lhs, rhs]),
+        }
+    }
+}
+
+impl BitOr for TagFilter {
+    type Output = TagFilter;
+
+    fn bitor(self, rhs: TagFilter) -> TagFilter {
+        match self {
+            TagFilter::Any(mut filters) => {
+                filters.push(rhs);
+                TagFilter::Any(filters)
+            }
+            lhs => TagFilter::Any(vec![lhs, rhs]),
+        }
+    }
+}
+
+impl Not for TagFilter {
+    type Output = TagFilter;
+
+    fn not(self) -> TagFilter {
+        match self {
+            TagFilter::Not(filter) => *filter,
+            filter => TagFilter::Not(Box::new(filter)),
+        }
+    }
+}
+
+/// Bitmap index from keys to events
+#[derive(Debug, Clone, Default)]
 pub struct BitmapIndex {
-    // TODO: Implement bitmap index
+    /// Entity IDs by ordinal
+    entities: Vec<String>,
+    /// Ordinal of each entity ID
+    ordinals: HashMap<String, u32>,
+    /// Entity ordinal of each event, by event ordinal
+    event_entities: Vec<u32>,
+    /// Event ordinals, by key
+    bitmaps: HashMap<String, RoaringBitmap>,
 }
 
 impl BitmapIndex {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    /// Index the next event, of `entity_id` and having `keys`; returns its
+    /// ordinal
+    pub fn insert_event<S: AsRef<str>>(&mut self, entity_id: &str, keys: &[S]) -> u32 {
+        let entity = match self.ordinals.get(entity_id) {
+            Some(&ordinal) => ordinal,
+            None => {
+                let ordinal = self.entities.len() as u32;
+                self.entities.push(entity_id.to_string());
+                self.ordinals.insert(entity_id.to_string(), ordinal);
+                ordinal
+            }
+        };
+        let ordinal = self.event_entities.len() as u32;
+        self.event_entities.push(entity);
+        for key in keys {
+            match self.bitmaps.get_mut(key.as_ref()) {
+                Some(bitmap) => {
+                    bitmap.insert(ordinal);
+                }
+                None => {
+                    let bitmap = RoaringBitmap::from_iter([ordinal]);
+                    self.bitmaps.insert(key.as_ref().to_string(), bitmap);
+                }
+            }
+        }
+        ordinal
+    }
+
+    /// Number of events indexed
+    pub fn len(&self) -> u64 {
+        self.event_entities.len() as u64
+    }
+
+    /// Whether no event is indexed
+    pub fn is_empty(&self) -> bool {
+        self.event_entities.is_empty()
+    }
+
+    /// Number of events having `key`
+    pub fn count(&self, key: &str) -> u64 {
+        self.bitmaps.get(key).map_or(0, RoaringBitmap::len)
+    }
+
+    /// Events having at least one of `keys`
+    pub fn events_with_any<S: AsRef<str>>(&self, keys: &[S]) -> RoaringBitmap {
+        keys.iter()
+            .filter_map(|key| self.bitmaps.get(key.as_ref()))
+            .fold(RoaringBitmap::new(), |union, bitmap| union | bitmap)
+    }
+
+    /// Events matching `filter`
+    pub fn evaluate(&self, filter: &TagFilter) -> RoaringBitmap {
+        match filter {
+            TagFilter::Tag(key) => self.bitmaps.get(key).cloned().unwrap_or_default(),
+            TagFilter::All(filters) => {
+                let mut filters = filters.iter();
+                let Some(first) = filters.next() else {
+                    return self.all_events();
+                };
+                let mut events = self.evaluate(first);
+                for filter in filters {
+                    if events.is_empty() {
+                        break;
+                    }
+                    events &= self.evaluate(filter);
+                }
+                events
+            }
+            TagFilter::Any(filters) => filters
+                .iter()
+                .fold(RoaringBitmap::new(), |union, f| union | self.evaluate(f)),
+            TagFilter::Not(filter) => self.all_events() - self.evaluate(filter),
+        }
+    }
+
+    /// Entities of `events`, in the order they were first indexed
+    pub fn entities_of(&self, events: &RoaringBitmap) -> Vec<&str> {
+        let entities: RoaringBitmap = events
+            .iter()
+            .filter_map(|ordinal| self.event_entities.get(ordinal as usize))
+            .copied()
+            .collect();
+        entities
+            .iter()
+            .map(|ordinal| self.entities[ordinal as usize].as_str())
+            .collect()
+    }
+
+    fn all_events(&self) -> RoaringBitmap {
+        let mut all = RoaringBitmap::new();
+        all.insert_range(0..self.event_entities.len() as u32);
+        all
+    }
+}
+
+impl IndexMaintainer for BitmapIndex {
+    fn on_append(&mut self, event: &Event) {
+        self.insert_event(event.entity_id(), &event.metadata.tags);
     }
 }
 
-impl Default for BitmapIndex {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitmap_unions_keys() {
+        let mut index = BitmapIndex::new();
+        for i in 0..100 {
+            let entity = format!("order:{}", i);
+            let mut keys = Vec::new();
+            if i % 10 == 0 {
+                keys.push("refund");
+            }
+            if i == 70 || i == 99 {
+                keys.push("manual");
+            }
+            index.insert_event(&entity, &keys);
+        }
+        assert_eq!(index.count("refund"), 10);
+        assert_eq!(index.count("unknown"), 0);
+        let events = index.events_with_any(&["manual", "refund", "unknown"]);
+        let entities = index.entities_of(&events);
+        assert_eq!(entities.len(), 11);
+        assert_eq!(entities.first(), Some(&"order:0"));
+        assert_eq!(entities.last(), Some(&"order:99"));
+    }
+
+    #[test]
+    fn test_combines_tags_with_and_or_not() {
+        let mut index = BitmapIndex::new();
+        let tagged = [
+            ("order:1", vec!["billing", "manual"]),
+            ("order:1", vec!["billing"]),
+            ("order:2", vec!["manual"]),
+            ("order:3", vec![]),
+        ];
+        for (entity_id, tags) in &tagged {
+            index.insert_event(entity_id, tags);
+        }
+        let tag = TagFilter::tag;
+        let ordinals = |filter: TagFilter| {
+            let events = index.evaluate(&filter);
+            for (ordinal, (_, tags)) in tagged.iter().enumerate() {
+                let tags: Vec<String> = tags.iter().map(|t| t.to_string()).collect();
+                assert_eq!(events.contains(ordinal as u32), filter.matches(&tags));
+            }
+            events.iter().collect::<Vec<_>>()
+        };
+        assert_eq!(ordinals(tag("billing") & tag("manual")), [0]);
+        assert_eq!(ordinals(tag("billing") | tag("manual")), [0, 1, 2]);
+        assert_eq!(ordinals(tag("billing") & !tag("manual")), [1]);
+        assert_eq!(ordinals(!(tag("billing") | tag("manual"))), [3]);
+        assert_eq!(ordinals(TagFilter::All(Vec::new())), [0, 1, 2, 3]);
+        assert_eq!(ordinals(TagFilter::Any(Vec::new())), Vec::<u32>::new());
+
+        let manual = index.evaluate(&tag("manual"));
+        assert_eq!(index.entities_of(&manual), ["order:1", "order:2"]);
     }
 }
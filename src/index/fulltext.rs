@@ -0,0 +1,329 @@
+//! Full-text index over string payload fields.
+//!
+//! A [`FullTextIndex`] tokenizes the string fields at its paths, over the
+//! events of entities with a given ID prefix, into an in-memory tantivy
+//! index, together with each event's timestamp. A [`TextMatch`] combined
+//! with a time range is then answered with one search instead of a scan of
+//! the range, e.g. audit events mentioning "refund" in March. Like
+//! secondary indexes, it is built from the journal when created, kept up
+//! to date as events are committed and not persisted.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::Result;
+use crate::index::secondary::IndexEntry;
+use crate::index::text::TextMatch;
+use crate::storage::{FieldPath, FieldValue};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tantivy::collector::DocSetCollector;
+use tantivy::query::{BooleanQuery, Occur, Query, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED,
+};
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+/// Memory the index writer buffers documents in before flushing them
+pub const FULLTEXT_WRITER_BYTES: usize = 15_000_000;
+
+/// Full-text index of string fields over the events of entities with a
+/// prefix
+pub struct FullTextIndex {
+    entity_prefix: String,
+    paths: Vec<FieldPath>,
+    /// Text field of each path
+    fields: Vec<Field>,
+    timestamp: Field,
+    /// Position of the event in `entries`
+    row: Field,
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    /// Whether events were added since the writer last committed
+    pending: AtomicBool,
+    entries: Vec<IndexEntry>,
+}
+
+impl FullTextIndex {
+    /// Empty index of the string fields at `paths` of the events of
+    /// entities whose ID starts with `entity_prefix`
+    pub fn new(entity_prefix: impl Into<String>, paths: Vec<FieldPath>) -> Result<Self> {
+        let mut builder = Schema::builder();
+        let indexing = TextFieldIndexing::default()
+            .set_tokenizer("default")
+            .set_index_option(IndexRecordOption::Basic);
+        let text = TextOptions::default().set_indexing_options(indexing);
+        let fields = (0..paths.len())
+            .map(|i| builder.add_text_field(&format!("f{}", i), text.clone()))
+            .collect();
+        let timestamp = builder.add_i64_field("timestamp", INDEXED | FAST);
+        let row = builder.add_u64_field("row", STORED);
+        let index = Index::create_in_ram(builder.build());
+        let writer = index.writer_with_num_threads(1, FULLTEXT_WRITER_BYTES)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            entity_prefix: entity_prefix.into(),
+            paths,
+            fields,
+            timestamp,
+            row,
+            writer: Mutex::new(writer),
+            reader,
+            pending: AtomicBool::new(false),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Prefix of the entities indexed
+    pub fn entity_prefix(&self) -> &str {
+        &self.entity_prefix
+    }
+
+    /// Fields indexed
+    pub fn paths(&self) -> &[FieldPath] {
+        &self.paths
+    }
+
+    /// Whether every entity whose ID starts with `prefix` is indexed
+    pub fn covers(&self, prefix: &str) -> bool {
+        prefix.starts_with(&self.entity_prefix)
+    }
+
+    /// Number of events indexed
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    /// Whether no event is indexed
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Index `event` if its entity is covered and one of the fields holds a
+    /// string; returns whether it was indexed
+    pub fn insert(&mut self, event: &Event) -> Result<bool> {
+        if !self.covers(event.entity_id()) {
+            return Ok(false);
+        }
+        let mut doc = TantivyDocument::new();
+        for (path, field) in self.paths.iter().zip(&self.fields) {
+            if let Some(FieldValue::Str(text)) = path.extract(event) {
+                doc.add_text(*field, text);
+            }
+        }
+        if doc.len() == 0 {
+            return Ok(false);
+        }
+        doc.add_i64(self.timestamp, event.timestamp().as_nanos());
+        doc.add_u64(self.row, self.entries.len() as u64);
+        let writer = self.writer.get_mut().expect("FullTextIndex poisoned lock");
+        writer.add_document(doc)?;
+        self.pending.store(true, Ordering::Release);
+        self.entries.push(IndexEntry {
+            entity_id: event.entity_id().to_string(),
+            timestamp: event.timestamp(),
+            offset: self.entries.len() as u64,
+        });
+        Ok(true)
+    }
+
+    /// Events in `[start, end)` whose indexed fields mention every word of
+    /// `text`, in timestamp order, or `None` if `text` searches a field the
+    /// index does not cover
+    pub fn search(
+        &self,
+        text: &TextMatch,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Option<Vec<IndexEntry>>> {
+        let Some(fields) = text
+            .paths
+            .iter()
+            .map(|path| self.paths.iter().position(|p| p == path))
+            .map(|i| i.map(|i| self.fields[i]))
+            .collect::<Option<Vec<Field>>>()
+        else {
+            return Ok(None);
+        };
+        if text.words.is_empty() || start >= end {
+            return Ok(Some(Vec::new()));
+        }
+        if self.pending.swap(false, Ordering::AcqRel) {
+            self.writer
+                .lock()
+                .expect("FullTextIndex poisoned lock")
+                .commit()?;
+            self.reader.reload()?;
+        }
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = text
+            .words
+            .iter()
+            .map(|word| {
+                let any_field = fields
+                    .iter()
+                    .map(|field| {
+                        let term = Term::from_field_text(*field, word);
+                        let query = TermQuery::new(term, IndexRecordOption::Basic);
+                        (Occur::Should, Box::new(query) as Box<dyn Query>)
+                    })
+                    .collect();
+                (
+                    Occur::Must,
+                    Box::new(BooleanQuery::new(any_field)) as Box<dyn Query>,
+                )
+            })
+            .collect();
+        let from = Term::from_field_i64(self.timestamp, start.as_nanos());
+        let to = Term::from_field_i64(self.timestamp, end.as_nanos());
+        let in_range = RangeQuery::new(Bound::Included(from), Bound::Excluded(to));
+        clauses.push((Occur::Must, Box::new(in_range)));
+
+        let searcher = self.reader.searcher();
+        let docs = searcher.search(&BooleanQuery::new(clauses), &DocSetCollector)?;
+        let mut entries = Vec::with_capacity(docs.len());
+        for address in docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(row) = doc.get_first(self.row).and_then(|v| v.as_u64()) {
+                entries.push(self.entries[row as usize].clone());
+            }
+        }
+        entries.sort_by_key(|e| (e.timestamp, e.offset));
+        Ok(Some(entries))
+    }
+}
+
+impl std::fmt::Debug for FullTextIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FullTextIndex")
+            .field("entity_prefix", &self.entity_prefix)
+            .field("paths", &self.paths)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+/// Full-text indexes of a database
+#[derive(Debug, Default)]
+pub struct FullTextIndexes {
+    indexes: Vec<FullTextIndex>,
+}
+
+impl FullTextIndexes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `index`; returns `false`, dropping it, if an index of the same
+    /// fields over the same prefix exists
+    pub fn add(&mut self, index: FullTextIndex) -> bool {
+        if self.get(index.entity_prefix(), index.paths()).is_some() {
+            return false;
+        }
+        self.indexes.push(index);
+        true
+    }
+
+    /// Remove the index of `paths` over `entity_prefix`; returns whether it
+    /// existed
+    pub fn remove(&mut self, entity_prefix: &str, paths: &[FieldPath]) -> bool {
+        let before = self.indexes.len();
+        self.indexes
+            .retain(|i| !(i.entity_prefix() == entity_prefix && i.paths() == paths));
+        self.indexes.len() != before
+    }
+
+    /// The index of `paths` over `entity_prefix`
+    pub fn get(&self, entity_prefix: &str, paths: &[FieldPath]) -> Option<&FullTextIndex> {
+        self.indexes
+            .iter()
+            .find(|i| i.entity_prefix() == entity_prefix && i.paths() == paths)
+    }
+
+    /// Indexes covering every entity starting with `prefix`, in the order
+    /// they were added
+    pub fn covering<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a FullTextIndex> {
+        self.indexes.iter().filter(move |i| i.covers(prefix))
+    }
+
+    /// Indexes, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &FullTextIndex> {
+        self.indexes.iter()
+    }
+
+    /// Index a committed event, logging indexes that fail to
+    pub fn observe(&mut self, event: &Event) {
+        for index in &mut self.indexes {
+            if let Err(e) = index.insert(event) {
+                tracing::warn!(
+                    "Full-text index over {} failed to index event: {}",
+                    index.entity_prefix(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, secs: i64, payload: serde_json::Value) -> Event {
+        let payload = EventPayload::from_json(&payload).unwrap();
+        let ts = Timestamp::from_secs(secs);
+        Event::new("audit".to_string(), ts, entity_id.to_string(), payload)
+    }
+
+    #[test]
+    fn test_searches_words_within_time_range() {
+        let note = FieldPath::parse("note").unwrap();
+        let reason = FieldPath::parse("reason").unwrap();
+        let mut index = FullTextIndex::new("order:", vec![note.clone(), reason.clone()]).unwrap();
+        let events = [
+            event(
+                "order:1",
+                10,
+                serde_json::json!({ "note": "Refund issued" }),
+            ),
+            event(
+                "order:2",
+                20,
+                serde_json::json!({ "note": "Sent", "reason": "refund denied" }),
+            ),
+            event(
+                "order:3",
+                30,
+                serde_json::json!({ "note": "refund issued again" }),
+            ),
+            event("user:1", 15, serde_json::json!({ "note": "refund" })),
+            event("order:4", 25, serde_json::json!({ "total": 3 })),
+        ];
+        let indexed: Vec<bool> = events.iter().map(|e| index.insert(e).unwrap()).collect();
+        assert_eq!(indexed, [true, true, true, false, false]);
+
+        let ts = Timestamp::from_secs;
+        let found = |index: &FullTextIndex, paths: &[FieldPath], text, start, end| {
+            let text = TextMatch::new(paths.to_vec(), text);
+            let entries = index.search(&text, ts(start), ts(end)).unwrap().unwrap();
+            entries.into_iter().map(|e| e.entity_id).collect::<Vec<_>>()
+        };
+        let both = [note.clone(), reason];
+        let note = [note];
+        let all = found(&index, &both, "REFUND", 0, 100);
+        assert_eq!(all, ["order:1", "order:2", "order:3"]);
+        assert_eq!(found(&index, &both, "refund", 15, 30), ["order:2"]);
+        let issued = found(&index, &note, "refund issued", 0, 100);
+        assert_eq!(issued, ["order:1", "order:3"]);
+
+        // Events indexed after a search are found by the next one.
+        let later = event("order:5", 40, serde_json::json!({ "note": "refund" }));
+        index.insert(&later).unwrap();
+        assert_eq!(found(&index, &note, "refund", 35, 100), ["order:5"]);
+        let total = TextMatch::new(vec![FieldPath::parse("total").unwrap()], "3");
+        assert!(index.search(&total, ts(0), ts(100)).unwrap().is_none());
+    }
+}
@@ -1,7 +1,19 @@
 //! Indexing for temporal queries
 
+pub mod advisor;
 pub mod bitmap;
+#[cfg(feature = "fulltext")]
+pub mod fulltext;
+pub mod rebuild;
+pub mod secondary;
 pub mod temporal;
+pub mod text;
 
+pub use advisor::*;
 pub use bitmap::*;
+#[cfg(feature = "fulltext")]
+pub use fulltext::*;
+pub use rebuild::*;
+pub use secondary::*;
 pub use temporal::*;
+pub use text::*;
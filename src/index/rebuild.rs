@@ -0,0 +1,166 @@
+//! Online index rebuilds.
+//!
+//! [`TemporalDB::rebuild_index`](crate::TemporalDB::rebuild_index) refills
+//! a secondary index from the journal a chunk of events at a time, holding
+//! the journal only while a chunk is read so commits go on meanwhile, and
+//! pauses between chunks to keep under a read rate. Chunks are time
+//! windows cut from the optimizer's event histogram. Progress is published
+//! on a watch channel after every chunk.
+
+use crate::core::temporal::Timestamp;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Default number of events read per chunk of a rebuild
+pub const DEFAULT_REBUILD_CHUNK: u64 = 10_000;
+
+/// How an index rebuild reads the journal and reports progress
+#[derive(Debug, Clone)]
+pub struct RebuildOptions {
+    /// Events read per chunk, roughly: chunks are cut at histogram buckets
+    pub chunk_events: u64,
+    /// Most events read per second, or `None` to read as fast as possible
+    pub max_events_per_sec: Option<u64>,
+    /// Channel the progress is published on after every chunk
+    pub progress: Option<watch::Sender<RebuildProgress>>,
+}
+
+impl RebuildOptions {
+    /// Read chunks of [`DEFAULT_REBUILD_CHUNK`] events without a rate limit
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read about `events` events per chunk
+    pub fn with_chunk_events(mut self, events: u64) -> Self {
+        self.chunk_events = events.max(1);
+        self
+    }
+
+    /// Read at most `events` events per second
+    pub fn with_max_events_per_sec(mut self, events: u64) -> Self {
+        self.max_events_per_sec = Some(events.max(1));
+        self
+    }
+
+    /// Publish progress on `sender`
+    pub fn with_progress(mut self, sender: watch::Sender<RebuildProgress>) -> Self {
+        self.progress = Some(sender);
+        self
+    }
+
+    /// Time to pause after reading `events` in `elapsed` to keep under the
+    /// rate limit
+    pub fn pause(&self, events: u64, elapsed: Duration) -> Duration {
+        match self.max_events_per_sec {
+            Some(rate) => {
+                Duration::from_secs_f64(events as f64 / rate as f64).saturating_sub(elapsed)
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Time windows `[start, end)` of the chunks to read, covering all time,
+    /// from event counts per histogram bucket of `width` in time order
+    pub fn chunks(
+        &self,
+        buckets: &[(Timestamp, u64)],
+        width: Duration,
+    ) -> Vec<(Timestamp, Timestamp)> {
+        let width = width.as_nanos().min(i64::MAX as u128) as i64;
+        let mut chunks = Vec::new();
+        let mut start = Timestamp::from_nanos(i64::MIN);
+        let mut events = 0;
+        for (bucket, count) in buckets {
+            events += count;
+            if events >= self.chunk_events {
+                let end = Timestamp::from_nanos(bucket.as_nanos().saturating_add(width));
+                chunks.push((start, end));
+                start = end;
+                events = 0;
+            }
+        }
+        if start.as_nanos() < i64::MAX {
+            chunks.push((start, Timestamp::from_nanos(i64::MAX)));
+        }
+        chunks
+    }
+}
+
+impl Default for RebuildOptions {
+    fn default() -> Self {
+        Self {
+            chunk_events: DEFAULT_REBUILD_CHUNK,
+            max_events_per_sec: None,
+            progress: None,
+        }
+    }
+}
+
+/// Progress of an index rebuild
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RebuildProgress {
+    /// Events read from the journal so far
+    pub events: u64,
+    /// Events read that were indexed
+    pub indexed: u64,
+    /// Events in the journal when the rebuild began
+    pub total: u64,
+    /// Time since the rebuild began, pauses included
+    pub elapsed: Duration,
+    /// Estimated time left, once events were read
+    pub eta: Option<Duration>,
+    /// Whether the rebuilt index replaced the live one
+    pub done: bool,
+}
+
+impl RebuildProgress {
+    /// Account for `elapsed` since the rebuild began, estimating the time
+    /// left from the rate so far
+    pub fn update(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        let left = self.total.saturating_sub(self.events);
+        self.eta = (self.events > 0).then(|| elapsed.mul_f64(left as f64 / self.events as f64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_buckets_and_paces_reads() {
+        let hour = Duration::from_secs(3600);
+        let ts = |hours: i64| Timestamp::from_secs(hours * 3600);
+        let buckets = [(ts(0), 4), (ts(1), 3), (ts(5), 9), (ts(6), 1)];
+        let options = RebuildOptions::new().with_chunk_events(5);
+        let (min, max) = (
+            Timestamp::from_nanos(i64::MIN),
+            Timestamp::from_nanos(i64::MAX),
+        );
+        assert_eq!(
+            options.chunks(&buckets, hour),
+            [(min, ts(2)), (ts(2), ts(6)), (ts(6), max)]
+        );
+        assert_eq!(options.chunks(&[], hour), [(min, max)]);
+
+        assert_eq!(
+            options.pause(1000, Duration::from_millis(10)),
+            Duration::ZERO
+        );
+        let limited = options.with_max_events_per_sec(100);
+        assert_eq!(
+            limited.pause(50, Duration::from_millis(100)),
+            Duration::from_millis(400)
+        );
+        assert_eq!(limited.pause(50, Duration::from_secs(1)), Duration::ZERO);
+
+        let mut progress = RebuildProgress {
+            events: 25,
+            total: 100,
+            ..Default::default()
+        };
+        progress.update(Duration::from_secs(2));
+        assert_eq!(progress.eta, Some(Duration::from_secs(6)));
+    }
+}
@@ -0,0 +1,717 @@
+//! Secondary indexes on payload fields.
+//!
+//! A [`SecondaryIndex`] maps the values of one payload field, over the
+//! events of entities with a given ID prefix, to where those events are:
+//! their entity, timestamp and offset. Values are kept in order, so both
+//! equality and range predicates on the field are answered without reading
+//! the events that do not match.
+//!
+//! An index may also include other payload fields. Such a covering index
+//! stores their values with each entry, so an entity's history of those
+//! fields is answered from the index without reading events.
+//!
+//! Entries are partitioned by coarse time buckets of their events (a day
+//! by default), each partition with its own value tree. Lookups over a
+//! time range touch only the partitions overlapping it, so queries on
+//! recent data work over small trees, and once retention has removed
+//! every event of a partition's bucket the whole partition is dropped.
+//!
+//! Indexes are built from the journal when created and maintained as
+//! events are committed; they live in memory and are not persisted. An
+//! index can be rebuilt online: a fresh copy is filled from the journal in
+//! chunks while it also indexes the events committed meanwhile, then
+//! replaces the live one.
+
+use crate::core::event::{Event, EventId};
+use crate::core::temporal::Timestamp;
+use crate::storage::{CompareOp, FieldPath, FieldPredicate, FieldValue, IndexMaintainer};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::time::Duration;
+
+/// Default width of the time partitions of a [`SecondaryIndex`]
+pub const DEFAULT_INDEX_PARTITION: Duration = Duration::from_secs(86_400);
+
+/// Where an indexed event is
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IndexEntry {
+    /// Entity of the event
+    pub entity_id: String,
+    /// Valid time of the event
+    pub timestamp: Timestamp,
+    /// Position of the event in the order events were indexed, counting
+    /// events of dropped partitions
+    pub offset: u64,
+}
+
+/// Fields of an event, read from a covering index
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectedEvent {
+    /// Entity of the event
+    pub entity_id: String,
+    /// Valid time of the event
+    pub timestamp: Timestamp,
+    /// Value of each field asked for, `None` where the event has no scalar
+    /// there
+    pub fields: Vec<Option<FieldValue>>,
+}
+
+/// An indexed event with the values of the fields the index stores
+#[derive(Debug, Clone)]
+struct IndexRow {
+    entry: IndexEntry,
+    /// Value of the indexed field, then of each included field
+    fields: Vec<Option<FieldValue>>,
+}
+
+/// Indexed value of a field.
+///
+/// Numbers are keyed as `f64`, so integers beyond 2^53 may share a key:
+/// lookups return candidates that callers check against the predicate.
+#[derive(Debug, Clone)]
+enum IndexKey {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+
+impl IndexKey {
+    fn new(value: &FieldValue) -> Self {
+        match value {
+            FieldValue::Null => IndexKey::Null,
+            FieldValue::Bool(b) => IndexKey::Bool(*b),
+            // Adding zero folds -0.0 into 0.0, which compare equal.
+            FieldValue::Int(n) => IndexKey::Number(*n as f64 + 0.0),
+            FieldValue::Float(n) => IndexKey::Number(*n + 0.0),
+            FieldValue::Str(s) => IndexKey::Str(s.clone()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Null => 0,
+            IndexKey::Bool(_) => 1,
+            IndexKey::Number(_) => 2,
+            IndexKey::Str(_) => 3,
+        }
+    }
+
+    /// Bounds of the keys comparable with this one
+    fn kind_bounds(&self) -> (Bound<IndexKey>, Bound<IndexKey>) {
+        match self {
+            IndexKey::Null => (
+                Bound::Included(IndexKey::Null),
+                Bound::Included(IndexKey::Null),
+            ),
+            IndexKey::Bool(_) => (
+                Bound::Included(IndexKey::Bool(false)),
+                Bound::Included(IndexKey::Bool(true)),
+            ),
+            IndexKey::Number(_) => (
+                Bound::Included(IndexKey::Number(f64::NEG_INFINITY)),
+                Bound::Included(IndexKey::Number(f64::INFINITY)),
+            ),
+            IndexKey::Str(_) => (
+                Bound::Included(IndexKey::Str(String::new())),
+                Bound::Unbounded,
+            ),
+        }
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Bool(a), IndexKey::Bool(b)) => a.cmp(b),
+            (IndexKey::Number(a), IndexKey::Number(b)) => a.total_cmp(b),
+            (IndexKey::Str(a), IndexKey::Str(b)) => a.cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+/// Indexed events of one time bucket
+#[derive(Debug, Clone, Default)]
+struct Partition {
+    /// Events in the order they were indexed
+    rows: Vec<IndexRow>,
+    /// Positions of the events in `rows`, by value
+    values: BTreeMap<IndexKey, Vec<usize>>,
+    /// Positions of the events of each entity, when fields are included
+    by_entity: HashMap<String, Vec<usize>>,
+}
+
+/// Index of one payload field over the events of entities with a prefix
+#[derive(Debug, Clone)]
+pub struct SecondaryIndex {
+    entity_prefix: String,
+    path: FieldPath,
+    /// Fields stored with each entry besides the indexed one
+    included: Vec<FieldPath>,
+    /// Width of the time buckets, in nanoseconds
+    partition_width: i64,
+    /// Partitions by the start of their time bucket
+    partitions: BTreeMap<Timestamp, Partition>,
+    /// Events indexed with each value, over all partitions
+    value_counts: BTreeMap<IndexKey, usize>,
+    /// Events indexed so far, including those of dropped partitions
+    indexed: u64,
+}
+
+impl SecondaryIndex {
+    /// Empty index of the field at `path` of the events of entities whose
+    /// ID starts with `entity_prefix`
+    pub fn new(entity_prefix: impl Into<String>, path: FieldPath) -> Self {
+        Self {
+            entity_prefix: entity_prefix.into(),
+            path,
+            included: Vec::new(),
+            partition_width: DEFAULT_INDEX_PARTITION.as_nanos() as i64,
+            partitions: BTreeMap::new(),
+            value_counts: BTreeMap::new(),
+            indexed: 0,
+        }
+    }
+
+    /// Also store the fields at `paths` with each entry, making this a
+    /// covering index for them. Set before any event is indexed.
+    pub fn with_included(mut self, paths: Vec<FieldPath>) -> Self {
+        self.included = paths.into_iter().filter(|p| *p != self.path).collect();
+        self
+    }
+
+    /// Partition entries by time buckets of `width`, e.g. a week for data
+    /// retained for years. Set before any event is indexed.
+    pub fn with_partition_width(mut self, width: Duration) -> Self {
+        self.partition_width = width.as_nanos().clamp(1, i64::MAX as u128) as i64;
+        self
+    }
+
+    /// Width of the time partitions
+    pub fn partition_width(&self) -> Duration {
+        Duration::from_nanos(self.partition_width as u64)
+    }
+
+    /// Number of time partitions holding entries
+    pub fn partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// Start of the partition holding events at `timestamp`
+    fn bucket(&self, timestamp: Timestamp) -> Timestamp {
+        let ts = timestamp.as_nanos();
+        Timestamp::from_nanos(ts.saturating_sub(ts.rem_euclid(self.partition_width)))
+    }
+
+    /// Empty index with the same prefix, fields and partitioning
+    pub fn emptied(&self) -> Self {
+        Self::new(self.entity_prefix.clone(), self.path.clone())
+            .with_included(self.included.clone())
+            .with_partition_width(self.partition_width())
+    }
+
+    /// Name of the index: its entity prefix followed by its field, e.g.
+    /// `order:status` for `status` of `order:` entities
+    pub fn name(&self) -> String {
+        format!("{}{}", self.entity_prefix, self.path)
+    }
+
+    /// Prefix of the entities indexed
+    pub fn entity_prefix(&self) -> &str {
+        &self.entity_prefix
+    }
+
+    /// Field indexed
+    pub fn path(&self) -> &FieldPath {
+        &self.path
+    }
+
+    /// Fields stored with each entry besides the indexed one
+    pub fn included(&self) -> &[FieldPath] {
+        &self.included
+    }
+
+    /// Whether the index stores every field of `paths`
+    pub fn stores(&self, paths: &[FieldPath]) -> bool {
+        paths
+            .iter()
+            .all(|p| *p == self.path || self.included.contains(p))
+    }
+
+    /// Whether every entity whose ID starts with `prefix` is indexed
+    pub fn covers(&self, prefix: &str) -> bool {
+        prefix.starts_with(&self.entity_prefix)
+    }
+
+    /// Number of events indexed, over the partitions kept
+    pub fn len(&self) -> u64 {
+        self.partitions.values().map(|p| p.rows.len() as u64).sum()
+    }
+
+    /// Whether no event is indexed
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    /// Number of distinct values indexed
+    pub fn distinct_values(&self) -> usize {
+        self.value_counts.len()
+    }
+
+    /// Index `event` if its entity is covered and the field holds a scalar;
+    /// returns whether it was indexed
+    pub fn insert(&mut self, event: &Event) -> bool {
+        if !self.covers(event.entity_id()) {
+            return false;
+        }
+        let Some(value) = self.path.extract(event) else {
+            return false;
+        };
+        let key = IndexKey::new(&value);
+        *self.value_counts.entry(key.clone()).or_default() += 1;
+        let bucket = self.bucket(event.timestamp());
+        let partition = self.partitions.entry(bucket).or_default();
+        let position = partition.rows.len();
+        partition.values.entry(key).or_default().push(position);
+        let mut fields = vec![Some(value)];
+        if !self.included.is_empty() {
+            fields.extend(self.included.iter().map(|p| p.extract(event)));
+            partition
+                .by_entity
+                .entry(event.entity_id().to_string())
+                .or_default()
+                .push(position);
+        }
+        let entry = IndexEntry {
+            entity_id: event.entity_id().to_string(),
+            timestamp: event.timestamp(),
+            offset: self.indexed,
+        };
+        partition.rows.push(IndexRow { entry, fields });
+        self.indexed += 1;
+        true
+    }
+
+    /// Drop the partitions whose time bucket ends at or before `timestamp`,
+    /// e.g. once retention removed every event before it; returns how many
+    /// entries were dropped
+    pub fn drop_before(&mut self, timestamp: Timestamp) -> u64 {
+        let kept = self.partitions.split_off(&self.bucket(timestamp));
+        let dropped = std::mem::replace(&mut self.partitions, kept);
+        let mut entries = 0;
+        for partition in dropped.into_values() {
+            entries += partition.rows.len() as u64;
+            for (key, positions) in partition.values {
+                if let Some(count) = self.value_counts.get_mut(&key) {
+                    *count -= positions.len();
+                    if *count == 0 {
+                        self.value_counts.remove(&key);
+                    }
+                }
+            }
+        }
+        entries
+    }
+
+    /// Events whose field may satisfy `predicate`, by time partition and
+    /// then in value order, or `None` if the index cannot serve it:
+    /// `predicate` is on another field or is a `!=` comparison
+    pub fn lookup(&self, predicate: &FieldPredicate) -> Option<Vec<&IndexEntry>> {
+        let start = Timestamp::from_nanos(i64::MIN);
+        self.lookup_in(predicate, start, Timestamp::from_nanos(i64::MAX))
+    }
+
+    /// Like [`SecondaryIndex::lookup`], reading only the partitions
+    /// overlapping `[start, end)`; events of those partitions outside the
+    /// range may be listed too
+    pub fn lookup_in(
+        &self,
+        predicate: &FieldPredicate,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Option<Vec<&IndexEntry>> {
+        if predicate.path != self.path {
+            return None;
+        }
+        let key = IndexKey::new(&predicate.value);
+        let (lowest, highest) = key.kind_bounds();
+        let range = match predicate.op {
+            CompareOp::Eq => (Bound::Included(key.clone()), Bound::Included(key)),
+            CompareOp::Ne => return None,
+            CompareOp::Lt => (lowest, Bound::Excluded(key)),
+            CompareOp::Le => (lowest, Bound::Included(key)),
+            CompareOp::Gt => (Bound::Excluded(key), highest),
+            CompareOp::Ge => (Bound::Included(key), highest),
+        };
+        if start >= end {
+            return Some(Vec::new());
+        }
+        let partitions = self.partitions.range(self.bucket(start)..end);
+        let entries = partitions.flat_map(|(_, partition)| {
+            let positions = partition.values.range(range.clone()).flat_map(|(_, p)| p);
+            positions.map(|&position| &partition.rows[position].entry)
+        });
+        Some(entries.collect())
+    }
+
+    /// Fields at `paths` of the indexed events of `entity_id`, in timestamp
+    /// order, or `None` unless the index includes fields and stores all of
+    /// `paths`. Events without a scalar in the indexed field are not listed.
+    pub fn history(&self, entity_id: &str, paths: &[FieldPath]) -> Option<Vec<ProjectedEvent>> {
+        if self.included.is_empty() || !self.stores(paths) {
+            return None;
+        }
+        let columns: Vec<usize> = paths
+            .iter()
+            .map(|p| {
+                let included = self.included.iter().position(|i| i == p);
+                included.map_or(0, |i| i + 1)
+            })
+            .collect();
+        let rows = self.partitions.values().flat_map(|partition| {
+            let positions = partition.by_entity.get(entity_id);
+            let positions = positions.map_or(&[][..], Vec::as_slice);
+            positions.iter().map(|&position| &partition.rows[position])
+        });
+        let mut history: Vec<ProjectedEvent> = rows
+            .map(|row| ProjectedEvent {
+                entity_id: row.entry.entity_id.clone(),
+                timestamp: row.entry.timestamp,
+                fields: columns.iter().map(|&c| row.fields[c].clone()).collect(),
+            })
+            .collect();
+        history.sort_by_key(|p| p.timestamp);
+        Some(history)
+    }
+}
+
+/// Copy of an index being rebuilt
+#[derive(Debug, Clone)]
+struct Rebuild {
+    name: String,
+    index: SecondaryIndex,
+    /// Events committed since the rebuild began, indexed as committed
+    committed: HashSet<EventId>,
+}
+
+/// Secondary indexes of a database
+#[derive(Debug, Clone, Default)]
+pub struct SecondaryIndexes {
+    indexes: Vec<SecondaryIndex>,
+    rebuilds: Vec<Rebuild>,
+}
+
+impl SecondaryIndexes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `index`, unless there is one on the same prefix and field;
+    /// returns whether it was added
+    pub fn add(&mut self, index: SecondaryIndex) -> bool {
+        if self.get(index.entity_prefix(), index.path()).is_some() {
+            return false;
+        }
+        self.indexes.push(index);
+        true
+    }
+
+    /// Remove the index on `path` over `entity_prefix`; returns whether
+    /// there was one
+    pub fn remove(&mut self, entity_prefix: &str, path: &FieldPath) -> bool {
+        let before = self.indexes.len();
+        self.indexes
+            .retain(|i| i.entity_prefix() != entity_prefix || i.path() != path);
+        let name = format!("{}{}", entity_prefix, path);
+        self.rebuilds.retain(|r| r.name != name);
+        self.indexes.len() < before
+    }
+
+    /// The index named `name` (see [`SecondaryIndex::name`]), if any
+    pub fn named(&self, name: &str) -> Option<&SecondaryIndex> {
+        self.indexes.iter().find(|i| i.name() == name)
+    }
+
+    /// The index on `path` over `entity_prefix`, if any
+    pub fn get(&self, entity_prefix: &str, path: &FieldPath) -> Option<&SecondaryIndex> {
+        self.indexes
+            .iter()
+            .find(|i| i.entity_prefix() == entity_prefix && i.path() == path)
+    }
+
+    /// The index on `path` covering every entity whose ID starts with
+    /// `prefix` with the fewest events, if any
+    pub fn covering(&self, prefix: &str, path: &FieldPath) -> Option<&SecondaryIndex> {
+        self.indexes
+            .iter()
+            .filter(|i| i.path() == path && i.covers(prefix))
+            .min_by_key(|i| i.len())
+    }
+
+    /// The covering index storing every field of `paths` of `entity_id`'s
+    /// events with the fewest events, if any
+    pub fn storing(&self, entity_id: &str, paths: &[FieldPath]) -> Option<&SecondaryIndex> {
+        self.indexes
+            .iter()
+            .filter(|i| !i.included().is_empty() && i.stores(paths) && i.covers(entity_id))
+            .min_by_key(|i| i.len())
+    }
+
+    /// Indexes, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &SecondaryIndex> {
+        self.indexes.iter()
+    }
+
+    /// Index a committed event
+    pub fn observe(&mut self, event: &Event) {
+        for index in &mut self.indexes {
+            index.insert(event);
+        }
+        for rebuild in &mut self.rebuilds {
+            if rebuild.index.insert(event) {
+                rebuild.committed.insert(event.id());
+            }
+        }
+    }
+
+    /// Start rebuilding the index named `name` into an empty copy, which
+    /// indexes committed events from now on; returns `false` if there is
+    /// no such index or it is already being rebuilt
+    pub fn begin_rebuild(&mut self, name: &str) -> bool {
+        if self.rebuilds.iter().any(|r| r.name == name) {
+            return false;
+        }
+        let Some(index) = self.named(name) else {
+            return false;
+        };
+        let index = index.emptied();
+        self.rebuilds.push(Rebuild {
+            name: name.to_string(),
+            index,
+            committed: HashSet::new(),
+        });
+        true
+    }
+
+    /// Index `event`, read from the journal, into the copy of the index
+    /// named `name` being rebuilt, unless it was committed since the
+    /// rebuild began; returns whether it was indexed
+    pub fn backfill(&mut self, name: &str, event: &Event) -> bool {
+        match self.rebuilds.iter_mut().find(|r| r.name == name) {
+            Some(rebuild) if !rebuild.committed.contains(&event.id()) => {
+                rebuild.index.insert(event)
+            }
+            _ => false,
+        }
+    }
+
+    /// Replace the index named `name` by its rebuilt copy; returns whether
+    /// it was being rebuilt
+    pub fn finish_rebuild(&mut self, name: &str) -> bool {
+        let Some(position) = self.rebuilds.iter().position(|r| r.name == name) else {
+            return false;
+        };
+        let rebuild = self.rebuilds.swap_remove(position);
+        match self.indexes.iter_mut().find(|i| i.name() == name) {
+            Some(index) => *index = rebuild.index,
+            None => return false,
+        }
+        true
+    }
+
+    /// Stop rebuilding the index named `name`, keeping the live one
+    pub fn abort_rebuild(&mut self, name: &str) {
+        self.rebuilds.retain(|r| r.name != name);
+    }
+
+    /// Drop the partitions of every index ending at or before `timestamp`;
+    /// returns how many entries were dropped
+    pub fn drop_before(&mut self, timestamp: Timestamp) -> u64 {
+        self.indexes
+            .iter_mut()
+            .map(|index| index.drop_before(timestamp))
+            .sum()
+    }
+}
+
+impl IndexMaintainer for SecondaryIndexes {
+    fn on_append(&mut self, event: &Event) {
+        self.observe(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, secs: i64, payload: serde_json::Value) -> Event {
+        let payload = EventPayload::from_json(&payload).unwrap();
+        let ts = Timestamp::from_secs(secs);
+        Event::new("update".to_string(), ts, entity_id.to_string(), payload)
+    }
+
+    #[test]
+    fn test_looks_up_values_and_ranges() {
+        let status = FieldPath::parse("status").unwrap();
+        let total = FieldPath::parse("total").unwrap();
+        let mut indexes = SecondaryIndexes::new();
+        assert!(indexes.add(SecondaryIndex::new("order:", status.clone())));
+        assert!(indexes.add(SecondaryIndex::new("order:", total.clone())));
+        assert!(!indexes.add(SecondaryIndex::new("order:", total.clone())));
+        let events = [
+            event(
+                "order:1",
+                1,
+                serde_json::json!({"status": "open", "total": 5}),
+            ),
+            event(
+                "order:2",
+                2,
+                serde_json::json!({"status": "paid", "total": 7.5}),
+            ),
+            event(
+                "order:1",
+                3,
+                serde_json::json!({"status": "paid", "total": -0.0}),
+            ),
+            event("order:3", 4, serde_json::json!({"total": "n/a"})),
+            event("user:1", 5, serde_json::json!({"status": "paid"})),
+        ];
+        for event in &events {
+            indexes.observe(event);
+        }
+
+        let by_status = indexes.covering("order:1", &status).unwrap();
+        assert_eq!(by_status.len(), 3);
+        assert!(indexes.covering("user:", &status).is_none());
+        let lookup = |index: &SecondaryIndex, predicate: &str| {
+            let predicate = FieldPredicate::parse(predicate).unwrap();
+            let entries = index.lookup(&predicate)?;
+            Some(
+                entries
+                    .iter()
+                    .map(|e| e.timestamp.as_secs())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(lookup(by_status, "status = 'paid'"), Some(vec![2, 3]));
+        assert_eq!(lookup(by_status, "status != 'paid'"), None);
+        assert_eq!(lookup(by_status, "total = 5"), None);
+
+        let by_total = indexes.get("order:", &total).unwrap();
+        assert_eq!(lookup(by_total, "total = 0"), Some(vec![3]));
+        assert_eq!(lookup(by_total, "total > 0"), Some(vec![1, 2]));
+        assert_eq!(lookup(by_total, "total <= 5"), Some(vec![3, 1]));
+        assert_eq!(lookup(by_total, "total >= 'a'"), Some(vec![4]));
+
+        assert!(indexes.remove("order:", &total));
+        assert!(indexes.get("order:", &total).is_none());
+    }
+
+    #[test]
+    fn test_partitions_by_day_and_drops_expired_ones() {
+        let day = 86_400;
+        let status = FieldPath::parse("status").unwrap();
+        let mut index = SecondaryIndex::new("order:", status);
+        for (i, status) in ["open", "paid", "open", "void"].into_iter().enumerate() {
+            let json = serde_json::json!({ "status": status });
+            index.insert(&event("order:1", i as i64 * day + 60, json));
+        }
+        assert_eq!(
+            (index.len(), index.partitions(), index.distinct_values()),
+            (4, 4, 3)
+        );
+
+        let open = FieldPredicate::parse("status = 'open'").unwrap();
+        let ts = |days: i64| Timestamp::from_secs(days * day);
+        let offsets =
+            |entries: Vec<&IndexEntry>| entries.iter().map(|e| e.offset).collect::<Vec<_>>();
+        assert_eq!(offsets(index.lookup(&open).unwrap()), [0, 2]);
+        assert_eq!(offsets(index.lookup_in(&open, ts(1), ts(4)).unwrap()), [2]);
+        assert_eq!(
+            offsets(index.lookup_in(&open, ts(4), ts(1)).unwrap()),
+            Vec::<u64>::new()
+        );
+
+        // Day 2 still has events at its end, so only days 0 and 1 go.
+        assert_eq!(index.drop_before(Timestamp::from_secs(2 * day + 30)), 2);
+        assert_eq!(
+            (index.len(), index.partitions(), index.distinct_values()),
+            (2, 2, 2)
+        );
+        assert_eq!(offsets(index.lookup(&open).unwrap()), [2]);
+        let json = serde_json::json!({ "status": "paid" });
+        assert!(index.insert(&event("order:2", 5 * day, json)));
+        assert_eq!(index.lookup(&open).unwrap().len(), 1);
+        let paid = FieldPredicate::parse("status = 'paid'").unwrap();
+        assert_eq!(offsets(index.lookup(&paid).unwrap()), [4]);
+    }
+
+    #[test]
+    fn test_covering_index_answers_history() {
+        let status = FieldPath::parse("status").unwrap();
+        let note = FieldPath::parse("note").unwrap();
+        let total = FieldPath::parse("total").unwrap();
+        let mut indexes = SecondaryIndexes::new();
+        indexes.add(SecondaryIndex::new("user:", status.clone()));
+        let covering = SecondaryIndex::new("user:", total.clone())
+            .with_included(vec![status.clone(), note.clone()]);
+        indexes.add(covering);
+        indexes.observe(&event(
+            "user:1",
+            5,
+            serde_json::json!({"status": "paid", "total": 2}),
+        ));
+        indexes.observe(&event(
+            "user:1",
+            2,
+            serde_json::json!({"status": "open", "total": 1}),
+        ));
+        indexes.observe(&event(
+            "user:2",
+            3,
+            serde_json::json!({"status": "open", "total": 9}),
+        ));
+        indexes.observe(&event("user:1", 7, serde_json::json!({"status": "void"})));
+
+        let paths = [status.clone(), note.clone(), total.clone()];
+        let index = indexes.storing("user:1", &paths).unwrap();
+        assert_eq!(index.path(), &total);
+        let history = index.history("user:1", &paths).unwrap();
+        let rows: Vec<_> = history
+            .iter()
+            .map(|p| (p.timestamp.as_secs(), p.fields.clone()))
+            .collect();
+        let str = |s: &str| Some(FieldValue::Str(s.to_string()));
+        assert_eq!(
+            rows,
+            [
+                (2, vec![str("open"), None, Some(FieldValue::Int(1))]),
+                (5, vec![str("paid"), None, Some(FieldValue::Int(2))]),
+            ]
+        );
+        assert_eq!(index.history("user:3", &paths), Some(Vec::new()));
+        let unstored = FieldPath::parse("owner").unwrap();
+        assert!(indexes.storing("user:1", &[unstored]).is_none());
+        assert!(indexes.storing("order:1", &paths).is_none());
+        let plain = indexes.get("user:", &status).unwrap();
+        assert!(plain.history("user:1", &[status]).is_none());
+    }
+}
@@ -2,27 +2,39 @@
 
 use crate::core::event::Event;
 use crate::core::temporal::Timestamp;
+use crate::storage::IndexMaintainer;
 use std::collections::BTreeMap;
 
 /// Temporal index for fast time-based queries
 pub struct TemporalIndex {
     /// Map from timestamp to event offsets
     time_index: BTreeMap<Timestamp, Vec<usize>>,
+    /// Number of events indexed
+    len: usize,
 }
 
 impl TemporalIndex {
     pub fn new() -> Self {
         Self {
             time_index: BTreeMap::new(),
+            len: 0,
         }
     }
 
     pub fn add_event(&mut self, event: &Event, offset: usize) {
         let timestamp = event.timestamp();
-        self.time_index
-            .entry(timestamp)
-            .or_insert_with(Vec::new)
-            .push(offset);
+        self.time_index.entry(timestamp).or_default().push(offset);
+        self.len += 1;
+    }
+
+    /// Number of events indexed
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no event is indexed
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
     pub fn find_in_range(&self, start: Timestamp, end: Timestamp) -> Vec<usize> {
@@ -33,8 +45,239 @@ impl TemporalIndex {
     }
 }
 
+/// Offsets are append positions
+impl IndexMaintainer for TemporalIndex {
+    fn on_append(&mut self, event: &Event) {
+        self.add_event(event, self.len);
+    }
+}
+
 impl Default for TemporalIndex {
     fn default() -> Self {
         Self::new()
     }
 }
+
+/// Interval tree over half-open `[start, end)` time ranges, e.g. the valid
+/// ranges of bitemporal values.
+///
+/// Each node of a treap ordered by range start also holds the greatest end
+/// in its subtree, so [`IntervalIndex::stab`] and
+/// [`IntervalIndex::overlapping`] skip every subtree ending too early and
+/// answer in `O(log n + k)` expected time for `k` matches. Ranges without
+/// an end never expire.
+#[derive(Debug, Clone)]
+pub struct IntervalIndex<T> {
+    root: Option<Box<IntervalNode<T>>>,
+    len: usize,
+    /// State of the priority generator
+    seed: u64,
+}
+
+#[derive(Debug, Clone)]
+struct IntervalNode<T> {
+    start: Timestamp,
+    end: Timestamp,
+    value: T,
+    /// Greatest end in the subtree
+    max_end: Timestamp,
+    priority: u64,
+    left: Option<Box<IntervalNode<T>>>,
+    right: Option<Box<IntervalNode<T>>>,
+}
+
+impl<T> IntervalNode<T> {
+    fn update(&mut self) {
+        let children = [&self.left, &self.right];
+        self.max_end = children
+            .into_iter()
+            .flatten()
+            .map(|child| child.max_end)
+            .fold(self.end, Timestamp::max);
+    }
+
+    fn rotate_right(mut self: Box<Self>) -> Box<Self> {
+        let mut left = self.left.take().expect("rotate_right without left child");
+        self.left = left.right.take();
+        self.update();
+        left.right = Some(self);
+        left.update();
+        left
+    }
+
+    fn rotate_left(mut self: Box<Self>) -> Box<Self> {
+        let mut right = self.right.take().expect("rotate_left without right child");
+        self.right = right.left.take();
+        self.update();
+        right.left = Some(self);
+        right.update();
+        right
+    }
+
+    fn insert(node: Option<Box<Self>>, new: Box<Self>) -> Box<Self> {
+        let Some(mut node) = node else {
+            return new;
+        };
+        if new.start < node.start {
+            node.left = Some(Self::insert(node.left.take(), new));
+            node.update();
+            if node
+                .left
+                .as_ref()
+                .is_some_and(|l| l.priority > node.priority)
+            {
+                node = node.rotate_right();
+            }
+        } else {
+            node.right = Some(Self::insert(node.right.take(), new));
+            node.update();
+            if node
+                .right
+                .as_ref()
+                .is_some_and(|r| r.priority > node.priority)
+            {
+                node = node.rotate_left();
+            }
+        }
+        node
+    }
+
+    /// Visit the ranges overlapping `[start, end)` in start order
+    fn overlapping<'a>(&'a self, start: Timestamp, end: Timestamp, out: &mut Vec<&'a T>) {
+        if self.max_end <= start {
+            return;
+        }
+        if let Some(left) = &self.left {
+            left.overlapping(start, end, out);
+        }
+        if self.start >= end {
+            return;
+        }
+        if self.end > start {
+            out.push(&self.value);
+        }
+        if let Some(right) = &self.right {
+            right.overlapping(start, end, out);
+        }
+    }
+}
+
+impl<T> IntervalIndex<T> {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            seed: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    /// Index `value` as valid from `start` until `end` (exclusive), or for
+    /// good without an `end`. Empty ranges are not indexed.
+    pub fn insert(&mut self, start: Timestamp, end: Option<Timestamp>, value: T) {
+        let end = end.unwrap_or(Timestamp::from_nanos(i64::MAX));
+        if start >= end {
+            return;
+        }
+        let node = Box::new(IntervalNode {
+            start,
+            end,
+            value,
+            max_end: end,
+            priority: self.next_priority(),
+            left: None,
+            right: None,
+        });
+        self.root = Some(IntervalNode::insert(self.root.take(), node));
+        self.len += 1;
+    }
+
+    /// Values whose range contains `at`, in start order
+    pub fn stab(&self, at: Timestamp) -> Vec<&T> {
+        let after = Timestamp::from_nanos(at.as_nanos().saturating_add(1));
+        self.overlapping(at, after)
+    }
+
+    /// Values whose range overlaps `[start, end)`, in start order
+    pub fn overlapping(&self, start: Timestamp, end: Timestamp) -> Vec<&T> {
+        let mut out = Vec::new();
+        if let (Some(root), true) = (&self.root, start < end) {
+            root.overlapping(start, end, &mut out);
+        }
+        out
+    }
+
+    /// Number of ranges indexed
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no range is indexed
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// SplitMix64 step
+    fn next_priority(&mut self) -> u64 {
+        self.seed = self.seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl IntervalIndex<usize> {
+    /// Index the valid range of `event`, from its timestamp until its
+    /// valid-to, under `offset`
+    pub fn add_event(&mut self, event: &Event, offset: usize) {
+        self.insert(event.timestamp(), event.valid_to(), offset);
+    }
+}
+
+impl IndexMaintainer for IntervalIndex<usize> {
+    fn on_append(&mut self, event: &Event) {
+        self.add_event(event, self.len());
+    }
+}
+
+impl<T> Default for IntervalIndex<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_stab_and_overlap() {
+        let ts = Timestamp::from_secs;
+        let mut index = IntervalIndex::new();
+        let ranges = (0..500i64).map(|i| (i * 10, (i % 7 != 0).then_some(i * 10 + 25)));
+        for (i, (start, end)) in ranges.clone().enumerate() {
+            index.insert(ts(start), end.map(ts), i);
+        }
+        index.insert(ts(5), Some(ts(5)), usize::MAX);
+        assert_eq!(index.len(), 500);
+
+        let naive = |start: i64, end: i64| -> Vec<usize> {
+            let ranges = ranges.clone().enumerate();
+            ranges
+                .filter(|(_, (s, e))| *s < end && e.is_none_or(|e| e > start))
+                .map(|(i, _)| i)
+                .collect()
+        };
+        let found = |values: Vec<&usize>| values.into_iter().copied().collect::<Vec<_>>();
+        assert_eq!(found(index.stab(ts(1000))), naive(1000, 1001));
+        assert_eq!(found(index.stab(ts(1000))).len(), 17);
+        assert!(index.stab(ts(-1)).is_empty());
+        assert!(index.overlapping(ts(10), ts(10)).is_empty());
+        for (start, end) in [(0, 1), (995, 1042), (4000, 6000)] {
+            assert_eq!(
+                found(index.overlapping(ts(start), ts(end))),
+                naive(start, end)
+            );
+        }
+    }
+}
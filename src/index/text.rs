@@ -0,0 +1,110 @@
+//! Text match predicates.
+//!
+//! A [`TextMatch`] selects events whose string payload fields mention
+//! every word of a search text, e.g. `note MATCH 'refund issued'`. Text is
+//! split into words at any character that is not alphanumeric and
+//! lowercased, the way the full-text index tokenizes it (see
+//! [`fulltext`](crate::index::fulltext) with the `fulltext` feature), so
+//! events the index lists can be checked against the predicate directly,
+//! and queries without an index still find the same events by scanning.
+
+use crate::core::event::Event;
+use crate::error::{Error, Result};
+use crate::storage::{FieldPath, FieldValue};
+use std::fmt;
+
+/// Words of at least this many bytes are not indexed or matched
+pub const MAX_WORD_BYTES: usize = 40;
+
+/// Lowercased words of `text`, in order
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() < MAX_WORD_BYTES)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Events whose string fields at `paths` mention every word of a text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMatch {
+    /// Fields searched; a word may be found in any of them
+    pub paths: Vec<FieldPath>,
+    /// Words searched for, lowercased
+    pub words: Vec<String>,
+}
+
+impl TextMatch {
+    /// Events mentioning every word of `text` in one of the fields at
+    /// `paths`
+    pub fn new(paths: Vec<FieldPath>, text: &str) -> Self {
+        let mut words = tokenize(text);
+        words.sort();
+        words.dedup();
+        Self { paths, words }
+    }
+
+    /// Parse `<path>[, <path>...] MATCH '<text>'`, e.g.
+    /// `note, reason MATCH 'refund'`
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || Error::Query(format!("Invalid text match: {}", text));
+        let (paths, quoted) = text.split_once(" MATCH ").ok_or_else(invalid)?;
+        let quoted = quoted.trim();
+        let search = quoted
+            .strip_prefix('\'')
+            .and_then(|q| q.strip_suffix('\''))
+            .ok_or_else(invalid)?;
+        let paths = paths
+            .split(',')
+            .map(|path| FieldPath::parse(path.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(paths, search))
+    }
+
+    /// Whether `event` mentions every word; a match without words matches
+    /// nothing
+    pub fn matches_event(&self, event: &Event) -> bool {
+        if self.words.is_empty() {
+            return false;
+        }
+        let mut found: Vec<String> = Vec::new();
+        for path in &self.paths {
+            if let Some(FieldValue::Str(text)) = path.extract(event) {
+                found.extend(tokenize(&text));
+            }
+        }
+        self.words.iter().all(|word| found.contains(word))
+    }
+}
+
+impl fmt::Display for TextMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paths: Vec<String> = self.paths.iter().map(FieldPath::to_string).collect();
+        write!(f, "{} MATCH '{}'", paths.join(", "), self.words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+
+    #[test]
+    fn test_matches_every_word_in_any_field() {
+        let json = serde_json::json!({ "note": "Refund issued!", "reason": "Damaged box", "n": 3 });
+        let payload = EventPayload::from_json(&json).unwrap();
+        let ts = Timestamp::from_secs(0);
+        let event = Event::new("audit".to_string(), ts, "order:1".to_string(), payload);
+
+        assert_eq!(tokenize("Refund-issued, 2x"), ["refund", "issued", "2x"]);
+        let matches = |text: &str| TextMatch::parse(text).unwrap().matches_event(&event);
+        assert!(matches("note MATCH 'refund'"));
+        assert!(matches("note, reason MATCH 'REFUND box'"));
+        assert!(!matches("note MATCH 'refund box'"));
+        assert!(!matches("n MATCH '3'"));
+        assert!(!matches("note MATCH '...'"));
+        assert!(TextMatch::parse("note MATCH refund").is_err());
+        let parsed = TextMatch::parse("note, reason MATCH 'box refund'").unwrap();
+        assert_eq!(parsed.to_string(), "note, reason MATCH 'box refund'");
+    }
+}
@@ -27,12 +27,17 @@
 //! ```
 
 pub mod api;
+pub mod backup;
 pub mod cli;
+pub mod config;
 pub mod core;
 pub mod crdt;
 pub mod distributed;
 pub mod error;
+pub mod export;
 pub mod index;
+pub mod metrics;
+pub mod outbox;
 pub mod query;
 pub mod storage;
 
@@ -50,6 +55,7 @@ pub mod prelude {
 #[cfg(test)]
 mod tests {
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn it_works() {
         assert!(true);
     }
@@ -1,8 +1,12 @@
 //! Temporal-DB: Main entry point
 
 use clap::Parser;
+use std::sync::Arc;
+use temporal_db::backup::{restore_backup, restore_point_in_time};
 use temporal_db::cli::Cli;
-use temporal_db::error::Result;
+use temporal_db::db::SEGMENTS_DIR;
+use temporal_db::error::{Error, Result};
+use temporal_db::storage::{verify_segments, LocalObjectStore, WalArchiver};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -12,10 +16,12 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
+    let config = cli.load_config()?;
 
     match cli.command {
         temporal_db::cli::Commands::Start { port } => {
             println!("Starting Temporal-DB server on port {}", port);
+            println!("Journal: {:?}", config.journal);
             // TODO: Start server
             Ok(())
         }
@@ -29,5 +35,73 @@ async fn main() -> Result<()> {
             // TODO: Implement query
             Ok(())
         }
+        temporal_db::cli::Commands::Doctor { data_dir, repair } => {
+            let dir = data_dir.or(config.data_dir).ok_or_else(|| {
+                Error::Configuration(
+                    "doctor needs --data-dir or data_dir in the config".to_string(),
+                )
+            })?;
+            let report = verify_segments(dir.join(SEGMENTS_DIR), repair)?;
+            for check in &report.segments {
+                let status = match (check.is_healthy(), check.repaired) {
+                    (true, _) => "ok",
+                    (false, true) => "repaired",
+                    (false, false) => "damaged",
+                };
+                println!(
+                    "segment {}: {} ({} blocks, {} events)",
+                    check.segment_id, status, check.blocks, check.events
+                );
+                if !check.checksum_ok {
+                    println!("  segment checksum mismatch");
+                }
+                for fault in &check.faults {
+                    println!("  block at offset {}: {}", fault.offset, fault.error);
+                }
+                if check.lost_events > 0 {
+                    println!("  {} events unreadable", check.lost_events);
+                }
+            }
+            let damaged = report.damaged().filter(|check| !check.repaired).count();
+            if damaged > 0 {
+                return Err(Error::Storage(format!(
+                    "{} damaged segments, rerun with --repair to drop unreadable blocks",
+                    damaged
+                )));
+            }
+            Ok(())
+        }
+        temporal_db::cli::Commands::Restore {
+            backups,
+            data_dir,
+            archive,
+            archive_prefix,
+            until,
+        } => {
+            let dir = data_dir.or(config.data_dir).ok_or_else(|| {
+                Error::Configuration(
+                    "restore needs --data-dir or data_dir in the config".to_string(),
+                )
+            })?;
+            let Some(archive) = archive else {
+                let manifest = restore_backup(&backups, &dir)?;
+                println!(
+                    "Restored backup {} up to WAL LSN {}",
+                    manifest.backup_id, manifest.marker.wal_lsn
+                );
+                return Ok(());
+            };
+            let store = Arc::new(LocalObjectStore::new(archive)?);
+            let mut archiver = WalArchiver::new(store, archive_prefix);
+            if let Some(keys) = &config.wal_encryption {
+                archiver = archiver.with_key_provider(Arc::new(keys.key_ring()?));
+            }
+            let lsn = restore_point_in_time(&backups, &dir, &archiver, until).await?;
+            match until {
+                Some(until) => println!("Restored to WAL LSN {} as of {}", lsn, until),
+                None => println!("Restored to WAL LSN {}", lsn),
+            }
+            Ok(())
+        }
     }
 }
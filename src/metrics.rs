@@ -0,0 +1,347 @@
+//! In-process metrics.
+//!
+//! The database records latencies and counters in lock-free structures and
+//! hands out plain-struct snapshots through [`TemporalDB::metrics`], so
+//! embedded users can forward them to whatever metrics system they already
+//! run instead of depending on a particular exporter.
+//!
+//! Latencies go into [`LatencyHistogram`]s with HDR-style log-linear buckets:
+//! values below 32ns are exact and larger values keep their top five
+//! significant bits, bounding the relative error to about 6% over the whole
+//! `u64` range with a fixed set of buckets.
+//!
+//! [`TemporalDB::metrics`]: crate::db::TemporalDB::metrics
+
+use crate::storage::scrub::ScrubReport;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const HALF_SUB_BUCKETS: u64 = SUB_BUCKETS / 2;
+/// Enough buckets to cover every `u64` value
+const BUCKETS: usize = (SUB_BUCKETS + (64 - SUB_BUCKET_BITS as u64) * HALF_SUB_BUCKETS) as usize;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    // Keep the top SUB_BUCKET_BITS bits of the value.
+    let shift = (63 - value.leading_zeros()) - (SUB_BUCKET_BITS - 1);
+    (SUB_BUCKETS + (shift as u64 - 1) * HALF_SUB_BUCKETS + ((value >> shift) - HALF_SUB_BUCKETS))
+        as usize
+}
+
+/// Inclusive upper bound of the values mapped to bucket `index`
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let offset = index - SUB_BUCKETS;
+    let shift = offset / HALF_SUB_BUCKETS + 1;
+    let low = (HALF_SUB_BUCKETS + offset % HALF_SUB_BUCKETS) << shift;
+    low + ((1u64 << shift) - 1)
+}
+
+/// Lock-free latency histogram
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram
+    pub fn new() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Record one observation
+    pub fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Record the time elapsed since `start`
+    pub fn record_since(&self, start: Instant) {
+        self.record(start.elapsed());
+    }
+
+    /// Copy the current state.
+    ///
+    /// Concurrent recordings may be partially reflected; the snapshot is
+    /// consistent enough for monitoring, not for exact accounting.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<HistogramBucket> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(index, count)| {
+                let count = count.load(Ordering::Relaxed);
+                (count > 0).then(|| HistogramBucket {
+                    upper_bound: Duration::from_nanos(bucket_upper_bound(index)),
+                    count,
+                })
+            })
+            .collect();
+        let count = self.count.load(Ordering::Relaxed);
+        let min = self.min.load(Ordering::Relaxed);
+        HistogramSnapshot {
+            count,
+            sum: Duration::from_nanos(self.sum.load(Ordering::Relaxed)),
+            min: Duration::from_nanos(if count == 0 { 0 } else { min }),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One non-empty histogram bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistogramBucket {
+    /// Largest latency counted in this bucket
+    pub upper_bound: Duration,
+    /// Number of observations in this bucket
+    pub count: u64,
+}
+
+/// Point-in-time copy of a [`LatencyHistogram`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Number of observations
+    pub count: u64,
+    /// Sum of all observations
+    pub sum: Duration,
+    /// Smallest observation
+    pub min: Duration,
+    /// Largest observation
+    pub max: Duration,
+    /// Non-empty buckets in increasing order
+    pub buckets: Vec<HistogramBucket>,
+}
+
+impl HistogramSnapshot {
+    /// Mean latency
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.sum.as_nanos() / self.count as u128) as u64)
+    }
+
+    /// Latency at quantile `q` (between 0.0 and 1.0), reported as the upper
+    /// bound of the bucket holding it
+    pub fn quantile(&self, q: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for bucket in &self.buckets {
+            seen += bucket.count;
+            if seen >= rank {
+                return bucket.upper_bound.min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Median latency
+    pub fn p50(&self) -> Duration {
+        self.quantile(0.5)
+    }
+
+    /// 99th percentile latency
+    pub fn p99(&self) -> Duration {
+        self.quantile(0.99)
+    }
+
+    /// 99.9th percentile latency
+    pub fn p999(&self) -> Duration {
+        self.quantile(0.999)
+    }
+}
+
+/// Metrics collected by a database instance
+#[derive(Default)]
+pub struct Metrics {
+    append: LatencyHistogram,
+    query: LatencyHistogram,
+    flush: LatencyHistogram,
+    events_appended: AtomicU64,
+    bytes_written: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    segments_scrubbed: AtomicU64,
+    damaged_segments: AtomicU64,
+    segments_repaired: AtomicU64,
+    segments_flagged: AtomicU64,
+    hints_dropped: AtomicU64,
+}
+
+impl Metrics {
+    /// Create an empty set of metrics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an appended event of `bytes` serialized bytes
+    pub fn record_append(&self, latency: Duration, bytes: u64) {
+        self.append.record(latency);
+        self.events_appended.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record a read query
+    pub fn record_query(&self, latency: Duration) {
+        self.query.record(latency);
+    }
+
+    /// Record a flush
+    pub fn record_flush(&self, latency: Duration) {
+        self.flush.record(latency);
+    }
+
+    /// Record a cache lookup
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of a scrub pass
+    pub fn record_scrub(&self, report: &ScrubReport) {
+        let add = |counter: &AtomicU64, n: usize| counter.fetch_add(n as u64, Ordering::Relaxed);
+        add(&self.segments_scrubbed, report.checked.len());
+        add(&self.damaged_segments, report.damaged.len());
+        add(&self.segments_repaired, report.repaired.len());
+        add(&self.segments_flagged, report.flagged.len());
+    }
+
+    /// Record replica writes not held as hints, see
+    /// [`ReplicatedDB::with_max_hints`](crate::distributed::ReplicatedDB::with_max_hints)
+    pub fn record_hints_dropped(&self, writes: u64) {
+        self.hints_dropped.fetch_add(writes, Ordering::Relaxed);
+    }
+
+    /// Copy the current values
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            append: self.append.snapshot(),
+            query: self.query.snapshot(),
+            flush: self.flush.snapshot(),
+            events_appended: self.events_appended.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            segments_scrubbed: self.segments_scrubbed.load(Ordering::Relaxed),
+            damaged_segments: self.damaged_segments.load(Ordering::Relaxed),
+            segments_repaired: self.segments_repaired.load(Ordering::Relaxed),
+            segments_flagged: self.segments_flagged.load(Ordering::Relaxed),
+            hints_dropped: self.hints_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a database's [`Metrics`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Latency of appending an event, including derived state updates
+    pub append: HistogramSnapshot,
+    /// Latency of read queries
+    pub query: HistogramSnapshot,
+    /// Latency of flushes
+    pub flush: HistogramSnapshot,
+    /// Number of events appended
+    pub events_appended: u64,
+    /// Serialized size of all appended events
+    pub bytes_written: u64,
+    /// Point-in-time reads answered from pinned hot-entity timelines
+    pub cache_hits: u64,
+    /// Point-in-time reads that went to the journal
+    pub cache_misses: u64,
+    /// Segment verifications done by scrubbing
+    pub segments_scrubbed: u64,
+    /// Damaged segments found by scrubbing
+    pub damaged_segments: u64,
+    /// Damaged segments replaced from a replica
+    pub segments_repaired: u64,
+    /// Damaged segments flagged for restore
+    pub segments_flagged: u64,
+    /// Writes for replicas that are down not held as hints because the
+    /// replica had the most hints already; read repair restores them
+    pub hints_dropped: u64,
+}
+
+impl MetricsSnapshot {
+    /// Fraction of point-in-time reads answered from the cache
+    pub fn cache_hit_ratio(&self) -> f64 {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / total as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_bounds() {
+        for value in [0, 1, 31, 32, 33, 100, 1_000, 123_456_789, u64::MAX] {
+            let index = bucket_index(value);
+            assert!(index < BUCKETS);
+            assert!(value <= bucket_upper_bound(index));
+            if index > 0 {
+                assert!(value > bucket_upper_bound(index - 1));
+            }
+        }
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_histogram_quantiles() {
+        let histogram = LatencyHistogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 1000);
+        assert_eq!(snapshot.min, Duration::from_micros(1));
+        assert_eq!(snapshot.max, Duration::from_micros(1000));
+        assert_eq!(snapshot.mean(), Duration::from_nanos(500_500));
+
+        // Quantiles are accurate to the bucket width (~6%).
+        let p50 = snapshot.p50().as_micros() as f64;
+        assert!((500.0..=500.0 * 1.07).contains(&p50), "p50 = {p50}");
+        let p99 = snapshot.p99().as_micros() as f64;
+        assert!((990.0..=1000.0).contains(&p99), "p99 = {p99}");
+        assert_eq!(LatencyHistogram::new().snapshot().p99(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,339 @@
+//! Ingestion from transactional outboxes.
+//!
+//! Services that follow the outbox pattern write the events they publish to
+//! an outbox table in the same transaction as their own changes. An
+//! [`OutboxSource`] reads such a table in offset order; the consumer created
+//! with [`TemporalDB::outbox_consumer`](crate::db::TemporalDB::outbox_consumer)
+//! appends its records to the journal exactly once.
+//!
+//! Every ingested event carries an `outbox:<source>@<offset>` tag, so the
+//! source offset reaches the WAL in the same record as the event. After each
+//! batch the consumer also checkpoints the last offset on the internal
+//! `$sys:outbox:<source>` entity; on resume, records after the checkpoint
+//! whose tagged event is already in the journal are skipped.
+
+use crate::core::temporal::Timestamp;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(feature = "postgres")]
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "postgres")]
+use std::time::Instant;
+
+/// Prefix of the entity holding an outbox source's checkpoint events
+pub const OUTBOX_ENTITY_PREFIX: &str = "$sys:outbox:";
+
+/// Event type recorded when an outbox checkpoint is saved
+pub const OUTBOX_CHECKPOINT: &str = "outbox.checkpoint";
+
+/// Records an outbox consumer reads per poll by default
+pub const OUTBOX_BATCH_SIZE: usize = 256;
+
+/// How long a PostgreSQL outbox waits for a missing offset by default, see
+/// [`PostgresOutboxSource::with_gap_timeout`]
+pub const OUTBOX_GAP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Prefix of the tag recording the source and offset of an ingested event
+pub const OUTBOX_TAG_PREFIX: &str = "outbox:";
+
+/// Tag recording that an event was ingested from `offset` of `source`
+pub fn outbox_tag(source: &str, offset: u64) -> String {
+    format!("{}{}@{}", OUTBOX_TAG_PREFIX, source, offset)
+}
+
+/// One row of an outbox
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxRecord {
+    /// Position in the source, increasing with every record
+    pub offset: u64,
+    /// Entity the event belongs to
+    pub entity_id: String,
+    /// Event type
+    pub event_type: String,
+    /// JSON payload
+    pub payload: Value,
+    /// Valid time of the event
+    pub timestamp: Timestamp,
+}
+
+/// Ingestion progress of an outbox source
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxCheckpoint {
+    /// Source name
+    pub source: String,
+    /// Offset of the last record ingested
+    pub offset: u64,
+    /// When the checkpoint was saved
+    pub updated_at: Timestamp,
+}
+
+/// A source of outbox records, read in offset order
+#[async_trait::async_trait]
+pub trait OutboxSource: Send + Sync {
+    /// Name identifying the source; its checkpoint and tags are keyed by it
+    fn name(&self) -> &str;
+
+    /// Up to `limit` records with an offset greater than `after` (or from
+    /// the start if `None`), in offset order
+    async fn poll(&self, after: Option<u64>, limit: usize) -> Result<Vec<OutboxRecord>>;
+}
+
+/// Outbox held in memory, for tests and embedded producers
+pub struct InMemoryOutbox {
+    name: String,
+    records: Mutex<Vec<OutboxRecord>>,
+}
+
+impl InMemoryOutbox {
+    /// Create an empty outbox
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Add a record, returning its offset
+    pub fn push(
+        &self,
+        entity_id: impl Into<String>,
+        event_type: impl Into<String>,
+        payload: Value,
+        timestamp: Timestamp,
+    ) -> u64 {
+        let mut records = self.records.lock().expect("InMemoryOutbox poisoned lock");
+        let offset = records.last().map_or(1, |r| r.offset + 1);
+        records.push(OutboxRecord {
+            offset,
+            entity_id: entity_id.into(),
+            event_type: event_type.into(),
+            payload,
+            timestamp,
+        });
+        offset
+    }
+}
+
+#[async_trait::async_trait]
+impl OutboxSource for InMemoryOutbox {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn poll(&self, after: Option<u64>, limit: usize) -> Result<Vec<OutboxRecord>> {
+        let records = self.records.lock().expect("InMemoryOutbox poisoned lock");
+        Ok(records
+            .iter()
+            .filter(|r| after.is_none_or(|after| r.offset > after))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Outbox table in PostgreSQL.
+///
+/// The table needs the columns `id BIGINT` (the offset, e.g. a
+/// `BIGSERIAL` primary key), `entity_id TEXT`, `event_type TEXT`,
+/// `payload JSONB` and `occurred_at TIMESTAMPTZ`.
+///
+/// Sequence values are taken when a row is inserted but become visible
+/// when its transaction commits, so a row can show up after rows with
+/// higher IDs. A poll therefore stops before a missing ID and waits for it
+/// for up to the gap timeout; only then is the ID taken to belong to a
+/// rolled-back transaction and skipped. Producer transactions must commit
+/// within the timeout for none of their rows to be missed.
+#[cfg(feature = "postgres")]
+pub struct PostgresOutboxSource {
+    name: String,
+    client: tokio_postgres::Client,
+    query: String,
+    gap_timeout: Duration,
+    gaps: Mutex<SequenceGaps>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresOutboxSource {
+    /// Read the outbox table `table`, optionally schema-qualified, over
+    /// `client`
+    pub fn new(
+        name: impl Into<String>,
+        client: tokio_postgres::Client,
+        table: &str,
+    ) -> Result<Self> {
+        let query = format!(
+            "SELECT id, entity_id, event_type, payload::text, \
+             (extract(epoch FROM occurred_at) * 1000000)::bigint \
+             FROM {} WHERE id > $1 ORDER BY id LIMIT $2",
+            quote_table(table)?
+        );
+        Ok(Self {
+            name: name.into(),
+            client,
+            query,
+            gap_timeout: OUTBOX_GAP_TIMEOUT,
+            gaps: Mutex::default(),
+        })
+    }
+
+    /// Wait up to `timeout` for a missing ID before skipping it
+    pub fn with_gap_timeout(mut self, timeout: Duration) -> Self {
+        self.gap_timeout = timeout;
+        self
+    }
+}
+
+/// `table`, optionally `schema.table`, quoted as an SQL identifier
+#[cfg(feature = "postgres")]
+fn quote_table(table: &str) -> Result<String> {
+    let parts: Vec<&str> = table.split('.').collect();
+    if parts.len() > 2 || parts.iter().any(|part| part.is_empty()) {
+        return Err(crate::error::Error::Configuration(format!(
+            "Invalid outbox table name: {}",
+            table
+        )));
+    }
+    let quoted: Vec<String> = parts
+        .iter()
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect();
+    Ok(quoted.join("."))
+}
+
+/// IDs a [`PostgresOutboxSource`] found missing and is waiting for
+#[cfg(feature = "postgres")]
+#[derive(Debug, Default)]
+struct SequenceGaps {
+    /// When each missing ID a poll stopped at was first seen missing
+    seen: HashMap<u64, Instant>,
+}
+
+#[cfg(feature = "postgres")]
+impl SequenceGaps {
+    /// How many of `ids`, ascending and all after `after`, can be handed
+    /// out: all of them up to the first missing ID that has not been
+    /// missing for `timeout` yet
+    fn visible(
+        &mut self,
+        after: Option<u64>,
+        ids: &[u64],
+        timeout: Duration,
+        now: Instant,
+    ) -> usize {
+        self.seen
+            .retain(|id, _| after.is_none_or(|after| *id > after));
+        let mut expected = after.map(|after| after + 1);
+        for (i, &id) in ids.iter().enumerate() {
+            if let Some(missing) = expected.filter(|expected| *expected < id) {
+                let since = *self.seen.entry(missing).or_insert(now);
+                if now.duration_since(since) < timeout {
+                    return i;
+                }
+                self.seen.remove(&missing);
+            }
+            expected = Some(id + 1);
+        }
+        ids.len()
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait::async_trait]
+impl OutboxSource for PostgresOutboxSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn poll(&self, after: Option<u64>, limit: usize) -> Result<Vec<OutboxRecord>> {
+        let pg_error = |e: tokio_postgres::Error| {
+            crate::error::Error::Network(format!("Outbox poll failed: {}", e))
+        };
+        let from = after.map_or(0, |offset| offset as i64);
+        let rows = self
+            .client
+            .query(&self.query, &[&from, &(limit as i64)])
+            .await
+            .map_err(pg_error)?;
+        let ids = rows
+            .iter()
+            .map(|row| row.try_get::<_, i64>(0).map(|id| id as u64))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(pg_error)?;
+        let visible = self
+            .gaps
+            .lock()
+            .expect("PostgresOutboxSource poisoned gaps lock")
+            .visible(after, &ids, self.gap_timeout, Instant::now());
+        rows[..visible]
+            .iter()
+            .map(|row| {
+                let offset: i64 = row.try_get(0).map_err(pg_error)?;
+                let payload: String = row.try_get(3).map_err(pg_error)?;
+                let micros: i64 = row.try_get(4).map_err(pg_error)?;
+                Ok(OutboxRecord {
+                    offset: offset as u64,
+                    entity_id: row.try_get(1).map_err(pg_error)?,
+                    event_type: row.try_get(2).map_err(pg_error)?,
+                    payload: serde_json::from_str(&payload)?,
+                    timestamp: Timestamp::from_nanos(micros.saturating_mul(1000)),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_in_memory_outbox_polls_after_offset() {
+        let outbox = InMemoryOutbox::new("orders");
+        for i in 0..5 {
+            outbox.push(
+                "order:1",
+                "order.updated",
+                json!(i),
+                Timestamp::from_secs(i),
+            );
+        }
+        let first = outbox.poll(None, 2).await.unwrap();
+        assert_eq!(first.iter().map(|r| r.offset).collect::<Vec<_>>(), [1, 2]);
+        let rest = outbox.poll(Some(2), 10).await.unwrap();
+        assert_eq!(rest.iter().map(|r| r.offset).collect::<Vec<_>>(), [3, 4, 5]);
+        assert_eq!(outbox_tag("orders", 3), "outbox:orders@3");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_postgres_outbox_waits_for_missing_ids() {
+        let timeout = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut gaps = SequenceGaps::default();
+        // 3 is taken by a transaction that has not committed yet.
+        assert_eq!(gaps.visible(Some(1), &[2, 4, 5], timeout, start), 1);
+        assert_eq!(gaps.visible(Some(2), &[4, 5], timeout, start), 0);
+        let later = start + Duration::from_secs(10);
+        assert_eq!(gaps.visible(Some(2), &[3, 4, 5], timeout, later), 3);
+        // 7 never commits: it is skipped once the timeout passed.
+        assert_eq!(gaps.visible(Some(5), &[6, 8], timeout, later), 1);
+        assert_eq!(gaps.visible(Some(6), &[8], timeout, later), 0);
+        assert_eq!(gaps.visible(Some(6), &[8], timeout, later + timeout), 1);
+        assert!(gaps.seen.is_empty());
+
+        assert_eq!(quote_table("outbox").unwrap(), "\"outbox\"");
+        assert_eq!(
+            quote_table("app.out\"box").unwrap(),
+            "\"app\".\"out\"\"box\""
+        );
+        assert!(quote_table("a.b.c").is_err());
+        assert_eq!(
+            quote_table("outbox; DROP TABLE x").unwrap(),
+            "\"outbox; DROP TABLE x\""
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Approximate query operators.
+//!
+//! Exploratory queries over long histories rarely need exact answers. These
+//! operators make a single pass over the events in fixed memory:
+//!
+//! - [`ReservoirSampler`]: a uniform random sample of fixed size;
+//! - [`HyperLogLog`]: distinct counts within a few percent;
+//! - [`TDigest`]: percentiles, most accurate at the tails.
+//!
+//! Sketches of disjoint inputs can be merged, e.g. across shards.
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Default HyperLogLog precision: 2^14 registers, about 0.8% standard error
+pub const DEFAULT_HLL_PRECISION: u8 = 14;
+
+/// Default t-digest compression: about 100 centroids
+pub const DEFAULT_TDIGEST_COMPRESSION: f64 = 100.0;
+
+/// SplitMix64 step, used both as a generator and as a hash finalizer
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Stable 64-bit hash of `data`, so sketches built on different nodes can
+/// be merged
+fn hash64(data: &[u8]) -> u64 {
+    // FNV-1a, then mixed so all bits are usable.
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    splitmix64(hash)
+}
+
+/// Uniform random sample of at most `capacity` items (Algorithm R)
+#[derive(Debug, Clone)]
+pub struct ReservoirSampler<T> {
+    capacity: usize,
+    seen: u64,
+    state: u64,
+    items: Vec<T>,
+}
+
+impl<T> ReservoirSampler<T> {
+    /// Sampler keeping `capacity` items, seeded from the clock
+    pub fn new(capacity: usize) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self::with_seed(capacity, seed)
+    }
+
+    /// Sampler keeping `capacity` items with a reproducible `seed`
+    pub fn with_seed(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            state: seed,
+            items: Vec::with_capacity(capacity.min(1024)),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        splitmix64(self.state)
+    }
+
+    /// Offer an item; it is kept with probability `capacity / seen`
+    pub fn add(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return;
+        }
+        let slot = self.next_random() % self.seen;
+        if slot < self.capacity as u64 {
+            self.items[slot as usize] = item;
+        }
+    }
+
+    /// Number of items offered
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// The sample, in no particular order
+    pub fn into_sample(self) -> Vec<T> {
+        self.items
+    }
+}
+
+/// HyperLogLog distinct-count sketch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Sketch with 2^`precision` registers; precision must be 4 to 18
+    pub fn new(precision: u8) -> Result<Self> {
+        if !(4..=18).contains(&precision) {
+            return Err(Error::Query(format!(
+                "HyperLogLog precision must be 4 to 18, got {}",
+                precision
+            )));
+        }
+        Ok(Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        })
+    }
+
+    /// Record `item`
+    pub fn add(&mut self, item: &[u8]) {
+        let hash = hash64(item);
+        let index = (hash >> (64 - self.precision)) as usize;
+        // Rank of the first set bit in the remaining bits, capped so an
+        // all-zero remainder still counts.
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() + 1).min(64 - self.precision as u32 + 1) as u8;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Record the string `item`
+    pub fn add_str(&mut self, item: &str) {
+        self.add(item.as_bytes());
+    }
+
+    /// Fold in a sketch of another input with the same precision
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        if other.precision != self.precision {
+            return Err(Error::Query(format!(
+                "Cannot merge HyperLogLog sketches of precision {} and {}",
+                self.precision, other.precision
+            )));
+        }
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+        Ok(())
+    }
+
+    /// Estimated number of distinct items recorded
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // Linear counting is more accurate for small cardinalities.
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as u64
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_HLL_PRECISION).expect("default precision is valid")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// t-digest percentile sketch (merging variant)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    /// Digest keeping about `compression` centroids
+    pub fn new(compression: f64) -> Self {
+        Self {
+            compression: compression.max(10.0),
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Record `value`; NaN is ignored
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= 5 * self.compression as usize {
+            self.compress();
+        }
+    }
+
+    /// Fold in a digest of another input
+    pub fn merge(&mut self, other: &Self) {
+        self.centroids.extend(&other.centroids);
+        self.buffer.extend(&other.buffer);
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.compress();
+    }
+
+    /// Number of values recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    fn compress(&mut self) {
+        let mut all: Vec<Centroid> = std::mem::take(&mut self.centroids);
+        all.extend(
+            self.buffer
+                .drain(..)
+                .map(|mean| Centroid { mean, weight: 1.0 }),
+        );
+        if all.is_empty() {
+            return;
+        }
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+
+        let mut merged = Vec::with_capacity(self.compression as usize * 2);
+        let mut current = all[0];
+        let mut before = 0.0;
+        for next in all.into_iter().skip(1) {
+            let q = (before + current.weight + next.weight / 2.0) / total;
+            // Centroids near the median may hold more weight than those at
+            // the tails.
+            let limit = (4.0 * total * q * (1.0 - q) / self.compression).max(1.0);
+            if current.weight + next.weight <= limit {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                before += current.weight;
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Estimated value at quantile `q` (0 to 1), or `None` if no values
+    /// were recorded
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.compress();
+        if self.centroids.is_empty() {
+            return None;
+        }
+        let q = q.clamp(0.0, 1.0);
+        let total = self.count as f64;
+        let target = q * total;
+        if self.centroids.len() == 1 || target <= 0.0 {
+            return Some(if q >= 1.0 { self.max } else { self.min });
+        }
+
+        // Interpolate between centroid centers, treating min and max as
+        // zero-width centroids at the ends.
+        let mut previous = (0.0, self.min);
+        let mut cumulative = 0.0;
+        for centroid in &self.centroids {
+            let center = cumulative + centroid.weight / 2.0;
+            if target <= center {
+                let (prev_rank, prev_value) = previous;
+                let span = center - prev_rank;
+                let t = if span > 0.0 {
+                    (target - prev_rank) / span
+                } else {
+                    0.0
+                };
+                return Some(prev_value + t * (centroid.mean - prev_value));
+            }
+            previous = (center, centroid.mean);
+            cumulative += centroid.weight;
+        }
+        let (prev_rank, prev_value) = previous;
+        let span = total - prev_rank;
+        let t = if span > 0.0 {
+            (target - prev_rank) / span
+        } else {
+            1.0
+        };
+        Some(prev_value + t * (self.max - prev_value))
+    }
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        Self::new(DEFAULT_TDIGEST_COMPRESSION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sketches_are_close() {
+        let mut sampler = ReservoirSampler::with_seed(100, 7);
+        for i in 0..10_000u32 {
+            sampler.add(i);
+        }
+        assert_eq!(sampler.seen(), 10_000);
+        let sample = sampler.into_sample();
+        assert_eq!(sample.len(), 100);
+        // A uniform sample is spread over the whole input.
+        assert!(sample.iter().any(|i| *i < 5_000) && sample.iter().any(|i| *i >= 5_000));
+
+        let mut hll = HyperLogLog::default();
+        let mut other = HyperLogLog::default();
+        for i in 0..50_000 {
+            hll.add_str(&format!("user:{}", i));
+            other.add_str(&format!("user:{}", i + 25_000));
+        }
+        let error = |estimate: u64, exact: f64| (estimate as f64 - exact).abs() / exact;
+        assert!(error(hll.estimate(), 50_000.0) < 0.03);
+        hll.merge(&other).unwrap();
+        assert!(error(hll.estimate(), 75_000.0) < 0.03);
+        assert!(hll.merge(&HyperLogLog::new(10).unwrap()).is_err());
+
+        let mut digest = TDigest::default();
+        for i in 1..=10_000 {
+            digest.add(i as f64);
+        }
+        let median = digest.quantile(0.5).unwrap();
+        assert!((median - 5_000.0).abs() < 100.0, "{}", median);
+        let p99 = digest.quantile(0.99).unwrap();
+        assert!((p99 - 9_900.0).abs() < 20.0, "{}", p99);
+        assert_eq!(digest.quantile(0.0), Some(1.0));
+        assert_eq!(digest.quantile(1.0), Some(10_000.0));
+        assert_eq!(TDigest::default().quantile(0.5), None);
+    }
+}
@@ -1,12 +1,346 @@
 //! Query executor
+//!
+//! Executes parsed [`TemporalQuery`]s against an [`EventJournal`], reading
+//! it along the access path of a [`QueryPlan`]. Operators
+//! that buffer rows account for them with the query's [`MemoryTracker`]: the
+//! sort and group-by spill to disk once the budget is used up, and the query
+//! fails with an [`Error::Query`] at the hard cap. Range reads can also be
+//! consumed incrementally as a [`QueryStream`].
 
-use crate::error::Result;
-use crate::query::parser::TemporalQuery;
+use crate::core::event::{Event, EventId};
+use crate::core::namespace::is_internal_entity;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::index::IndexEntry;
+use crate::query::memory::{ExternalSorter, MemoryTracker, SpillingGroupBy};
+use crate::query::optimizer::{query_bounds, AccessPath, QueryPlan};
+use crate::query::parser::{QueryType, TemporalQuery};
+use crate::storage::EventJournal;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
-/// Execute a temporal query
-pub async fn execute_query(_query: &TemporalQuery) -> Result<()> {
-    // TODO: Implement query execution
-    Err(crate::error::Error::Query(
-        "Query executor not yet implemented".to_string(),
-    ))
+/// Events a [`QueryStream`] reads from the journal at a time
+pub const STREAM_PAGE_SIZE: usize = 1024;
+
+/// Query results read from the journal a page at a time as they are polled
+pub type QueryStream = BoxStream<'static, Result<Event>>;
+
+type EventOrder = fn(&Event, &Event) -> Ordering;
+
+/// Order of query results: by timestamp, ties broken by event ID
+fn timeline_order(a: &Event, b: &Event) -> Ordering {
+    a.timestamp()
+        .cmp(&b.timestamp())
+        .then_with(|| a.id().cmp(&b.id()))
+}
+
+/// Whether `query`'s time range, entity, event type and tags select
+/// `event`; payload predicates are not checked
+pub fn query_selects(query: &TemporalQuery, event: &Event) -> bool {
+    let (start, end) = query_bounds(query);
+    let ts = event.timestamp();
+    ts >= start
+        && ts < end
+        && query
+            .entity_id
+            .as_ref()
+            .is_none_or(|id| event.entity_id() == id)
+        && query
+            .entity_prefix
+            .as_ref()
+            .is_none_or(|prefix| event.entity_id().starts_with(prefix.as_str()))
+        && query
+            .event_type
+            .as_ref()
+            .is_none_or(|t| event.event_type() == t)
+        && (query.tags.is_empty() || event.metadata.tags.iter().any(|t| query.tags.contains(t)))
+        && query
+            .tag_filter
+            .as_ref()
+            .is_none_or(|f| f.matches(&event.metadata.tags))
+}
+
+/// Read the events of each entity `entries` list, over the times of its
+/// listed events. Other events of those entities in between are read too.
+pub async fn read_indexed(
+    journal: &dyn EventJournal,
+    entries: &[IndexEntry],
+) -> Result<Vec<Event>> {
+    let mut spans: BTreeMap<&str, (Timestamp, Timestamp)> = BTreeMap::new();
+    for entry in entries {
+        let ts = entry.timestamp;
+        let span = spans.entry(&entry.entity_id).or_insert((ts, ts));
+        *span = (span.0.min(ts), span.1.max(ts));
+    }
+    let mut events = Vec::new();
+    for (entity_id, (first, last)) in spans {
+        let end = Timestamp::from_nanos(last.as_nanos().saturating_add(1));
+        events.extend(journal.get_events(entity_id, first, end).await?);
+    }
+    Ok(events)
+}
+
+/// Execute a `SELECT`: the user events matching the query's entity and time
+/// range that `allows` accepts, in timestamp order
+pub async fn execute_query<F>(
+    journal: &dyn EventJournal,
+    query: &TemporalQuery,
+    tracker: Arc<MemoryTracker>,
+    allows: F,
+) -> Result<Vec<Event>>
+where
+    F: Fn(&Event) -> bool + Send + Sync,
+{
+    let plan = QueryPlan::unplanned(query);
+    execute_plan(journal, query, &plan, tracker, allows).await
+}
+
+/// Execute a `SELECT` reading the journal along `plan`: the user events
+/// matching every predicate of the query that `allows` accepts, in
+/// timestamp order
+pub async fn execute_plan<F>(
+    journal: &dyn EventJournal,
+    query: &TemporalQuery,
+    plan: &QueryPlan,
+    tracker: Arc<MemoryTracker>,
+    allows: F,
+) -> Result<Vec<Event>>
+where
+    F: Fn(&Event) -> bool + Send + Sync,
+{
+    if !matches!(query.query_type, QueryType::Select) {
+        return Err(Error::Query(format!(
+            "Only SELECT queries can be executed, got {:?}",
+            query.query_type
+        )));
+    }
+    let (start, end) = query_bounds(query);
+    let selects = |event: &Event| {
+        query_selects(query, event) && !is_internal_entity(event.entity_id()) && allows(event)
+    };
+    let satisfies_text = |event: &Event| query.text_matches.iter().all(|t| t.matches_event(event));
+    let satisfies_fields = |event: &Event| {
+        query
+            .field_predicates
+            .iter()
+            .all(|p| p.matches_event(event))
+            && satisfies_text(event)
+    };
+    let mut sorter = ExternalSorter::new(tracker, timeline_order as EventOrder);
+    let candidates = match &plan.access {
+        AccessPath::EntityTimeline(entity_id) => journal.get_entity_events(entity_id).await?,
+        AccessPath::TypeIndex(event_type) => {
+            journal.get_events_by_type(event_type, start, end).await?
+        }
+        AccessPath::TagIndex { entities } => {
+            let mut events = Vec::new();
+            for entity_id in entities {
+                events.extend(journal.get_entity_events(entity_id).await?);
+            }
+            events
+        }
+        AccessPath::FieldIndex { entries, .. } | AccessPath::TextIndex { entries } => {
+            read_indexed(journal, entries).await?
+        }
+        AccessPath::SegmentScan => {
+            let mut failed = None;
+            // Payload predicates are evaluated by the journal as it decodes.
+            let predicates = &query.field_predicates;
+            journal
+                .scan_range_where(start, end, predicates, &mut |event| {
+                    if failed.is_some() || !selects(event) || !satisfies_text(event) {
+                        return;
+                    }
+                    if let Err(e) = sorter.push(event.clone()) {
+                        failed = Some(e);
+                    }
+                })
+                .await?;
+            if let Some(e) = failed {
+                return Err(e);
+            }
+            Vec::new()
+        }
+    };
+    for event in candidates {
+        if selects(&event) && satisfies_fields(&event) {
+            sorter.push(event)?;
+        }
+    }
+    sorter.finish()?.collect()
+}
+
+/// Number of user events accepted by `allows` per entity, over events with a
+/// timestamp in `[start, end)`, in entity order
+pub async fn count_events_by_entity<F>(
+    journal: &dyn EventJournal,
+    start: Timestamp,
+    end: Timestamp,
+    tracker: Arc<MemoryTracker>,
+    allows: F,
+) -> Result<Vec<(String, u64)>>
+where
+    F: Fn(&Event) -> bool + Send + Sync,
+{
+    let add: fn(&mut u64, u64) = |count, n| *count += n;
+    let mut groups = SpillingGroupBy::new(tracker, add);
+    let mut failed = None;
+    journal
+        .scan_range(start, end, &mut |event| {
+            if failed.is_some() || is_internal_entity(event.entity_id()) || !allows(event) {
+                return;
+            }
+            if let Err(e) = groups.add(event.entity_id().to_string(), 1) {
+                failed = Some(e);
+            }
+        })
+        .await?;
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    groups.finish()?.collect()
+}
+
+/// Position of a [`stream_entity_range`] in the entity's timeline
+struct RangeCursor {
+    journal: Arc<RwLock<dyn EventJournal>>,
+    entity_id: String,
+    /// Timestamp of the last event read, where the next page starts
+    start: Timestamp,
+    end: Timestamp,
+    /// Events read so far with timestamp `start`
+    read_at_start: HashSet<EventId>,
+    done: bool,
+}
+
+impl RangeCursor {
+    /// Read the next page, or `None` past the end of the range
+    async fn next_page(mut self) -> Result<Option<(Vec<Event>, Self)>> {
+        if self.done {
+            return Ok(None);
+        }
+        // Re-read the events at `start` read before, so ties never stall.
+        let limit = STREAM_PAGE_SIZE + self.read_at_start.len();
+        let page = self
+            .journal
+            .read()
+            .await
+            .get_events_page(&self.entity_id, self.start, self.end, limit)
+            .await?;
+        self.done = page.len() < limit;
+        let mut fresh = Vec::with_capacity(page.len());
+        for event in page {
+            if event.timestamp() != self.start {
+                self.start = event.timestamp();
+                self.read_at_start.clear();
+            }
+            if self.read_at_start.insert(event.id()) {
+                fresh.push(event);
+            }
+        }
+        Ok(Some((fresh, self)))
+    }
+}
+
+/// Stream the events of `entity_id` in `[start, end)` that `allows`
+/// accepts, in the journal's timeline order.
+///
+/// A page of [`STREAM_PAGE_SIZE`] events is read only once the previous one
+/// has been consumed, holding the journal lock just for the read, so a
+/// slow consumer holds back neither memory nor writers. Events committed
+/// while streaming are included if they sort after the current position.
+pub fn stream_entity_range<F>(
+    journal: Arc<RwLock<dyn EventJournal>>,
+    entity_id: String,
+    start: Timestamp,
+    end: Timestamp,
+    allows: F,
+) -> QueryStream
+where
+    F: Fn(&Event) -> bool + Send + Sync + 'static,
+{
+    let cursor = RangeCursor {
+        journal,
+        entity_id,
+        start,
+        end,
+        read_at_start: HashSet::new(),
+        done: false,
+    };
+    stream::try_unfold(cursor, RangeCursor::next_page)
+        .map_ok(|page| stream::iter(page.into_iter().map(Ok)))
+        .try_flatten()
+        .try_filter(move |event| std::future::ready(allows(event)))
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::query::memory::MemoryBudget;
+    use crate::query::parser::TimeRange;
+    use crate::storage::InMemoryJournal;
+
+    #[tokio::test]
+    async fn test_select_spills_and_counts_by_entity() {
+        let mut journal = InMemoryJournal::new();
+        for i in 0..50i64 {
+            let ts = Timestamp::from_secs(1000 + (i * 7) % 50);
+            let entity = format!("sensor:{}", i % 5);
+            let payload = EventPayload::from_json(&i).unwrap();
+            let event = Event::new("reading".to_string(), ts, entity, payload);
+            journal.append(event).await.unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MemoryTracker::new(MemoryBudget::new(1024).with_spill_dir(dir.path()));
+        let from = TimeRange::From(Timestamp::from_secs(1010).as_nanos());
+        let query = TemporalQuery::select().with_time_range(from);
+
+        let events = execute_query(&journal, &query, tracker.clone(), |_| true)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 40);
+        assert!(events
+            .windows(2)
+            .all(|w| timeline_order(&w[0], &w[1]) == Ordering::Less));
+        assert!(tracker.spilled_bytes() > 0);
+
+        let start = Timestamp::from_secs(0);
+        let counts = count_events_by_entity(&journal, start, Timestamp::now(), tracker, |_| true)
+            .await
+            .unwrap();
+        let expected: Vec<_> = (0..5).map(|i| (format!("sensor:{}", i), 10)).collect();
+        assert_eq!(counts, expected);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reads_pages_across_ties() {
+        let mut journal = InMemoryJournal::new();
+        // Three events per timestamp, so ties straddle page boundaries.
+        for i in 0..2500i64 {
+            let ts = Timestamp::from_secs(1000 + i / 3);
+            let entity = format!("sensor:{}", i % 2);
+            let payload = EventPayload::from_json(&i).unwrap();
+            let event = Event::new("reading".to_string(), ts, entity, payload);
+            journal.append(event).await.unwrap();
+        }
+        let journal: Arc<RwLock<dyn EventJournal>> = Arc::new(RwLock::new(journal));
+
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::now());
+        let stream = stream_entity_range(journal, "sensor:0".to_string(), start, end, |e| {
+            e.payload().to_json::<i64>().unwrap() % 10 != 0
+        });
+        let events: Vec<Event> = stream.try_collect().await.unwrap();
+        assert_eq!(events.len(), 1000);
+        let ids: HashSet<_> = events.iter().map(Event::id).collect();
+        assert_eq!(ids.len(), events.len());
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].timestamp() <= w[1].timestamp()));
+    }
 }
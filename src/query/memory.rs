@@ -0,0 +1,557 @@
+//! Per-query memory accounting and spill-to-disk operators.
+//!
+//! Every query gets a [`MemoryTracker`] shared by its operators. Operators
+//! reserve memory for what they buffer, estimated by serialized size. When
+//! a reservation would exceed the [`MemoryBudget`], sort and group-by
+//! operators write their buffer to a sorted run on disk and continue; the
+//! runs are merged when results are read. A reservation that still does
+//! not fit, or any overflow with spilling disabled, aborts the query with
+//! an [`Error::Query`] instead of growing without bound.
+
+use crate::error::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Default memory limit of a query
+pub const DEFAULT_QUERY_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Memory a query may use and where it may spill
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// Bytes of buffered data a query may hold
+    pub limit: usize,
+    /// Directory for spill files; `None` disables spilling
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl MemoryBudget {
+    /// `limit` bytes, spilling to the system temporary directory
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            spill_dir: Some(std::env::temp_dir()),
+        }
+    }
+
+    /// Spill to `dir`
+    pub fn with_spill_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spill_dir = Some(dir.into());
+        self
+    }
+
+    /// Abort instead of spilling once the limit is reached
+    pub fn without_spilling(mut self) -> Self {
+        self.spill_dir = None;
+        self
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUERY_MEMORY_LIMIT)
+    }
+}
+
+/// Memory accounting shared by the operators of one query
+#[derive(Debug)]
+pub struct MemoryTracker {
+    budget: MemoryBudget,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    spilled: AtomicU64,
+}
+
+impl MemoryTracker {
+    /// Tracker enforcing `budget`
+    pub fn new(budget: MemoryBudget) -> Arc<Self> {
+        Arc::new(Self {
+            budget,
+            used: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            spilled: AtomicU64::new(0),
+        })
+    }
+
+    /// Budget being enforced
+    pub fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    /// Reserve `bytes` if they fit in the budget
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        let reserved =
+            self.used
+                .fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |used| {
+                    used.checked_add(bytes)
+                        .filter(|total| *total <= self.budget.limit)
+                });
+        match reserved {
+            Ok(used) => {
+                self.peak.fetch_max(used + bytes, AtomicOrdering::Relaxed);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Reserve `bytes`, failing the query if they do not fit
+    pub fn reserve(&self, bytes: usize) -> Result<()> {
+        if self.try_reserve(bytes) {
+            Ok(())
+        } else {
+            Err(self.exceeded(bytes, ""))
+        }
+    }
+
+    /// Return `bytes` reserved earlier
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, AtomicOrdering::AcqRel);
+    }
+
+    /// Bytes currently reserved
+    pub fn used(&self) -> usize {
+        self.used.load(AtomicOrdering::Acquire)
+    }
+
+    /// Most bytes reserved at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Bytes written to spill files
+    pub fn spilled_bytes(&self) -> u64 {
+        self.spilled.load(AtomicOrdering::Relaxed)
+    }
+
+    fn exceeded(&self, bytes: usize, reason: &str) -> Error {
+        Error::Query(format!(
+            "Query memory limit of {} bytes exceeded: {} bytes in use, {} more needed{}",
+            self.budget.limit,
+            self.used(),
+            bytes,
+            reason
+        ))
+    }
+
+    /// Directory to spill to, or the limit error if spilling is disabled
+    fn spill_dir(&self, bytes: usize) -> Result<&PathBuf> {
+        self.budget
+            .spill_dir
+            .as_ref()
+            .ok_or_else(|| self.exceeded(bytes, "; spilling is disabled"))
+    }
+}
+
+/// Memory reserved by an operator, released when dropped
+struct Reservation {
+    tracker: Arc<MemoryTracker>,
+    bytes: usize,
+}
+
+impl Reservation {
+    fn new(tracker: Arc<MemoryTracker>) -> Self {
+        Self { tracker, bytes: 0 }
+    }
+
+    /// Grow by `bytes`, or return false if the budget has no room
+    fn try_grow(&mut self, bytes: usize) -> bool {
+        let reserved = self.tracker.try_reserve(bytes);
+        if reserved {
+            self.bytes += bytes;
+        }
+        reserved
+    }
+
+    fn grow(&mut self, bytes: usize) -> Result<()> {
+        self.tracker.reserve(bytes)?;
+        self.bytes += bytes;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.tracker.release(std::mem::take(&mut self.bytes));
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Serialized size used to account for an item
+fn item_size<T: Serialize>(item: &T) -> Result<usize> {
+    Ok(bincode::serialized_size(item)? as usize)
+}
+
+/// Sorted run of items on disk, deleted when dropped
+struct SpillRun {
+    path: PathBuf,
+}
+
+impl SpillRun {
+    /// Write `items` as length-prefixed bincode records
+    fn write<T: Serialize>(tracker: &MemoryTracker, items: &[T], bytes: usize) -> Result<Self> {
+        let dir = tracker.spill_dir(bytes)?;
+        std::fs::create_dir_all(dir)?;
+        let run = Self {
+            path: dir.join(format!("query-spill-{}.run", Uuid::new_v4())),
+        };
+        let mut writer = BufWriter::new(File::create(&run.path)?);
+        let mut written = 0;
+        for item in items {
+            let data = bincode::serialize(item)?;
+            writer.write_all(&(data.len() as u32).to_le_bytes())?;
+            writer.write_all(&data)?;
+            written += 4 + data.len() as u64;
+        }
+        writer.flush()?;
+        tracker.spilled.fetch_add(written, AtomicOrdering::Relaxed);
+        Ok(run)
+    }
+
+    fn reader<T: DeserializeOwned>(self) -> Result<RunReader<T>> {
+        Ok(RunReader {
+            reader: BufReader::new(File::open(&self.path)?),
+            _run: self,
+            _item: PhantomData,
+        })
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+struct RunReader<T> {
+    reader: BufReader<File>,
+    _run: SpillRun,
+    _item: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> RunReader<T> {
+    fn next_item(&mut self) -> Result<Option<T>> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(bincode::deserialize(&data)?))
+    }
+}
+
+enum RunSource<T> {
+    Memory(std::vec::IntoIter<T>),
+    Disk(RunReader<T>),
+}
+
+impl<T: DeserializeOwned> RunSource<T> {
+    fn next_item(&mut self) -> Result<Option<T>> {
+        match self {
+            Self::Memory(items) => Ok(items.next()),
+            Self::Disk(reader) => reader.next_item(),
+        }
+    }
+}
+
+/// Sort operator that spills sorted runs to disk when over budget
+pub struct ExternalSorter<T, F> {
+    reservation: Reservation,
+    compare: F,
+    buffer: Vec<T>,
+    runs: Vec<SpillRun>,
+}
+
+impl<T, F> ExternalSorter<T, F>
+where
+    T: Serialize + DeserializeOwned,
+    F: Fn(&T, &T) -> Ordering,
+{
+    /// Sorter ordering items by `compare`, accounting to `tracker`
+    pub fn new(tracker: Arc<MemoryTracker>, compare: F) -> Self {
+        Self {
+            reservation: Reservation::new(tracker),
+            compare,
+            buffer: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    /// Add an item, spilling the buffered items first if it does not fit
+    pub fn push(&mut self, item: T) -> Result<()> {
+        let size = item_size(&item)?;
+        if !self.reservation.try_grow(size) {
+            self.spill(size)?;
+            self.reservation.grow(size)?;
+        }
+        self.buffer.push(item);
+        Ok(())
+    }
+
+    /// Number of runs spilled to disk so far
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self, needed: usize) -> Result<()> {
+        let tracker = &self.reservation.tracker;
+        if self.buffer.is_empty() {
+            return Err(tracker.exceeded(needed, ""));
+        }
+        self.buffer.sort_by(&self.compare);
+        let run = SpillRun::write(tracker, &self.buffer, needed)?;
+        self.runs.push(run);
+        self.buffer.clear();
+        self.reservation.clear();
+        Ok(())
+    }
+
+    /// Sort the remaining buffer and merge it with the spilled runs
+    pub fn finish(mut self) -> Result<SortedIter<T, F>> {
+        self.buffer.sort_by(&self.compare);
+        SortedIter::new(self.reservation, self.compare, self.buffer, self.runs)
+    }
+}
+
+/// Sorted output of an [`ExternalSorter`]
+pub struct SortedIter<T, F> {
+    _reservation: Reservation,
+    compare: F,
+    sources: Vec<RunSource<T>>,
+    heads: Vec<Option<T>>,
+}
+
+impl<T: DeserializeOwned, F> SortedIter<T, F> {
+    /// Merge the sorted `buffer` with the sorted `runs`
+    fn new(
+        reservation: Reservation,
+        compare: F,
+        buffer: Vec<T>,
+        runs: Vec<SpillRun>,
+    ) -> Result<Self> {
+        let mut sources = vec![RunSource::Memory(buffer.into_iter())];
+        for run in runs {
+            sources.push(RunSource::Disk(run.reader()?));
+        }
+        let heads = sources
+            .iter_mut()
+            .map(RunSource::next_item)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            _reservation: reservation,
+            compare,
+            sources,
+            heads,
+        })
+    }
+}
+
+impl<T, F> Iterator for SortedIter<T, F>
+where
+    T: DeserializeOwned,
+    F: Fn(&T, &T) -> Ordering,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(item) = head else { continue };
+            let smaller = min.is_none_or(|m| {
+                let current = self.heads[m].as_ref().expect("min head is set");
+                (self.compare)(item, current) == Ordering::Less
+            });
+            if smaller {
+                min = Some(i);
+            }
+        }
+        let i = min?;
+        let item = self.heads[i].take();
+        match self.sources[i].next_item() {
+            Ok(next) => self.heads[i] = next,
+            Err(e) => return Some(Err(e)),
+        }
+        item.map(Ok)
+    }
+}
+
+type KeyOrder<K, A> = fn(&(K, A), &(K, A)) -> Ordering;
+
+/// Group-by operator that spills partial aggregates to disk when over
+/// budget.
+///
+/// Each group's accumulator is accounted when the group is created;
+/// accumulators are assumed not to grow as values are merged into them.
+pub struct SpillingGroupBy<K, A, M> {
+    reservation: Reservation,
+    merge: M,
+    groups: HashMap<K, A>,
+    runs: Vec<SpillRun>,
+}
+
+impl<K, A, M> SpillingGroupBy<K, A, M>
+where
+    K: Eq + Hash + Ord + Serialize + DeserializeOwned,
+    A: Serialize + DeserializeOwned,
+    M: Fn(&mut A, A),
+{
+    /// Group-by combining values of the same key with `merge`
+    pub fn new(tracker: Arc<MemoryTracker>, merge: M) -> Self {
+        Self {
+            reservation: Reservation::new(tracker),
+            merge,
+            groups: HashMap::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    /// Merge `value` into the group of `key`
+    pub fn add(&mut self, key: K, value: A) -> Result<()> {
+        if let Some(acc) = self.groups.get_mut(&key) {
+            (self.merge)(acc, value);
+            return Ok(());
+        }
+        let size = item_size(&(&key, &value))?;
+        if !self.reservation.try_grow(size) {
+            self.spill(size)?;
+            self.reservation.grow(size)?;
+        }
+        self.groups.insert(key, value);
+        Ok(())
+    }
+
+    /// Number of runs spilled to disk so far
+    pub fn spilled_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sorted_groups(&mut self) -> Vec<(K, A)> {
+        let mut groups: Vec<_> = self.groups.drain().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        groups
+    }
+
+    fn spill(&mut self, needed: usize) -> Result<()> {
+        if self.groups.is_empty() {
+            return Err(self.reservation.tracker.exceeded(needed, ""));
+        }
+        let groups = self.sorted_groups();
+        let run = SpillRun::write(&self.reservation.tracker, &groups, needed)?;
+        self.runs.push(run);
+        self.reservation.clear();
+        Ok(())
+    }
+
+    /// Merge the groups in memory with the spilled ones; groups come out in
+    /// key order
+    pub fn finish(mut self) -> Result<Grouped<K, A, M>> {
+        let groups = self.sorted_groups();
+        let compare: KeyOrder<K, A> = |a, b| a.0.cmp(&b.0);
+        Ok(Grouped {
+            sorted: SortedIter::new(self.reservation, compare, groups, self.runs)?,
+            merge: self.merge,
+            pending: None,
+        })
+    }
+}
+
+/// Groups produced by a [`SpillingGroupBy`], in key order
+pub struct Grouped<K, A, M> {
+    sorted: SortedIter<(K, A), KeyOrder<K, A>>,
+    merge: M,
+    pending: Option<(K, A)>,
+}
+
+impl<K, A, M> Iterator for Grouped<K, A, M>
+where
+    K: Ord + DeserializeOwned,
+    A: DeserializeOwned,
+    M: Fn(&mut A, A),
+{
+    type Item = Result<(K, A)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut group = match self.pending.take() {
+            Some(group) => group,
+            None => match self.sorted.next()? {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        loop {
+            match self.sorted.next() {
+                Some(Ok((key, value))) if key == group.0 => (self.merge)(&mut group.1, value),
+                Some(Ok(next)) => {
+                    self.pending = Some(next);
+                    break;
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            }
+        }
+        Some(Ok(group))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_and_group_by_spill_under_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = MemoryTracker::new(MemoryBudget::new(64).with_spill_dir(dir.path()));
+
+        let mut sorter = ExternalSorter::new(tracker.clone(), |a: &u64, b: &u64| b.cmp(a));
+        for i in 0..100u64 {
+            sorter.push((i * 37) % 100).unwrap();
+        }
+        assert!(sorter.spilled_runs() > 1);
+        let sorted: Vec<u64> = sorter.finish().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(sorted, (0..100).rev().collect::<Vec<_>>());
+
+        let mut groups = SpillingGroupBy::new(tracker.clone(), |acc: &mut u64, v| *acc += v);
+        for i in 0..200u64 {
+            groups.add(i % 20, 1).unwrap();
+        }
+        assert!(groups.spilled_runs() > 0);
+        let counts: Vec<(u64, u64)> = groups.finish().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(counts, (0..20).map(|k| (k, 10)).collect::<Vec<_>>());
+
+        assert_eq!(tracker.used(), 0);
+        assert!(tracker.peak() <= 64);
+        assert!(tracker.spilled_bytes() > 0);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_hard_cap_aborts_query() {
+        let tracker = MemoryTracker::new(MemoryBudget::new(16).without_spilling());
+        let mut sorter = ExternalSorter::new(tracker.clone(), |a: &u64, b: &u64| a.cmp(b));
+        sorter.push(1).unwrap();
+        sorter.push(2).unwrap();
+        let err = sorter.push(3).unwrap_err().to_string();
+        assert!(err.contains("memory limit of 16 bytes exceeded"), "{}", err);
+        assert!(err.contains("spilling is disabled"), "{}", err);
+
+        // A single value larger than the budget cannot be spilled either.
+        let spilling = MemoryTracker::new(MemoryBudget::new(8));
+        let mut sorter = ExternalSorter::new(spilling, |a: &String, b: &String| a.cmp(b));
+        assert!(sorter.push("x".repeat(100)).is_err());
+    }
+}
@@ -1,9 +1,21 @@
 //! Query engine for temporal queries
 
+pub mod approx;
 pub mod executor;
+pub mod memory;
 pub mod optimizer;
 pub mod parser;
+pub mod prepared;
+pub mod result_cache;
+pub mod security;
+pub mod window;
 
+pub use approx::*;
 pub use executor::*;
+pub use memory::*;
 pub use optimizer::*;
 pub use parser::*;
+pub use prepared::*;
+pub use result_cache::*;
+pub use security::*;
+pub use window::*;
@@ -1,10 +1,801 @@
 //! Query optimizer
+//!
+//! Chooses how a `SELECT` reads the journal. Each [`PlanRule`] proposes a
+//! [`QueryPlan`] for an access path it can serve — an entity's timeline,
+//! the per-segment event type index, the tag [`BitmapIndex`], a
+//! [`SecondaryIndex`](crate::index::SecondaryIndex) on a payload field, a
+//! full-text index for a text match (with the `fulltext` feature) or a
+//! scan of the segments overlapping the time range — costed from
+//! [`QueryStatistics`], and the [`Optimizer`] picks the cheapest. Every plan
+//! returns the same events: the executor applies the whole query to what
+//! the access path reads.
+//!
+//! Statistics are collected as events are committed and recomputed from
+//! the journal when retention removes events. [`QueryPlanner::report`]
+//! exposes them, with the cardinality of each secondary index.
 
-use crate::error::Result;
-use crate::query::parser::TemporalQuery;
+use crate::core::event::Event;
+use crate::core::namespace::is_internal_entity;
+use crate::core::temporal::Timestamp;
+use crate::index::bitmap::BitmapIndex;
+#[cfg(feature = "fulltext")]
+use crate::index::fulltext::FullTextIndexes;
+use crate::index::secondary::{IndexEntry, SecondaryIndexes};
+use crate::query::parser::{TemporalQuery, TimeRange};
+use crate::storage::{CompareOp, FieldPath, IndexMaintainer};
+use roaring::RoaringBitmap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Optimize a temporal query
-pub fn optimize_query(query: &TemporalQuery) -> Result<TemporalQuery> {
-    // TODO: Implement query optimization
-    Ok(query.clone())
+/// Width of the time histogram buckets
+pub const HISTOGRAM_BUCKET: Duration = Duration::from_secs(3600);
+
+/// Cost of reading an event through a block index, relative to a
+/// sequential scan; index hits share their blocks with other events
+pub const INDEX_READ_COST: f64 = 2.0;
+
+/// Fixed cost of reading one entity's timeline
+pub const ENTITY_LOOKUP_COST: f64 = 8.0;
+
+/// Event counts by type, tag, entity and time
+#[derive(Debug, Clone)]
+pub struct QueryStatistics {
+    total: u64,
+    by_type: HashMap<String, u64>,
+    by_tag: HashMap<String, u64>,
+    by_entity: HashMap<String, u64>,
+    /// Events per [`HISTOGRAM_BUCKET`], by bucket number
+    histogram: BTreeMap<i64, u64>,
+    bucket_width: i64,
+}
+
+impl QueryStatistics {
+    /// Statistics of an empty journal
+    pub fn new() -> Self {
+        Self {
+            total: 0,
+            by_type: HashMap::new(),
+            by_tag: HashMap::new(),
+            by_entity: HashMap::new(),
+            histogram: BTreeMap::new(),
+            bucket_width: HISTOGRAM_BUCKET.as_nanos() as i64,
+        }
+    }
+
+    /// Count a committed event
+    pub fn observe(&mut self, event: &Event) {
+        self.total += 1;
+        *self
+            .by_type
+            .entry(event.event_type().to_string())
+            .or_default() += 1;
+        for tag in &event.metadata.tags {
+            *self.by_tag.entry(tag.clone()).or_default() += 1;
+        }
+        *self
+            .by_entity
+            .entry(event.entity_id().to_string())
+            .or_default() += 1;
+        let bucket = event.timestamp().as_nanos().div_euclid(self.bucket_width);
+        *self.histogram.entry(bucket).or_default() += 1;
+    }
+
+    /// Events counted
+    pub fn total_events(&self) -> u64 {
+        self.total
+    }
+
+    /// Events of `event_type`
+    pub fn type_count(&self, event_type: &str) -> u64 {
+        self.by_type.get(event_type).copied().unwrap_or(0)
+    }
+
+    /// Events tagged `tag`
+    pub fn tag_count(&self, tag: &str) -> u64 {
+        self.by_tag.get(tag).copied().unwrap_or(0)
+    }
+
+    /// Events of `entity_id`
+    pub fn entity_count(&self, entity_id: &str) -> u64 {
+        self.by_entity.get(entity_id).copied().unwrap_or(0)
+    }
+
+    /// Share of the events of `event_type`
+    pub fn type_frequency(&self, event_type: &str) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.type_count(event_type) as f64 / self.total as f64
+    }
+
+    /// Estimated share of the events with a timestamp in `[start, end)`,
+    /// assuming events are spread evenly within a histogram bucket
+    pub fn range_selectivity(&self, start: Timestamp, end: Timestamp) -> f64 {
+        if self.total == 0 || start >= end {
+            return 0.0;
+        }
+        let (start, end) = (start.as_nanos() as i128, end.as_nanos() as i128);
+        let width = self.bucket_width as i128;
+        let first = start.div_euclid(width) as i64;
+        let last = (end - 1).div_euclid(width) as i64;
+        let in_range: f64 = self
+            .histogram
+            .range(first..=last)
+            .map(|(&bucket, &count)| {
+                let lo = bucket as i128 * width;
+                let overlap = end.min(lo + width) - start.max(lo);
+                count as f64 * overlap as f64 / width as f64
+            })
+            .sum();
+        in_range / self.total as f64
+    }
+
+    /// Events per type, most frequent first
+    pub fn type_counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .by_type
+            .iter()
+            .map(|(event_type, &count)| (event_type.clone(), count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Events per [`HISTOGRAM_BUCKET`], by bucket start, in time order
+    pub fn time_buckets(&self) -> Vec<(Timestamp, u64)> {
+        self.histogram
+            .iter()
+            .map(|(&bucket, &count)| {
+                let start = bucket.saturating_mul(self.bucket_width);
+                (Timestamp::from_nanos(start), count)
+            })
+            .collect()
+    }
+}
+
+/// Size and cardinality of a secondary index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStatistics {
+    /// Prefix of the entities indexed
+    pub entity_prefix: String,
+    /// Field indexed
+    pub path: FieldPath,
+    /// Events indexed
+    pub entries: u64,
+    /// Distinct values indexed
+    pub distinct_values: usize,
+    /// Time partitions holding entries
+    pub partitions: usize,
+}
+
+/// Statistics the optimizer plans with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    /// Events counted
+    pub total_events: u64,
+    /// Events per type, most frequent first
+    pub types: Vec<(String, u64)>,
+    /// Events per [`HISTOGRAM_BUCKET`], by bucket start, in time order
+    pub time_buckets: Vec<(Timestamp, u64)>,
+    /// Secondary indexes, in the order they were created
+    pub indexes: Vec<IndexStatistics>,
+}
+
+impl Default for QueryStatistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a query reads the journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessPath {
+    /// The timeline of one entity
+    EntityTimeline(String),
+    /// Blocks the segment index lists for an event type
+    TypeIndex(String),
+    /// Timelines of the entities the tag index lists
+    TagIndex {
+        /// Entities of the events matching the query's tags
+        entities: Vec<String>,
+    },
+    /// Events a secondary index lists for a payload predicate
+    FieldIndex {
+        /// Field indexed
+        path: FieldPath,
+        /// Events whose field may satisfy the predicate, within the time
+        /// range
+        entries: Vec<IndexEntry>,
+    },
+    /// Events a full-text index lists for a text match, within the time
+    /// range
+    TextIndex {
+        /// Events mentioning the words searched for
+        entries: Vec<IndexEntry>,
+    },
+    /// Every block overlapping the time range
+    SegmentScan,
+}
+
+/// An access path and its estimated cost
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan {
+    /// How the journal is read
+    pub access: AccessPath,
+    /// Rule that proposed the plan
+    pub rule: String,
+    /// Estimated events read
+    pub cost: f64,
+    /// Estimated events returned
+    pub estimated_rows: f64,
+}
+
+impl QueryPlan {
+    /// Plan used without statistics: the entity's timeline for a query on
+    /// one entity, a segment scan otherwise
+    pub fn unplanned(query: &TemporalQuery) -> Self {
+        let access = match &query.entity_id {
+            Some(entity_id) => AccessPath::EntityTimeline(entity_id.clone()),
+            None => AccessPath::SegmentScan,
+        };
+        Self {
+            access,
+            rule: "unplanned".to_string(),
+            cost: f64::INFINITY,
+            estimated_rows: f64::INFINITY,
+        }
+    }
+}
+
+/// What rules plan with
+#[derive(Debug, Clone, Copy)]
+pub struct PlanContext<'a> {
+    /// Event counts
+    pub statistics: &'a QueryStatistics,
+    /// Events by tag
+    pub tags: &'a BitmapIndex,
+    /// Events by payload field value
+    pub indexes: &'a SecondaryIndexes,
+    /// Events by the words of their string fields
+    #[cfg(feature = "fulltext")]
+    pub fulltext: &'a FullTextIndexes,
+}
+
+impl PlanContext<'_> {
+    /// Events carrying one of the query's tags and satisfying its tag
+    /// filter, or `None` if the query does not filter on tags
+    pub fn tagged_events(&self, query: &TemporalQuery) -> Option<RoaringBitmap> {
+        let any = (!query.tags.is_empty()).then(|| self.tags.events_with_any(&query.tags));
+        let filtered = query.tag_filter.as_ref().map(|f| self.tags.evaluate(f));
+        match (any, filtered) {
+            (Some(any), Some(filtered)) => Some(any & filtered),
+            (any, filtered) => any.or(filtered),
+        }
+    }
+
+    /// Estimated events the query returns, assuming its predicates are
+    /// independent
+    pub fn estimated_rows(&self, query: &TemporalQuery) -> f64 {
+        let stats = self.statistics;
+        let total = stats.total_events() as f64;
+        if total == 0.0 {
+            return 0.0;
+        }
+        let (start, end) = query_bounds(query);
+        let mut rows = total * stats.range_selectivity(start, end);
+        if let Some(entity_id) = &query.entity_id {
+            rows *= stats.entity_count(entity_id) as f64 / total;
+        }
+        if let Some(event_type) = &query.event_type {
+            rows *= stats.type_count(event_type) as f64 / total;
+        }
+        if let Some(tagged) = self.tagged_events(query) {
+            rows *= (tagged.len() as f64 / total).min(1.0);
+        }
+        // Equality on an indexed field selects one of its distinct values.
+        let prefix = indexed_prefix(query);
+        for predicate in &query.field_predicates {
+            if predicate.op != CompareOp::Eq {
+                continue;
+            }
+            if let Some(index) = self.indexes.covering(prefix, &predicate.path) {
+                rows /= index.distinct_values().max(1) as f64;
+            }
+        }
+        rows
+    }
+}
+
+/// Prefix of the entities a query selects, to find the indexes covering
+/// them
+fn indexed_prefix(query: &TemporalQuery) -> &str {
+    query
+        .entity_id
+        .as_deref()
+        .or(query.entity_prefix.as_deref())
+        .unwrap_or("")
+}
+
+/// Half-open timestamp range `[start, end)` a query selects
+pub fn query_bounds(query: &TemporalQuery) -> (Timestamp, Timestamp) {
+    let (start, end) = match &query.time_range {
+        None => (i64::MIN, i64::MAX),
+        Some(TimeRange::AsOf(ts)) => (i64::MIN, ts.saturating_add(1)),
+        Some(TimeRange::Between { start, end }) => (*start, *end),
+        Some(TimeRange::From(start)) => (*start, i64::MAX),
+    };
+    (Timestamp::from_nanos(start), Timestamp::from_nanos(end))
+}
+
+/// Proposes a plan for the queries it can serve
+pub trait PlanRule: Send + Sync {
+    /// Name reported in [`QueryPlan::rule`]
+    fn name(&self) -> &str;
+
+    /// A plan for `query`, or `None` if the rule does not apply
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan>;
+}
+
+/// Reads the timeline of the queried entity
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EntityTimelineRule;
+
+impl PlanRule for EntityTimelineRule {
+    fn name(&self) -> &str {
+        "entity_timeline"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let entity_id = query.entity_id.as_ref()?;
+        Some(QueryPlan {
+            access: AccessPath::EntityTimeline(entity_id.clone()),
+            rule: self.name().to_string(),
+            cost: ENTITY_LOOKUP_COST + context.statistics.entity_count(entity_id) as f64,
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Reads the blocks holding the queried event type
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TypeIndexRule;
+
+impl PlanRule for TypeIndexRule {
+    fn name(&self) -> &str {
+        "type_index"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let event_type = query.event_type.as_ref()?;
+        let stats = context.statistics;
+        let (start, end) = query_bounds(query);
+        let of_type = stats.type_count(event_type) as f64 * stats.range_selectivity(start, end);
+        Some(QueryPlan {
+            access: AccessPath::TypeIndex(event_type.clone()),
+            rule: self.name().to_string(),
+            cost: of_type * INDEX_READ_COST,
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Reads the timelines of the entities of the events the tag index lists
+/// for the query's tags
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagIndexRule;
+
+impl PlanRule for TagIndexRule {
+    fn name(&self) -> &str {
+        "tag_index"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let tagged = context.tagged_events(query)?;
+        let entities: Vec<String> = context
+            .tags
+            .entities_of(&tagged)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let cost = entities
+            .iter()
+            .map(|id| ENTITY_LOOKUP_COST + context.statistics.entity_count(id) as f64)
+            .sum();
+        Some(QueryPlan {
+            access: AccessPath::TagIndex { entities },
+            rule: self.name().to_string(),
+            cost,
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Reads the events a secondary index lists for the most selective
+/// payload predicate it can serve
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FieldIndexRule;
+
+impl PlanRule for FieldIndexRule {
+    fn name(&self) -> &str {
+        "field_index"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let prefix = indexed_prefix(query);
+        let (start, end) = query_bounds(query);
+        let (path, entries) = query
+            .field_predicates
+            .iter()
+            .filter_map(|predicate| {
+                let index = context.indexes.covering(prefix, &predicate.path)?;
+                let entries: Vec<IndexEntry> = index
+                    .lookup_in(predicate, start, end)?
+                    .into_iter()
+                    .filter(|e| {
+                        e.timestamp >= start && e.timestamp < end && e.entity_id.starts_with(prefix)
+                    })
+                    .cloned()
+                    .collect();
+                Some((predicate.path.clone(), entries))
+            })
+            .min_by_key(|(_, entries)| entries.len())?;
+        let entities: HashSet<&str> = entries.iter().map(|e| e.entity_id.as_str()).collect();
+        let cost =
+            entities.len() as f64 * ENTITY_LOOKUP_COST + entries.len() as f64 * INDEX_READ_COST;
+        Some(QueryPlan {
+            access: AccessPath::FieldIndex { path, entries },
+            rule: self.name().to_string(),
+            cost,
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Reads the events a full-text index lists for the most selective text
+/// match it can serve
+#[cfg(feature = "fulltext")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FullTextRule;
+
+#[cfg(feature = "fulltext")]
+impl PlanRule for FullTextRule {
+    fn name(&self) -> &str {
+        "fulltext_index"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let prefix = indexed_prefix(query);
+        let (start, end) = query_bounds(query);
+        let entries: Vec<IndexEntry> = query
+            .text_matches
+            .iter()
+            .filter_map(|text| {
+                context.fulltext.covering(prefix).find_map(|index| {
+                    match index.search(text, start, end) {
+                        Ok(entries) => entries,
+                        Err(e) => {
+                            tracing::warn!("Full-text search for {} failed: {}", text, e);
+                            None
+                        }
+                    }
+                })
+            })
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .filter(|e| e.entity_id.starts_with(prefix))
+                    .collect::<Vec<_>>()
+            })
+            .min_by_key(Vec::len)?;
+        let entities: HashSet<&str> = entries.iter().map(|e| e.entity_id.as_str()).collect();
+        let cost =
+            entities.len() as f64 * ENTITY_LOOKUP_COST + entries.len() as f64 * INDEX_READ_COST;
+        Some(QueryPlan {
+            access: AccessPath::TextIndex { entries },
+            rule: self.name().to_string(),
+            cost,
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Scans the segments overlapping the time range
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegmentScanRule;
+
+impl PlanRule for SegmentScanRule {
+    fn name(&self) -> &str {
+        "segment_scan"
+    }
+
+    fn plan(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> Option<QueryPlan> {
+        let stats = context.statistics;
+        let (start, end) = query_bounds(query);
+        Some(QueryPlan {
+            access: AccessPath::SegmentScan,
+            rule: self.name().to_string(),
+            cost: stats.total_events() as f64 * stats.range_selectivity(start, end),
+            estimated_rows: context.estimated_rows(query),
+        })
+    }
+}
+
+/// Picks the cheapest plan the rules propose
+#[derive(Clone)]
+pub struct Optimizer {
+    rules: Vec<Arc<dyn PlanRule>>,
+}
+
+impl Optimizer {
+    /// Optimizer with the built-in rules
+    pub fn new() -> Self {
+        let optimizer = Self::without_rules()
+            .with_rule(EntityTimelineRule)
+            .with_rule(TypeIndexRule)
+            .with_rule(TagIndexRule)
+            .with_rule(FieldIndexRule);
+        #[cfg(feature = "fulltext")]
+        let optimizer = optimizer.with_rule(FullTextRule);
+        optimizer.with_rule(SegmentScanRule)
+    }
+
+    /// Optimizer without rules; it always falls back to
+    /// [`QueryPlan::unplanned`]
+    pub fn without_rules() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    /// Also consider the plans of `rule`; on equal cost, earlier rules win
+    pub fn with_rule<R: PlanRule + 'static>(mut self, rule: R) -> Self {
+        self.rules.push(Arc::new(rule));
+        self
+    }
+
+    /// Names of the rules, in the order they are consulted
+    pub fn rules(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|rule| rule.name())
+    }
+
+    /// Cheapest plan for `query`
+    pub fn optimize(&self, query: &TemporalQuery, context: &PlanContext<'_>) -> QueryPlan {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.plan(query, context))
+            .reduce(|best, plan| if plan.cost < best.cost { plan } else { best })
+            .unwrap_or_else(|| QueryPlan::unplanned(query))
+    }
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Optimizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Optimizer")
+            .field("rules", &self.rules().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Statistics, tag, secondary and full-text indexes and optimizer of a
+/// database
+#[derive(Debug, Default)]
+pub struct QueryPlanner {
+    statistics: QueryStatistics,
+    tags: BitmapIndex,
+    indexes: SecondaryIndexes,
+    #[cfg(feature = "fulltext")]
+    fulltext: FullTextIndexes,
+    optimizer: Optimizer,
+}
+
+impl QueryPlanner {
+    /// Planner with empty statistics and the built-in rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for a committed event
+    pub fn observe(&mut self, event: &Event) {
+        self.statistics.observe(event);
+        self.tags
+            .insert_event(event.entity_id(), &event.metadata.tags);
+        self.indexes.observe(event);
+        #[cfg(feature = "fulltext")]
+        self.fulltext.observe(event);
+    }
+
+    /// Statistics collected so far
+    pub fn statistics(&self) -> &QueryStatistics {
+        &self.statistics
+    }
+
+    /// Replace the statistics with `statistics`, e.g. recomputed from the
+    /// journal after events were removed
+    pub fn set_statistics(&mut self, statistics: QueryStatistics) {
+        self.statistics = statistics;
+    }
+
+    /// Statistics and index cardinalities the optimizer plans with
+    pub fn report(&self) -> Statistics {
+        let indexes = self
+            .indexes
+            .iter()
+            .map(|index| IndexStatistics {
+                entity_prefix: index.entity_prefix().to_string(),
+                path: index.path().clone(),
+                entries: index.len(),
+                distinct_values: index.distinct_values(),
+                partitions: index.partitions(),
+            })
+            .collect();
+        Statistics {
+            total_events: self.statistics.total_events(),
+            types: self.statistics.type_counts(),
+            time_buckets: self.statistics.time_buckets(),
+            indexes,
+        }
+    }
+
+    /// Secondary indexes on payload fields
+    pub fn indexes(&self) -> &SecondaryIndexes {
+        &self.indexes
+    }
+
+    /// Secondary indexes, to add or remove one
+    pub fn indexes_mut(&mut self) -> &mut SecondaryIndexes {
+        &mut self.indexes
+    }
+
+    /// Full-text indexes on string payload fields
+    #[cfg(feature = "fulltext")]
+    pub fn fulltext(&self) -> &FullTextIndexes {
+        &self.fulltext
+    }
+
+    /// Full-text indexes, to add or remove one
+    #[cfg(feature = "fulltext")]
+    pub fn fulltext_mut(&mut self) -> &mut FullTextIndexes {
+        &mut self.fulltext
+    }
+
+    /// Drop the secondary index partitions ending at or before
+    /// `timestamp`, once no event before it is left; returns how many
+    /// entries were dropped
+    pub fn drop_index_partitions(&mut self, timestamp: Timestamp) -> u64 {
+        self.indexes.drop_before(timestamp)
+    }
+
+    /// Replace the optimizer, e.g. to add rules
+    pub fn set_optimizer(&mut self, optimizer: Optimizer) {
+        self.optimizer = optimizer;
+    }
+
+    /// Cheapest plan for `query`
+    pub fn plan(&self, query: &TemporalQuery) -> QueryPlan {
+        let context = PlanContext {
+            statistics: &self.statistics,
+            tags: &self.tags,
+            indexes: &self.indexes,
+            #[cfg(feature = "fulltext")]
+            fulltext: &self.fulltext,
+        };
+        self.optimizer.optimize(query, &context)
+    }
+}
+
+/// Internal entities are not planned over
+impl IndexMaintainer for QueryPlanner {
+    fn on_append(&mut self, event: &Event) {
+        if !is_internal_entity(event.entity_id()) {
+            self.observe(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, event_type: &str, secs: i64, tags: &[&str]) -> Event {
+        Event::builder(
+            event_type.to_string(),
+            Timestamp::from_secs(secs),
+            entity_id.to_string(),
+            EventPayload::from_json(&secs).unwrap(),
+        )
+        .tags(tags.iter().map(|tag| tag.to_string()).collect())
+        .build()
+    }
+
+    #[test]
+    fn test_picks_cheapest_access_path() {
+        let mut planner = QueryPlanner::new();
+        for i in 0..1000 {
+            let entity = format!("sensor:{}", i % 50);
+            planner.observe(&event(&entity, "reading", i * 36, &[]));
+        }
+        for i in 0..5 {
+            planner.observe(&event("order:1", "refund", i, &["manual"]));
+        }
+
+        let stats = planner.statistics();
+        let hour = Timestamp::from_secs(3600);
+        let first_hour = stats.range_selectivity(Timestamp::from_secs(0), hour);
+        assert!((first_hour - 105.0 / 1005.0).abs() < 1e-9);
+
+        let plan = |query: TemporalQuery| planner.plan(&query).access;
+        assert_eq!(
+            plan(TemporalQuery::select().with_entity("sensor:1")),
+            AccessPath::EntityTimeline("sensor:1".to_string())
+        );
+        assert_eq!(
+            plan(TemporalQuery::select().with_event_type("refund")),
+            AccessPath::TypeIndex("refund".to_string())
+        );
+        let tagged = TemporalQuery::select()
+            .with_event_type("reading")
+            .with_tag("manual");
+        let entities = vec!["order:1".to_string()];
+        assert_eq!(plan(tagged), AccessPath::TagIndex { entities });
+        let recent = TimeRange::From(Timestamp::from_secs(35_000).as_nanos());
+        assert_eq!(
+            plan(
+                TemporalQuery::select()
+                    .with_event_type("reading")
+                    .with_time_range(recent)
+            ),
+            AccessPath::SegmentScan
+        );
+
+        let mut custom = planner;
+        custom.set_optimizer(Optimizer::without_rules());
+        let query = TemporalQuery::select().with_event_type("refund");
+        assert_eq!(custom.plan(&query).rule, "unplanned");
+    }
+
+    #[test]
+    fn test_reports_statistics_and_index_cardinality() {
+        use crate::index::SecondaryIndex;
+        use crate::storage::FieldPredicate;
+        let mut planner = QueryPlanner::new();
+        let status = FieldPath::parse("status").unwrap();
+        planner
+            .indexes_mut()
+            .add(SecondaryIndex::new("order:", status));
+        for i in 0..100 {
+            let status = ["open", "paid", "void", "held"][i % 4];
+            let json = serde_json::json!({ "status": status });
+            let mut event = event("order:1", "update", i as i64 * 60, &[]);
+            event.payload = EventPayload::from_json(&json).unwrap();
+            planner.observe(&event);
+        }
+        planner.observe(&event("order:2", "refund", 7200, &[]));
+
+        let report = planner.report();
+        assert_eq!(report.total_events, 101);
+        assert_eq!(report.types[0], ("update".to_string(), 100));
+        let buckets: Vec<u64> = report.time_buckets.iter().map(|(_, n)| *n).collect();
+        assert_eq!(buckets, [60, 40, 1]);
+        assert_eq!(report.time_buckets[2].0, Timestamp::from_secs(7200));
+        assert_eq!(
+            (report.indexes[0].entries, report.indexes[0].distinct_values),
+            (100, 4)
+        );
+        assert!((planner.statistics().type_frequency("refund") - 1.0 / 101.0).abs() < 1e-9);
+
+        // Equality on the indexed field is estimated to select one value.
+        let paid = TemporalQuery::select()
+            .with_entity_prefix("order:")
+            .with_field_predicate(FieldPredicate::parse("status = 'paid'").unwrap());
+        assert!((planner.plan(&paid).estimated_rows - 101.0 / 4.0).abs() < 1e-9);
+
+        planner.set_statistics(QueryStatistics::new());
+        assert_eq!(planner.report().total_events, 0);
+    }
 }
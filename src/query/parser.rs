@@ -1,6 +1,9 @@
 //! SQL parser for temporal queries
 
 use crate::error::Result;
+use crate::index::bitmap::TagFilter;
+use crate::index::text::TextMatch;
+use crate::storage::FieldPredicate;
 
 /// Parsed temporal query
 #[derive(Debug, Clone)]
@@ -9,8 +12,90 @@ pub struct TemporalQuery {
     pub query_type: QueryType,
     /// Entity ID filter (if any)
     pub entity_id: Option<String>,
+    /// Only events of entities whose ID starts with this prefix
+    pub entity_prefix: Option<String>,
     /// Time range
     pub time_range: Option<TimeRange>,
+    /// Event type filter (if any)
+    pub event_type: Option<String>,
+    /// Only events carrying at least one of these tags
+    pub tags: Vec<String>,
+    /// Only events whose tags satisfy this filter
+    pub tag_filter: Option<TagFilter>,
+    /// Only events whose payload satisfies all of these
+    pub field_predicates: Vec<FieldPredicate>,
+    /// Only events whose string fields mention the words of all of these
+    pub text_matches: Vec<TextMatch>,
+}
+
+impl TemporalQuery {
+    /// `SELECT` of every event
+    pub fn select() -> Self {
+        Self {
+            query_type: QueryType::Select,
+            entity_id: None,
+            entity_prefix: None,
+            time_range: None,
+            event_type: None,
+            tags: Vec::new(),
+            tag_filter: None,
+            field_predicates: Vec::new(),
+            text_matches: Vec::new(),
+        }
+    }
+
+    /// Only select events of `entity_id`
+    pub fn with_entity(mut self, entity_id: impl Into<String>) -> Self {
+        self.entity_id = Some(entity_id.into());
+        self
+    }
+
+    /// Only select events of entities whose ID starts with `prefix`
+    pub fn with_entity_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.entity_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only select events within `range`
+    pub fn with_time_range(mut self, range: TimeRange) -> Self {
+        self.time_range = Some(range);
+        self
+    }
+
+    /// Only select events of `event_type`
+    pub fn with_event_type(mut self, event_type: impl Into<String>) -> Self {
+        self.event_type = Some(event_type.into());
+        self
+    }
+
+    /// Also accept events tagged `tag`
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
+
+    /// Only select events whose tags satisfy `filter`, as well as any
+    /// filter given before
+    pub fn with_tag_filter(mut self, filter: TagFilter) -> Self {
+        self.tag_filter = Some(match self.tag_filter.take() {
+            Some(previous) => previous & filter,
+            None => filter,
+        });
+        self
+    }
+
+    /// Only select events whose payload satisfies `predicate`
+    pub fn with_field_predicate(mut self, predicate: FieldPredicate) -> Self {
+        self.field_predicates.push(predicate);
+        self
+    }
+
+    /// Only select events whose string fields mention every word of
+    /// `text`, e.g. `TextMatch::parse("note MATCH 'refund'")`
+    pub fn with_text_match(mut self, text: TextMatch) -> Self {
+        self.text_matches.push(text);
+        self
+    }
 }
 
 /// Query type
@@ -0,0 +1,221 @@
+//! Prepared queries with bound parameters.
+//!
+//! A [`PreparedQuery`] is a `SELECT` whose entity ID and time bounds are
+//! left as positional parameters (`$1`, `$2`, ...). It is validated and
+//! planned once, then executed with different [`ParamValue`]s. Values are
+//! type-checked against their [`ParamSlot`] and never spliced into query
+//! text, so they can come straight from API requests.
+
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::query::optimizer::{AccessPath, QueryPlan};
+use crate::query::parser::{QueryType, TemporalQuery, TimeRange};
+use serde::{Deserialize, Serialize};
+
+/// Part of a query a parameter fills in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamSlot {
+    /// Entity ID; takes [`ParamValue::Text`]
+    EntityId,
+    /// `AS OF` timestamp; takes [`ParamValue::Timestamp`]
+    AsOf,
+    /// Start of the time range (inclusive); takes [`ParamValue::Timestamp`]
+    RangeStart,
+    /// End of the time range (exclusive); takes [`ParamValue::Timestamp`]
+    RangeEnd,
+}
+
+/// Value bound to a parameter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamValue {
+    /// Text, e.g. an entity ID
+    Text(String),
+    /// Point in time
+    Timestamp(Timestamp),
+}
+
+impl From<&str> for ParamValue {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_string())
+    }
+}
+
+impl From<String> for ParamValue {
+    fn from(value: String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl From<Timestamp> for ParamValue {
+    fn from(value: Timestamp) -> Self {
+        Self::Timestamp(value)
+    }
+}
+
+/// A `SELECT` validated and planned once, executed with bound parameters
+#[derive(Debug, Clone)]
+pub struct PreparedQuery {
+    template: TemporalQuery,
+    slots: Vec<ParamSlot>,
+    plan: QueryPlan,
+}
+
+impl PreparedQuery {
+    /// Prepare `template` with parameter `$n` filling `slots[n - 1]`.
+    ///
+    /// The template must leave the parameterized parts unset, and time
+    /// parameters replace its whole time range: `AsOf` cannot be combined
+    /// with range bounds, and a missing bound is open.
+    pub fn new(template: TemporalQuery, slots: Vec<ParamSlot>) -> Result<Self> {
+        if !matches!(template.query_type, QueryType::Select) {
+            return Err(Error::Query(format!(
+                "Only SELECT queries can be prepared, got {:?}",
+                template.query_type
+            )));
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            if slots[..i].contains(slot) {
+                return Err(Error::Query(format!(
+                    "Parameter slot {:?} is bound twice",
+                    slot
+                )));
+            }
+        }
+        let has = |slot| slots.contains(&slot);
+        if has(ParamSlot::EntityId) && template.entity_id.is_some() {
+            return Err(Error::Query(
+                "The entity ID is both fixed and a parameter".to_string(),
+            ));
+        }
+        let timed = has(ParamSlot::AsOf) || has(ParamSlot::RangeStart) || has(ParamSlot::RangeEnd);
+        if timed && template.time_range.is_some() {
+            return Err(Error::Query(
+                "The time range is both fixed and a parameter".to_string(),
+            ));
+        }
+        if has(ParamSlot::AsOf) && (has(ParamSlot::RangeStart) || has(ParamSlot::RangeEnd)) {
+            return Err(Error::Query(
+                "AS OF cannot be combined with range bounds".to_string(),
+            ));
+        }
+        let mut prepared = Self {
+            plan: QueryPlan::unplanned(&template),
+            template,
+            slots,
+        };
+        prepared.plan = QueryPlan::unplanned(&prepared.planning_query());
+        Ok(prepared)
+    }
+
+    /// Use `plan` for every execution. An entity timeline plan reads the
+    /// timeline of the bound entity.
+    pub fn with_plan(mut self, plan: QueryPlan) -> Self {
+        self.plan = plan;
+        self
+    }
+
+    /// Query the template stands for when planning: a parameterized entity
+    /// is present but unknown, parameterized times are unbounded
+    pub fn planning_query(&self) -> TemporalQuery {
+        let mut query = self.template.clone();
+        if self.slots.contains(&ParamSlot::EntityId) {
+            query.entity_id = Some(String::new());
+        }
+        query
+    }
+
+    /// Parameter slots, in parameter order
+    pub fn slots(&self) -> &[ParamSlot] {
+        &self.slots
+    }
+
+    /// Plan chosen when the query was prepared
+    pub fn plan(&self) -> &QueryPlan {
+        &self.plan
+    }
+
+    /// The query and plan with `values` bound to the parameters
+    pub fn bind(&self, values: &[ParamValue]) -> Result<(TemporalQuery, QueryPlan)> {
+        if values.len() != self.slots.len() {
+            return Err(Error::Query(format!(
+                "Expected {} parameters, got {}",
+                self.slots.len(),
+                values.len()
+            )));
+        }
+        let mut query = self.template.clone();
+        let (mut as_of, mut start, mut end) = (None, None, None);
+        for (i, (slot, value)) in self.slots.iter().zip(values).enumerate() {
+            match (slot, value) {
+                (ParamSlot::EntityId, ParamValue::Text(id)) => query.entity_id = Some(id.clone()),
+                (ParamSlot::AsOf, ParamValue::Timestamp(ts)) => as_of = Some(ts.as_nanos()),
+                (ParamSlot::RangeStart, ParamValue::Timestamp(ts)) => start = Some(ts.as_nanos()),
+                (ParamSlot::RangeEnd, ParamValue::Timestamp(ts)) => end = Some(ts.as_nanos()),
+                _ => {
+                    return Err(Error::Query(format!(
+                        "Parameter ${} ({:?}) cannot take {:?}",
+                        i + 1,
+                        slot,
+                        value
+                    )))
+                }
+            }
+        }
+        query.time_range = match (as_of, start, end) {
+            (Some(ts), _, _) => Some(TimeRange::AsOf(ts)),
+            (None, Some(start), None) => Some(TimeRange::From(start)),
+            (None, start, Some(end)) => Some(TimeRange::Between {
+                start: start.unwrap_or(i64::MIN),
+                end,
+            }),
+            (None, None, None) => query.time_range,
+        };
+
+        let mut plan = self.plan.clone();
+        if let (AccessPath::EntityTimeline(_), Some(entity_id)) = (&plan.access, &query.entity_id) {
+            plan.access = AccessPath::EntityTimeline(entity_id.clone());
+        }
+        Ok((query, plan))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_checks_parameters() {
+        let template = TemporalQuery::select().with_event_type("reading");
+        let slots = vec![
+            ParamSlot::EntityId,
+            ParamSlot::RangeStart,
+            ParamSlot::RangeEnd,
+        ];
+        let prepared = PreparedQuery::new(template, slots).unwrap();
+
+        let start = Timestamp::from_secs(10);
+        let end = Timestamp::from_secs(20);
+        let (query, plan) = prepared
+            .bind(&["sensor:1".into(), start.into(), end.into()])
+            .unwrap();
+        assert_eq!(query.entity_id.as_deref(), Some("sensor:1"));
+        let bounds = (start.as_nanos(), end.as_nanos());
+        assert!(matches!(
+            query.time_range,
+            Some(TimeRange::Between { start, end }) if (start, end) == bounds
+        ));
+        assert_eq!(
+            plan.access,
+            AccessPath::EntityTimeline("sensor:1".to_string())
+        );
+
+        assert!(prepared.bind(&["sensor:1".into(), start.into()]).is_err());
+        assert!(prepared
+            .bind(&[start.into(), "sensor:1".into(), end.into()])
+            .is_err());
+        let slots = vec![ParamSlot::AsOf, ParamSlot::RangeEnd];
+        assert!(PreparedQuery::new(TemporalQuery::select(), slots).is_err());
+    }
+}
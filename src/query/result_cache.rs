@@ -0,0 +1,314 @@
+//! Cache of query results.
+//!
+//! A [`QueryResultCache`] keeps the results of recent `SELECT`s keyed by
+//! the normalized query, whose time bounds carry any `AS OF` timestamp.
+//! Committing an event drops exactly the entries whose query selects it,
+//! found through an index on the queried entity or event type, so a
+//! dashboard repeating a query is answered from memory until its result
+//! actually changes.
+
+use crate::core::event::Event;
+use crate::query::executor::query_selects;
+use crate::query::optimizer::query_bounds;
+use crate::query::parser::TemporalQuery;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Default number of results kept in a [`QueryResultCache`]
+pub const DEFAULT_QUERY_CACHE_ENTRIES: usize = 256;
+
+/// Largest result, in events, worth caching
+pub const QUERY_CACHE_MAX_ROWS: usize = 10_000;
+
+/// Cache key of a query.
+///
+/// Queries differing only in how their time range is written, or in the
+/// order or repetition of tags, payload predicates and text matches, share
+/// a key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey(String);
+
+impl QueryKey {
+    /// Key of `query`
+    pub fn new(query: &TemporalQuery) -> Self {
+        let mut tags = query.tags.clone();
+        tags.sort();
+        tags.dedup();
+        let mut predicates: Vec<String> = query
+            .field_predicates
+            .iter()
+            .map(|p| format!("{:?}", p))
+            .collect();
+        predicates.sort();
+        predicates.dedup();
+        let mut texts: Vec<String> = query.text_matches.iter().map(|t| t.to_string()).collect();
+        texts.sort();
+        texts.dedup();
+        Self(format!(
+            "{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}",
+            query.query_type,
+            query.entity_id,
+            query.entity_prefix,
+            query.event_type,
+            query_bounds(query),
+            tags,
+            query.tag_filter,
+            predicates,
+            texts
+        ))
+    }
+}
+
+/// Part of the index that a query's entry is found through
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Entity(String),
+    Type(String),
+    Any,
+}
+
+impl Scope {
+    fn of(query: &TemporalQuery) -> Self {
+        match (&query.entity_id, &query.event_type) {
+            (Some(entity_id), _) => Scope::Entity(entity_id.clone()),
+            (None, Some(event_type)) => Scope::Type(event_type.clone()),
+            (None, None) => Scope::Any,
+        }
+    }
+}
+
+/// Counters of a [`QueryResultCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    /// Queries answered from the cache
+    pub hits: u64,
+    /// Queries that had to be executed
+    pub misses: u64,
+    /// Entries dropped because a new event changed their result
+    pub invalidations: u64,
+    /// Results currently cached
+    pub entries: usize,
+}
+
+struct Entry {
+    query: TemporalQuery,
+    events: Vec<Event>,
+    /// Tick of the last lookup, for LRU eviction
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<QueryKey, Entry>,
+    by_scope: HashMap<Scope, HashSet<QueryKey>>,
+    tick: u64,
+    stats: QueryCacheStats,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &QueryKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            let scope = Scope::of(&entry.query);
+            if let Some(keys) = self.by_scope.get_mut(&scope) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.by_scope.remove(&scope);
+                }
+            }
+        }
+    }
+}
+
+/// Bounded cache of `SELECT` results, invalidated by new events
+pub struct QueryResultCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl QueryResultCache {
+    /// Cache keeping up to `capacity` results; `0` disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Maximum number of results kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Cached result of `query`, if any
+    pub fn get(&self, query: &TemporalQuery) -> Option<Vec<Event>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let key = QueryKey::new(query);
+        let mut inner = self.inner.lock().expect("QueryResultCache poisoned lock");
+        inner.tick += 1;
+        let tick = inner.tick;
+        let events = inner.entries.get_mut(&key).map(|entry| {
+            entry.last_used = tick;
+            entry.events.clone()
+        });
+        match events {
+            Some(_) => inner.stats.hits += 1,
+            None => inner.stats.misses += 1,
+        }
+        events
+    }
+
+    /// Cache `events` as the result of `query`, evicting the least recently
+    /// used result when full. Results over [`QUERY_CACHE_MAX_ROWS`] events
+    /// are not kept.
+    ///
+    /// The caller must keep events from being committed between executing
+    /// `query` and this call, or a result they change could be cached.
+    pub fn insert(&self, query: &TemporalQuery, events: &[Event]) {
+        if self.capacity == 0 || events.len() > QUERY_CACHE_MAX_ROWS {
+            return;
+        }
+        let key = QueryKey::new(query);
+        let mut inner = self.inner.lock().expect("QueryResultCache poisoned lock");
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.capacity {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.remove(&oldest);
+            }
+        }
+        inner.tick += 1;
+        let entry = Entry {
+            query: query.clone(),
+            events: events.to_vec(),
+            last_used: inner.tick,
+        };
+        inner
+            .by_scope
+            .entry(Scope::of(query))
+            .or_default()
+            .insert(key.clone());
+        inner.entries.insert(key, entry);
+        inner.stats.entries = inner.entries.len();
+    }
+
+    /// Drop the results `event` changes, i.e. those of queries selecting
+    /// it. Returns how many were dropped.
+    pub fn invalidate(&self, event: &Event) -> usize {
+        let mut inner = self.inner.lock().expect("QueryResultCache poisoned lock");
+        if inner.entries.is_empty() {
+            return 0;
+        }
+        let scopes = [
+            Scope::Entity(event.entity_id().to_string()),
+            Scope::Type(event.event_type().to_string()),
+            Scope::Any,
+        ];
+        let stale: Vec<QueryKey> = scopes
+            .iter()
+            .filter_map(|scope| inner.by_scope.get(scope))
+            .flatten()
+            .filter(|key| {
+                let query = &inner.entries[*key].query;
+                query_selects(query, event)
+                    && query
+                        .field_predicates
+                        .iter()
+                        .all(|p| p.matches_event(event))
+                    && query.text_matches.iter().all(|t| t.matches_event(event))
+            })
+            .cloned()
+            .collect();
+        for key in &stale {
+            inner.remove(key);
+        }
+        inner.stats.invalidations += stale.len() as u64;
+        inner.stats.entries = inner.entries.len();
+        stale.len()
+    }
+
+    /// Drop every cached result
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().expect("QueryResultCache poisoned lock");
+        inner.entries.clear();
+        inner.by_scope.clear();
+        inner.stats.entries = 0;
+    }
+
+    /// Hit, miss and invalidation counters
+    pub fn stats(&self) -> QueryCacheStats {
+        self.inner
+            .lock()
+            .expect("QueryResultCache poisoned lock")
+            .stats
+    }
+}
+
+impl Default for QueryResultCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUERY_CACHE_ENTRIES)
+    }
+}
+
+impl std::fmt::Debug for QueryResultCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueryResultCache")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use crate::query::parser::TimeRange;
+
+    fn event(entity_id: &str, event_type: &str, secs: i64) -> Event {
+        let payload = EventPayload::from_json(&secs).unwrap();
+        let ts = Timestamp::from_secs(secs);
+        Event::new(event_type.to_string(), ts, entity_id.to_string(), payload)
+    }
+
+    #[test]
+    fn test_invalidates_only_affected_results() {
+        let cache = QueryResultCache::new(2);
+        let as_of = TimeRange::AsOf(Timestamp::from_secs(100).as_nanos());
+        let order = TemporalQuery::select()
+            .with_entity("order:1")
+            .with_time_range(as_of);
+        let payments = TemporalQuery::select().with_event_type("payment");
+        cache.insert(&order, &[event("order:1", "placed", 50)]);
+        cache.insert(&payments, &[]);
+        let same = TemporalQuery::select()
+            .with_entity("order:1")
+            .with_time_range(TimeRange::Between {
+                start: i64::MIN,
+                end: Timestamp::from_secs(100).as_nanos() + 1,
+            });
+        assert_eq!(cache.get(&same).unwrap().len(), 1);
+
+        // Later than the AS OF, another entity, another type: all kept.
+        assert_eq!(cache.invalidate(&event("order:1", "shipped", 200)), 0);
+        assert_eq!(cache.invalidate(&event("order:2", "placed", 50)), 0);
+        assert_eq!(cache.invalidate(&event("order:1", "paid", 60)), 1);
+        assert!(cache.get(&order).is_none());
+        assert_eq!(cache.invalidate(&event("user:1", "payment", 60)), 1);
+        assert_eq!(cache.stats().entries, 0);
+
+        // The least recently used result is evicted.
+        let other = TemporalQuery::select().with_entity("order:2");
+        cache.insert(&order, &[]);
+        cache.insert(&payments, &[]);
+        cache.get(&order);
+        cache.insert(&other, &[]);
+        assert!(cache.get(&order).is_some());
+        assert!(cache.get(&payments).is_none());
+    }
+}
@@ -0,0 +1,281 @@
+//! Row-level security.
+//!
+//! A [`SecurityPolicy`] attaches predicates to roles, such as
+//! `payload.tenant_id == $principal.tenant`. Every event returned to a
+//! [`Principal`] is checked server-side: it is visible if, for at least one
+//! of the principal's roles, all of that role's predicates hold. Roles
+//! without predicates see everything; principals without a known role see
+//! nothing.
+//!
+//! Predicates are conjunctions (`&&`) of `==` / `!=` comparisons between
+//! operands:
+//!
+//! - `payload.<path>`: a field of the event's JSON payload;
+//! - `entity_id`, `event_type`, `actor`: event metadata;
+//! - `$principal.id` or `$principal.<attribute>`;
+//! - JSON literals: `"text"`, numbers, `true`, `false`, `null`.
+//!
+//! A comparison involving a missing field or attribute is false, so
+//! policies fail closed.
+
+use crate::core::event::Event;
+use crate::error::{Error, Result};
+use crate::storage::field_cache::{FieldPath, FieldValue};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Identity a query runs as
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Principal {
+    /// Principal ID, available as `$principal.id`
+    pub id: String,
+    /// Role names
+    pub roles: Vec<String>,
+    /// Attributes available as `$principal.<name>`
+    pub attributes: HashMap<String, Value>,
+}
+
+impl Principal {
+    /// Principal without roles or attributes
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Grant `role`
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.roles.push(role.into());
+        self
+    }
+
+    /// Set attribute `name` to `value`
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// Side of a comparison
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Payload(FieldPath),
+    EntityId,
+    EventType,
+    Actor,
+    PrincipalId,
+    Attribute(String),
+    Literal(FieldValue),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        if let Some(path) = text.strip_prefix("payload.") {
+            return Ok(Self::Payload(FieldPath::parse(path)?));
+        }
+        if let Some(name) = text.strip_prefix("$principal.") {
+            return Ok(match name {
+                "id" => Self::PrincipalId,
+                _ if !name.is_empty() => Self::Attribute(name.to_string()),
+                _ => return Err(Error::Query("Empty principal attribute".to_string())),
+            });
+        }
+        match text {
+            "entity_id" => return Ok(Self::EntityId),
+            "event_type" => return Ok(Self::EventType),
+            "actor" => return Ok(Self::Actor),
+            _ => {}
+        }
+        serde_json::from_str::<Value>(text)
+            .ok()
+            .as_ref()
+            .and_then(FieldValue::from_json)
+            .map(Self::Literal)
+            .ok_or_else(|| Error::Query(format!("Invalid predicate operand: {}", text)))
+    }
+
+    fn evaluate(&self, event: &Event, principal: &Principal) -> Option<FieldValue> {
+        match self {
+            Self::Payload(path) => path.extract(event),
+            Self::EntityId => Some(FieldValue::Str(event.entity_id().to_string())),
+            Self::EventType => Some(FieldValue::Str(event.event_type().to_string())),
+            Self::Actor => event.metadata.actor.clone().map(FieldValue::Str),
+            Self::PrincipalId => Some(FieldValue::Str(principal.id.clone())),
+            Self::Attribute(name) => principal
+                .attributes
+                .get(name)
+                .and_then(FieldValue::from_json),
+            Self::Literal(value) => Some(value.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    left: Operand,
+    right: Operand,
+    equal: bool,
+}
+
+/// Row filter attached to a role, see the [module docs](self)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityPredicate {
+    source: String,
+    comparisons: Vec<Comparison>,
+}
+
+impl SecurityPredicate {
+    /// Parse a predicate such as `payload.tenant_id == $principal.tenant`
+    pub fn parse(source: &str) -> Result<Self> {
+        let comparisons = source
+            .split("&&")
+            .map(|clause| {
+                let (left, right, equal) = if let Some((l, r)) = clause.split_once("!=") {
+                    (l, r, false)
+                } else if let Some((l, r)) = clause.split_once("==") {
+                    (l, r, true)
+                } else {
+                    return Err(Error::Query(format!(
+                        "Expected == or != in predicate clause: {}",
+                        clause.trim()
+                    )));
+                };
+                Ok(Comparison {
+                    left: Operand::parse(left)?,
+                    right: Operand::parse(right)?,
+                    equal,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            source: source.trim().to_string(),
+            comparisons,
+        })
+    }
+
+    /// Whether `event` satisfies the predicate for `principal`
+    pub fn matches(&self, event: &Event, principal: &Principal) -> bool {
+        self.comparisons.iter().all(|c| {
+            match (
+                c.left.evaluate(event, principal),
+                c.right.evaluate(event, principal),
+            ) {
+                (Some(left), Some(right)) => {
+                    (left.partial_cmp(&right) == Some(Ordering::Equal)) == c.equal
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+impl fmt::Display for SecurityPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Row-level security predicates per role
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecurityPolicy {
+    roles: HashMap<String, Vec<SecurityPredicate>>,
+}
+
+impl SecurityPolicy {
+    /// Policy without roles
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare `role`, which sees every event unless predicates are added
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.roles.entry(role.into()).or_default();
+        self
+    }
+
+    /// Restrict `role` to events matching `predicate`
+    pub fn with_predicate(mut self, role: impl Into<String>, predicate: &str) -> Result<Self> {
+        let predicate = SecurityPredicate::parse(predicate)?;
+        self.roles.entry(role.into()).or_default().push(predicate);
+        Ok(self)
+    }
+
+    /// Predicates of `role`, if it is declared
+    pub fn predicates(&self, role: &str) -> Option<&[SecurityPredicate]> {
+        self.roles.get(role).map(Vec::as_slice)
+    }
+
+    /// Whether `principal` may see `event`
+    pub fn allows(&self, principal: &Principal, event: &Event) -> bool {
+        principal.roles.iter().any(|role| {
+            self.roles
+                .get(role)
+                .is_some_and(|predicates| predicates.iter().all(|p| p.matches(event, principal)))
+        })
+    }
+
+    /// Keep the events `principal` may see
+    pub fn filter(&self, principal: &Principal, events: Vec<Event>) -> Vec<Event> {
+        events
+            .into_iter()
+            .filter(|event| self.allows(principal, event))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+
+    fn event(tenant: &str, amount: i64) -> Event {
+        let payload =
+            EventPayload::from_json(&serde_json::json!({"tenant_id": tenant, "amount": amount}))
+                .unwrap();
+        Event::new(
+            "invoice.created".to_string(),
+            Timestamp::from_secs(1000),
+            format!("invoice:{}", tenant),
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_role_predicates() {
+        let policy = SecurityPolicy::new()
+            .with_role("admin")
+            .with_predicate("tenant", "payload.tenant_id == $principal.tenant")
+            .unwrap()
+            .with_predicate(
+                "small",
+                "payload.amount != 100 && event_type == \"invoice.created\"",
+            )
+            .unwrap();
+        let acme = Principal::new("alice")
+            .with_role("tenant")
+            .with_attribute("tenant", "acme");
+
+        assert!(policy.allows(&acme, &event("acme", 1)));
+        assert!(!policy.allows(&acme, &event("globex", 1)));
+        assert!(policy.allows(
+            &Principal::new("root").with_role("admin"),
+            &event("globex", 1)
+        ));
+        assert!(!policy.allows(&Principal::new("nobody"), &event("acme", 1)));
+        // A missing attribute never matches.
+        assert!(!policy.allows(
+            &Principal::new("bob").with_role("tenant"),
+            &event("acme", 1)
+        ));
+
+        let small = Principal::new("carol").with_role("small");
+        assert!(policy.allows(&small, &event("acme", 99)));
+        assert!(!policy.allows(&small, &event("acme", 100)));
+        assert!(SecurityPredicate::parse("payload.x > 1").is_err());
+    }
+}
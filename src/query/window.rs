@@ -0,0 +1,194 @@
+//! Window functions over ordered events.
+//!
+//! `GROUP BY WINDOW(ts, '5m')` folds a numeric payload field over tumbling
+//! windows, or over overlapping ones with a slide as in
+//! `WINDOW(ts, '5m', '1m')`; see [`WindowSpec`]. `LAG` and `LEAD` read a
+//! field of an earlier or later event of the same entity; see
+//! [`OffsetFn`]. Both are evaluated by the executor over the events a
+//! `SELECT` returns, which come in timestamp order.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::storage::{AggFn, FieldPath, FieldValue, WindowAggregator, WindowBucket};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Windows of a `GROUP BY WINDOW(ts, width[, slide])`.
+///
+/// Windows are aligned to the Unix epoch, so 5-minute windows start on the
+/// clock's 5 minutes whatever range is queried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSpec {
+    /// Length of each window
+    pub width: Duration,
+    /// Distance between the starts of consecutive windows
+    pub slide: Duration,
+}
+
+impl WindowSpec {
+    /// Back-to-back windows of `width`
+    pub fn tumbling(width: Duration) -> Self {
+        Self {
+            width,
+            slide: width,
+        }
+    }
+
+    /// Windows of `width` starting every `slide`
+    pub fn sliding(width: Duration, slide: Duration) -> Self {
+        Self { width, slide }
+    }
+
+    /// Parse the arguments of `WINDOW(ts, width[, slide])`, e.g. `"5m"`
+    pub fn parse(width: &str, slide: Option<&str>) -> Result<Self> {
+        let width = parse_interval(width)?;
+        match slide {
+            Some(slide) => Ok(Self::sliding(width, parse_interval(slide)?)),
+            None => Ok(Self::tumbling(width)),
+        }
+    }
+
+    /// Fold the field at `path` of `events` with `agg` over these windows.
+    ///
+    /// Events without a numeric value at `path` are skipped; only windows
+    /// with a value are returned, in time order.
+    pub fn aggregate<'a>(
+        &self,
+        events: impl IntoIterator<Item = &'a Event>,
+        agg: AggFn,
+        path: &FieldPath,
+    ) -> Result<Vec<WindowBucket>> {
+        let epoch = Timestamp::from_nanos(0);
+        let mut aggregator =
+            WindowAggregator::new(agg, epoch, self.width)?.with_slide(self.slide)?;
+        for event in events {
+            if let Some(value) = path.extract(event).and_then(|v| v.as_f64()) {
+                aggregator.add(event.timestamp(), value);
+            }
+        }
+        Ok(aggregator.finish())
+    }
+}
+
+/// Parse an interval such as `250ms`, `30s`, `5m`, `1h` or `7d`; quotes
+/// around it are ignored
+pub fn parse_interval(text: &str) -> Result<Duration> {
+    let text = text.trim().trim_matches('\'');
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (amount, unit) = text.split_at(split);
+    let invalid = || Error::Query(format!("Invalid interval: '{}'", text));
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit.trim() {
+        "ms" => return Ok(Duration::from_millis(amount)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(invalid()),
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// `LAG` or `LEAD` by a number of events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OffsetFn {
+    /// Value of the event this many events earlier
+    Lag(usize),
+    /// Value of the event this many events later
+    Lead(usize),
+}
+
+impl OffsetFn {
+    /// The value at `path` of the event `self` points to, for each of
+    /// `events`, looking only at events of the same entity.
+    ///
+    /// `events` must be in timestamp order; rows keep that order.
+    pub fn evaluate(&self, events: Vec<Event>, path: &FieldPath) -> Vec<OffsetRow> {
+        let mut partitions: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, event) in events.iter().enumerate() {
+            partitions.entry(event.entity_id()).or_default().push(i);
+        }
+        let mut values = vec![None; events.len()];
+        for positions in partitions.values() {
+            for (n, &i) in positions.iter().enumerate() {
+                let source = match *self {
+                    OffsetFn::Lag(offset) => n.checked_sub(offset),
+                    OffsetFn::Lead(offset) => n.checked_add(offset),
+                };
+                values[i] = source
+                    .and_then(|source| positions.get(source))
+                    .and_then(|&j| path.extract(&events[j]));
+            }
+        }
+        events
+            .into_iter()
+            .zip(values)
+            .map(|(event, value)| OffsetRow { event, value })
+            .collect()
+    }
+}
+
+/// An event with the value `LAG` or `LEAD` found for it
+#[derive(Debug, Clone)]
+pub struct OffsetRow {
+    /// The event
+    pub event: Event,
+    /// Value of the field at the offset event, `None` past either end of
+    /// the entity's events or where the field is missing
+    pub value: Option<FieldValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn reading(entity_id: &str, secs: i64, value: f64) -> Event {
+        let payload = EventPayload::from_json(&serde_json::json!({ "value": value })).unwrap();
+        let ts = Timestamp::from_secs(secs);
+        Event::new("reading".to_string(), ts, entity_id.to_string(), payload)
+    }
+
+    #[test]
+    fn test_windows_and_offsets() {
+        let events = vec![
+            reading("sensor:1", 0, 1.0),
+            reading("sensor:2", 100, 10.0),
+            reading("sensor:1", 200, 2.0),
+            reading("sensor:1", 400, 4.0),
+        ];
+        let path = FieldPath::parse("value").unwrap();
+
+        let spec = WindowSpec::parse("'5m'", None).unwrap();
+        let sums = spec.aggregate(&events, AggFn::Sum, &path).unwrap();
+        let sums: Vec<f64> = sums.iter().map(|b| b.value).collect();
+        assert_eq!(sums, [13.0, 4.0]);
+        let spec = WindowSpec::parse("5m", Some("100s")).unwrap();
+        assert_eq!(
+            spec.aggregate(&events, AggFn::Count, &path).unwrap().len(),
+            7
+        );
+        assert!(WindowSpec::parse("5 minutes", None).is_err());
+
+        let lag = OffsetFn::Lag(1).evaluate(events.clone(), &path);
+        let lagged: Vec<_> = lag
+            .iter()
+            .map(|r| r.value.as_ref().and_then(FieldValue::as_f64))
+            .collect();
+        assert_eq!(lagged, [None, None, Some(1.0), Some(2.0)]);
+        let lead = OffsetFn::Lead(2).evaluate(events, &path);
+        assert_eq!(
+            lead[0].value.as_ref().and_then(FieldValue::as_f64),
+            Some(4.0)
+        );
+        assert!(lead[2].value.is_none());
+    }
+}
@@ -0,0 +1,489 @@
+//! Asynchronous write-ahead log.
+//!
+//! [`WriteAheadLog`](crate::storage::WriteAheadLog) performs blocking file
+//! I/O, which stalls the async append path. This module provides an async
+//! counterpart whose file-backed implementation writes through `tokio::fs`
+//! and replays on the blocking thread pool. The on-disk record format is the
+//! same as [`FileWAL`], so files can be read by either implementation.
+
+use crate::core::event::Event;
+use crate::error::{Error, Result};
+use crate::storage::checksum::ChecksumKind;
+use crate::storage::journal::Lsn;
+use crate::storage::wal::{
+    FileWAL, InMemoryWAL, RecordCodec, RecoveryMode, RecoveryReport, WalCompression, WalFileState,
+    WriteAheadLog,
+};
+use crate::storage::wal_cipher::{KeyProvider, WalEncryptionKey};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Asynchronous write-ahead log trait
+#[async_trait]
+pub trait AsyncWriteAheadLog: Send + Sync {
+    /// Append an event to the WAL
+    async fn append(&mut self, event: &Event) -> Result<()>;
+
+    /// Append events to the WAL in one write, so a failure appends none of
+    /// them.
+    ///
+    /// The default appends them one by one, for logs whose appends cannot
+    /// fail halfway.
+    async fn append_batch(&mut self, events: &[Event]) -> Result<()> {
+        for event in events {
+            self.append(event).await?;
+        }
+        Ok(())
+    }
+
+    /// Flush the WAL to disk
+    async fn flush(&mut self) -> Result<()>;
+
+    /// Replay events from the WAL.
+    ///
+    /// Records appended since the last `flush` may not be visible.
+    async fn replay(&self) -> Result<Vec<Event>>;
+
+    /// Replay events from the WAL as a stream.
+    ///
+    /// The default collects [`AsyncWriteAheadLog::replay`]; file-backed logs
+    /// override it to read records incrementally so memory stays bounded.
+    async fn replay_stream(&self) -> Result<BoxStream<'static, Result<Event>>> {
+        let events = self.replay().await?;
+        Ok(stream::iter(events.into_iter().map(Ok)).boxed())
+    }
+
+    /// Replay events from the WAL together with their LSNs.
+    ///
+    /// The default numbers the events from [`AsyncWriteAheadLog::replay`]
+    /// so that the last one carries [`AsyncWriteAheadLog::last_lsn`].
+    async fn replay_records(&self) -> Result<Vec<(Lsn, Event)>> {
+        let events = self.replay().await?;
+        let first = (self.last_lsn() + 1).saturating_sub(events.len() as Lsn);
+        Ok((first..).zip(events).collect())
+    }
+
+    /// Replay events from the WAL together with their LSNs as a stream.
+    ///
+    /// The default collects [`AsyncWriteAheadLog::replay_records`];
+    /// file-backed logs override it to keep memory bounded, which recovery
+    /// relies on for large logs.
+    async fn replay_record_stream(&self) -> Result<BoxStream<'static, Result<(Lsn, Event)>>> {
+        let records = self.replay_records().await?;
+        Ok(stream::iter(records.into_iter().map(Ok)).boxed())
+    }
+
+    /// Clear the WAL (after checkpoint)
+    async fn clear(&mut self) -> Result<()>;
+
+    /// LSN of the most recently appended record, or `0` if none
+    fn last_lsn(&self) -> Lsn;
+
+    /// Continue numbering after `lsn` if it is past the last record.
+    ///
+    /// Used on recovery when the records up to `lsn` were truncated after
+    /// being cataloged elsewhere.
+    fn advance_lsn(&mut self, lsn: Lsn);
+
+    /// Path of the backing file, for logs that live in a single file
+    fn file_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Write records in format `version`, see
+    /// [`FileWAL::with_write_version`]. Logs without an on-disk format
+    /// have nothing to pin.
+    async fn set_write_version(&mut self, _version: u32) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsyncWriteAheadLog for InMemoryWAL {
+    async fn append(&mut self, event: &Event) -> Result<()> {
+        WriteAheadLog::append(self, event)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        WriteAheadLog::flush(self)
+    }
+
+    async fn replay(&self) -> Result<Vec<Event>> {
+        WriteAheadLog::replay(self)
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        WriteAheadLog::clear(self)
+    }
+
+    fn last_lsn(&self) -> Lsn {
+        WriteAheadLog::last_lsn(self)
+    }
+
+    fn advance_lsn(&mut self, lsn: Lsn) {
+        InMemoryWAL::advance_lsn(self, lsn)
+    }
+}
+
+/// When [`AsyncFileWAL`] forces appended records to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSyncPolicy {
+    /// Sync after every append; nothing acknowledged is lost on power failure
+    Always,
+    /// Sync only on [`AsyncWriteAheadLog::flush`]
+    #[default]
+    OnFlush,
+}
+
+/// Number of decoded events buffered ahead of a [`AsyncFileWAL`] replay stream
+const REPLAY_STREAM_BUFFER: usize = 1024;
+
+/// File-backed WAL that does not block the async runtime.
+///
+/// Appends are written with `tokio::fs`; replay reuses the [`FileWAL`]
+/// reader on the blocking thread pool.
+pub struct AsyncFileWAL {
+    path: PathBuf,
+    file: File,
+    recovery_mode: RecoveryMode,
+    codec: RecordCodec,
+    sync_policy: WalSyncPolicy,
+    state: WalFileState,
+}
+
+impl AsyncFileWAL {
+    /// Open (or create) a WAL file at the given path.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let scan_path = path.to_path_buf();
+        let state = tokio::task::spawn_blocking(move || RecordCodec::prepare_file(&scan_path))
+            .await
+            .map_err(|e| Error::Storage(format!("WAL scan task failed: {}", e)))??;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            recovery_mode: RecoveryMode::default(),
+            codec: RecordCodec::default(),
+            sync_policy: WalSyncPolicy::default(),
+            state,
+        })
+    }
+
+    /// Continue numbering after `lsn`. See [`FileWAL::with_base_lsn`].
+    pub fn with_base_lsn(mut self, lsn: Lsn) -> Self {
+        AsyncWriteAheadLog::advance_lsn(&mut self, lsn);
+        self
+    }
+
+    /// Format version of the file, see [`WAL_VERSION`](crate::storage::wal::WAL_VERSION)
+    pub fn format_version(&self) -> u32 {
+        self.state.version
+    }
+
+    /// Blocking reader over the same file and settings, for replay
+    fn blocking_reader(&self) -> impl FnOnce() -> Result<FileWAL> {
+        let path = self.path.clone();
+        let mode = self.recovery_mode;
+        let codec = self.codec.clone();
+        let base_lsn = self.state.base_lsn;
+        move || {
+            Ok(FileWAL::open(path)?
+                .with_recovery_mode(mode)
+                .with_codec(codec)
+                .with_base_lsn(base_lsn))
+        }
+    }
+
+    /// Protect new records with the given checksum. See [`FileWAL::with_checksum`].
+    pub fn with_checksum(mut self, checksum: ChecksumKind) -> Self {
+        self.codec.checksum = checksum;
+        self
+    }
+
+    /// Encrypt records with `key`. See [`FileWAL::with_encryption`].
+    pub fn with_encryption(mut self, key: &WalEncryptionKey) -> Self {
+        self.codec.keys = Some(RecordCodec::single_key(key));
+        self
+    }
+
+    /// Encrypt records with the keys of `keys`. See
+    /// [`FileWAL::with_key_provider`].
+    pub fn with_key_provider(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.codec.keys = Some(keys);
+        self
+    }
+
+    /// Compress large records. See [`FileWAL::with_compression`].
+    pub fn with_compression(mut self, compression: WalCompression) -> Self {
+        self.codec.compression = Some(compression);
+        self
+    }
+
+    /// Set when appends are synced to disk.
+    pub fn with_sync_policy(mut self, policy: WalSyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Set the recovery mode used by replay.
+    pub fn with_recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Path of the WAL file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replay the WAL according to the configured recovery mode and report
+    /// how many records were recovered or skipped.
+    pub async fn replay_with_report(&self) -> Result<(Vec<Event>, RecoveryReport)> {
+        let reader = self.blocking_reader();
+        tokio::task::spawn_blocking(move || reader()?.replay_with_report())
+            .await
+            .map_err(|e| Error::Storage(format!("WAL replay task failed: {}", e)))?
+    }
+}
+
+#[async_trait]
+impl AsyncWriteAheadLog for AsyncFileWAL {
+    async fn append(&mut self, event: &Event) -> Result<()> {
+        let lsn = self.state.last_lsn + 1;
+        let record = self.codec.encode_for(self.state.version, event, lsn)?;
+        self.file.write_all(&record).await?;
+        if self.sync_policy == WalSyncPolicy::Always {
+            self.file.flush().await?;
+            self.file.sync_data().await?;
+        }
+        self.state.appended(lsn);
+        Ok(())
+    }
+
+    async fn append_batch(&mut self, events: &[Event]) -> Result<()> {
+        let first = self.state.last_lsn + 1;
+        let mut records = Vec::new();
+        for (lsn, event) in (first..).zip(events) {
+            records.extend(self.codec.encode_for(self.state.version, event, lsn)?);
+        }
+        // Complete earlier writes, so the length is where the batch starts.
+        self.file.flush().await?;
+        let start = self.file.metadata().await?.len();
+        let written = async {
+            self.file.write_all(&records).await?;
+            self.file.flush().await?;
+            if self.sync_policy == WalSyncPolicy::Always {
+                self.file.sync_data().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        if let Err(e) = written.await {
+            // Drop whatever part of the batch reached the file.
+            self.file.set_len(start).await?;
+            return Err(e.into());
+        }
+        for lsn in (first..).take(events.len()) {
+            self.state.appended(lsn);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    async fn replay(&self) -> Result<Vec<Event>> {
+        let (events, _) = self.replay_with_report().await?;
+        Ok(events)
+    }
+
+    async fn replay_records(&self) -> Result<Vec<(Lsn, Event)>> {
+        let mut records = self.replay_record_stream().await?;
+        let mut collected = Vec::new();
+        while let Some(record) = records.next().await {
+            collected.push(record?);
+        }
+        Ok(collected)
+    }
+
+    async fn replay_stream(&self) -> Result<BoxStream<'static, Result<Event>>> {
+        Ok(self
+            .replay_record_stream()
+            .await?
+            .map(|record| record.map(|(_, event)| event))
+            .boxed())
+    }
+
+    async fn replay_record_stream(&self) -> Result<BoxStream<'static, Result<(Lsn, Event)>>> {
+        let reader = self.blocking_reader();
+        let (tx, rx) = tokio::sync::mpsc::channel(REPLAY_STREAM_BUFFER);
+
+        // The reader blocks on a full channel, so at most
+        // REPLAY_STREAM_BUFFER events are decoded ahead of the consumer.
+        tokio::task::spawn_blocking(move || {
+            let iter = reader().and_then(|wal| wal.replay_iter());
+            let mut iter = match iter {
+                Ok(iter) => iter,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            while let Some(item) = iter.next() {
+                let record = item.map(|event| (iter.last_lsn(), event));
+                if tx.blocking_send(record).is_err() {
+                    // Consumer dropped the stream.
+                    break;
+                }
+            }
+        });
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })
+        .boxed())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)
+            .await?;
+        // Reopen in append mode so later writes land at the end.
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        self.state.reset();
+        self.file
+            .write_all(&RecordCodec::file_header(self.state.version))
+            .await?;
+        self.file.sync_all().await?;
+        Ok(())
+    }
+
+    fn last_lsn(&self) -> Lsn {
+        self.state.last_lsn
+    }
+
+    fn advance_lsn(&mut self, lsn: Lsn) {
+        self.state.advance(lsn);
+    }
+
+    async fn set_write_version(&mut self, version: u32) -> Result<()> {
+        if self.state.pin(&self.codec, version)? {
+            self.file.set_len(0).await?;
+            if let Some(header) = self.state.reset() {
+                self.file.write_all(&header).await?;
+            }
+            self.file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    fn file_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_async_file_wal_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let mut wal = AsyncFileWAL::open(&path).await.unwrap();
+
+        for i in 0..3 {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            let event = Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                "entity:1".to_string(),
+                payload,
+            );
+            wal.append(&event).await.unwrap();
+        }
+        wal.flush().await.unwrap();
+        assert_eq!(wal.replay().await.unwrap().len(), 3);
+
+        let streamed: Vec<Event> = wal
+            .replay_stream()
+            .await
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect()
+            .await;
+        assert_eq!(streamed.len(), 3);
+        let lsns: Vec<Lsn> = wal
+            .replay_record_stream()
+            .await
+            .unwrap()
+            .map(|record| record.unwrap().0)
+            .collect()
+            .await;
+        assert_eq!(lsns, vec![1, 2, 3]);
+
+        // Records are compatible with the blocking implementation.
+        assert_eq!(FileWAL::open(&path).unwrap().replay().unwrap().len(), 3);
+
+        wal.clear().await.unwrap();
+        assert!(wal.replay().await.unwrap().is_empty());
+        assert_eq!(wal.last_lsn(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_async_file_wal_appends_a_batch() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let mut wal = AsyncFileWAL::open(&path).await.unwrap();
+        let event = |i: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            let entity = "entity:1".to_string();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(i),
+                entity,
+                payload,
+            )
+        };
+
+        let batch: Vec<Event> = (0..3).map(event).collect();
+        wal.append_batch(&batch).await.unwrap();
+        wal.append(&event(3)).await.unwrap();
+        assert_eq!(wal.last_lsn(), 4);
+        let records = wal.replay_records().await.unwrap();
+        let lsns: Vec<Lsn> = records.iter().map(|(lsn, _)| *lsn).collect();
+        assert_eq!(lsns, vec![1, 2, 3, 4]);
+        assert_eq!(records[2].1.id(), batch[2].id());
+    }
+}
@@ -0,0 +1,182 @@
+//! Decoded segment block cache.
+//!
+//! Reading an event from a segment means decompressing and deserializing its
+//! whole block. The [`BlockCache`] keeps a bounded number of recently used
+//! decoded blocks so that repeated reads of hot entities don't pay that cost
+//! every time. Blocks are immutable once written, so entries never go stale
+//! until their segment is removed.
+
+use crate::core::event::Event;
+use crate::error::Result;
+use crate::storage::field_cache::BlockId;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Default number of decoded blocks kept in a [`BlockCache`]
+pub const DEFAULT_BLOCK_CACHE_BLOCKS: usize = 64;
+
+/// Hit/miss counters for a [`BlockCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups that had to read the block from disk
+    pub misses: u64,
+    /// Blocks currently cached
+    pub blocks: usize,
+}
+
+struct CacheInner {
+    /// Cached blocks with the tick of their last use
+    blocks: HashMap<BlockId, (Arc<Vec<Event>>, u64)>,
+    /// Last-use tick to block, oldest first
+    recency: BTreeMap<u64, BlockId>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheInner {
+    fn touch(&mut self, id: BlockId) -> Option<Arc<Vec<Event>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (events, last_used) = self.blocks.get_mut(&id)?;
+        self.recency.remove(last_used);
+        *last_used = tick;
+        self.recency.insert(tick, id);
+        Some(events.clone())
+    }
+}
+
+/// Bounded LRU cache of decoded segment blocks
+pub struct BlockCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` blocks
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                blocks: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        self.inner.lock().expect("BlockCache poisoned lock")
+    }
+
+    /// Get the events of `block`, calling `load` to read them on a miss
+    pub fn get_or_load(
+        &self,
+        block: BlockId,
+        load: impl FnOnce() -> Result<Vec<Event>>,
+    ) -> Result<Arc<Vec<Event>>> {
+        {
+            let mut inner = self.lock();
+            if let Some(events) = inner.touch(block) {
+                inner.hits += 1;
+                return Ok(events);
+            }
+            inner.misses += 1;
+        }
+
+        // Decode outside the lock so concurrent lookups of other blocks proceed.
+        let events = Arc::new(load()?);
+        if self.capacity == 0 {
+            return Ok(events);
+        }
+
+        let mut inner = self.lock();
+        if inner.touch(block).is_none() {
+            let tick = inner.tick;
+            inner.blocks.insert(block, (events.clone(), tick));
+            inner.recency.insert(tick, block);
+        }
+        while inner.blocks.len() > self.capacity {
+            match inner.recency.pop_first() {
+                Some((_, old)) => {
+                    inner.blocks.remove(&old);
+                }
+                None => break,
+            }
+        }
+        Ok(events)
+    }
+
+    /// Drop all cached blocks belonging to a segment
+    pub fn invalidate_segment(&self, segment_id: u64) {
+        let mut inner = self.lock();
+        inner
+            .blocks
+            .retain(|block, _| block.segment_id != segment_id);
+        inner
+            .recency
+            .retain(|_, block| block.segment_id != segment_id);
+    }
+
+    /// Most blocks the cache holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Current cache statistics
+    pub fn stats(&self) -> BlockCacheStats {
+        let inner = self.lock();
+        BlockCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            blocks: inner.blocks.len(),
+        }
+    }
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCK_CACHE_BLOCKS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(offset: u64) -> BlockId {
+        BlockId {
+            segment_id: 1,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(2);
+        cache.get_or_load(block(1), || Ok(Vec::new())).unwrap();
+        cache.get_or_load(block(2), || Ok(Vec::new())).unwrap();
+        // Touch block 1 so block 2 becomes the least recently used.
+        cache.get_or_load(block(1), || unreachable!()).unwrap();
+        cache.get_or_load(block(3), || Ok(Vec::new())).unwrap();
+
+        assert!(cache.get_or_load(block(1), || unreachable!()).is_ok());
+        let mut reloaded = false;
+        cache
+            .get_or_load(block(2), || {
+                reloaded = true;
+                Ok(Vec::new())
+            })
+            .unwrap();
+        assert!(reloaded);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.blocks), (2, 4, 2));
+        cache.invalidate_segment(1);
+        assert_eq!(cache.stats().blocks, 0);
+    }
+}
@@ -0,0 +1,267 @@
+//! Entity catalog: sorted set of every entity ID in a journal.
+//!
+//! The catalog answers "which entities exist" without scanning events. It
+//! is kept in entity order so prefix scans and pagination only visit the
+//! requested range: a page starts after the last entity ID of the previous
+//! page (the cursor), so paging through millions of entities costs the
+//! same per page.
+//!
+//! An [`EntityFilter`] over the same IDs answers most lookups of unknown
+//! entities without searching the set.
+//!
+//! The segment manager persists the catalog next to the manifest, stamped
+//! with the append position it covers. A catalog that does not match the
+//! manifest is rebuilt from the segment indexes on open.
+
+use crate::core::namespace::is_internal_entity;
+use crate::error::{Error, Result};
+use crate::storage::entity_filter::EntityFilter;
+use crate::storage::journal::Lsn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+/// Entity catalog file name inside a segment directory
+pub const ENTITY_CATALOG_FILE: &str = "ENTITIES";
+
+/// Current entity catalog format version
+pub const ENTITY_CATALOG_VERSION: u32 = 1;
+
+/// One page of entity IDs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntityPage {
+    /// Entity IDs, in order
+    pub entities: Vec<String>,
+    /// Cursor of the next page; `None` on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl EntityPage {
+    /// Build a page of at most `limit` IDs from `ids`, which must be sorted
+    /// and start at [`page_start`]. Internal entities are left out.
+    pub fn from_sorted<'a>(ids: impl Iterator<Item = &'a str>, prefix: &str, limit: usize) -> Self {
+        let mut entities: Vec<String> = ids
+            .take_while(|id| id.starts_with(prefix))
+            .filter(|id| !is_internal_entity(id))
+            .take(limit.saturating_add(1))
+            .map(str::to_string)
+            .collect();
+        let next_cursor = if entities.len() > limit {
+            entities.truncate(limit);
+            entities.last().cloned()
+        } else {
+            None
+        };
+        Self {
+            entities,
+            next_cursor,
+        }
+    }
+}
+
+/// First key of the page after `cursor` among IDs starting with `prefix`
+pub fn page_start<'a>(prefix: &'a str, cursor: Option<&'a str>) -> Bound<&'a str> {
+    match cursor {
+        Some(cursor) if cursor >= prefix => Bound::Excluded(cursor),
+        _ => Bound::Included(prefix),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CatalogFile {
+    version: u32,
+    position: Lsn,
+    entities: Vec<String>,
+}
+
+/// Sorted set of entity IDs
+#[derive(Debug, Clone, Default)]
+pub struct EntityCatalog {
+    entities: BTreeSet<String>,
+    /// Filter of `entities`, for fast misses
+    filter: EntityFilter,
+    /// Whether entities changed since the catalog was last stored
+    dirty: bool,
+    /// Append position the catalog was last stored as covering
+    stored_position: Option<Lsn>,
+}
+
+impl EntityCatalog {
+    /// Empty catalog
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Catalog of `ids`
+    pub fn from_ids<I, S>(ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::from_set(ids.into_iter().map(Into::into).collect(), true, None)
+    }
+
+    fn from_set(entities: BTreeSet<String>, dirty: bool, stored_position: Option<Lsn>) -> Self {
+        Self {
+            filter: EntityFilter::from_ids(entities.iter().map(String::as_str)),
+            entities,
+            dirty,
+            stored_position,
+        }
+    }
+
+    /// Record that `entity_id` has events
+    pub fn record(&mut self, entity_id: &str) {
+        if !self.contains(entity_id) {
+            self.entities.insert(entity_id.to_string());
+            self.filter.insert(entity_id);
+            self.dirty = true;
+        }
+    }
+
+    /// Whether `entity_id` is cataloged
+    pub fn contains(&self, entity_id: &str) -> bool {
+        self.filter.may_contain(entity_id) && self.entities.contains(entity_id)
+    }
+
+    /// Whether `entity_id` may be cataloged; `false` means it is not. Cheaper
+    /// than [`EntityCatalog::contains`], but wrong for a few unknown IDs.
+    pub fn may_contain(&self, entity_id: &str) -> bool {
+        self.filter.may_contain(entity_id)
+    }
+
+    /// Number of cataloged entities
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    /// Whether the catalog is empty
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Up to `limit` entity IDs starting with `prefix`, after `cursor`
+    pub fn page(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> EntityPage {
+        let ids = self
+            .entities
+            .range::<str, _>((page_start(prefix, cursor), Bound::Unbounded))
+            .map(String::as_str);
+        EntityPage::from_sorted(ids, prefix, limit)
+    }
+
+    /// Path of the catalog file inside `dir`
+    pub fn path_in<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join(ENTITY_CATALOG_FILE)
+    }
+
+    /// Load the catalog from `dir` if it covers exactly append position
+    /// `position`; `None` if it is missing, stale or unreadable.
+    pub fn load<P: AsRef<Path>>(dir: P, position: Lsn) -> Result<Option<Self>> {
+        let path = Self::path_in(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(&path)?;
+        let file: CatalogFile = match bincode::deserialize(&data) {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!("Ignoring unreadable entity catalog {:?}: {}", path, e);
+                return Ok(None);
+            }
+        };
+        if file.version != ENTITY_CATALOG_VERSION {
+            return Err(Error::Storage(format!(
+                "Unsupported entity catalog version: {}",
+                file.version
+            )));
+        }
+        if file.position != position {
+            return Ok(None);
+        }
+        let entities = file.entities.into_iter().collect();
+        Ok(Some(Self::from_set(entities, false, Some(position))))
+    }
+
+    /// Atomically persist the catalog into `dir` as covering append
+    /// position `position`, unless it was already stored that way.
+    pub fn store<P: AsRef<Path>>(&mut self, dir: P, position: Lsn) -> Result<()> {
+        if !self.dirty && self.stored_position == Some(position) {
+            return Ok(());
+        }
+        let dir = dir.as_ref();
+        let tmp_path = dir.join(format!("{ENTITY_CATALOG_FILE}.tmp"));
+        let file = CatalogFile {
+            version: ENTITY_CATALOG_VERSION,
+            position,
+            entities: self.entities.iter().cloned().collect(),
+        };
+        let data = bincode::serialize(&file)?;
+        let mut tmp = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, Self::path_in(dir))?;
+        if let Ok(dir_handle) = File::open(dir) {
+            let _ = dir_handle.sync_all();
+        }
+        self.dirty = false;
+        self.stored_position = Some(position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_and_persistence() {
+        let mut catalog = EntityCatalog::new();
+        for i in 0..25 {
+            catalog.record(&format!("user:{:02}", i));
+        }
+        catalog.record("order:1");
+        catalog.record("user:00");
+        assert_eq!(catalog.len(), 26);
+
+        let mut cursor = None;
+        let mut seen = Vec::new();
+        loop {
+            let page = catalog.page("user:", cursor.as_deref(), 10);
+            assert!(page.entities.len() <= 10);
+            seen.extend(page.entities);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(
+            seen,
+            (0..25)
+                .map(|i| format!("user:{:02}", i))
+                .collect::<Vec<_>>()
+        );
+        // A cursor before the prefix starts at the prefix.
+        assert_eq!(
+            catalog.page("user:", Some("a"), 1).entities,
+            vec!["user:00"]
+        );
+        assert_eq!(catalog.page("", None, 1).entities, vec!["order:1"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        catalog.store(dir.path(), 26).unwrap();
+        assert_eq!(
+            EntityCatalog::load(dir.path(), 26).unwrap().unwrap().len(),
+            26
+        );
+        assert!(EntityCatalog::load(dir.path(), 27).unwrap().is_none());
+    }
+}
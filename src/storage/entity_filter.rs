@@ -0,0 +1,158 @@
+//! Probabilistic filter of known entity IDs.
+//!
+//! An [`EntityFilter`] is a bloom filter: it answers "does this entity
+//! exist" with no false negatives and a small share of false positives, in
+//! a few hashes instead of a tree lookup or one index probe per segment.
+//! Lookups of entities that never had events, common when callers probe
+//! for cache misses, return before any segment or timeline is touched.
+//!
+//! The filter grows by adding layers, each twice as large as the last and
+//! with half its false positive rate, so the overall rate stays below
+//! [`ENTITY_FILTER_FALSE_POSITIVE_RATE`] however many entities are added.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bound on the share of unknown entity IDs an [`EntityFilter`] lets through
+pub const ENTITY_FILTER_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Entities the first layer of an [`EntityFilter`] is sized for
+pub const ENTITY_FILTER_INITIAL_CAPACITY: usize = 1024;
+
+/// One fixed-size bloom filter
+#[derive(Debug, Clone)]
+struct Layer {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: usize,
+    false_positive_rate: f64,
+    len: usize,
+}
+
+impl Layer {
+    fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (capacity as f64 * -false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = (-false_positive_rate.log2()).ceil().max(1.0) as u32;
+        Self {
+            bits: vec![0; bits.div_ceil(64).max(1)],
+            hashes,
+            capacity,
+            false_positive_rate,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        for position in positions(self.bits.len(), self.hashes, hash) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+        self.len += 1;
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        positions(self.bits.len(), self.hashes, hash)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// Bloom filter of entity IDs
+#[derive(Debug, Clone)]
+pub struct EntityFilter {
+    layers: Vec<Layer>,
+}
+
+impl EntityFilter {
+    /// Empty filter
+    pub fn new() -> Self {
+        let first = Layer::new(
+            ENTITY_FILTER_INITIAL_CAPACITY,
+            ENTITY_FILTER_FALSE_POSITIVE_RATE / 2.0,
+        );
+        Self {
+            layers: vec![first],
+        }
+    }
+
+    /// Filter of `ids`
+    pub fn from_ids<'a>(ids: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut filter = Self::new();
+        for id in ids {
+            filter.insert(id);
+        }
+        filter
+    }
+
+    /// Add `entity_id`; adding an entity twice counts it twice
+    pub fn insert(&mut self, entity_id: &str) {
+        let last = self.layers.last().expect("EntityFilter without layers");
+        if last.len >= last.capacity {
+            let layer = Layer::new(last.capacity * 2, last.false_positive_rate / 2.0);
+            self.layers.push(layer);
+        }
+        let hash = hash(entity_id);
+        if let Some(layer) = self.layers.last_mut() {
+            layer.insert(hash);
+        }
+    }
+
+    /// Whether `entity_id` may have been added; `false` means it was not
+    pub fn may_contain(&self, entity_id: &str) -> bool {
+        let hash = hash(entity_id);
+        self.layers.iter().any(|layer| layer.may_contain(hash))
+    }
+
+    /// Number of entities added
+    pub fn len(&self) -> usize {
+        self.layers.iter().map(|layer| layer.len).sum()
+    }
+
+    /// Whether no entity was added
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes taken by the filter bits
+    pub fn size_bytes(&self) -> usize {
+        self.layers.iter().map(|layer| layer.bits.len() * 8).sum()
+    }
+}
+
+impl Default for EntityFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bit positions, in a filter of `words` words, of the entity hashed to
+/// `hash`, by double hashing
+fn positions(words: usize, hashes: u32, hash: u64) -> impl Iterator<Item = usize> {
+    let bits = words as u64 * 64;
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+fn hash(entity_id: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    entity_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_bounded_false_positives() {
+        let known: Vec<String> = (0..10_000).map(|i| format!("user:{}", i)).collect();
+        let filter = EntityFilter::from_ids(known.iter().map(String::as_str));
+        assert_eq!(filter.len(), 10_000);
+        assert!(known.iter().all(|id| filter.may_contain(id)));
+
+        let unknown = (0..10_000)
+            .filter(|i| filter.may_contain(&format!("order:{}", i)))
+            .count();
+        assert!(unknown < 150, "{} false positives", unknown);
+        assert!(!EntityFilter::new().may_contain("user:0"));
+    }
+}
@@ -0,0 +1,169 @@
+//! Continuation cursors for paging through an entity's events.
+//!
+//! An [`EventCursor`] marks the last event of a page by its timestamp and
+//! where it is stored: its segment and its position in that segment. The
+//! next page starts right after the mark, so a journal only reads blocks
+//! from the cursor's timestamp on, however deep into a long history a
+//! client has paged. Clients see cursors as opaque tokens.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Position of an event in an entity's history.
+///
+/// Cursors order events by timestamp, then by where they are stored, i.e.
+/// events sharing a timestamp come in the order they were appended. Events
+/// appended later therefore never land before a cursor already handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct EventCursor {
+    timestamp: Timestamp,
+    segment_id: u64,
+    offset: u64,
+}
+
+impl EventCursor {
+    /// Cursor of the event at `timestamp` stored at position `offset` of
+    /// segment `segment_id`.
+    ///
+    /// Journals without segments use segment `0` and the event's position
+    /// among the events sharing its timestamp.
+    pub fn new(timestamp: Timestamp, segment_id: u64, offset: u64) -> Self {
+        Self {
+            timestamp,
+            segment_id,
+            offset,
+        }
+    }
+
+    /// Cursor whose next page starts with the first event at `timestamp`
+    pub fn before(timestamp: Timestamp) -> Self {
+        let previous = timestamp.as_nanos().saturating_sub(1);
+        Self::new(Timestamp::from_nanos(previous), u64::MAX, u64::MAX)
+    }
+
+    /// Timestamp of the event
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    /// Segment the event is stored in
+    pub fn segment_id(&self) -> u64 {
+        self.segment_id
+    }
+
+    /// Position of the event in its segment
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.timestamp.as_nanos() as u64;
+        write!(
+            f,
+            "{:016x}{:016x}{:016x}",
+            nanos, self.segment_id, self.offset
+        )
+    }
+}
+
+impl FromStr for EventCursor {
+    type Err = Error;
+
+    fn from_str(token: &str) -> Result<Self> {
+        let invalid = || Error::Query(format!("Invalid event cursor: {}", token));
+        if token.len() != 48 || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let field = |i: usize| u64::from_str_radix(&token[i * 16..(i + 1) * 16], 16);
+        match (field(0), field(1), field(2)) {
+            (Ok(nanos), Ok(segment_id), Ok(offset)) => {
+                let timestamp = Timestamp::from_nanos(nanos as i64);
+                Ok(Self::new(timestamp, segment_id, offset))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<EventCursor> for String {
+    fn from(cursor: EventCursor) -> Self {
+        cursor.to_string()
+    }
+}
+
+impl TryFrom<String> for EventCursor {
+    type Error = Error;
+
+    fn try_from(token: String) -> Result<Self> {
+        token.parse()
+    }
+}
+
+/// One page of an entity's events
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventPage {
+    /// Events, in cursor order
+    pub events: Vec<Event>,
+    /// Cursor of the next page; `None` on the last page
+    pub next_cursor: Option<EventCursor>,
+}
+
+impl EventPage {
+    /// Build a page of at most `limit` of `located` events, which must be in
+    /// cursor order. Pass one event more than `limit` to tell whether
+    /// another page follows.
+    pub fn from_located(mut located: Vec<(EventCursor, Event)>, limit: usize) -> Self {
+        let next_cursor = if located.len() > limit {
+            located.truncate(limit);
+            located.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+        Self {
+            events: located.into_iter().map(|(_, event)| event).collect(),
+            next_cursor,
+        }
+    }
+}
+
+/// Locate `events`, in timestamp order, by their position among the events
+/// sharing their timestamp, for journals without segments
+pub fn locate_by_timestamp(events: Vec<Event>) -> Vec<(EventCursor, Event)> {
+    let mut located: Vec<(EventCursor, Event)> = Vec::with_capacity(events.len());
+    for event in events {
+        let offset = match located.last() {
+            Some((last, _)) if last.timestamp == event.timestamp() => last.offset + 1,
+            _ => 0,
+        };
+        located.push((EventCursor::new(event.timestamp(), 0, offset), event));
+    }
+    located
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_round_trip_and_order() {
+        let cursor = EventCursor::new(Timestamp::from_nanos(-5), 3, 1_000_000);
+        let token = cursor.to_string();
+        assert_eq!(token.parse::<EventCursor>().unwrap(), cursor);
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(json, format!("\"{}\"", token));
+        assert!("not a cursor".parse::<EventCursor>().is_err());
+        assert!(token[1..].parse::<EventCursor>().is_err());
+
+        let ts = Timestamp::from_secs(10);
+        assert!(EventCursor::before(ts) < EventCursor::new(ts, 0, 0));
+        assert!(EventCursor::new(ts, 0, 9) < EventCursor::new(ts, 1, 0));
+        assert!(EventCursor::new(ts, 9, 9) < EventCursor::new(ts.add_nanos(1), 0, 0));
+    }
+}
@@ -0,0 +1,392 @@
+//! Payload field extraction cache.
+//!
+//! Filtering on a JSON payload field normally means re-parsing every payload
+//! for every query. This module extracts a field from all events of a segment
+//! block once, stores the result as a typed column keyed by
+//! `(block, field path)`, and hands the same column to later queries and
+//! index builds. Finalized segment blocks are immutable, so cached columns
+//! never go stale until their segment is removed.
+
+use crate::core::event::Event;
+use crate::error::{Error, Result};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Default number of columns kept in a [`FieldExtractionCache`]
+pub const DEFAULT_FIELD_CACHE_COLUMNS: usize = 1024;
+
+/// Dotted path to a field inside a JSON payload, e.g. `customer.address.city`.
+///
+/// A leading `$.` is accepted and ignored. Numeric segments index into arrays.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FieldPath {
+    raw: String,
+    segments: Vec<String>,
+}
+
+impl FieldPath {
+    /// Parse a dotted field path
+    pub fn parse(path: &str) -> Result<Self> {
+        let trimmed = path.strip_prefix("$.").unwrap_or(path);
+        if trimmed.is_empty() {
+            return Err(Error::Query("Empty field path".to_string()));
+        }
+        let segments: Vec<String> = trimmed.split('.').map(str::to_string).collect();
+        if segments.iter().any(|s| s.is_empty()) {
+            return Err(Error::Query(format!("Invalid field path: {}", path)));
+        }
+        Ok(Self {
+            raw: trimmed.to_string(),
+            segments,
+        })
+    }
+
+    /// Path as written, without any `$.` prefix
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Look up the field in a JSON document
+    pub fn lookup<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.segments
+            .iter()
+            .try_fold(value, |current, segment| match current {
+                Value::Object(map) => map.get(segment),
+                Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+                _ => None,
+            })
+    }
+
+    /// Extract the field from an event's JSON payload as a scalar value
+    pub fn extract(&self, event: &Event) -> Option<FieldValue> {
+        if event.payload().format != "json" {
+            return None;
+        }
+        let doc: Value = event.payload().to_json().ok()?;
+        self.lookup(&doc).and_then(FieldValue::from_json)
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// Scalar value extracted from a payload field
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    /// JSON null
+    Null,
+    /// Boolean
+    Bool(bool),
+    /// Integer that fits in an i64
+    Int(i64),
+    /// Any other number
+    Float(f64),
+    /// String
+    Str(String),
+}
+
+impl FieldValue {
+    /// Convert a scalar JSON value; objects and arrays yield `None`
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(Self::Null),
+            Value::Bool(b) => Some(Self::Bool(*b)),
+            Value::Number(n) => n
+                .as_i64()
+                .map(Self::Int)
+                .or_else(|| n.as_f64().map(Self::Float)),
+            Value::String(s) => Some(Self::Str(s.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Numeric value, if this is a number
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Int(i) => Some(*i as f64),
+            Self::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    /// String value, if this is a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Column kind of this value
+    pub fn kind(&self) -> ColumnKind {
+        match self {
+            Self::Null => ColumnKind::Null,
+            Self::Bool(_) => ColumnKind::Bool,
+            Self::Int(_) => ColumnKind::Int,
+            Self::Float(_) => ColumnKind::Float,
+            Self::Str(_) => ColumnKind::Str,
+        }
+    }
+}
+
+impl PartialOrd for FieldValue {
+    /// Values of the same kind compare naturally; integers and floats compare
+    /// numerically. Other combinations are unordered.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Null, Self::Null) => Some(Ordering::Equal),
+            (Self::Bool(a), Self::Bool(b)) => a.partial_cmp(b),
+            (Self::Int(a), Self::Int(b)) => a.partial_cmp(b),
+            (Self::Str(a), Self::Str(b)) => a.partial_cmp(b),
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+}
+
+/// Type of an extracted column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnKind {
+    /// No values, or only nulls
+    Null,
+    /// Booleans
+    Bool,
+    /// Integers
+    Int,
+    /// Numbers, some of which are not integers
+    Float,
+    /// Strings
+    Str,
+    /// Values of more than one kind
+    Mixed,
+}
+
+/// A field extracted from every event of a block, in event order
+#[derive(Debug, Clone)]
+pub struct FieldColumn {
+    /// Column type
+    pub kind: ColumnKind,
+    /// One entry per event; `None` where the field is absent or not scalar
+    pub values: Vec<Option<FieldValue>>,
+}
+
+impl FieldColumn {
+    /// Extract `path` from every event
+    pub fn extract(events: &[Event], path: &FieldPath) -> Self {
+        let values: Vec<Option<FieldValue>> = events.iter().map(|e| path.extract(e)).collect();
+        let kind = values
+            .iter()
+            .flatten()
+            .map(FieldValue::kind)
+            .filter(|k| *k != ColumnKind::Null)
+            .fold(ColumnKind::Null, |acc, k| match (acc, k) {
+                (ColumnKind::Null, k) => k,
+                (a, k) if a == k => a,
+                (ColumnKind::Int, ColumnKind::Float) | (ColumnKind::Float, ColumnKind::Int) => {
+                    ColumnKind::Float
+                }
+                _ => ColumnKind::Mixed,
+            });
+        Self { kind, values }
+    }
+
+    /// Number of rows
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Check if the column has no rows
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Identity of a segment block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId {
+    /// Segment the block belongs to
+    pub segment_id: u64,
+    /// Byte offset of the block inside the segment file
+    pub offset: u64,
+}
+
+/// Hit/miss counters for a [`FieldExtractionCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FieldCacheStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups that had to parse payloads
+    pub misses: u64,
+    /// Columns currently cached
+    pub columns: usize,
+}
+
+type ColumnKey = (BlockId, String);
+
+struct CacheInner {
+    columns: HashMap<ColumnKey, Arc<FieldColumn>>,
+    /// Insertion order, used for FIFO eviction
+    order: VecDeque<ColumnKey>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Bounded cache of extracted payload field columns, keyed by block and path
+pub struct FieldExtractionCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl FieldExtractionCache {
+    /// Create a cache holding at most `capacity` columns
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                columns: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        self.inner
+            .lock()
+            .expect("FieldExtractionCache poisoned lock")
+    }
+
+    /// Get the column for `path` in `block`, extracting it from `events` on a miss.
+    ///
+    /// `events` must be the block's events in stored order.
+    pub fn column(&self, block: BlockId, events: &[Event], path: &FieldPath) -> Arc<FieldColumn> {
+        let key = (block, path.as_str().to_string());
+        {
+            let mut inner = self.lock();
+            if let Some(column) = inner.columns.get(&key).cloned() {
+                inner.hits += 1;
+                return column;
+            }
+            inner.misses += 1;
+        }
+
+        // Parse outside the lock so concurrent lookups of other blocks proceed.
+        let column = Arc::new(FieldColumn::extract(events, path));
+        if self.capacity == 0 {
+            return column;
+        }
+
+        let mut inner = self.lock();
+        if inner.columns.insert(key.clone(), column.clone()).is_none() {
+            inner.order.push_back(key);
+        }
+        while inner.columns.len() > self.capacity {
+            match inner.order.pop_front() {
+                Some(old) => {
+                    inner.columns.remove(&old);
+                }
+                None => break,
+            }
+        }
+        column
+    }
+
+    /// Drop all cached columns belonging to a segment
+    pub fn invalidate_segment(&self, segment_id: u64) {
+        let mut inner = self.lock();
+        inner
+            .columns
+            .retain(|(block, _), _| block.segment_id != segment_id);
+        inner
+            .order
+            .retain(|(block, _)| block.segment_id != segment_id);
+    }
+
+    /// Current cache statistics
+    pub fn stats(&self) -> FieldCacheStats {
+        let inner = self.lock();
+        FieldCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            columns: inner.columns.len(),
+        }
+    }
+}
+
+impl Default for FieldExtractionCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_FIELD_CACHE_COLUMNS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+
+    fn event(payload: serde_json::Value) -> Event {
+        Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(1000),
+            "entity:1".to_string(),
+            EventPayload::from_json(&payload).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_field_path_extract() {
+        let e = event(serde_json::json!({"order": {"amount": 42, "items": ["a", "b"]}}));
+        let amount = FieldPath::parse("$.order.amount").unwrap();
+        assert_eq!(amount.extract(&e), Some(FieldValue::Int(42)));
+        let item = FieldPath::parse("order.items.1").unwrap();
+        assert_eq!(item.extract(&e), Some(FieldValue::Str("b".to_string())));
+        assert_eq!(FieldPath::parse("order.missing").unwrap().extract(&e), None);
+        assert!(FieldPath::parse("a..b").is_err());
+
+        assert!(FieldValue::Int(2) < FieldValue::Float(2.5));
+        assert_eq!(
+            FieldValue::Str("a".into()).partial_cmp(&FieldValue::Int(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_column_cache_reuse_and_eviction() {
+        let events = vec![
+            event(serde_json::json!({"amount": 1})),
+            event(serde_json::json!({"amount": 2.5})),
+            event(serde_json::json!({"other": true})),
+        ];
+        let path = FieldPath::parse("amount").unwrap();
+        let cache = FieldExtractionCache::new(1);
+        let block = BlockId {
+            segment_id: 1,
+            offset: 64,
+        };
+
+        let column = cache.column(block, &events, &path);
+        assert_eq!(column.kind, ColumnKind::Float);
+        assert_eq!(column.values[2], None);
+        let again = cache.column(block, &events, &path);
+        assert!(Arc::ptr_eq(&column, &again));
+        assert_eq!(cache.stats().hits, 1);
+
+        // Capacity of one: a second block evicts the first.
+        let other = BlockId {
+            segment_id: 2,
+            offset: 64,
+        };
+        cache.column(other, &events, &path);
+        assert_eq!(cache.stats().columns, 1);
+
+        cache.invalidate_segment(2);
+        assert_eq!(cache.stats().columns, 0);
+    }
+}
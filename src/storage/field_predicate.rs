@@ -0,0 +1,209 @@
+//! Predicates on payload fields.
+//!
+//! A [`FieldPredicate`] compares the payload field at a [`FieldPath`] with a
+//! constant, e.g. `payload->>'status' = 'active'`. Journals evaluate it
+//! while decoding segment blocks, on the columns of the field extraction
+//! cache, and skip blocks whose zone maps rule out a numeric comparison,
+//! so callers never deserialize payloads that cannot match.
+
+use crate::core::event::Event;
+use crate::error::{Error, Result};
+use crate::storage::field_cache::{FieldPath, FieldValue};
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// Comparison of a [`FieldPredicate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    /// `=`
+    Eq,
+    /// `<>` or `!=`
+    Ne,
+    /// `<`
+    Lt,
+    /// `<=`
+    Le,
+    /// `>`
+    Gt,
+    /// `>=`
+    Ge,
+}
+
+impl CompareOp {
+    /// Operators by their SQL spelling, longest first so that `<=` is not
+    /// read as `<`
+    const SYMBOLS: [(&'static str, CompareOp); 7] = [
+        ("<>", CompareOp::Ne),
+        ("!=", CompareOp::Ne),
+        ("<=", CompareOp::Le),
+        (">=", CompareOp::Ge),
+        ("=", CompareOp::Eq),
+        ("<", CompareOp::Lt),
+        (">", CompareOp::Gt),
+    ];
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Ne => ordering.is_ne(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Le => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+/// Comparison of a payload field with a constant
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldPredicate {
+    /// Field compared
+    pub path: FieldPath,
+    /// Comparison
+    pub op: CompareOp,
+    /// Constant the field is compared with
+    pub value: FieldValue,
+}
+
+impl FieldPredicate {
+    /// Predicate `path op value`
+    pub fn new(path: FieldPath, op: CompareOp, value: FieldValue) -> Self {
+        Self { path, op, value }
+    }
+
+    /// Parse a predicate such as `payload->>'status' = 'active'` or
+    /// `payload->'order'->>'total' >= 100`.
+    ///
+    /// The field may also be given as a dotted path, e.g. `order.total`.
+    /// Constants are quoted strings, numbers, `true`, `false` or `null`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || Error::Query(format!("Invalid payload predicate: {}", text));
+        let (at, symbol, op) = find_operator(text).ok_or_else(invalid)?;
+        let (field, constant) = (text[..at].trim(), text[at + symbol.len()..].trim());
+        let path = match field.strip_prefix("payload") {
+            Some(arrows) => parse_arrows(arrows).ok_or_else(invalid)?,
+            None => FieldPath::parse(field)?,
+        };
+        let value = parse_constant(constant).ok_or_else(invalid)?;
+        Ok(Self::new(path, op, value))
+    }
+
+    /// Whether a field holding `value` satisfies the predicate. A missing
+    /// field, or one not comparable with the constant, never does.
+    pub fn matches(&self, value: Option<&FieldValue>) -> bool {
+        value
+            .and_then(|value| value.partial_cmp(&self.value))
+            .is_some_and(|ordering| self.op.holds(ordering))
+    }
+
+    /// Whether `event`'s payload satisfies the predicate
+    pub fn matches_event(&self, event: &Event) -> bool {
+        self.matches(self.path.extract(event).as_ref())
+    }
+
+    /// Numbers a matching field may hold, for comparisons with a number
+    /// that zone maps can rule out
+    pub fn numeric_range(&self) -> Option<(Bound<f64>, Bound<f64>)> {
+        let n = self.value.as_f64()?;
+        match self.op {
+            CompareOp::Eq => Some((Bound::Included(n), Bound::Included(n))),
+            CompareOp::Ne => None,
+            CompareOp::Lt => Some((Bound::Unbounded, Bound::Excluded(n))),
+            CompareOp::Le => Some((Bound::Unbounded, Bound::Included(n))),
+            CompareOp::Gt => Some((Bound::Excluded(n), Bound::Unbounded)),
+            CompareOp::Ge => Some((Bound::Included(n), Bound::Unbounded)),
+        }
+    }
+}
+
+/// First comparison operator outside quotes and `->` steps
+fn find_operator(text: &str) -> Option<(usize, &'static str, CompareOp)> {
+    let bytes = text.as_bytes();
+    let mut quoted = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' => quoted = !quoted,
+            b'-' if !quoted && bytes.get(i + 1) == Some(&b'>') => {
+                // Skip `->`, or `->>`.
+                i += if bytes.get(i + 2) == Some(&b'>') { 3 } else { 2 };
+                continue;
+            }
+            _ if !quoted => {
+                let found = CompareOp::SYMBOLS
+                    .iter()
+                    .find(|(symbol, _)| bytes[i..].starts_with(symbol.as_bytes()));
+                if let Some(&(symbol, op)) = found {
+                    return Some((i, symbol, op));
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Path of `->'key'` and `->>'key'` steps; array indexes may be unquoted
+fn parse_arrows(mut arrows: &str) -> Option<FieldPath> {
+    let mut segments = Vec::new();
+    while !arrows.trim().is_empty() {
+        let rest = arrows.trim_start().strip_prefix("->")?;
+        let rest = rest.strip_prefix('>').unwrap_or(rest).trim_start();
+        let (segment, rest) = match rest.strip_prefix('\'') {
+            Some(quoted) => quoted.split_once('\'')?,
+            None => rest.split_at(rest.find("->").unwrap_or(rest.len())),
+        };
+        segments.push(segment.trim());
+        arrows = rest;
+    }
+    FieldPath::parse(&segments.join(".")).ok()
+}
+
+/// Quoted string, number, boolean or `null`
+fn parse_constant(constant: &str) -> Option<FieldValue> {
+    if let Some(quoted) = constant.strip_prefix('\'') {
+        let text = quoted.strip_suffix('\'')?;
+        return Some(FieldValue::Str(text.replace("''", "'")));
+    }
+    match constant.to_ascii_lowercase().as_str() {
+        "true" => Some(FieldValue::Bool(true)),
+        "false" => Some(FieldValue::Bool(false)),
+        "null" => Some(FieldValue::Null),
+        _ => constant
+            .parse()
+            .map(FieldValue::Int)
+            .or_else(|_| constant.parse().map(FieldValue::Float))
+            .ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match() {
+        let status = FieldPredicate::parse("payload->>'status' = 'active'").unwrap();
+        assert_eq!(status.path.as_str(), "status");
+        assert!(status.matches(Some(&FieldValue::Str("active".to_string()))));
+        assert!(!status.matches(Some(&FieldValue::Int(1))));
+        assert!(!status.matches(None));
+        assert_eq!(status.numeric_range(), None);
+
+        let total = FieldPredicate::parse("payload->'order'->>'total' >= 100").unwrap();
+        assert_eq!(
+            (total.path.as_str(), total.op),
+            ("order.total", CompareOp::Ge)
+        );
+        assert!(total.matches(Some(&FieldValue::Float(100.5))));
+        assert!(!total.matches(Some(&FieldValue::Int(99))));
+        let range = (Bound::Included(100.0), Bound::Unbounded);
+        assert_eq!(total.numeric_range(), Some(range));
+
+        let dotted = FieldPredicate::parse("items.0.sku <> 'a''b'").unwrap();
+        assert_eq!(dotted.value, FieldValue::Str("a'b".to_string()));
+        assert!(FieldPredicate::parse("payload->>'status' ~ 'x'").is_err());
+        assert!(FieldPredicate::parse("payload->>'status' = active").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Gorilla-style compression of time series.
+//!
+//! Regularly spaced timestamps are stored as delta-of-deltas, which are
+//! usually zero and cost a single bit. Float values are XORed with their
+//! predecessor, and only the meaningful bits of the XOR are stored, so
+//! slowly changing gauges take a few bits per sample. Integer values use
+//! the same delta-of-delta encoding as timestamps.
+//!
+//! See "Gorilla: A Fast, Scalable, In-Memory Time Series Database"
+//! (Pelkonen et al., VLDB 2015).
+
+use crate::error::{Error, Result};
+
+/// Appends bits to a byte buffer, most significant bit first
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte (0 means it is full or absent)
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().expect("byte pushed above") |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Write the low `count` bits of `value`
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for shift in (0..count).rev() {
+            self.write_bit((value >> shift) & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Reads bits written by [`BitWriter`]
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or_else(|| Error::Storage("Truncated Gorilla-compressed series".to_string()))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+/// Delta-of-delta buckets: control prefix length and payload width
+const DOD_BUCKETS: [(u32, u32); 3] = [(2, 7), (3, 9), (4, 12)];
+
+/// Encode a sequence of integers (e.g. timestamps in nanoseconds) as
+/// delta-of-deltas
+pub fn encode_deltas(values: &[i64]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    let mut previous = 0i64;
+    let mut previous_delta = 0i64;
+    for (i, value) in values.iter().enumerate() {
+        match i {
+            0 => out.write_bits(*value as u64, 64),
+            1 => {
+                previous_delta = value.wrapping_sub(previous);
+                out.write_bits(previous_delta as u64, 64);
+            }
+            _ => {
+                let delta = value.wrapping_sub(previous);
+                let dod = zigzag(delta.wrapping_sub(previous_delta));
+                previous_delta = delta;
+                if dod == 0 {
+                    out.write_bit(false);
+                } else if let Some((prefix, width)) =
+                    DOD_BUCKETS.iter().find(|(_, width)| dod < 1 << width)
+                {
+                    // Prefix of `prefix - 1` ones and a terminating zero
+                    out.write_bits((1 << prefix) - 2, *prefix);
+                    out.write_bits(dod, *width);
+                } else {
+                    out.write_bits(0b1111, 4);
+                    out.write_bits(dod, 64);
+                }
+            }
+        }
+        previous = *value;
+    }
+    out.finish()
+}
+
+/// Decode `count` integers written by [`encode_deltas`]
+pub fn decode_deltas(data: &[u8], count: usize) -> Result<Vec<i64>> {
+    let mut input = BitReader::new(data);
+    let mut values = Vec::with_capacity(count);
+    let mut previous = 0i64;
+    let mut previous_delta = 0i64;
+    for i in 0..count {
+        let value = match i {
+            0 => input.read_bits(64)? as i64,
+            1 => {
+                previous_delta = input.read_bits(64)? as i64;
+                previous.wrapping_add(previous_delta)
+            }
+            _ => {
+                let mut ones = 0;
+                while ones < 4 && input.read_bit()? {
+                    ones += 1;
+                }
+                let dod = match ones {
+                    0 => 0,
+                    4 => input.read_bits(64)?,
+                    n => input.read_bits(DOD_BUCKETS[n - 1].1)?,
+                };
+                previous_delta = previous_delta.wrapping_add(unzigzag(dod));
+                previous.wrapping_add(previous_delta)
+            }
+        };
+        values.push(value);
+        previous = value;
+    }
+    Ok(values)
+}
+
+/// Encode a sequence of floats by XOR with their predecessor
+pub fn encode_floats(values: &[f64]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    let mut previous = 0u64;
+    // Window of meaningful bits of the previous XOR: (leading, trailing)
+    let mut window: Option<(u32, u32)> = None;
+    for (i, value) in values.iter().enumerate() {
+        let bits = value.to_bits();
+        if i == 0 {
+            out.write_bits(bits, 64);
+            previous = bits;
+            continue;
+        }
+        let xor = bits ^ previous;
+        previous = bits;
+        if xor == 0 {
+            out.write_bit(false);
+            continue;
+        }
+        out.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match window {
+            Some((lead, trail)) if leading >= lead && trailing >= trail => {
+                out.write_bit(false);
+                out.write_bits(xor >> trail, 64 - lead - trail);
+            }
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                out.write_bit(true);
+                out.write_bits(leading as u64, 5);
+                // 64 meaningful bits are stored as 0.
+                out.write_bits(meaningful as u64 & 0x3f, 6);
+                out.write_bits(xor >> trailing, meaningful);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+    out.finish()
+}
+
+/// Decode `count` floats written by [`encode_floats`]
+pub fn decode_floats(data: &[u8], count: usize) -> Result<Vec<f64>> {
+    let mut input = BitReader::new(data);
+    let mut values = Vec::with_capacity(count);
+    let mut previous = 0u64;
+    let mut window = (0u32, 0u32);
+    for i in 0..count {
+        if i == 0 {
+            previous = input.read_bits(64)?;
+        } else if input.read_bit()? {
+            if input.read_bit()? {
+                let leading = input.read_bits(5)? as u32;
+                let meaningful = match input.read_bits(6)? as u32 {
+                    0 => 64,
+                    n => n,
+                };
+                if leading + meaningful > 64 {
+                    return Err(Error::Storage(
+                        "Corrupt Gorilla-compressed float series".to_string(),
+                    ));
+                }
+                window = (leading, 64 - leading - meaningful);
+            }
+            let (leading, trailing) = window;
+            previous ^= input.read_bits(64 - leading - trailing)? << trailing;
+        }
+        values.push(f64::from_bits(previous));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_compactness() {
+        // Samples every 10s with a little jitter.
+        let timestamps: Vec<i64> = (0..1000i64)
+            .map(|i| 1_700_000_000_000_000_000 + i * 10_000_000_000 + (i % 7) * 1_000)
+            .collect();
+        let encoded = encode_deltas(&timestamps);
+        assert_eq!(
+            decode_deltas(&encoded, timestamps.len()).unwrap(),
+            timestamps
+        );
+        assert!(encoded.len() < timestamps.len() * 3);
+
+        let extremes = vec![i64::MIN, i64::MAX, 0, -1, i64::MAX, 5];
+        let encoded = encode_deltas(&extremes);
+        assert_eq!(decode_deltas(&encoded, extremes.len()).unwrap(), extremes);
+
+        let gauge: Vec<f64> = (0..1000).map(|i| 20.0 + ((i / 50) as f64) * 0.5).collect();
+        let encoded = encode_floats(&gauge);
+        assert_eq!(decode_floats(&encoded, gauge.len()).unwrap(), gauge);
+        assert!(encoded.len() < gauge.len());
+
+        let odd = vec![1.5, -0.0, f64::NAN, f64::INFINITY, f64::MIN_POSITIVE, 1e300];
+        let decoded = decode_floats(&encode_floats(&odd), odd.len()).unwrap();
+        let bits = |v: &[f64]| v.iter().map(|f| f.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&decoded), bits(&odd));
+
+        assert!(decode_floats(&[0x40], 2).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Hot-entity detection and adaptive timeline caching.
+//!
+//! Access counts are tracked per entity and periodically decayed so that the
+//! "hot set" follows the current workload. Entities in the hot set keep their
+//! most recent events pinned in memory, which lets AS OF lookups near the
+//! present be answered without touching the journal.
+
+use crate::core::event::{Event, EventOrdering};
+use crate::core::temporal::Timestamp;
+use crate::core::timeline::Timeline;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Configuration for hot-entity tracking
+#[derive(Debug, Clone)]
+pub struct HotEntityConfig {
+    /// Maximum number of entities pinned in memory
+    pub capacity: usize,
+    /// Minimum access score before an entity is considered hot
+    pub promote_threshold: u64,
+    /// Number of most recent events kept per pinned entity
+    pub recent_events: usize,
+    /// Halve all access counters after this many recorded accesses
+    pub decay_every: u64,
+    /// Ordering of same-timestamp events; must match the journal's
+    pub ordering: EventOrdering,
+}
+
+impl Default for HotEntityConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            promote_threshold: 8,
+            recent_events: 256,
+            decay_every: 10_000,
+            ordering: EventOrdering::default(),
+        }
+    }
+}
+
+/// Access statistics for a single entity
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntityAccessStats {
+    /// Decayed read count
+    pub reads: u64,
+    /// Decayed write count
+    pub writes: u64,
+}
+
+impl EntityAccessStats {
+    /// Combined access score used for ranking
+    pub fn score(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// Snapshot of a hot entity for inspection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotEntity {
+    /// Entity ID
+    pub entity_id: String,
+    /// Current access statistics
+    pub stats: EntityAccessStats,
+    /// Number of events pinned in memory
+    pub pinned_events: usize,
+}
+
+struct HotState {
+    stats: HashMap<String, EntityAccessStats>,
+    pinned: HashMap<String, Timeline>,
+    accesses_since_decay: u64,
+}
+
+/// Tracks per-entity access frequency and pins recent timelines of hot entities.
+pub struct HotEntityCache {
+    config: HotEntityConfig,
+    state: Mutex<HotState>,
+}
+
+impl HotEntityCache {
+    /// Create a new cache with the given configuration
+    pub fn new(config: HotEntityConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(HotState {
+                stats: HashMap::new(),
+                pinned: HashMap::new(),
+                accesses_since_decay: 0,
+            }),
+        }
+    }
+
+    /// Get the cache configuration
+    pub fn config(&self) -> &HotEntityConfig {
+        &self.config
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HotState> {
+        self.state.lock().expect("HotEntityCache poisoned lock")
+    }
+
+    /// Record a read of `entity_id`.
+    ///
+    /// Returns `true` when the entity just became hot and should be loaded
+    /// with [`HotEntityCache::pin`].
+    pub fn record_read(&self, entity_id: &str) -> bool {
+        self.record(entity_id, |s| s.reads += 1)
+    }
+
+    /// Record a write to `entity_id`.
+    ///
+    /// Returns `true` when the entity just became hot and should be loaded
+    /// with [`HotEntityCache::pin`].
+    pub fn record_write(&self, entity_id: &str) -> bool {
+        self.record(entity_id, |s| s.writes += 1)
+    }
+
+    fn record(&self, entity_id: &str, bump: impl FnOnce(&mut EntityAccessStats)) -> bool {
+        if self.config.capacity == 0 {
+            return false;
+        }
+
+        let mut state = self.lock();
+        let stats = state.stats.entry(entity_id.to_string()).or_default();
+        bump(stats);
+        let score = stats.score();
+
+        state.accesses_since_decay += 1;
+        if state.accesses_since_decay >= self.config.decay_every {
+            state.accesses_since_decay = 0;
+            state.stats.retain(|_, s| {
+                s.reads /= 2;
+                s.writes /= 2;
+                s.score() > 0
+            });
+        }
+
+        if score < self.config.promote_threshold || state.pinned.contains_key(entity_id) {
+            return false;
+        }
+        if state.pinned.len() < self.config.capacity {
+            return true;
+        }
+
+        // Cache is full: only promote if this entity is hotter than the coldest pinned one.
+        let coldest = state
+            .pinned
+            .keys()
+            .map(|id| (state.stats.get(id).map(|s| s.score()).unwrap_or(0), id))
+            .min();
+        matches!(coldest, Some((cold_score, _)) if cold_score < score)
+    }
+
+    /// Pin the timeline of `entity_id`, keeping only its most recent events.
+    ///
+    /// `events` should be the entity's full history as stored in the journal.
+    /// If the cache is full, the coldest pinned entity is evicted.
+    pub fn pin(&self, entity_id: &str, events: Vec<Event>) {
+        let mut state = self.lock();
+        if !state.pinned.contains_key(entity_id) && state.pinned.len() >= self.config.capacity {
+            let coldest = state
+                .pinned
+                .keys()
+                .min_by_key(|id| state.stats.get(*id).map(|s| s.score()).unwrap_or(0))
+                .cloned();
+            if let Some(id) = coldest {
+                state.pinned.remove(&id);
+            }
+        }
+
+        let mut timeline = Timeline::with_ordering(entity_id.to_string(), self.config.ordering);
+        timeline.append_many(events);
+        Self::trim(&mut timeline, self.config.recent_events);
+        state.pinned.insert(entity_id.to_string(), timeline);
+    }
+
+    /// Remove an entity from the pinned set
+    pub fn unpin(&self, entity_id: &str) {
+        self.lock().pinned.remove(entity_id);
+    }
+
+    /// Apply a newly committed event to the pinned timeline, if any
+    pub fn apply_event(&self, event: &Event) {
+        let mut state = self.lock();
+        if let Some(timeline) = state.pinned.get_mut(event.entity_id()) {
+            // Events older than the pinned window cannot change answers the
+            // cache is allowed to give, so they are not kept.
+            let in_window = timeline
+                .first_timestamp()
+                .map(|first| event.timestamp() >= first)
+                .unwrap_or(true);
+            if in_window {
+                timeline.append(event.clone());
+                Self::trim(timeline, self.config.recent_events);
+            }
+        }
+    }
+
+    /// Answer an AS OF lookup from the pinned window.
+    ///
+    /// Returns `None` if the entity is not pinned or `timestamp` is older than
+    /// the pinned window; otherwise returns the journal-equivalent answer.
+    pub fn latest_before(&self, entity_id: &str, timestamp: Timestamp) -> Option<Option<Event>> {
+        let state = self.lock();
+        let timeline = state.pinned.get(entity_id)?;
+        match timeline.first_timestamp() {
+            Some(first) if timestamp >= first => Some(timeline.latest_before(timestamp).cloned()),
+            _ => None,
+        }
+    }
+
+    /// Check whether an entity is currently pinned
+    pub fn is_pinned(&self, entity_id: &str) -> bool {
+        self.lock().pinned.contains_key(entity_id)
+    }
+
+    /// Access statistics for a single entity
+    pub fn stats(&self, entity_id: &str) -> Option<EntityAccessStats> {
+        self.lock().stats.get(entity_id).cloned()
+    }
+
+    /// Inspect the current hot set, hottest first
+    pub fn hot_set(&self) -> Vec<HotEntity> {
+        let state = self.lock();
+        let mut hot: Vec<HotEntity> = state
+            .pinned
+            .iter()
+            .map(|(id, timeline)| HotEntity {
+                entity_id: id.clone(),
+                stats: state.stats.get(id).cloned().unwrap_or_default(),
+                pinned_events: timeline.len(),
+            })
+            .collect();
+        hot.sort_by(|a, b| {
+            b.stats
+                .score()
+                .cmp(&a.stats.score())
+                .then_with(|| a.entity_id.cmp(&b.entity_id))
+        });
+        hot
+    }
+
+    /// Drop the oldest events until at most `keep` remain, always removing
+    /// whole timestamps so the window stays "every event since its start".
+    fn trim(timeline: &mut Timeline, keep: usize) {
+        while timeline.len() > keep {
+            let next = timeline
+                .first_timestamp()
+                .and_then(|first| timeline.earliest_after(first.add_nanos(1)))
+                .map(|e| e.timestamp());
+            match next {
+                Some(next) => {
+                    timeline.remove_before(next);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Default for HotEntityCache {
+    fn default() -> Self {
+        Self::new(HotEntityConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, secs: i64) -> Event {
+        let payload = EventPayload::from_json(&serde_json::json!(secs)).unwrap();
+        Event::new(
+            "value.changed".to_string(),
+            Timestamp::from_secs(secs),
+            entity_id.to_string(),
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_promotion_and_hot_set() {
+        let cache = HotEntityCache::new(HotEntityConfig {
+            capacity: 1,
+            promote_threshold: 3,
+            ..HotEntityConfig::default()
+        });
+
+        assert!(!cache.record_read("user:1"));
+        assert!(!cache.record_read("user:1"));
+        assert!(cache.record_read("user:1"));
+        cache.pin("user:1", vec![event("user:1", 1)]);
+        assert!(cache.is_pinned("user:1"));
+
+        // A colder entity cannot displace the pinned one.
+        for _ in 0..3 {
+            cache.record_read("user:2");
+        }
+        cache.record_read("user:1");
+        assert!(!cache.record_read("user:2"));
+
+        // Once it is hotter, it takes over the single slot.
+        for _ in 0..3 {
+            cache.record_read("user:2");
+        }
+        assert!(cache.record_read("user:2"));
+        cache.pin("user:2", vec![]);
+        let hot = cache.hot_set();
+        assert_eq!(hot.len(), 1);
+        assert_eq!(hot[0].entity_id, "user:2");
+    }
+
+    #[test]
+    fn test_pinned_window_answers() {
+        let cache = HotEntityCache::new(HotEntityConfig {
+            recent_events: 2,
+            ..HotEntityConfig::default()
+        });
+        cache.pin("e", vec![event("e", 10), event("e", 20), event("e", 30)]);
+
+        // Only the two most recent events are kept.
+        assert_eq!(cache.hot_set()[0].pinned_events, 2);
+        assert!(cache.latest_before("e", Timestamp::from_secs(15)).is_none());
+        let hit = cache.latest_before("e", Timestamp::from_secs(25)).unwrap();
+        assert_eq!(hit.unwrap().timestamp(), Timestamp::from_secs(20));
+
+        cache.apply_event(&event("e", 40));
+        let hit = cache.latest_before("e", Timestamp::from_secs(45)).unwrap();
+        assert_eq!(hit.unwrap().timestamp(), Timestamp::from_secs(40));
+    }
+}
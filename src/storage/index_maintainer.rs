@@ -0,0 +1,136 @@
+//! Index maintenance on the append path.
+//!
+//! An [`IndexMaintainer`] is told about every event a journal appends, so
+//! an index registered with the journal (see
+//! [`EventJournal::register_index`](crate::storage::EventJournal::register_index))
+//! stays consistent with it without the journal knowing the index. Indexes
+//! are shared with their readers behind a mutex, which the journal locks
+//! once per append or batch.
+
+use crate::core::event::Event;
+use std::sync::{Arc, Mutex};
+
+/// Index kept up to date with a journal's appends
+pub trait IndexMaintainer: Send {
+    /// Index an event appended to the journal
+    fn on_append(&mut self, event: &Event);
+
+    /// Index events appended together, in append order
+    fn on_append_batch(&mut self, events: &[Event]) {
+        for event in events {
+            self.on_append(event);
+        }
+    }
+}
+
+/// Index registered with a journal and shared with its readers
+pub type SharedIndex = Arc<Mutex<dyn IndexMaintainer>>;
+
+/// Indexes registered with a journal
+#[derive(Clone, Default)]
+pub struct IndexRegistry {
+    indexes: Vec<SharedIndex>,
+}
+
+impl IndexRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maintain `index` on every later append
+    pub fn register(&mut self, index: SharedIndex) {
+        self.indexes.push(index);
+    }
+
+    /// Number of indexes registered
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    /// Whether no index is registered
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Pass an appended event to every index
+    pub fn on_append(&self, event: &Event) {
+        for index in &self.indexes {
+            index
+                .lock()
+                .expect("IndexRegistry poisoned index lock")
+                .on_append(event);
+        }
+    }
+
+    /// Pass events appended together to every index
+    pub fn on_append_batch(&self, events: &[Event]) {
+        for index in &self.indexes {
+            index
+                .lock()
+                .expect("IndexRegistry poisoned index lock")
+                .on_append_batch(events);
+        }
+    }
+}
+
+impl std::fmt::Debug for IndexRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexRegistry")
+            .field("indexes", &self.indexes.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use crate::index::TemporalIndex;
+    use crate::storage::{EventJournal, InMemoryJournal, InMemoryWAL, SegmentedJournal};
+
+    /// Records how events were passed
+    #[derive(Default)]
+    struct Calls(Vec<usize>);
+
+    impl IndexMaintainer for Calls {
+        fn on_append(&mut self, _event: &Event) {
+            self.0.push(1);
+        }
+
+        fn on_append_batch(&mut self, events: &[Event]) {
+            self.0.push(events.len());
+        }
+    }
+
+    fn event(secs: i64) -> Event {
+        let payload = EventPayload::from_json(&secs).unwrap();
+        let ts = Timestamp::from_secs(secs);
+        Event::new("tick".to_string(), ts, "sensor:1".to_string(), payload)
+    }
+
+    #[tokio::test]
+    async fn test_journals_maintain_registered_indexes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let segmented = SegmentedJournal::new(dir.path(), InMemoryWAL::new()).unwrap();
+        let journals: Vec<Box<dyn EventJournal>> =
+            vec![Box::new(InMemoryJournal::new()), Box::new(segmented)];
+        for mut journal in journals {
+            journal.append(event(1)).await.unwrap();
+            let calls = Arc::new(Mutex::new(Calls::default()));
+            let temporal = Arc::new(Mutex::new(TemporalIndex::new()));
+            journal.register_index(calls.clone());
+            journal.register_index(temporal.clone());
+
+            journal.append(event(5)).await.unwrap();
+            let batch = vec![event(3), event(9)];
+            journal.append_batch(batch).await.unwrap();
+
+            assert_eq!(calls.lock().unwrap().0, [1, 2]);
+            let temporal = temporal.lock().unwrap();
+            assert_eq!(temporal.len(), 3);
+            let range = temporal.find_in_range(Timestamp::from_secs(0), Timestamp::from_secs(6));
+            assert_eq!(range, [1, 0]);
+        }
+    }
+}
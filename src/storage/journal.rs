@@ -1,11 +1,36 @@
 //! Event journal: append-only storage for events
 
-use crate::core::event::Event;
+use crate::core::event::{Event, EventId, EventOrdering};
+use crate::core::filter::EventFilter;
 use crate::core::temporal::Timestamp;
 use crate::core::timeline::Timeline;
 use crate::error::Result;
+use crate::storage::entity_catalog::{page_start, EntityPage};
+use crate::storage::event_cursor::{locate_by_timestamp, EventCursor};
+use crate::storage::field_cache::{FieldPath, FieldValue};
+use crate::storage::field_predicate::FieldPredicate;
+use crate::storage::index_maintainer::{IndexRegistry, SharedIndex};
+use crate::storage::retention::{RetentionPolicy, RetentionReport};
+use crate::storage::scrub::{ScrubPolicy, ScrubReport};
+use crate::storage::type_index::TypeTimeIndex;
+use crate::storage::window::WindowAggregator;
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::ops::Bound;
+
+/// Log sequence number: the 1-based position of an event in a journal's
+/// append order. `0` denotes the empty prefix, i.e. "before any event".
+pub type Lsn = u64;
+
+/// Events found by scanning for a payload field value
+#[derive(Debug, Clone, Default)]
+pub struct FieldScan {
+    /// Matching events
+    pub events: Vec<Event>,
+    /// Events examined to find them
+    pub scanned: u64,
+}
 
 /// Trait for event journal implementations
 #[async_trait]
@@ -27,7 +52,107 @@ pub trait EventJournal: Send + Sync {
     /// Get all events for an entity
     async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>>;
 
-    /// Get events by type in a time range
+    /// Get the events of an entity recorded at or after `since`, by
+    /// transaction time, in the order of [`EventJournal::get_entity_events`]
+    async fn get_entity_events_recorded_since(
+        &self,
+        entity_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let mut events = self.get_entity_events(entity_id).await?;
+        events.retain(|e| e.metadata.transaction_time >= since);
+        Ok(events)
+    }
+
+    /// Up to `limit` events positioned after `lsn`, each with its
+    /// position, in append order
+    async fn events_after(&self, lsn: Lsn, limit: usize) -> Result<Vec<(Lsn, Event)>>;
+
+    /// Get the first `limit` events, in the order of
+    /// [`EventJournal::get_events`], of an entity in a time range.
+    ///
+    /// The default truncates [`EventJournal::get_events`]; journals on disk
+    /// override it to stop reading once the page is known.
+    async fn get_events_page(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        let mut events = self.get_events(entity_id, start, end).await?;
+        events.truncate(limit);
+        Ok(events)
+    }
+
+    /// Up to `limit` events of an entity after `after`, each with its
+    /// cursor, in cursor order; from the first event when `after` is `None`.
+    ///
+    /// The default reads pages of [`EventJournal::get_events_page`] from the
+    /// cursor's timestamp and locates events by their position among the
+    /// events sharing a timestamp.
+    async fn get_events_after(
+        &self,
+        entity_id: &str,
+        after: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<(EventCursor, Event)>> {
+        let start = after.map_or(Timestamp::from_nanos(i64::MIN), EventCursor::timestamp);
+        let end = Timestamp::from_nanos(i64::MAX);
+        let mut want = limit.max(1);
+        loop {
+            let page = self.get_events_page(entity_id, start, end, want).await?;
+            let complete = page.len() < want;
+            let mut located = locate_by_timestamp(page);
+            located.retain(|(cursor, _)| after.is_none_or(|after| cursor > after));
+            if complete || located.len() >= limit {
+                located.truncate(limit);
+                return Ok(located);
+            }
+            want = want.saturating_mul(2);
+        }
+    }
+
+    /// Get the events of an entity in a time range that match `filter`
+    async fn get_events_filtered(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Event>> {
+        let mut events = self.get_events(entity_id, start, end).await?;
+        events.retain(|e| filter.matches(e));
+        Ok(events)
+    }
+
+    /// Get the events of every entity with a timestamp in `[start, end)`
+    /// that match `filter`, in no particular order.
+    ///
+    /// Filters with a correlation ID are served from the correlation index;
+    /// others scan the range.
+    async fn find_events(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        filter: &EventFilter,
+    ) -> Result<Vec<Event>> {
+        if let Some(correlation_id) = &filter.correlation_id {
+            let mut events = self.get_events_by_correlation(correlation_id).await?;
+            events.retain(|e| e.timestamp() >= start && e.timestamp() < end && filter.matches(e));
+            return Ok(events);
+        }
+        let mut events = Vec::new();
+        self.scan_range(start, end, &mut |event| {
+            if filter.matches(event) {
+                events.push(event.clone());
+            }
+        })
+        .await?;
+        Ok(events)
+    }
+
+    /// Get events by type in a time range, in timestamp order
     async fn get_events_by_type(
         &self,
         event_type: &str,
@@ -42,28 +167,345 @@ pub trait EventJournal: Send + Sync {
         timestamp: Timestamp,
     ) -> Result<Option<Event>>;
 
+    /// Latest event at or before `timestamp` of each of `entity_ids` that
+    /// has one, resolved in a single pass
+    async fn get_latest_events(
+        &self,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>>;
+
+    /// Latest event at or before `timestamp` of every entity whose ID
+    /// starts with `prefix`, in entity order
+    async fn get_latest_events_with_prefix(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>>;
+
+    /// Get all events stamped with a correlation ID, in append order
+    async fn get_events_by_correlation(&self, correlation_id: &str) -> Result<Vec<Event>>;
+
+    /// Get events whose payload field at `path` equals `value`.
+    ///
+    /// Payload fields are not indexed, so this examines every event, though
+    /// journals may serve extracted values from a cache; the scan reports
+    /// how many.
+    async fn scan_field(&self, path: &FieldPath, value: &FieldValue) -> Result<FieldScan>;
+
+    /// Visit every event with a timestamp in `[start, end)` without
+    /// collecting them, in no particular order
+    async fn scan_range(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        visit: &mut (dyn for<'e> FnMut(&'e Event) + Send),
+    ) -> Result<()>;
+
+    /// Visit every event with a timestamp in `[start, end)` whose payload
+    /// satisfies all of `predicates`, in no particular order.
+    ///
+    /// The default tests the predicates on the events of
+    /// [`EventJournal::scan_range`]; journals with a field cache evaluate
+    /// them on cached columns and skip blocks whose zone maps rule them out.
+    async fn scan_range_where(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        predicates: &[FieldPredicate],
+        visit: &mut (dyn for<'e> FnMut(&'e Event) + Send),
+    ) -> Result<()> {
+        self.scan_range(start, end, &mut |event| {
+            if predicates.iter().all(|p| p.matches_event(event)) {
+                visit(event);
+            }
+        })
+        .await
+    }
+
+    /// The first `limit` events with a timestamp in `[start, end)` that
+    /// `keep` accepts, in timestamp order, followed by every other one
+    /// sharing the last one's timestamp, so callers may order ties.
+    ///
+    /// The default sorts the events of [`EventJournal::scan_range`];
+    /// journals override it to stop reading once the page is known.
+    async fn scan_range_page(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        keep: &(dyn for<'e> Fn(&'e Event) -> bool + Sync),
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        self.scan_range(start, end, &mut |event| {
+            if keep(event) {
+                events.push(event.clone());
+            }
+        })
+        .await?;
+        events.sort_by_key(Event::timestamp);
+        truncate_after_ties(&mut events, limit);
+        Ok(events)
+    }
+
+    /// Up to `limit` IDs of entities with events whose ID starts with
+    /// `prefix`, in entity order, after `cursor`, the last ID of the
+    /// previous page
+    async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage>;
+
+    /// Maintain `index` on every later append and batch append. Events
+    /// already in the journal are not passed to it.
+    fn register_index(&mut self, index: SharedIndex);
+
+    /// Whether `entity_id` may have events; `false` means it has none.
+    /// Journals keeping an entity filter answer without reading events.
+    fn may_contain_entity(&self, _entity_id: &str) -> bool {
+        true
+    }
+
     /// Flush pending writes to disk
     async fn flush(&mut self) -> Result<()>;
+
+    /// Remove history expired under `policy` at `now`.
+    ///
+    /// Retention drops whole segments, so journals without segments keep
+    /// everything and report nothing removed.
+    async fn enforce_retention(
+        &mut self,
+        _policy: &RetentionPolicy,
+        _now: Timestamp,
+    ) -> Result<RetentionReport> {
+        Ok(RetentionReport::default())
+    }
+
+    /// Verify the next segments in rotation. Damaged segments are only
+    /// reported; see [`EventJournal::repair`].
+    ///
+    /// Journals without segments have nothing to scrub.
+    async fn scrub(&self, _policy: &ScrubPolicy) -> Result<ScrubReport> {
+        Ok(ScrubReport::default())
+    }
+
+    /// Repair the segments `report` found damaged from the replica in
+    /// `policy` where possible, recording them as repaired or flagged.
+    async fn repair(&mut self, _report: &mut ScrubReport, _policy: &ScrubPolicy) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write new segments and WAL records in the given format versions,
+    /// e.g. the ones negotiated with the cluster during a rolling upgrade.
+    ///
+    /// Journals without on-disk formats have nothing to pin.
+    async fn pin_write_versions(&mut self, _segment: u8, _wal: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Feed `aggregator` the numeric payload field at `path` of the events
+    /// with a timestamp in `[start, end)` on entities whose ID starts with
+    /// `prefix`, skipping events `allows` rejects.
+    ///
+    /// Only window accumulators are kept; journals with a field cache read
+    /// the values from it instead of parsing payloads.
+    async fn aggregate_field(
+        &self,
+        prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        path: &FieldPath,
+        aggregator: &mut WindowAggregator,
+        allows: &(dyn for<'e> Fn(&'e Event) -> bool + Send + Sync),
+    ) -> Result<()> {
+        self.scan_range(start, end, &mut |event| {
+            if !event.entity_id().starts_with(prefix) || !allows(event) {
+                return;
+            }
+            if let Some(value) = path.extract(event).and_then(|v| v.as_f64()) {
+                aggregator.add(event.timestamp(), value);
+            }
+        })
+        .await
+    }
+
+    /// Preload the newest segment blocks into the block cache, e.g. right
+    /// after opening. Returns the number of blocks loaded.
+    ///
+    /// Journals without segments have nothing to preload.
+    async fn warm_up(&self) -> Result<usize> {
+        Ok(0)
+    }
 }
 
 /// In-memory implementation of event journal backed by per-entity timelines.
 ///
 /// This keeps events ordered by timestamp and enables efficient temporal queries.
+/// Timelines are kept in a `BTreeMap` ordered by entity ID so that prefix and
+/// namespace scans (e.g. all `user:*` entities) only visit the matching range.
 pub struct InMemoryJournal {
-    /// Map from entity ID to ordered timeline
-    timelines: HashMap<String, Timeline>,
-    /// Map from event type to events (for simple filtering by type)
-    events_by_type: HashMap<String, Vec<Event>>,
+    /// Map from entity ID to ordered timeline, sorted by entity ID
+    timelines: BTreeMap<String, Timeline>,
+    /// Entity ordinals by (event type, timestamp); the events themselves
+    /// are read from the timelines
+    events_by_type: TypeTimeIndex<u32>,
+    /// Entity IDs by ordinal
+    entity_ids: Vec<String>,
+    /// Ordinal of each entity ID
+    entity_ordinals: HashMap<String, u32>,
+    /// Map from correlation ID to events, in append order
+    events_by_correlation: HashMap<String, Vec<Event>>,
+    /// Entity ordinal, timestamp and ID of every event, in append order;
+    /// the events themselves are read from the timelines
+    appended: Vec<(u32, Timestamp, EventId)>,
+    /// How timelines order same-timestamp events
+    ordering: EventOrdering,
+    /// Indexes maintained on append
+    indexes: IndexRegistry,
 }
 
 impl InMemoryJournal {
     /// Create a new in-memory journal
     pub fn new() -> Self {
+        Self::with_ordering(EventOrdering::default())
+    }
+
+    /// Create a new in-memory journal ordering same-timestamp events by
+    /// `ordering`
+    pub fn with_ordering(ordering: EventOrdering) -> Self {
         Self {
-            timelines: HashMap::new(),
-            events_by_type: HashMap::new(),
+            timelines: BTreeMap::new(),
+            events_by_type: TypeTimeIndex::new(),
+            entity_ids: Vec::new(),
+            entity_ordinals: HashMap::new(),
+            events_by_correlation: HashMap::new(),
+            appended: Vec::new(),
+            ordering,
+            indexes: IndexRegistry::new(),
+        }
+    }
+
+    /// Iterate over timelines whose entity ID starts with `prefix`, in entity order.
+    ///
+    /// Only the matching key range is visited, so the cost is proportional to
+    /// the number of matching entities rather than the size of the journal.
+    pub fn timelines_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = &'a Timeline> + 'a {
+        self.timelines
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(id, _)| id.starts_with(prefix))
+            .map(|(_, timeline)| timeline)
+    }
+
+    /// List entity IDs starting with `prefix`, in entity order.
+    pub fn entities_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.timelines_with_prefix(prefix).map(|t| t.entity_id())
+    }
+
+    /// List entity IDs matching a glob pattern (`*` matches any run of
+    /// characters, `?` matches a single character).
+    ///
+    /// The literal prefix before the first wildcard is used to narrow the scan.
+    pub fn entities_matching<'a>(&'a self, pattern: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let literal_len = pattern.find(['*', '?']).unwrap_or(pattern.len());
+        self.entities_with_prefix(&pattern[..literal_len])
+            .filter(move |id| glob_match(pattern, id))
+    }
+
+    /// Get the timeline for an entity, if any events were recorded for it.
+    pub fn timeline(&self, entity_id: &str) -> Option<&Timeline> {
+        self.timelines.get(entity_id)
+    }
+
+    /// Number of distinct entities in the journal.
+    pub fn entity_count(&self) -> usize {
+        self.timelines.len()
+    }
+
+    /// Add `event` to the timelines and lookup maps
+    fn insert(&mut self, event: Event) {
+        let entity_id = event.entity_id().to_string();
+        let timestamp = event.timestamp();
+
+        // Add to entity timeline (ordered by timestamp)
+        let timeline = self.timelines.entry(entity_id).or_insert_with(|| {
+            let ordinal = self.entity_ids.len() as u32;
+            self.entity_ids.push(event.entity_id().to_string());
+            self.entity_ordinals
+                .insert(event.entity_id().to_string(), ordinal);
+            Timeline::with_ordering(event.entity_id().to_string(), self.ordering)
+        });
+        // The type index holds an entity once per (type, timestamp), since
+        // reads take every event of the type at that timestamp.
+        let indexed = timeline
+            .events_at(timestamp)
+            .iter()
+            .any(|e| e.event_type() == event.event_type());
+        timeline.append(event.clone());
+
+        let ordinal = self.entity_ordinals[event.entity_id()];
+        if !indexed {
+            self.events_by_type
+                .insert(event.event_type(), timestamp, timestamp, ordinal);
+        }
+        self.appended.push((ordinal, timestamp, event.id()));
+
+        if let Some(correlation_id) = &event.metadata.correlation_id {
+            self.events_by_correlation
+                .entry(correlation_id.clone())
+                .or_default()
+                .push(event);
+        }
+    }
+}
+
+/// Keep the first `limit` of `events`, in timestamp order, and the ones
+/// after them sharing the last one's timestamp
+pub(crate) fn truncate_after_ties(events: &mut Vec<Event>, limit: usize) {
+    let Some(last) = limit.checked_sub(1).and_then(|i| events.get(i)) else {
+        events.clear();
+        return;
+    };
+    let last = last.timestamp();
+    let ties = events[limit..]
+        .iter()
+        .take_while(|e| e.timestamp() == last)
+        .count();
+    events.truncate(limit + ties);
+}
+
+/// Match `text` against a glob `pattern` supporting `*` and `?`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text index it was tried at.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
         }
     }
+
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 impl Default for InMemoryJournal {
@@ -75,30 +517,15 @@ impl Default for InMemoryJournal {
 #[async_trait]
 impl EventJournal for InMemoryJournal {
     async fn append(&mut self, event: Event) -> Result<()> {
-        let entity_id = event.entity_id().to_string();
-        let event_type = event.event_type().to_string();
-
-        // Add to entity timeline (ordered by timestamp)
-        let timeline = self
-            .timelines
-            .entry(entity_id)
-            .or_insert_with(|| Timeline::new(event.entity_id().to_string()));
-        timeline.append(event.clone());
-
-        // Add to type index (kept as a flat list for now)
-        self.events_by_type
-            .entry(event_type)
-            .or_insert_with(Vec::new)
-            .push(event);
-
+        self.indexes.on_append(&event);
+        self.insert(event);
         Ok(())
     }
 
     async fn append_batch(&mut self, events: Vec<Event>) -> Result<()> {
-        // For in-memory journal we just reuse append; persistent journals
-        // can override this for true batch semantics.
+        self.indexes.on_append_batch(&events);
         for event in events {
-            self.append(event).await?;
+            self.insert(event);
         }
         Ok(())
     }
@@ -133,27 +560,74 @@ impl EventJournal for InMemoryJournal {
         Ok(all)
     }
 
-    async fn get_events_by_type(
+    async fn get_entity_events_recorded_since(
         &self,
-        event_type: &str,
+        entity_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let recorded = self
+            .timelines
+            .get(entity_id)
+            .map(|timeline| {
+                timeline
+                    .events()
+                    .filter(|e| e.metadata.transaction_time >= since)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(recorded)
+    }
+
+    async fn events_after(&self, lsn: Lsn, limit: usize) -> Result<Vec<(Lsn, Event)>> {
+        let start = (lsn as usize).min(self.appended.len());
+        let appended = self.appended[start..].iter().take(limit);
+        let mut events = Vec::new();
+        for (i, &(ordinal, timestamp, id)) in appended.enumerate() {
+            let timeline = &self.timelines[&self.entity_ids[ordinal as usize]];
+            if let Some(event) = timeline.events_at(timestamp).iter().find(|e| e.id() == id) {
+                events.push((lsn + i as Lsn + 1, event.clone()));
+            }
+        }
+        Ok(events)
+    }
+
+    async fn get_events_page(
+        &self,
+        entity_id: &str,
         start: Timestamp,
         end: Timestamp,
+        limit: usize,
     ) -> Result<Vec<Event>> {
-        let events = self
-            .events_by_type
-            .get(event_type)
-            .map(|evts| {
-                evts
-                    .iter()
-                    .filter(|e| {
-                        let ts = e.timestamp();
-                        ts >= start && ts < end
-                    })
+        let page = self
+            .timelines
+            .get(entity_id)
+            .map(|timeline| {
+                timeline
+                    .events_in_range(start, end)
+                    .into_iter()
+                    .take(limit)
                     .cloned()
                     .collect()
             })
             .unwrap_or_default();
+        Ok(page)
+    }
 
+    async fn get_events_by_type(
+        &self,
+        event_type: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for (timestamp, &ordinal) in self.events_by_type.overlapping(event_type, start, end) {
+            let entity_id = &self.entity_ids[ordinal as usize];
+            if let Some(timeline) = self.timelines.get(entity_id) {
+                let tied = timeline.events_at(timestamp).iter();
+                events.extend(tied.filter(|e| e.event_type() == event_type).cloned());
+            }
+        }
         Ok(events)
     }
 
@@ -170,8 +644,179 @@ impl EventJournal for InMemoryJournal {
         Ok(event)
     }
 
+    async fn get_latest_events(
+        &self,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>> {
+        Ok(entity_ids
+            .iter()
+            .filter_map(|id| self.timelines.get(*id))
+            .filter_map(|timeline| timeline.latest_before(timestamp).cloned())
+            .collect())
+    }
+
+    async fn get_latest_events_with_prefix(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>> {
+        Ok(self
+            .timelines_with_prefix(prefix)
+            .filter_map(|timeline| timeline.latest_before(timestamp).cloned())
+            .collect())
+    }
+
+    async fn get_events_by_correlation(&self, correlation_id: &str) -> Result<Vec<Event>> {
+        Ok(self
+            .events_by_correlation
+            .get(correlation_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn scan_field(&self, path: &FieldPath, value: &FieldValue) -> Result<FieldScan> {
+        let mut scan = FieldScan::default();
+        for event in self.timelines.values().flat_map(Timeline::events) {
+            scan.scanned += 1;
+            if path.extract(event).as_ref() == Some(value) {
+                scan.events.push(event.clone());
+            }
+        }
+        Ok(scan)
+    }
+
+    async fn scan_range(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        visit: &mut (dyn for<'e> FnMut(&'e Event) + Send),
+    ) -> Result<()> {
+        for timeline in self.timelines.values() {
+            timeline
+                .events_in_range(start, end)
+                .into_iter()
+                .for_each(&mut *visit);
+        }
+        Ok(())
+    }
+
+    async fn scan_range_page(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        keep: &(dyn for<'e> Fn(&'e Event) -> bool + Sync),
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        // Merge the timelines lazily, so only the page and its ties are read.
+        let mut runs: Vec<_> = self
+            .timelines
+            .values()
+            .map(|timeline| {
+                timeline
+                    .iter_range(start, end)
+                    .filter(|e| keep(e))
+                    .peekable()
+            })
+            .collect();
+        let mut heads: BinaryHeap<Reverse<(Timestamp, usize)>> = runs
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, run)| run.peek().map(|e| Reverse((e.timestamp(), i))))
+            .collect();
+        let mut page: Vec<Event> = Vec::new();
+        while let Some(Reverse((timestamp, i))) = heads.pop() {
+            let full = page.len() >= limit;
+            if full && page.last().is_none_or(|e| e.timestamp() < timestamp) {
+                break;
+            }
+            page.extend(runs[i].next().cloned());
+            if let Some(next) = runs[i].peek() {
+                heads.push(Reverse((next.timestamp(), i)));
+            }
+        }
+        Ok(page)
+    }
+
+    fn register_index(&mut self, index: SharedIndex) {
+        self.indexes.register(index);
+    }
+
+    fn may_contain_entity(&self, entity_id: &str) -> bool {
+        self.timelines.contains_key(entity_id)
+    }
+
+    async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        let ids = self
+            .timelines
+            .range::<str, _>((page_start(prefix, cursor), Bound::Unbounded))
+            .map(|(id, _)| id.as_str());
+        Ok(EntityPage::from_sorted(ids, prefix, limit))
+    }
+
     async fn flush(&mut self) -> Result<()> {
         // In-memory journal doesn't need flushing
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, secs: i64) -> Event {
+        let payload = EventPayload::from_json(&serde_json::json!({"value": secs})).unwrap();
+        Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(secs),
+            entity_id.to_string(),
+            payload,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_prefix_scan() {
+        let mut journal = InMemoryJournal::new();
+        for id in ["order:1", "user:2", "user:1", "users:9", "account:1"] {
+            journal.append(event(id, 1000)).await.unwrap();
+        }
+
+        let users: Vec<&str> = journal.entities_with_prefix("user:").collect();
+        assert_eq!(users, vec!["user:1", "user:2"]);
+
+        let all_users: Vec<&str> = journal.entities_with_prefix("user").collect();
+        assert_eq!(all_users, vec!["user:1", "user:2", "users:9"]);
+
+        assert_eq!(journal.entities_with_prefix("").count(), 5);
+        assert_eq!(journal.entities_with_prefix("zzz").count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_glob_scan() {
+        let mut journal = InMemoryJournal::new();
+        for id in [
+            "user:1:profile",
+            "user:2:profile",
+            "user:2:settings",
+            "order:1",
+        ] {
+            journal.append(event(id, 1000)).await.unwrap();
+        }
+
+        let profiles: Vec<&str> = journal.entities_matching("user:*:profile").collect();
+        assert_eq!(profiles, vec!["user:1:profile", "user:2:profile"]);
+
+        let single: Vec<&str> = journal.entities_matching("user:?:settings").collect();
+        assert_eq!(single, vec!["user:2:settings"]);
+
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b", "axxc"));
+    }
+}
@@ -4,7 +4,7 @@
 //! be backed by in-memory maps, remote stores, or other implementations.
 
 use crate::core::event::Event;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use async_trait::async_trait;
 use std::collections::HashMap;
 use std::sync::RwLock;
@@ -20,6 +20,23 @@ pub trait MaterializedView: Send + Sync {
 
     /// Get the raw serialized value for an entity, if present.
     async fn get_current_raw(&self, entity_id: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Export the full view state as `(entity_id, raw value)` pairs.
+    ///
+    /// Used to persist view snapshots. Views that cannot be exported return
+    /// an error.
+    async fn export_state(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        Err(Error::Storage(
+            "Materialized view does not support snapshots".to_string(),
+        ))
+    }
+
+    /// Replace the view state with previously exported entries.
+    async fn import_state(&self, _entries: Vec<(String, Vec<u8>)>) -> Result<()> {
+        Err(Error::Storage(
+            "Materialized view does not support snapshots".to_string(),
+        ))
+    }
 }
 
 /// Simple in-memory materialized view storing the latest payload per entity.
@@ -36,6 +53,12 @@ impl InMemoryMaterializedView {
     }
 }
 
+impl Default for InMemoryMaterializedView {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[async_trait]
 impl MaterializedView for InMemoryMaterializedView {
     async fn apply_event(&self, event: &Event) -> Result<()> {
@@ -43,10 +66,13 @@ impl MaterializedView for InMemoryMaterializedView {
             .state
             .write()
             .expect("InMemoryMaterializedView poisoned write lock");
-        guard.insert(
-            event.entity_id().to_string(),
-            event.payload().data.clone(),
-        );
+        if event.is_tombstone() {
+            guard.remove(event.entity_id());
+        } else {
+            // The view holds JSON, so numeric samples are stored as their value.
+            let data = event.payload().json_data()?.into_owned();
+            guard.insert(event.entity_id().to_string(), data);
+        }
         Ok(())
     }
 
@@ -57,5 +83,25 @@ impl MaterializedView for InMemoryMaterializedView {
             .expect("InMemoryMaterializedView poisoned read lock");
         Ok(guard.get(entity_id).cloned())
     }
+
+    async fn export_state(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let guard = self
+            .state
+            .read()
+            .expect("InMemoryMaterializedView poisoned read lock");
+        let mut entries: Vec<(String, Vec<u8>)> =
+            guard.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    async fn import_state(&self, entries: Vec<(String, Vec<u8>)>) -> Result<()> {
+        let mut guard = self
+            .state
+            .write()
+            .expect("InMemoryMaterializedView poisoned write lock");
+        *guard = entries.into_iter().collect();
+        Ok(())
+    }
 }
 
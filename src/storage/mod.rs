@@ -1,17 +1,65 @@
 //! Storage layer for event journal and materialized views
 
+pub mod async_wal;
+pub mod block_cache;
+pub mod bulk;
+pub mod checksum;
+pub mod dir_lock;
+pub mod entity_catalog;
+pub mod entity_filter;
+pub mod event_cursor;
+pub mod field_cache;
+pub mod field_predicate;
+pub mod gorilla;
+pub mod hot_entities;
+pub mod index_maintainer;
 pub mod journal;
+pub mod manifest;
 pub mod segment;
 pub mod segment_file;
+pub mod segment_index;
 pub mod segment_journal;
 pub mod materialized_view;
+pub mod object_store;
+pub mod retention;
+pub mod scrub;
+pub mod type_index;
+pub mod view_snapshot;
 pub mod wal;
+pub mod wal_archive;
+pub mod wal_cipher;
+pub mod warmup;
+pub mod window;
 
+pub use async_wal::*;
+pub use block_cache::*;
+pub use bulk::*;
+pub use checksum::*;
+pub use dir_lock::*;
+pub use entity_catalog::*;
+pub use entity_filter::*;
+pub use event_cursor::*;
+pub use field_cache::*;
+pub use field_predicate::*;
+pub use gorilla::*;
+pub use hot_entities::*;
+pub use index_maintainer::*;
 pub use journal::*;
+pub use manifest::*;
 pub use segment_file::*;
+pub use segment_index::*;
 pub use segment_journal::*;
 pub use materialized_view::*;
+pub use object_store::*;
+pub use retention::*;
+pub use scrub::*;
+pub use type_index::*;
+pub use view_snapshot::*;
 pub use wal::*;
+pub use wal_archive::*;
+pub use wal_cipher::*;
+pub use warmup::*;
+pub use window::*;
 
 // Re-export segment types that don't conflict
 pub use segment::Segment;
@@ -0,0 +1,204 @@
+//! Minimal object storage abstraction.
+//!
+//! Archived WAL files and backups are shipped to an object store. The
+//! [`ObjectStore`] trait models the subset of an S3-compatible API we need
+//! (flat keys with `/` separators, whole-object put/get, prefix listing), so
+//! an S3, GCS or MinIO client can be plugged in by implementing it.
+
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// Whole-object key/value store with prefix listing
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Store `data` under `key`, replacing any existing object
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Fetch the object stored under `key`
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// List keys starting with `prefix`, in lexicographic order
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Remove the object stored under `key`, if any
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// In-memory object store, mainly for tests
+#[derive(Debug, Default)]
+pub struct InMemoryObjectStore {
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl InMemoryObjectStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ObjectStore for InMemoryObjectStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.objects
+            .lock()
+            .expect("InMemoryObjectStore poisoned lock")
+            .insert(key.to_string(), data);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("InMemoryObjectStore poisoned lock")
+            .get(key)
+            .cloned())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(self
+            .objects
+            .lock()
+            .expect("InMemoryObjectStore poisoned lock")
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.objects
+            .lock()
+            .expect("InMemoryObjectStore poisoned lock")
+            .remove(key);
+        Ok(())
+    }
+}
+
+/// Object store backed by a local directory (e.g. a mounted network share).
+///
+/// Each key maps to a file below the root; `/` in keys becomes a
+/// subdirectory.
+#[derive(Debug, Clone)]
+pub struct LocalObjectStore {
+    root: PathBuf,
+}
+
+impl LocalObjectStore {
+    /// Create a store rooted at `root`, creating the directory if needed
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        let valid = !key.is_empty()
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !valid {
+            return Err(Error::Storage(format!("Invalid object key: {:?}", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+
+    fn collect_keys(dir: &Path, key_prefix: &str, keys: &mut Vec<String>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            let key = format!("{}{}", key_prefix, name);
+            if entry.file_type()?.is_dir() {
+                Self::collect_keys(&entry.path(), &format!("{}/", key), keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalObjectStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file first so readers never see a partial object.
+        let mut tmp_name = path.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut tmp = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path_for(key)?;
+        match fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        Self::collect_keys(&self.root, "", &mut keys)?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path_for(key)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_local_object_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = LocalObjectStore::new(temp_dir.path()).unwrap();
+
+        store.put("wal/b", b"two".to_vec()).await.unwrap();
+        store.put("wal/a", b"one".to_vec()).await.unwrap();
+        store.put("other", b"three".to_vec()).await.unwrap();
+
+        assert_eq!(store.list("wal/").await.unwrap(), vec!["wal/a", "wal/b"]);
+        assert_eq!(store.get("wal/a").await.unwrap(), Some(b"one".to_vec()));
+        assert!(store.get("../escape").await.is_err());
+
+        store.delete("wal/a").await.unwrap();
+        assert_eq!(store.get("wal/a").await.unwrap(), None);
+        assert_eq!(store.list("").await.unwrap(), vec!["other", "wal/b"]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Retention policies.
+//!
+//! Retention works on whole finalized segments, oldest first: a segment is
+//! expired once every event type it holds is past its maximum age, or while
+//! the segments together exceed the size limit. Expired segments are
+//! dropped from the manifest and deleted, optionally after being copied to
+//! an [`ObjectStore`]. Segments are only ever removed from the start of the
+//! catalog, so a young segment keeps every newer one alive.
+
+use crate::core::temporal::Timestamp;
+use crate::storage::object_store::ObjectStore;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Key prefix of segments archived by retention
+pub const EXPIRED_SEGMENT_PREFIX: &str = "expired-segments";
+
+/// Limits on how long and how much history is kept
+#[derive(Clone, Default)]
+pub struct RetentionPolicy {
+    /// Maximum age of events, by valid time
+    pub max_age: Option<Duration>,
+    /// Maximum age of events of particular types, overriding `max_age`
+    pub max_age_by_type: HashMap<String, Duration>,
+    /// Maximum total size of finalized segments in bytes
+    pub max_size: Option<u64>,
+    /// Store expired segments are copied to before deletion
+    pub archive: Option<Arc<dyn ObjectStore>>,
+}
+
+impl RetentionPolicy {
+    /// Policy that keeps everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expire events older than `max_age`
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Expire events of `event_type` older than `max_age`
+    pub fn with_event_type_max_age(
+        mut self,
+        event_type: impl Into<String>,
+        max_age: Duration,
+    ) -> Self {
+        self.max_age_by_type.insert(event_type.into(), max_age);
+        self
+    }
+
+    /// Expire the oldest segments while all segments exceed `bytes`
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Copy expired segments to `store` before deleting them
+    pub fn with_archive(mut self, store: Arc<dyn ObjectStore>) -> Self {
+        self.archive = Some(store);
+        self
+    }
+
+    /// Whether the policy never expires anything
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_age_by_type.is_empty() && self.max_size.is_none()
+    }
+
+    /// Events of `event_type` valid before the returned time are expired at
+    /// `now`; `None` if that type is kept forever
+    pub fn cutoff(&self, event_type: &str, now: Timestamp) -> Option<Timestamp> {
+        let max_age = self
+            .max_age_by_type
+            .get(event_type)
+            .or(self.max_age.as_ref())?;
+        let nanos = i64::try_from(max_age.as_nanos()).unwrap_or(i64::MAX);
+        Some(Timestamp::from_nanos(now.as_nanos().saturating_sub(nanos)))
+    }
+}
+
+impl fmt::Debug for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetentionPolicy")
+            .field("max_age", &self.max_age)
+            .field("max_age_by_type", &self.max_age_by_type)
+            .field("max_size", &self.max_size)
+            .field("archive", &self.archive.is_some())
+            .finish()
+    }
+}
+
+/// Outcome of enforcing a retention policy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// IDs of the expired segments
+    pub segments: Vec<u64>,
+    /// Events removed
+    pub events: u64,
+    /// Bytes of segment files removed
+    pub bytes: u64,
+    /// Object store keys of archived segments
+    pub archived: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cutoff_per_event_type() {
+        let policy = RetentionPolicy::new()
+            .with_max_age(Duration::from_secs(100))
+            .with_event_type_max_age("audit", Duration::from_secs(1000));
+        let now = Timestamp::from_secs(5000);
+
+        assert_eq!(policy.cutoff("tick", now), Some(Timestamp::from_secs(4900)));
+        assert_eq!(
+            policy.cutoff("audit", now),
+            Some(Timestamp::from_secs(4000))
+        );
+        assert!(!policy.is_unlimited());
+        assert_eq!(RetentionPolicy::new().cutoff("tick", now), None);
+    }
+}
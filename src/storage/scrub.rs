@@ -0,0 +1,75 @@
+//! Background scrubbing.
+//!
+//! Bit rot in finalized segments goes unnoticed until the damaged block is
+//! read. The scrubber re-verifies a few segments per pass, rotating through
+//! the catalog, so every segment is checked periodically. A damaged
+//! segment is replaced by its copy in the replica store if that copy is
+//! intact; otherwise it is flagged for restore from a backup.
+
+use crate::storage::object_store::ObjectStore;
+use crate::storage::segment_journal::SegmentCheck;
+use std::fmt;
+use std::sync::Arc;
+
+/// Key prefix of segment copies in a replica store
+pub const REPLICA_SEGMENT_PREFIX: &str = "segments";
+
+/// How much to scrub per pass and where to repair from
+#[derive(Clone)]
+pub struct ScrubPolicy {
+    /// Segments verified per pass
+    pub segments_per_pass: usize,
+    /// Store holding intact copies of segments under
+    /// [`REPLICA_SEGMENT_PREFIX`]
+    pub replica: Option<Arc<dyn ObjectStore>>,
+}
+
+impl ScrubPolicy {
+    /// Verify one segment per pass, without a replica
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verify `count` segments per pass
+    pub fn with_segments_per_pass(mut self, count: usize) -> Self {
+        self.segments_per_pass = count;
+        self
+    }
+
+    /// Repair damaged segments from their copies in `store`
+    pub fn with_replica(mut self, store: Arc<dyn ObjectStore>) -> Self {
+        self.replica = Some(store);
+        self
+    }
+}
+
+impl Default for ScrubPolicy {
+    fn default() -> Self {
+        Self {
+            segments_per_pass: 1,
+            replica: None,
+        }
+    }
+}
+
+impl fmt::Debug for ScrubPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScrubPolicy")
+            .field("segments_per_pass", &self.segments_per_pass)
+            .field("replica", &self.replica.is_some())
+            .finish()
+    }
+}
+
+/// Outcome of a scrub pass
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// IDs of the verified segments
+    pub checked: Vec<u64>,
+    /// Checks of the segments found damaged
+    pub damaged: Vec<SegmentCheck>,
+    /// IDs of damaged segments replaced from the replica
+    pub repaired: Vec<u64>,
+    /// IDs of damaged segments that need a restore
+    pub flagged: Vec<u64>,
+}
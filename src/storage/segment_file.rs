@@ -1,16 +1,50 @@
 //! Segment file format: low-level on-disk storage
-
-use crate::core::event::Event;
+//!
+//! The header records the format version a segment was written with, and
+//! readers accept every version from [`MIN_SEGMENT_VERSION`] up to
+//! [`SEGMENT_VERSION`]:
+//!
+//! - version 1 frames each compressed block as `[len u32][data]`;
+//! - version 2 adds a CRC32 of the block, `[len u32][crc32 u32][data]`, so
+//!   a corrupt block can be pinpointed without reading the whole segment;
+//! - version 3 starts each decompressed block with an encoding byte. Blocks
+//!   of only numeric samples are stored column-wise, with timestamps and
+//!   values Gorilla-compressed (see [`crate::storage::gorilla`]).
+//!
+//! Segments written with an older version can be rewritten in the current
+//! one with [`SegmentManager::migrate_segments`](crate::storage::SegmentManager::migrate_segments).
+
+use crate::core::event::{Event, EventPayload};
+use crate::core::numeric::{Number, NumericPayload};
 use crate::core::temporal::Timestamp;
 use crate::error::{Error, Result};
+use crate::storage::field_cache::FieldPath;
+use crate::storage::gorilla;
+use crate::storage::segment_index::SegmentIndex;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crc32fast::Hasher as Crc32Hasher;
+use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-/// Segment file format version
-pub const SEGMENT_VERSION: u8 = 1;
+/// Segment file format version written by this release
+pub const SEGMENT_VERSION: u8 = 3;
+
+/// Oldest segment file format version this release can read
+pub const MIN_SEGMENT_VERSION: u8 = 1;
+
+/// First version whose blocks carry their own CRC32
+const BLOCK_CHECKSUM_VERSION: u8 = 2;
+
+/// First version whose blocks start with a block encoding byte
+const BLOCK_ENCODING_VERSION: u8 = 3;
+
+/// Block encoding: length-prefixed bincode events
+const BLOCK_ROWS: u8 = 0;
+
+/// Block encoding: numeric samples stored column-wise
+const BLOCK_NUMERIC: u8 = 1;
 
 /// Segment header size (64 bytes)
 pub const HEADER_SIZE: usize = 64;
@@ -31,8 +65,11 @@ pub const ZSTD_COMPRESSION_LEVEL: i32 = 3;
 pub const FLAG_COMPRESSED: u8 = 0x01; // Segment data is compressed with ZSTD
 
 /// Segment header structure
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SegmentHeader {
+    /// Format version the segment was written with
+    #[serde(default = "legacy_segment_version")]
+    pub version: u8,
     pub segment_id: u64,
     pub start_time: Timestamp,
     pub end_time: Timestamp,
@@ -42,10 +79,16 @@ pub struct SegmentHeader {
     pub flags: u8,
 }
 
+/// Version of headers cataloged before versions were recorded in manifests
+fn legacy_segment_version() -> u8 {
+    1
+}
+
 impl SegmentHeader {
     /// Create a new segment header
     pub fn new(segment_id: u64, start_time: Timestamp, end_time: Timestamp) -> Self {
         Self {
+            version: SEGMENT_VERSION,
             segment_id,
             start_time,
             end_time,
@@ -64,7 +107,7 @@ impl SegmentHeader {
         buf.put_slice(MAGIC);
         
         // Version (1 byte)
-        buf.put_u8(SEGMENT_VERSION);
+        buf.put_u8(self.version);
         
         // Reserved (2 bytes)
         buf.put_u16(0);
@@ -116,9 +159,15 @@ impl SegmentHeader {
 
         // Version
         let version = buf.get_u8();
-        if version != SEGMENT_VERSION {
+        if version < MIN_SEGMENT_VERSION {
             return Err(Error::Storage(format!("Unsupported version: {}", version)));
         }
+        if version > SEGMENT_VERSION {
+            return Err(Error::Storage(format!(
+                "Segment version {} is newer than supported version {}",
+                version, SEGMENT_VERSION
+            )));
+        }
 
         // Reserved
         buf.advance(2);
@@ -147,6 +196,7 @@ impl SegmentHeader {
         let flags = buf.get_u8();
 
         Ok(Self {
+            version,
             segment_id,
             start_time,
             end_time,
@@ -165,6 +215,7 @@ pub struct SegmentWriter {
     event_buffer: Vec<Event>,
     current_offset: u64,
     checksum_hasher: Crc32Hasher,
+    index: SegmentIndex,
 }
 
 impl SegmentWriter {
@@ -201,9 +252,27 @@ impl SegmentWriter {
             event_buffer: Vec::new(),
             current_offset: HEADER_SIZE as u64,
             checksum_hasher: Crc32Hasher::new(),
+            index: SegmentIndex::new(segment_id),
         })
     }
 
+    /// Write the segment in an older format `version`, e.g. so that older
+    /// releases can still read it during a rolling upgrade.
+    ///
+    /// Must be called before any block has been written.
+    pub fn with_format_version(mut self, version: u8) -> Result<Self> {
+        if !(MIN_SEGMENT_VERSION..=SEGMENT_VERSION).contains(&version) {
+            return Err(Error::Storage(format!("Unsupported version: {}", version)));
+        }
+        if self.current_offset != HEADER_SIZE as u64 {
+            return Err(Error::Storage(
+                "Segment format version must be set before writing".to_string(),
+            ));
+        }
+        self.header.version = version;
+        Ok(self)
+    }
+
     /// Append an event to the segment
     pub fn append(&mut self, event: Event) -> Result<()> {
         // Validate timestamp
@@ -235,13 +304,7 @@ impl SegmentWriter {
         }
 
         // Serialize events
-        let mut serialized = Vec::new();
-        for event in &self.event_buffer {
-            let event_bytes = bincode::serialize(event)
-                .map_err(|e| Error::Serialization(e.to_string()))?;
-            serialized.extend_from_slice(&(event_bytes.len() as u32).to_le_bytes());
-            serialized.extend_from_slice(&event_bytes);
-        }
+        let serialized = encode_block(&self.event_buffer, self.header.version)?;
 
         // Compress with ZSTD
         let compressed = zstd::encode_all(&serialized[..], ZSTD_COMPRESSION_LEVEL)
@@ -251,13 +314,21 @@ impl SegmentWriter {
         self.checksum_hasher.update(&compressed);
 
         // Write compressed data with length prefix
+        let block_offset = self.current_offset;
         let compressed_len = compressed.len() as u32;
         self.file.write_all(&compressed_len.to_le_bytes())
-            .map_err(|e| Error::Io(e))?;
+            .map_err(Error::Io)?;
+        self.current_offset += 4;
+        if self.header.version >= BLOCK_CHECKSUM_VERSION {
+            let crc = crc32fast::hash(&compressed);
+            self.file.write_all(&crc.to_le_bytes())?;
+            self.current_offset += 4;
+        }
         self.file.write_all(&compressed)
-            .map_err(|e| Error::Io(e))?;
+            .map_err(Error::Io)?;
         
-        self.current_offset += 4 + compressed.len() as u64;
+        self.current_offset += compressed.len() as u64;
+        self.index.add_block(block_offset, &self.event_buffer);
 
         // Mark segment as compressed
         self.header.flags |= FLAG_COMPRESSED;
@@ -273,7 +344,12 @@ impl SegmentWriter {
 
     /// Finalize the segment (write header and close)
     /// Returns the finalized header with updated checksum and flags
-    pub fn finalize(mut self) -> Result<SegmentHeader> {
+    pub fn finalize(self) -> Result<SegmentHeader> {
+        Ok(self.finalize_with_index()?.0)
+    }
+
+    /// Finalize the segment, also returning the index of its blocks
+    pub fn finalize_with_index(mut self) -> Result<(SegmentHeader, SegmentIndex)> {
         // Flush remaining events
         self.flush_buffer()?;
 
@@ -286,13 +362,244 @@ impl SegmentWriter {
         self.file.write_all(&header_bytes)?;
         self.file.sync_all()?;
 
-        Ok(self.header)
+        Ok((self.header, self.index))
     }
 
     /// Get current segment info
     pub fn header(&self) -> &SegmentHeader {
         &self.header
     }
+
+    /// Index of the blocks written so far
+    pub fn index(&self) -> &SegmentIndex {
+        &self.index
+    }
+
+    /// Keep zone maps for the payload field at `path` in blocks written from
+    /// now on. See [`SegmentIndex::track_field`].
+    pub fn track_field(&mut self, path: FieldPath) {
+        self.index.track_field(path);
+    }
+
+    /// Events appended but not yet written out as a block
+    pub fn buffered_events(&self) -> &[Event] {
+        &self.event_buffer
+    }
+}
+
+/// A decoded block of events within a segment file
+#[derive(Debug, Clone)]
+pub struct SegmentBlock {
+    /// Byte offset of the block (its length prefix) within the file
+    pub offset: u64,
+    /// Events stored in the block, in write order
+    pub events: Vec<Event>,
+}
+
+/// Block of a segment that could not be read back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFault {
+    /// Byte offset of the block (its length prefix) within the file
+    pub offset: u64,
+    /// What is wrong with the block
+    pub error: String,
+}
+
+/// Result of [`SegmentReader::verify_blocks`]
+#[derive(Debug, Clone)]
+pub struct BlockVerification {
+    /// Blocks that passed their checksum and decoded
+    pub blocks: Vec<SegmentBlock>,
+    /// Blocks that did not
+    pub faults: Vec<BlockFault>,
+    /// Whether the segment checksum in the header matches the block data
+    pub checksum_ok: bool,
+}
+
+impl BlockVerification {
+    /// Whether every block is readable and the segment checksum matches
+    pub fn is_healthy(&self) -> bool {
+        self.faults.is_empty() && self.checksum_ok
+    }
+
+    /// Events in the readable blocks
+    pub fn event_count(&self) -> u64 {
+        self.blocks.iter().map(|b| b.events.len() as u64).sum()
+    }
+}
+
+/// Numeric samples of a block stored column-wise
+#[derive(Serialize, Deserialize)]
+struct NumericColumns {
+    /// Valid times, delta-of-delta encoded
+    timestamps: Vec<u8>,
+    /// Transaction times, delta-of-delta encoded
+    transaction_times: Vec<u8>,
+    /// One bit per sample, set for floats
+    kinds: Vec<u8>,
+    /// Float samples, XOR encoded
+    floats: Vec<u8>,
+    /// Integer samples, delta-of-delta encoded
+    ints: Vec<u8>,
+    /// Units as `(unit, run length)`
+    units: Vec<(Option<String>, u32)>,
+    /// The events without times and payload data
+    rest: Vec<Event>,
+}
+
+impl NumericColumns {
+    /// Columns of `events`, or `None` unless they are all numeric samples
+    fn encode(events: &[Event]) -> Option<Self> {
+        let samples = events
+            .iter()
+            .map(|e| e.payload().to_numeric().ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut kinds = vec![0u8; samples.len().div_ceil(8)];
+        let mut floats = Vec::new();
+        let mut ints = Vec::new();
+        let mut units: Vec<(Option<String>, u32)> = Vec::new();
+        for (i, sample) in samples.into_iter().enumerate() {
+            match sample.value {
+                Number::Float(f) => {
+                    kinds[i / 8] |= 1 << (i % 8);
+                    floats.push(f);
+                }
+                Number::Int(v) => ints.push(v),
+            }
+            match units.last_mut() {
+                Some((unit, run)) if *unit == sample.unit => *run += 1,
+                _ => units.push((sample.unit, 1)),
+            }
+        }
+        let times = |f: fn(&Event) -> Timestamp| {
+            let nanos: Vec<i64> = events.iter().map(|e| f(e).as_nanos()).collect();
+            gorilla::encode_deltas(&nanos)
+        };
+        Some(Self {
+            timestamps: times(|e| e.metadata.timestamp),
+            transaction_times: times(|e| e.metadata.transaction_time),
+            kinds,
+            floats: gorilla::encode_floats(&floats),
+            ints: gorilla::encode_deltas(&ints),
+            units,
+            rest: events
+                .iter()
+                .map(|e| {
+                    let mut rest = e.clone();
+                    rest.metadata.timestamp = Timestamp::from_nanos(0);
+                    rest.metadata.transaction_time = Timestamp::from_nanos(0);
+                    rest.payload.data.clear();
+                    rest
+                })
+                .collect(),
+        })
+    }
+
+    /// Reassemble the events
+    fn decode(self) -> Result<Vec<Event>> {
+        let count = self.rest.len();
+        let is_float = |i: usize| {
+            self.kinds
+                .get(i / 8)
+                .is_some_and(|k| k & (1 << (i % 8)) != 0)
+        };
+        let float_count = (0..count).filter(|i| is_float(*i)).count();
+        let timestamps = gorilla::decode_deltas(&self.timestamps, count)?;
+        let transaction_times = gorilla::decode_deltas(&self.transaction_times, count)?;
+        let mut floats = gorilla::decode_floats(&self.floats, float_count)?.into_iter();
+        let mut ints = gorilla::decode_deltas(&self.ints, count - float_count)?.into_iter();
+        let mut units = self
+            .units
+            .iter()
+            .flat_map(|(unit, run)| std::iter::repeat_n(unit, *run as usize));
+
+        let mut events = Vec::with_capacity(count);
+        for (i, mut event) in self.rest.into_iter().enumerate() {
+            let value = if is_float(i) {
+                floats.next().map(Number::Float)
+            } else {
+                ints.next().map(Number::Int)
+            };
+            let (Some(value), Some(unit)) = (value, units.next()) else {
+                return Err(Error::Storage("Corrupt numeric block".to_string()));
+            };
+            event.metadata.timestamp = Timestamp::from_nanos(timestamps[i]);
+            event.metadata.transaction_time = Timestamp::from_nanos(transaction_times[i]);
+            event.payload = EventPayload::from_numeric(&NumericPayload {
+                value,
+                unit: unit.clone(),
+            });
+            events.push(event);
+        }
+        Ok(events)
+    }
+}
+
+/// Serialize a block of events in format `version`, before compression
+fn encode_block(events: &[Event], version: u8) -> Result<Vec<u8>> {
+    let mut serialized = Vec::new();
+    if version >= BLOCK_ENCODING_VERSION {
+        if let Some(columns) = NumericColumns::encode(events) {
+            serialized.push(BLOCK_NUMERIC);
+            bincode::serialize_into(&mut serialized, &columns)
+                .map_err(|e| Error::Serialization(e.to_string()))?;
+            return Ok(serialized);
+        }
+        serialized.push(BLOCK_ROWS);
+    }
+    for event in events {
+        let event_bytes =
+            bincode::serialize(event).map_err(|e| Error::Serialization(e.to_string()))?;
+        serialized.extend_from_slice(&(event_bytes.len() as u32).to_le_bytes());
+        serialized.extend_from_slice(&event_bytes);
+    }
+    Ok(serialized)
+}
+
+/// Decompress a block written in format `version` and parse its events
+fn decode_block(compressed: &[u8], version: u8) -> Result<Vec<Event>> {
+    let decompressed = zstd::decode_all(compressed)
+        .map_err(|e| Error::Storage(format!("ZSTD decompression failed: {}", e)))?;
+
+    let mut offset = 0;
+    if version >= BLOCK_ENCODING_VERSION {
+        match decompressed.first() {
+            Some(&BLOCK_ROWS) => offset = 1,
+            Some(&BLOCK_NUMERIC) => {
+                let columns: NumericColumns = bincode::deserialize(&decompressed[1..])
+                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                return columns.decode();
+            }
+            Some(other) => return Err(Error::Storage(format!("Unknown block encoding {}", other))),
+            None => return Err(Error::Storage("Empty block".to_string())),
+        }
+    }
+
+    let mut events = Vec::new();
+    while offset < decompressed.len() {
+        if offset + 4 > decompressed.len() {
+            return Err(Error::Storage("Truncated event length".to_string()));
+        }
+
+        let event_len = u32::from_le_bytes([
+            decompressed[offset],
+            decompressed[offset + 1],
+            decompressed[offset + 2],
+            decompressed[offset + 3],
+        ]) as usize;
+        offset += 4;
+
+        if offset + event_len > decompressed.len() {
+            return Err(Error::Storage("Truncated event data".to_string()));
+        }
+
+        let event: Event = bincode::deserialize(&decompressed[offset..offset + event_len])
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        events.push(event);
+        offset += event_len;
+    }
+    Ok(events)
 }
 
 /// Segment file reader
@@ -323,7 +630,16 @@ impl SegmentReader {
 
     /// Read all events from the segment
     pub fn read_events(&mut self) -> Result<Vec<Event>> {
-        let mut events = Vec::new();
+        let blocks = self.read_blocks()?;
+        Ok(blocks.into_iter().flat_map(|b| b.events).collect())
+    }
+
+    /// Read the segment block by block.
+    ///
+    /// Compressed segments yield one [`SegmentBlock`] per compressed block;
+    /// legacy uncompressed segments yield a single block holding every event.
+    pub fn read_blocks(&mut self) -> Result<Vec<SegmentBlock>> {
+        let mut blocks = Vec::new();
         let mut checksum_hasher = Crc32Hasher::new();
 
         // Seek past header
@@ -335,6 +651,8 @@ impl SegmentReader {
         if is_compressed {
             // Read compressed blocks until EOF
             loop {
+                let offset = self.file.stream_position()?;
+
                 // Read compressed block length
                 let mut len_buf = [0u8; 4];
                 match self.file.read_exact(&mut len_buf) {
@@ -343,43 +661,15 @@ impl SegmentReader {
                     Err(e) => return Err(Error::Io(e)),
                 }
 
-                let compressed_len = u32::from_le_bytes(len_buf) as usize;
-
-                // Read compressed data
-                let mut compressed_buf = vec![0u8; compressed_len];
-                self.file.read_exact(&mut compressed_buf)?;
+                let compressed_buf = self.read_block_body(offset, len_buf)?;
 
                 // Update checksum
                 checksum_hasher.update(&compressed_buf);
 
-                // Decompress
-                let decompressed = zstd::decode_all(&compressed_buf[..])
-                    .map_err(|e| Error::Storage(format!("ZSTD decompression failed: {}", e)))?;
-
-                // Parse events from decompressed data
-                let mut offset = 0;
-                while offset < decompressed.len() {
-                    if offset + 4 > decompressed.len() {
-                        return Err(Error::Storage("Truncated event length".to_string()));
-                    }
-
-                    let event_len = u32::from_le_bytes([
-                        decompressed[offset],
-                        decompressed[offset + 1],
-                        decompressed[offset + 2],
-                        decompressed[offset + 3],
-                    ]) as usize;
-                    offset += 4;
-
-                    if offset + event_len > decompressed.len() {
-                        return Err(Error::Storage("Truncated event data".to_string()));
-                    }
-
-                    let event: Event = bincode::deserialize(&decompressed[offset..offset + event_len])
-                        .map_err(|e| Error::Serialization(e.to_string()))?;
-                    events.push(event);
-                    offset += event_len;
-                }
+                blocks.push(SegmentBlock {
+                    offset,
+                    events: decode_block(&compressed_buf, self.header.version)?,
+                });
             }
 
             // Verify checksum
@@ -392,6 +682,7 @@ impl SegmentReader {
             }
         } else {
             // Legacy format: read uncompressed events
+            let mut events = Vec::new();
             loop {
                 // Read event length
                 let mut len_buf = [0u8; 4];
@@ -411,9 +702,128 @@ impl SegmentReader {
                 let event: Event = bincode::deserialize(&event_buf)?;
                 events.push(event);
             }
+            blocks.push(SegmentBlock {
+                offset: HEADER_SIZE as u64,
+                events,
+            });
         }
 
-        Ok(events)
+        Ok(blocks)
+    }
+
+    /// Read every block, collecting the unreadable ones instead of failing.
+    ///
+    /// A block whose checksum does not match or whose events do not decode
+    /// is skipped and reading continues with the next one. A length prefix
+    /// running past the end of the file loses the block framing, so
+    /// everything from there on is reported as one truncated block. Legacy
+    /// uncompressed segments have no blocks to skip and are either readable
+    /// or reported as a single fault.
+    pub fn verify_blocks(&mut self) -> Result<BlockVerification> {
+        if self.header.flags & FLAG_COMPRESSED == 0 {
+            return Ok(match self.read_blocks() {
+                Ok(blocks) => BlockVerification {
+                    blocks,
+                    faults: Vec::new(),
+                    checksum_ok: true,
+                },
+                Err(e) => BlockVerification {
+                    blocks: Vec::new(),
+                    faults: vec![BlockFault {
+                        offset: HEADER_SIZE as u64,
+                        error: e.to_string(),
+                    }],
+                    checksum_ok: false,
+                },
+            });
+        }
+
+        let file_len = self.file.metadata()?.len();
+        let framing = if self.header.version >= BLOCK_CHECKSUM_VERSION {
+            8
+        } else {
+            4
+        };
+        let mut blocks = Vec::new();
+        let mut faults = Vec::new();
+        let mut checksum_hasher = Crc32Hasher::new();
+        let mut offset = HEADER_SIZE as u64;
+        while offset < file_len {
+            let mut len_buf = [0u8; 4];
+            let next = if file_len - offset >= framing {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut len_buf)?;
+                offset + framing + u32::from_le_bytes(len_buf) as u64
+            } else {
+                u64::MAX
+            };
+            if next > file_len {
+                faults.push(BlockFault {
+                    offset,
+                    error: format!("Truncated block: {} trailing bytes", file_len - offset),
+                });
+                break;
+            }
+
+            match self.read_block_body(offset, len_buf) {
+                Ok(compressed_buf) => {
+                    checksum_hasher.update(&compressed_buf);
+                    match decode_block(&compressed_buf, self.header.version) {
+                        Ok(events) => blocks.push(SegmentBlock { offset, events }),
+                        Err(e) => faults.push(BlockFault {
+                            offset,
+                            error: e.to_string(),
+                        }),
+                    }
+                }
+                Err(e) => faults.push(BlockFault {
+                    offset,
+                    error: e.to_string(),
+                }),
+            }
+            offset = next;
+        }
+
+        Ok(BlockVerification {
+            blocks,
+            checksum_ok: faults.is_empty() && checksum_hasher.finalize() == self.header.checksum,
+            faults,
+        })
+    }
+
+    /// Read the single compressed block starting at `offset`.
+    ///
+    /// Unlike [`read_blocks`](Self::read_blocks) this does not verify the
+    /// segment checksum, so it also works on blocks of a segment that is
+    /// still being written.
+    pub fn read_block_at(&mut self, offset: u64) -> Result<Vec<Event>> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut len_buf = [0u8; 4];
+        self.file.read_exact(&mut len_buf)?;
+        let compressed_buf = self.read_block_body(offset, len_buf)?;
+        decode_block(&compressed_buf, self.header.version)
+    }
+
+    /// Read the rest of the block at `offset` after its length prefix,
+    /// verifying the block checksum when the format has one
+    fn read_block_body(&mut self, offset: u64, len_buf: [u8; 4]) -> Result<Vec<u8>> {
+        let expected_crc = if self.header.version >= BLOCK_CHECKSUM_VERSION {
+            let mut crc_buf = [0u8; 4];
+            self.file.read_exact(&mut crc_buf)?;
+            Some(u32::from_le_bytes(crc_buf))
+        } else {
+            None
+        };
+        let mut compressed_buf = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+        self.file.read_exact(&mut compressed_buf)?;
+        if expected_crc.is_some_and(|crc| crc != crc32fast::hash(&compressed_buf)) {
+            return Err(Error::Storage(format!(
+                "Block checksum mismatch at offset {} in {}",
+                offset,
+                self.path.display()
+            )));
+        }
+        Ok(compressed_buf)
     }
 
     /// Get segment header
@@ -430,7 +840,6 @@ impl SegmentReader {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::event::{Event, EventPayload};
     use tempfile::TempDir;
 
     #[test]
@@ -640,7 +1049,7 @@ mod tests {
                     "data": format!("batch-{}-event-{}", batch, i)
                 })).unwrap();
                 // Use nanoseconds to ensure unique timestamps within the range
-                let timestamp = Timestamp::from_nanos(1000_000_000_000 + (batch * 1000 + i) as i64);
+                let timestamp = Timestamp::from_nanos(1_000_000_000_000 + (batch * 1000 + i) as i64);
                 let event = Event::new(
                     "test.event".to_string(),
                     timestamp,
@@ -661,4 +1070,108 @@ mod tests {
         let events = reader.read_events().unwrap();
         assert_eq!(events.len(), 3000);
     }
+
+    #[test]
+    fn test_numeric_blocks_are_columnar() {
+        let temp_dir = TempDir::new().unwrap();
+        let start = Timestamp::from_secs(1000);
+        let end = Timestamp::from_secs(100_000);
+        let mut events: Vec<Event> = (0..1000i64)
+            .map(|i| {
+                let sample = if i % 10 == 0 {
+                    NumericPayload::new(i)
+                } else {
+                    NumericPayload::new(20.0 + (i / 100) as f64 * 0.25).with_unit("celsius")
+                };
+                Event::new(
+                    "metric.sample".to_string(),
+                    Timestamp::from_secs(1000 + i * 10),
+                    "sensor:1".to_string(),
+                    EventPayload::from_numeric(&sample),
+                )
+            })
+            .collect();
+        // A block with a JSON event falls back to rows.
+        events.push(Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(20_000),
+            "sensor:1".to_string(),
+            EventPayload::from_json(&serde_json::json!({"reset": true})).unwrap(),
+        ));
+
+        let mut sizes = Vec::new();
+        for version in [SEGMENT_VERSION - 1, SEGMENT_VERSION] {
+            let path = temp_dir.path().join(format!("numeric-v{}.temp", version));
+            let mut writer = SegmentWriter::create(&path, 1, start, end)
+                .unwrap()
+                .with_format_version(version)
+                .unwrap();
+            for event in &events {
+                writer.append(event.clone()).unwrap();
+            }
+            writer.finalize().unwrap();
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+
+            let read = SegmentReader::open(&path).unwrap().read_events().unwrap();
+            assert_eq!(read.len(), events.len());
+            for (read, written) in read.iter().zip(&events) {
+                assert_eq!(read.id(), written.id());
+                assert_eq!(read.timestamp(), written.timestamp());
+                assert_eq!(
+                    read.metadata.transaction_time,
+                    written.metadata.transaction_time
+                );
+                assert_eq!(read.payload().format, written.payload().format);
+                assert_eq!(read.payload().data, written.payload().data);
+            }
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_reads_every_supported_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let ts = Timestamp::from_secs(1000);
+        let payload = EventPayload::from_json(&serde_json::json!({"value": 1})).unwrap();
+        let event = Event::new(
+            "test.event".to_string(),
+            ts,
+            "entity:1".to_string(),
+            payload,
+        );
+
+        for version in MIN_SEGMENT_VERSION..=SEGMENT_VERSION {
+            let path = temp_dir.path().join(format!("v{}.temp", version));
+            let end = Timestamp::from_secs(2000);
+            let mut writer = SegmentWriter::create(&path, 1, ts, end)
+                .unwrap()
+                .with_format_version(version)
+                .unwrap();
+            writer.append(event.clone()).unwrap();
+            writer.finalize().unwrap();
+
+            let mut reader = SegmentReader::open(&path).unwrap();
+            assert_eq!(reader.header().version, version);
+            let blocks = reader.read_blocks().unwrap();
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(reader.read_block_at(blocks[0].offset).unwrap().len(), 1);
+        }
+
+        let mut header = SegmentHeader::new(1, ts, ts);
+        header.version = SEGMENT_VERSION + 1;
+        let err = SegmentHeader::deserialize(&header.serialize()).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+
+        // A v2 block checksum pinpoints the corrupt block.
+        let path = temp_dir.path().join(format!("v{}.temp", SEGMENT_VERSION));
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+        let err = SegmentReader::open(&path)
+            .unwrap()
+            .read_block_at(HEADER_SIZE as u64)
+            .unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {}", HEADER_SIZE)));
+    }
 }
@@ -0,0 +1,368 @@
+//! Per-segment block indexes.
+//!
+//! A [`SegmentIndex`] records, for every block of a segment, its byte offset
+//! and time range, and maps entity IDs, event types and correlation IDs to
+//! the blocks that contain them. Event types are mapped by time as well,
+//! through the span of each type's events within each block. Queries use it to decode only the blocks
+//! that can match instead of keeping every event in memory. Its size grows
+//! with the number of distinct keys per block, not with the number of
+//! events.
+//!
+//! For payload fields registered with [`SegmentIndex::track_field`], the
+//! index also keeps a [`ZoneMap`] per block: the minimum and maximum numeric
+//! value of the field in that block. Numeric range predicates use them to
+//! skip blocks without decompressing them.
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::storage::field_cache::FieldPath;
+use crate::storage::segment_file::SegmentBlock;
+use crate::storage::type_index::TypeTimeIndex;
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+
+/// Location and time range of one segment block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSummary {
+    /// Byte offset of the block (its length prefix) within the segment file
+    pub offset: u64,
+    /// Earliest event timestamp in the block
+    pub min_time: Timestamp,
+    /// Latest event timestamp in the block
+    pub max_time: Timestamp,
+    /// Number of events in the block
+    pub event_count: u32,
+}
+
+impl BlockSummary {
+    /// Whether the block may hold events in `[start, end)`
+    pub fn overlaps(&self, start: Timestamp, end: Timestamp) -> bool {
+        self.min_time < end && self.max_time >= start
+    }
+}
+
+/// Smallest and largest numeric value of a payload field within a block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoneMap {
+    /// Smallest value
+    pub min: f64,
+    /// Largest value
+    pub max: f64,
+}
+
+impl ZoneMap {
+    /// Zone of a block whose values were not recorded; it matches any range
+    pub const UNBOUNDED: ZoneMap = ZoneMap {
+        min: f64::NEG_INFINITY,
+        max: f64::INFINITY,
+    };
+
+    /// Zone of the numeric values of `path` in `events`, or `None` if no
+    /// event has a numeric value there
+    pub fn compute(events: &[Event], path: &FieldPath) -> Option<Self> {
+        events
+            .iter()
+            .filter_map(|event| path.extract(event)?.as_f64())
+            .filter(|value| !value.is_nan())
+            .fold(None, |zone, value| {
+                Some(match zone {
+                    None => ZoneMap {
+                        min: value,
+                        max: value,
+                    },
+                    Some(ZoneMap { min, max }) => ZoneMap {
+                        min: min.min(value),
+                        max: max.max(value),
+                    },
+                })
+            })
+    }
+
+    /// Whether the zone may hold values in `range`
+    pub fn overlaps(&self, range: &impl RangeBounds<f64>) -> bool {
+        let above_start = match range.start_bound() {
+            Bound::Included(start) => self.max >= *start,
+            Bound::Excluded(start) => self.max > *start,
+            Bound::Unbounded => true,
+        };
+        let below_end = match range.end_bound() {
+            Bound::Included(end) => self.min <= *end,
+            Bound::Excluded(end) => self.min < *end,
+            Bound::Unbounded => true,
+        };
+        above_start && below_end
+    }
+}
+
+/// Block-level index of one segment
+#[derive(Debug, Clone)]
+pub struct SegmentIndex {
+    segment_id: u64,
+    blocks: Vec<BlockSummary>,
+    by_entity: HashMap<String, Vec<usize>>,
+    /// Block positions by event type and the span of its events there
+    by_type: TypeTimeIndex<usize>,
+    by_correlation: HashMap<String, Vec<usize>>,
+    /// Per-block zone maps of tracked payload fields, parallel to `blocks`
+    zone_maps: HashMap<FieldPath, Vec<Option<ZoneMap>>>,
+}
+
+impl SegmentIndex {
+    /// Create an empty index for a segment
+    pub fn new(segment_id: u64) -> Self {
+        Self {
+            segment_id,
+            blocks: Vec::new(),
+            by_entity: HashMap::new(),
+            by_type: TypeTimeIndex::new(),
+            by_correlation: HashMap::new(),
+            zone_maps: HashMap::new(),
+        }
+    }
+
+    /// Rebuild the index of a segment from its decoded blocks
+    pub fn from_blocks(segment_id: u64, blocks: &[SegmentBlock]) -> Self {
+        let mut index = Self::new(segment_id);
+        for block in blocks {
+            index.add_block(block.offset, &block.events);
+        }
+        index
+    }
+
+    /// Keep zone maps for the payload field at `path` in blocks added from
+    /// now on. Blocks already indexed get [`ZoneMap::UNBOUNDED`].
+    pub fn track_field(&mut self, path: FieldPath) {
+        let known = self.blocks.len();
+        self.zone_maps
+            .entry(path)
+            .or_insert_with(|| vec![Some(ZoneMap::UNBOUNDED); known]);
+    }
+
+    /// Record zone maps for `path` from the segment's decoded blocks,
+    /// replacing any partial ones.
+    pub fn track_field_from_blocks(&mut self, path: FieldPath, blocks: &[SegmentBlock]) {
+        let zones = blocks
+            .iter()
+            .filter(|block| !block.events.is_empty())
+            .map(|block| ZoneMap::compute(&block.events, &path))
+            .collect();
+        self.zone_maps.insert(path, zones);
+    }
+
+    /// Segment this index describes
+    pub fn segment_id(&self) -> u64 {
+        self.segment_id
+    }
+
+    /// Record a block written at `offset` holding `events`
+    pub fn add_block(&mut self, offset: u64, events: &[Event]) {
+        let Some(first) = events.first() else {
+            return;
+        };
+        let position = self.blocks.len();
+        let mut summary = BlockSummary {
+            offset,
+            min_time: first.timestamp(),
+            max_time: first.timestamp(),
+            event_count: events.len() as u32,
+        };
+        let mut type_spans: HashMap<&str, (Timestamp, Timestamp)> = HashMap::new();
+
+        for event in events {
+            summary.min_time = summary.min_time.min(event.timestamp());
+            summary.max_time = summary.max_time.max(event.timestamp());
+            add_posting(&mut self.by_entity, event.entity_id(), position);
+            let ts = event.timestamp();
+            let span = type_spans.entry(event.event_type()).or_insert((ts, ts));
+            *span = (span.0.min(ts), span.1.max(ts));
+            if let Some(correlation_id) = &event.metadata.correlation_id {
+                add_posting(&mut self.by_correlation, correlation_id, position);
+            }
+        }
+        for (event_type, (first, last)) in type_spans {
+            self.by_type.insert(event_type, first, last, position);
+        }
+        for (path, zones) in &mut self.zone_maps {
+            zones.push(ZoneMap::compute(events, path));
+        }
+        self.blocks.push(summary);
+    }
+
+    /// Latest event timestamp in the segment, if it has any events
+    pub fn max_time(&self) -> Option<Timestamp> {
+        self.blocks.iter().map(|block| block.max_time).max()
+    }
+
+    /// Distinct entity IDs in the segment
+    pub fn entity_ids(&self) -> impl Iterator<Item = &str> + '_ {
+        self.by_entity.keys().map(String::as_str)
+    }
+
+    /// Distinct event types in the segment
+    pub fn event_types(&self) -> impl Iterator<Item = &str> + '_ {
+        self.by_type.event_types()
+    }
+
+    /// All blocks, in file order
+    pub fn blocks(&self) -> &[BlockSummary] {
+        &self.blocks
+    }
+
+    /// Blocks holding events of `entity_id`, in file order
+    pub fn entity_blocks(&self, entity_id: &str) -> impl Iterator<Item = &BlockSummary> + '_ {
+        self.postings(&self.by_entity, entity_id)
+    }
+
+    /// Blocks holding events of type `event_type` with a timestamp in
+    /// `[start, end)`, in file order
+    pub fn type_blocks(
+        &self,
+        event_type: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Vec<&BlockSummary> {
+        let mut positions: Vec<usize> = self
+            .by_type
+            .overlapping(event_type, start, end)
+            .map(|(_, &position)| position)
+            .collect();
+        positions.sort_unstable();
+        positions
+            .iter()
+            .map(|&position| &self.blocks[position])
+            .collect()
+    }
+
+    /// Blocks holding events with correlation ID `correlation_id`, in file order
+    pub fn correlation_blocks(
+        &self,
+        correlation_id: &str,
+    ) -> impl Iterator<Item = &BlockSummary> + '_ {
+        self.postings(&self.by_correlation, correlation_id)
+    }
+
+    /// Blocks that may hold a numeric value of the payload field at `path`
+    /// within `range`, in file order. Without zone maps for `path`, every
+    /// block is returned.
+    pub fn field_range_blocks<'a>(
+        &'a self,
+        path: &FieldPath,
+        range: &impl RangeBounds<f64>,
+    ) -> Box<dyn Iterator<Item = &'a BlockSummary> + 'a> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        match self.zone_maps.get(path) {
+            Some(zones) => Box::new(
+                self.blocks
+                    .iter()
+                    .zip(zones)
+                    .filter(move |(_, zone)| zone.is_some_and(|zone| zone.overlaps(&range)))
+                    .map(|(block, _)| block),
+            ),
+            None => Box::new(self.blocks.iter()),
+        }
+    }
+
+    fn postings<'a>(
+        &'a self,
+        map: &'a HashMap<String, Vec<usize>>,
+        key: &str,
+    ) -> impl Iterator<Item = &'a BlockSummary> + 'a {
+        map.get(key)
+            .into_iter()
+            .flatten()
+            .map(move |&position| &self.blocks[position])
+    }
+}
+
+fn add_posting(map: &mut HashMap<String, Vec<usize>>, key: &str, position: usize) {
+    let postings = map.entry(key.to_string()).or_default();
+    if postings.last() != Some(&position) {
+        postings.push(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+
+    fn event(entity_id: &str, event_type: &str, secs: i64) -> Event {
+        Event::new(
+            event_type.to_string(),
+            Timestamp::from_secs(secs),
+            entity_id.to_string(),
+            EventPayload::from_json(&serde_json::json!({})).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_block_postings() {
+        let mut index = SegmentIndex::new(7);
+        index.add_block(64, &[event("a", "created", 10), event("a", "updated", 30)]);
+        index.add_block(200, &[event("b", "created", 20)]);
+        index.add_block(300, &[]);
+
+        assert_eq!(index.blocks().len(), 2);
+        assert_eq!(index.blocks()[0].min_time, Timestamp::from_secs(10));
+        assert_eq!(index.blocks()[0].max_time, Timestamp::from_secs(30));
+
+        let offsets =
+            |blocks: Vec<&BlockSummary>| blocks.iter().map(|b| b.offset).collect::<Vec<_>>();
+        assert_eq!(offsets(index.entity_blocks("a").collect()), vec![64]);
+        let ts = Timestamp::from_secs;
+        assert_eq!(
+            offsets(index.type_blocks("created", ts(0), ts(100))),
+            vec![64, 200]
+        );
+        // The first block spans 10..=30, but its "created" event is at 10.
+        assert_eq!(
+            offsets(index.type_blocks("created", ts(15), ts(100))),
+            vec![200]
+        );
+        assert_eq!(
+            offsets(index.type_blocks("updated", ts(15), ts(100))),
+            vec![64]
+        );
+        assert!(index.entity_blocks("missing").next().is_none());
+        assert!(!index.blocks()[1].overlaps(Timestamp::from_secs(21), Timestamp::from_secs(40)));
+    }
+
+    #[test]
+    fn test_zone_maps_skip_blocks() {
+        let amount = |value: serde_json::Value| {
+            let mut event = event("a", "paid", 0);
+            event.payload = EventPayload::from_json(&serde_json::json!({"amount": value})).unwrap();
+            event
+        };
+        let path = FieldPath::parse("amount").unwrap();
+        let mut index = SegmentIndex::new(1);
+        index.add_block(10, &[amount(5.into())]);
+        index.track_field(path.clone());
+        index.add_block(20, &[amount(100.into()), amount(900.into())]);
+        index.add_block(30, &[amount(1500.into()), amount(2000.into())]);
+        index.add_block(40, &[amount("n/a".into())]);
+
+        let offsets = |range| {
+            index
+                .field_range_blocks(&path, &range)
+                .map(|b| b.offset)
+                .collect::<Vec<_>>()
+        };
+        // The first block predates tracking, so it can never be skipped.
+        assert_eq!(
+            offsets((Bound::Excluded(1000.0), Bound::Unbounded)),
+            vec![10, 30]
+        );
+        assert_eq!(
+            offsets((Bound::Excluded(900.0), Bound::Excluded(1500.0))),
+            vec![10]
+        );
+        assert_eq!(
+            offsets((Bound::Included(900.0), Bound::Unbounded)),
+            vec![10, 20, 30]
+        );
+
+        let other = FieldPath::parse("other").unwrap();
+        assert_eq!(index.field_range_blocks(&other, &(0.0..1.0)).count(), 4);
+    }
+}
@@ -1,19 +1,101 @@
 //! Persistent event journal backed by WAL + segment files.
 //!
 //! This module provides a disk-backed implementation of `EventJournal`
-//! that writes all events to a write-ahead log and periodically flushes
+//! that writes all events to an async write-ahead log and periodically flushes
 //! them into immutable segment files managed by `SegmentManager`.
+//!
+//! Finalized segments are recorded in a [`Manifest`]; once a segment is in
+//! the manifest its events no longer need the WAL, which lets
+//! [`SegmentedJournal::checkpoint`] truncate it safely.
+//!
+//! Reads are served from the segments themselves: each segment keeps a
+//! [`SegmentIndex`] of its blocks, matching blocks are decoded through a
+//! bounded [`BlockCache`], and only the active segment's unwritten block
+//! buffer lives in memory. Memory use is therefore bounded by the cache
+//! capacity and the index sizes rather than by the number of events.
 
-use crate::core::event::Event;
+use crate::core::event::{Event, EventOrdering};
 use crate::core::temporal::Timestamp;
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::storage::block_cache::{BlockCache, BlockCacheStats};
+use crate::storage::dir_lock::DirLock;
+use crate::storage::entity_catalog::{EntityCatalog, EntityPage};
+use crate::storage::event_cursor::EventCursor;
+use crate::storage::field_cache::{BlockId, FieldExtractionCache, FieldPath, FieldValue};
+use crate::storage::field_predicate::FieldPredicate;
+use crate::storage::index_maintainer::{IndexRegistry, SharedIndex};
+use crate::storage::journal::{truncate_after_ties, FieldScan, Lsn};
+use crate::storage::manifest::{CheckpointRecord, Manifest};
+use crate::storage::retention::{RetentionPolicy, RetentionReport, EXPIRED_SEGMENT_PREFIX};
+use crate::storage::scrub::{ScrubPolicy, ScrubReport, REPLICA_SEGMENT_PREFIX};
 use crate::storage::segment_file::{
-    SegmentHeader, SegmentReader, SegmentWriter, MAX_EVENTS_PER_SEGMENT, MAX_SEGMENT_SIZE,
+    BlockFault, BlockVerification, SegmentHeader, SegmentReader, SegmentWriter, HEADER_SIZE,
+    MAX_EVENTS_PER_SEGMENT, MAX_SEGMENT_SIZE, MIN_SEGMENT_VERSION, SEGMENT_VERSION,
 };
-use crate::storage::{EventJournal, InMemoryJournal, WriteAheadLog};
-use std::collections::HashMap;
+use crate::storage::segment_index::{BlockSummary, SegmentIndex};
+use crate::storage::wal_archive::WalArchiver;
+use crate::storage::window::WindowAggregator;
+use crate::storage::{AsyncWriteAheadLog, EventJournal, InMemoryWAL};
+use futures::StreamExt;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
 use std::fs;
+use std::ops::{ControlFlow, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Extension of segment files being rewritten by a migration or repair.
+const MIGRATION_EXTENSION: &str = "seg.migrate";
+
+/// Default number of segments a range scan reads concurrently.
+pub const DEFAULT_SCAN_PARALLELISM: usize = 4;
+
+/// Outcome of verifying one finalized segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentCheck {
+    /// Segment ID.
+    pub segment_id: u64,
+    /// Readable blocks.
+    pub blocks: usize,
+    /// Events in the readable blocks.
+    pub events: u64,
+    /// Unreadable blocks.
+    pub faults: Vec<BlockFault>,
+    /// Whether the segment checksum matched.
+    pub checksum_ok: bool,
+    /// Cataloged events missing from the readable blocks.
+    pub lost_events: u64,
+    /// Whether the segment was rewritten without its unreadable blocks.
+    pub repaired: bool,
+}
+
+impl SegmentCheck {
+    /// Whether the segment needed no repair.
+    pub fn is_healthy(&self) -> bool {
+        self.faults.is_empty() && self.checksum_ok
+    }
+}
+
+/// Outcome of [`SegmentManager::verify_all`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Checks of every finalized segment, in catalog order.
+    pub segments: Vec<SegmentCheck>,
+}
+
+impl VerifyReport {
+    /// Whether every segment needed no repair.
+    pub fn is_healthy(&self) -> bool {
+        self.segments.iter().all(SegmentCheck::is_healthy)
+    }
+
+    /// Segments that needed repair.
+    pub fn damaged(&self) -> impl Iterator<Item = &SegmentCheck> {
+        self.segments.iter().filter(|check| !check.is_healthy())
+    }
+}
 
 /// Manages creation and rotation of segment files on disk.
 pub struct SegmentManager {
@@ -25,30 +107,243 @@ pub struct SegmentManager {
     next_segment_id: u64,
     /// Known segment headers (metadata catalog).
     segments: Vec<SegmentHeader>,
+    /// Block indexes of finalized segments, parallel to `segments`.
+    indexes: Vec<SegmentIndex>,
+    /// Recently decoded blocks.
+    block_cache: Arc<BlockCache>,
+    /// How reads order same-timestamp events.
+    ordering: EventOrdering,
+    /// Latest checkpoint recorded in the manifest.
+    checkpoint: Option<CheckpointRecord>,
+    /// Events whose segments were removed by retention.
+    expired_events: u64,
+    /// Payload fields whose per-block zone maps are kept.
+    zone_fields: Vec<FieldPath>,
+    /// Payload field columns extracted from finalized segment blocks.
+    field_cache: FieldExtractionCache,
+    /// Format version new segments are written in.
+    write_version: u8,
+    /// Catalog position the next scrub pass starts at.
+    scrub_cursor: AtomicUsize,
+    /// Every entity ID with events in the segments or the active segment.
+    entities: EntityCatalog,
+    /// Removed segment files kept until no read-only process holds `dir`.
+    deferred_unlinks: Vec<PathBuf>,
+    /// Segments a range scan reads concurrently.
+    scan_parallelism: usize,
+    /// Whether `dir` was opened by [`SegmentManager::open_read_only`].
+    read_only: bool,
+    /// Transaction time after which recorded events are hidden.
+    as_of: Option<Timestamp>,
+    /// Exclusive writer lock on `dir`, or a shared reader lock when read
+    /// only, held for the manager's lifetime.
+    _lock: Option<DirLock>,
 }
 
 impl SegmentManager {
     /// Create a new manager rooted at the given directory.
+    ///
+    /// Takes the exclusive writer lock on `dir`; fails if another writer
+    /// already owns it.
     pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
         let dir = dir.as_ref().to_path_buf();
         fs::create_dir_all(&dir)?;
+        let lock = DirLock::exclusive(&dir)?;
+        Ok(Self::with_lock(dir, Some(lock)))
+    }
 
-        // For now, start from segment ID 1 and ignore any existing files.
-        // Later we can scan `dir` and pick the next available ID.
-        Ok(Self {
+    /// An empty manager rooted at `dir` holding `lock`.
+    ///
+    /// Starts from segment ID 1 and ignores any existing files;
+    /// [`SegmentManager::restore`] loads them from the manifest.
+    fn with_lock(dir: PathBuf, lock: Option<DirLock>) -> Self {
+        Self {
             dir,
             active: None,
             next_segment_id: 1,
             segments: Vec::new(),
-        })
+            indexes: Vec::new(),
+            block_cache: Arc::default(),
+            ordering: EventOrdering::default(),
+            checkpoint: None,
+            expired_events: 0,
+            zone_fields: Vec::new(),
+            field_cache: FieldExtractionCache::default(),
+            write_version: SEGMENT_VERSION,
+            scrub_cursor: AtomicUsize::new(0),
+            entities: EntityCatalog::new(),
+            deferred_unlinks: Vec::new(),
+            scan_parallelism: DEFAULT_SCAN_PARALLELISM,
+            read_only: false,
+            as_of: None,
+            _lock: lock,
+        }
+    }
+
+    /// Open the manager rooted at `dir`, restoring the segment catalog from
+    /// its manifest.
+    ///
+    /// Block indexes are rebuilt from the cataloged segment files. Segment
+    /// files missing from the manifest were still being written when the
+    /// previous writer stopped; their events are still in the WAL, so they
+    /// are removed rather than recovered. Files of segments dropped by
+    /// retention are removed the same way once no reader holds `dir`.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let mut manager = Self::new(dir)?;
+        let Some(manifest) = Manifest::load(&manager.dir)? else {
+            return Ok(manager);
+        };
+        manager.restore(&manifest)?;
+        for entry in fs::read_dir(&manager.dir)? {
+            let path = entry?.path();
+            let orphan = segment_file_id(&path)
+                .is_some_and(|id| !manifest.segments.iter().any(|h| h.segment_id == id));
+            if orphan || is_migration_file(&path) {
+                manager.unlink_segment_file(path)?;
+            }
+        }
+        Ok(manager)
+    }
+
+    /// Open the finalized segments cataloged in the manifest of `dir`
+    /// without writing to it, e.g. while another process owns it as the
+    /// writer. Anything that would write fails.
+    ///
+    /// With `as_of`, events recorded after it (by transaction time) are
+    /// hidden from every read.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P, as_of: Option<Timestamp>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let manifest = Manifest::load(&dir)?
+            .ok_or_else(|| Error::Storage(format!("No manifest found in {}", dir.display())))?;
+        // Best effort: backups on read-only media cannot hold a lock file.
+        let lock = DirLock::shared(&dir).ok();
+        let mut manager = Self::with_lock(dir, lock);
+        manager.read_only = true;
+        manager.as_of = as_of;
+        manager.restore(&manifest)?;
+        Ok(manager)
+    }
+
+    /// Load the segments cataloged in `manifest`, with their block indexes
+    /// and the entity catalog.
+    fn restore(&mut self, manifest: &Manifest) -> Result<()> {
+        // The file header is authoritative: a migration may have replaced
+        // the file without getting to update the manifest.
+        for header in &manifest.segments {
+            let mut reader = SegmentReader::open(self.segment_path(header.segment_id))?;
+            let blocks = reader.read_blocks()?;
+            self.indexes
+                .push(SegmentIndex::from_blocks(header.segment_id, &blocks));
+            self.segments.push(reader.header().clone());
+        }
+        self.next_segment_id = manifest.last_segment_id().map_or(1, |id| id + 1);
+        self.checkpoint = manifest.checkpoint.clone();
+        self.expired_events = manifest.expired_events;
+        if self.as_of.is_some() {
+            // Entities whose events are all hidden are left out.
+            let mut ids = Vec::new();
+            self.visit(
+                |index| index.blocks().iter().collect(),
+                |event| ids.push(event.entity_id().to_string()),
+            )?;
+            self.entities = EntityCatalog::from_ids(ids);
+            return Ok(());
+        }
+        match EntityCatalog::load(&self.dir, manifest.end_position())? {
+            Some(entities) => self.entities = entities,
+            None => self.rebuild_entity_catalog(),
+        }
+        Ok(())
+    }
+
+    /// Fail if `dir` was opened read-only.
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Storage(format!(
+                "Segments in {} are opened read-only",
+                self.dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether reads hide `event`, recorded after the `as_of` time the
+    /// segments were opened at.
+    fn hides(&self, event: &Event) -> bool {
+        self.as_of
+            .is_some_and(|as_of| event.metadata.transaction_time > as_of)
+    }
+
+    /// The events of a decoded block that reads may see.
+    fn visible<'e>(&self, events: &'e [Event]) -> Cow<'e, [Event]> {
+        if !events.iter().any(|event| self.hides(event)) {
+            return Cow::Borrowed(events);
+        }
+        let shown = events.iter().filter(|event| !self.hides(event));
+        Cow::Owned(shown.cloned().collect())
+    }
+
+    /// Append position covered by finalized segments, counting events
+    /// expired by retention.
+    pub fn cataloged_event_count(&self) -> u64 {
+        self.expired_events
+            + self
+                .segments
+                .iter()
+                .map(|h| h.event_count as u64)
+                .sum::<u64>()
+    }
+
+    /// Replace the block cache with one holding at most `blocks` blocks.
+    pub fn set_block_cache_capacity(&mut self, blocks: usize) {
+        self.block_cache = Arc::new(BlockCache::new(blocks));
+    }
+
+    /// Read up to `segments` segments concurrently in range scans; `1`
+    /// reads them one after another.
+    pub fn set_scan_parallelism(&mut self, segments: usize) {
+        self.scan_parallelism = segments.max(1);
+    }
+
+    /// Set how reads order same-timestamp events.
+    pub fn set_ordering(&mut self, ordering: EventOrdering) {
+        self.ordering = ordering;
+    }
+
+    /// Write new segments in format `version` rather than the current
+    /// [`SEGMENT_VERSION`].
+    ///
+    /// During a rolling upgrade this keeps segments readable by nodes that
+    /// have not been upgraded yet; see
+    /// [`negotiate_cluster`](crate::distributed::negotiate_cluster). The
+    /// active segment keeps the version it was created with.
+    pub fn set_write_version(&mut self, version: u8) -> Result<()> {
+        if !(MIN_SEGMENT_VERSION..=SEGMENT_VERSION).contains(&version) {
+            return Err(Error::Storage(format!(
+                "Unsupported segment version {}, expected {} to {}",
+                version, MIN_SEGMENT_VERSION, SEGMENT_VERSION
+            )));
+        }
+        self.write_version = version;
+        Ok(())
+    }
+
+    /// Format version new segments are written in.
+    pub fn write_version(&self) -> u8 {
+        self.write_version
+    }
+
+    /// Directory where segment files are stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
     }
 
     fn segment_path(&self, segment_id: u64) -> PathBuf {
-        self.dir
-            .join(format!("segment-{segment_id:020}.seg"))
+        Manifest::segment_path(&self.dir, segment_id)
     }
 
     fn open_new_segment(&mut self) -> Result<()> {
+        self.ensure_writable()?;
         // Use a very wide time range so we don't reject events by timestamp.
         let start = Timestamp::from_nanos(i64::MIN + 1);
         let end = Timestamp::from_nanos(i64::MAX);
@@ -56,7 +351,11 @@ impl SegmentManager {
         self.next_segment_id += 1;
 
         let path = self.segment_path(segment_id);
-        let writer = SegmentWriter::create(path, segment_id, start, end)?;
+        let mut writer = SegmentWriter::create(path, segment_id, start, end)?
+            .with_format_version(self.write_version)?;
+        for field in &self.zone_fields {
+            writer.track_field(field.clone());
+        }
         self.active = Some(writer);
         Ok(())
     }
@@ -67,19 +366,30 @@ impl SegmentManager {
             if header.event_count >= MAX_EVENTS_PER_SEGMENT
                 || header.compressed_size as u64 >= MAX_SEGMENT_SIZE
             {
-                // Finalize current segment and drop the writer.
-                let writer = self.active.take().unwrap();
-                let header = writer.finalize()?;
-                self.segments.push(header);
+                self.finalize_active()?;
             }
         }
         Ok(())
     }
 
-    fn append_event(&mut self, event: Event) -> Result<()> {
+    /// Finalize the active segment, if any, and catalog it.
+    fn finalize_active(&mut self) -> Result<()> {
+        if let Some(writer) = self.active.take() {
+            let (header, index) = writer.finalize_with_index()?;
+            self.segments.push(header);
+            self.indexes.push(index);
+            self.persist_manifest()?;
+        }
+        Ok(())
+    }
+
+    /// Append an event to the active segment, opening one if needed and
+    /// rotating it once full. Durability is up to the caller.
+    pub(crate) fn append_event(&mut self, event: Event) -> Result<()> {
         if self.active.is_none() {
             self.open_new_segment()?;
         }
+        self.entities.record(event.entity_id());
         if let Some(writer) = self.active.as_mut() {
             writer.append(event)?;
         }
@@ -89,11 +399,293 @@ impl SegmentManager {
 
     /// Flush all active data to disk and close the current segment.
     pub fn flush(&mut self) -> Result<()> {
-        if let Some(writer) = self.active.take() {
-            let header = writer.finalize()?;
-            self.segments.push(header);
+        self.finalize_active()
+    }
+
+    /// Write the current segment catalog and checkpoint to the manifest,
+    /// and the entity catalog next to it.
+    fn persist_manifest(&mut self) -> Result<()> {
+        self.ensure_writable()?;
+        let manifest = Manifest {
+            segments: self.segments.clone(),
+            checkpoint: self.checkpoint.clone(),
+            expired_events: self.expired_events,
+            ..Manifest::new()
+        };
+        manifest.store(&self.dir)?;
+        self.entities.store(&self.dir, manifest.end_position())
+    }
+
+    /// Recompute the entity catalog from the segment indexes and the
+    /// active segment.
+    fn rebuild_entity_catalog(&mut self) {
+        let active = self.active.as_ref();
+        let ids = self
+            .indexes
+            .iter()
+            .chain(active.map(|w| w.index()))
+            .flat_map(|index| index.entity_ids())
+            .chain(
+                active
+                    .into_iter()
+                    .flat_map(|w| w.buffered_events().iter().map(|e| e.entity_id())),
+            );
+        self.entities = EntityCatalog::from_ids(ids);
+    }
+
+    /// Whether `entity_id` may have events; `false` means it has none.
+    /// Answered from the entity filter without touching any segment.
+    pub fn may_contain_entity(&self, entity_id: &str) -> bool {
+        self.entities.may_contain(entity_id)
+    }
+
+    /// Up to `limit` entity IDs starting with `prefix`, in entity order,
+    /// after `cursor`, the last ID of the previous page.
+    pub fn list_entities(&self, prefix: &str, cursor: Option<&str>, limit: usize) -> EntityPage {
+        self.entities.page(prefix, cursor, limit)
+    }
+
+    /// Finalize the active segment and record a checkpoint covering every
+    /// finalized segment in the manifest, up to WAL position `wal_lsn`.
+    ///
+    /// When this returns, all events appended so far are durably cataloged.
+    pub fn checkpoint(&mut self, wal_lsn: Lsn) -> Result<CheckpointRecord> {
+        self.flush()?;
+
+        let checkpoint_id = self
+            .checkpoint
+            .as_ref()
+            .map(|c| c.checkpoint_id + 1)
+            .unwrap_or(1);
+        let last_segment_id = self.segments.iter().map(|h| h.segment_id).max();
+        let record = CheckpointRecord {
+            checkpoint_id,
+            last_segment_id: last_segment_id.unwrap_or(0),
+            event_count: self.cataloged_event_count(),
+            wal_lsn,
+            created_at: Timestamp::now(),
+        };
+        self.checkpoint = Some(record.clone());
+        self.persist_manifest()?;
+        Ok(record)
+    }
+
+    /// Latest checkpoint, if one has been taken.
+    pub fn last_checkpoint(&self) -> Option<&CheckpointRecord> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Number of leading finalized segments that `policy` expires at `now`.
+    pub fn expired_segment_count(&self, policy: &RetentionPolicy, now: Timestamp) -> usize {
+        let size = |header: &SegmentHeader| HEADER_SIZE as u64 + header.compressed_size as u64;
+        let mut total: u64 = self.segments.iter().map(size).sum();
+        let mut count = 0;
+        for (header, index) in self.segments.iter().zip(&self.indexes) {
+            let over_size = policy.max_size.is_some_and(|max| total > max);
+            let too_old = index.max_time().is_some_and(|max_time| {
+                index.event_types().all(|event_type| {
+                    policy
+                        .cutoff(event_type, now)
+                        .is_some_and(|cutoff| max_time < cutoff)
+                })
+            });
+            if !(over_size || too_old) {
+                break;
+            }
+            total -= size(header);
+            count += 1;
+        }
+        count
+    }
+
+    /// Remove the `count` oldest finalized segments.
+    ///
+    /// The manifest is updated before the files are deleted; files left
+    /// behind by a crash are cleaned up by [`SegmentManager::open`]. While
+    /// read-only processes hold the directory, the files are kept until a
+    /// later call finds no readers.
+    pub fn drop_oldest_segments(&mut self, count: usize) -> Result<Vec<SegmentHeader>> {
+        self.unlink_deferred()?;
+        let count = count.min(self.segments.len());
+        let dropped: Vec<SegmentHeader> = self.segments.drain(..count).collect();
+        self.indexes.drain(..count);
+        self.expired_events += dropped.iter().map(|h| h.event_count as u64).sum::<u64>();
+        // Entities may have lost their last events.
+        self.rebuild_entity_catalog();
+        self.persist_manifest()?;
+
+        for header in &dropped {
+            self.block_cache.invalidate_segment(header.segment_id);
+            self.field_cache.invalidate_segment(header.segment_id);
+            self.unlink_segment_file(self.segment_path(header.segment_id))?;
+        }
+        Ok(dropped)
+    }
+
+    /// Remove a segment file no longer in the catalog, or defer it while
+    /// read-only processes may still be reading it.
+    fn unlink_segment_file(&mut self, path: PathBuf) -> Result<()> {
+        self.ensure_writable()?;
+        if DirLock::has_readers(&self.dir)? {
+            self.deferred_unlinks.push(path);
+            return Ok(());
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove the files deferred by earlier removals if no reader holds
+    /// the directory any more. Returns the number of files removed.
+    pub fn unlink_deferred(&mut self) -> Result<usize> {
+        if self.deferred_unlinks.is_empty() || DirLock::has_readers(&self.dir)? {
+            return Ok(0);
+        }
+        let paths = std::mem::take(&mut self.deferred_unlinks);
+        for path in &paths {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(paths.len())
+    }
+
+    /// Segment files removed from the catalog but kept for readers
+    pub fn deferred_unlinks(&self) -> &[PathBuf] {
+        &self.deferred_unlinks
+    }
+
+    /// Rewrite finalized segments written in an older format version in
+    /// the write version, by default the current [`SEGMENT_VERSION`],
+    /// returning the migrated segment IDs.
+    ///
+    /// Each segment is written to a temporary file and renamed over the
+    /// original, so a crash leaves either the old or the new file in place.
+    /// Readers that already opened the old file keep reading it; nothing is
+    /// unlinked.
+    /// Compaction should call this so old segments are upgraded as they
+    /// are rewritten anyway.
+    pub fn migrate_segments(&mut self) -> Result<Vec<u64>> {
+        self.ensure_writable()?;
+        let mut migrated = Vec::new();
+        for i in 0..self.segments.len() {
+            let old = &self.segments[i];
+            if old.version >= self.write_version {
+                continue;
+            }
+            let segment_id = old.segment_id;
+            let path = self.segment_path(segment_id);
+            let events = SegmentReader::open(&path)?.read_events()?;
+            let (header, index) =
+                rewrite_segment(&path, old, events, self.write_version, &self.zone_fields)?;
+
+            self.segments[i] = header;
+            self.indexes[i] = index;
+            self.block_cache.invalidate_segment(segment_id);
+            self.field_cache.invalidate_segment(segment_id);
+            migrated.push(segment_id);
+        }
+        if !migrated.is_empty() {
+            self.persist_manifest()?;
+        }
+        Ok(migrated)
+    }
+
+    /// Re-read every finalized segment, verifying block and segment
+    /// checksums and that all events decode.
+    ///
+    /// With `repair`, damaged segments are rewritten without their
+    /// unreadable blocks. The lost events keep counting toward the append
+    /// position, like events expired by retention, so WAL positions stay
+    /// aligned. The active segment is not checked.
+    pub fn verify_all(&mut self, repair: bool) -> Result<VerifyReport> {
+        if repair {
+            self.ensure_writable()?;
+        }
+        let mut report = VerifyReport::default();
+        let mut repaired = false;
+        for i in 0..self.segments.len() {
+            let header = &self.segments[i];
+            let path = self.segment_path(header.segment_id);
+            let (check, rewritten) =
+                check_segment(&path, header, repair, self.write_version, &self.zone_fields)?;
+            if let Some((header, index)) = rewritten {
+                self.block_cache.invalidate_segment(check.segment_id);
+                self.field_cache.invalidate_segment(check.segment_id);
+                self.expired_events += check.lost_events;
+                self.segments[i] = header;
+                self.indexes[i] = index;
+                repaired = true;
+            }
+            report.segments.push(check);
+        }
+        if repaired {
+            self.persist_manifest()?;
+        }
+        Ok(report)
+    }
+
+    /// The next `count` finalized segments to verify and their paths,
+    /// continuing where the previous call stopped and wrapping around the
+    /// catalog.
+    pub fn scrub_targets(&self, count: usize) -> Vec<(SegmentHeader, PathBuf)> {
+        let len = self.segments.len();
+        let count = count.min(len);
+        let start = self.scrub_cursor.fetch_add(count, Ordering::Relaxed);
+        (start..start + count)
+            .map(|i| {
+                let header = &self.segments[i % len];
+                (header.clone(), self.segment_path(header.segment_id))
+            })
+            .collect()
+    }
+
+    /// Replace finalized segment `segment_id` with the segment file `data`,
+    /// e.g. an intact copy from a replica.
+    ///
+    /// The copy is only installed if it verifies and holds as many events
+    /// as the cataloged segment; returns whether it was.
+    pub fn restore_segment(&mut self, segment_id: u64, data: &[u8]) -> Result<bool> {
+        self.ensure_writable()?;
+        let Some(i) = self
+            .segments
+            .iter()
+            .position(|h| h.segment_id == segment_id)
+        else {
+            return Ok(false);
+        };
+        let path = self.segment_path(segment_id);
+        let tmp_path = path.with_extension(MIGRATION_EXTENSION);
+        fs::write(&tmp_path, data)?;
+        let verified = SegmentReader::open(&tmp_path).and_then(|mut reader| {
+            let header = reader.header().clone();
+            Ok((header, reader.verify_blocks()?))
+        });
+        match verified {
+            Ok((header, verification))
+                if verification.is_healthy()
+                    && header.segment_id == segment_id
+                    && verification.event_count() == self.segments[i].event_count as u64 =>
+            {
+                fs::rename(&tmp_path, &path)?;
+                let mut index = SegmentIndex::from_blocks(segment_id, &verification.blocks);
+                for field in &self.zone_fields {
+                    index.track_field_from_blocks(field.clone(), &verification.blocks);
+                }
+                self.segments[i] = header;
+                self.indexes[i] = index;
+                self.block_cache.invalidate_segment(segment_id);
+                self.field_cache.invalidate_segment(segment_id);
+                self.persist_manifest()?;
+                Ok(true)
+            }
+            _ => {
+                fs::remove_file(&tmp_path)?;
+                Ok(false)
+            }
         }
-        Ok(())
     }
 
     /// List all known segment headers.
@@ -108,159 +700,1558 @@ impl SegmentManager {
             let path = self.segment_path(header.segment_id);
             if path.exists() {
                 let mut reader = SegmentReader::open(&path)?;
-                all.extend(reader.read_events()?);
+                all.extend(reader.read_events()?.into_iter().filter(|e| !self.hides(e)));
             }
         }
         Ok(all)
     }
-}
 
-/// Disk-backed implementation of `EventJournal` using a WAL and segment files.
-///
-/// For now, queries are served from an in-memory journal built alongside
-/// the WAL/segment writes. On startup, a future constructor can rebuild
-/// this state by replaying WAL + segments.
-pub struct SegmentedJournal<W: WriteAheadLog> {
-    wal: W,
-    segment_manager: SegmentManager,
-    /// In-memory view used for fast queries.
-    in_memory: InMemoryJournal,
-    /// Simple index by event type for this journal.
-    events_by_type: HashMap<String, Vec<Event>>,
-}
+    /// Every event, including those not yet in a finalized segment, in
+    /// append order.
+    pub fn all_events(&self) -> Result<Vec<Event>> {
+        self.scan(|index| index.blocks().iter().collect(), |_| true)
+    }
 
-impl<W: WriteAheadLog> SegmentedJournal<W> {
-    /// Create a new segmented journal rooted at `dir` using the provided WAL.
-    pub fn new<P: AsRef<Path>>(dir: P, wal: W) -> Result<Self> {
-        let segment_manager = SegmentManager::new(dir)?;
-        Ok(Self {
-            wal,
-            segment_manager,
-            in_memory: InMemoryJournal::new(),
-            events_by_type: HashMap::new(),
-        })
+    /// Append position of the last event, counting events expired by
+    /// retention and those not yet in a finalized segment.
+    pub fn end_position(&self) -> Lsn {
+        self.cataloged_event_count()
+            + self
+                .active
+                .as_ref()
+                .map_or(0, |writer| writer.header().event_count as u64)
     }
 
-    fn index_event_by_type(&mut self, event: &Event) {
-        let ty = event.event_type().to_string();
-        self.events_by_type
-            .entry(ty)
-            .or_insert_with(Vec::new)
-            .push(event.clone());
+    /// Pass every event positioned after `lsn` to `visit` with its
+    /// position, in append order, including events not yet in a finalized
+    /// segment, until `visit` breaks.
+    ///
+    /// Blocks are read one at a time and those lying entirely at or before
+    /// `lsn` are skipped, so memory stays bounded and the cost follows the
+    /// length of the suffix.
+    pub fn visit_events_after(
+        &self,
+        lsn: Lsn,
+        mut visit: impl FnMut(Lsn, &Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        let mut position = self.expired_events;
+        let mut visit_block = |events: &[Event], position: Lsn| {
+            let skip = lsn.saturating_sub(position) as usize;
+            for (i, event) in events.iter().enumerate().skip(skip) {
+                if !self.hides(event) {
+                    visit(position + i as Lsn + 1, event)?;
+                }
+            }
+            ControlFlow::Continue(())
+        };
+
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        for index in self.indexes.iter().chain(active_index) {
+            let path = self.segment_path(index.segment_id());
+            let mut reader: Option<SegmentReader> = None;
+            for block in index.blocks() {
+                let count = block.event_count as Lsn;
+                if position + count <= lsn {
+                    position += count;
+                    continue;
+                }
+                let id = BlockId {
+                    segment_id: index.segment_id(),
+                    offset: block.offset,
+                };
+                let events = self.block_cache.get_or_load(id, || {
+                    let reader = match reader.as_mut() {
+                        Some(reader) => reader,
+                        None => reader.insert(SegmentReader::open(&path)?),
+                    };
+                    reader.read_block_at(block.offset)
+                })?;
+                if visit_block(&events, position).is_break() {
+                    return Ok(());
+                }
+                position += count;
+            }
+        }
+
+        if let Some(writer) = &self.active {
+            let _ = visit_block(writer.buffered_events(), position);
+        }
+        Ok(())
     }
 
-    /// Get list of all segment headers.
-    pub fn segments(&self) -> &[SegmentHeader] {
-        self.segment_manager.segments()
+    /// Find events in finalized segments whose payload field at `path`
+    /// satisfies `predicate`.
+    ///
+    /// The field is extracted once per block and cached, so repeated filters
+    /// on the same field skip payload parsing.
+    pub fn find_by_field(
+        &self,
+        path: &FieldPath,
+        predicate: impl Fn(&FieldValue) -> bool,
+    ) -> Result<Vec<Event>> {
+        let mut matches = Vec::new();
+        for header in &self.segments {
+            let mut reader = SegmentReader::open(self.segment_path(header.segment_id))?;
+            for block in reader.read_blocks()? {
+                let id = BlockId {
+                    segment_id: header.segment_id,
+                    offset: block.offset,
+                };
+                let events = self.visible(&block.events);
+                let column = self.field_cache.column(id, &events, path);
+                for (event, value) in events.iter().zip(column.values.iter()) {
+                    if value.as_ref().is_some_and(&predicate) {
+                        matches.push(event.clone());
+                    }
+                }
+            }
+        }
+        Ok(matches)
     }
 
-    /// Read all events from all segments (used for recovery).
-    pub fn read_all_events(&self) -> Result<Vec<Event>> {
-        self.segment_manager.read_all_events()
+    /// Keep per-block zone maps for the numeric payload field at `path`, so
+    /// [`SegmentManager::find_by_field_range`] can skip blocks.
+    ///
+    /// Zone maps of finalized segments are computed by reading them once;
+    /// blocks the active segment has already written stay unskippable.
+    pub fn track_field(&mut self, path: FieldPath) -> Result<()> {
+        if self.zone_fields.contains(&path) {
+            return Ok(());
+        }
+        for index in &mut self.indexes {
+            let path_on_disk = Manifest::segment_path(&self.dir, index.segment_id());
+            let blocks = SegmentReader::open(path_on_disk)?.read_blocks()?;
+            index.track_field_from_blocks(path.clone(), &blocks);
+        }
+        if let Some(writer) = self.active.as_mut() {
+            writer.track_field(path.clone());
+        }
+        self.zone_fields.push(path);
+        Ok(())
     }
-}
 
-#[async_trait::async_trait]
-impl<W> EventJournal for SegmentedJournal<W>
-where
-    W: WriteAheadLog + Send + Sync,
-{
-    async fn append(&mut self, event: Event) -> Result<()> {
-        // 1. Write to WAL for durability.
-        self.wal.append(&event)?;
+    /// Find events whose payload field at `path` is a number within
+    /// `range`, in append order.
+    ///
+    /// Blocks whose zone map for `path` lies outside `range` are skipped
+    /// without being read.
+    pub fn find_by_field_range(
+        &self,
+        path: &FieldPath,
+        range: impl RangeBounds<f64>,
+    ) -> Result<Vec<Event>> {
+        self.scan(
+            |index| index.field_range_blocks(path, &range).collect(),
+            |event| {
+                path.extract(event)
+                    .and_then(|value| value.as_f64())
+                    .is_some_and(|value| range.contains(&value))
+            },
+        )
+    }
 
-        // 2. Append to segment files.
-        self.segment_manager.append_event(event.clone())?;
+    /// Cache of extracted payload field columns.
+    pub fn field_cache(&self) -> &FieldExtractionCache {
+        &self.field_cache
+    }
 
-        // 3. Update in-memory indexes for fast queries.
-        self.in_memory.append(event.clone()).await?;
-        self.index_event_by_type(&event);
+    /// Block cache statistics.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_cache.stats()
+    }
 
-        Ok(())
+    /// The newest finalized blocks that fit in the block cache and their
+    /// segment paths, oldest first.
+    pub fn warmup_targets(&self) -> Vec<(BlockId, PathBuf)> {
+        let mut targets = Vec::new();
+        'segments: for index in self.indexes.iter().rev() {
+            for block in index.blocks().iter().rev() {
+                if targets.len() >= self.block_cache.capacity() {
+                    break 'segments;
+                }
+                let id = BlockId {
+                    segment_id: index.segment_id(),
+                    offset: block.offset,
+                };
+                targets.push((id, self.segment_path(index.segment_id())));
+            }
+        }
+        // Loading oldest first leaves the newest blocks last to be evicted.
+        targets.reverse();
+        targets
     }
 
-    async fn append_batch(&mut self, events: Vec<Event>) -> Result<()> {
-        // For v1, just apply append() in a loop to keep behavior simple.
-        for ev in events {
-            self.append(ev).await?;
+    /// Add blocks read by the caller to the block cache.
+    pub fn warm_block_cache(&self, blocks: Vec<(BlockId, Vec<Event>)>) -> Result<usize> {
+        let count = blocks.len();
+        for (id, events) in blocks {
+            self.block_cache.get_or_load(id, || Ok(events))?;
         }
-        Ok(())
+        Ok(count)
     }
 
-    async fn get_events(
+    /// Events of `entity_id` in `[start, end)`, ordered by timestamp with
+    /// ties broken by the configured [`EventOrdering`].
+    pub fn entity_events(
         &self,
         entity_id: &str,
         start: Timestamp,
         end: Timestamp,
     ) -> Result<Vec<Event>> {
-        self.in_memory.get_events(entity_id, start, end).await
+        if !self.entities.may_contain(entity_id) {
+            return Ok(Vec::new());
+        }
+        let mut events = self.scan(
+            |index| {
+                index
+                    .entity_blocks(entity_id)
+                    .filter(|block| block.overlaps(start, end))
+                    .collect()
+            },
+            |event| {
+                event.entity_id() == entity_id
+                    && event.timestamp() >= start
+                    && event.timestamp() < end
+            },
+        )?;
+        self.ordering.sort(&mut events);
+        Ok(events)
     }
 
-    async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
-        self.in_memory.get_entity_events(entity_id).await
+    /// Events of `entity_id` recorded at or after `since`, by transaction
+    /// time, ordered like [`SegmentManager::entity_events`]; only those
+    /// are copied out of the blocks.
+    pub fn entity_events_recorded_since(
+        &self,
+        entity_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        if !self.entities.may_contain(entity_id) {
+            return Ok(Vec::new());
+        }
+        let mut events = self.scan(
+            |index| index.entity_blocks(entity_id).collect(),
+            |event| event.entity_id() == entity_id && event.metadata.transaction_time >= since,
+        )?;
+        self.ordering.sort(&mut events);
+        Ok(events)
     }
 
-    async fn get_events_by_type(
+    /// The first `limit` events of [`SegmentManager::entity_events`].
+    ///
+    /// Blocks are read in order of their earliest timestamp until `limit`
+    /// events are known to precede every unread block, so a page near the
+    /// start of a long history reads only the blocks it needs.
+    pub fn entity_events_page(
         &self,
-        event_type: &str,
+        entity_id: &str,
         start: Timestamp,
         end: Timestamp,
+        limit: usize,
     ) -> Result<Vec<Event>> {
-        let events = self
-            .events_by_type
-            .get(event_type)
-            .map(|evts| {
-                evts.iter()
-                    .filter(|e| {
-                        let ts = e.timestamp();
-                        ts >= start && ts < end
-                    })
-                    .cloned()
-                    .collect()
+        if !self.entities.may_contain(entity_id) {
+            return Ok(Vec::new());
+        }
+        let keep = |event: &&Event| {
+            event.entity_id() == entity_id && event.timestamp() >= start && event.timestamp() < end
+        };
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        let mut blocks: Vec<_> = self
+            .indexes
+            .iter()
+            .chain(active_index)
+            .flat_map(|index| {
+                index
+                    .entity_blocks(entity_id)
+                    .filter(|block| block.overlaps(start, end))
+                    .map(move |block| (index.segment_id(), block))
             })
-            .unwrap_or_default();
+            .collect();
+        blocks.sort_by_key(|(_, block)| block.min_time);
+
+        let mut events = Vec::new();
+        if let Some(writer) = &self.active {
+            events.extend(writer.buffered_events().iter().filter(keep).cloned());
+        }
+        for (i, &(segment_id, block)) in blocks.iter().enumerate() {
+            let id = BlockId {
+                segment_id,
+                offset: block.offset,
+            };
+            let loaded = self.block_cache.get_or_load(id, || {
+                SegmentReader::open(self.segment_path(segment_id))?.read_block_at(block.offset)
+            })?;
+            events.extend(self.visible(&loaded).iter().filter(keep).cloned());
+            if let Some((_, next)) = blocks.get(i + 1) {
+                let known = events.iter().filter(|e| e.timestamp() < next.min_time);
+                if known.count() >= limit {
+                    break;
+                }
+            }
+        }
+        self.ordering.sort(&mut events);
+        events.truncate(limit);
         Ok(events)
     }
 
-    async fn get_latest_event(
+    /// The first `limit` events of every entity with a timestamp in
+    /// `[start, end)` that `keep` accepts, in timestamp order, and the ones
+    /// after them sharing the last one's timestamp.
+    ///
+    /// Like [`SegmentManager::entity_events_page`], blocks are read in order
+    /// of their earliest timestamp until the page is known to precede every
+    /// unread block.
+    pub fn range_page(
         &self,
-        entity_id: &str,
-        timestamp: Timestamp,
-    ) -> Result<Option<Event>> {
-        self.in_memory.get_latest_event(entity_id, timestamp).await
+        start: Timestamp,
+        end: Timestamp,
+        keep: impl Fn(&Event) -> bool,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        let keep =
+            |event: &&Event| event.timestamp() >= start && event.timestamp() < end && keep(event);
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        let mut blocks: Vec<_> = self
+            .indexes
+            .iter()
+            .chain(active_index)
+            .flat_map(|index| {
+                index
+                    .blocks()
+                    .iter()
+                    .filter(|block| block.overlaps(start, end))
+                    .map(move |block| (index.segment_id(), block))
+            })
+            .collect();
+        blocks.sort_by_key(|(_, block)| block.min_time);
+
+        let mut events = Vec::new();
+        if let Some(writer) = &self.active {
+            events.extend(writer.buffered_events().iter().filter(keep).cloned());
+        }
+        for (i, &(segment_id, block)) in blocks.iter().enumerate() {
+            let id = BlockId {
+                segment_id,
+                offset: block.offset,
+            };
+            let loaded = self.block_cache.get_or_load(id, || {
+                SegmentReader::open(self.segment_path(segment_id))?.read_block_at(block.offset)
+            })?;
+            events.extend(self.visible(&loaded).iter().filter(keep).cloned());
+            if let Some((_, next)) = blocks.get(i + 1) {
+                let known = events.iter().filter(|e| e.timestamp() < next.min_time);
+                if known.count() >= limit {
+                    break;
+                }
+            }
+        }
+        events.sort_by_key(Event::timestamp);
+        truncate_after_ties(&mut events, limit);
+        Ok(events)
     }
 
-    async fn flush(&mut self) -> Result<()> {
-        self.wal.flush()?;
-        self.segment_manager.flush()?;
-        Ok(())
+    /// Up to `limit` events of `entity_id` after `after`, each with its
+    /// cursor, in cursor order.
+    ///
+    /// Only blocks reaching the cursor's timestamp are considered, read in
+    /// order of their earliest timestamp until `limit` events are known to
+    /// precede every unread block, so each page costs the same however far
+    /// into the history it is.
+    pub fn entity_events_after(
+        &self,
+        entity_id: &str,
+        after: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<(EventCursor, Event)>> {
+        if !self.entities.may_contain(entity_id) {
+            return Ok(Vec::new());
+        }
+        let start = after.map_or(Timestamp::from_nanos(i64::MIN), EventCursor::timestamp);
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        let mut blocks: Vec<_> = self
+            .indexes
+            .iter()
+            .chain(active_index)
+            .flat_map(|index| {
+                index
+                    .entity_blocks(entity_id)
+                    .filter(|block| block.max_time >= start)
+                    .map(move |block| (index, block))
+            })
+            .collect();
+        blocks.sort_by_key(|(_, block)| block.min_time);
+
+        // Events of the entity after the cursor, located at `first` onwards
+        // in `segment_id`
+        let locate = |segment_id: u64, first: u64, events: &[Event]| {
+            let mut located = Vec::new();
+            for (i, event) in events.iter().enumerate() {
+                let cursor = EventCursor::new(event.timestamp(), segment_id, first + i as u64);
+                if event.entity_id() == entity_id && after.is_none_or(|after| cursor > *after) {
+                    located.push((cursor, event.clone()));
+                }
+            }
+            located
+        };
+        let mut located = Vec::new();
+        if let Some(writer) = &self.active {
+            let index = writer.index();
+            let first = events_before(index, u64::MAX);
+            located = locate(index.segment_id(), first, writer.buffered_events());
+        }
+        for (i, &(index, block)) in blocks.iter().enumerate() {
+            let segment_id = index.segment_id();
+            let id = BlockId {
+                segment_id,
+                offset: block.offset,
+            };
+            let loaded = self.block_cache.get_or_load(id, || {
+                SegmentReader::open(self.segment_path(segment_id))?.read_block_at(block.offset)
+            })?;
+            let first = events_before(index, block.offset);
+            let mut block_located = locate(segment_id, first, &loaded);
+            block_located.retain(|(_, event)| !self.hides(event));
+            located.extend(block_located);
+            if let Some((_, next)) = blocks.get(i + 1) {
+                let known = located
+                    .iter()
+                    .filter(|(cursor, _)| cursor.timestamp() < next.min_time);
+                if known.count() >= limit {
+                    break;
+                }
+            }
+        }
+        located.sort_by_key(|(cursor, _)| *cursor);
+        located.truncate(limit);
+        Ok(located)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::core::event::{Event, EventPayload};
-    use crate::core::temporal::Timestamp;
-    use crate::storage::wal::InMemoryWAL;
-    use tempfile::TempDir;
+    /// Latest event of `entity_id` at or before `timestamp`; ties are broken
+    /// by the configured [`EventOrdering`].
+    pub fn latest_event(&self, entity_id: &str, timestamp: Timestamp) -> Result<Option<Event>> {
+        if !self.entities.may_contain(entity_id) {
+            return Ok(None);
+        }
+        let events = self.scan(
+            |index| {
+                index
+                    .entity_blocks(entity_id)
+                    .filter(|block| block.min_time <= timestamp)
+                    .collect()
+            },
+            |event| event.entity_id() == entity_id && event.timestamp() <= timestamp,
+        )?;
+        Ok(self.ordering.latest(events))
+    }
 
-    #[tokio::test]
-    async fn test_segmented_journal_basic() {
-        let temp_dir = TempDir::new().unwrap();
-        let segments_dir = temp_dir.path().join("segments");
-        let wal = InMemoryWAL::new();
+    /// Latest event at or before `timestamp` of each of `entity_ids` that
+    /// has one, in entity order; ties are broken by the configured
+    /// [`EventOrdering`].
+    ///
+    /// Every block holding one of the entities is read once, however many
+    /// of them it holds.
+    pub fn latest_events(&self, entity_ids: &[&str], timestamp: Timestamp) -> Result<Vec<Event>> {
+        let wanted: HashSet<&str> = entity_ids
+            .iter()
+            .copied()
+            .filter(|id| self.entities.may_contain(id))
+            .collect();
+        let events = self.scan(
+            |index| {
+                let mut blocks: Vec<&BlockSummary> = wanted
+                    .iter()
+                    .flat_map(|id| index.entity_blocks(id))
+                    .filter(|block| block.min_time <= timestamp)
+                    .collect();
+                blocks.sort_by_key(|block| block.offset);
+                blocks.dedup_by_key(|block| block.offset);
+                blocks
+            },
+            |event| wanted.contains(event.entity_id()) && event.timestamp() <= timestamp,
+        )?;
 
-        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+        let mut by_entity: BTreeMap<String, Vec<Event>> = BTreeMap::new();
+        for event in events {
+            by_entity
+                .entry(event.entity_id().to_string())
+                .or_default()
+                .push(event);
+        }
+        Ok(by_entity
+            .into_values()
+            .filter_map(|events| self.ordering.latest(events))
+            .collect())
+    }
 
-        // Append events
-        let payload1 = EventPayload::from_json(&serde_json::json!({"value": "test1"})).unwrap();
-        let event1 = Event::new(
-            "test.event".to_string(),
-            Timestamp::from_secs(1000),
-            "entity:1".to_string(),
-            payload1,
-        );
+    /// Latest event at or before `timestamp` of every cataloged entity
+    /// whose ID starts with `prefix`, in entity order.
+    pub fn latest_events_with_prefix(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let page = self.entities.page(prefix, None, usize::MAX);
+        let ids: Vec<&str> = page.entities.iter().map(String::as_str).collect();
+        self.latest_events(&ids, timestamp)
+    }
+
+    /// Events of type `event_type` in `[start, end)`, in timestamp order.
+    ///
+    /// Only blocks holding events of the type within the range are read.
+    pub fn events_by_type(
+        &self,
+        event_type: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<Event>> {
+        let mut events = self.scan(
+            |index| index.type_blocks(event_type, start, end),
+            |event| {
+                event.event_type() == event_type
+                    && event.timestamp() >= start
+                    && event.timestamp() < end
+            },
+        )?;
+        self.ordering.sort(&mut events);
+        Ok(events)
+    }
+
+    /// Events whose payload field at `path` equals `value`, in append
+    /// order, read from every block including the active segment's.
+    ///
+    /// Field values of written blocks come from the field cache, so
+    /// repeated lookups on the same field skip payload parsing.
+    pub fn scan_field(&self, path: &FieldPath, value: &FieldValue) -> Result<FieldScan> {
+        let mut scan = FieldScan::default();
+        self.visit_blocks(
+            |index| index.blocks().iter().collect(),
+            |block, events| {
+                scan.scanned += events.len() as u64;
+                let column = block.map(|id| self.field_cache.column(id, events, path));
+                for (i, event) in events.iter().enumerate() {
+                    let matched = match &column {
+                        Some(column) => column.values[i].as_ref() == Some(value),
+                        None => path.extract(event).as_ref() == Some(value),
+                    };
+                    if matched {
+                        scan.events.push(event.clone());
+                    }
+                }
+            },
+        )?;
+        Ok(scan)
+    }
+
+    /// Feed `aggregator` the numeric payload field at `path` of the events
+    /// with a timestamp in `[start, end)` on entities starting with
+    /// `prefix`, reading only the blocks that overlap the range.
+    ///
+    /// Field values of written blocks come from the field cache.
+    pub fn aggregate_field(
+        &self,
+        prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        path: &FieldPath,
+        aggregator: &mut WindowAggregator,
+        allows: impl Fn(&Event) -> bool,
+    ) -> Result<()> {
+        self.visit_blocks(
+            |index| {
+                index
+                    .blocks()
+                    .iter()
+                    .filter(|block| block.overlaps(start, end))
+                    .collect()
+            },
+            |block, events| {
+                let column = block.map(|id| self.field_cache.column(id, events, path));
+                for (i, event) in events.iter().enumerate() {
+                    let ts = event.timestamp();
+                    if ts < start || ts >= end || !event.entity_id().starts_with(prefix) {
+                        continue;
+                    }
+                    let value = match &column {
+                        Some(column) => column.values[i].as_ref().and_then(FieldValue::as_f64),
+                        None => path.extract(event).and_then(|v| v.as_f64()),
+                    };
+                    if let Some(value) = value.filter(|_| allows(event)) {
+                        aggregator.add(ts, value);
+                    }
+                }
+            },
+        )
+    }
+
+    /// Events carrying `correlation_id`, in append order.
+    pub fn events_by_correlation(&self, correlation_id: &str) -> Result<Vec<Event>> {
+        self.scan(
+            |index| index.correlation_blocks(correlation_id).collect(),
+            |event| event.metadata.correlation_id.as_deref() == Some(correlation_id),
+        )
+    }
+
+    /// Visit every event with a timestamp in `[start, end)` in timestamp
+    /// order, reading only the blocks that overlap the range. See
+    /// [`SegmentManager::visit_merged`].
+    pub async fn visit_range(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        visit: impl FnMut(&Event),
+    ) -> Result<()> {
+        self.visit_merged(
+            |index| {
+                index
+                    .blocks()
+                    .iter()
+                    .filter(|block| block.overlaps(start, end))
+                    .collect()
+            },
+            |_, events, run| {
+                let in_range = |e: &&Event| e.timestamp() >= start && e.timestamp() < end;
+                run.extend(events.iter().filter(in_range).cloned());
+            },
+            visit,
+        )
+        .await
+    }
+
+    /// Visit every event with a timestamp in `[start, end)` whose payload
+    /// satisfies all of `predicates`, in timestamp order.
+    ///
+    /// Blocks outside the range, or whose zone maps rule out a numeric
+    /// predicate, are not read; the predicates of written blocks are tested
+    /// on columns from the field cache rather than on parsed payloads.
+    pub async fn visit_range_where(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        predicates: &[FieldPredicate],
+        visit: impl FnMut(&Event),
+    ) -> Result<()> {
+        let ranges: Vec<_> = predicates
+            .iter()
+            .filter_map(|p| p.numeric_range().map(|range| (&p.path, range)))
+            .collect();
+        self.visit_merged(
+            |index| {
+                let mut blocks: Vec<_> = index
+                    .blocks()
+                    .iter()
+                    .filter(|block| block.overlaps(start, end))
+                    .collect();
+                for (path, range) in &ranges {
+                    let zoned: HashSet<u64> = index
+                        .field_range_blocks(path, range)
+                        .map(|block| block.offset)
+                        .collect();
+                    blocks.retain(|block| zoned.contains(&block.offset));
+                }
+                blocks
+            },
+            |block, events, run| {
+                let columns: Option<Vec<_>> = block.map(|id| {
+                    predicates
+                        .iter()
+                        .map(|p| self.field_cache.column(id, events, &p.path))
+                        .collect()
+                });
+                for (i, event) in events.iter().enumerate() {
+                    if event.timestamp() < start || event.timestamp() >= end {
+                        continue;
+                    }
+                    let matched = match &columns {
+                        Some(columns) => predicates
+                            .iter()
+                            .zip(columns)
+                            .all(|(p, column)| p.matches(column.values[i].as_ref())),
+                        None => predicates.iter().all(|p| p.matches_event(event)),
+                    };
+                    if matched {
+                        run.push(event.clone());
+                    }
+                }
+            },
+            visit,
+        )
+        .await
+    }
+
+    /// Collect events matching `keep` from the blocks chosen by `select` in
+    /// every segment (including blocks already written by the active one),
+    /// then from the active segment's buffer. Events are returned in append
+    /// order.
+    fn scan(
+        &self,
+        select: impl Fn(&SegmentIndex) -> Vec<&BlockSummary>,
+        keep: impl Fn(&Event) -> bool,
+    ) -> Result<Vec<Event>> {
+        let mut matches = Vec::new();
+        self.visit(select, |event| {
+            if keep(event) {
+                matches.push(event.clone());
+            }
+        })?;
+        Ok(matches)
+    }
+
+    /// Pass every event in the blocks chosen by `select`, and in the active
+    /// segment's buffer, to `visit`.
+    fn visit(
+        &self,
+        select: impl Fn(&SegmentIndex) -> Vec<&BlockSummary>,
+        mut visit: impl FnMut(&Event),
+    ) -> Result<()> {
+        self.visit_blocks(select, |_, events| events.iter().for_each(&mut visit))
+    }
+
+    /// Pass the events of every block chosen by `select` to `visit` with
+    /// the block's ID, then the active segment's buffer without one.
+    fn visit_blocks(
+        &self,
+        select: impl Fn(&SegmentIndex) -> Vec<&BlockSummary>,
+        mut visit: impl FnMut(Option<BlockId>, &[Event]),
+    ) -> Result<()> {
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        for index in self.indexes.iter().chain(active_index) {
+            let blocks = select(index);
+            if blocks.is_empty() {
+                continue;
+            }
+            let path = self.segment_path(index.segment_id());
+            let mut reader: Option<SegmentReader> = None;
+            for block in blocks {
+                let id = BlockId {
+                    segment_id: index.segment_id(),
+                    offset: block.offset,
+                };
+                let events = self.block_cache.get_or_load(id, || {
+                    let reader = match reader.as_mut() {
+                        Some(reader) => reader,
+                        None => reader.insert(SegmentReader::open(&path)?),
+                    };
+                    reader.read_block_at(block.offset)
+                })?;
+                visit(Some(id), &self.visible(&events));
+            }
+        }
+
+        if let Some(writer) = &self.active {
+            visit(None, writer.buffered_events());
+        }
+        Ok(())
+    }
+
+    /// Visit the events `keep` takes from the blocks chosen by `select`,
+    /// then from the active segment's buffer, in timestamp order with ties
+    /// in append order.
+    ///
+    /// The chosen blocks are read on the blocking pool, where up to
+    /// `scan_parallelism` workers take one segment at a time and read and
+    /// checksum its blocks. What `keep` takes from each segment is sorted
+    /// into a run, and the runs are then merged.
+    async fn visit_merged(
+        &self,
+        select: impl Fn(&SegmentIndex) -> Vec<&BlockSummary>,
+        keep: impl Fn(Option<BlockId>, &[Event], &mut Vec<Event>),
+        mut visit: impl FnMut(&Event),
+    ) -> Result<()> {
+        let active_index = self.active.as_ref().map(SegmentWriter::index);
+        let scans: Vec<SegmentScan> = self
+            .indexes
+            .iter()
+            .chain(active_index)
+            .map(|index| SegmentScan {
+                path: self.segment_path(index.segment_id()),
+                blocks: select(index)
+                    .into_iter()
+                    .map(|block| BlockId {
+                        segment_id: index.segment_id(),
+                        offset: block.offset,
+                    })
+                    .collect(),
+            })
+            .filter(|scan| !scan.blocks.is_empty())
+            .collect();
+        let segments = if scans.is_empty() {
+            Vec::new()
+        } else {
+            let cache = self.block_cache.clone();
+            let workers = self.scan_parallelism;
+            tokio::task::spawn_blocking(move || read_segments(&cache, &scans, workers))
+                .await
+                .map_err(|e| Error::Storage(format!("Segment scan failed: {}", e)))??
+        };
+        let mut runs: Vec<Vec<Event>> = segments
+            .into_iter()
+            .map(|blocks| {
+                let mut run = Vec::new();
+                for (id, events) in blocks {
+                    keep(Some(id), &self.visible(&events), &mut run);
+                }
+                run.sort_by_key(Event::timestamp);
+                run
+            })
+            .collect();
+        if let Some(writer) = &self.active {
+            let mut run = Vec::new();
+            keep(None, writer.buffered_events(), &mut run);
+            run.sort_by_key(Event::timestamp);
+            runs.push(run);
+        }
+
+        let mut heads: BinaryHeap<_> = runs
+            .iter()
+            .enumerate()
+            .filter_map(|(r, run)| Some(Reverse((run.first()?.timestamp(), r, 0))))
+            .collect();
+        while let Some(Reverse((_, r, i))) = heads.pop() {
+            visit(&runs[r][i]);
+            if let Some(next) = runs[r].get(i + 1) {
+                heads.push(Reverse((next.timestamp(), r, i + 1)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Verify the finalized segments in `dir` without opening a journal on it,
+/// e.g. because a damaged segment keeps it from opening. See
+/// [`SegmentManager::verify_all`].
+pub fn verify_segments<P: AsRef<Path>>(dir: P, repair: bool) -> Result<VerifyReport> {
+    let dir = dir.as_ref();
+    let _lock = DirLock::exclusive(dir)?;
+    let mut report = VerifyReport::default();
+    let Some(mut manifest) = Manifest::load(dir)? else {
+        return Ok(report);
+    };
+
+    let mut repaired = false;
+    for header in &mut manifest.segments {
+        let path = Manifest::segment_path(dir, header.segment_id);
+        let (check, rewritten) = check_segment(&path, header, repair, SEGMENT_VERSION, &[])?;
+        if let Some((rewritten, _)) = rewritten {
+            manifest.expired_events += check.lost_events;
+            *header = rewritten;
+            repaired = true;
+        }
+        report.segments.push(check);
+    }
+    if repaired {
+        manifest.store(dir)?;
+    }
+    Ok(report)
+}
+
+/// Verify the segment file of `header`, rewriting it without its
+/// unreadable blocks if it is damaged and `repair` is set.
+fn check_segment(
+    path: &Path,
+    header: &SegmentHeader,
+    repair: bool,
+    version: u8,
+    zone_fields: &[FieldPath],
+) -> Result<(SegmentCheck, Option<(SegmentHeader, SegmentIndex)>)> {
+    let verification = match SegmentReader::open(path) {
+        Ok(mut reader) => reader.verify_blocks()?,
+        Err(e) => BlockVerification {
+            blocks: Vec::new(),
+            faults: vec![BlockFault {
+                offset: 0,
+                error: e.to_string(),
+            }],
+            checksum_ok: false,
+        },
+    };
+    let events = verification.event_count();
+    let mut check = SegmentCheck {
+        segment_id: header.segment_id,
+        blocks: verification.blocks.len(),
+        events,
+        checksum_ok: verification.checksum_ok,
+        lost_events: (header.event_count as u64).saturating_sub(events),
+        repaired: false,
+        faults: verification.faults,
+    };
+    if !repair || check.is_healthy() {
+        return Ok((check, None));
+    }
+
+    let events = verification.blocks.into_iter().flat_map(|b| b.events);
+    let rewritten = rewrite_segment(path, header, events, version, zone_fields)?;
+    check.repaired = true;
+    Ok((check, Some(rewritten)))
+}
+
+/// Replace the segment file of `old` with one holding `events` in format
+/// `version`, via a temporary file so a crash leaves either file.
+fn rewrite_segment(
+    path: &Path,
+    old: &SegmentHeader,
+    events: impl IntoIterator<Item = Event>,
+    version: u8,
+    zone_fields: &[FieldPath],
+) -> Result<(SegmentHeader, SegmentIndex)> {
+    let tmp_path = path.with_extension(MIGRATION_EXTENSION);
+    let mut writer =
+        SegmentWriter::create(&tmp_path, old.segment_id, old.start_time, old.end_time)?
+            .with_format_version(version)?;
+    for field in zone_fields {
+        writer.track_field(field.clone());
+    }
+    for event in events {
+        writer.append(event)?;
+    }
+    let rewritten = writer.finalize_with_index()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(rewritten)
+}
+
+/// Whether `path` is a segment rewrite left behind by `migrate_segments`.
+fn is_migration_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(&format!(".{MIGRATION_EXTENSION}")))
+}
+
+/// Segment ID encoded in a segment file name, if `path` names one.
+/// Number of events in the blocks of `index` written before `offset`, i.e.
+/// the position in the segment of the first event of the block there
+fn events_before(index: &SegmentIndex, offset: u64) -> u64 {
+    index
+        .blocks()
+        .iter()
+        .take_while(|block| block.offset < offset)
+        .map(|block| u64::from(block.event_count))
+        .sum()
+}
+
+fn segment_file_id(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("segment-")?
+        .strip_suffix(".seg")?
+        .parse()
+        .ok()
+}
+
+/// Decoded blocks of one segment, in the order they were chosen
+type SegmentBlocks = Vec<(BlockId, Arc<Vec<Event>>)>;
+
+/// Blocks of one segment a range scan reads
+struct SegmentScan {
+    path: PathBuf,
+    blocks: Vec<BlockId>,
+}
+
+/// Read the blocks of every scan through `cache`, with up to `workers`
+/// threads each taking one segment at a time; the blocks come back grouped
+/// by segment, in the order of `scans`
+fn read_segments(
+    cache: &BlockCache,
+    scans: &[SegmentScan],
+    workers: usize,
+) -> Result<Vec<SegmentBlocks>> {
+    let read_segment = |scan: &SegmentScan| {
+        let mut reader: Option<SegmentReader> = None;
+        scan.blocks
+            .iter()
+            .map(|&id| {
+                let events = cache.get_or_load(id, || {
+                    let reader = match reader.as_mut() {
+                        Some(reader) => reader,
+                        None => reader.insert(SegmentReader::open(&scan.path)?),
+                    };
+                    reader.read_block_at(id.offset)
+                })?;
+                Ok((id, events))
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+
+    let workers = workers.min(scans.len());
+    if workers <= 1 {
+        return scans.iter().map(read_segment).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let worker = || {
+        let mut read = Vec::new();
+        let mut i = next.fetch_add(1, Ordering::Relaxed);
+        while let Some(scan) = scans.get(i) {
+            read.push((i, read_segment(scan)));
+            i = next.fetch_add(1, Ordering::Relaxed);
+        }
+        read
+    };
+    let mut read = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers).map(|_| scope.spawn(worker)).collect();
+        let mut read = Vec::new();
+        for handle in handles {
+            let segments = handle
+                .join()
+                .map_err(|_| Error::Storage("Segment scan worker panicked".to_string()))?;
+            read.extend(segments);
+        }
+        Ok::<_, Error>(read)
+    })?;
+    // Back in segment order, so ties keep their append order.
+    read.sort_by_key(|(i, _)| *i);
+    read.into_iter().map(|(_, blocks)| blocks).collect()
+}
+
+/// Disk-backed implementation of `EventJournal` using a WAL and segment files.
+///
+/// Queries read the segment files through their block indexes; no copy of
+/// the event history is kept in memory.
+pub struct SegmentedJournal<W: AsyncWriteAheadLog> {
+    wal: W,
+    segment_manager: SegmentManager,
+    /// Ships the WAL to an object store before checkpoints truncate it.
+    archiver: Option<WalArchiver>,
+    /// Indexes maintained on append.
+    indexes: IndexRegistry,
+}
+
+impl<W: AsyncWriteAheadLog> SegmentedJournal<W> {
+    /// Create a new segmented journal rooted at `dir` using the provided WAL.
+    pub fn new<P: AsRef<Path>>(dir: P, wal: W) -> Result<Self> {
+        let segment_manager = SegmentManager::new(dir)?;
+        Ok(Self {
+            wal,
+            segment_manager,
+            archiver: None,
+            indexes: IndexRegistry::new(),
+        })
+    }
+
+    /// Open an existing journal rooted at `dir`, recovering its state.
+    ///
+    /// The segment catalog is restored from the manifest, then WAL records
+    /// that are not yet in a cataloged segment are appended again. WAL LSNs
+    /// match append positions, so records at or below the cataloged event
+    /// count are skipped; this also covers a crash between a checkpoint's
+    /// manifest write and the WAL truncation. An empty `dir` yields an empty
+    /// journal.
+    ///
+    /// Records are streamed, so recovering a large WAL does not hold it in
+    /// memory. Records of a baseline WAL carry no LSNs and are numbered
+    /// after the cataloged position, so the numbering is settled before
+    /// replay.
+    pub async fn open<P: AsRef<Path>>(dir: P, mut wal: W) -> Result<Self> {
+        let mut segment_manager = SegmentManager::open(dir)?;
+        let cataloged = segment_manager.cataloged_event_count();
+        wal.advance_lsn(cataloged);
+        let mut records = wal.replay_record_stream().await?;
+        while let Some((lsn, event)) = records.next().await.transpose()? {
+            if lsn > cataloged {
+                segment_manager.append_event(event)?;
+            }
+        }
+        Ok(Self {
+            wal,
+            segment_manager,
+            archiver: None,
+            indexes: IndexRegistry::new(),
+        })
+    }
+
+    /// Order same-timestamp events by `ordering` in query results.
+    pub fn with_ordering(mut self, ordering: EventOrdering) -> Self {
+        self.segment_manager.set_ordering(ordering);
+        self
+    }
+
+    /// Keep at most `blocks` decoded segment blocks in memory.
+    pub fn with_block_cache_capacity(mut self, blocks: usize) -> Self {
+        self.segment_manager.set_block_cache_capacity(blocks);
+        self
+    }
+
+    /// Read up to `segments` segments concurrently in range scans.
+    pub fn with_scan_parallelism(mut self, segments: usize) -> Self {
+        self.segment_manager.set_scan_parallelism(segments);
+        self
+    }
+
+    /// Archive the WAL before each checkpoint truncates it.
+    ///
+    /// Only WALs backed by a single file (see
+    /// [`AsyncWriteAheadLog::file_path`]) can be archived; for others the
+    /// archiver is never invoked.
+    pub fn with_archiver(mut self, archiver: WalArchiver) -> Self {
+        self.archiver = Some(archiver);
+        self
+    }
+
+    /// Get list of all segment headers.
+    pub fn segments(&self) -> &[SegmentHeader] {
+        self.segment_manager.segments()
+    }
+
+    /// Read all events from all segments (used for recovery).
+    pub fn read_all_events(&self) -> Result<Vec<Event>> {
+        self.segment_manager.read_all_events()
+    }
+
+    /// Every event in the journal, in append order.
+    pub fn all_events(&self) -> Result<Vec<Event>> {
+        self.segment_manager.all_events()
+    }
+
+    /// Append position of the last event, see
+    /// [`SegmentManager::end_position`].
+    pub fn end_position(&self) -> Lsn {
+        self.segment_manager.end_position()
+    }
+
+    /// Pass every event positioned after `lsn` to `visit` with its
+    /// position, see [`SegmentManager::visit_events_after`].
+    pub fn visit_events_after(
+        &self,
+        lsn: Lsn,
+        visit: impl FnMut(Lsn, &Event) -> ControlFlow<()>,
+    ) -> Result<()> {
+        self.segment_manager.visit_events_after(lsn, visit)
+    }
+
+    /// Find flushed events whose payload field at `path` satisfies `predicate`.
+    pub fn find_by_field(
+        &self,
+        path: &FieldPath,
+        predicate: impl Fn(&FieldValue) -> bool,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.find_by_field(path, predicate)
+    }
+
+    /// Keep per-block zone maps for the numeric payload field at `path`.
+    /// See [`SegmentManager::track_field`].
+    pub fn track_field(&mut self, path: FieldPath) -> Result<()> {
+        self.segment_manager.track_field(path)
+    }
+
+    /// Find events whose numeric payload field at `path` lies in `range`.
+    pub fn find_by_field_range(
+        &self,
+        path: &FieldPath,
+        range: impl RangeBounds<f64>,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.find_by_field_range(path, range)
+    }
+
+    /// Rewrite segments in older format versions. See
+    /// [`SegmentManager::migrate_segments`].
+    pub fn migrate_segments(&mut self) -> Result<Vec<u64>> {
+        self.segment_manager.migrate_segments()
+    }
+
+    /// Write new segments in format `version`. See
+    /// [`SegmentManager::set_write_version`].
+    pub fn set_segment_write_version(&mut self, version: u8) -> Result<()> {
+        self.segment_manager.set_write_version(version)
+    }
+
+    /// Verify (and optionally repair) finalized segments. See
+    /// [`SegmentManager::verify_all`].
+    pub fn verify_all(&mut self, repair: bool) -> Result<VerifyReport> {
+        self.segment_manager.verify_all(repair)
+    }
+
+    /// Block cache statistics.
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.segment_manager.block_cache_stats()
+    }
+
+    /// Access the underlying write-ahead log.
+    pub fn wal(&self) -> &W {
+        &self.wal
+    }
+
+    /// Checkpoint the journal and truncate the WAL.
+    ///
+    /// The active segment is finalized and the manifest (including the new
+    /// checkpoint record) is persisted *before* the WAL is cleared, so a crash
+    /// at any point leaves every event either in the WAL or in a cataloged
+    /// segment. With an archiver configured, the WAL is also uploaded before
+    /// it is cleared.
+    pub async fn checkpoint(&mut self) -> Result<CheckpointRecord> {
+        self.wal.flush().await?;
+        if let (Some(archiver), Some(path)) = (&self.archiver, self.wal.file_path()) {
+            archiver.archive_file(path).await?;
+        }
+        let record = self.segment_manager.checkpoint(self.wal.last_lsn())?;
+        self.wal.clear().await?;
+        Ok(record)
+    }
+
+    /// Latest checkpoint, if one has been taken.
+    pub fn last_checkpoint(&self) -> Option<&CheckpointRecord> {
+        self.segment_manager.last_checkpoint()
+    }
+}
+
+impl SegmentedJournal<InMemoryWAL> {
+    /// Open the journal rooted at `dir` read-only, see
+    /// [`SegmentManager::open_read_only`].
+    ///
+    /// The WAL is never opened, so only events in finalized segments are
+    /// read; appends fail.
+    pub fn open_read_only<P: AsRef<Path>>(dir: P, as_of: Option<Timestamp>) -> Result<Self> {
+        Ok(Self {
+            wal: InMemoryWAL::new(),
+            segment_manager: SegmentManager::open_read_only(dir, as_of)?,
+            archiver: None,
+            indexes: IndexRegistry::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<W> EventJournal for SegmentedJournal<W>
+where
+    W: AsyncWriteAheadLog,
+{
+    async fn append(&mut self, event: Event) -> Result<()> {
+        self.segment_manager.ensure_writable()?;
+        // 1. Write to WAL for durability.
+        self.wal.append(&event).await?;
+
+        // 2. Append to segment files; the active segment's buffer and block
+        //    index make the event visible to queries.
+        if self.indexes.is_empty() {
+            return self.segment_manager.append_event(event);
+        }
+        self.segment_manager.append_event(event.clone())?;
+
+        // 3. Bring registered indexes up to date.
+        self.indexes.on_append(&event);
+
+        Ok(())
+    }
+
+    async fn append_batch(&mut self, events: Vec<Event>) -> Result<()> {
+        self.segment_manager.ensure_writable()?;
+        // The batch is logged in one write, so it is recovered whole or
+        // not at all, then appended to segments and indexed together.
+        self.wal.append_batch(&events).await?;
+        let indexed = (!self.indexes.is_empty()).then(|| events.clone());
+        for ev in events {
+            self.segment_manager.append_event(ev)?;
+        }
+        if let Some(events) = indexed {
+            self.indexes.on_append_batch(&events);
+        }
+        Ok(())
+    }
+
+    async fn get_events(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.entity_events(entity_id, start, end)
+    }
+
+    async fn get_entity_events(&self, entity_id: &str) -> Result<Vec<Event>> {
+        self.segment_manager.entity_events(
+            entity_id,
+            Timestamp::from_nanos(i64::MIN),
+            Timestamp::from_nanos(i64::MAX),
+        )
+    }
+
+    async fn get_entity_events_recorded_since(
+        &self,
+        entity_id: &str,
+        since: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager
+            .entity_events_recorded_since(entity_id, since)
+    }
+
+    async fn events_after(&self, lsn: Lsn, limit: usize) -> Result<Vec<(Lsn, Event)>> {
+        let mut events = Vec::new();
+        if limit > 0 {
+            self.segment_manager.visit_events_after(lsn, |lsn, event| {
+                events.push((lsn, event.clone()));
+                if events.len() < limit {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            })?;
+        }
+        Ok(events)
+    }
+
+    async fn get_events_page(
+        &self,
+        entity_id: &str,
+        start: Timestamp,
+        end: Timestamp,
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager
+            .entity_events_page(entity_id, start, end, limit)
+    }
+
+    async fn get_events_after(
+        &self,
+        entity_id: &str,
+        after: Option<&EventCursor>,
+        limit: usize,
+    ) -> Result<Vec<(EventCursor, Event)>> {
+        self.segment_manager
+            .entity_events_after(entity_id, after, limit)
+    }
+
+    async fn get_events_by_type(
+        &self,
+        event_type: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.events_by_type(event_type, start, end)
+    }
+
+    async fn get_latest_event(
+        &self,
+        entity_id: &str,
+        timestamp: Timestamp,
+    ) -> Result<Option<Event>> {
+        self.segment_manager.latest_event(entity_id, timestamp)
+    }
+
+    async fn get_latest_events(
+        &self,
+        entity_ids: &[&str],
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.latest_events(entity_ids, timestamp)
+    }
+
+    async fn get_latest_events_with_prefix(
+        &self,
+        prefix: &str,
+        timestamp: Timestamp,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager
+            .latest_events_with_prefix(prefix, timestamp)
+    }
+
+    async fn get_events_by_correlation(&self, correlation_id: &str) -> Result<Vec<Event>> {
+        self.segment_manager.events_by_correlation(correlation_id)
+    }
+
+    async fn scan_field(&self, path: &FieldPath, value: &FieldValue) -> Result<FieldScan> {
+        self.segment_manager.scan_field(path, value)
+    }
+
+    async fn scan_range(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        visit: &mut (dyn for<'e> FnMut(&'e Event) + Send),
+    ) -> Result<()> {
+        self.segment_manager.visit_range(start, end, visit).await
+    }
+
+    async fn scan_range_page(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        keep: &(dyn for<'e> Fn(&'e Event) -> bool + Sync),
+        limit: usize,
+    ) -> Result<Vec<Event>> {
+        self.segment_manager.range_page(start, end, keep, limit)
+    }
+
+    async fn scan_range_where(
+        &self,
+        start: Timestamp,
+        end: Timestamp,
+        predicates: &[FieldPredicate],
+        visit: &mut (dyn for<'e> FnMut(&'e Event) + Send),
+    ) -> Result<()> {
+        self.segment_manager
+            .visit_range_where(start, end, predicates, visit)
+            .await
+    }
+
+    async fn list_entities(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<EntityPage> {
+        Ok(self.segment_manager.list_entities(prefix, cursor, limit))
+    }
+
+    fn register_index(&mut self, index: SharedIndex) {
+        self.indexes.register(index);
+    }
+
+    fn may_contain_entity(&self, entity_id: &str) -> bool {
+        self.segment_manager.may_contain_entity(entity_id)
+    }
+
+    async fn aggregate_field(
+        &self,
+        prefix: &str,
+        start: Timestamp,
+        end: Timestamp,
+        path: &FieldPath,
+        aggregator: &mut WindowAggregator,
+        allows: &(dyn for<'e> Fn(&'e Event) -> bool + Send + Sync),
+    ) -> Result<()> {
+        self.segment_manager
+            .aggregate_field(prefix, start, end, path, aggregator, allows)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.wal.flush().await?;
+        self.segment_manager.flush()?;
+        Ok(())
+    }
+
+    async fn enforce_retention(
+        &mut self,
+        policy: &RetentionPolicy,
+        now: Timestamp,
+    ) -> Result<RetentionReport> {
+        let count = self.segment_manager.expired_segment_count(policy, now);
+        let mut report = RetentionReport::default();
+        if let Some(store) = &policy.archive {
+            for header in &self.segment_manager.segments()[..count] {
+                let path = self.segment_manager.segment_path(header.segment_id);
+                let key = format!(
+                    "{}/{}",
+                    EXPIRED_SEGMENT_PREFIX,
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                store.put(&key, tokio::fs::read(&path).await?).await?;
+                report.archived.push(key);
+            }
+        }
+
+        for header in self.segment_manager.drop_oldest_segments(count)? {
+            report.segments.push(header.segment_id);
+            report.events += header.event_count as u64;
+            report.bytes += HEADER_SIZE as u64 + header.compressed_size as u64;
+        }
+        Ok(report)
+    }
+
+    async fn scrub(&self, policy: &ScrubPolicy) -> Result<ScrubReport> {
+        let targets = self.segment_manager.scrub_targets(policy.segments_per_pass);
+        let version = self.segment_manager.write_version();
+        let checks = tokio::task::spawn_blocking(move || {
+            targets
+                .iter()
+                .map(|(header, path)| {
+                    check_segment(path, header, false, version, &[]).map(|(check, _)| check)
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .await
+        .map_err(|e| Error::Storage(format!("Scrub task failed: {}", e)))??;
+
+        let mut report = ScrubReport::default();
+        for check in checks {
+            report.checked.push(check.segment_id);
+            if !check.is_healthy() {
+                tracing::warn!(
+                    "Scrub found segment {} damaged: {} bad blocks",
+                    check.segment_id,
+                    check.faults.len()
+                );
+                report.damaged.push(check);
+            }
+        }
+        Ok(report)
+    }
+
+    async fn repair(&mut self, report: &mut ScrubReport, policy: &ScrubPolicy) -> Result<()> {
+        // Retention may have removed a segment since it was verified.
+        let segments = self.segment_manager.segments();
+        report
+            .damaged
+            .retain(|check| segments.iter().any(|h| h.segment_id == check.segment_id));
+        for check in &report.damaged {
+            let segment_id = check.segment_id;
+            let mut repaired = false;
+            if let Some(store) = &policy.replica {
+                let path = self.segment_manager.segment_path(segment_id);
+                let key = format!(
+                    "{}/{}",
+                    REPLICA_SEGMENT_PREFIX,
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                if let Some(data) = store.get(&key).await? {
+                    repaired = self.segment_manager.restore_segment(segment_id, &data)?;
+                }
+            }
+            if repaired {
+                report.repaired.push(segment_id);
+            } else {
+                report.flagged.push(segment_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn pin_write_versions(&mut self, segment: u8, wal: u32) -> Result<()> {
+        self.segment_manager.set_write_version(segment)?;
+        self.wal.set_write_version(wal).await
+    }
+
+    async fn warm_up(&self) -> Result<usize> {
+        let targets = self.segment_manager.warmup_targets();
+        let blocks = tokio::task::spawn_blocking(move || {
+            let mut current: Option<(PathBuf, SegmentReader)> = None;
+            let mut blocks = Vec::with_capacity(targets.len());
+            for (id, path) in targets {
+                if current.as_ref().is_none_or(|(open, _)| *open != path) {
+                    current = Some((path.clone(), SegmentReader::open(&path)?));
+                }
+                let (_, reader) = current.as_mut().expect("segment reader is open");
+                blocks.push((id, reader.read_block_at(id.offset)?));
+            }
+            Ok::<_, Error>(blocks)
+        })
+        .await
+        .map_err(|e| Error::Storage(format!("Warmup task failed: {}", e)))??;
+        self.segment_manager.warm_block_cache(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::{Event, EventPayload};
+    use crate::core::temporal::Timestamp;
+    use crate::storage::async_wal::AsyncFileWAL;
+    use crate::storage::wal::{InMemoryWAL, RecordCodec, MIN_WAL_VERSION, WAL_VERSION};
+    use std::ops::Bound;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_segmented_journal_basic() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal = InMemoryWAL::new();
+
+        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+
+        // Append events
+        let payload1 = EventPayload::from_json(&serde_json::json!({"value": "test1"})).unwrap();
+        let event1 = Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(1000),
+            "entity:1".to_string(),
+            payload1,
+        );
 
         let payload2 = EventPayload::from_json(&serde_json::json!({"value": "test2"})).unwrap();
         let event2 = Event::new(
@@ -270,79 +2261,919 @@ mod tests {
             payload2,
         );
 
-        journal.append(event1.clone()).await.unwrap();
-        journal.append(event2.clone()).await.unwrap();
+        journal.append(event1.clone()).await.unwrap();
+        journal.append(event2.clone()).await.unwrap();
+        journal.flush().await.unwrap();
+
+        // Query events
+        let events = journal
+            .get_events(
+                "entity:1",
+                Timestamp::from_secs(1000),
+                Timestamp::from_secs(2000),
+            )
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 2);
+
+        // Verify segments were created and finalized
+        let segments = journal.segment_manager.segments();
+        assert!(
+            !segments.is_empty(),
+            "At least one segment should be created"
+        );
+        // After flush, segment should be finalized and compressed
+        // Note: compression happens when buffer is flushed (at 1000 events or on finalize)
+        let segment = &segments[0];
+        assert_eq!(segment.event_count, 2);
+        // Even with 2 events, finalize() should compress the buffer
+        assert_ne!(
+            segment.flags & crate::storage::segment_file::FLAG_COMPRESSED,
+            0,
+            "Segment should be compressed after finalize"
+        );
+        assert_ne!(segment.checksum, 0, "Checksum should be calculated");
+    }
+
+    #[tokio::test]
+    async fn test_segmented_journal_with_compression() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal = InMemoryWAL::new();
+
+        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+
+        // Add many events to trigger compression
+        // Need at least 1000 events to trigger automatic flush_buffer, or rely on finalize()
+        for i in 0..100 {
+            let payload = EventPayload::from_json(&serde_json::json!({
+                "index": i,
+                "data": "repetitive data".repeat(20)
+            }))
+            .unwrap();
+            let event = Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i as i64),
+                format!("entity:{}", i % 10),
+                payload,
+            );
+            journal.append(event).await.unwrap();
+        }
+        journal.flush().await.unwrap();
+
+        // Verify compression
+        let segments = journal.segment_manager.segments();
+        assert!(
+            !segments.is_empty(),
+            "At least one segment should be created"
+        );
+
+        // After flush(), segment should be finalized which triggers compression
+        for segment in segments {
+            // finalize() should compress even small buffers
+            assert_ne!(
+                segment.flags & crate::storage::segment_file::FLAG_COMPRESSED,
+                0,
+                "Segment should be compressed after finalize (flags={})",
+                segment.flags
+            );
+            assert_ne!(segment.checksum, 0, "Checksum should be set");
+        }
+
+        // Verify we can read all events back
+        let all_events = journal.segment_manager.read_all_events().unwrap();
+        assert_eq!(all_events.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_find_by_field_uses_cache() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..10 {
+            let payload = EventPayload::from_json(&serde_json::json!({"amount": i * 100})).unwrap();
+            let event = Event::new(
+                "payment.made".to_string(),
+                Timestamp::from_secs(1000 + i),
+                format!("account:{}", i % 3),
+                payload,
+            );
+            journal.append(event).await.unwrap();
+        }
+        journal.flush().await.unwrap();
+
+        let path = FieldPath::parse("amount").unwrap();
+        let large = |v: &FieldValue| v.as_f64().is_some_and(|a| a > 500.0);
+        assert_eq!(journal.find_by_field(&path, large).unwrap().len(), 4);
+        assert_eq!(journal.find_by_field(&path, large).unwrap().len(), 4);
+
+        let stats = journal.segment_manager.field_cache().stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 1);
+
+        // Equality scans share the cached column.
+        let scan = journal
+            .scan_field(&path, &FieldValue::Int(900))
+            .await
+            .unwrap();
+        assert_eq!((scan.events.len(), scan.scanned), (1, 10));
+        assert_eq!(journal.segment_manager.field_cache().stats().hits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_entity_events_recorded_since() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..6 {
+            let payload = EventPayload::from_json(&i).unwrap();
+            let mut event = Event::new(
+                "value.changed".to_string(),
+                Timestamp::from_secs(100 - i),
+                "account:1".to_string(),
+                payload,
+            );
+            event.metadata.transaction_time = Timestamp::from_secs(1000 + i);
+            journal.append(event).await.unwrap();
+            if i == 2 {
+                journal.flush().await.unwrap();
+            }
+        }
+
+        let since = Timestamp::from_secs(1002);
+        let recorded = journal
+            .get_entity_events_recorded_since("account:1", since)
+            .await
+            .unwrap();
+        let times: Vec<i64> = recorded.iter().map(|e| e.timestamp().as_secs()).collect();
+        assert_eq!(times, [95, 96, 97, 98]);
+        let none = journal
+            .get_entity_events_recorded_since("account:2", since)
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_field_range_skips_blocks_by_zone_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..3000 {
+            let payload = EventPayload::from_json(&serde_json::json!({"amount": i})).unwrap();
+            let event = Event::new(
+                "payment.made".to_string(),
+                Timestamp::from_secs(1000 + i),
+                "account:1".to_string(),
+                payload,
+            );
+            journal.append(event).await.unwrap();
+        }
+        journal.flush().await.unwrap();
+
+        let path = FieldPath::parse("amount").unwrap();
+        journal.track_field(path.clone()).unwrap();
+        let large = journal
+            .find_by_field_range(&path, (Bound::Excluded(2500.0), Bound::Unbounded))
+            .unwrap();
+        assert_eq!(large.len(), 499);
+        // Only the last of the three blocks was decoded.
+        assert_eq!(journal.block_cache_stats().misses, 1);
+    }
+
+    #[tokio::test]
+    async fn test_scan_pushes_payload_predicates_down() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..3000 {
+            let status = if i % 3 == 0 { "active" } else { "closed" };
+            let json = serde_json::json!({"amount": i, "status": status});
+            let payload = EventPayload::from_json(&json).unwrap();
+            let ts = Timestamp::from_secs(1000 + i);
+            let account = "account:1".to_string();
+            let event = Event::new("account.updated".to_string(), ts, account, payload);
+            journal.append(event).await.unwrap();
+        }
         journal.flush().await.unwrap();
+        let amount = FieldPath::parse("amount").unwrap();
+        journal.track_field(amount).unwrap();
 
-        // Query events
-        let events = journal
+        let predicates = [
+            FieldPredicate::parse("payload->>'amount' >= 2400").unwrap(),
+            FieldPredicate::parse("payload->>'status' = 'active'").unwrap(),
+        ];
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(10_000));
+        let mut matched = 0;
+        journal
+            .scan_range_where(start, end, &predicates, &mut |_| matched += 1)
+            .await
+            .unwrap();
+        assert_eq!(matched, 200);
+        // The zone map ruled out the first two blocks; the last one was
+        // filtered on cached columns of both fields.
+        assert_eq!(journal.block_cache_stats().misses, 1);
+        assert_eq!(journal.segment_manager.field_cache().stats().misses, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reads_served_from_segment_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new())
+                .unwrap()
+                .with_block_cache_capacity(2);
+
+        // 2500 events: two blocks written to the active segment, 500 buffered.
+        for i in 0..2500 {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            let event = Event::new(
+                if i % 100 == 0 { "milestone" } else { "tick" }.to_string(),
+                Timestamp::from_secs(1000 + i),
+                format!("entity:{}", i % 5),
+                payload,
+            );
+            journal.append(event).await.unwrap();
+        }
+
+        let events = journal.get_entity_events("entity:3").await.unwrap();
+        assert_eq!(events.len(), 500);
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].timestamp() <= w[1].timestamp()));
+
+        // Only the second block overlaps this range.
+        let before = journal.block_cache_stats();
+        let ranged = journal
             .get_events(
-                "entity:1",
-                Timestamp::from_secs(1000),
+                "entity:0",
                 Timestamp::from_secs(2000),
+                Timestamp::from_secs(2010),
             )
             .await
             .unwrap();
-        assert_eq!(events.len(), 2);
+        assert_eq!(ranged.len(), 2);
+        assert_eq!(journal.block_cache_stats().hits, before.hits + 1);
 
-        // Verify segments were created and finalized
-        let segments = journal.segment_manager.segments();
-        assert!(!segments.is_empty(), "At least one segment should be created");
-        // After flush, segment should be finalized and compressed
-        // Note: compression happens when buffer is flushed (at 1000 events or on finalize)
-        let segment = &segments[0];
-        assert_eq!(segment.event_count, 2);
-        // Even with 2 events, finalize() should compress the buffer
-        assert_ne!(segment.flags & crate::storage::segment_file::FLAG_COMPRESSED, 0,
-            "Segment should be compressed after finalize");
-        assert_ne!(segment.checksum, 0, "Checksum should be calculated");
+        let milestones = journal
+            .get_events_by_type(
+                "milestone",
+                Timestamp::from_secs(0),
+                Timestamp::from_secs(10_000),
+            )
+            .await
+            .unwrap();
+        assert_eq!(milestones.len(), 25);
+
+        let latest = journal
+            .get_latest_event("entity:1", Timestamp::from_secs(3100))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.timestamp(), Timestamp::from_secs(3096));
+
+        // Results are unchanged once everything is in finalized segments.
+        journal.flush().await.unwrap();
+        assert_eq!(
+            journal.get_entity_events("entity:3").await.unwrap().len(),
+            500
+        );
+        assert!(journal.block_cache_stats().blocks <= 2);
     }
 
     #[tokio::test]
-    async fn test_segmented_journal_with_compression() {
+    async fn test_entity_page_reads_leading_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..2500 {
+            let payload = EventPayload::from_json(&i).unwrap();
+            let ts = Timestamp::from_secs(1000 + i);
+            let event = Event::new("tick".to_string(), ts, format!("entity:{}", i % 5), payload);
+            journal.append(event).await.unwrap();
+        }
+
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(10_000));
+        let page = journal
+            .get_events_page("entity:3", start, end, 10)
+            .await
+            .unwrap();
+        // Only the first of the two written blocks was read.
+        assert_eq!(journal.block_cache_stats().misses, 1);
+        let all = journal.get_entity_events("entity:3").await.unwrap();
+        let ids = |events: &[Event]| events.iter().map(Event::id).collect::<Vec<_>>();
+        assert_eq!(ids(&page), ids(&all[..10]));
+    }
+
+    #[tokio::test]
+    async fn test_range_page_stops_after_ties() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..2500 {
+            let payload = EventPayload::from_json(&i).unwrap();
+            // Two events per timestamp.
+            let ts = Timestamp::from_secs(1000 + i / 2);
+            let event = Event::new("tick".to_string(), ts, format!("entity:{}", i % 5), payload);
+            journal.append(event).await.unwrap();
+        }
+
+        let (start, end) = (Timestamp::from_secs(0), Timestamp::from_secs(10_000));
+        let page = journal
+            .scan_range_page(start, end, &|e| e.entity_id() != "entity:0", 7)
+            .await
+            .unwrap();
+        assert_eq!(journal.block_cache_stats().misses, 1);
+        // The seventh event shares its timestamp with the eighth.
+        let times: Vec<i64> = page.iter().map(|e| e.timestamp().as_secs()).collect();
+        assert_eq!(times, [1000, 1001, 1001, 1002, 1003, 1003, 1004, 1004]);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_pages_cover_history_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        for i in 0..2500 {
+            let payload = EventPayload::from_json(&i).unwrap();
+            // Out of order, three events per timestamp.
+            let ts = Timestamp::from_secs(1000 + (i * 7) % 2500 / 3);
+            let event = Event::new("tick".to_string(), ts, format!("entity:{}", i % 2), payload);
+            journal.append(event).await.unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let mut after: Option<EventCursor> = None;
+        loop {
+            let page = journal
+                .get_events_after("entity:1", after.as_ref(), 100)
+                .await
+                .unwrap();
+            let Some((last, _)) = page.last() else { break };
+            assert!(after.is_none_or(|after| page[0].0 > after));
+            after = Some(*last);
+            seen.extend(page);
+        }
+        assert!(seen.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let mut ids: Vec<_> = seen.iter().map(|(_, event)| event.id()).collect();
+        let mut all: Vec<_> = journal.get_entity_events("entity:1").await.unwrap();
+        assert_eq!(ids.len(), all.len());
+        ids.sort();
+        all.sort_by_key(Event::id);
+        assert_eq!(ids, all.iter().map(Event::id).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_range_scan_merges_segments() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new())
+                .unwrap()
+                .with_scan_parallelism(3);
+        for i in 0..520 {
+            let payload = EventPayload::from_json(&i).unwrap();
+            // Every segment spans the whole range, with repeated timestamps.
+            let ts = Timestamp::from_secs(1000 + (i * 37) % 200);
+            let event = Event::new("tick".to_string(), ts, format!("entity:{}", i % 3), payload);
+            journal.append(event).await.unwrap();
+            if i % 100 == 99 {
+                journal.flush().await.unwrap();
+            }
+        }
+        assert_eq!(journal.segments().len(), 5);
+
+        let (start, end) = (Timestamp::from_secs(1050), Timestamp::from_secs(1150));
+        async fn scan(
+            manager: &SegmentManager,
+            start: Timestamp,
+            end: Timestamp,
+        ) -> Vec<crate::core::event::EventId> {
+            let mut seen = Vec::new();
+            let visited = manager.visit_range(start, end, |e| seen.push(e.id()));
+            visited.await.map(|_| seen).unwrap()
+        }
+        let parallel = scan(&journal.segment_manager, start, end).await;
+        journal.segment_manager.set_scan_parallelism(1);
+        assert_eq!(parallel, scan(&journal.segment_manager, start, end).await);
+        assert_eq!(parallel.len(), 260);
+
+        let mut timestamps = Vec::new();
+        journal
+            .scan_range(start, end, &mut |e| timestamps.push(e.timestamp()))
+            .await
+            .unwrap();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn test_second_writer_is_rejected() {
         let temp_dir = TempDir::new().unwrap();
         let segments_dir = temp_dir.path().join("segments");
-        let wal = InMemoryWAL::new();
 
-        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+        let journal = SegmentedJournal::new(&segments_dir, InMemoryWAL::new()).unwrap();
+        assert!(SegmentedJournal::new(&segments_dir, InMemoryWAL::new()).is_err());
 
-        // Add many events to trigger compression
-        // Need at least 1000 events to trigger automatic flush_buffer, or rely on finalize()
-        for i in 0..100 {
-            let payload = EventPayload::from_json(&serde_json::json!({
-                "index": i,
-                "data": "repetitive data".repeat(20)
-            }))
+        drop(journal);
+        assert!(SegmentedJournal::new(&segments_dir, InMemoryWAL::new()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_truncates_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal = AsyncFileWAL::open(temp_dir.path().join("wal.log"))
+            .await
             .unwrap();
+
+        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+        for i in 0..5 {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
             let event = Event::new(
                 "test.event".to_string(),
-                Timestamp::from_secs(1000 + i as i64),
-                format!("entity:{}", i % 10),
+                Timestamp::from_secs(1000 + i),
+                "entity:1".to_string(),
                 payload,
             );
             journal.append(event).await.unwrap();
         }
         journal.flush().await.unwrap();
+        assert_eq!(journal.wal().replay().await.unwrap().len(), 5);
 
-        // Verify compression
-        let segments = journal.segment_manager.segments();
-        assert!(!segments.is_empty(), "At least one segment should be created");
-        
-        // After flush(), segment should be finalized which triggers compression
-        for segment in segments {
-            // finalize() should compress even small buffers
-            assert_ne!(
-                segment.flags & crate::storage::segment_file::FLAG_COMPRESSED,
-                0,
-                "Segment should be compressed after finalize (flags={})",
-                segment.flags
+        let record = journal.checkpoint().await.unwrap();
+        assert_eq!(record.checkpoint_id, 1);
+        assert_eq!(record.event_count, 5);
+        assert_eq!(record.wal_lsn, 5);
+        assert!(journal.wal().replay().await.unwrap().is_empty());
+
+        // The manifest must already describe the segments covering the WAL.
+        let manifest = Manifest::load(&segments_dir).unwrap().unwrap();
+        assert_eq!(manifest.event_count(), 5);
+        assert_eq!(manifest.checkpoint, Some(record));
+
+        // Events are still queryable after the WAL was truncated.
+        let events = journal.get_entity_events("entity:1").await.unwrap();
+        assert_eq!(events.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_open_recovers_segments_and_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal_path = temp_dir.path().join("wal.log");
+        let event = |i: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                format!("entity:{}", i % 2),
+                payload,
+            )
+        };
+
+        {
+            let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+            let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+            for i in 0..4 {
+                journal.append(event(i)).await.unwrap();
+            }
+            journal.checkpoint().await.unwrap();
+            for i in 4..7 {
+                journal.append(event(i)).await.unwrap();
+            }
+            journal.flush().await.unwrap();
+            // Appended and logged, but never finalized into a segment.
+            for i in 7..10 {
+                journal.append(event(i)).await.unwrap();
+            }
+            journal.wal.flush().await.unwrap();
+        }
+
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let mut journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        assert_eq!(journal.segments().len(), 2);
+        assert_eq!(journal.last_checkpoint().unwrap().event_count, 4);
+        let mut recovered = journal.get_entity_events("entity:0").await.unwrap();
+        recovered.extend(journal.get_entity_events("entity:1").await.unwrap());
+        assert_eq!(recovered.len(), 10);
+
+        journal.append(event(10)).await.unwrap();
+        assert_eq!(journal.wal().last_lsn(), 11);
+        let record = journal.checkpoint().await.unwrap();
+        assert_eq!(record.event_count, 11);
+        drop(journal);
+
+        // After a checkpoint the WAL is empty and numbering resumes past it.
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        assert_eq!(journal.wal().last_lsn(), 11);
+        assert_eq!(
+            journal.get_entity_events("entity:0").await.unwrap().len(),
+            6
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_recovers_baseline_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal_path = temp_dir.path().join("wal.log");
+        let event = |i: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                "entity:1".to_string(),
+                payload,
+            )
+        };
+
+        // A data directory as the baseline release left it: a headerless
+        // WAL and no manifest.
+        let mut raw = Vec::new();
+        for i in 0..3 {
+            raw.extend(RecordCodec::default().encode_baseline(&event(i)).unwrap());
+        }
+        std::fs::write(&wal_path, raw).unwrap();
+
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        assert_eq!(wal.format_version(), MIN_WAL_VERSION);
+        let mut journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        assert_eq!(journal.all_events().unwrap().len(), 3);
+        journal.append(event(3)).await.unwrap();
+        assert_eq!(journal.wal().last_lsn(), 4);
+
+        // The checkpoint clears the WAL, which is then written in the
+        // current format.
+        journal.checkpoint().await.unwrap();
+        journal.append(event(4)).await.unwrap();
+        journal.flush().await.unwrap();
+        drop(journal);
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        assert_eq!(wal.format_version(), WAL_VERSION);
+        let journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        assert_eq!(journal.all_events().unwrap().len(), 5);
+        assert_eq!(journal.wal().last_lsn(), 5);
+
+        // Positions continue from the cataloged segment into the WAL tail.
+        assert_eq!(journal.end_position(), 5);
+        let mut visited = Vec::new();
+        journal
+            .visit_events_after(2, |lsn, event| {
+                visited.push((lsn, event.timestamp()));
+                if lsn < 4 {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            })
+            .unwrap();
+        let expected: Vec<_> = (3..=4)
+            .map(|i| (i, event(i as i64 - 1).timestamp()))
+            .collect();
+        assert_eq!(visited, expected);
+    }
+
+    #[tokio::test]
+    async fn test_entity_catalog_survives_reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal_path = temp_dir.path().join("wal.log");
+        let event = |entity_id: &str| {
+            let payload = EventPayload::from_json(&serde_json::json!({})).unwrap();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000),
+                entity_id.to_string(),
+                payload,
+            )
+        };
+
+        {
+            let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+            let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+            for id in ["user:2", "order:1", "user:1"] {
+                journal.append(event(id)).await.unwrap();
+            }
+            journal.flush().await.unwrap();
+            // Only in the WAL.
+            journal.append(event("user:3")).await.unwrap();
+            journal.wal.flush().await.unwrap();
+        }
+        assert!(EntityCatalog::path_in(&segments_dir).exists());
+
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        let page = journal.list_entities("user:", None, 2).await.unwrap();
+        assert_eq!(page.entities, vec!["user:1", "user:2"]);
+        let rest = journal
+            .list_entities("user:", page.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(rest.entities, vec!["user:3"]);
+        assert_eq!(rest.next_cursor, None);
+        assert!(journal.may_contain_entity("user:3"));
+        assert!(!journal.may_contain_entity("user:4"));
+        let missing = journal.get_entity_events("user:4").await.unwrap();
+        assert!(missing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_latest_events_in_one_pass() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal =
+            SegmentedJournal::new(temp_dir.path().join("segments"), InMemoryWAL::new()).unwrap();
+        let event = |entity_id: &str, secs: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({})).unwrap();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(secs),
+                entity_id.to_string(),
+                payload,
+            )
+        };
+        for (entity_id, secs) in [
+            ("user:1", 100),
+            ("user:2", 100),
+            ("user:1", 200),
+            ("order:1", 100),
+        ] {
+            journal.append(event(entity_id, secs)).await.unwrap();
+        }
+        journal.flush().await.unwrap();
+        journal.append(event("user:2", 300)).await.unwrap();
+
+        let latest = journal
+            .get_latest_events(&["user:1", "user:2", "missing"], Timestamp::from_secs(250))
+            .await
+            .unwrap();
+        let found: Vec<_> = latest
+            .iter()
+            .map(|e| (e.entity_id(), e.timestamp().as_nanos() / 1_000_000_000))
+            .collect();
+        assert_eq!(found, vec![("user:1", 200), ("user:2", 100)]);
+
+        let users = journal
+            .get_latest_events_with_prefix("user:", Timestamp::from_secs(300))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].timestamp(), Timestamp::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_retention_expires_oldest_segments() {
+        use crate::storage::object_store::{InMemoryObjectStore, ObjectStore};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let temp_dir = TempDir::new().unwrap();
+        let segments_dir = temp_dir.path().join("segments");
+        let wal_path = temp_dir.path().join("wal.log");
+        let event = |event_type: &str, secs: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({})).unwrap();
+            Event::new(
+                event_type.to_string(),
+                Timestamp::from_secs(secs),
+                "entity:1".to_string(),
+                payload,
+            )
+        };
+
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let mut journal = SegmentedJournal::new(&segments_dir, wal).unwrap();
+        // Segments: [tick@100, tick@200], [audit@300], [tick@5000]
+        for (event_type, secs) in [("tick", 100), ("tick", 200), ("audit", 300), ("tick", 5000)] {
+            journal.append(event(event_type, secs)).await.unwrap();
+            if secs != 100 {
+                journal.flush().await.unwrap();
+            }
+        }
+
+        let store = Arc::new(InMemoryObjectStore::new());
+        let policy = RetentionPolicy::new()
+            .with_max_age(Duration::from_secs(1000))
+            .with_event_type_max_age("audit", Duration::from_secs(10_000))
+            .with_archive(store.clone());
+        let now = Timestamp::from_secs(6000);
+        let report = journal.enforce_retention(&policy, now).await.unwrap();
+
+        // The audit segment is still retained, so retention stops there.
+        assert_eq!(report.segments, vec![1]);
+        assert_eq!(report.events, 2);
+        assert_eq!(
+            store.list(EXPIRED_SEGMENT_PREFIX).await.unwrap(),
+            report.archived
+        );
+        assert!(!Manifest::segment_path(&segments_dir, 1).exists());
+        assert_eq!(
+            journal.get_entity_events("entity:1").await.unwrap().len(),
+            2
+        );
+
+        // Positions stay aligned with the WAL across a checkpoint and reopen.
+        journal.checkpoint().await.unwrap();
+        drop(journal);
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let journal = SegmentedJournal::open(&segments_dir, wal).await.unwrap();
+        assert_eq!(journal.wal().last_lsn(), 4);
+        assert_eq!(
+            journal.get_entity_events("entity:1").await.unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_dropped_segments_kept_while_readers_hold_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = SegmentManager::new(temp_dir.path()).unwrap();
+        for i in 0..2 {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            manager
+                .append_event(Event::new(
+                    "test.event".to_string(),
+                    Timestamp::from_secs(1000 + i),
+                    "entity:1".to_string(),
+                    payload,
+                ))
+                .unwrap();
+            manager.flush().unwrap();
+        }
+
+        let reader = DirLock::shared(temp_dir.path()).unwrap();
+        manager.drop_oldest_segments(1).unwrap();
+        let path = Manifest::segment_path(temp_dir.path(), 1);
+        assert!(path.exists());
+        assert_eq!(manager.deferred_unlinks().len(), 1);
+        assert_eq!(manager.unlink_deferred().unwrap(), 0);
+
+        drop(reader);
+        assert_eq!(manager.unlink_deferred().unwrap(), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_migrate_segments_upgrades_old_versions() {
+        let temp_dir = TempDir::new().unwrap();
+        let event = |i: i64| {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                "entity:1".to_string(),
+                payload,
+            )
+        };
+
+        let mut manager = SegmentManager::new(temp_dir.path()).unwrap();
+        for i in 0..3 {
+            manager.append_event(event(i)).unwrap();
+        }
+        manager.flush().unwrap();
+        drop(manager);
+
+        // Rewrite the segment as an older release would have.
+        let path = Manifest::segment_path(temp_dir.path(), 1);
+        let events = SegmentReader::open(&path).unwrap().read_events().unwrap();
+        let start = Timestamp::from_nanos(i64::MIN + 1);
+        let end = Timestamp::from_nanos(i64::MAX);
+        let mut writer = SegmentWriter::create(&path, 1, start, end)
+            .unwrap()
+            .with_format_version(1)
+            .unwrap();
+        for event in events {
+            writer.append(event).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut manager = SegmentManager::open(temp_dir.path()).unwrap();
+        assert_eq!(manager.segments()[0].version, 1);
+        assert_eq!(manager.read_all_events().unwrap().len(), 3);
+
+        assert_eq!(manager.migrate_segments().unwrap(), vec![1]);
+        assert!(manager.migrate_segments().unwrap().is_empty());
+        assert_eq!(manager.segments()[0].version, SEGMENT_VERSION);
+        drop(manager);
+
+        let manager = SegmentManager::open(temp_dir.path()).unwrap();
+        assert_eq!(manager.segments()[0].version, SEGMENT_VERSION);
+        assert_eq!(manager.read_all_events().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_scrub_repairs_from_replica() {
+        use crate::storage::object_store::{InMemoryObjectStore, ObjectStore};
+        use std::sync::Arc;
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut journal = SegmentedJournal::new(temp_dir.path(), InMemoryWAL::new()).unwrap();
+        for segment in 0..2 {
+            let payload = EventPayload::from_json(&serde_json::json!({"n": segment})).unwrap();
+            let event = Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + segment),
+                "entity:1".to_string(),
+                payload,
             );
-            assert_ne!(segment.checksum, 0, "Checksum should be set");
+            journal.append(event).await.unwrap();
+            journal.flush().await.unwrap();
         }
 
-        // Verify we can read all events back
-        let all_events = journal.segment_manager.read_all_events().unwrap();
-        assert_eq!(all_events.len(), 100);
+        let path = Manifest::segment_path(temp_dir.path(), 1);
+        let intact = fs::read(&path).unwrap();
+        let replica = Arc::new(InMemoryObjectStore::new());
+        replica
+            .put("segments/segment-00000000000000000001.seg", intact.clone())
+            .await
+            .unwrap();
+        let mut corrupt = intact;
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &corrupt).unwrap();
+
+        // Without a replica the damaged segment is flagged, one per pass.
+        let policy = ScrubPolicy::new();
+        let mut report = journal.scrub(&policy).await.unwrap();
+        assert_eq!(report.checked, vec![1]);
+        assert!(report.flagged.is_empty());
+        journal.repair(&mut report, &policy).await.unwrap();
+        assert_eq!(report.flagged, vec![1]);
+        let report = journal.scrub(&ScrubPolicy::new()).await.unwrap();
+        assert_eq!(report.checked, vec![2]);
+        assert!(report.damaged.is_empty());
+
+        let policy = ScrubPolicy::new()
+            .with_segments_per_pass(2)
+            .with_replica(replica);
+        let mut report = journal.scrub(&policy).await.unwrap();
+        journal.repair(&mut report, &policy).await.unwrap();
+        assert_eq!(report.repaired, vec![1]);
+        assert!(journal.scrub(&policy).await.unwrap().damaged.is_empty());
+        let events = journal.get_entity_events("entity:1").await.unwrap();
+        assert_eq!(events.len(), 2);
     }
-}
 
+    #[test]
+    fn test_write_version_pins_segment_format() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = SegmentManager::new(temp_dir.path()).unwrap();
+        assert!(manager.set_write_version(SEGMENT_VERSION + 1).is_err());
+        manager.set_write_version(1).unwrap();
+        let payload = EventPayload::from_json(&serde_json::json!({"index": 1})).unwrap();
+        let event = Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(1000),
+            "entity:1".to_string(),
+            payload,
+        );
+        manager.append_event(event).unwrap();
+        manager.flush().unwrap();
+        assert_eq!(manager.segments()[0].version, 1);
+        assert!(manager.migrate_segments().unwrap().is_empty());
+
+        // Once every node reads the new format, upgrade the old segments.
+        manager.set_write_version(SEGMENT_VERSION).unwrap();
+        assert_eq!(manager.migrate_segments().unwrap(), vec![1]);
+        assert_eq!(manager.read_all_events().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_verify_all_repairs_corrupt_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut manager = SegmentManager::new(temp_dir.path()).unwrap();
+        for i in 0..2500 {
+            let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+            let event = Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                format!("entity:{}", i),
+                payload,
+            );
+            manager.append_event(event).unwrap();
+        }
+        manager.flush().unwrap();
+        assert!(manager.verify_all(false).unwrap().is_healthy());
+        let bad_offset = manager.indexes[0].blocks()[1].offset;
+        drop(manager);
+
+        // Flip a byte inside the second block.
+        let path = Manifest::segment_path(temp_dir.path(), 1);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[bad_offset as usize + 20] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        assert!(SegmentManager::open(temp_dir.path()).is_err());
+
+        let report = verify_segments(temp_dir.path(), false).unwrap();
+        let damaged: Vec<_> = report.damaged().collect();
+        assert_eq!(damaged.len(), 1);
+        assert_eq!(damaged[0].faults.len(), 1);
+        assert_eq!(damaged[0].faults[0].offset, bad_offset);
+        assert_eq!(damaged[0].lost_events, 1000);
+        assert!(!damaged[0].repaired);
+
+        let report = verify_segments(temp_dir.path(), true).unwrap();
+        assert!(report.segments[0].repaired);
+        let mut manager = SegmentManager::open(temp_dir.path()).unwrap();
+        assert_eq!(manager.read_all_events().unwrap().len(), 1500);
+        assert_eq!(manager.cataloged_event_count(), 2500);
+        assert!(manager.verify_all(true).unwrap().is_healthy());
+    }
+}
@@ -0,0 +1,137 @@
+//! Composite index of events by type and time.
+//!
+//! A [`TypeTimeIndex`] keeps, for each event type, its entries ordered by
+//! timestamp, so the entries of one type in a time range are found with a
+//! tree lookup followed by a walk over the matches only. Each entry covers
+//! a span of time: a single event covers one instant, a segment block the
+//! span of its events of that type. Entries store a small handle (an
+//! entity ordinal, a block position) rather than the events themselves.
+
+use crate::core::temporal::Timestamp;
+use std::collections::{BTreeMap, HashMap};
+
+/// Entries of one event type
+#[derive(Debug, Clone)]
+struct TypeEntries<T> {
+    /// Entries by the start of their span, with the end of the span
+    by_start: BTreeMap<Timestamp, Vec<(Timestamp, T)>>,
+    /// Longest span of any entry, in nanoseconds
+    widest: i64,
+}
+
+impl<T> Default for TypeEntries<T> {
+    fn default() -> Self {
+        Self {
+            by_start: BTreeMap::new(),
+            widest: 0,
+        }
+    }
+}
+
+/// Index from (event type, time) to entries
+#[derive(Debug, Clone)]
+pub struct TypeTimeIndex<T> {
+    types: HashMap<String, TypeEntries<T>>,
+    len: usize,
+}
+
+impl<T> TypeTimeIndex<T> {
+    pub fn new() -> Self {
+        Self {
+            types: HashMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Add `value` for events of `event_type` from `first` to `last`,
+    /// both inclusive
+    pub fn insert(&mut self, event_type: &str, first: Timestamp, last: Timestamp, value: T) {
+        let entries = match self.types.get_mut(event_type) {
+            Some(entries) => entries,
+            None => self.types.entry(event_type.to_string()).or_default(),
+        };
+        let width = last.as_nanos().saturating_sub(first.as_nanos());
+        entries.widest = entries.widest.max(width);
+        entries
+            .by_start
+            .entry(first)
+            .or_default()
+            .push((last, value));
+        self.len += 1;
+    }
+
+    /// Entries of `event_type` whose span meets `[start, end)`, in order of
+    /// span start, then insertion
+    pub fn overlapping(
+        &self,
+        event_type: &str,
+        start: Timestamp,
+        end: Timestamp,
+    ) -> impl Iterator<Item = (Timestamp, &T)> + '_ {
+        let matches = self.types.get(event_type).filter(|_| start < end);
+        matches.into_iter().flat_map(move |entries| {
+            // No span is wider than `widest`, so spans meeting the range
+            // start no earlier than `start - widest`.
+            let lowest = Timestamp::from_nanos(start.as_nanos().saturating_sub(entries.widest));
+            entries
+                .by_start
+                .range(lowest..end)
+                .flat_map(|(first, spans)| spans.iter().map(move |span| (*first, span)))
+                .filter(move |(_, (last, _))| *last >= start)
+                .map(|(first, (_, value))| (first, value))
+        })
+    }
+
+    /// Distinct event types indexed
+    pub fn event_types(&self) -> impl Iterator<Item = &str> + '_ {
+        self.types.keys().map(String::as_str)
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T> Default for TypeTimeIndex<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finds_overlapping_spans_of_a_type() {
+        let ts = Timestamp::from_secs;
+        let mut index = TypeTimeIndex::new();
+        for i in 0..1000 {
+            index.insert("tick", ts(i), ts(i), i);
+        }
+        index.insert("block", ts(10), ts(40), 0);
+        index.insert("block", ts(50), ts(60), 1);
+        index.insert("block", ts(20), ts(25), 2);
+        assert_eq!(index.len(), 1003);
+
+        let ticks: Vec<_> = index.overlapping("tick", ts(500), ts(503)).collect();
+        assert_eq!(ticks, [(ts(500), &500), (ts(501), &501), (ts(502), &502)]);
+        let blocks = |start, end| {
+            index
+                .overlapping("block", ts(start), ts(end))
+                .map(|(_, position)| *position)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(blocks(30, 55), [0, 1]);
+        assert_eq!(blocks(0, 21), [0, 2]);
+        assert_eq!(blocks(41, 50), Vec::<i64>::new());
+        assert_eq!(blocks(60, 60), Vec::<i64>::new());
+        assert!(index.overlapping("missing", ts(0), ts(10)).next().is_none());
+    }
+}
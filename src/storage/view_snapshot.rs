@@ -0,0 +1,349 @@
+//! Periodic snapshots of materialized views.
+//!
+//! Rebuilding a large view means replaying the whole journal. A
+//! [`ViewSnapshotter`] applies events to a view and counts them by [`Lsn`];
+//! a background job persists the full view state together with the LSN it
+//! covers whenever enough events have been applied since the last
+//! snapshot. On restart the snapshot is loaded and only the journal events
+//! after that LSN, from segments and the WAL alike, are replayed.
+//!
+//! Snapshot file format (little-endian):
+//! - 8 bytes: magic `TDBVSNAP`
+//! - 4 bytes: CRC32 of the body
+//! - rest: bincode-serialized [`ViewSnapshot`]
+
+use crate::core::event::Event;
+use crate::core::temporal::Timestamp;
+use crate::error::{Error, Result};
+use crate::storage::async_wal::AsyncWriteAheadLog;
+use crate::storage::journal::Lsn;
+use crate::storage::materialized_view::MaterializedView;
+use crate::storage::segment_journal::SegmentedJournal;
+use crc32fast::Hasher as Crc32Hasher;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// View snapshot file name inside a segment directory
+pub const VIEW_SNAPSHOT_FILE: &str = "VIEW_SNAPSHOT";
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TDBVSNAP";
+
+/// Journal events replayed into the view at a time during recovery
+const RECOVERY_BATCH: usize = 1024;
+
+/// Persisted state of a materialized view
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ViewSnapshot {
+    /// Number of journal events reflected in `entries`
+    pub lsn: Lsn,
+    /// Wall-clock time the snapshot was taken
+    pub created_at: Timestamp,
+    /// Exported view state
+    pub entries: Vec<(String, Vec<u8>)>,
+}
+
+impl ViewSnapshot {
+    /// Path of the snapshot file inside `dir`
+    pub fn path_in<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join(VIEW_SNAPSHOT_FILE)
+    }
+
+    /// Load the snapshot from `dir`, returning `None` if none has been written yet.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Option<Self>> {
+        let path = Self::path_in(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read(&path)?;
+        if data.len() < 12 || &data[..8] != SNAPSHOT_MAGIC {
+            return Err(Error::Storage(format!(
+                "Invalid view snapshot: {}",
+                path.display()
+            )));
+        }
+        let crc = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        let body = &data[12..];
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(body);
+        if hasher.finalize() != crc {
+            return Err(Error::Storage(format!(
+                "View snapshot checksum mismatch: {}",
+                path.display()
+            )));
+        }
+
+        let snapshot =
+            bincode::deserialize(body).map_err(|e| Error::Serialization(e.to_string()))?;
+        Ok(Some(snapshot))
+    }
+
+    /// Atomically persist the snapshot into `dir`.
+    pub fn store<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let path = Self::path_in(dir);
+        let tmp_path = dir.join(format!("{VIEW_SNAPSHOT_FILE}.tmp"));
+
+        let body = bincode::serialize(self).map_err(|e| Error::Serialization(e.to_string()))?;
+        let mut hasher = Crc32Hasher::new();
+        hasher.update(&body);
+
+        let mut tmp = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(SNAPSHOT_MAGIC)?;
+        tmp.write_all(&hasher.finalize().to_le_bytes())?;
+        tmp.write_all(&body)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, &path)?;
+        // Make the rename itself durable.
+        if let Ok(dir_handle) = File::open(dir) {
+            let _ = dir_handle.sync_all();
+        }
+        Ok(())
+    }
+}
+
+/// When a [`ViewSnapshotter`] writes a new snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewSnapshotPolicy {
+    /// A snapshot is due once this many events have been applied since
+    /// the previous one. `0` disables automatic snapshots.
+    pub every_events: u64,
+}
+
+impl Default for ViewSnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            every_events: 10_000,
+        }
+    }
+}
+
+struct SnapshotState {
+    /// LSN of the last event applied to the view
+    applied: Lsn,
+    /// LSN covered by the most recent snapshot on disk
+    snapshot: Lsn,
+}
+
+/// Applies journal events to a view and snapshots it.
+///
+/// Events must be applied in journal order so that the LSN count matches
+/// their position in the journal.
+pub struct ViewSnapshotter {
+    dir: PathBuf,
+    view: Arc<dyn MaterializedView>,
+    policy: ViewSnapshotPolicy,
+    state: Mutex<SnapshotState>,
+    /// Serializes snapshot writes so an older one never replaces a newer one
+    writer: Mutex<()>,
+}
+
+impl ViewSnapshotter {
+    /// Create a snapshotter storing snapshots in `dir` (normally the segment
+    /// directory, next to the manifest).
+    pub fn new<P: AsRef<Path>>(
+        dir: P,
+        view: Arc<dyn MaterializedView>,
+        policy: ViewSnapshotPolicy,
+    ) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            view,
+            policy,
+            state: Mutex::new(SnapshotState {
+                applied: 0,
+                snapshot: 0,
+            }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Rebuild the view on startup from the latest snapshot and the events
+    /// of `journal` after the LSN it covers, including those only in the
+    /// WAL.
+    ///
+    /// A snapshot that is ahead of the journal (e.g. the journal was reset)
+    /// is ignored and the view is rebuilt from scratch. Returns the LSN the
+    /// view now reflects.
+    pub async fn recover<W: AsyncWriteAheadLog>(
+        &self,
+        journal: &SegmentedJournal<W>,
+    ) -> Result<Lsn> {
+        let mut state = self.state.lock().await;
+        let dir = self.dir.clone();
+        let end = journal.end_position();
+        let snapshot = tokio::task::spawn_blocking(move || ViewSnapshot::load(dir))
+            .await
+            .map_err(|e| Error::Storage(format!("View snapshot task failed: {}", e)))??
+            .filter(|snapshot| snapshot.lsn <= end);
+
+        let start = match snapshot {
+            Some(snapshot) => {
+                self.view.import_state(snapshot.entries).await?;
+                snapshot.lsn
+            }
+            None => 0,
+        };
+        // Replay in batches so memory stays bounded without a snapshot.
+        let mut position = start;
+        loop {
+            let mut batch = Vec::with_capacity(RECOVERY_BATCH);
+            journal.visit_events_after(position, |lsn, event| {
+                batch.push(event.clone());
+                position = lsn;
+                if batch.len() < RECOVERY_BATCH {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            })?;
+            for event in &batch {
+                self.view.apply_event(event).await?;
+            }
+            if batch.len() < RECOVERY_BATCH {
+                break;
+            }
+        }
+        state.snapshot = start;
+        state.applied = end;
+        Ok(end)
+    }
+
+    /// Apply the next journal event to the view. Returns the event's LSN.
+    pub async fn apply(&self, event: &Event) -> Result<Lsn> {
+        let mut state = self.state.lock().await;
+        self.view.apply_event(event).await?;
+        state.applied += 1;
+        Ok(state.applied)
+    }
+
+    /// Write a snapshot if the policy says one is due. Returns the LSN it
+    /// covers, if one was written.
+    pub async fn snapshot_if_due(&self) -> Result<Option<Lsn>> {
+        let every = self.policy.every_events;
+        let due = {
+            let state = self.state.lock().await;
+            every > 0 && state.applied - state.snapshot >= every
+        };
+        if !due {
+            return Ok(None);
+        }
+        self.snapshot().await.map(Some)
+    }
+
+    /// Write a snapshot of the current view state now.
+    ///
+    /// Applying events waits only while the state is exported; the file is
+    /// written on the blocking thread pool.
+    pub async fn snapshot(&self) -> Result<Lsn> {
+        let _writer = self.writer.lock().await;
+        let snapshot = {
+            let state = self.state.lock().await;
+            ViewSnapshot {
+                lsn: state.applied,
+                created_at: Timestamp::now(),
+                entries: self.view.export_state().await?,
+            }
+        };
+        let lsn = snapshot.lsn;
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || snapshot.store(dir))
+            .await
+            .map_err(|e| Error::Storage(format!("View snapshot task failed: {}", e)))??;
+
+        let mut state = self.state.lock().await;
+        state.snapshot = state.snapshot.max(lsn);
+        Ok(lsn)
+    }
+
+    /// LSN of the last event applied to the view
+    pub async fn applied_lsn(&self) -> Lsn {
+        self.state.lock().await.applied
+    }
+
+    /// LSN covered by the most recent snapshot written or loaded
+    pub async fn snapshot_lsn(&self) -> Lsn {
+        self.state.lock().await.snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::storage::async_wal::AsyncFileWAL;
+    use crate::storage::materialized_view::InMemoryMaterializedView;
+    use crate::storage::EventJournal;
+    use tempfile::TempDir;
+
+    fn event(entity_id: &str, value: i64) -> Event {
+        Event::new(
+            "value.changed".to_string(),
+            Timestamp::from_secs(1000 + value),
+            entity_id.to_string(),
+            EventPayload::from_json(&value).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_and_delta_replay() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("segments");
+        let wal_path = temp_dir.path().join("wal.log");
+        let view = Arc::new(InMemoryMaterializedView::new());
+        {
+            let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+            let mut journal = SegmentedJournal::new(&dir, wal).unwrap();
+            let snapshotter =
+                ViewSnapshotter::new(&dir, view.clone(), ViewSnapshotPolicy { every_events: 3 });
+            for i in 0..4 {
+                let e = event(if i % 2 == 0 { "a" } else { "b" }, i);
+                journal.append(e.clone()).await.unwrap();
+                snapshotter.apply(&e).await.unwrap();
+                if i == 1 {
+                    assert_eq!(snapshotter.snapshot_if_due().await.unwrap(), None);
+                }
+                if i == 2 {
+                    assert_eq!(snapshotter.snapshot_if_due().await.unwrap(), Some(3));
+                }
+            }
+            // The last event is only in the WAL.
+            journal.flush().await.unwrap();
+            assert_eq!(ViewSnapshot::load(&dir).unwrap().unwrap().lsn, 3);
+        }
+
+        // A fresh view recovers the snapshot plus the one event after it.
+        let wal = AsyncFileWAL::open(&wal_path).await.unwrap();
+        let journal = SegmentedJournal::open(&dir, wal).await.unwrap();
+        let restored = Arc::new(InMemoryMaterializedView::new());
+        let recovering =
+            ViewSnapshotter::new(&dir, restored.clone(), ViewSnapshotPolicy::default());
+        assert_eq!(recovering.recover(&journal).await.unwrap(), 4);
+        assert_eq!(recovering.snapshot_lsn().await, 3);
+        for entity in ["a", "b"] {
+            assert_eq!(
+                restored.get_current_raw(entity).await.unwrap(),
+                view.get_current_raw(entity).await.unwrap()
+            );
+        }
+        let mut delta = Vec::new();
+        journal
+            .visit_events_after(3, |lsn, _| {
+                delta.push(lsn);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(delta, vec![4]);
+    }
+}
@@ -6,10 +6,16 @@
 
 use crate::core::event::Event;
 use crate::error::{Error, Result};
-use crc32fast::Hasher as Crc32Hasher;
+use crate::storage::checksum::ChecksumKind;
+use crate::storage::journal::Lsn;
+use crate::storage::wal_cipher::{
+    KeyId, KeyProvider, KeyRing, WalCipher, WalEncryptionKey, LEGACY_KEY_ID,
+};
+use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Write-Ahead Log trait
 pub trait WriteAheadLog: Send + Sync {
@@ -24,22 +30,43 @@ pub trait WriteAheadLog: Send + Sync {
 
     /// Clear WAL (after checkpoint)
     fn clear(&mut self) -> Result<()>;
+
+    /// LSN of the most recently appended record, or `0` if none.
+    ///
+    /// LSNs keep increasing across [`WriteAheadLog::clear`].
+    fn last_lsn(&self) -> Lsn;
 }
 
 /// In-memory WAL (for testing)
 pub struct InMemoryWAL {
     events: Vec<Event>,
+    last_lsn: Lsn,
 }
 
 impl InMemoryWAL {
     pub fn new() -> Self {
-        Self { events: Vec::new() }
+        Self {
+            events: Vec::new(),
+            last_lsn: 0,
+        }
+    }
+
+    /// Continue numbering after `lsn` if it is past the last record.
+    pub fn advance_lsn(&mut self, lsn: Lsn) {
+        self.last_lsn = self.last_lsn.max(lsn);
+    }
+}
+
+impl Default for InMemoryWAL {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl WriteAheadLog for InMemoryWAL {
     fn append(&mut self, event: &Event) -> Result<()> {
         self.events.push(event.clone());
+        self.last_lsn += 1;
         Ok(())
     }
 
@@ -55,17 +82,666 @@ impl WriteAheadLog for InMemoryWAL {
         self.events.clear();
         Ok(())
     }
+
+    fn last_lsn(&self) -> Lsn {
+        self.last_lsn
+    }
+}
+
+/// How `FileWAL` reacts to a corrupt record during replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecoveryMode {
+    /// Fail replay on the first corrupt record.
+    #[default]
+    Strict,
+    /// Stop at the first corrupt record and truncate the file there, so that
+    /// new appends are not hidden behind the damaged region.
+    TruncateAtCorruption,
+    /// Skip corrupt records (trusting their length field) and keep going.
+    SkipCorrupt,
+}
+
+/// Summary of a WAL replay.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of records successfully recovered
+    pub records_recovered: u64,
+    /// Number of corrupt records skipped or dropped
+    pub records_skipped: u64,
+    /// Number of bytes removed from the end of the file
+    pub bytes_truncated: u64,
+    /// Byte offset of the first corrupt record, if any
+    pub first_corruption_offset: Option<u64>,
+}
+
+/// Version of the WAL format, stored in the file header and advertised to
+/// peers during upgrades.
+///
+/// Version 2 frames records with flags and an LSN and tags encrypted
+/// records with their key ID.
+pub const WAL_VERSION: u32 = 2;
+
+/// Version of the headerless layout written before WAL files had a header:
+/// `[crc32][len][payload]` records without flags or LSNs
+pub const WAL_VERSION_BASELINE: u32 = 1;
+
+/// Oldest WAL format version still replayed: the baseline layout, so data
+/// directories written before the header was introduced can be opened
+pub const MIN_WAL_VERSION: u32 = WAL_VERSION_BASELINE;
+
+/// Magic bytes opening a WAL file with a header
+const WAL_MAGIC: [u8; 4] = *b"TWAL";
+
+/// Size of the file header: magic and format version
+const WAL_HEADER_SIZE: usize = 8;
+
+/// Size of a baseline record prefix: CRC32, length
+const BASELINE_PREFIX_SIZE: usize = 8;
+
+/// Record flag: the payload is zstd-compressed
+pub const RECORD_FLAG_COMPRESSED: u8 = 0x01;
+
+/// Record flag: the record is protected by a CRC64 instead of a CRC32
+pub const RECORD_FLAG_CRC64: u8 = 0x02;
+
+/// Record flag: the payload is encrypted and starts with the 4-byte ID of
+/// its key
+pub const RECORD_FLAG_KEY_ID: u8 = 0x04;
+
+/// Size of the fixed record prefix: length, flags, LSN
+const RECORD_PREFIX_SIZE: usize = 13;
+
+/// Per-record compression settings for the WAL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalCompression {
+    /// Serialized events smaller than this many bytes are stored as-is
+    pub min_size: usize,
+    /// zstd compression level
+    pub level: i32,
+}
+
+impl Default for WalCompression {
+    fn default() -> Self {
+        Self {
+            min_size: 4096,
+            level: 3,
+        }
+    }
+}
+
+/// Encodes events into record payloads and back, applying the optional
+/// compression and encryption stages.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RecordCodec {
+    pub(crate) compression: Option<WalCompression>,
+    pub(crate) keys: Option<Arc<dyn KeyProvider>>,
+    pub(crate) checksum: ChecksumKind,
+}
+
+impl RecordCodec {
+    /// Seal records with a single key, see [`FileWAL::with_encryption`]
+    pub(crate) fn single_key(key: &WalEncryptionKey) -> Arc<dyn KeyProvider> {
+        Arc::new(KeyRing::new(LEGACY_KEY_ID, key.clone()))
+    }
+
+    /// Encode an event as a framed WAL record:
+    /// `[len][flags][lsn][checksum][payload]`.
+    ///
+    /// The payload is compressed before it is encrypted; encrypted bytes do
+    /// not compress. Encryption authenticates the flags, LSN and key ID as
+    /// associated data, so a sealed payload cannot be moved to another
+    /// record or relabeled with another key.
+    pub(crate) fn encode(&self, event: &Event, lsn: Lsn) -> Result<Vec<u8>> {
+        let mut payload =
+            bincode::serialize(event).map_err(|e| Error::Serialization(e.to_string()))?;
+        let mut flags = 0u8;
+
+        if let Some(compression) = self.compression {
+            if payload.len() >= compression.min_size {
+                let compressed = zstd::bulk::compress(&payload, compression.level)
+                    .map_err(|e| Error::Storage(format!("WAL compression failed: {}", e)))?;
+                // Keep the original when compression does not pay off.
+                if compressed.len() < payload.len() {
+                    payload = compressed;
+                    flags |= RECORD_FLAG_COMPRESSED;
+                }
+            }
+        }
+        if self.keys.is_some() {
+            flags |= RECORD_FLAG_KEY_ID;
+        }
+        if self.checksum == ChecksumKind::Crc64 {
+            flags |= RECORD_FLAG_CRC64;
+        }
+
+        let lsn_bytes = lsn.to_le_bytes();
+        if let Some(keys) = &self.keys {
+            let (id, key) = keys.current_key()?;
+            let aad = Self::associated_data(flags, &lsn_bytes, id);
+            let sealed = WalCipher::new(&key).encrypt(&payload, &aad)?;
+            payload = Vec::with_capacity(4 + sealed.len());
+            payload.extend_from_slice(&id.to_le_bytes());
+            payload.extend_from_slice(&sealed);
+        }
+        let checksum = self.checksum.compute(&[&[flags], &lsn_bytes, &payload]);
+        let len = payload.len() as u32;
+
+        let mut record =
+            Vec::with_capacity(RECORD_PREFIX_SIZE + self.checksum.width() + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.push(flags);
+        record.extend_from_slice(&lsn_bytes);
+        record.extend_from_slice(&self.checksum.encode(checksum));
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Bytes of the record header authenticated along with an encrypted
+    /// payload: flags, LSN and key ID
+    fn associated_data(flags: u8, lsn_bytes: &[u8], key_id: KeyId) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + lsn_bytes.len() + 4);
+        aad.push(flags);
+        aad.extend_from_slice(lsn_bytes);
+        aad.extend_from_slice(&key_id.to_le_bytes());
+        aad
+    }
+
+    /// Checksum kind used by a record with the given flags
+    fn checksum_kind(flags: u8) -> ChecksumKind {
+        if flags & RECORD_FLAG_CRC64 != 0 {
+            ChecksumKind::Crc64
+        } else {
+            ChecksumKind::Crc32
+        }
+    }
+
+    /// Encode an event as a baseline record, `[crc32][len][payload]`.
+    ///
+    /// The layout has no flags, so records can be neither compressed nor
+    /// encrypted and are always protected by a CRC32.
+    pub(crate) fn encode_baseline(&self, event: &Event) -> Result<Vec<u8>> {
+        self.check_write_version(WAL_VERSION_BASELINE)?;
+        let payload = bincode::serialize(event).map_err(|e| Error::Serialization(e.to_string()))?;
+        let crc = ChecksumKind::Crc32.compute(&[&payload]) as u32;
+        let mut record = Vec::with_capacity(BASELINE_PREFIX_SIZE + payload.len());
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    /// Check that records can be written in format `version` with these
+    /// settings
+    pub(crate) fn check_write_version(&self, version: u32) -> Result<()> {
+        if !(MIN_WAL_VERSION..=WAL_VERSION).contains(&version) {
+            return Err(Error::Configuration(format!(
+                "Unsupported WAL version {}, expected {} to {}",
+                version, MIN_WAL_VERSION, WAL_VERSION
+            )));
+        }
+        if version == WAL_VERSION_BASELINE
+            && (self.keys.is_some()
+                || self.compression.is_some()
+                || self.checksum != ChecksumKind::Crc32)
+        {
+            return Err(Error::Configuration(
+                "The baseline WAL format supports no encryption, compression or CRC64".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encode an event as a record of a WAL file in format `version`
+    pub(crate) fn encode_for(&self, version: u32, event: &Event, lsn: Lsn) -> Result<Vec<u8>> {
+        if version == WAL_VERSION_BASELINE {
+            self.encode_baseline(event)
+        } else {
+            self.encode(event, lsn)
+        }
+    }
+
+    /// File header of a WAL in format `version`; baseline files have none
+    pub(crate) fn file_header(version: u32) -> Vec<u8> {
+        if version == WAL_VERSION_BASELINE {
+            return Vec::new();
+        }
+        let mut header = Vec::with_capacity(WAL_HEADER_SIZE);
+        header.extend_from_slice(&WAL_MAGIC);
+        header.extend_from_slice(&version.to_le_bytes());
+        header
+    }
+
+    /// Identify the format of a WAL from its first bytes, returning the
+    /// version and the size of its header.
+    ///
+    /// Files that do not start with the magic predate the header and hold
+    /// baseline records. `None` means nothing was written yet: the file is
+    /// empty or its header is torn.
+    pub(crate) fn parse_header(start: &[u8]) -> Result<Option<(u32, usize)>> {
+        let magic = start.len().min(WAL_MAGIC.len());
+        if start[..magic] != WAL_MAGIC[..magic] {
+            return Ok(Some((WAL_VERSION_BASELINE, 0)));
+        }
+        if start.len() < WAL_HEADER_SIZE {
+            return Ok(None);
+        }
+        let version = u32::from_le_bytes([start[4], start[5], start[6], start[7]]);
+        if version == WAL_VERSION_BASELINE || !(MIN_WAL_VERSION..=WAL_VERSION).contains(&version) {
+            return Err(Error::Storage(format!(
+                "Unsupported WAL format version {}",
+                version
+            )));
+        }
+        Ok(Some((version, WAL_HEADER_SIZE)))
+    }
+
+    /// Read the header at the start of `reader` and position it at the
+    /// first record. See [`RecordCodec::parse_header`].
+    fn read_header<R: Read + Seek>(reader: &mut R) -> Result<Option<(u32, u64)>> {
+        let mut start = Vec::with_capacity(WAL_HEADER_SIZE);
+        reader
+            .by_ref()
+            .take(WAL_HEADER_SIZE as u64)
+            .read_to_end(&mut start)?;
+        let header = Self::parse_header(&start)?;
+        let header_len = header.map_or(0, |(_, len)| len as u64);
+        reader.seek(SeekFrom::Start(header_len))?;
+        Ok(header.map(|(version, _)| (version, header_len)))
+    }
+
+    /// Scan a WAL file, writing a header for [`WAL_VERSION`] if it holds
+    /// nothing yet.
+    ///
+    /// Records are appended in the format of the file, so a log keeps its
+    /// format until it is cleared.
+    pub(crate) fn prepare_file(path: &Path) -> Result<WalFileState> {
+        let scan = Self::scan_file(path)?;
+        let version = match scan.version {
+            Some(version) => version,
+            None => {
+                let mut file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(0)?;
+                file.write_all(&Self::file_header(WAL_VERSION))?;
+                file.sync_all()?;
+                WAL_VERSION
+            }
+        };
+        Ok(WalFileState {
+            version,
+            write_version: WAL_VERSION,
+            base_lsn: 0,
+            last_lsn: scan.range.map_or(0, |(_, last)| last),
+            records: scan.records,
+        })
+    }
+
+    /// Scan the header and complete records of a WAL file.
+    ///
+    /// Only record prefixes are read; payloads are skipped without being
+    /// verified.
+    pub(crate) fn scan_file(path: &Path) -> Result<WalScan> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        Self::scan_records(BufReader::new(file), file_len)
+    }
+
+    /// Size of the record prefix in format `version`
+    fn prefix_size(version: u32) -> usize {
+        if version == WAL_VERSION_BASELINE {
+            BASELINE_PREFIX_SIZE
+        } else {
+            RECORD_PREFIX_SIZE
+        }
+    }
+
+    /// Size of the rest of a record after its prefix, and its LSN if the
+    /// format stores one
+    fn parse_prefix(version: u32, prefix: &[u8]) -> (u64, Option<Lsn>) {
+        if version == WAL_VERSION_BASELINE {
+            let len = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+            return (len as u64, None);
+        }
+        let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64;
+        let width = Self::checksum_kind(prefix[4]).width() as u64;
+        let mut lsn = [0u8; 8];
+        lsn.copy_from_slice(&prefix[5..13]);
+        (width + len, Some(u64::from_le_bytes(lsn)))
+    }
+
+    /// Walk the header and record prefixes of `file_len` bytes of WAL.
+    pub(crate) fn scan_records<R: Read + Seek>(mut reader: R, file_len: u64) -> Result<WalScan> {
+        let Some((version, header_len)) = Self::read_header(&mut reader)? else {
+            return Ok(WalScan::default());
+        };
+        let mut scan = WalScan {
+            version: Some(version),
+            complete: header_len,
+            ..WalScan::default()
+        };
+
+        let mut prefix = vec![0u8; Self::prefix_size(version)];
+        while scan.complete + prefix.len() as u64 <= file_len {
+            reader.read_exact(&mut prefix)?;
+            let (body, lsn) = Self::parse_prefix(version, &prefix);
+            let end = scan.complete + prefix.len() as u64 + body;
+            if end > file_len {
+                // Torn record at the tail.
+                break;
+            }
+            scan.records += 1;
+            let lsn = lsn.unwrap_or(scan.records);
+            scan.range = Some(match scan.range {
+                Some((first, last)) => (first.min(lsn), last.max(lsn)),
+                None => (lsn, lsn),
+            });
+            reader.seek(SeekFrom::Current(body as i64))?;
+            scan.complete = end;
+        }
+        Ok(scan)
+    }
+
+    /// Split the complete records of an in-memory copy of a WAL into their
+    /// LSNs and raw bytes, without verifying or decoding them. Also returns
+    /// the format version, or `None` if the copy holds nothing.
+    ///
+    /// Baseline records are numbered from 1.
+    pub(crate) fn split_records(bytes: &[u8]) -> Result<SplitWal<'_>> {
+        let start = &bytes[..bytes.len().min(WAL_HEADER_SIZE)];
+        let Some((version, mut offset)) = Self::parse_header(start)? else {
+            return Ok((None, Vec::new()));
+        };
+        let prefix_size = Self::prefix_size(version);
+        let mut records = Vec::new();
+        while offset + prefix_size <= bytes.len() {
+            let (body, lsn) = Self::parse_prefix(version, &bytes[offset..offset + prefix_size]);
+            let end = offset + prefix_size + body as usize;
+            if end > bytes.len() {
+                // Torn record at the tail.
+                break;
+            }
+            let lsn = lsn.unwrap_or(records.len() as Lsn + 1);
+            records.push((lsn, &bytes[offset..end]));
+            offset = end;
+        }
+        Ok((Some(version), records))
+    }
+
+    /// Decode every record in an in-memory copy of a WAL.
+    ///
+    /// Used for archived logs, which must be intact: any corrupt or
+    /// unauthenticated record is an error.
+    pub(crate) fn decode_all(&self, bytes: &[u8]) -> Result<Vec<(Lsn, Event)>> {
+        let start = &bytes[..bytes.len().min(WAL_HEADER_SIZE)];
+        let Some((version, header_len)) = Self::parse_header(start)? else {
+            return Ok(Vec::new());
+        };
+        let mut bytes = &bytes[header_len..];
+        let mut records = Vec::new();
+        while !bytes.is_empty() {
+            let offset = records.len();
+            match self.read_next(&mut bytes, version, offset as Lsn + 1)? {
+                (RecordRead::Record(lsn, event), _) => records.push((lsn, *event)),
+                (RecordRead::Eof, _) => {
+                    return Err(Error::Storage(format!(
+                        "Archived WAL truncated after record {}",
+                        offset
+                    )))
+                }
+                (RecordRead::Corrupt | RecordRead::Unauthenticated, _) => {
+                    return Err(Error::Storage(format!(
+                        "Archived WAL record {} is corrupt or cannot be decrypted",
+                        offset
+                    )))
+                }
+                (RecordRead::MissingKey, _) => {
+                    return Err(Error::Configuration(format!(
+                        "Archived WAL record {} is encrypted but no key is configured",
+                        offset
+                    )))
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Decrypt a sealed payload with the key it is tagged with. Returns
+    /// `None` if the key is unknown or authentication fails.
+    fn open_sealed(
+        keys: &dyn KeyProvider,
+        flags: u8,
+        lsn_bytes: &[u8],
+        sealed: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        if sealed.len() < 4 {
+            return Ok(None);
+        }
+        let (id, sealed) = sealed.split_at(4);
+        let id = KeyId::from_le_bytes([id[0], id[1], id[2], id[3]]);
+        let Some(key) = keys.key(id)? else {
+            tracing::warn!("WAL record sealed with unknown key {}", id);
+            return Ok(None);
+        };
+        let aad = Self::associated_data(flags, lsn_bytes, id);
+        Ok(WalCipher::new(&key).decrypt(sealed, &aad).ok())
+    }
+
+    /// Read the next record of a WAL in format `version`, returning it with
+    /// the number of bytes consumed. Baseline records are numbered
+    /// `baseline_lsn`.
+    fn read_next<R: Read>(
+        &self,
+        reader: &mut R,
+        version: u32,
+        baseline_lsn: Lsn,
+    ) -> Result<(RecordRead, u64)> {
+        if version == WAL_VERSION_BASELINE {
+            return Self::read_baseline(reader, baseline_lsn);
+        }
+        let mut prefix = [0u8; RECORD_PREFIX_SIZE];
+        match reader.read_exact(&mut prefix) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                // Clean EOF: no more records.
+                return Ok((RecordRead::Eof, 0));
+            }
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        let flags = prefix[4];
+        let lsn_bytes = &prefix[5..13];
+        let kind = Self::checksum_kind(flags);
+
+        let mut checksum = [0u8; 8];
+        let mut buf = vec![0u8; len];
+        let body = reader
+            .read_exact(&mut checksum[..kind.width()])
+            .and_then(|_| reader.read_exact(&mut buf));
+        if let Err(e) = body {
+            // Truncated record at end of file; treat as logical EOF.
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                return Ok((RecordRead::Eof, 0));
+            }
+            return Err(Error::Io(e));
+        }
+        let consumed = (RECORD_PREFIX_SIZE + kind.width() + len) as u64;
+
+        if kind.compute(&[&[flags], lsn_bytes, &buf]) != kind.decode(&checksum) {
+            return Ok((RecordRead::Corrupt, consumed));
+        }
+        let mut lsn = [0u8; 8];
+        lsn.copy_from_slice(lsn_bytes);
+        let lsn = u64::from_le_bytes(lsn);
+
+        if flags & RECORD_FLAG_KEY_ID != 0 {
+            let Some(keys) = &self.keys else {
+                return Ok((RecordRead::MissingKey, consumed));
+            };
+            buf = match Self::open_sealed(keys.as_ref(), flags, lsn_bytes, &buf)? {
+                Some(plain) => plain,
+                None => return Ok((RecordRead::Unauthenticated, consumed)),
+            };
+        }
+        if flags & RECORD_FLAG_COMPRESSED != 0 {
+            buf = match zstd::stream::decode_all(buf.as_slice()) {
+                Ok(plain) => plain,
+                Err(_) => return Ok((RecordRead::Corrupt, consumed)),
+            };
+        }
+
+        let record = match bincode::deserialize(&buf) {
+            Ok(event) => RecordRead::Record(lsn, Box::new(event)),
+            Err(_) => RecordRead::Corrupt,
+        };
+        Ok((record, consumed))
+    }
+
+    /// Read a baseline record, `[crc32][len][payload]`, numbering it `lsn`.
+    fn read_baseline<R: Read>(reader: &mut R, lsn: Lsn) -> Result<(RecordRead, u64)> {
+        let mut prefix = [0u8; BASELINE_PREFIX_SIZE];
+        match reader.read_exact(&mut prefix) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok((RecordRead::Eof, 0));
+            }
+            Err(e) => return Err(Error::Io(e)),
+        }
+        let crc = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+        let len = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]) as usize;
+        let mut buf = vec![0u8; len];
+        if let Err(e) = reader.read_exact(&mut buf) {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                return Ok((RecordRead::Eof, 0));
+            }
+            return Err(Error::Io(e));
+        }
+        let consumed = (BASELINE_PREFIX_SIZE + len) as u64;
+
+        if ChecksumKind::Crc32.compute(&[&buf]) != crc as u64 {
+            return Ok((RecordRead::Corrupt, consumed));
+        }
+        let record = match bincode::deserialize(&buf) {
+            Ok(event) => RecordRead::Record(lsn, Box::new(event)),
+            Err(_) => RecordRead::Corrupt,
+        };
+        Ok((record, consumed))
+    }
+}
+
+/// Format version and raw records of a WAL copy, see
+/// [`RecordCodec::split_records`]
+pub(crate) type SplitWal<'a> = (Option<u32>, Vec<(Lsn, &'a [u8])>);
+
+/// Header and extent of the records in a WAL file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct WalScan {
+    /// Format version, or `None` if nothing was written yet
+    pub(crate) version: Option<u32>,
+    /// LSN range of the complete records; baseline records are numbered
+    /// from 1
+    pub(crate) range: Option<(Lsn, Lsn)>,
+    /// Number of complete records
+    pub(crate) records: u64,
+    /// Bytes taken up by the header and the complete records
+    pub(crate) complete: u64,
+}
+
+/// Outcome of reading a single WAL record.
+enum RecordRead {
+    /// A valid record and its LSN
+    Record(Lsn, Box<Event>),
+    /// A complete record whose checksum or encoding is invalid
+    Corrupt,
+    /// A record with a valid checksum that could not be decrypted
+    Unauthenticated,
+    /// An encrypted record read without any key configured
+    MissingKey,
+    /// Clean or truncated end of file
+    Eof,
 }
 
 /// On-disk WAL implementation.
 ///
+/// The file starts with an 8-byte header: the magic `TWAL` and the format
+/// version ([`WAL_VERSION`]) as a little-endian u32. Files without it were
+/// written in the baseline layout ([`WAL_VERSION_BASELINE`]) and stay
+/// readable; records are appended in the format of the file until it is
+/// cleared.
+///
 /// Record format (little-endian):
-/// - 4 bytes: CRC32 of payload
 /// - 4 bytes: payload length in bytes (N)
+/// - 1 byte: flags ([`RECORD_FLAG_COMPRESSED`], [`RECORD_FLAG_CRC64`],
+///   [`RECORD_FLAG_KEY_ID`])
+/// - 8 bytes: LSN
+/// - 4 or 8 bytes: CRC32 or CRC64 of flags, LSN and payload
 /// - N bytes: bincode-serialized `Event`
+///
+/// Every record carries a monotonically increasing LSN, so higher layers can
+/// refer to exact WAL positions. With compression enabled, large payloads
+/// are zstd-compressed and flagged. With encryption enabled the payload is
+/// then sealed by [`WalCipher`] and prefixed with the key ID; the checksum
+/// covers the sealed bytes.
 pub struct FileWAL {
     path: PathBuf,
     file: File,
+    recovery_mode: RecoveryMode,
+    codec: RecordCodec,
+    state: WalFileState,
+}
+
+/// Format and LSN numbering of the WAL file being appended to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct WalFileState {
+    /// Format version of the file
+    pub(crate) version: u32,
+    /// Format version the file is written in once it is cleared
+    pub(crate) write_version: u32,
+    /// LSN preceding the first record of a baseline file, which stores none
+    pub(crate) base_lsn: Lsn,
+    /// LSN of the most recently appended record
+    pub(crate) last_lsn: Lsn,
+    /// Complete records in the file
+    pub(crate) records: u64,
+}
+
+impl WalFileState {
+    /// Move the LSN numbering past `lsn`, see [`FileWAL::with_base_lsn`]
+    pub(crate) fn advance(&mut self, lsn: Lsn) {
+        if self.version == WAL_VERSION_BASELINE {
+            if lsn > self.base_lsn {
+                self.last_lsn += lsn - self.base_lsn;
+                self.base_lsn = lsn;
+            }
+        } else {
+            self.last_lsn = self.last_lsn.max(lsn);
+        }
+    }
+
+    /// Record that the record numbered `lsn` was appended
+    pub(crate) fn appended(&mut self, lsn: Lsn) {
+        self.last_lsn = lsn;
+        self.records += 1;
+    }
+
+    /// Switch to the write version once the file is emptied. Returns the
+    /// header to write, if the format changed.
+    pub(crate) fn reset(&mut self) -> Option<Vec<u8>> {
+        self.base_lsn = self.last_lsn;
+        self.records = 0;
+        let changed = self.version != self.write_version;
+        self.version = self.write_version;
+        changed.then(|| RecordCodec::file_header(self.version))
+    }
+
+    /// Write future records in format `version`. Takes effect at once if
+    /// the file holds no record yet, and otherwise when it is cleared.
+    /// Returns whether the file must be emptied and given the header
+    /// returned by [`WalFileState::reset`].
+    pub(crate) fn pin(&mut self, codec: &RecordCodec, version: u32) -> Result<bool> {
+        codec.check_write_version(version)?;
+        self.write_version = version;
+        Ok(self.records == 0 && self.version != version)
+    }
 }
 
 impl FileWAL {
@@ -79,16 +755,111 @@ impl FileWAL {
         let file = OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
             .open(path)?;
+        let state = RecordCodec::prepare_file(path)?;
 
         Ok(Self {
             path: path.to_path_buf(),
             file,
+            recovery_mode: RecoveryMode::default(),
+            codec: RecordCodec::default(),
+            state,
         })
     }
 
+    /// Continue numbering after `lsn` if the log holds no later record.
+    ///
+    /// A checkpoint clears the WAL, so after a restart the file alone no
+    /// longer knows the last LSN; pass the LSN recorded by the checkpoint.
+    /// Baseline files store no LSNs, so their records are numbered after
+    /// `lsn`.
+    pub fn with_base_lsn(mut self, lsn: Lsn) -> Self {
+        self.state.advance(lsn);
+        self
+    }
+
+    /// Format version of the file, see [`WAL_VERSION`]
+    pub fn format_version(&self) -> u32 {
+        self.state.version
+    }
+
+    /// Write records in format `version` instead of [`WAL_VERSION`], e.g.
+    /// the version negotiated with older peers during a rolling upgrade.
+    ///
+    /// The file keeps its format while it holds records, so the version
+    /// takes effect once it is cleared. The baseline format rules out
+    /// encryption, compression and CRC64.
+    pub fn with_write_version(mut self, version: u32) -> Result<Self> {
+        if self.state.pin(&self.codec, version)? {
+            self.file.set_len(0)?;
+            if let Some(header) = self.state.reset() {
+                self.file.write_all(&header)?;
+            }
+            self.file.sync_all()?;
+        }
+        Ok(self)
+    }
+
+    /// Protect new records with the given checksum.
+    ///
+    /// The checksum kind is stored per record, so logs written with
+    /// different settings remain readable.
+    pub fn with_checksum(mut self, checksum: ChecksumKind) -> Self {
+        self.codec.checksum = checksum;
+        self
+    }
+
+    /// Encrypt records with `key`.
+    ///
+    /// A WAL must always be opened with the key it was written with; records
+    /// that fail authentication, or are encrypted while no key is set, abort
+    /// replay regardless of recovery mode. Unencrypted records written
+    /// before encryption was enabled stay readable.
+    pub fn with_encryption(mut self, key: &WalEncryptionKey) -> Self {
+        self.codec.keys = Some(RecordCodec::single_key(key));
+        self
+    }
+
+    /// Encrypt records with the current key of `keys`.
+    ///
+    /// Each record is tagged with its key ID, so the provider may rotate
+    /// keys at any time as long as it keeps the retired ones for replay.
+    pub fn with_key_provider(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.codec.keys = Some(keys);
+        self
+    }
+
+    /// Compress records whose serialized size reaches `compression.min_size`.
+    ///
+    /// Compressed and uncompressed records can be mixed in one file, so this
+    /// can be changed between restarts.
+    pub fn with_compression(mut self, compression: WalCompression) -> Self {
+        self.codec.compression = Some(compression);
+        self
+    }
+
+    pub(crate) fn with_codec(mut self, codec: RecordCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Whether records are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.codec.keys.is_some()
+    }
+
+    /// Set the recovery mode used by replay.
+    pub fn with_recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// Get the recovery mode used by replay.
+    pub fn recovery_mode(&self) -> RecoveryMode {
+        self.recovery_mode
+    }
+
     /// Open a fresh read handle positioned at the beginning of the WAL.
     fn open_read(&self) -> Result<File> {
         let mut f = OpenOptions::new().read(true).open(&self.path)?;
@@ -96,64 +867,165 @@ impl FileWAL {
         Ok(f)
     }
 
-    fn write_record(file: &mut File, event: &Event) -> Result<()> {
-        let payload =
-            bincode::serialize(event).map_err(|e| Error::Serialization(e.to_string()))?;
+    fn write_record(&mut self, event: &Event) -> Result<()> {
+        let lsn = self.state.last_lsn + 1;
+        let record = self.codec.encode_for(self.state.version, event, lsn)?;
+        self.file.write_all(&record)?;
+        self.state.appended(lsn);
+        Ok(())
+    }
 
-        let mut hasher = Crc32Hasher::new();
-        hasher.update(&payload);
-        let crc = hasher.finalize();
-        let len = payload.len() as u32;
+    /// Replay the WAL according to the configured recovery mode and report
+    /// how many records were recovered or skipped.
+    pub fn replay_with_report(&self) -> Result<(Vec<Event>, RecoveryReport)> {
+        let mut iter = self.replay_iter()?;
+        let events = iter.by_ref().collect::<Result<Vec<_>>>()?;
+        Ok((events, iter.into_report()))
+    }
+
+    /// Replay the WAL one record at a time.
+    ///
+    /// Unlike [`WriteAheadLog::replay`], only the record being decoded is
+    /// held in memory, so arbitrarily large logs can be recovered
+    /// incrementally.
+    pub fn replay_iter(&self) -> Result<WalReplayIter> {
+        let mut reader = BufReader::new(self.open_read()?);
+        let header = RecordCodec::read_header(&mut reader)?;
+        let (version, offset) = header.unwrap_or((self.state.version, 0));
+        Ok(WalReplayIter {
+            reader,
+            path: self.path.clone(),
+            codec: self.codec.clone(),
+            recovery_mode: self.recovery_mode,
+            version,
+            offset,
+            position: self.state.base_lsn,
+            last_lsn: 0,
+            report: RecoveryReport::default(),
+            done: header.is_none(),
+        })
+    }
+}
 
-        // [crc32][len][payload]
-        file.write_all(&crc.to_le_bytes())?;
-        file.write_all(&len.to_le_bytes())?;
-        file.write_all(&payload)?;
+/// Streaming replay over a [`FileWAL`], created by [`FileWAL::replay_iter`].
+///
+/// Corrupt records are handled according to the WAL's recovery mode. After
+/// an error is yielded the iterator is exhausted. The [`RecoveryReport`] is
+/// complete once the iterator has returned `None`.
+pub struct WalReplayIter {
+    reader: BufReader<File>,
+    path: PathBuf,
+    codec: RecordCodec,
+    recovery_mode: RecoveryMode,
+    /// Format version of the file
+    version: u32,
+    /// Byte offset of the next record
+    offset: u64,
+    /// LSN of the last complete record read, numbering baseline records
+    position: Lsn,
+    /// LSN of the record most recently yielded
+    last_lsn: Lsn,
+    report: RecoveryReport,
+    done: bool,
+}
 
-        Ok(())
+impl WalReplayIter {
+    /// LSN of the record most recently yielded, or `0` before the first one
+    pub fn last_lsn(&self) -> Lsn {
+        self.last_lsn
     }
 
-    fn read_next_record(file: &mut File) -> Result<Option<Event>> {
-        let mut header = [0u8; 8];
-        match file.read_exact(&mut header) {
-            Ok(()) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                // Clean EOF: no more records.
-                return Ok(None);
-            }
-            Err(e) => return Err(Error::Io(e)),
-        }
+    /// Replay statistics so far
+    pub fn report(&self) -> &RecoveryReport {
+        &self.report
+    }
 
-        let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
-        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    /// Consume the iterator, returning the replay statistics
+    pub fn into_report(self) -> RecoveryReport {
+        self.report
+    }
 
-        let mut buf = vec![0u8; len];
-        if let Err(e) = file.read_exact(&mut buf) {
-            // Truncated record at end of file; treat as logical EOF.
-            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                return Ok(None);
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let offset = self.offset;
+            let (record, consumed) =
+                self.codec
+                    .read_next(&mut self.reader, self.version, self.position + 1)?;
+            self.offset += consumed;
+            if consumed > 0 {
+                self.position += 1;
+            }
+            match record {
+                RecordRead::Record(lsn, ev) => {
+                    self.report.records_recovered += 1;
+                    self.last_lsn = lsn;
+                    return Ok(Some(*ev));
+                }
+                RecordRead::Eof => return Ok(None),
+                RecordRead::Unauthenticated => {
+                    // Not a torn write: the key is wrong or the file was
+                    // tampered with, so no recovery mode may drop data here.
+                    return Err(Error::Storage(format!(
+                        "WAL record at offset {} failed authentication (wrong encryption key?)",
+                        offset
+                    )));
+                }
+                RecordRead::MissingKey => {
+                    return Err(Error::Configuration(format!(
+                        "WAL record at offset {} is encrypted but no encryption key is configured",
+                        offset
+                    )));
+                }
+                RecordRead::Corrupt => {
+                    self.report.records_skipped += 1;
+                    self.report.first_corruption_offset.get_or_insert(offset);
+                    match self.recovery_mode {
+                        RecoveryMode::Strict => {
+                            return Err(Error::Storage(format!(
+                                "WAL CRC mismatch at offset {}",
+                                offset
+                            )));
+                        }
+                        RecoveryMode::SkipCorrupt => continue,
+                        RecoveryMode::TruncateAtCorruption => {
+                            let file_len = std::fs::metadata(&self.path)?.len();
+                            let writer = OpenOptions::new().write(true).open(&self.path)?;
+                            writer.set_len(offset)?;
+                            writer.sync_all()?;
+                            self.report.bytes_truncated = file_len - offset;
+                            return Ok(None);
+                        }
+                    }
+                }
             }
-            return Err(Error::Io(e));
         }
+    }
+}
 
-        let mut hasher = Crc32Hasher::new();
-        hasher.update(&buf);
-        let actual_crc = hasher.finalize();
-        if actual_crc != crc {
-            // Corruption detected; stop replay here.
-            return Err(Error::Storage("WAL CRC mismatch".to_string()));
-        }
+impl Iterator for WalReplayIter {
+    type Item = Result<Event>;
 
-        let event: Event =
-            bincode::deserialize(&buf).map_err(|e| Error::Serialization(e.to_string()))?;
-        Ok(Some(event))
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
 impl WriteAheadLog for FileWAL {
     fn append(&mut self, event: &Event) -> Result<()> {
-        Self::write_record(&mut self.file, event)?;
-        Ok(())
+        self.write_record(event)
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -163,16 +1035,7 @@ impl WriteAheadLog for FileWAL {
     }
 
     fn replay(&self) -> Result<Vec<Event>> {
-        let mut f = self.open_read()?;
-        let mut events = Vec::new();
-
-        loop {
-            match Self::read_next_record(&mut f)? {
-                Some(ev) => events.push(ev),
-                None => break,
-            }
-        }
-
+        let (events, _) = self.replay_with_report()?;
         Ok(events)
     }
 
@@ -185,6 +1048,324 @@ impl WriteAheadLog for FileWAL {
             .truncate(true)
             .open(&self.path)?;
         self.file.seek(SeekFrom::Start(0))?;
+        self.state.reset();
+        self.file
+            .write_all(&RecordCodec::file_header(self.state.version))?;
         Ok(())
     }
+
+    fn last_lsn(&self) -> Lsn {
+        self.state.last_lsn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::event::EventPayload;
+    use crate::core::temporal::Timestamp;
+    use tempfile::TempDir;
+
+    fn event(i: i64) -> Event {
+        let payload = EventPayload::from_json(&serde_json::json!({"index": i})).unwrap();
+        Event::new(
+            "test.event".to_string(),
+            Timestamp::from_secs(1000 + i),
+            "entity:1".to_string(),
+            payload,
+        )
+    }
+
+    /// Write three records and flip a byte inside the second one's payload.
+    fn write_corrupted_wal(path: &Path) {
+        let mut wal = FileWAL::open(path).unwrap();
+        wal.append(&event(0)).unwrap();
+        wal.flush().unwrap();
+        let second_offset = std::fs::metadata(path).unwrap().len();
+        wal.append(&event(1)).unwrap();
+        wal.append(&event(2)).unwrap();
+        wal.flush().unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(second_offset + 12)).unwrap();
+        file.write_all(&[0xFF]).unwrap();
+        file.sync_all().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        write_corrupted_wal(&path);
+
+        let strict = FileWAL::open(&path).unwrap();
+        assert!(strict.replay().is_err());
+
+        let skip = FileWAL::open(&path)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::SkipCorrupt);
+        let (events, report) = skip.replay_with_report().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(report.records_recovered, 2);
+        assert_eq!(report.records_skipped, 1);
+
+        let truncate = FileWAL::open(&path)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::TruncateAtCorruption);
+        let (events, report) = truncate.replay_with_report().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(report.records_skipped, 1);
+        assert!(report.bytes_truncated > 0);
+
+        // After truncation the file is clean again, even in strict mode.
+        let strict = FileWAL::open(&path).unwrap();
+        assert_eq!(strict.replay().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_replay_iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        write_corrupted_wal(&path);
+
+        let mut iter = FileWAL::open(&path).unwrap().replay_iter().unwrap();
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+
+        let wal = FileWAL::open(&path)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::SkipCorrupt);
+        let mut iter = wal.replay_iter().unwrap();
+        let timestamps: Vec<Timestamp> = iter.by_ref().map(|e| e.unwrap().timestamp()).collect();
+        assert_eq!(timestamps, vec![event(0).timestamp(), event(2).timestamp()]);
+        assert_eq!(iter.report().records_skipped, 1);
+    }
+
+    #[test]
+    fn test_lsns_and_crc64() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+
+        let mut wal = FileWAL::open(&path).unwrap();
+        assert_eq!(wal.last_lsn(), 0);
+        wal.append(&event(0)).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+
+        // Numbering resumes from the file; checksums can differ per record.
+        let mut wal = FileWAL::open(&path)
+            .unwrap()
+            .with_checksum(ChecksumKind::Crc64);
+        assert_eq!(wal.last_lsn(), 1);
+        wal.append(&event(1)).unwrap();
+        wal.flush().unwrap();
+
+        let mut iter = wal.replay_iter().unwrap();
+        let mut lsns = Vec::new();
+        while let Some(event) = iter.next() {
+            event.unwrap();
+            lsns.push(iter.last_lsn());
+        }
+        assert_eq!(lsns, vec![1, 2]);
+
+        // After a checkpoint clears the file, the base LSN keeps numbering monotonic.
+        wal.clear().unwrap();
+        drop(wal);
+        let mut wal = FileWAL::open(&path).unwrap().with_base_lsn(2);
+        wal.append(&event(2)).unwrap();
+        assert_eq!(wal.last_lsn(), 3);
+    }
+
+    #[test]
+    fn test_baseline_wal_replays() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+
+        // Headerless `[crc32][len][payload]` records, as written before WAL
+        // files had a header.
+        let mut raw = Vec::new();
+        for i in 0..2 {
+            let payload = bincode::serialize(&event(i)).unwrap();
+            raw.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            raw.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            raw.extend_from_slice(&payload);
+        }
+        std::fs::write(&path, &raw).unwrap();
+
+        let mut wal = FileWAL::open(&path).unwrap();
+        assert_eq!(wal.format_version(), WAL_VERSION_BASELINE);
+        assert_eq!(wal.last_lsn(), 2);
+        // The file keeps its format until it is cleared.
+        wal.append(&event(2)).unwrap();
+        wal.flush().unwrap();
+        let mut iter = wal.replay_iter().unwrap();
+        let mut lsns = Vec::new();
+        while let Some(event) = iter.next() {
+            event.unwrap();
+            lsns.push(iter.last_lsn());
+        }
+        assert_eq!(lsns, vec![1, 2, 3]);
+        assert!(std::fs::read(&path).unwrap().starts_with(&raw));
+
+        wal.clear().unwrap();
+        wal.append(&event(3)).unwrap();
+        wal.flush().unwrap();
+        assert_eq!(wal.format_version(), WAL_VERSION);
+        assert!(std::fs::read(&path).unwrap().starts_with(b"TWAL"));
+        let events = FileWAL::open(&path).unwrap().replay().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].timestamp(), event(3).timestamp());
+
+        // A pinned older format takes effect once the file is cleared.
+        let mut wal = FileWAL::open(&path)
+            .unwrap()
+            .with_write_version(WAL_VERSION_BASELINE)
+            .unwrap();
+        assert_eq!(wal.format_version(), WAL_VERSION);
+        wal.clear().unwrap();
+        wal.append(&event(4)).unwrap();
+        wal.flush().unwrap();
+        assert_eq!(wal.format_version(), WAL_VERSION_BASELINE);
+        assert!(!std::fs::read(&path).unwrap().starts_with(b"TWAL"));
+        assert_eq!(FileWAL::open(&path).unwrap().replay().unwrap().len(), 1);
+
+        let encrypted = FileWAL::open(&path)
+            .unwrap()
+            .with_encryption(&WalEncryptionKey::generate());
+        assert!(encrypted.with_write_version(WAL_VERSION_BASELINE).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_wal() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let key = WalEncryptionKey::generate();
+
+        let mut wal = FileWAL::open(&path).unwrap().with_encryption(&key);
+        for i in 0..3 {
+            wal.append(&event(i)).unwrap();
+        }
+        wal.flush().unwrap();
+        let events = wal.replay().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].timestamp(), event(2).timestamp());
+
+        // Payloads are not stored in the clear.
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"index"));
+
+        // A wrong or missing key is an error even when skipping corrupt records.
+        let wrong = FileWAL::open(&path)
+            .unwrap()
+            .with_encryption(&WalEncryptionKey::generate())
+            .with_recovery_mode(RecoveryMode::SkipCorrupt);
+        assert!(matches!(wrong.replay(), Err(Error::Storage(_))));
+        let keyless = FileWAL::open(&path)
+            .unwrap()
+            .with_recovery_mode(RecoveryMode::SkipCorrupt);
+        assert!(matches!(keyless.replay(), Err(Error::Configuration(_))));
+
+        // The sealed payload is bound to its record's LSN and key ID, even
+        // where two IDs name the same key.
+        let codec = RecordCodec {
+            keys: Some(Arc::new(KeyRing::new(1, key.clone()).with_key(2, key))),
+            ..RecordCodec::default()
+        };
+        let record = codec.encode(&event(0), 1).unwrap();
+        let tampered = |range: std::ops::Range<usize>, bytes: &[u8]| {
+            let mut record = record.clone();
+            record[range].copy_from_slice(bytes);
+            let kind = ChecksumKind::Crc32;
+            let crc = kind.compute(&[&record[4..5], &record[5..13], &record[17..]]);
+            record[13..17].copy_from_slice(&kind.encode(crc));
+            let (read, _) = codec
+                .read_next(&mut record.as_slice(), WAL_VERSION, 0)
+                .unwrap();
+            matches!(read, RecordRead::Unauthenticated)
+        };
+        assert!(!tampered(5..13, &1u64.to_le_bytes()));
+        assert!(tampered(5..13, &2u64.to_le_bytes()));
+        assert!(tampered(17..21, &2u32.to_le_bytes()));
+    }
+
+    #[test]
+    fn test_key_rotation_mid_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let (first, second) = (WalEncryptionKey::generate(), WalEncryptionKey::generate());
+        let keys = Arc::new(KeyRing::new(1, first.clone()));
+
+        let mut wal = FileWAL::open(&path)
+            .unwrap()
+            .with_key_provider(keys.clone());
+        wal.append(&event(0)).unwrap();
+        keys.rotate(2, second.clone()).unwrap();
+        wal.append(&event(1)).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+
+        // Replay picks each record's key by its tag.
+        let both = KeyRing::new(2, second.clone()).with_key(1, first);
+        let events = FileWAL::open(&path)
+            .unwrap()
+            .with_key_provider(Arc::new(both))
+            .replay()
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].timestamp(), event(1).timestamp());
+
+        // Without the retired key the older record cannot be read.
+        let only_new = FileWAL::open(&path)
+            .unwrap()
+            .with_key_provider(Arc::new(KeyRing::new(2, second)));
+        assert!(only_new.replay().is_err());
+    }
+
+    #[test]
+    fn test_compressed_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("wal.log");
+        let big = |i: i64| {
+            let text = "lorem ipsum ".repeat(1000);
+            let payload = EventPayload::from_json(&serde_json::json!({"i": i, "text": text}));
+            Event::new(
+                "test.event".to_string(),
+                Timestamp::from_secs(1000 + i),
+                "entity:1".to_string(),
+                payload.unwrap(),
+            )
+        };
+
+        let mut wal = FileWAL::open(&path)
+            .unwrap()
+            .with_compression(WalCompression::default());
+        wal.append(&big(0)).unwrap();
+        wal.append(&event(1)).unwrap();
+        wal.flush().unwrap();
+        let raw_size = bincode::serialize(&big(0)).unwrap().len() as u64;
+        assert!(std::fs::metadata(&path).unwrap().len() < raw_size);
+
+        // Small records stay uncompressed and both kinds replay without the setting.
+        let events = FileWAL::open(&path).unwrap().replay().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].payload().data, big(0).payload().data);
+
+        // Compression composes with encryption.
+        let key = WalEncryptionKey::generate();
+        let enc_path = temp_dir.path().join("enc.log");
+        let mut wal = FileWAL::open(&enc_path)
+            .unwrap()
+            .with_compression(WalCompression::default())
+            .with_encryption(&key);
+        wal.append(&big(2)).unwrap();
+        wal.flush().unwrap();
+        assert!(std::fs::metadata(&enc_path).unwrap().len() < raw_size);
+        let events = FileWAL::open(&enc_path)
+            .unwrap()
+            .with_encryption(&key)
+            .replay()
+            .unwrap();
+        assert_eq!(events[0].payload().data, big(2).payload().data);
+    }
 }
@@ -0,0 +1,98 @@
+//! Cold-start warmup of caches.
+//!
+//! After a restart the block cache and the pinned hot-entity timelines are
+//! empty, so the first reads all go to disk. A database periodically records
+//! its hot set in a [`HotSet`] file; when warmup is enabled, opening the
+//! database pins those entities' timelines again and preloads the newest
+//! segment blocks into the block cache.
+
+use crate::core::temporal::Timestamp;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Hot set file name inside a data directory
+pub const HOT_SET_FILE: &str = "HOT_SET";
+
+/// Hot entities recorded by the last run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HotSet {
+    /// Entity IDs, hottest first
+    pub entities: Vec<String>,
+    /// Wall-clock time the hot set was recorded
+    pub saved_at: Timestamp,
+}
+
+impl HotSet {
+    /// Hot set of `entities`, recorded now
+    pub fn new(entities: Vec<String>) -> Self {
+        Self {
+            entities,
+            saved_at: Timestamp::now(),
+        }
+    }
+
+    /// Path of the hot set file inside `dir`
+    pub fn path_in<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join(HOT_SET_FILE)
+    }
+
+    /// Load the hot set from `dir`, returning `None` if none has been
+    /// recorded yet
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Option<Self>> {
+        let path = Self::path_in(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(&path)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Atomically persist the hot set into `dir`.
+    pub fn store<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        let tmp_path = dir.join(format!("{HOT_SET_FILE}.tmp"));
+        let data = serde_json::to_vec_pretty(self)?;
+
+        let mut tmp = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, Self::path_in(dir))?;
+        if let Ok(dir_handle) = File::open(dir) {
+            let _ = dir_handle.sync_all();
+        }
+        Ok(())
+    }
+}
+
+/// What a warmup preloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WarmupReport {
+    /// Segment blocks loaded into the block cache
+    pub blocks: usize,
+    /// Entities of the recorded hot set whose timelines were pinned
+    pub entities: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_set_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(HotSet::load(dir.path()).unwrap(), None);
+
+        let hot_set = HotSet::new(vec!["user:2".to_string(), "user:1".to_string()]);
+        hot_set.store(dir.path()).unwrap();
+        assert_eq!(HotSet::load(dir.path()).unwrap(), Some(hot_set));
+    }
+}